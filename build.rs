@@ -23,6 +23,23 @@ fn main() -> std::io::Result<()> {
 
     // Needed for older versions of protoc
     protoc.protoc_arg("--experimental_allow_proto3_optional");
+    // JSON fallback transport (see proto::WireFormat), the messages fields can be omitted like
+    // in protobuf and the oneofs variants are named like their proto fields
+    protoc
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]")
+        .enum_attribute(".", "#[serde(rename_all = \"snake_case\")]")
+        // Only set on the WS errors, the other responses don't carry it
+        .field_attribute(
+            ".cmd.CommandResponse.debug",
+            "#[serde(skip_serializing_if = \"Option::is_none\")]",
+        )
+        .field_attribute(
+            ".cmd.CommandResponse.request_id",
+            "#[serde(skip_serializing_if = \"Option::is_none\")]",
+        )
+        // prost_types' one cannot be serialized
+        .extern_path(".google.protobuf.Timestamp", "crate::proto::Timestamp");
     protoc.compile_protos(&proto_files, &[PROTO_DIR])?;
 
     let _ = fs::create_dir(PROTO_TS_OUT);
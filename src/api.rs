@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config;
+
+/// Versions served side by side, each one under its own scope (/v1, /v2...) with the same
+/// handlers. The version of the scope is in its app data so handlers can branch on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    pub const ALL: [Self; 2] = [Self::V1, Self::V2];
+    pub const LATEST: Self = Self::V2;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+        }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::V1 => "/v1",
+            Self::V2 => "/v2",
+        }
+    }
+
+    /// Set with the API_{VERSION}_DEPRECATED_AT and API_{VERSION}_SUNSET_AT env vars
+    pub fn deprecation(&self) -> Option<Deprecation> {
+        config::get().api_deprecations.get(self).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+    pub deprecated_at: DateTime<Utc>,
+    /// The version answers 410 Gone from then on
+    pub sunset_at: Option<DateTime<Utc>>,
+}
+
+impl Deprecation {
+    pub fn is_sunset(&self, now: DateTime<Utc>) -> bool {
+        self.sunset_at.is_some_and(|sunset_at| sunset_at <= now)
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::api::{ApiVersion, Deprecation};
+use crate::sharify::content_policy::ContentPolicy;
+use crate::sharify::identity::{IdentityProvider, OAuthClient};
+use crate::sharify::room::{
+    DEFAULT_MAX_LOGS_LEN, DEFAULT_RECONNECT_GRACE_PERIOD, OwnerlessRoomPolicy,
+};
+use crate::sharify::spotify::{
+    DEFAULT_MAX_OUTAGE_DURATION, DEFAULT_MAX_OUTAGE_RETRY_INTERVAL, DEFAULT_OUTAGE_RETRY_BACKOFF,
+    SpotifyBaseUrls,
+};
+use crate::sharify::websocket::WsTransport;
+use crate::sharify::websocket::commands::{CommandCategory, CommandRateLimit};
+use crate::telemetry::{self, LogFormat};
+
+const ENV_FILE: &str = ".env";
+
+static CONFIG: LazyLock<ArcSwap<Config>> =
+    LazyLock::new(|| ArcSwap::from_pointee(Config::from_env()));
+
+/// Settings read from the env (.env file), see .env.example
+///
+/// Hot-reloadable ones are applied by reload(), the others are only read at startup
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // Hot-reloadable
+    /// tracing EnvFilter directives, e.g. "info,sharify_be=debug"
+    pub log_level: String,
+    /// Bearer token of the /admin routes, they're disabled when unset
+    pub admin_token: Option<String>,
+    pub room_max_logs: usize,
+    pub discord_webhook: Option<String>,
+    /// Interval of the room activity check
+    pub data_fetching_interval: Duration,
+    /// Default interval of the Spotify data fetching when no track end is expected
+    pub spotify_data_interval: Duration,
+    pub spotify_requests_per_window: u8,
+    /// TTL of the playback/queue responses shared by the rooms of the same host account
+    pub spotify_cache_ttl: Duration,
+    /// Interval of the in-memory state integrity sweeper
+    pub sweeper_interval: Duration,
+    /// Deprecated API versions, signalled with the Deprecation and Sunset headers
+    pub api_deprecations: HashMap<ApiVersion, Deprecation>,
+    /// Per user rate limit of the WS commands of each category
+    pub ws_command_limits: HashMap<CommandCategory, CommandRateLimit>,
+    /// OAuth clients of the enabled identity providers
+    pub oauth_clients: HashMap<IdentityProvider, OAuthClient>,
+    /// Public base URL of the server, the providers redirect to its /auth/{provider}/callback
+    pub oauth_redirect_base_url: Option<String>,
+    /// Where the users are redirected after logging in, with the identity token as fragment
+    pub oauth_frontend_url: Option<String>,
+    /// Rejects the rooms creation/join and the WS sessions without identity token
+    pub identity_required: bool,
+    /// Content types and host markets denied in every room
+    pub content_policy: ContentPolicy,
+    /// Applied to the rooms none of whose connected members can manage them
+    pub ownerless_room_policy: OwnerlessRoomPolicy,
+    /// Spotify app of the PKCE logins and tokens refreshes
+    pub spotify_client_id: Option<String>,
+    /// A user reconnecting within it isn't announced again and can resume its session
+    pub reconnect_grace_period: Duration,
+    /// Delay before the first retry of a room whose Spotify fetch failed, doubled on each failure
+    pub spotify_outage_retry_backoff: Duration,
+    pub spotify_outage_max_retry_interval: Duration,
+    /// A room whose Spotify fetches keep failing for this long is closed
+    pub spotify_outage_max_duration: Duration,
+    /// The per client throttles read the client IP from the Forwarded/X-Forwarded-For headers
+    /// set by the reverse proxy instead of the peer address
+    pub trusted_proxy: bool,
+
+    // Require a restart
+    pub is_prod: bool,
+    pub host: Ipv4Addr,
+    pub port: u16,
+    pub tls_private_key: Option<String>,
+    pub tls_cert_key: Option<String>,
+    pub governor_burst_size: u32,
+    pub governor_seconds_per_request: u64,
+    /// HTTP workers (single-threaded runtimes), 0 for one per physical core
+    pub http_workers: usize,
+    /// Blocking thread pool size of each HTTP worker, 0 for actix's default
+    pub http_max_blocking_threads: usize,
+    /// Threads of the runtime running the long-running room tasks
+    pub room_tasks_threads: usize,
+    /// HMAC key of the identity and session tokens, random when unset
+    pub identity_secret: Option<String>,
+    /// Base64 AES-256 key of the Spotify tokens kept in memory, random when unset
+    pub tokens_encryption_key: Option<String>,
+    /// Limits of the WS messages sent by the clients, validated at startup
+    pub ws_transport: WsTransport,
+    /// JSON lines file of the feedback store, kept in memory only when unset
+    pub feedback_file: Option<String>,
+    pub log_format: LogFormat,
+    /// Roots of the Spotify Web API and accounts service, e.g. a caching proxy
+    pub spotify_base_urls: SpotifyBaseUrls,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    pub applied: Vec<&'static str>,
+    /// Changed settings that are only read at startup
+    pub requires_restart: Vec<&'static str>,
+}
+
+fn var<T: FromStr>(key: &str, default: T) -> T {
+    dotenvy::var(key)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+/// RFC 3339 date
+fn date_var(key: &str) -> Option<DateTime<Utc>> {
+    dotenvy::var(key)
+        .ok()
+        .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+        .map(|date| date.with_timezone(&Utc))
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            log_level: dotenvy::var("LOG").unwrap_or("debug".into()),
+            admin_token: dotenvy::var("ADMIN_TOKEN").ok().filter(|s| !s.is_empty()),
+            room_max_logs: var("ROOM_MAX_LOGS", DEFAULT_MAX_LOGS_LEN),
+            discord_webhook: dotenvy::var("DISCORD_WEBHOOK").ok(),
+            data_fetching_interval: Duration::from_millis(var("DATA_FETCHING_INTERVAL_MS", 5000)),
+            spotify_data_interval: Duration::from_millis(var(
+                "SPOTIFY_DATA_INTERVAL_MS",
+                1000 * 60 * 2,
+            )),
+            spotify_requests_per_window: var("SPOTIFY_REQUESTS_PER_WINDOW", 20),
+            spotify_cache_ttl: Duration::from_millis(var("SPOTIFY_CACHE_TTL_MS", 1000)),
+            sweeper_interval: Duration::from_millis(var("SWEEPER_INTERVAL_MS", 1000 * 60 * 5)),
+            api_deprecations: ApiVersion::ALL
+                .into_iter()
+                .filter_map(|version| {
+                    let prefix = format!("API_{}", version.as_str().to_uppercase());
+
+                    Some((
+                        version,
+                        Deprecation {
+                            deprecated_at: date_var(&format!("{prefix}_DEPRECATED_AT"))?,
+                            sunset_at: date_var(&format!("{prefix}_SUNSET_AT")),
+                        },
+                    ))
+                })
+                .collect(),
+            ws_command_limits: CommandCategory::ALL
+                .into_iter()
+                .map(|category| {
+                    let prefix = format!("WS_{}", category.as_str().to_uppercase());
+                    let default = category.default_limit();
+
+                    (
+                        category,
+                        CommandRateLimit {
+                            burst: var(&format!("{prefix}_BURST"), default.burst),
+                            per_min: var(&format!("{prefix}_PER_MIN"), default.per_min),
+                        },
+                    )
+                })
+                .collect(),
+            oauth_clients: IdentityProvider::ALL
+                .into_iter()
+                .filter_map(|provider| {
+                    let prefix = format!("OAUTH_{}", provider.as_str().to_uppercase());
+
+                    Some((
+                        provider,
+                        OAuthClient {
+                            client_id: dotenvy::var(format!("{prefix}_CLIENT_ID")).ok()?,
+                            client_secret: dotenvy::var(format!("{prefix}_CLIENT_SECRET")).ok()?,
+                        },
+                    ))
+                })
+                .collect(),
+            oauth_redirect_base_url: dotenvy::var("OAUTH_REDIRECT_BASE_URL").ok(),
+            oauth_frontend_url: dotenvy::var("OAUTH_FRONTEND_URL").ok(),
+            identity_required: dotenvy::var("IDENTITY_REQUIRED")
+                .map(|s| &s == "true")
+                .unwrap_or(false),
+            content_policy: ContentPolicy::parse(
+                &dotenvy::var("CONTENT_POLICY_DENIED_TYPES").unwrap_or_default(),
+                &dotenvy::var("CONTENT_POLICY_DENIED_MARKETS").unwrap_or_default(),
+            ),
+            ownerless_room_policy: var("OWNERLESS_ROOM_POLICY", OwnerlessRoomPolicy::default()),
+            spotify_client_id: dotenvy::var("SPOTIFY_CLIENT_ID")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            reconnect_grace_period: Duration::from_millis(var(
+                "RECONNECT_GRACE_PERIOD_MS",
+                DEFAULT_RECONNECT_GRACE_PERIOD.as_millis() as u64,
+            )),
+            spotify_outage_retry_backoff: Duration::from_millis(var(
+                "SPOTIFY_OUTAGE_RETRY_BACKOFF_MS",
+                DEFAULT_OUTAGE_RETRY_BACKOFF.as_millis() as u64,
+            )),
+            spotify_outage_max_retry_interval: Duration::from_millis(var(
+                "SPOTIFY_OUTAGE_MAX_RETRY_INTERVAL_MS",
+                DEFAULT_MAX_OUTAGE_RETRY_INTERVAL.as_millis() as u64,
+            )),
+            spotify_outage_max_duration: Duration::from_millis(var(
+                "SPOTIFY_OUTAGE_MAX_DURATION_MS",
+                DEFAULT_MAX_OUTAGE_DURATION.as_millis() as u64,
+            )),
+            trusted_proxy: var("TRUSTED_PROXY", false),
+            is_prod: dotenvy::var("IS_PROD")
+                .map(|s| &s == "true")
+                .unwrap_or(false),
+            host: var("HOST", Ipv4Addr::new(0, 0, 0, 0)),
+            port: var("PORT", 3100),
+            tls_private_key: dotenvy::var("TLS_PRIVATE_KEY").ok(),
+            tls_cert_key: dotenvy::var("TLS_CERT_KEY").ok(),
+            governor_burst_size: var("GOVERNOR_BURST_SIZE", 10),
+            governor_seconds_per_request: var("GOVERNOR_SECONDS_PER_REQUEST", 2),
+            http_workers: var("HTTP_WORKERS", 0),
+            http_max_blocking_threads: var("HTTP_MAX_BLOCKING_THREADS", 0),
+            room_tasks_threads: var("ROOM_TASKS_THREADS", 2),
+            identity_secret: dotenvy::var("IDENTITY_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            tokens_encryption_key: dotenvy::var("TOKENS_ENCRYPTION_KEY")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            ws_transport: {
+                let default = WsTransport::default();
+
+                WsTransport {
+                    max_frame_size: var("WS_MAX_FRAME_SIZE", default.max_frame_size),
+                    max_continuation_size: var(
+                        "WS_MAX_CONTINUATION_SIZE",
+                        default.max_continuation_size,
+                    ),
+                    aggregate_continuations: var(
+                        "WS_AGGREGATE_CONTINUATIONS",
+                        default.aggregate_continuations,
+                    ),
+                }
+            },
+            feedback_file: dotenvy::var("FEEDBACK_FILE").ok().filter(|s| !s.is_empty()),
+            log_format: var("LOG_FORMAT", LogFormat::default()),
+            spotify_base_urls: SpotifyBaseUrls::new(
+                dotenvy::var("SPOTIFY_API_URL").ok(),
+                dotenvy::var("SPOTIFY_ACCOUNTS_URL").ok(),
+            ),
+        }
+    }
+
+    fn diff(&self, new: &Self) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        macro_rules! check {
+            ($list:ident, $($field:ident),+) => {
+                $(if self.$field != new.$field {
+                    report.$list.push(stringify!($field));
+                })+
+            };
+        }
+
+        check!(
+            applied,
+            log_level,
+            admin_token,
+            room_max_logs,
+            discord_webhook,
+            data_fetching_interval,
+            spotify_data_interval,
+            spotify_requests_per_window,
+            spotify_cache_ttl,
+            sweeper_interval,
+            api_deprecations,
+            ws_command_limits,
+            oauth_clients,
+            oauth_redirect_base_url,
+            oauth_frontend_url,
+            identity_required,
+            content_policy,
+            ownerless_room_policy,
+            spotify_client_id,
+            reconnect_grace_period,
+            spotify_outage_retry_backoff,
+            spotify_outage_max_retry_interval,
+            spotify_outage_max_duration,
+            trusted_proxy
+        );
+        check!(
+            requires_restart,
+            is_prod,
+            host,
+            port,
+            tls_private_key,
+            tls_cert_key,
+            governor_burst_size,
+            governor_seconds_per_request,
+            http_workers,
+            http_max_blocking_threads,
+            room_tasks_threads,
+            identity_secret,
+            tokens_encryption_key,
+            ws_transport,
+            feedback_file,
+            log_format,
+            spotify_base_urls
+        );
+
+        report
+    }
+}
+
+pub fn get() -> Arc<Config> {
+    CONFIG.load_full()
+}
+
+/// Overrides settings of the env config, the tests share it since they run in the same process
+#[cfg(test)]
+pub fn set_for_tests(update: impl Fn(&mut Config)) {
+    CONFIG.rcu(|current| {
+        let mut new = Config::clone(current);
+        update(&mut new);
+        new
+    });
+}
+
+/// Re-reads the .env file (overriding the process env) and swaps the hot-reloadable settings
+///
+/// Settings requiring a restart keep their current value so the running config stays coherent
+pub fn reload() -> ReloadReport {
+    if let Err(err) = dotenvy::from_filename_override(ENV_FILE) {
+        warn!("Failed to re-read {ENV_FILE} file, using the process env only: {err}");
+    }
+
+    let current = get();
+    let mut new = Config::from_env();
+    let report = current.diff(&new);
+
+    new.is_prod = current.is_prod;
+    new.host = current.host;
+    new.port = current.port;
+    new.tls_private_key = current.tls_private_key.clone();
+    new.tls_cert_key = current.tls_cert_key.clone();
+    new.governor_burst_size = current.governor_burst_size;
+    new.governor_seconds_per_request = current.governor_seconds_per_request;
+    new.http_workers = current.http_workers;
+    new.http_max_blocking_threads = current.http_max_blocking_threads;
+    new.room_tasks_threads = current.room_tasks_threads;
+    new.ws_transport = current.ws_transport;
+    new.feedback_file = current.feedback_file.clone();
+    new.log_format = current.log_format;
+    new.spotify_base_urls = current.spotify_base_urls.clone();
+
+    telemetry::set_filter(&new.log_level);
+
+    CONFIG.store(Arc::new(new));
+
+    info!(
+        "Config reloaded, applied: {:?}, requires a restart: {:?}",
+        report.applied, report.requires_restart
+    );
+
+    report
+}
+
+/// Reloads the config on SIGHUP
+#[cfg(unix)]
+pub fn init_reload_on_sighup() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    actix_rt::spawn(async {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(err) => {
+                error!("Failed to listen to SIGHUP, config reload is disabled: {err}");
+                return;
+            }
+        };
+
+        while sighup.recv().await.is_some() {
+            reload();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn init_reload_on_sighup() {}
@@ -1,9 +1,34 @@
-use std::sync::LazyLock;
-use std::time::Duration;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use reqwest::{Client, ClientBuilder};
-use serde::Deserialize;
+use reqwest::{Client, ClientBuilder, StatusCode, header};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::sharify::room::RoomID;
+use crate::sharify::tasks::spawn_room_task;
+
+/// Webhooks waiting to be sent, the /webhook route answers once its submission is stored
+pub const WEBHOOK_QUEUE_CAPACITY: usize = 128;
+/// Attempts made after the first one for transient failures (network, 429, 5xx)
+pub const MAX_WEBHOOK_RETRIES: u32 = 3;
+/// Doubled on each retry, unless Discord sent a Retry-After header
+pub const WEBHOOK_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// A longer Retry-After is capped to this
+pub const MAX_WEBHOOK_RETRY_AFTER: Duration = Duration::from_secs(30);
+/// Webhooks each IP can send through the /webhook route per WEBHOOK_THROTTLE_WINDOW
+pub const MAX_WEBHOOKS_PER_IP: usize = 3;
+pub const WEBHOOK_THROTTLE_WINDOW: Duration = Duration::from_secs(60 * 10);
+/// The same webhook (type and content) sent again within this window is dropped
+pub const WEBHOOK_DEDUP_WINDOW: Duration = Duration::from_secs(60 * 10);
+/// Discord's limit of an embed description
+pub const MAX_WEBHOOK_CONTENT_LEN: usize = 4096;
+pub const MAX_WEBHOOK_USER_ID_LEN: usize = 64;
 
 static CLIENT: LazyLock<Client> = LazyLock::new(|| {
     ClientBuilder::new()
@@ -12,16 +37,27 @@ static CLIENT: LazyLock<Client> = LazyLock::new(|| {
         .expect("Failed to build HTTP Client")
 });
 
+static QUEUE: OnceLock<mpsc::Sender<Webhook>> = OnceLock::new();
+
+static THROTTLE: LazyLock<Mutex<WebhookThrottle>> = LazyLock::new(Mutex::default);
+
 #[derive(Deserialize)]
 pub struct SendWebhookPayload {
     pub wh_type: WebhookType,
     pub content: String,
+    /// Optional context shown in the embed
+    pub room_id: Option<RoomID>,
+    pub user_id: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum WebhookType {
     Feedback,
     BugReport,
+    /// Sent by the server, see RoomSettings.daily_digest
+    RoomDigest,
+    /// Sent by the server, see RoomSettings.session_summary_webhook
+    SessionSummary,
 }
 
 impl std::fmt::Display for WebhookType {
@@ -29,23 +65,233 @@ impl std::fmt::Display for WebhookType {
         f.write_str(match self {
             WebhookType::Feedback => "Feedback",
             WebhookType::BugReport => "Bug Report",
+            WebhookType::RoomDigest => "Room Daily Digest",
+            WebhookType::SessionSummary => "Room Session Summary",
         })
     }
 }
 
-pub async fn send_webhook(wh_type: WebhookType, content: String) -> Result<(), String> {
-    let webhook = dotenvy::var("DISCORD_WEBHOOK").expect("DISCORD_WEBOOK env var not found");
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    pub wh_type: WebhookType,
+    pub content: String,
+    pub room_id: Option<RoomID>,
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WebhookError {
+    /// DISCORD_WEBHOOK is unset
+    NotConfigured,
+    InvalidContent,
+    /// Only sent by the server
+    ForbiddenType,
+    Throttled,
+    Duplicate,
+    QueueFull,
+}
+
+impl From<WebhookError> for String {
+    fn from(err: WebhookError) -> Self {
+        match err {
+            WebhookError::NotConfigured => "DISCORD_WEBHOOK env var not found".into(),
+            WebhookError::InvalidContent => format!(
+                "The content must be 1 to {MAX_WEBHOOK_CONTENT_LEN} chars and the user ID at most {MAX_WEBHOOK_USER_ID_LEN}"
+            ),
+            WebhookError::ForbiddenType => "This webhook type is reserved to the server".into(),
+            WebhookError::Throttled => "Too many webhooks sent, retry later".into(),
+            WebhookError::Duplicate => "This webhook has already been sent".into(),
+            WebhookError::QueueFull => "Too many webhooks queued, retry later".into(),
+        }
+    }
+}
+
+impl TryFrom<SendWebhookPayload> for Webhook {
+    type Error = WebhookError;
+
+    /// Webhooks sent by the clients
+    fn try_from(payload: SendWebhookPayload) -> Result<Self, Self::Error> {
+        if matches!(
+            payload.wh_type,
+            WebhookType::RoomDigest | WebhookType::SessionSummary
+        ) {
+            return Err(WebhookError::ForbiddenType);
+        }
+
+        let content = payload.content.trim();
+
+        if content.is_empty()
+            || content.chars().count() > MAX_WEBHOOK_CONTENT_LEN
+            || payload
+                .user_id
+                .as_ref()
+                .is_some_and(|id| id.chars().count() > MAX_WEBHOOK_USER_ID_LEN)
+        {
+            return Err(WebhookError::InvalidContent);
+        }
+
+        Ok(Self {
+            wh_type: payload.wh_type,
+            content: content.to_owned(),
+            room_id: payload.room_id,
+            user_id: payload.user_id,
+        })
+    }
+}
+
+impl Webhook {
+    pub fn new(wh_type: WebhookType, content: String) -> Self {
+        Self {
+            wh_type,
+            content,
+            room_id: None,
+            user_id: None,
+        }
+    }
+
+    fn dedup_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+
+        self.wh_type.hash(&mut hasher);
+        self.content.hash(&mut hasher);
+
+        hasher.finish()
+    }
+}
+
+/// Per IP limit and deduplication of the webhooks sent by the clients
+#[derive(Debug, Default)]
+pub struct WebhookThrottle {
+    sent_by_ip: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When each webhook was last accepted, by dedup key
+    recent: HashMap<u64, Instant>,
+}
+
+impl WebhookThrottle {
+    pub fn admit(
+        &mut self,
+        ip: IpAddr,
+        webhook: &Webhook,
+        now: Instant,
+    ) -> Result<(), WebhookError> {
+        self.sent_by_ip.retain(|_, sent| {
+            while sent
+                .front()
+                .is_some_and(|at| now.saturating_duration_since(*at) >= WEBHOOK_THROTTLE_WINDOW)
+            {
+                sent.pop_front();
+            }
+
+            !sent.is_empty()
+        });
+        self.recent
+            .retain(|_, at| now.saturating_duration_since(*at) < WEBHOOK_DEDUP_WINDOW);
+
+        let sent = self.sent_by_ip.entry(ip).or_default();
+
+        if sent.len() >= MAX_WEBHOOKS_PER_IP {
+            return Err(WebhookError::Throttled);
+        }
+
+        // Counted against the IP anyway so resending it in a loop gets throttled
+        sent.push_back(now);
+
+        if self.recent.insert(webhook.dedup_key(), now).is_some() {
+            return Err(WebhookError::Duplicate);
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the webhook sent by a client through the /webhook route, see WebhookThrottle
+pub fn admit(ip: IpAddr, webhook: &Webhook, now: Instant) -> Result<(), WebhookError> {
+    THROTTLE.lock().unwrap().admit(ip, webhook, now)
+}
+
+pub fn enqueue(webhook: Webhook) -> Result<(), WebhookError> {
+    if crate::config::get().discord_webhook.is_none() {
+        return Err(WebhookError::NotConfigured);
+    }
+
+    let queue = QUEUE.get().ok_or(WebhookError::NotConfigured)?;
+
+    queue.try_send(webhook).map_err(|err| match err {
+        mpsc::error::TrySendError::Full(_) => WebhookError::QueueFull,
+        mpsc::error::TrySendError::Closed(_) => WebhookError::NotConfigured,
+    })
+}
+
+/// Sends the queued webhooks one at a time, with retries
+pub fn init_webhook_queue() {
+    let (tx, mut rx) = mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+
+    spawn_room_task(async move {
+        while let Some(webhook) = rx.recv().await {
+            if let Err(err) = send_with_retries(&webhook).await {
+                error!("Failed to send {} webhook: {err}", webhook.wh_type);
+            }
+        }
+    });
+}
+
+async fn send_with_retries(webhook: &Webhook) -> Result<(), String> {
+    let mut backoff = WEBHOOK_RETRY_BACKOFF;
+    let mut retries = 0;
+
+    loop {
+        match send_webhook(webhook).await {
+            Ok(()) => return Ok(()),
+            Err(SendError::Retryable { err, retry_after }) if retries < MAX_WEBHOOK_RETRIES => {
+                let delay = retry_after.unwrap_or(backoff).min(MAX_WEBHOOK_RETRY_AFTER);
+
+                debug!("Retrying webhook request in {delay:?} after error: {err}");
+
+                tokio::time::sleep(delay).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(SendError::Retryable { err, .. } | SendError::Fatal(err)) => return Err(err),
+        }
+    }
+}
+
+enum SendError {
+    /// Network error, 429 or 5xx
+    Retryable {
+        err: String,
+        retry_after: Option<Duration>,
+    },
+    Fatal(String),
+}
+
+async fn send_webhook(webhook: &Webhook) -> Result<(), SendError> {
+    let url = crate::config::get()
+        .discord_webhook
+        .clone()
+        .ok_or(SendError::Fatal("DISCORD_WEBHOOK env var not found".into()))?;
 
     let ts = chrono::Utc::now();
 
+    let mut fields = Vec::new();
+
+    if let Some(room_id) = webhook.room_id {
+        fields.push(json!({ "name": "Room", "value": room_id.to_string(), "inline": true }));
+    }
+
+    if let Some(ref user_id) = webhook.user_id {
+        fields.push(json!({ "name": "User", "value": user_id, "inline": true }));
+    }
+
     let payload = json!({
         "embeds": [{
-            // TODO To anonymize or not to ?
-            /* "author": {
-                "name": "username (email)"
-            }, */
-            "title": wh_type.to_string(),
-            "description": content,
+            "title": webhook.wh_type.to_string(),
+            "description": webhook.content,
+            "fields": fields,
             "timestamp": ts.to_rfc3339(),
             "color": 0x7437dd,
             "footer": {
@@ -55,21 +301,37 @@ pub async fn send_webhook(wh_type: WebhookType, content: String) -> Result<(), S
         }]
     });
 
-    let req = CLIENT
-        .post(webhook)
+    let res = CLIENT
+        .post(url)
         .header("Content-Type", "application/json")
         .json(&payload)
         .send()
         .await
-        .map_err(|err| format!("Failed to send webhook request: {err}"))?;
-
-    if !req.status().is_success() {
-        return Err(format!(
-            "Webhook request failed with status {} and response {:?}",
-            req.status(),
-            req.text().await
-        ));
+        .map_err(|err| SendError::Retryable {
+            err: format!("Failed to send webhook request: {err}"),
+            retry_after: None,
+        })?;
+
+    let status = res.status();
+
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let retry_after = res
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+    let err = format!(
+        "Webhook request failed with status {status} and response {:?}",
+        res.text().await
+    );
+
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        return Err(SendError::Retryable { err, retry_after });
     }
 
-    Ok(())
+    Err(SendError::Fatal(err))
 }
@@ -0,0 +1,177 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::discord::{Webhook, WebhookType};
+use crate::sharify::room::RoomID;
+
+/// Beyond it the oldest submissions are dropped, the acknowledged ones first
+pub const MAX_STORED_FEEDBACK: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackStatus {
+    New,
+    /// Handled by an operator
+    Acknowledged,
+}
+
+/// Feedback or bug report sent through the /webhook route
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Feedback {
+    pub id: Uuid,
+    pub received_at: DateTime<Utc>,
+    pub status: FeedbackStatus,
+    pub wh_type: WebhookType,
+    pub content: String,
+    pub room_id: Option<RoomID>,
+    pub user_id: Option<String>,
+}
+
+/// Submissions of the clients, kept as JSON lines in FEEDBACK_FILE (in memory only when unset)
+/// and listed by the /admin/v1/feedback routes. The Discord webhook only notifies of them
+///
+/// insert and acknowledge write the file, the routes call them through web::block
+#[derive(Debug, Default)]
+pub struct FeedbackStore {
+    path: Option<PathBuf>,
+    entries: Mutex<Vec<Feedback>>,
+}
+
+impl FeedbackStore {
+    /// Loads the stored submissions, the invalid lines are skipped
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut entries = Vec::new();
+
+        if let Some(ref path) = path {
+            match File::open(path) {
+                Ok(file) => {
+                    for (idx, line) in BufReader::new(file).lines().enumerate() {
+                        match serde_json::from_str(&line?) {
+                            Ok(feedback) => entries.push(feedback),
+                            Err(err) => {
+                                warn!("Skipped invalid line {} of {path:?}: {err}", idx + 1);
+                            }
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn insert(&self, webhook: &Webhook, now: DateTime<Utc>) -> io::Result<Feedback> {
+        let feedback = Feedback {
+            id: Uuid::now_v7(),
+            received_at: now,
+            status: FeedbackStatus::New,
+            wh_type: webhook.wh_type,
+            content: webhook.content.clone(),
+            room_id: webhook.room_id,
+            user_id: webhook.user_id.clone(),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() < MAX_STORED_FEEDBACK {
+            self.append(&feedback)?;
+            entries.push(feedback.clone());
+
+            return Ok(feedback);
+        }
+
+        let idx = entries
+            .iter()
+            .position(|feedback| feedback.status == FeedbackStatus::Acknowledged)
+            .unwrap_or(0);
+        let dropped = entries.remove(idx);
+
+        entries.push(feedback.clone());
+
+        if let Err(err) = self.rewrite(&entries) {
+            // Kept coherent with the file
+            entries.pop();
+            entries.insert(idx, dropped);
+
+            return Err(err);
+        }
+
+        Ok(feedback)
+    }
+
+    /// Newest first, filtered by status
+    pub fn list(&self, status: Option<FeedbackStatus>) -> Vec<Feedback> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|feedback| status.is_none_or(|status| feedback.status == status))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns false if not found
+    pub fn acknowledge(&self, id: Uuid) -> io::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let Some(feedback) = entries.iter_mut().find(|feedback| feedback.id == id) else {
+            return Ok(false);
+        };
+
+        if feedback.status == FeedbackStatus::Acknowledged {
+            return Ok(true);
+        }
+
+        feedback.status = FeedbackStatus::Acknowledged;
+
+        if let Err(err) = self.rewrite(&entries) {
+            // Kept coherent with the file
+            if let Some(feedback) = entries.iter_mut().find(|feedback| feedback.id == id) {
+                feedback.status = FeedbackStatus::New;
+            }
+
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+
+    fn append(&self, feedback: &Feedback) -> io::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        writeln!(file, "{}", serde_json::to_string(feedback)?)
+    }
+
+    /// Written to a temporary file first so a failure doesn't lose the stored submissions
+    fn rewrite(&self, entries: &[Feedback]) -> io::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+
+        for feedback in entries {
+            writeln!(file, "{}", serde_json::to_string(feedback)?)?;
+        }
+
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+}
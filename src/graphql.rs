@@ -0,0 +1,141 @@
+use std::sync::{Arc, LazyLock};
+
+use actix_web::web;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, ID, Object, Schema, SimpleObject};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::sharify::room::{Room, RoomLifecycle};
+use crate::sharify::room_manager::RoomManager;
+
+/// A query cannot select more fields than this, lists count once
+const MAX_COMPLEXITY: usize = 200;
+const MAX_DEPTH: usize = 5;
+
+/// Read-only: the state is only ever read, through its snapshots
+pub type AdminSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<AdminSchema> = LazyLock::new(|| {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_complexity(MAX_COMPLEXITY)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+});
+
+/// Served under the /admin/v1 scope so it's behind the ADMIN_TOKEN too
+pub async fn handler(
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    SCHEMA
+        .execute(req.into_inner().data(Arc::clone(sharify_state.get_ref())))
+        .await
+        .into()
+}
+
+#[derive(SimpleObject)]
+pub struct GqlTrack {
+    pub id: String,
+    pub name: String,
+    pub artist_name: String,
+    pub duration_ms: u64,
+}
+
+#[derive(SimpleObject)]
+pub struct GqlQueuedTrack {
+    pub id: String,
+    pub name: String,
+    pub duration_ms: u32,
+    /// Member who queued it
+    pub user_id: String,
+    /// Not in the Spotify queue yet (fair queue mode)
+    pub pending: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct GqlRoom {
+    pub id: ID,
+    pub name: String,
+    pub users_count: usize,
+    pub connected_users_count: usize,
+    pub max_users: usize,
+    pub is_public: bool,
+    pub is_closing: bool,
+    pub is_hibernating: bool,
+    pub current_track: Option<GqlTrack>,
+    pub queue: Vec<GqlQueuedTrack>,
+}
+
+impl From<&Room> for GqlRoom {
+    fn from(room: &Room) -> Self {
+        Self {
+            id: ID(room.id.to_string()),
+            name: room.name.clone(),
+            users_count: room.users.len(),
+            connected_users_count: room.users.iter().filter(|user| user.is_connected).count(),
+            max_users: room.settings.max_users,
+            is_public: room.settings.is_public,
+            is_closing: room.lifecycle == RoomLifecycle::Closing,
+            is_hibernating: room.hibernating,
+            current_track: room.now_playing.as_ref().map(|playback| GqlTrack {
+                id: playback.track_id.clone(),
+                name: playback.track_name.clone(),
+                artist_name: playback.artist_name.clone(),
+                duration_ms: playback.duration_ms,
+            }),
+            queue: room
+                .tracks_queue
+                .iter()
+                .enumerate()
+                .map(|(idx, track)| GqlQueuedTrack {
+                    id: track.track_id.clone(),
+                    name: track.track_name.clone(),
+                    duration_ms: track.track_duration,
+                    user_id: track.user_id.clone(),
+                    pending: idx >= room.pushed_tracks_len,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Every room, oldest first, the filters are optional
+    async fn rooms(
+        &self,
+        ctx: &Context<'_>,
+        min_users: Option<usize>,
+        min_connected_users: Option<usize>,
+        is_public: Option<bool>,
+    ) -> async_graphql::Result<Vec<GqlRoom>> {
+        let state_guard = ctx.data::<Arc<RwLock<RoomManager>>>()?.read().await;
+
+        let mut rooms = state_guard
+            .rooms()
+            .map(|room| GqlRoom::from(&*room))
+            .filter(|room| {
+                min_users.is_none_or(|min| room.users_count >= min)
+                    && min_connected_users.is_none_or(|min| room.connected_users_count >= min)
+                    && is_public.is_none_or(|is_public| room.is_public == is_public)
+            })
+            .collect::<Vec<_>>();
+
+        // UUIDv7, their string is sorted by creation date
+        rooms.sort_unstable_by(|a, b| a.id.0.cmp(&b.id.0));
+
+        Ok(rooms)
+    }
+
+    async fn room(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<Option<GqlRoom>> {
+        let room_id = Uuid::parse_str(&id)?;
+        let state_guard = ctx.data::<Arc<RwLock<RoomManager>>>()?.read().await;
+
+        Ok(state_guard
+            .get_room(&room_id)
+            .map(|room| GqlRoom::from(&*room)))
+    }
+}
@@ -1,109 +1,177 @@
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
+mod api;
+mod config;
 mod discord;
+mod feedback;
+#[cfg(feature = "graphql")]
+mod graphql;
 mod proto;
 mod routes;
 mod sharify;
+mod telemetry;
 
 #[cfg(test)]
 mod tests;
 
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::str::FromStr;
-use std::sync::{Arc, OnceLock};
-use std::time::Duration;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 use actix_cors::Cors;
 use actix_governor::{Governor, GovernorConfigBuilder};
 use actix_web::middleware;
 use actix_web::{App, HttpResponse, HttpServer, middleware::Logger, web};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use tokio::sync::{Mutex, RwLock, mpsc};
+use tokio::sync::RwLock;
 
-use sharify::room::RoomID;
+use api::ApiVersion;
+use feedback::FeedbackStore;
+use sharify::clock::{SharedClock, SystemClock};
+use sharify::random::system_random;
 use sharify::room_manager::RoomManager;
-use sharify::websocket::{self, SharifyWsManager};
-
-const DEFAULT_SOCKET_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(0, 0, 0, 0), 3100);
-
-// static REFRESH_TOKEN_INTERVALS: OnceLock<Arc<Mutex<HashMap<RoomID, SpawnHandle>>>> =
-//     OnceLock::new();
-static DATA_FETCHING_INTERVALS: OnceLock<Arc<Mutex<HashMap<RoomID, mpsc::Sender<()>>>>> =
-    OnceLock::new();
-
-pub const DATA_FETCHING_INTERVAL: Duration = Duration::from_millis(5000);
+use sharify::spotify::SpotifyBaseUrls;
+use sharify::websocket::SharifyWsManager;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().expect("failed to load .env file");
 
-    env_logger::init_from_env(env_logger::Env::new().filter_or("LOG", "debug"));
+    let config = config::get();
 
-    let is_prod = dotenvy::var("IS_PROD")
-        .map(|s| &s == "true")
-        .unwrap_or(false);
+    telemetry::init(&config.log_level, config.log_format);
 
-    serve(is_prod).await
+    serve(
+        config.is_prod,
+        Arc::new(SystemClock),
+        config.port,
+        config.spotify_base_urls.clone(),
+    )
+    .await
 }
 
-// Needed to be ran in tests
-async fn serve(is_prod: bool) -> std::io::Result<()> {
+// Needed to be ran in tests, which can drive the time with a MockClock, need a port per server
+// since they run concurrently and talk to their own mock Spotify
+async fn serve(
+    is_prod: bool,
+    clock: SharedClock,
+    port: u16,
+    spotify_base_urls: SpotifyBaseUrls,
+) -> std::io::Result<()> {
     let sharify_ws_manager = Arc::new(RwLock::new(SharifyWsManager::default()));
-    let sharify_state = Arc::new(RwLock::new(RoomManager::default()));
+    let sharify_state = Arc::new(RwLock::new(
+        RoomManager::new(clock, system_random()).with_spotify_base_urls(spotify_base_urls),
+    ));
+    let config = config::get();
+    let feedback_store = Arc::new(
+        FeedbackStore::open(config.feedback_file.as_ref().map(Into::into))
+            .expect("Failed to open the feedback store"),
+    );
+
+    if let Err(err) = config.ws_transport.validate() {
+        panic!("Invalid WS transport config: {err}");
+    }
+
+    config::init_reload_on_sighup();
+    sharify::sweeper::init_integrity_sweeper(
+        Arc::clone(&sharify_ws_manager),
+        Arc::clone(&sharify_state),
+    );
+    discord::init_webhook_queue();
+    sharify::digest::init_daily_digest_scheduler(Arc::clone(&sharify_state));
+    sharify::schedule::init_scheduled_rooms_opener(Arc::clone(&sharify_state));
+    sharify::room_events::init_event_log(sharify_state.read().await.subscribe_events());
+    sharify::websocket::events::init_stream_cleanup(
+        sharify_state.read().await.subscribe_events(),
+    );
+    sharify::websocket::init_role_change_notifications(
+        sharify_state.read().await.subscribe_events(),
+        Arc::clone(&sharify_ws_manager),
+    );
+    sharify::waitlist::init_waitlist_admissions(
+        sharify_state.read().await.subscribe_events(),
+        Arc::clone(&sharify_state),
+    );
 
     // TODO: If behind a (reverse) proxy, change the key extractor because the peer IP will be the same
     // https://docs.rs/actix-governor/latest/actix_governor/struct.PeerIpKeyExtractor.html
     // https://docs.nginx.com/nginx/admin-guide/web-server/reverse-proxy/#passing-request-headers
     let governor_conf = GovernorConfigBuilder::default()
-        .burst_size(10)
-        .seconds_per_request(2)
+        .burst_size(config.governor_burst_size)
+        .seconds_per_request(config.governor_seconds_per_request)
         .finish()
         .expect("Failed to build governor (rate limiter)");
 
-    let socket = (
-        IpAddr::from(
-            Ipv4Addr::from_str(&dotenvy::var("HOST").unwrap_or("".to_owned()))
-                .unwrap_or(DEFAULT_SOCKET_ADDR.0),
-        ),
-        dotenvy::var("PORT")
-            .map(|s| s.parse().expect("Failed to parse PORT env to valid u16"))
-            .unwrap_or(DEFAULT_SOCKET_ADDR.1),
-    );
+    let socket = (IpAddr::from(config.host), port);
 
     let server = HttpServer::new(move || {
         App::new()
+            // Inner to the Logger so it logs the response header
+            .wrap(middleware::from_fn(routes::correlation_id))
             .wrap(
-                Logger::new("%a/%{r}a %r status %s %Dms")
-                    .exclude_regex("(/v1/[a-f0-9]{8}-.*|/v1/code.*)"),
+                Logger::new("%a/%{r}a %r status %s %Dms %{x-correlation-id}o")
+                    .exclude_regex("(/v[0-9]+/[a-f0-9]{8}-.*|/v[0-9]+/code.*|/healthz|/readyz)"),
             )
             .wrap(Cors::permissive()) // TODO prod: Change this
             .wrap(middleware::Compress::default())
             .wrap(Governor::new(&governor_conf))
             .app_data(web::Data::new(Arc::clone(&sharify_ws_manager)))
             .app_data(web::Data::new(Arc::clone(&sharify_state)))
+            .app_data(web::Data::new(Arc::clone(&feedback_store)))
             .default_service(web::to(HttpResponse::NotFound))
             .service(routes::root)
-            .service(routes::proto_command)
-            .service(routes::code_verifier)
-            .service(routes::code_challenge)
-            .service(routes::send_discord_webhook)
+            .service(routes::healthz)
+            .service(routes::readyz)
+            .service(routes::versions)
+            .service(routes::reload_config)
+            .service(routes::sweeper_metrics)
             .service(
-                web::resource("/v1/{room_id}/{user_id}")
-                    .route(web::get().to(websocket::SharifyWsInstance::init)),
+                web::scope("/admin/v1")
+                    .wrap(middleware::from_fn(routes::require_admin_token))
+                    .configure(routes::configure_admin),
             )
+            .service(
+                web::scope("/spectate/{room_id}")
+                    .wrap(middleware::from_fn(routes::verify_signed_url))
+                    .service(routes::spectate_now_playing)
+                    .service(routes::spectate_events),
+            )
+            .configure(|cfg| {
+                for version in ApiVersion::ALL {
+                    cfg.service(
+                        web::scope(version.prefix())
+                            .app_data(version)
+                            .wrap(middleware::from_fn(routes::api_deprecation))
+                            .configure(routes::configure_versioned),
+                    );
+                }
+            })
     });
 
+    let server = match config.http_workers {
+        0 => server,
+        workers => server.workers(workers),
+    };
+    let server = match config.http_max_blocking_threads {
+        0 => server,
+        threads => server.worker_max_blocking_threads(threads),
+    };
+
     match is_prod {
         true => {
-            let key_path = dotenvy::var("TLS_PRIVATE_KEY").expect("TLS_PRIVATE_KEY env not found");
-            let cert_path = dotenvy::var("TLS_CERT_KEY").expect("TLS_CERT_KEY env not found");
+            let key_path = config
+                .tls_private_key
+                .as_ref()
+                .expect("TLS_PRIVATE_KEY env not found");
+            let cert_path = config
+                .tls_cert_key
+                .as_ref()
+                .expect("TLS_CERT_KEY env not found");
 
             let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
 
-            builder.set_private_key_file(&key_path, SslFiletype::PEM)?;
-            builder.set_certificate_chain_file(&cert_path)?;
+            builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+            builder.set_certificate_chain_file(cert_path)?;
 
             server.bind_openssl(socket, builder)?.run().await?;
         }
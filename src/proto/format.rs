@@ -0,0 +1,65 @@
+use actix_web::web::Bytes;
+use prost::Message;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Encoding of the commands, JSON (the same messages serialized with serde) is a fallback for
+/// the clients that can't use protobuf
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+impl WireFormat {
+    /// fmt query param of the WS URL
+    pub fn from_query(fmt: Option<&str>) -> Self {
+        match fmt {
+            Some("json") => Self::Json,
+            _ => Self::Protobuf,
+        }
+    }
+
+    /// Content-Type of the HTTP commands
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.starts_with("application/json") => Self::Json,
+            _ => Self::Protobuf,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Protobuf => "application/x-protobuf",
+            Self::Json => "application/json",
+        }
+    }
+
+    pub fn decode<M: Message + Default + DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<M, String> {
+        match self {
+            Self::Protobuf => M::decode(bytes).map_err(|err| err.to_string()),
+            Self::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn encode<M: Message + Serialize>(&self, msg: &M) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Protobuf => Ok(msg.encode_to_vec()),
+            Self::Json => serde_json::to_vec(msg).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Messages broadcasted in a room are encoded once in protobuf then converted for the JSON
+    /// sessions
+    pub fn protobuf_to_json<M: Message + Default + Serialize>(
+        buf: Bytes,
+    ) -> Result<String, String> {
+        let msg = M::decode(buf).map_err(|err| err.to_string())?;
+
+        serde_json::to_string(&msg).map_err(|err| err.to_string())
+    }
+}
@@ -0,0 +1,313 @@
+use actix_ws::{CloseCode, CloseReason};
+use chrono::DateTime;
+
+use crate::proto;
+use crate::proto::cmd::command_response::{self, content_denied};
+use crate::sharify::content_policy::ContentPolicyError;
+use crate::sharify::digest::SessionSummary;
+use crate::sharify::room::{
+    ExplicitTrackDenied, ExportSource, LogFilter, LogType, QueuePosition, TrackTooLong,
+    VolumeOutOfRange,
+};
+use crate::sharify::room_metadata::{ListeningStats, SeekVoteStatus};
+use crate::sharify::schedule::ScheduledRoomInfo;
+use crate::sharify::websocket::WsTransport;
+use crate::sharify::websocket::commands::CommandCategory;
+
+/// Control frame payloads are capped at 125 bytes, 2 of them being the close code
+const MAX_CLOSE_DESCRIPTION_LEN: usize = 123;
+
+impl proto::cmd::WsCloseCode {
+    /// Close frame with the application code and description, truncated on a char boundary
+    /// when it doesn't fit the frame (e.g. a long Kick reason)
+    pub fn reason(self, description: impl Into<String>) -> CloseReason {
+        let mut description = description.into();
+
+        description.truncate(description.floor_char_boundary(MAX_CLOSE_DESCRIPTION_LEN));
+
+        CloseReason {
+            code: self.into(),
+            description: Some(description),
+        }
+    }
+}
+
+impl From<proto::cmd::WsCloseCode> for CloseCode {
+    fn from(code: proto::cmd::WsCloseCode) -> Self {
+        Self::from(code as i32 as u16)
+    }
+}
+
+impl From<CommandCategory> for i32 {
+    fn from(category: CommandCategory) -> Self {
+        proto::cmd::CommandCategory::from(category).into()
+    }
+}
+
+impl From<CommandCategory> for proto::cmd::CommandCategory {
+    fn from(category: CommandCategory) -> Self {
+        match category {
+            CommandCategory::Search => Self::Search,
+            CommandCategory::Playback => Self::Playback,
+            CommandCategory::Queue => Self::Queue,
+            CommandCategory::Other => Self::Other,
+        }
+    }
+}
+
+impl proto::cmd::CommandResponse {
+    /// Responses sent as one frame, the Batch is skipped when there's a single one
+    pub fn batch(mut responses: Vec<Self>) -> Option<Self> {
+        match responses.len() {
+            0 | 1 => responses.pop(),
+            _ => Some(Self {
+                r#type: Some(command_response::Type::Batch(command_response::Batch {
+                    responses,
+                })),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+impl command_response::RoomDelta {
+    /// None when the rooms are the same (regardless of their seq), appended_logs is how many
+    /// logs were pushed since the previous room
+    pub fn between(
+        previous: &proto::room::Room,
+        current: &proto::room::Room,
+        seq: u64,
+        appended_logs: usize,
+    ) -> Option<Self> {
+        let upserted_users = current
+            .users
+            .iter()
+            .filter(|user| !previous.users.contains(user))
+            .cloned()
+            .collect::<Vec<_>>();
+        let removed_users = previous
+            .users
+            .iter()
+            .filter(|user| !current.users.iter().any(|current| current.id == user.id))
+            .map(|user| user.id.clone())
+            .collect::<Vec<_>>();
+        let tracks_queue = (current.tracks_queue != previous.tracks_queue).then(|| {
+            command_response::room_delta::TracksQueue {
+                tracks: current.tracks_queue.clone(),
+            }
+        });
+        // The embedded logs are the most recent ones, identical logs pushed in a row included
+        let appended_logs =
+            current.logs[current.logs.len().saturating_sub(appended_logs)..].to_vec();
+        let without_deltas = |room: &proto::room::Room| proto::room::Room {
+            users: Vec::new(),
+            tracks_queue: Vec::new(),
+            logs: Vec::new(),
+            logs_total: 0,
+            seq: 0,
+            ..room.clone()
+        };
+        let room = without_deltas(current);
+        let room = (room != without_deltas(previous)).then_some(room);
+
+        if upserted_users.is_empty()
+            && removed_users.is_empty()
+            && tracks_queue.is_none()
+            && appended_logs.is_empty()
+            && current.logs_total == previous.logs_total
+            && room.is_none()
+        {
+            return None;
+        }
+
+        Some(Self {
+            seq,
+            upserted_users,
+            removed_users,
+            tracks_queue,
+            appended_logs,
+            logs_total: current.logs_total,
+            room,
+        })
+    }
+}
+
+impl From<WsTransport> for command_response::WsTransport {
+    fn from(transport: WsTransport) -> Self {
+        Self {
+            max_frame_size: transport.max_frame_size as _,
+            max_continuation_size: transport.max_continuation_size as _,
+            aggregate_continuations: transport.aggregate_continuations,
+        }
+    }
+}
+
+impl From<ContentPolicyError> for command_response::Type {
+    fn from(err: ContentPolicyError) -> Self {
+        let reason = match err {
+            ContentPolicyError::TypeDenied(content_type) => content_denied::Reason::ContentType(
+                proto::spotify::ContentType::from(content_type).into(),
+            ),
+            ContentPolicyError::MarketDenied(market) => content_denied::Reason::Market(market),
+        };
+
+        Self::ContentDenied(command_response::ContentDenied {
+            reason: Some(reason),
+        })
+    }
+}
+
+impl From<proto::cmd::ExportSource> for ExportSource {
+    fn from(source: proto::cmd::ExportSource) -> Self {
+        match source {
+            proto::cmd::ExportSource::History => Self::History,
+            proto::cmd::ExportSource::Queue => Self::Queue,
+        }
+    }
+}
+
+impl From<TrackTooLong> for command_response::Type {
+    fn from(err: TrackTooLong) -> Self {
+        Self::TrackTooLong(command_response::TrackTooLong {
+            track_id: err.track_id,
+            track_duration_ms: err
+                .track_duration
+                .as_millis()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            max_track_duration_secs: err.max_track_duration.as_secs() as _,
+        })
+    }
+}
+
+impl From<VolumeOutOfRange> for command_response::Type {
+    fn from(err: VolumeOutOfRange) -> Self {
+        Self::VolumeOutOfRange(command_response::VolumeOutOfRange {
+            volume: err.volume,
+            volume_limits: Some(err.volume_limits.into()),
+        })
+    }
+}
+
+impl From<SeekVoteStatus> for command_response::SeekVote {
+    fn from(status: SeekVoteStatus) -> Self {
+        Self {
+            track_id: status.track_id,
+            offset_ms: status.offset_ms,
+            votes: status.votes as _,
+            required_votes: status.required_votes as _,
+            passed: status.seek_to_ms.is_some(),
+        }
+    }
+}
+
+impl From<ExplicitTrackDenied> for command_response::Type {
+    fn from(err: ExplicitTrackDenied) -> Self {
+        Self::ExplicitTrackDenied(command_response::ExplicitTrackDenied {
+            track_id: err.track_id,
+        })
+    }
+}
+
+impl From<QueuePosition> for command_response::YourTrackPosition {
+    fn from(position: QueuePosition) -> Self {
+        Self {
+            track_id: position.track_id,
+            position: position.position,
+            eta_ms: position.eta.map(|eta| eta.as_millis() as _),
+        }
+    }
+}
+
+impl From<ScheduledRoomInfo> for command_response::ScheduledRoom {
+    fn from(info: ScheduledRoomInfo) -> Self {
+        Self {
+            id: info.id.into_bytes().into(),
+            name: info.name,
+            opens_at: Some(proto::Timestamp {
+                seconds: info.opens_at.timestamp(),
+                nanos: info.opens_at.timestamp_subsec_nanos() as _,
+            }),
+            ticket: info.ticket.into_bytes().into(),
+            registered_users: info.registered_users as _,
+        }
+    }
+}
+
+impl From<&proto::cmd::command::GetLogs> for LogFilter {
+    fn from(opts: &proto::cmd::command::GetLogs) -> Self {
+        let to_date = |ts: &proto::Timestamp| DateTime::from_timestamp(ts.seconds, ts.nanos as _);
+
+        Self {
+            types: opts
+                .types()
+                .map(|r#type| LogType::from(r#type as i32))
+                .collect(),
+            since: opts.since.as_ref().and_then(to_date),
+            until: opts.until.as_ref().and_then(to_date),
+        }
+    }
+}
+
+impl From<&ListeningStats> for command_response::ListeningStats {
+    fn from(stats: &ListeningStats) -> Self {
+        let mut users = stats
+            .tracks_queued
+            .keys()
+            .chain(stats.skips.keys())
+            .collect::<std::collections::HashSet<_>>()
+            .into_iter()
+            .map(|user_id| command_response::listening_stats::UserStats {
+                user_id: user_id.clone(),
+                tracks_queued: stats
+                    .tracks_queued
+                    .get(user_id)
+                    .copied()
+                    .unwrap_or_default(),
+                skips: stats.skips.get(user_id).copied().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+        users.sort_unstable_by(|a, b| {
+            b.tracks_queued
+                .cmp(&a.tracks_queued)
+                .then_with(|| a.user_id.cmp(&b.user_id))
+        });
+
+        Self {
+            tracks_played: stats.tracks_played,
+            listening_time_secs: stats
+                .listening_time
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            users,
+            top_artists: stats
+                .top_artists()
+                .into_iter()
+                .map(
+                    |(artist_name, plays)| command_response::listening_stats::ArtistPlays {
+                        artist_name: artist_name.to_owned(),
+                        plays,
+                    },
+                )
+                .collect(),
+        }
+    }
+}
+
+impl From<SessionSummary> for command_response::SessionSummary {
+    fn from(summary: SessionSummary) -> Self {
+        Self {
+            duration_secs: summary.duration.as_secs().try_into().unwrap_or(u32::MAX),
+            tracks_played: summary.tracks_played,
+            top_contributor: summary.top_contributor.map(|top| {
+                command_response::session_summary::TopContributor {
+                    user_id: top.user_id,
+                    username: top.username.unwrap_or_default(),
+                    tracks_queued: top.tracks_queued,
+                }
+            }),
+        }
+    }
+}
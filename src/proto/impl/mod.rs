@@ -1,3 +1,4 @@
+pub mod cmd;
 pub mod role;
 pub mod room;
 pub mod spotify;
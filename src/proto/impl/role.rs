@@ -1,12 +1,19 @@
+use std::time::Duration;
+
 use uuid::Uuid;
 
 use crate::proto;
+use crate::proto::cmd::command_response::{self, content_denied};
+use crate::sharify::content_policy::ContentType;
 use crate::sharify::role;
 
 impl From<role::RoleError> for i32 {
     fn from(err: role::RoleError) -> Self {
         match err {
             role::RoleError::NameAlreadyExists => 0,
+            role::RoleError::InvalidColor => 1,
+            role::RoleError::InvalidIconID => 2,
+            role::RoleError::DescriptionTooLong => 3,
         }
     }
 }
@@ -17,10 +24,28 @@ impl From<role::RoleError> for proto::cmd::command_response::Type {
     }
 }
 
+impl From<role::RoleContentError> for proto::cmd::command_response::Type {
+    fn from(err: role::RoleContentError) -> Self {
+        match err {
+            role::RoleContentError::TrackTooLong(err) => err.into(),
+            role::RoleContentError::EpisodeDenied => {
+                Self::ContentDenied(command_response::ContentDenied {
+                    reason: Some(content_denied::Reason::ContentType(
+                        proto::spotify::ContentType::from(ContentType::Episode).into(),
+                    )),
+                })
+            }
+        }
+    }
+}
+
 impl From<proto::role::RoleError> for role::RoleError {
     fn from(err: proto::role::RoleError) -> Self {
         match err {
             proto::role::RoleError::NameAlreadyExists => Self::NameAlreadyExists,
+            proto::role::RoleError::InvalidColor => Self::InvalidColor,
+            proto::role::RoleError::InvalidIconId => Self::InvalidIconID,
+            proto::role::RoleError::DescriptionTooLong => Self::DescriptionTooLong,
         }
     }
 }
@@ -29,6 +54,9 @@ impl From<role::RoleError> for proto::role::RoleError {
     fn from(err: role::RoleError) -> Self {
         match err {
             role::RoleError::NameAlreadyExists => Self::NameAlreadyExists,
+            role::RoleError::InvalidColor => Self::InvalidColor,
+            role::RoleError::InvalidIconID => Self::InvalidIconId,
+            role::RoleError::DescriptionTooLong => Self::DescriptionTooLong,
         }
     }
 }
@@ -57,12 +85,56 @@ impl From<role::RolePermission> for proto::role::RolePermission {
     }
 }
 
+impl From<proto::role::RoleDisplay> for role::RoleDisplay {
+    fn from(display: proto::role::RoleDisplay) -> Self {
+        Self {
+            color: display.color,
+            icon_id: display.icon_id,
+            description: display.description,
+        }
+    }
+}
+
+impl From<role::RoleDisplay> for proto::role::RoleDisplay {
+    fn from(display: role::RoleDisplay) -> Self {
+        Self {
+            color: display.color,
+            icon_id: display.icon_id,
+            description: display.description,
+        }
+    }
+}
+
+impl From<proto::role::RoleContent> for role::RoleContent {
+    fn from(content: proto::role::RoleContent) -> Self {
+        Self {
+            max_track_duration: (content.max_track_duration_secs > 0)
+                .then(|| Duration::from_secs(content.max_track_duration_secs as _)),
+            deny_episodes: content.deny_episodes,
+        }
+    }
+}
+
+impl From<role::RoleContent> for proto::role::RoleContent {
+    fn from(content: role::RoleContent) -> Self {
+        Self {
+            max_track_duration_secs: content
+                .max_track_duration
+                .map(|max_track_duration| max_track_duration.as_secs() as _)
+                .unwrap_or_default(),
+            deny_episodes: content.deny_episodes,
+        }
+    }
+}
+
 impl From<proto::role::Role> for role::Role {
     fn from(role: proto::role::Role) -> Self {
         Self {
             id: Uuid::from_slice(&role.id[..16]).unwrap(),
             name: role.name,
             permissions: role.permissions.map(Into::into).unwrap(),
+            display: role.display.map(Into::into).unwrap_or_default(),
+            content: role.content.map(Into::into).unwrap_or_default(),
         }
     }
 }
@@ -73,6 +145,8 @@ impl From<role::Role> for proto::role::Role {
             id: role.id.into_bytes().into(),
             name: role.name,
             permissions: Some(role.permissions.into()),
+            display: Some(role.display.into()),
+            content: Some(role.content.into()),
         }
     }
 }
@@ -1,7 +1,11 @@
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
 use uuid::Uuid;
 
 use crate::proto;
 use crate::sharify::room;
+use crate::sharify::room_metadata::{DjRotation, ParkedPlayback};
 
 impl From<room::LogType> for i32 {
     fn from(log: room::LogType) -> Self {
@@ -13,6 +17,9 @@ impl From<room::LogType> for i32 {
             room::LogType::JoinRoom => 4,
             room::LogType::LeaveRoom => 5,
             room::LogType::UsernameChange => 6,
+            room::LogType::RoleChange => 7,
+            room::LogType::Playback => 8,
+            room::LogType::RemoveTrack => 9,
         }
     }
 }
@@ -27,6 +34,9 @@ impl From<i32> for room::LogType {
             4 => Self::JoinRoom,
             5 => Self::LeaveRoom,
             6 => Self::UsernameChange,
+            7 => Self::RoleChange,
+            8 => Self::Playback,
+            9 => Self::RemoveTrack,
             _ => unreachable!(),
         }
     }
@@ -37,6 +47,11 @@ impl From<proto::room::Log> for room::Log {
         Self {
             r#type: log.r#type.into(),
             details: log.details,
+            author_id: Some(log.author_id).filter(|id| !id.is_empty()),
+            created_at: log
+                .created_at
+                .and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos as _))
+                .unwrap_or_default(),
         }
     }
 }
@@ -46,6 +61,11 @@ impl From<room::Log> for proto::room::Log {
         Self {
             r#type: log.r#type.into(),
             details: log.details,
+            author_id: log.author_id.unwrap_or_default(),
+            created_at: Some(proto::Timestamp {
+                seconds: log.created_at.timestamp(),
+                nanos: log.created_at.timestamp_subsec_nanos() as _,
+            }),
         }
     }
 }
@@ -63,6 +83,22 @@ impl From<room::RoomError> for i32 {
             room::RoomError::UserBanned => 7,
             room::RoomError::UserIDExists => 8,
             room::RoomError::Unreachable => 9,
+            room::RoomError::QueueEditExpired => 10,
+            room::RoomError::PlaybackAlreadyParked => 11,
+            room::RoomError::PlaybackNotParked => 12,
+            room::RoomError::QueueFull => 13,
+            room::RoomError::InvalidSettings => 14,
+            room::RoomError::DjRotationNotStarted => 15,
+            room::RoomError::RoomClosing => 16,
+            room::RoomError::InvalidSpotifyGrant => 17,
+            room::RoomError::InvalidProfile => 18,
+            room::RoomError::InvalidUsername => 19,
+            room::RoomError::UsernameTaken => 20,
+            room::RoomError::NotWaitlisted => 21,
+            room::RoomError::ScheduledRoomNotFound => 22,
+            room::RoomError::InvalidSchedule => 23,
+            room::RoomError::InvalidSeekOffset => 24,
+            room::RoomError::SeekVoteRunning => 25,
         }
     }
 }
@@ -80,6 +116,22 @@ impl From<i32> for room::RoomError {
             7 => room::RoomError::UserBanned,
             8 => room::RoomError::UserIDExists,
             9 => room::RoomError::Unreachable,
+            10 => room::RoomError::QueueEditExpired,
+            11 => room::RoomError::PlaybackAlreadyParked,
+            12 => room::RoomError::PlaybackNotParked,
+            13 => room::RoomError::QueueFull,
+            14 => room::RoomError::InvalidSettings,
+            15 => room::RoomError::DjRotationNotStarted,
+            16 => room::RoomError::RoomClosing,
+            17 => room::RoomError::InvalidSpotifyGrant,
+            18 => room::RoomError::InvalidProfile,
+            19 => room::RoomError::InvalidUsername,
+            20 => room::RoomError::UsernameTaken,
+            21 => room::RoomError::NotWaitlisted,
+            22 => room::RoomError::ScheduledRoomNotFound,
+            23 => room::RoomError::InvalidSchedule,
+            24 => room::RoomError::InvalidSeekOffset,
+            25 => room::RoomError::SeekVoteRunning,
             _ => unreachable!(),
         }
     }
@@ -98,6 +150,22 @@ impl From<room::RoomError> for proto::room::RoomError {
             room::RoomError::UserBanned => Self::UserBanned,
             room::RoomError::UserIDExists => Self::UserIdExists,
             room::RoomError::Unreachable => Self::Unreachable,
+            room::RoomError::QueueEditExpired => Self::QueueEditExpired,
+            room::RoomError::PlaybackAlreadyParked => Self::PlaybackAlreadyParked,
+            room::RoomError::PlaybackNotParked => Self::PlaybackNotParked,
+            room::RoomError::QueueFull => Self::QueueFull,
+            room::RoomError::InvalidSettings => Self::InvalidSettings,
+            room::RoomError::DjRotationNotStarted => Self::DjRotationNotStarted,
+            room::RoomError::RoomClosing => Self::RoomClosing,
+            room::RoomError::InvalidSpotifyGrant => Self::InvalidSpotifyGrant,
+            room::RoomError::InvalidProfile => Self::InvalidProfile,
+            room::RoomError::InvalidUsername => Self::InvalidUsername,
+            room::RoomError::UsernameTaken => Self::UsernameTaken,
+            room::RoomError::NotWaitlisted => Self::NotWaitlisted,
+            room::RoomError::ScheduledRoomNotFound => Self::ScheduledRoomNotFound,
+            room::RoomError::InvalidSchedule => Self::InvalidSchedule,
+            room::RoomError::InvalidSeekOffset => Self::InvalidSeekOffset,
+            room::RoomError::SeekVoteRunning => Self::SeekVoteRunning,
         }
     }
 }
@@ -115,6 +183,22 @@ impl From<proto::room::RoomError> for room::RoomError {
             proto::room::RoomError::UserBanned => Self::UserBanned,
             proto::room::RoomError::UserIdExists => Self::UserIDExists,
             proto::room::RoomError::Unreachable => Self::Unreachable,
+            proto::room::RoomError::QueueEditExpired => Self::QueueEditExpired,
+            proto::room::RoomError::PlaybackAlreadyParked => Self::PlaybackAlreadyParked,
+            proto::room::RoomError::PlaybackNotParked => Self::PlaybackNotParked,
+            proto::room::RoomError::QueueFull => Self::QueueFull,
+            proto::room::RoomError::InvalidSettings => Self::InvalidSettings,
+            proto::room::RoomError::DjRotationNotStarted => Self::DjRotationNotStarted,
+            proto::room::RoomError::RoomClosing => Self::RoomClosing,
+            proto::room::RoomError::InvalidSpotifyGrant => Self::InvalidSpotifyGrant,
+            proto::room::RoomError::InvalidProfile => Self::InvalidProfile,
+            proto::room::RoomError::InvalidUsername => Self::InvalidUsername,
+            proto::room::RoomError::UsernameTaken => Self::UsernameTaken,
+            proto::room::RoomError::NotWaitlisted => Self::NotWaitlisted,
+            proto::room::RoomError::ScheduledRoomNotFound => Self::ScheduledRoomNotFound,
+            proto::room::RoomError::InvalidSchedule => Self::InvalidSchedule,
+            proto::room::RoomError::InvalidSeekOffset => Self::InvalidSeekOffset,
+            proto::room::RoomError::SeekVoteRunning => Self::SeekVoteRunning,
         }
     }
 }
@@ -129,6 +213,23 @@ impl From<room::RoomError> for proto::cmd::CommandResponse {
     fn from(err: room::RoomError) -> Self {
         Self {
             r#type: Some(err.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<room::ModerationResults> for proto::cmd::command_response::ModerationResults {
+    fn from(results: room::ModerationResults) -> Self {
+        Self {
+            results: results
+                .into_iter()
+                .map(
+                    |(user_id, result)| proto::cmd::command_response::ModerationResult {
+                        user_id,
+                        error: result.err().map(Into::into),
+                    },
+                )
+                .collect(),
         }
     }
 }
@@ -140,6 +241,8 @@ impl From<proto::room::RoomTrack> for room::RoomTrack {
             track_id: track.track_id,
             track_name: track.track_name,
             track_duration: track.track_duration,
+            item_type: Default::default(),
+            added_at: Instant::now(),
         }
     }
 }
@@ -162,6 +265,25 @@ impl From<proto::room::RoomUser> for room::RoomUser {
             username: user.username,
             role_id: Uuid::from_slice(&user.role_id[..16]).unwrap(),
             is_connected: user.is_connected,
+            profile: user.profile.map(Into::into).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<proto::room::UserProfile> for room::UserProfile {
+    fn from(profile: proto::room::UserProfile) -> Self {
+        Self {
+            avatar_url: profile.avatar_url,
+            color: profile.color,
+        }
+    }
+}
+
+impl From<room::UserProfile> for proto::room::UserProfile {
+    fn from(profile: room::UserProfile) -> Self {
+        Self {
+            avatar_url: profile.avatar_url,
+            color: profile.color,
         }
     }
 }
@@ -173,6 +295,7 @@ impl From<room::RoomUser> for proto::room::RoomUser {
             username: user.username,
             role_id: user.role_id.into_bytes().into(),
             is_connected: user.is_connected,
+            profile: Some(user.profile.into()),
         }
     }
 }
@@ -183,18 +306,190 @@ impl From<proto::room::Room> for room::Room {
     }
 }
 
+impl From<&ParkedPlayback> for proto::room::ParkedPlayback {
+    fn from(parked: &ParkedPlayback) -> Self {
+        Self {
+            track_id: parked.track_id.clone(),
+            progress_ms: parked.progress_ms,
+            parked_by: parked.parked_by.clone(),
+        }
+    }
+}
+
+impl From<room::RoomSettings> for proto::room::RoomSettings {
+    fn from(settings: room::RoomSettings) -> Self {
+        Self {
+            max_users: settings.max_users as _,
+            max_tracks_queue_len: settings.max_tracks_queue_len as _,
+            inactivity_timeout_mins: (settings.inactivity_timeout.as_secs() / 60) as _,
+            is_public: settings.is_public,
+            idle_pause_mins: settings
+                .idle_pause_after
+                .map(|idle_pause_after| (idle_pause_after.as_secs() / 60) as _)
+                .unwrap_or_default(),
+            fair_queue: settings.fair_queue,
+            daily_digest: settings.daily_digest.map(Into::into),
+            max_track_duration_secs: settings
+                .max_track_duration
+                .map(|max_track_duration| max_track_duration.as_secs() as _)
+                .unwrap_or_default(),
+            auto_promote_owner: settings.auto_promote_owner,
+            join_ttl_mins: settings
+                .join_ttl
+                .map(|join_ttl| (join_ttl.as_secs() / 60) as _)
+                .unwrap_or_default(),
+            waitlist: settings.waitlist,
+            allow_explicit: Some(settings.allow_explicit),
+            session_summary_webhook: settings.session_summary_webhook,
+            volume_limits: settings.volume_limits.map(Into::into),
+            auto_fill: settings.auto_fill,
+        }
+    }
+}
+
+impl From<room::VolumeLimits> for proto::room::VolumeLimits {
+    fn from(limits: room::VolumeLimits) -> Self {
+        Self {
+            min_volume: limits.min as _,
+            max_volume: limits.max as _,
+        }
+    }
+}
+
+impl From<proto::room::VolumeLimits> for room::VolumeLimits {
+    fn from(limits: proto::room::VolumeLimits) -> Self {
+        Self {
+            // Out of range values are saturated so they're rejected by RoomSettings::validate
+            min: limits.min_volume.try_into().unwrap_or(u8::MAX),
+            max: limits.max_volume.try_into().unwrap_or(u8::MAX),
+        }
+    }
+}
+
+impl From<room::DailyDigest> for proto::room::DailyDigest {
+    fn from(digest: room::DailyDigest) -> Self {
+        Self {
+            hour: digest.hour as _,
+            utc_offset_mins: digest.utc_offset_mins as _,
+        }
+    }
+}
+
+impl From<proto::room::DailyDigest> for room::DailyDigest {
+    fn from(digest: proto::room::DailyDigest) -> Self {
+        Self {
+            // Out of range values are saturated so they're rejected by RoomSettings::validate
+            hour: digest.hour.try_into().unwrap_or(u8::MAX),
+            utc_offset_mins: digest.utc_offset_mins.clamp(i16::MIN as _, i16::MAX as _) as _,
+        }
+    }
+}
+
+impl From<proto::room::RoomSettings> for room::RoomSettings {
+    fn from(settings: proto::room::RoomSettings) -> Self {
+        Self {
+            max_users: settings.max_users as _,
+            max_tracks_queue_len: settings.max_tracks_queue_len as _,
+            inactivity_timeout: Duration::from_secs(settings.inactivity_timeout_mins as u64 * 60),
+            is_public: settings.is_public,
+            idle_pause_after: (settings.idle_pause_mins > 0)
+                .then(|| Duration::from_secs(settings.idle_pause_mins as u64 * 60)),
+            fair_queue: settings.fair_queue,
+            daily_digest: settings.daily_digest.map(Into::into),
+            max_track_duration: (settings.max_track_duration_secs > 0)
+                .then(|| Duration::from_secs(settings.max_track_duration_secs as _)),
+            auto_promote_owner: settings.auto_promote_owner,
+            join_ttl: (settings.join_ttl_mins > 0)
+                .then(|| Duration::from_secs(settings.join_ttl_mins as u64 * 60)),
+            waitlist: settings.waitlist,
+            allow_explicit: settings.allow_explicit.unwrap_or(true),
+            session_summary_webhook: settings.session_summary_webhook,
+            volume_limits: settings.volume_limits.map(Into::into),
+            auto_fill: settings.auto_fill,
+        }
+    }
+}
+
+impl From<room::PublicRoom> for proto::room::PublicRoom {
+    fn from(room: room::PublicRoom) -> Self {
+        Self {
+            id: room.id.into_bytes().into(),
+            name: room.name,
+            current_track: room.current_track.map(Into::into),
+            users_count: room.users_count as _,
+            max_users: room.max_users as _,
+        }
+    }
+}
+
+impl From<room::HistoryTrack> for proto::room::HistoryTrack {
+    fn from(history_track: room::HistoryTrack) -> Self {
+        Self {
+            track: Some(history_track.track.into()),
+            played_at: Some(proto::Timestamp {
+                seconds: history_track.played_at.timestamp(),
+                nanos: history_track.played_at.timestamp_subsec_nanos() as _,
+            }),
+            pre_session: history_track.pre_session,
+        }
+    }
+}
+
+impl From<proto::room::HistoryTrack> for room::HistoryTrack {
+    fn from(history_track: proto::room::HistoryTrack) -> Self {
+        Self {
+            track: history_track.track.unwrap_or_default().into(),
+            played_at: history_track
+                .played_at
+                .and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos as _))
+                .unwrap_or_default(),
+            pre_session: history_track.pre_session,
+            added_by: None,
+        }
+    }
+}
+
+impl From<&DjRotation> for proto::room::DjRotation {
+    fn from(rotation: &DjRotation) -> Self {
+        Self {
+            turn_secs: rotation.turn_duration.as_secs() as _,
+            members: rotation.members.clone(),
+            current_dj: rotation.current_dj().cloned().unwrap_or_default(),
+            turn_tracks: rotation.turn_tracks.unwrap_or_default(),
+            turn_tracks_played: rotation.turn_tracks_played,
+        }
+    }
+}
+
 impl From<room::Room> for proto::room::Room {
     fn from(room: room::Room) -> Self {
+        let parked = room.parked.as_ref().map(Into::into);
+        let dj_rotation = room.dj_rotation.as_ref().map(Into::into);
+        let logs_total = room.logs.len();
+        let seq = room.room_seq;
+
         Self {
             id: room.id.into_bytes().into(),
             name: room.name,
             password: room.password,
+            invite_code: room.invite_code,
             users: room.users.into_iter().map(Into::into).collect(),
             banned_users: room.banned_users,
             role_manager: Some(room.role_manager.into()),
             tracks_queue: room.tracks_queue.into_iter().map(Into::into).collect(),
-            logs: room.logs.into_iter().map(Into::into).collect(),
-            max_users: room.max_users as _,
+            logs: room
+                .logs
+                .into_iter()
+                .skip(logs_total.saturating_sub(room::SNAPSHOT_LOGS_LEN))
+                .map(Into::into)
+                .collect(),
+            logs_total: logs_total as _,
+            settings: Some(room.settings.into()),
+            queue_edit_grace_secs: room.queue_edit_grace_period.as_secs() as _,
+            parked,
+            dj_rotation,
+            history: room.history.into_iter().map(Into::into).collect(),
+            seq,
         }
     }
 }
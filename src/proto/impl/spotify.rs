@@ -1,4 +1,7 @@
 use crate::proto;
+use crate::sharify::content_policy::ContentType;
+use crate::sharify::music_provider::ProviderError;
+use crate::sharify::room_metadata::SpotifyTokensStatus;
 use crate::sharify::spotify;
 use crate::sharify::spotify::web_utils;
 
@@ -6,6 +9,7 @@ impl From<spotify::SpotifyError> for proto::cmd::CommandResponse {
     fn from(err: spotify::SpotifyError) -> Self {
         Self {
             r#type: Some(err.into()),
+            ..Default::default()
         }
     }
 }
@@ -13,12 +17,34 @@ impl From<spotify::SpotifyError> for proto::cmd::CommandResponse {
 impl From<spotify::SpotifyError> for proto::cmd::command_response::Type {
     fn from(err: spotify::SpotifyError) -> Self {
         match err {
-            spotify::SpotifyError::Generic(error) => Self::GenericError(error),
+            spotify::SpotifyError::Generic(error)
+            | spotify::SpotifyError::Unauthorized(error)
+            | spotify::SpotifyError::Forbidden(error) => Self::GenericError(error),
             spotify::SpotifyError::RateLimited(time) => Self::SpotifyRateLimited(time),
         }
     }
 }
 
+impl From<ProviderError> for proto::cmd::CommandResponse {
+    fn from(err: ProviderError) -> Self {
+        Self {
+            r#type: Some(err.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ProviderError> for proto::cmd::command_response::Type {
+    fn from(err: ProviderError) -> Self {
+        match err {
+            ProviderError::Generic(error)
+            | ProviderError::Unauthorized(error)
+            | ProviderError::Forbidden(error) => Self::GenericError(error),
+            ProviderError::RateLimited(time) => Self::SpotifyRateLimited(time),
+        }
+    }
+}
+
 impl From<web_utils::SpotifyCurrentPlaybackOutput> for proto::spotify::PlaybackState {
     fn from(state: web_utils::SpotifyCurrentPlaybackOutput) -> Self {
         Self {
@@ -31,7 +57,48 @@ impl From<web_utils::SpotifyCurrentPlaybackOutput> for proto::spotify::PlaybackS
             track_id: state.track_id,
             track_name: state.track_name,
             artist_name: state.artist_name,
+            album_image_rev: web_utils::image_revision(&state.album_image_src),
             album_image_src: state.album_image_src,
+            item_type: proto::spotify::PlaybackItemType::from(state.item_type) as _,
+            repeat: proto::spotify::RepeatMode::from(state.repeat) as _,
+        }
+    }
+}
+
+impl From<web_utils::PlaybackItemType> for proto::spotify::PlaybackItemType {
+    fn from(item_type: web_utils::PlaybackItemType) -> Self {
+        match item_type {
+            web_utils::PlaybackItemType::Track => Self::Track,
+            web_utils::PlaybackItemType::Episode => Self::Episode,
+        }
+    }
+}
+
+impl From<proto::spotify::PlaybackItemType> for web_utils::PlaybackItemType {
+    fn from(item_type: proto::spotify::PlaybackItemType) -> Self {
+        match item_type {
+            proto::spotify::PlaybackItemType::Track => Self::Track,
+            proto::spotify::PlaybackItemType::Episode => Self::Episode,
+        }
+    }
+}
+
+impl From<web_utils::RepeatMode> for proto::spotify::RepeatMode {
+    fn from(mode: web_utils::RepeatMode) -> Self {
+        match mode {
+            web_utils::RepeatMode::Off => Self::Off,
+            web_utils::RepeatMode::Context => Self::Context,
+            web_utils::RepeatMode::Track => Self::Track,
+        }
+    }
+}
+
+impl From<proto::spotify::RepeatMode> for web_utils::RepeatMode {
+    fn from(mode: proto::spotify::RepeatMode) -> Self {
+        match mode {
+            proto::spotify::RepeatMode::Off => Self::Off,
+            proto::spotify::RepeatMode::Context => Self::Context,
+            proto::spotify::RepeatMode::Track => Self::Track,
         }
     }
 }
@@ -39,10 +106,15 @@ impl From<web_utils::SpotifyCurrentPlaybackOutput> for proto::spotify::PlaybackS
 impl From<proto::spotify::Track> for web_utils::SpotifyTrack {
     fn from(track: proto::spotify::Track) -> Self {
         Self {
+            item_type: track.item_type().into(),
             track_id: track.track_id,
             track_name: track.track_name,
             artist_name: track.artist_name,
             track_duration: track.track_duration,
+            album_name: track.album_name,
+            album_image_src: track.album_image_src,
+            explicit: track.explicit,
+            popularity: track.popularity,
         }
     }
 }
@@ -54,6 +126,12 @@ impl From<web_utils::SpotifyTrack> for proto::spotify::Track {
             track_name: track.track_name,
             artist_name: track.artist_name,
             track_duration: track.track_duration,
+            album_name: track.album_name,
+            album_image_rev: web_utils::image_revision(&track.album_image_src),
+            album_image_src: track.album_image_src,
+            explicit: track.explicit,
+            popularity: track.popularity,
+            item_type: proto::spotify::PlaybackItemType::from(track.item_type) as _,
         }
     }
 }
@@ -71,3 +149,117 @@ impl From<web_utils::SpotifyTackArray> for proto::spotify::TrackArray {
         }
     }
 }
+
+impl From<web_utils::SpotifyDevice> for proto::spotify::Device {
+    fn from(device: web_utils::SpotifyDevice) -> Self {
+        Self {
+            device_id: device.device_id,
+            name: device.name,
+            device_type: device.device_type,
+            is_active: device.is_active,
+            volume_percent: device.volume_percent.map(Into::into),
+        }
+    }
+}
+
+impl From<web_utils::SpotifyDeviceArray> for proto::spotify::DeviceArray {
+    fn from(devices: web_utils::SpotifyDeviceArray) -> Self {
+        Self {
+            devices: devices.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<web_utils::SpotifyPlaylist> for proto::spotify::Playlist {
+    fn from(playlist: web_utils::SpotifyPlaylist) -> Self {
+        Self {
+            playlist_id: playlist.playlist_id,
+            name: playlist.name,
+            owner_name: playlist.owner_name,
+            tracks_count: playlist.tracks_count,
+            image_src: playlist.image_src,
+        }
+    }
+}
+
+impl From<web_utils::SpotifyPlaylistArray> for proto::spotify::PlaylistArray {
+    fn from(playlists: web_utils::SpotifyPlaylistArray) -> Self {
+        Self {
+            playlists: playlists.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<web_utils::SpotifyAlbum> for proto::spotify::Album {
+    fn from(album: web_utils::SpotifyAlbum) -> Self {
+        Self {
+            album_id: album.album_id,
+            name: album.name,
+            artist_name: album.artist_name,
+            image_src: album.image_src,
+            tracks_count: album.tracks_count,
+        }
+    }
+}
+
+impl From<web_utils::SpotifyArtist> for proto::spotify::Artist {
+    fn from(artist: web_utils::SpotifyArtist) -> Self {
+        Self {
+            artist_id: artist.artist_id,
+            name: artist.name,
+            image_src: artist.image_src,
+        }
+    }
+}
+
+impl From<ContentType> for proto::spotify::ContentType {
+    fn from(content_type: ContentType) -> Self {
+        match content_type {
+            ContentType::Track => Self::Track,
+            ContentType::Episode => Self::Episode,
+            ContentType::Local => Self::Local,
+            ContentType::Album => Self::Album,
+            ContentType::Artist => Self::Artist,
+            ContentType::Playlist => Self::Playlist,
+        }
+    }
+}
+
+impl From<proto::spotify::SearchType> for web_utils::SearchType {
+    fn from(search_type: proto::spotify::SearchType) -> Self {
+        match search_type {
+            proto::spotify::SearchType::Track => Self::Track,
+            proto::spotify::SearchType::Album => Self::Album,
+            proto::spotify::SearchType::Artist => Self::Artist,
+            proto::spotify::SearchType::Playlist => Self::Playlist,
+        }
+    }
+}
+
+impl From<web_utils::SpotifySearchResults> for proto::spotify::SearchResults {
+    fn from(results: web_utils::SpotifySearchResults) -> Self {
+        Self {
+            tracks: results.tracks.into_iter().map(Into::into).collect(),
+            tracks_total: results.tracks_total,
+            albums: results.albums.into_iter().map(Into::into).collect(),
+            albums_total: results.albums_total,
+            artists: results.artists.into_iter().map(Into::into).collect(),
+            artists_total: results.artists_total,
+            playlists: results.playlists.into_iter().map(Into::into).collect(),
+            playlists_total: results.playlists_total,
+        }
+    }
+}
+
+impl From<SpotifyTokensStatus> for proto::spotify::SpotifyTokensStatus {
+    fn from(status: SpotifyTokensStatus) -> Self {
+        Self {
+            expires_in_secs: status.expires_in.map(|expires_in| expires_in.num_seconds()),
+            refreshed_at: status.refreshed_at.map(|refreshed_at| proto::Timestamp {
+                seconds: refreshed_at.timestamp(),
+                nanos: refreshed_at.timestamp_subsec_nanos() as _,
+            }),
+            refresh_failures: status.refresh_failures,
+        }
+    }
+}
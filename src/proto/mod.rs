@@ -1,22 +1,36 @@
 pub mod cmd;
+mod format;
 pub mod r#impl;
 pub mod role;
 pub mod room;
 pub mod spotify;
 
-use prost::Message as _;
+use serde::{Deserialize, Serialize};
 
-pub fn create_error_response(error: impl Into<String>) -> Result<Vec<u8>, String> {
+pub use format::WireFormat;
+
+/// google.protobuf.Timestamp, used instead of prost_types' one so it can be serialized to JSON
+#[derive(Clone, Copy, PartialEq, Eq, Hash, prost::Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+pub fn create_error_response(
+    error: impl Into<String>,
+    format: WireFormat,
+) -> Result<Vec<u8>, String> {
     let proto_cmd = cmd::CommandResponse {
         r#type: Some(cmd::command_response::Type::GenericError(error.into())),
+        ..Default::default()
     };
 
-    let mut buf = Vec::new();
-    if let Err(err) = proto_cmd.encode(&mut buf) {
-        return Err(format!(
+    format.encode(&proto_cmd).map_err(|err| {
+        format!(
             "Unexpected error while encoding newly created CommandResponse to protobuf command: {err}"
-        ));
-    }
-
-    Ok(buf)
+        )
+    })
 }
@@ -1,32 +1,406 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 
-use actix_web::{HttpResponse, Responder, get, post, web};
+use actix_rt::time;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::{self, Next};
+use actix_web::{
+    HttpMessage as _, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, delete, get, post,
+    web,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt as _, stream};
+use openssl::memcmp;
 use prost::Message as _;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, broadcast, mpsc};
+use tracing::Instrument as _;
 use uuid::Uuid;
 
+use crate::api::{self, ApiVersion};
+use crate::config;
 use crate::discord;
-use crate::proto::cmd::{CommandResponse, HttpCommand, command_response, http_command};
-use crate::proto::create_error_response;
+use crate::feedback::{FeedbackStatus, FeedbackStore};
+use crate::proto::cmd::{
+    CommandResponse, HttpCommand, WsCloseCode, command_response, http_command,
+};
+use crate::proto::{WireFormat, create_error_response};
 use crate::sharify;
-use crate::sharify::room::CredentialsInput;
+use crate::sharify::content_policy::ContentPolicy;
+use crate::sharify::identity::{self, IdentityError, IdentityProvider};
+use crate::sharify::room::{PRE_SESSION_HISTORY_LEN, RoomError, RoomID, UserProfile};
+use crate::sharify::room_events::RoomEvent;
 use crate::sharify::room_manager::RoomManager;
-use crate::sharify::spotify::Timestamp;
+use crate::sharify::schedule::{ScheduledRoomInfo, ScheduledRoomStatus};
+use crate::sharify::signed_url::SignedUrlError;
+use crate::sharify::spotify;
+use crate::sharify::tasks::spawn_room_task;
+use crate::sharify::waitlist::WaitlistStatus;
+use crate::sharify::websocket::{self, SharifyWsManager, events};
+
+/// Response header of CreateRoom and JoinRoom holding the session token, it's sent back as Bearer
+/// token of GetRoom and as session_token query param of the WS init
+pub const SESSION_TOKEN_HEADER: &str = "x-session-token";
+
+/// Response header holding the ID of the request, it prefixes the logs of the WS sessions opened
+/// after a CreateRoom/JoinRoom so they can be matched to the request
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// Request extension set by the correlation_id middleware
+#[derive(Clone)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    pub fn of(req: &HttpRequest) -> String {
+        req.extensions()
+            .get::<Self>()
+            .map(|Self(id)| id.clone())
+            .unwrap_or_else(|| Uuid::now_v7().simple().to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct PublicRoomsQuery {
+    #[serde(default)]
+    offset: usize,
+    /// Defaults to 20 when 0, capped to 50
+    #[serde(default)]
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    /// "json" for the JSON fallback, protobuf otherwise
+    fmt: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SpotifyLoginQuery {
+    /// Login of a member streaming the room (RegisterListener) instead of a room host
+    #[serde(default)]
+    listener: bool,
+}
+
+#[derive(Deserialize)]
+struct OAuthCallbackQuery {
+    code: String,
+    state: String,
+}
+
+#[derive(Serialize)]
+struct VersionStatus {
+    version: ApiVersion,
+    #[serde(flatten)]
+    deprecation: Option<api::Deprecation>,
+}
+
+#[derive(Serialize)]
+struct VersionsStatus {
+    latest: ApiVersion,
+    versions: Vec<VersionStatus>,
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    status: &'static str,
+    active_rooms: usize,
+    ws_sessions: usize,
+    content_policy: ContentPolicy,
+}
+
+#[derive(Serialize)]
+struct ReadinessStatus {
+    status: &'static str,
+    active_rooms: usize,
+    ws_sessions: usize,
+    spotify: SpotifyStatus,
+}
+
+#[derive(Serialize)]
+struct SpotifyStatus {
+    reachable: bool,
+    rate_limited_rooms: usize,
+}
+
+#[derive(Deserialize)]
+struct SignedUrlQuery {
+    expires: i64,
+    sig: String,
+}
 
 #[get("/")]
 pub async fn root() -> impl Responder {
     HttpResponse::Ok()
 }
 
-#[post("/v1")]
+/// Routes served under each API version scope, see ApiVersion
+pub fn configure_versioned(cfg: &mut web::ServiceConfig) {
+    cfg.service(proto_command)
+        // Before the WS resource, both match /{}/{}
+        .service(join_code)
+        .service(public_rooms)
+        .service(code_verifier)
+        .service(code_challenge)
+        .service(send_discord_webhook)
+        .service(identity_login)
+        .service(identity_callback)
+        .service(spotify_login)
+        .service(spotify_callback)
+        .service(room_waitlist)
+        .service(scheduled_room_events)
+        .service(scheduled_room)
+        .service(
+            web::resource("/{room_id}/events")
+                .wrap(middleware::from_fn(verify_signed_url))
+                .route(web::get().to(room_events)),
+        )
+        .service(
+            web::resource("/{room_id}/{user_id}")
+                .route(web::get().to(websocket::SharifyWsInstance::init)),
+        );
+}
+
+/// Lets clients know whether the version they speak is deprecated before it's removed
+#[get("/versions")]
+pub async fn versions() -> impl Responder {
+    HttpResponse::Ok().json(VersionsStatus {
+        latest: ApiVersion::LATEST,
+        versions: ApiVersion::ALL
+            .into_iter()
+            .map(|version| VersionStatus {
+                version,
+                deprecation: version.deprecation(),
+            })
+            .collect(),
+    })
+}
+
+/// Generates the request's CorrelationId and sends it back in the CORRELATION_ID_HEADER
+pub async fn correlation_id<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let id = Uuid::now_v7().simple().to_string();
+
+    req.extensions_mut().insert(CorrelationId(id.clone()));
+
+    let span = info_span!(
+        "request",
+        correlation_id = %id,
+        method = %req.method(),
+        path = req.path()
+    );
+    let mut res = next.call(req).instrument(span).await?;
+
+    if let Ok(value) = id.parse() {
+        res.headers_mut().insert(
+            header::HeaderName::from_static(CORRELATION_ID_HEADER),
+            value,
+        );
+    }
+
+    Ok(res)
+}
+
+/// Signals the deprecation of the scope's API version (RFC 9745 and RFC 8594 headers) and
+/// answers 410 Gone once it's sunset
+pub async fn api_deprecation<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let Some((version, deprecation)) = req.app_data::<ApiVersion>().and_then(|version| {
+        version
+            .deprecation()
+            .map(|deprecation| (*version, deprecation))
+    }) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    if deprecation.is_sunset(Utc::now()) {
+        let response = HttpResponse::Gone().body(format!(
+            "API {} has been removed, use {}",
+            version.as_str(),
+            ApiVersion::LATEST.as_str()
+        ));
+
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+
+    if let Ok(value) = format!("@{}", deprecation.deprecated_at.timestamp()).parse() {
+        headers.insert(header::HeaderName::from_static("deprecation"), value);
+    }
+
+    if let Some(sunset_at) = deprecation.sunset_at
+        && let Ok(value) = sunset_at
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+            .parse()
+    {
+        headers.insert(header::HeaderName::from_static("sunset"), value);
+    }
+
+    if let Ok(value) = format!(
+        "<{}>; rel=\"successor-version\"",
+        ApiVersion::LATEST.prefix()
+    )
+    .parse()
+    {
+        headers.insert(header::LINK, value);
+    }
+
+    Ok(res.map_into_left_body())
+}
+
+/// Liveness probe: only tells that the server answers, no external dependency is checked
+#[get("/healthz")]
+pub async fn healthz(
+    ws_mgr: web::Data<Arc<RwLock<SharifyWsManager>>>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let active_rooms = sharify_state.read().await.rooms_count();
+    let ws_sessions = ws_mgr.read().await.len();
+
+    HttpResponse::Ok().json(HealthStatus {
+        status: "ok",
+        active_rooms,
+        ws_sessions,
+        content_policy: config::get().content_policy.clone(),
+    })
+}
+
+/// Readiness probe: fails with a 503 when the Spotify API cannot be reached since no room
+/// can work without it
+#[get("/readyz")]
+pub async fn readyz(
+    ws_mgr: web::Data<Arc<RwLock<SharifyWsManager>>>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (active_rooms, rate_limiters) = {
+        let state_guard = sharify_state.read().await;
+
+        (state_guard.rooms_count(), state_guard.rate_limiters())
+    };
+    let ws_sessions = ws_mgr.read().await.len();
+
+    let mut rate_limited_rooms = 0;
+    for (_, rate_limiter) in rate_limiters {
+        if rate_limiter.read().await.is_rate_limited() {
+            rate_limited_rooms += 1;
+        }
+    }
+
+    let reachable = spotify::is_api_reachable().await;
+
+    let status = ReadinessStatus {
+        status: if reachable { "ready" } else { "unavailable" },
+        active_rooms,
+        ws_sessions,
+        spotify: SpotifyStatus {
+            reachable,
+            rate_limited_rooms,
+        },
+    };
+
+    if !reachable {
+        return HttpResponse::ServiceUnavailable().json(status);
+    }
+
+    HttpResponse::Ok().json(status)
+}
+
+/// Room browser: the rooms whose owner opted in with RoomSettings.is_public, most recent first
+#[get("/rooms")]
+pub async fn public_rooms(
+    query: web::Query<PublicRoomsQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (rooms, total) = sharify_state
+        .read()
+        .await
+        .get_public_rooms(query.offset, query.limit);
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::PublicRoomPage(
+            command_response::PublicRoomPage {
+                rooms: rooms.into_iter().map(Into::into).collect(),
+                total: total as _,
+                offset: query.offset as _,
+            },
+        )),
+        ..Default::default()
+    };
+
+    let mut buf = Vec::new();
+    if let Err(err) = cmd.encode(&mut buf) {
+        return HttpResponse::InternalServerError().body(format!(
+            "Unexpected error while encoding public rooms to protobuf command: {err}"
+        ));
+    }
+
+    HttpResponse::Ok().body(buf)
+}
+
+/// Resolves an invite code to the room, the same way as the GetRoom HTTP command
+#[get("/join/{code}")]
+pub async fn join_code(
+    code: web::Path<String>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let state_guard = sharify_state.read().await;
+    let Some(room) = state_guard.get_room_by_invite_code(&code) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::Room(room.clone().into())),
+        ..Default::default()
+    };
+
+    drop(room);
+    drop(state_guard);
+
+    let mut buf = Vec::new();
+    if let Err(err) = cmd.encode(&mut buf) {
+        return HttpResponse::InternalServerError().body(format!(
+            "Unexpected error while encoding Room to protobuf command: {err}"
+        ));
+    }
+
+    HttpResponse::Ok().body(buf)
+}
+
+#[post("")]
 pub async fn proto_command(
+    req: HttpRequest,
     body: web::Payload,
     sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
 ) -> impl Responder {
-    let bad_request =
-        HttpResponse::BadRequest().body("Failed to decode HTTP POST command with Protobuf");
+    // JSON fallback when the command is sent as application/json, answered in the same format
+    let format = WireFormat::from_content_type(
+        req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()),
+    );
+    let bad_request = HttpResponse::BadRequest().body(format!(
+        "Failed to decode HTTP POST command with {format:?}"
+    ));
 
-    let Ok(Ok(command)) = body.to_bytes().await.map(HttpCommand::decode) else {
+    let Ok(Ok(command)) = body
+        .to_bytes()
+        .await
+        .map(|bytes| format.decode::<HttpCommand>(&bytes))
+    else {
         return bad_request;
     };
 
@@ -39,50 +413,85 @@ pub async fn proto_command(
             user_id,
             username,
             name,
-            credentials: Some(credentials),
+            identity_token,
+            spotify_grant,
+            profile,
+            ..
         }) => {
             let mut state_guard = sharify_state.write().await;
-            let room = match state_guard.create_room(
+            let user_id = match identity::resolve_user_id(
                 user_id,
+                identity_token.as_deref(),
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(user_id) => user_id,
+                Err(err) => return identity_error_response(err, format),
+            };
+            let tokens = match take_spotify_tokens(&mut state_guard, spotify_grant, format) {
+                Ok(tokens) => tokens,
+                Err(response) => return response,
+            };
+            let room = match state_guard.create_room(
+                user_id.clone(),
                 username,
                 name,
-                CredentialsInput {
-                    access_token: credentials.access_token,
-                    refresh_token: credentials.refresh_token,
-                    expires_in: credentials.expires_in,
-                    created_at: Timestamp::new(credentials.created_at),
-                },
+                tokens,
+                profile.map(Into::into).unwrap_or_default(),
             ) {
                 Ok(room) => room,
                 Err(error) => {
-                    let proto_cmd: CommandResponse = error.into();
+                    return command_response(HttpResponse::BadRequest(), &error.into(), format);
+                }
+            };
 
-                    let mut buf = Vec::new();
-                    proto_cmd.encode(&mut buf).unwrap();
+            let session_token = match identity::issue_session_token(
+                room.id,
+                &user_id,
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(token) => token,
+                Err(err) => return identity_error_response(err, format),
+            };
 
-                    return HttpResponse::BadRequest().body(buf);
+            let _ = state_guard.set_correlation_id(room.id, &user_id, CorrelationId::of(&req));
+
+            drop(state_guard);
+
+            // Seeded in the background so the room creation doesn't wait for Spotify
+            let (room_id, spotify_handler) = (room.id, room.spotify_handler.clone());
+            let sharify_state = Arc::clone(&sharify_state);
+
+            spawn_room_task(async move {
+                match spotify_handler
+                    .get_recently_played(Some(PRE_SESSION_HISTORY_LEN))
+                    .await
+                {
+                    Ok(played) => {
+                        if let Some(mut room) = sharify_state.read().await.lock_room(&room_id) {
+                            room.seed_history(played);
+                        }
+                    }
+                    Err(err) => debug!(
+                        "[{room_id}] Failed to seed the room history: {}",
+                        String::from(err)
+                    ),
                 }
-            };
+            });
 
             let proto_command = CommandResponse {
                 r#type: Some(command_response::Type::Room(room.into())),
+                ..Default::default()
             };
+            let mut response = HttpResponse::Created();
 
-            drop(state_guard);
-
-            let mut buf = Vec::new();
-            if let Err(err) = proto_command.encode(&mut buf) {
-                return HttpResponse::InternalServerError().body(format!(
-                    "Unexpected error while encoding newly created Room to protobuf command: {err}"
-                ));
-            }
+            response.insert_header((SESSION_TOKEN_HEADER, session_token));
 
-            HttpResponse::Created().body(buf)
+            command_response(response, &proto_command, format)
         }
         http_command::Type::GetRoom(http_command::GetRoom { room_id }) => {
             let state_guard = sharify_state.read().await;
-            let Ok(uuid) = Uuid::from_slice(&room_id[..16]) else {
-                return match create_error_response("Wrong UUID format") {
+            let Ok(uuid) = Uuid::from_slice(&room_id) else {
+                return match create_error_response("Wrong UUID format", format) {
                     Err(err) => HttpResponse::InternalServerError().body(err),
                     Ok(buf) => HttpResponse::BadRequest().body(buf),
                 };
@@ -91,88 +500,1154 @@ pub async fn proto_command(
                 return HttpResponse::NotFound().finish();
             };
 
+            // Only the room users can read it, the token must still match a member
+            let user_id = match bearer_token(&req)
+                .ok_or(IdentityError::SessionRequired)
+                .and_then(|token| {
+                    identity::verify_session_token(token, uuid, state_guard.clock().utc_now())
+                }) {
+                Ok(user_id) => user_id,
+                Err(err) => return identity_error_response(err, format),
+            };
+
+            if !room.users.iter().any(|user| user.id == user_id) {
+                return command_response(
+                    HttpResponse::Unauthorized(),
+                    &RoomError::RoomUserNotFound.into(),
+                    format,
+                );
+            }
+
             let proto_command = CommandResponse {
                 r#type: Some(command_response::Type::Room(room.clone().into())),
+                ..Default::default()
             };
 
+            drop(room);
             drop(state_guard);
 
-            let mut buf = Vec::new();
-            if let Err(err) = proto_command.encode(&mut buf) {
-                return HttpResponse::InternalServerError().body(format!(
-                    "Unexpected error while encoding newly created Room to protobuf command: {err}"
-                ));
-            }
-
-            HttpResponse::Ok().body(buf)
+            command_response(HttpResponse::Ok(), &proto_command, format)
         }
         http_command::Type::JoinRoom(http_command::JoinRoom {
             room_id,
             user_id,
             username,
+            identity_token,
+            profile,
         }) => {
             if room_id.len() < 16 {
-                return match create_error_response("Room ID is an invalid UUID") {
+                return match create_error_response("Room ID is an invalid UUID", format) {
                     Err(err) => HttpResponse::InternalServerError().body(err),
                     Ok(buf) => HttpResponse::BadRequest().body(buf),
                 };
             }
 
             let mut state_guard = sharify_state.write().await;
-            let Ok(uuid) = Uuid::from_slice(&room_id[..16]) else {
-                return match create_error_response("Wrong UUID format") {
+            let Ok(uuid) = Uuid::from_slice(&room_id) else {
+                return match create_error_response("Wrong UUID format", format) {
                     Err(err) => HttpResponse::InternalServerError().body(err),
                     Ok(buf) => HttpResponse::BadRequest().body(buf),
                 };
             };
-            let room = match state_guard.join_room(uuid, username, user_id) {
+            let user_id = match identity::resolve_user_id(
+                user_id,
+                identity_token.as_deref(),
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(user_id) => user_id,
+                Err(err) => return identity_error_response(err, format),
+            };
+            let profile: UserProfile = profile.map(Into::into).unwrap_or_default();
+            let room = match state_guard.join_room(
+                uuid,
+                username.clone(),
+                user_id.clone(),
+                profile.clone(),
+            ) {
                 Ok(room) => room,
+                // Queued instead when the room has a waitlist, see room_waitlist
+                Err(RoomError::RoomFull) => {
+                    return match state_guard.join_waitlist(uuid, username, user_id, profile) {
+                        Ok((ticket, position)) => command_response(
+                            HttpResponse::Accepted(),
+                            &waitlisted_response(ticket, position),
+                            format,
+                        ),
+                        Err(err) => {
+                            command_response(HttpResponse::Unauthorized(), &err.into(), format)
+                        }
+                    };
+                }
                 Err(err) => {
-                    let mut buf = Vec::new();
-
-                    CommandResponse::from(err).encode(&mut buf).unwrap();
-
-                    return HttpResponse::Unauthorized().body(buf);
+                    return command_response(HttpResponse::Unauthorized(), &err.into(), format);
                 }
             };
+            let session_token = match identity::issue_session_token(
+                uuid,
+                &user_id,
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(token) => token,
+                Err(err) => return identity_error_response(err, format),
+            };
+
+            let _ = state_guard.set_correlation_id(uuid, &user_id, CorrelationId::of(&req));
 
             drop(state_guard);
 
             let proto_command = CommandResponse {
                 r#type: Some(command_response::Type::Room(room.into())),
+                ..Default::default()
             };
+            let mut response = HttpResponse::Ok();
+
+            response.insert_header((SESSION_TOKEN_HEADER, session_token));
+
+            command_response(response, &proto_command, format)
+        }
+        http_command::Type::ScheduleRoom(http_command::ScheduleRoom {
+            user_id,
+            username,
+            name,
+            identity_token,
+            spotify_grant,
+            profile,
+            settings,
+            opens_at,
+        }) => {
+            let Some(opens_at) =
+                opens_at.and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos as _))
+            else {
+                return command_response(
+                    HttpResponse::BadRequest(),
+                    &RoomError::InvalidSchedule.into(),
+                    format,
+                );
+            };
+
+            let mut state_guard = sharify_state.write().await;
+            let user_id = match identity::resolve_user_id(
+                user_id,
+                identity_token.as_deref(),
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(user_id) => user_id,
+                Err(err) => return identity_error_response(err, format),
+            };
+            let profile = profile.map(Into::into).unwrap_or_default();
+            let settings = settings.map(Into::into).unwrap_or_default();
+
+            // The grant stays usable when the schedule is rejected
+            if let Err(err) = state_guard.validate_schedule(&user_id, &profile, &settings, opens_at)
+            {
+                return command_response(HttpResponse::BadRequest(), &err.into(), format);
+            }
+
+            let tokens = match take_spotify_tokens(&mut state_guard, spotify_grant, format) {
+                Ok(tokens) => tokens,
+                Err(response) => return response,
+            };
+
+            match state_guard.schedule_room(
+                (user_id, username, profile),
+                name,
+                tokens,
+                settings,
+                opens_at,
+            ) {
+                Ok(info) => command_response(
+                    HttpResponse::Created(),
+                    &scheduled_room_response(info),
+                    format,
+                ),
+                Err(err) => command_response(HttpResponse::BadRequest(), &err.into(), format),
+            }
+        }
+        http_command::Type::RegisterScheduledRoom(http_command::RegisterScheduledRoom {
+            scheduled_room_id,
+            user_id,
+            username,
+            identity_token,
+            profile,
+        }) => {
+            let Ok(id) = Uuid::from_slice(&scheduled_room_id) else {
+                return match create_error_response("Wrong UUID format", format) {
+                    Err(err) => HttpResponse::InternalServerError().body(err),
+                    Ok(buf) => HttpResponse::BadRequest().body(buf),
+                };
+            };
+
+            let mut state_guard = sharify_state.write().await;
+            let user_id = match identity::resolve_user_id(
+                user_id,
+                identity_token.as_deref(),
+                state_guard.clock().utc_now(),
+            ) {
+                Ok(user_id) => user_id,
+                Err(err) => return identity_error_response(err, format),
+            };
+
+            match state_guard.register_scheduled_room(
+                id,
+                user_id,
+                username,
+                profile.map(Into::into).unwrap_or_default(),
+            ) {
+                Ok(info) => {
+                    command_response(HttpResponse::Ok(), &scheduled_room_response(info), format)
+                }
+                Err(err @ RoomError::ScheduledRoomNotFound) => {
+                    command_response(HttpResponse::NotFound(), &err.into(), format)
+                }
+                Err(err) => command_response(HttpResponse::BadRequest(), &err.into(), format),
+            }
+        }
+    }
+}
+
+/// Spotify tokens of the room host, from the grant of the server Spotify login
+fn take_spotify_tokens(
+    state_guard: &mut RoomManager,
+    spotify_grant: Option<String>,
+    format: WireFormat,
+) -> Result<spotify::SpotifyTokens, HttpResponse> {
+    state_guard
+        .take_spotify_grant(spotify_grant.as_deref().unwrap_or_default())
+        .map_err(|error| command_response(HttpResponse::BadRequest(), &error.into(), format))
+}
+
+fn scheduled_room_response(info: ScheduledRoomInfo) -> CommandResponse {
+    CommandResponse {
+        r#type: Some(command_response::Type::ScheduledRoom(info.into())),
+        ..Default::default()
+    }
+}
+
+/// Polled by the host and the users registered to a scheduled room, see ScheduleRoom
+///
+/// Answers the scheduled room until it opens, then the room along with their session token like
+/// JoinRoom
+#[get("/scheduled/{id}/{ticket}")]
+pub async fn scheduled_room(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    query: web::Query<FormatQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (id, ticket) = path.into_inner();
+    let format = WireFormat::from_query(query.fmt.as_deref());
+    let mut state_guard = sharify_state.write().await;
+
+    let (room, user_id) = match state_guard.poll_scheduled_room(id, ticket) {
+        Ok(ScheduledRoomStatus::Pending(info)) => {
+            return command_response(HttpResponse::Ok(), &scheduled_room_response(info), format);
+        }
+        Ok(ScheduledRoomStatus::Opened { room, user_id }) => (room, user_id),
+        Err(err) => {
+            return command_response(HttpResponse::NotFound(), &err.into(), format);
+        }
+    };
+
+    let session_token =
+        match identity::issue_session_token(room.id, &user_id, state_guard.clock().utc_now()) {
+            Ok(token) => token,
+            Err(err) => return identity_error_response(err, format),
+        };
+
+    let _ = state_guard.set_correlation_id(room.id, &user_id, CorrelationId::of(&req));
+
+    drop(state_guard);
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::Room((*room).into())),
+        ..Default::default()
+    };
+    let mut response = HttpResponse::Ok();
+
+    response.insert_header((SESSION_TOKEN_HEADER, session_token));
 
-            let mut buf = Vec::new();
-            if let Err(err) = proto_command.encode(&mut buf) {
-                return HttpResponse::InternalServerError().body(format!(
-                    "Unexpected error while encoding newly created Room to protobuf command: {err}"
-                ));
+    command_response(response, &cmd, format)
+}
+
+/// SSE stream of a scheduled room for its host and registered users: a scheduled_room event,
+/// then a scheduled_room_opened one once it opens and can be polled with GET
+/// /scheduled/{id}/{ticket}. Ends then, or when the scheduled room is dropped
+#[get("/scheduled/{id}/{ticket}/events")]
+pub async fn scheduled_room_events(
+    path: web::Path<(Uuid, Uuid)>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (id, ticket) = path.into_inner();
+    let state_mgr = Arc::clone(&sharify_state);
+
+    let (events, info, opened) = {
+        let state_guard = sharify_state.read().await;
+        // Subscribed before the lookup so the opening isn't missed in between
+        let events = state_guard.subscribe_events();
+
+        match state_guard.scheduled_room_info(id, ticket) {
+            Ok((info, opened)) => (events, info, opened),
+            Err(_) => return HttpResponse::NotFound().finish(),
+        }
+    };
+
+    let opened_event = events::sse_event(
+        "scheduled_room_opened",
+        &scheduled_room_response(info.clone()),
+    );
+    let snapshot = events::sse_event("scheduled_room", &scheduled_room_response(info));
+
+    let updates = stream::unfold(Some((events, opened)), move |state| {
+        let state_mgr = Arc::clone(&state_mgr);
+        let opened_event = opened_event.clone();
+
+        async move {
+            let (mut events, mut opened) = state?;
+
+            while !opened {
+                match time::timeout(events::EVENTS_KEEPALIVE_INTERVAL, events.recv()).await {
+                    Ok(Ok(RoomEvent::ScheduledRoomOpened {
+                        scheduled_room_id, ..
+                    })) => opened = scheduled_room_id == id,
+                    Ok(Ok(_)) => {}
+                    Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                    // Idle or missed events, the scheduled room is looked up again
+                    Ok(Err(broadcast::error::RecvError::Lagged(_))) | Err(_) => {
+                        match state_mgr.read().await.scheduled_room_info(id, ticket) {
+                            Ok((_, true)) => opened = true,
+                            Ok((_, false)) => {
+                                let keepalive = web::Bytes::from_static(b": keepalive\n\n");
+
+                                return Some((Some(keepalive), Some((events, false))));
+                            }
+                            // Its host couldn't open it
+                            Err(_) => return None,
+                        }
+                    }
+                }
             }
 
-            HttpResponse::Ok().body(buf)
+            Some((opened_event, None))
         }
-        _ => HttpResponse::ServiceUnavailable()
-            .body("Unreachable error: POST command unhandled or missing command parts"),
+    })
+    .filter_map(|event| async move { event.map(Ok::<_, actix_web::Error>) });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        // Skips the Compress middleware, it would buffer the events
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(
+            stream::iter(snapshot)
+                .map(Ok::<_, actix_web::Error>)
+                .chain(updates),
+        )
+}
+
+fn waitlisted_response(ticket: Uuid, position: usize) -> CommandResponse {
+    CommandResponse {
+        r#type: Some(command_response::Type::Waitlisted(
+            command_response::Waitlisted {
+                ticket: ticket.into_bytes().into(),
+                position: position as _,
+            },
+        )),
+        ..Default::default()
     }
 }
 
-#[post("/v1/webhook")]
+/// Polled by the users queued by a JoinRoom on a full room, see RoomSettings.waitlist
+///
+/// Answers their position until they're admitted, then the room along with their session token
+/// like JoinRoom
+#[get("/{room_id}/waitlist/{ticket}")]
+pub async fn room_waitlist(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    query: web::Query<FormatQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (room_id, ticket) = path.into_inner();
+    let format = WireFormat::from_query(query.fmt.as_deref());
+    let mut state_guard = sharify_state.write().await;
+
+    let (room, user_id) = match state_guard.poll_waitlist(room_id, ticket) {
+        Ok(WaitlistStatus::Waiting { position }) => {
+            return command_response(
+                HttpResponse::Ok(),
+                &waitlisted_response(ticket, position),
+                format,
+            );
+        }
+        Ok(WaitlistStatus::Admitted { room, user_id }) => (room, user_id),
+        Err(err) => {
+            return command_response(HttpResponse::NotFound(), &err.into(), format);
+        }
+    };
+
+    let session_token =
+        match identity::issue_session_token(room_id, &user_id, state_guard.clock().utc_now()) {
+            Ok(token) => token,
+            Err(err) => return identity_error_response(err, format),
+        };
+
+    let _ = state_guard.set_correlation_id(room_id, &user_id, CorrelationId::of(&req));
+
+    drop(state_guard);
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::Room((*room).into())),
+        ..Default::default()
+    };
+    let mut response = HttpResponse::Ok();
+
+    response.insert_header((SESSION_TOKEN_HEADER, session_token));
+
+    command_response(response, &cmd, format)
+}
+
+/// Encodes the CommandResponse in the format of the HTTP command
+fn command_response(
+    mut response: HttpResponseBuilder,
+    cmd: &CommandResponse,
+    format: WireFormat,
+) -> HttpResponse {
+    match format.encode(cmd) {
+        Ok(buf) => response.content_type(format.content_type()).body(buf),
+        Err(err) => HttpResponse::InternalServerError().body(format!(
+            "Unexpected error while encoding CommandResponse to {format:?}: {err}"
+        )),
+    }
+}
+
+fn identity_error_response(err: IdentityError, format: WireFormat) -> HttpResponse {
+    let mut response = match err {
+        IdentityError::ProviderDisabled => HttpResponse::NotFound(),
+        IdentityError::Provider(_) => HttpResponse::BadGateway(),
+        IdentityError::SigningFailed => HttpResponse::InternalServerError(),
+        _ => HttpResponse::Unauthorized(),
+    };
+
+    match create_error_response(err, format) {
+        Err(err) => HttpResponse::InternalServerError().body(err),
+        Ok(buf) => response.body(buf),
+    }
+}
+
+/// Provider redirect URI, it must be registered in the provider OAuth client
+fn identity_redirect_uri(provider: IdentityProvider, req: &HttpRequest) -> Option<String> {
+    let base_url = config::get().oauth_redirect_base_url.clone()?;
+    let version = req.app_data::<ApiVersion>()?;
+
+    Some(format!(
+        "{}{}/auth/{}/callback",
+        base_url.trim_end_matches('/'),
+        version.prefix(),
+        provider.as_str()
+    ))
+}
+
+/// Redirects the user to the identity provider login page
+#[get("/auth/{provider}/login")]
+pub async fn identity_login(
+    req: HttpRequest,
+    provider: web::Path<IdentityProvider>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let provider = provider.into_inner();
+    let config = config::get();
+
+    let (Some(client), Some(redirect_uri)) = (
+        config.oauth_clients.get(&provider),
+        identity_redirect_uri(provider, &req),
+    ) else {
+        return identity_error_response(IdentityError::ProviderDisabled, WireFormat::Protobuf);
+    };
+
+    let now = sharify_state.read().await.clock().utc_now();
+    let login = match identity::login_state(provider, now) {
+        Ok(login) => login,
+        Err(err) => return identity_error_response(err, WireFormat::Protobuf),
+    };
+    // Lax so it's sent along with the provider's redirection to the callback
+    let nonce_cookie = Cookie::build(identity::LOGIN_NONCE_COOKIE, login.nonce)
+        .path("/")
+        .http_only(true)
+        .secure(config.is_prod)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(
+            identity::LOGIN_STATE_TTL.as_secs() as _
+        ))
+        .finish();
+
+    HttpResponse::Found()
+        .insert_header((
+            header::LOCATION,
+            identity::authorize_url(provider, client, &redirect_uri, &login.state),
+        ))
+        .cookie(nonce_cookie)
+        .finish()
+}
+
+/// Issues the identity token of the provider account, its tokens are never stored
+#[get("/auth/{provider}/callback")]
+pub async fn identity_callback(
+    req: HttpRequest,
+    provider: web::Path<IdentityProvider>,
+    query: web::Query<OAuthCallbackQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let provider = provider.into_inner();
+    let config = config::get();
+
+    let (Some(client), Some(redirect_uri)) = (
+        config.oauth_clients.get(&provider),
+        identity_redirect_uri(provider, &req),
+    ) else {
+        return identity_error_response(IdentityError::ProviderDisabled, WireFormat::Protobuf);
+    };
+
+    let clock = Arc::clone(sharify_state.read().await.clock());
+    let nonce_cookie = req.cookie(identity::LOGIN_NONCE_COOKIE);
+
+    if let Err(err) = identity::verify_login_state(
+        provider,
+        &query.state,
+        nonce_cookie.as_ref().map(Cookie::value),
+        clock.utc_now(),
+    ) {
+        return identity_error_response(err, WireFormat::Protobuf);
+    }
+
+    // The state is used once
+    let mut nonce_removal = Cookie::build(identity::LOGIN_NONCE_COOKIE, "")
+        .path("/")
+        .finish();
+
+    nonce_removal.make_removal();
+
+    let identity =
+        match identity::fetch_identity(provider, client, &redirect_uri, &query.code).await {
+            Ok(identity) => identity,
+            Err(err) => return identity_error_response(err, WireFormat::Protobuf),
+        };
+
+    let user_id = identity.user_id();
+    let (identity_token, expires_at) =
+        match identity::issue_identity_token(&user_id, clock.utc_now()) {
+            Ok(token) => token,
+            Err(err) => return identity_error_response(err, WireFormat::Protobuf),
+        };
+
+    if let Some(frontend_url) = &config.oauth_frontend_url {
+        return HttpResponse::Found()
+            .insert_header((
+                header::LOCATION,
+                format!(
+                    "{frontend_url}#identity_token={}&user_id={}",
+                    urlencoding::encode(&identity_token),
+                    urlencoding::encode(&user_id)
+                ),
+            ))
+            .cookie(nonce_removal)
+            .finish();
+    }
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::IdentitySession(
+            command_response::IdentitySession {
+                identity_token,
+                user_id,
+                expires_at: Some(crate::proto::Timestamp {
+                    seconds: expires_at.timestamp(),
+                    nanos: expires_at.timestamp_subsec_nanos() as _,
+                }),
+            },
+        )),
+        ..Default::default()
+    };
+
+    let mut buf = Vec::new();
+    if let Err(err) = cmd.encode(&mut buf) {
+        return HttpResponse::InternalServerError().body(format!(
+            "Unexpected error while encoding IdentitySession to protobuf command: {err}"
+        ));
+    }
+
+    HttpResponse::Ok().cookie(nonce_removal).body(buf)
+}
+
+/// IP of the client, for the per client throttles, the one forwarded by the proxy when it's
+/// trusted (see Config::trusted_proxy)
+fn client_ip(req: &HttpRequest) -> IpAddr {
+    let forwarded = config::get()
+        .trusted_proxy
+        .then(|| {
+            let conn = req.connection_info();
+            let addr = conn.realip_remote_addr()?;
+
+            addr.parse::<IpAddr>()
+                .or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()
+        })
+        .flatten();
+
+    forwarded.unwrap_or_else(|| {
+        req.peer_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(Ipv4Addr::UNSPECIFIED.into())
+    })
+}
+
+/// Spotify redirect URI, it must be registered in the Spotify app
+fn spotify_redirect_uri(req: &HttpRequest) -> Option<String> {
+    let base_url = config::get().oauth_redirect_base_url.clone()?;
+    let version = req.app_data::<ApiVersion>()?;
+
+    Some(format!(
+        "{}{}/oauth/callback",
+        base_url.trim_end_matches('/'),
+        version.prefix()
+    ))
+}
+
+/// Redirects the room host (or a listener with ?listener=true) to the Spotify login page, the
+/// PKCE code verifier stays on the server
+#[get("/oauth/login")]
+pub async fn spotify_login(
+    req: HttpRequest,
+    query: web::Query<SpotifyLoginQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let Some(redirect_uri) = spotify_redirect_uri(&req) else {
+        return HttpResponse::ServiceUnavailable().body("The Spotify login is disabled");
+    };
+    let Some((state, verifier)) = sharify_state
+        .write()
+        .await
+        .begin_spotify_login(client_ip(&req))
+    else {
+        return HttpResponse::TooManyRequests().body("Too many Spotify logins in progress");
+    };
+
+    let scopes = match query.listener {
+        true => spotify::LISTENER_SPOTIFY_SCOPES,
+        false => spotify::SPOTIFY_SCOPES,
+    };
+
+    match spotify::Spotify::authorize_url(&state, &verifier, &redirect_uri, scopes) {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(err) => HttpResponse::InternalServerError().body(String::from(err)),
+    }
+}
+
+/// Exchanges the authorization code for the Spotify tokens and gives back a single use grant of
+/// CreateRoom (or RegisterListener), the tokens never reach the client
+#[get("/oauth/callback")]
+pub async fn spotify_callback(
+    req: HttpRequest,
+    query: web::Query<OAuthCallbackQuery>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let Some(redirect_uri) = spotify_redirect_uri(&req) else {
+        return HttpResponse::ServiceUnavailable().body("The Spotify login is disabled");
+    };
+    let Some(verifier) = sharify_state.write().await.take_spotify_login(&query.state) else {
+        return identity_error_response(IdentityError::InvalidState, WireFormat::Protobuf);
+    };
+
+    let base_urls = Arc::clone(sharify_state.read().await.spotify_base_urls());
+    let tokens =
+        match spotify::Spotify::exchange_code(&query.code, &verifier, &redirect_uri, base_urls)
+            .await
+        {
+            Ok(tokens) => tokens,
+            Err(err) => return HttpResponse::BadGateway().body(String::from(err)),
+        };
+
+    let (grant, expires_at) = sharify_state.write().await.store_spotify_grant(tokens);
+
+    if let Some(frontend_url) = &config::get().oauth_frontend_url {
+        return HttpResponse::Found()
+            .insert_header((
+                header::LOCATION,
+                format!(
+                    "{frontend_url}#spotify_grant={}",
+                    urlencoding::encode(&grant)
+                ),
+            ))
+            .finish();
+    }
+
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::SpotifyGrant(
+            command_response::SpotifyGrant {
+                grant,
+                expires_at: Some(crate::proto::Timestamp {
+                    seconds: expires_at.timestamp(),
+                    nanos: expires_at.timestamp_subsec_nanos() as _,
+                }),
+            },
+        )),
+        ..Default::default()
+    };
+
+    let mut buf = Vec::new();
+    if let Err(err) = cmd.encode(&mut buf) {
+        return HttpResponse::InternalServerError().body(format!(
+            "Unexpected error while encoding SpotifyGrant to protobuf command: {err}"
+        ));
+    }
+
+    HttpResponse::Ok().body(buf)
+}
+
+/// Stores the feedback/bug report, the Discord webhook (if set) is notified in the background,
+/// see discord::init_webhook_queue
+#[post("/webhook")]
 pub async fn send_discord_webhook(
+    req: HttpRequest,
     web::Json(payload): web::Json<discord::SendWebhookPayload>,
+    feedback_store: web::Data<Arc<FeedbackStore>>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
 ) -> impl Responder {
-    if let Err(err) = discord::send_webhook(payload.wh_type, payload.content).await {
-        return HttpResponse::InternalServerError().body(err);
+    let (now, utc_now) = {
+        let clock = Arc::clone(sharify_state.read().await.clock());
+
+        (clock.now(), clock.utc_now())
+    };
+    let webhook = match discord::Webhook::try_from(payload)
+        .and_then(|webhook| discord::admit(client_ip(&req), &webhook, now).map(|_| webhook))
+    {
+        Ok(webhook) => webhook,
+        // Not told apart so the clients cannot probe what has been sent
+        Err(discord::WebhookError::Duplicate) => return HttpResponse::Accepted().finish(),
+        Err(err @ discord::WebhookError::Throttled) => {
+            return HttpResponse::TooManyRequests().body(String::from(err));
+        }
+        Err(err) => return HttpResponse::BadRequest().body(String::from(err)),
+    };
+
+    let store = Arc::clone(&feedback_store);
+    let submission = webhook.clone();
+    let stored = web::block(move || store.insert(&submission, utc_now)).await;
+
+    if let Err(err) = stored.map_err(io::Error::other).and_then(|stored| stored) {
+        error!("Failed to store a {}: {err}", webhook.wh_type);
+
+        return HttpResponse::InternalServerError().body("Failed to store the submission");
+    }
+
+    match discord::enqueue(webhook) {
+        Ok(()) | Err(discord::WebhookError::NotConfigured) => {}
+        Err(err) => warn!(
+            "Discord not notified of a submission: {}",
+            String::from(err)
+        ),
+    }
+
+    HttpResponse::Accepted().finish()
+}
+
+/// Token of the Authorization: Bearer header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Checks the ADMIN_TOKEN Bearer token, the /admin routes are hidden (404) when it's unset
+fn check_admin_token(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let Some(admin_token) = config::get().admin_token.clone() else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+
+    // Constant time comparison so the token cannot be guessed byte per byte
+    let is_authorized = bearer_token(req).is_some_and(|token| {
+        token.len() == admin_token.len() && memcmp::eq(token.as_bytes(), admin_token.as_bytes())
+    });
+
+    if !is_authorized {
+        return Err(HttpResponse::Unauthorized().finish());
     }
 
-    HttpResponse::Ok().finish()
+    Ok(())
+}
+
+/// Same as sending SIGHUP to the process, requires the ADMIN_TOKEN as Bearer token
+#[post("/admin/config/reload")]
+pub async fn reload_config(req: HttpRequest) -> impl Responder {
+    if let Err(response) = check_admin_token(&req) {
+        return response;
+    }
+
+    HttpResponse::Ok().json(config::reload())
+}
+
+/// Integrity sweeper counters, growing orphan counts mean a cleanup path is missed somewhere
+#[get("/admin/metrics/sweeper")]
+pub async fn sweeper_metrics(req: HttpRequest) -> impl Responder {
+    if let Err(response) = check_admin_token(&req) {
+        return response;
+    }
+
+    HttpResponse::Ok().json(sharify::sweeper::METRICS.snapshot())
+}
+
+/// Guards the /admin/v1 scope with the same ADMIN_TOKEN check as the other /admin routes
+pub async fn require_admin_token<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    if let Err(response) = check_admin_token(req.request()) {
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Operators API served under /admin/v1, plain JSON unlike the user-facing protobuf API
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.service(admin_rooms)
+        .service(admin_close_room)
+        .service(admin_kick_user)
+        .service(admin_rate_limits)
+        .service(admin_smoke_test)
+        .service(admin_feedback)
+        .service(admin_ack_feedback);
+
+    #[cfg(feature = "graphql")]
+    cfg.route("/graphql", web::post().to(crate::graphql::handler));
+}
+
+#[get("/rooms")]
+pub async fn admin_rooms(sharify_state: web::Data<Arc<RwLock<RoomManager>>>) -> impl Responder {
+    HttpResponse::Ok().json(sharify_state.read().await.admin_room_summaries())
+}
+
+/// Closes the WS sessions of the room then deletes it, like an inactive room
+#[delete("/rooms/{room_id}")]
+pub async fn admin_close_room(
+    room_id: web::Path<Uuid>,
+    ws_mgr: web::Data<Arc<RwLock<SharifyWsManager>>>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let room_id = room_id.into_inner();
+
+    if sharify_state.read().await.get_room(&room_id).is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    warn!("Room ID {room_id} is being closed by an operator");
+
+    websocket::SharifyWsInstance::close_room(
+        Arc::clone(&ws_mgr),
+        Arc::clone(&sharify_state),
+        room_id,
+        WsCloseCode::ClosedByOperator.reason("The room has been closed by an operator"),
+    )
+    .await;
+
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct AdminKickQuery {
+    #[serde(default = "default_admin_kick_reason")]
+    reason: String,
+}
+
+fn default_admin_kick_reason() -> String {
+    "Kicked by an operator".into()
+}
+
+#[delete("/rooms/{room_id}/users/{user_id}")]
+pub async fn admin_kick_user(
+    path: web::Path<(Uuid, String)>,
+    query: web::Query<AdminKickQuery>,
+    ws_mgr: web::Data<Arc<RwLock<SharifyWsManager>>>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let (room_id, user_id) = path.into_inner();
+
+    match websocket::SharifyWsInstance::admin_kick_user(
+        Arc::clone(&ws_mgr),
+        Arc::clone(&sharify_state),
+        room_id,
+        &user_id,
+        query.into_inner().reason,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(RoomError::RoomNotFound | RoomError::RoomUserNotFound) => {
+            HttpResponse::NotFound().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{err:?}")),
+    }
+}
+
+#[derive(Serialize)]
+struct AdminRateLimit {
+    room_id: RoomID,
+    #[serde(flatten)]
+    state: spotify::RateLimiterState,
+}
+
+/// Spotify rate limiter of each room, a room that stays rate limited spams the Spotify API
+#[get("/rate-limits")]
+pub async fn admin_rate_limits(
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let rate_limiters = sharify_state.read().await.rate_limiters();
+
+    let mut rate_limits = Vec::with_capacity(rate_limiters.len());
+    for (room_id, rate_limiter) in rate_limiters {
+        rate_limits.push(AdminRateLimit {
+            room_id,
+            state: rate_limiter.read().await.state(),
+        });
+    }
+
+    rate_limits.sort_unstable_by_key(|rate_limit| rate_limit.room_id);
+
+    HttpResponse::Ok().json(rate_limits)
+}
+
+/// Deep health check to run after deploys, see sharify::smoke, 500 when a step fails
+#[post("/smoke")]
+pub async fn admin_smoke_test(
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let report = sharify::smoke::run(Some(Arc::clone(&sharify_state))).await;
+
+    if report.passed {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::InternalServerError().json(report)
+    }
+}
+
+#[derive(Deserialize)]
+struct AdminFeedbackQuery {
+    status: Option<FeedbackStatus>,
+}
+
+/// Stored feedback/bug reports, newest first
+#[get("/feedback")]
+pub async fn admin_feedback(
+    query: web::Query<AdminFeedbackQuery>,
+    feedback_store: web::Data<Arc<FeedbackStore>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(feedback_store.list(query.status))
+}
+
+#[post("/feedback/{id}/ack")]
+pub async fn admin_ack_feedback(
+    id: web::Path<Uuid>,
+    feedback_store: web::Data<Arc<FeedbackStore>>,
+) -> impl Responder {
+    let store = Arc::clone(&feedback_store);
+    let id = id.into_inner();
+
+    match web::block(move || store.acknowledge(id))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|acknowledged| acknowledged)
+    {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            HttpResponse::InternalServerError().body(format!("Failed to store the status: {err}"))
+        }
+    }
+}
+
+/// Validates the signature and expiry of the /spectate/{room_id} and /{room_id}/events URLs
+/// generated by the room owner(s) with the CreateSignedUrl command
+pub async fn verify_signed_url<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let room_id = req.match_info().query("room_id").parse::<Uuid>();
+    let query = web::Query::<SignedUrlQuery>::from_query(req.query_string());
+    let sharify_state = req
+        .app_data::<web::Data<Arc<RwLock<RoomManager>>>>()
+        .cloned();
+
+    let (Ok(room_id), Ok(query), Some(sharify_state)) = (room_id, query, sharify_state) else {
+        return Ok(req
+            .into_response(HttpResponse::Unauthorized().finish())
+            .map_into_right_body());
+    };
+
+    let verification = {
+        let state_guard = sharify_state.read().await;
+
+        state_guard.get_room(&room_id).map(|room| {
+            room.signing_secret.verify(
+                room_id,
+                query.expires,
+                &query.sig,
+                state_guard.clock().utc_now(),
+            )
+        })
+    };
+
+    let response = match verification {
+        Some(Ok(())) => {
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+        None => HttpResponse::NotFound().finish(),
+        Some(Err(SignedUrlError::Expired)) => HttpResponse::Gone().body("This URL has expired"),
+        Some(Err(SignedUrlError::InvalidSignature)) => HttpResponse::Unauthorized().finish(),
+        Some(Err(SignedUrlError::SigningFailed)) => HttpResponse::InternalServerError().finish(),
+    };
+
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Public (signed URL) current playback of a room, served from the last fetched state
+#[get("/now-playing")]
+pub async fn spectate_now_playing(
+    room_id: web::Path<Uuid>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let state_guard = sharify_state.read().await;
+
+    let Some(room) = state_guard.get_room(&room_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let Some(playback) = room.now_playing.clone() else {
+        return HttpResponse::NoContent().finish();
+    };
+
+    HttpResponse::Ok().json(events::NowPlaying::from(playback))
+}
+
+/// Display projection of the room events for the consumers that can't deal with WS nor
+/// protobuf, served with the signed spectator URLs. Starts with the current playback and queue
+#[get("/events")]
+pub async fn spectate_events(
+    room_id: web::Path<Uuid>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let room_id = room_id.into_inner();
+
+    let (rx, snapshots) = {
+        let state_guard = sharify_state.read().await;
+
+        let Some(room) = state_guard.get_room(&room_id) else {
+            return HttpResponse::NotFound().finish();
+        };
+
+        // Subscribed before the snapshot so no update is missed in between
+        let Some(rx) = events::subscribe(room_id, events::EventProjection::Display) else {
+            return HttpResponse::TooManyRequests().body("Too many event streams in this room");
+        };
+
+        (
+            rx,
+            [
+                events::now_playing_event(room.now_playing.clone().map(Into::into)),
+                events::queue_event(&room.tracks_queue.iter().map(Into::into).collect::<Vec<_>>()),
+            ],
+        )
+    };
+
+    event_stream(snapshots.into_iter().flatten().collect(), rx)
+}
+
+/// Read-only SSE stream of the room and playback updates for display-only clients, it doesn't
+/// create a RoomUser. Starts with the current room state then forwards the room broadcasts
+pub async fn room_events(
+    room_id: web::Path<Uuid>,
+    sharify_state: web::Data<Arc<RwLock<RoomManager>>>,
+) -> impl Responder {
+    let room_id = room_id.into_inner();
+
+    let (rx, snapshots) = {
+        let state_guard = sharify_state.read().await;
+
+        let Some(room) = state_guard.get_room(&room_id) else {
+            return HttpResponse::NotFound().finish();
+        };
+
+        // Subscribed before the snapshot so no update is missed in between
+        let Some(rx) = events::subscribe(room_id, events::EventProjection::Full) else {
+            return HttpResponse::TooManyRequests().body("Too many event streams in this room");
+        };
+
+        (
+            rx,
+            [
+                command_response::Type::Room(room.clone().into()),
+                command_response::Type::SpotifyPlaybackState(
+                    command_response::SpotifyPlaybackState {
+                        state: room.now_playing.clone().map(Into::into),
+                    },
+                ),
+            ],
+        )
+    };
+
+    let snapshots = snapshots
+        .into_iter()
+        .filter_map(|r#type| {
+            let (name, cmd) = events::public_event(CommandResponse {
+                r#type: Some(r#type),
+                ..Default::default()
+            })?;
+
+            events::sse_event(name, &cmd)
+        })
+        .collect();
+
+    event_stream(snapshots, rx)
+}
+
+/// SSE response sending the snapshots then the room events until the room is closed
+fn event_stream(snapshots: Vec<web::Bytes>, rx: mpsc::Receiver<web::Bytes>) -> HttpResponse {
+    let updates = stream::unfold(rx, |mut rx| async move {
+        let event = match time::timeout(events::EVENTS_KEEPALIVE_INTERVAL, rx.recv()).await {
+            Ok(Some(event)) => event,
+            // The room was closed
+            Ok(None) => return None,
+            Err(_) => web::Bytes::from_static(b": keepalive\n\n"),
+        };
+
+        Some((Ok(event), rx))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        // Skips the Compress middleware, it would buffer the events
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(
+            stream::iter(snapshots)
+                .map(Ok::<_, actix_web::Error>)
+                .chain(updates),
+        )
 }
 
-#[get("/v1/code_verifier")]
+#[get("/code_verifier")]
 pub async fn code_verifier() -> impl Responder {
-    HttpResponse::Ok().body(sharify::utils::generate_code_verifier())
+    HttpResponse::Ok().body(sharify::utils::generate_code_verifier(
+        &sharify::random::SystemRandom,
+    ))
 }
 
-#[get("/v1/code_challenge/{code_verifier}")]
+#[get("/code_challenge/{code_verifier}")]
 pub async fn code_challenge(data: web::Path<String>) -> impl Responder {
     let _code_verifier = data.into_inner();
     HttpResponse::Ok().body(sharify::utils::generate_code_challenge(_code_verifier))
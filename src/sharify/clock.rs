@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+/// Every time-based logic (heartbeats, inactivity, grace periods, rate limiting...) must get
+/// the time from a Clock so it can be driven by a MockClock in tests instead of sleeping
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> Instant;
+    fn utc_now(&self) -> DateTime<Utc>;
+
+    fn elapsed_since(&self, instant: Instant) -> Duration {
+        self.now().saturating_duration_since(instant)
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod mock {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use super::Clock;
+
+    /// Frozen clock that only moves forward when advanced
+    #[derive(Debug)]
+    pub struct MockClock {
+        start: Instant,
+        start_utc: DateTime<Utc>,
+        offset: Mutex<Duration>,
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self {
+                start: Instant::now(),
+                start_utc: Utc::now(),
+                offset: Mutex::new(Duration::ZERO),
+            }
+        }
+    }
+
+    impl MockClock {
+        pub fn advance(&self, duration: Duration) {
+            *self.offset.lock().unwrap() += duration;
+        }
+
+        fn offset(&self) -> Duration {
+            *self.offset.lock().unwrap()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + self.offset()
+        }
+
+        fn utc_now(&self) -> DateTime<Utc> {
+            self.start_utc + TimeDelta::from_std(self.offset()).unwrap_or_default()
+        }
+    }
+}
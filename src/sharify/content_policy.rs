@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use super::spotify::web_utils::{PlaybackItemType, SearchType, SpotifyTrack};
+
+/// Kind of Spotify content the server content policy can deny
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Track,
+    /// Podcast episode
+    Episode,
+    /// Local file of the host, it has no Spotify ID
+    Local,
+    Album,
+    Artist,
+    Playlist,
+}
+
+impl ContentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Episode => "episode",
+            Self::Local => "local",
+            Self::Album => "album",
+            Self::Artist => "artist",
+            Self::Playlist => "playlist",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "track" => Some(Self::Track),
+            "episode" => Some(Self::Episode),
+            "local" => Some(Self::Local),
+            "album" => Some(Self::Album),
+            "artist" => Some(Self::Artist),
+            "playlist" => Some(Self::Playlist),
+            _ => None,
+        }
+    }
+
+    /// Type of a queued track ID, only tracks and local files can be queued
+    pub fn of_track_id(track_id: &str) -> Self {
+        if track_id.starts_with("spotify:local:") {
+            Self::Local
+        } else {
+            Self::Track
+        }
+    }
+
+    pub fn of_track(track: &SpotifyTrack) -> Self {
+        Self::of_item(&track.track_id, track.item_type)
+    }
+
+    pub fn of_item(track_id: &str, item_type: PlaybackItemType) -> Self {
+        match item_type {
+            PlaybackItemType::Episode => Self::Episode,
+            PlaybackItemType::Track => Self::of_track_id(track_id),
+        }
+    }
+}
+
+impl From<SearchType> for ContentType {
+    fn from(search_type: SearchType) -> Self {
+        match search_type {
+            SearchType::Track => Self::Track,
+            SearchType::Album => Self::Album,
+            SearchType::Artist => Self::Artist,
+            SearchType::Playlist => Self::Playlist,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContentPolicyError {
+    TypeDenied(ContentType),
+    /// Market of the room host's Spotify account
+    MarketDenied(String),
+}
+
+/// Server-wide deny-list enforced on every room when searching, queuing and importing
+/// playlists, it's also exposed in /healthz
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ContentPolicy {
+    pub denied_types: BTreeSet<ContentType>,
+    /// ISO 3166-1 alpha-2 countries, uppercase
+    pub denied_markets: BTreeSet<String>,
+}
+
+impl ContentPolicy {
+    /// Comma-separated lists, unknown types and blank entries are ignored
+    pub fn parse(denied_types: &str, denied_markets: &str) -> Self {
+        Self {
+            denied_types: denied_types
+                .split(',')
+                .filter_map(|s| ContentType::parse(&s.trim().to_lowercase()))
+                .collect(),
+            denied_markets: denied_markets
+                .split(',')
+                .map(|s| s.trim().to_uppercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn check_type(&self, content_type: ContentType) -> Result<(), ContentPolicyError> {
+        if self.denied_types.contains(&content_type) {
+            return Err(ContentPolicyError::TypeDenied(content_type));
+        }
+
+        Ok(())
+    }
+
+    /// An unknown market (missing user-read-private scope) is allowed
+    pub fn check_market(&self, market: Option<&str>) -> Result<(), ContentPolicyError> {
+        match market {
+            Some(market) if self.denied_markets.contains(&market.to_uppercase()) => {
+                Err(ContentPolicyError::MarketDenied(market.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the denied episodes and local files from the results (playlists, track search)
+    pub fn filter_tracks(&self, tracks: &mut Vec<SpotifyTrack>) {
+        if self.denied_types.is_empty() {
+            return;
+        }
+
+        tracks.retain(|track| self.check_type(ContentType::of_track(track)).is_ok());
+    }
+}
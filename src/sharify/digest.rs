@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_rt::time;
+use chrono::NaiveDate;
+use tokio::sync::RwLock;
+
+use super::room::{RoomID, RoomUserID};
+use super::room_manager::RoomManager;
+use super::room_metadata::RoomStats;
+use super::tasks::spawn_room_task;
+use crate::discord::{self, Webhook, WebhookType};
+
+/// Granularity of the digests schedule
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Daily activity summary of a room, see RoomSettings.daily_digest
+#[derive(Debug)]
+pub struct RoomDigest {
+    pub room_id: RoomID,
+    pub room_name: String,
+    /// Local date of the room schedule
+    pub date: NaiveDate,
+    pub stats: RoomStats,
+}
+
+impl RoomDigest {
+    pub fn to_webhook_content(&self) -> String {
+        format!(
+            "**{}** ({}) - {}\nTracks played: {}\nUnique listeners: {}\nPeak concurrency: {}",
+            self.room_name,
+            self.room_id,
+            self.date,
+            self.stats.tracks_played,
+            self.stats.listeners.len(),
+            self.stats.peak_concurrency,
+        )
+    }
+}
+
+/// Member who queued the most tracks of the session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopContributor {
+    pub user_id: RoomUserID,
+    /// None when the member left before the end of the session
+    pub username: Option<String>,
+    pub tracks_queued: u32,
+}
+
+/// Recap of a room sent to its members when it closes, and to the webhook if opted in (see
+/// RoomSettings.session_summary_webhook)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub room_id: RoomID,
+    pub room_name: String,
+    pub duration: Duration,
+    pub tracks_played: u32,
+    pub top_contributor: Option<TopContributor>,
+}
+
+impl SessionSummary {
+    pub fn to_webhook_content(&self) -> String {
+        let mins = self.duration.as_secs() / 60;
+
+        format!(
+            "**{}** ({}) closed\nDuration: {}h{:02}\nTracks played: {}\nTop contributor: {}",
+            self.room_name,
+            self.room_id,
+            mins / 60,
+            mins % 60,
+            self.tracks_played,
+            match &self.top_contributor {
+                Some(top) => format!(
+                    "{} ({} tracks queued)",
+                    top.username.as_deref().unwrap_or(&top.user_id),
+                    top.tracks_queued
+                ),
+                None => "nobody".into(),
+            },
+        )
+    }
+}
+
+pub fn send_session_summary(summary: &SessionSummary) {
+    let mut webhook = Webhook::new(WebhookType::SessionSummary, summary.to_webhook_content());
+    webhook.room_id = Some(summary.room_id);
+
+    if let Err(err) = discord::enqueue(webhook) {
+        error!(
+            "Failed to queue the session summary of room {}: {}",
+            summary.room_id,
+            String::from(err)
+        );
+    }
+}
+
+/// Sends the due daily digests of the opted-in rooms to the Discord webhook
+pub fn init_daily_digest_scheduler(state_mgr: Arc<RwLock<RoomManager>>) {
+    spawn_room_task(async move {
+        loop {
+            time::sleep(DIGEST_CHECK_INTERVAL).await;
+
+            let digests = state_mgr.write().await.take_due_digests();
+
+            for digest in digests {
+                let mut webhook =
+                    Webhook::new(WebhookType::RoomDigest, digest.to_webhook_content());
+                webhook.room_id = Some(digest.room_id);
+
+                if let Err(err) = discord::enqueue(webhook) {
+                    error!(
+                        "Failed to queue the daily digest of room {}: {}",
+                        digest.room_id,
+                        String::from(err)
+                    );
+                }
+            }
+        }
+    });
+}
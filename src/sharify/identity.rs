@@ -0,0 +1,421 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::RngCore as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::room::{RoomID, RoomUserID};
+use crate::config;
+
+pub const IDENTITY_TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+pub const SESSION_TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+/// Time the user has to log in with the provider before the OAuth state expires
+pub const LOGIN_STATE_TTL: Duration = Duration::from_secs(60 * 10);
+const NONCE_LEN: usize = 16;
+/// Cookie binding the OAuth state to the browser that started the login, see login_state
+pub const LOGIN_NONCE_COOKIE: &str = "sharify_login_nonce";
+
+/// HMAC key of the identity tokens, session tokens and OAuth states, random (so every token is
+/// revoked on restart) unless IDENTITY_SECRET is set
+static IDENTITY_SECRET: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    config::get()
+        .identity_secret
+        .clone()
+        .map(String::into_bytes)
+        .unwrap_or_else(|| {
+            let mut secret = vec![0; 32];
+
+            rand::rng().fill_bytes(&mut secret);
+
+            secret
+        })
+});
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .user_agent("sharify-be")
+        .build()
+        .expect("Failed to build the identity providers HTTP client")
+});
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IdentityError {
+    /// No OAuth client is configured for the provider
+    ProviderDisabled,
+    InvalidState,
+    InvalidToken,
+    Expired,
+    /// The identity token is missing while IDENTITY_REQUIRED is set
+    Unauthenticated,
+    /// The session token returned by CreateRoom/JoinRoom is missing
+    SessionRequired,
+    Provider(String),
+    SigningFailed,
+}
+
+impl From<IdentityError> for String {
+    fn from(err: IdentityError) -> Self {
+        match err {
+            IdentityError::ProviderDisabled => "This identity provider is disabled".into(),
+            IdentityError::InvalidState => "Invalid or expired login state".into(),
+            IdentityError::InvalidToken => "Invalid identity token".into(),
+            IdentityError::Expired => "The identity token has expired".into(),
+            IdentityError::Unauthenticated => "An identity token is required".into(),
+            IdentityError::SessionRequired => "A session token is required".into(),
+            IdentityError::Provider(err) => format!("Identity provider error: {err}"),
+            IdentityError::SigningFailed => "Failed to sign the identity token".into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityProvider {
+    Google,
+    GitHub,
+}
+
+impl IdentityProvider {
+    pub const ALL: [Self; 2] = [Self::Google, Self::GitHub];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Google => "google",
+            Self::GitHub => "github",
+        }
+    }
+
+    fn authorize_url(&self) -> &'static str {
+        match self {
+            Self::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            Self::GitHub => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_url(&self) -> &'static str {
+        match self {
+            Self::Google => "https://oauth2.googleapis.com/token",
+            Self::GitHub => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    fn user_url(&self) -> &'static str {
+        match self {
+            Self::Google => "https://openidconnect.googleapis.com/v1/userinfo",
+            Self::GitHub => "https://api.github.com/user",
+        }
+    }
+
+    /// Only the subject ID is needed
+    fn scope(&self) -> &'static str {
+        match self {
+            Self::Google => "openid",
+            Self::GitHub => "read:user",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl std::fmt::Debug for OAuthClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuthClient")
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Account of a user at an identity provider, the subject is its stable ID there
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub provider: IdentityProvider,
+    pub subject: String,
+}
+
+impl Identity {
+    /// Stable RoomUserID of the account, in the same colon-separated hex format as the email
+    /// based ones
+    pub fn user_id(&self) -> RoomUserID {
+        let hash = Sha256::digest(format!("{}:{}", self.provider.as_str(), self.subject));
+
+        hash.chunks(2)
+            .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct GoogleUser {
+    sub: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: u64,
+}
+
+fn sign(payload: &str) -> Result<String, IdentityError> {
+    let key = PKey::hmac(&IDENTITY_SECRET).map_err(|_| IdentityError::SigningFailed)?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).map_err(|_| IdentityError::SigningFailed)?;
+
+    signer
+        .update(payload.as_bytes())
+        .map_err(|_| IdentityError::SigningFailed)?;
+
+    let signature = signer
+        .sign_to_vec()
+        .map_err(|_| IdentityError::SigningFailed)?;
+
+    Ok(URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// Constant time comparison so the signature cannot be guessed byte per byte
+fn verify_signature(payload: &str, signature: &str) -> Result<(), IdentityError> {
+    let expected = sign(payload)?;
+
+    if expected.len() != signature.len() || !memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+        return Err(IdentityError::InvalidToken);
+    }
+
+    Ok(())
+}
+
+/// Signed "{user_id}.{expires}" proving that the user logged in with an identity provider
+pub fn issue_identity_token(
+    user_id: &RoomUserID,
+    now: DateTime<Utc>,
+) -> Result<(String, DateTime<Utc>), IdentityError> {
+    let expires_at = now + IDENTITY_TOKEN_TTL;
+    let payload = format!("{user_id}.{}", expires_at.timestamp());
+
+    Ok((format!("{payload}.{}", sign(&payload)?), expires_at))
+}
+
+/// Returns the RoomUserID the token was issued for
+pub fn verify_identity_token(token: &str, now: DateTime<Utc>) -> Result<RoomUserID, IdentityError> {
+    let Some((payload, signature)) = token.rsplit_once('.') else {
+        return Err(IdentityError::InvalidToken);
+    };
+    let Some((user_id, expires)) = payload
+        .split_once('.')
+        .and_then(|(user_id, expires)| Some((user_id, expires.parse::<i64>().ok()?)))
+    else {
+        return Err(IdentityError::InvalidToken);
+    };
+
+    verify_signature(payload, signature)?;
+
+    if now.timestamp() >= expires {
+        return Err(IdentityError::Expired);
+    }
+
+    Ok(user_id.to_owned())
+}
+
+/// The body/path user ID is only trusted when no identity provider is configured and no
+/// identity is required, otherwise the caller is identified by its identity token only
+pub fn resolve_user_id(
+    user_id: RoomUserID,
+    identity_token: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<RoomUserID, IdentityError> {
+    match identity_token {
+        Some(token) => verify_identity_token(token, now),
+        None if !trusts_client_user_ids(&config::get()) => Err(IdentityError::Unauthenticated),
+        None => Ok(user_id),
+    }
+}
+
+pub fn trusts_client_user_ids(config: &config::Config) -> bool {
+    !config.identity_required && config.oauth_clients.is_empty()
+}
+
+/// Signed "{room_id}.{user_id}.{expires}" returned by CreateRoom and JoinRoom, it proves that
+/// the holder is the room user (its ID alone is known by every member)
+pub fn issue_session_token(
+    room_id: RoomID,
+    user_id: &RoomUserID,
+    now: DateTime<Utc>,
+) -> Result<String, IdentityError> {
+    let payload = format!(
+        "{room_id}.{user_id}.{}",
+        (now + SESSION_TOKEN_TTL).timestamp()
+    );
+    // Prefixed so an identity token signature can't be reused as a session one
+    let signature = sign(&format!("session.{payload}"))?;
+
+    Ok(format!("{payload}.{signature}"))
+}
+
+/// Returns the RoomUserID the token was issued for in this room
+pub fn verify_session_token(
+    token: &str,
+    room_id: RoomID,
+    now: DateTime<Utc>,
+) -> Result<RoomUserID, IdentityError> {
+    let Some((payload, signature)) = token.rsplit_once('.') else {
+        return Err(IdentityError::InvalidToken);
+    };
+    let Some((user_id, expires)) = payload
+        .strip_prefix(&format!("{room_id}."))
+        .and_then(|rest| rest.rsplit_once('.'))
+        .and_then(|(user_id, expires)| Some((user_id, expires.parse::<i64>().ok()?)))
+    else {
+        return Err(IdentityError::InvalidToken);
+    };
+
+    verify_signature(&format!("session.{payload}"), signature)?;
+
+    if now.timestamp() >= expires {
+        return Err(IdentityError::Expired);
+    }
+
+    Ok(user_id.to_owned())
+}
+
+/// OAuth state of a login, its nonce is set as the LOGIN_NONCE_COOKIE of the browser
+#[derive(Debug)]
+pub struct LoginState {
+    pub state: String,
+    pub nonce: String,
+}
+
+/// Stateless CSRF protection of the OAuth callback: "{nonce}.{expires}.{signature}", only
+/// accepted from the browser holding the nonce cookie
+pub fn login_state(
+    provider: IdentityProvider,
+    now: DateTime<Utc>,
+) -> Result<LoginState, IdentityError> {
+    let mut nonce = [0; NONCE_LEN];
+
+    rand::rng().fill_bytes(&mut nonce);
+
+    let nonce = URL_SAFE_NO_PAD.encode(nonce);
+    let payload = format!("{nonce}.{}", (now + LOGIN_STATE_TTL).timestamp());
+    let signature = sign(&format!("{}.{payload}", provider.as_str()))?;
+
+    Ok(LoginState {
+        state: format!("{payload}.{signature}"),
+        nonce,
+    })
+}
+
+/// cookie_nonce is the LOGIN_NONCE_COOKIE sent along with the callback
+pub fn verify_login_state(
+    provider: IdentityProvider,
+    state: &str,
+    cookie_nonce: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<(), IdentityError> {
+    let Some((payload, signature)) = state.rsplit_once('.') else {
+        return Err(IdentityError::InvalidState);
+    };
+    let Some((nonce, expires)) = payload
+        .split_once('.')
+        .and_then(|(nonce, expires)| Some((nonce, expires.parse::<i64>().ok()?)))
+    else {
+        return Err(IdentityError::InvalidState);
+    };
+
+    verify_signature(&format!("{}.{payload}", provider.as_str()), signature)
+        .map_err(|_| IdentityError::InvalidState)?;
+
+    if now.timestamp() >= expires {
+        return Err(IdentityError::InvalidState);
+    }
+
+    // Issued to another browser
+    if !cookie_nonce.is_some_and(|cookie_nonce| {
+        cookie_nonce.len() == nonce.len() && memcmp::eq(cookie_nonce.as_bytes(), nonce.as_bytes())
+    }) {
+        return Err(IdentityError::InvalidState);
+    }
+
+    Ok(())
+}
+
+/// Provider login page the user is redirected to
+pub fn authorize_url(
+    provider: IdentityProvider,
+    client: &OAuthClient,
+    redirect_uri: &str,
+    state: &str,
+) -> String {
+    format!(
+        "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}",
+        provider.authorize_url(),
+        urlencoding::encode(&client.client_id),
+        urlencoding::encode(redirect_uri),
+        urlencoding::encode(provider.scope()),
+        urlencoding::encode(state),
+    )
+}
+
+/// Exchanges the authorization code for the account subject ID, the provider access token is
+/// dropped right after
+pub async fn fetch_identity(
+    provider: IdentityProvider,
+    client: &OAuthClient,
+    redirect_uri: &str,
+    code: &str,
+) -> Result<Identity, IdentityError> {
+    let provider_err = |err: reqwest::Error| IdentityError::Provider(err.to_string());
+
+    let TokenResponse { access_token } = HTTP_CLIENT
+        .post(provider.token_url())
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &client.client_id),
+            ("client_secret", &client.client_secret),
+        ])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(provider_err)?
+        .json()
+        .await
+        .map_err(provider_err)?;
+
+    let user = HTTP_CLIENT
+        .get(provider.user_url())
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(provider_err)?;
+
+    let subject = match provider {
+        IdentityProvider::Google => user.json::<GoogleUser>().await.map_err(provider_err)?.sub,
+        IdentityProvider::GitHub => user
+            .json::<GitHubUser>()
+            .await
+            .map_err(provider_err)?
+            .id
+            .to_string(),
+    };
+
+    Ok(Identity { provider, subject })
+}
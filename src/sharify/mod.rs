@@ -1,7 +1,22 @@
+pub mod clock;
+pub mod content_policy;
+pub mod digest;
+pub mod identity;
+pub mod music_provider;
+pub mod random;
+pub mod recommendation;
 pub mod role;
 pub mod room;
+pub mod room_events;
 pub mod room_manager;
 pub mod room_metadata;
+pub mod schedule;
+pub mod secret;
+pub mod signed_url;
+pub mod smoke;
 pub mod spotify;
+pub mod sweeper;
+pub mod tasks;
 pub mod utils;
+pub mod waitlist;
 pub mod websocket;
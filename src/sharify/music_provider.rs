@@ -0,0 +1,331 @@
+use async_trait::async_trait;
+
+use super::spotify::web_utils::{
+    PlaybackItemType, RepeatMode, SearchType, SpotifyCurrentPlaybackOutput, SpotifyDevice,
+    SpotifyPlaylist, SpotifySearchResults, SpotifyTrack,
+};
+use super::spotify::{Spotify, SpotifyError};
+
+/// The Spotify models are the common ones, another backend converts its own into them
+pub type Track = SpotifyTrack;
+pub type Tracks = Vec<Track>;
+pub type Playback = SpotifyCurrentPlaybackOutput;
+pub type Device = SpotifyDevice;
+pub type Playlist = SpotifyPlaylist;
+pub type SearchResults = SpotifySearchResults;
+
+/// Streaming service a room plays from, picked per room with RoomMetadata.provider
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProviderKind {
+    #[default]
+    Spotify,
+    /// See OfflineProvider
+    Offline,
+}
+
+/// Failed request to a streaming service, each backend converts its errors into it
+#[derive(Clone, Debug)]
+pub enum ProviderError {
+    Generic(String),
+    /// Retry after, in seconds
+    RateLimited(u64),
+    /// The tokens of the account are invalid or expired
+    Unauthorized(String),
+    /// The request is valid but not allowed for the account (e.g. no premium subscription)
+    Forbidden(String),
+}
+
+impl From<ProviderError> for String {
+    fn from(err: ProviderError) -> Self {
+        match err {
+            ProviderError::Generic(string)
+            | ProviderError::Unauthorized(string)
+            | ProviderError::Forbidden(string) => string,
+            ProviderError::RateLimited(time) => format!("Rate limited for {time}s"),
+        }
+    }
+}
+
+impl From<SpotifyError> for ProviderError {
+    fn from(err: SpotifyError) -> Self {
+        match err {
+            SpotifyError::Generic(string) => Self::Generic(string),
+            SpotifyError::RateLimited(time) => Self::RateLimited(time),
+            SpotifyError::Unauthorized(string) => Self::Unauthorized(string),
+            SpotifyError::Forbidden(string) => Self::Forbidden(string),
+        }
+    }
+}
+
+/// What the websocket layer needs from a streaming service to search, queue, control and follow
+/// the playback of a room, see Room::music_provider
+///
+/// The account tokens and the features only Spotify has (recommendations, markets, listeners,
+/// playlist exports...) remain on the Spotify handler
+#[async_trait]
+pub trait MusicProvider: std::fmt::Debug + Send + Sync {
+    async fn search_tracks(&self, query: String) -> Result<Tracks, ProviderError>;
+    /// Searches tracks only when no type is given, the limit applies to each type
+    async fn search(
+        &self,
+        query: String,
+        types: &[SearchType],
+        offset: u32,
+        limit: u32,
+    ) -> Result<SearchResults, ProviderError>;
+    async fn add_to_queue(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<(), ProviderError>;
+    /// The item as the service knows it, its duration and explicit flag are checked against the
+    /// room settings instead of the ones sent by the client
+    async fn get_item(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<Track, ProviderError>;
+    /// Upcoming tracks of the service queue
+    async fn next_tracks(&self) -> Result<Tracks, ProviderError>;
+    /// Most recent first
+    async fn recent_tracks(&self, len: u16) -> Result<Tracks, ProviderError>;
+    async fn now_playing(&self) -> Result<Option<Playback>, ProviderError>;
+
+    async fn play_resume(&self) -> Result<(), ProviderError>;
+    /// Replaces the base queue with the items
+    async fn play_items(&self, items: Vec<(String, PlaybackItemType)>)
+    -> Result<(), ProviderError>;
+    async fn pause(&self) -> Result<(), ProviderError>;
+    async fn skip_next(&self) -> Result<(), ProviderError>;
+    async fn skip_previous(&self) -> Result<(), ProviderError>;
+    async fn seek_to_ms(&self, ms: u64) -> Result<(), ProviderError>;
+    /// In percent
+    async fn set_volume(&self, volume: u8) -> Result<(), ProviderError>;
+    async fn set_shuffle(&self, state: bool) -> Result<(), ProviderError>;
+    async fn set_repeat(&self, mode: RepeatMode) -> Result<(), ProviderError>;
+
+    /// Devices of the host account the playback can be transferred to
+    async fn devices(&self) -> Result<Vec<Device>, ProviderError>;
+    async fn transfer_playback(&self, device_id: String) -> Result<(), ProviderError>;
+    /// Playlists of the host account
+    async fn playlists(&self) -> Result<Vec<Playlist>, ProviderError>;
+    async fn playlist_tracks(&self, playlist_id: String) -> Result<Tracks, ProviderError>;
+}
+
+#[async_trait]
+impl MusicProvider for Spotify {
+    async fn search_tracks(&self, query: String) -> Result<Tracks, ProviderError> {
+        Ok(self.search_track(query).await?)
+    }
+
+    async fn search(
+        &self,
+        query: String,
+        types: &[SearchType],
+        offset: u32,
+        limit: u32,
+    ) -> Result<SearchResults, ProviderError> {
+        Ok(Spotify::search(self, query, types, offset, limit).await?)
+    }
+
+    async fn add_to_queue(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<(), ProviderError> {
+        Ok(self.add_track_to_queue(track_id, item_type).await?)
+    }
+
+    async fn get_item(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<Track, ProviderError> {
+        Ok(Spotify::get_item(self, track_id, item_type).await?)
+    }
+
+    async fn next_tracks(&self) -> Result<Tracks, ProviderError> {
+        Ok(self.get_next_tracks().await?)
+    }
+
+    async fn recent_tracks(&self, len: u16) -> Result<Tracks, ProviderError> {
+        Ok(self.get_recent_tracks(Some(len)).await?)
+    }
+
+    async fn now_playing(&self) -> Result<Option<Playback>, ProviderError> {
+        Ok(self.get_current_playback_state().await?)
+    }
+
+    async fn play_resume(&self) -> Result<(), ProviderError> {
+        Ok(Spotify::play_resume(self).await?)
+    }
+
+    async fn play_items(
+        &self,
+        items: Vec<(String, PlaybackItemType)>,
+    ) -> Result<(), ProviderError> {
+        Ok(Spotify::play_items(self, items).await?)
+    }
+
+    async fn pause(&self) -> Result<(), ProviderError> {
+        Ok(Spotify::pause(self).await?)
+    }
+
+    async fn skip_next(&self) -> Result<(), ProviderError> {
+        Ok(Spotify::skip_next(self).await?)
+    }
+
+    async fn skip_previous(&self) -> Result<(), ProviderError> {
+        Ok(Spotify::skip_previous(self).await?)
+    }
+
+    async fn seek_to_ms(&self, ms: u64) -> Result<(), ProviderError> {
+        Ok(Spotify::seek_to_ms(self, ms).await?)
+    }
+
+    async fn set_volume(&self, volume: u8) -> Result<(), ProviderError> {
+        Ok(Spotify::set_volume(self, volume).await?)
+    }
+
+    async fn set_shuffle(&self, state: bool) -> Result<(), ProviderError> {
+        Ok(Spotify::set_shuffle(self, state).await?)
+    }
+
+    async fn set_repeat(&self, mode: RepeatMode) -> Result<(), ProviderError> {
+        Ok(Spotify::set_repeat(self, mode).await?)
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, ProviderError> {
+        Ok(self.get_devices().await?)
+    }
+
+    async fn transfer_playback(&self, device_id: String) -> Result<(), ProviderError> {
+        Ok(Spotify::transfer_playback(self, device_id).await?)
+    }
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, ProviderError> {
+        Ok(self.get_user_playlists().await?)
+    }
+
+    async fn playlist_tracks(&self, playlist_id: String) -> Result<Tracks, ProviderError> {
+        Ok(self.get_playlist_tracks(playlist_id).await?)
+    }
+}
+
+/// Duration of the items of OfflineProvider
+pub const OFFLINE_ITEM_DURATION_MS: i64 = 180_000;
+
+/// Service without any account nor request: every item exists, nothing is ever playing and every
+/// playback command succeeds. The smoke test rooms play from it so Spotify is never called
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OfflineProvider;
+
+#[async_trait]
+impl MusicProvider for OfflineProvider {
+    async fn search_tracks(&self, _query: String) -> Result<Tracks, ProviderError> {
+        Ok(Vec::new())
+    }
+
+    async fn search(
+        &self,
+        _query: String,
+        _types: &[SearchType],
+        _offset: u32,
+        _limit: u32,
+    ) -> Result<SearchResults, ProviderError> {
+        Ok(SearchResults::default())
+    }
+
+    async fn add_to_queue(
+        &self,
+        _track_id: String,
+        _item_type: PlaybackItemType,
+    ) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn get_item(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<Track, ProviderError> {
+        Ok(Track {
+            track_name: track_id.clone(),
+            track_id,
+            artist_name: String::new(),
+            track_duration: OFFLINE_ITEM_DURATION_MS,
+            album_name: String::new(),
+            album_image_src: String::new(),
+            explicit: false,
+            popularity: None,
+            item_type,
+        })
+    }
+
+    async fn next_tracks(&self) -> Result<Tracks, ProviderError> {
+        Ok(Vec::new())
+    }
+
+    async fn recent_tracks(&self, _len: u16) -> Result<Tracks, ProviderError> {
+        Ok(Vec::new())
+    }
+
+    async fn now_playing(&self) -> Result<Option<Playback>, ProviderError> {
+        Ok(None)
+    }
+
+    async fn play_resume(&self) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn play_items(
+        &self,
+        _items: Vec<(String, PlaybackItemType)>,
+    ) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn pause(&self) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn skip_next(&self) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn skip_previous(&self) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn seek_to_ms(&self, _ms: u64) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn set_volume(&self, _volume: u8) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn set_shuffle(&self, _state: bool) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn set_repeat(&self, _mode: RepeatMode) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, ProviderError> {
+        Ok(Vec::new())
+    }
+
+    async fn transfer_playback(&self, _device_id: String) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, ProviderError> {
+        Ok(Vec::new())
+    }
+
+    async fn playlist_tracks(&self, _playlist_id: String) -> Result<Tracks, ProviderError> {
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use rand::RngCore as _;
+use uuid::Uuid;
+
+#[cfg(test)]
+pub use seeded::SeededRandom;
+
+pub const ALPHANUMERIC_CHARSET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Every room secret and code (passwords, invite codes, resume tokens, PKCE verifiers...) must
+/// be drawn from a RandomSource so tests can use a SeededRandom and be reproducible
+pub trait RandomSource: Send + Sync + std::fmt::Debug {
+    fn fill_bytes(&self, dest: &mut [u8]);
+
+    /// len chars uniformly picked from the charset (at most 256 chars), the bytes that would
+    /// bias the modulo are rejected
+    fn string_from(&self, charset: &[u8], len: usize) -> String {
+        debug_assert!(!charset.is_empty() && charset.len() <= 256);
+
+        let limit = 256 - 256 % charset.len();
+        let mut out = String::with_capacity(len);
+        let mut buf = [0; 64];
+
+        while out.len() < len {
+            self.fill_bytes(&mut buf);
+
+            out.extend(
+                buf.iter()
+                    .filter(|&&byte| (byte as usize) < limit)
+                    .map(|&byte| char::from(charset[byte as usize % charset.len()]))
+                    .take(len - out.len()),
+            );
+        }
+
+        out
+    }
+
+    fn alphanumeric(&self, len: usize) -> String {
+        self.string_from(ALPHANUMERIC_CHARSET, len)
+    }
+
+    /// Random (v4) UUID, for the ones that must not be guessable like the tickets
+    fn uuid(&self) -> Uuid {
+        let mut bytes = [0; 16];
+
+        self.fill_bytes(&mut bytes);
+
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+pub type SharedRandom = Arc<dyn RandomSource>;
+
+/// Thread-local CSPRNG of rand
+#[derive(Debug, Default)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        rand::rng().fill_bytes(dest);
+    }
+}
+
+pub fn system_random() -> SharedRandom {
+    Arc::new(SystemRandom)
+}
+
+#[cfg(test)]
+mod seeded {
+    use std::sync::Mutex;
+
+    use rand::rngs::StdRng;
+    use rand::{RngCore as _, SeedableRng as _};
+
+    use super::RandomSource;
+
+    /// Same sequence for the same seed
+    #[derive(Debug)]
+    pub struct SeededRandom(Mutex<StdRng>);
+
+    impl SeededRandom {
+        pub fn new(seed: u64) -> Self {
+            Self(Mutex::new(StdRng::seed_from_u64(seed)))
+        }
+    }
+
+    impl RandomSource for SeededRandom {
+        fn fill_bytes(&self, dest: &mut [u8]) {
+            self.0.lock().unwrap().fill_bytes(dest);
+        }
+    }
+}
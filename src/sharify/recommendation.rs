@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::room::{Room, RoomUserID};
+
+/// Recent contributions of each connected member used as seeds
+pub const MAX_SEEDS_PER_MEMBER: usize = 3;
+/// Weight of a contribution compared to the next more recent one of the same member
+const RECENCY_DECAY: f64 = 0.5;
+/// Delay before the queue gap can be filled again, by then the filled track is in the Spotify
+/// queue and the failed fills aren't retried on every fetch
+pub const GAP_FILL_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Track queued by a connected member that the recommendations are seeded with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seed {
+    pub track_id: String,
+    pub track_name: String,
+    pub user_id: RoomUserID,
+    pub username: String,
+    /// Each connected member with contributions weighs 1 in total, split by recency
+    pub weight: f64,
+}
+
+impl Seed {
+    pub fn explanation(&self) -> String {
+        format!("because {} queued {}", self.username, self.track_name)
+    }
+}
+
+/// Seeds from the latest tracks queued by each connected member, the queued ones first then the
+/// played ones. Members who didn't queue anything are skipped
+pub fn taste_seeds(room: &Room) -> Vec<Seed> {
+    let mut seeds = Vec::new();
+
+    for user in room.users.iter().filter(|user| user.is_connected) {
+        let mut seen = HashSet::new();
+        let contributions = room
+            .tracks_queue
+            .iter()
+            .rev()
+            .filter(|track| track.user_id == user.id)
+            .map(|track| (&track.track_id, &track.track_name))
+            .chain(
+                room.history
+                    .iter()
+                    .filter(|played| played.added_by.as_ref() == Some(&user.id))
+                    .map(|played| (&played.track.track_id, &played.track.track_name)),
+            )
+            .filter(|(track_id, _)| seen.insert(*track_id))
+            .take(MAX_SEEDS_PER_MEMBER)
+            .collect::<Vec<_>>();
+
+        let total = (0..contributions.len())
+            .map(|i| RECENCY_DECAY.powi(i as _))
+            .sum::<f64>();
+
+        seeds.extend(
+            contributions
+                .into_iter()
+                .enumerate()
+                .map(|(i, (track_id, track_name))| Seed {
+                    track_id: track_id.clone(),
+                    track_name: track_name.clone(),
+                    user_id: user.id.clone(),
+                    username: user.username.clone(),
+                    weight: RECENCY_DECAY.powi(i as _) / total,
+                }),
+        );
+    }
+
+    seeds
+}
+
+/// Weighted random pick so every connected member has the same chance to be the seed
+pub fn pick_seed<'a, R: Rng + ?Sized>(seeds: &'a [Seed], rng: &mut R) -> Option<&'a Seed> {
+    let total = seeds.iter().map(|seed| seed.weight).sum::<f64>();
+
+    if total <= 0. {
+        return None;
+    }
+
+    let mut target = rng.random_range(0. ..total);
+
+    seeds
+        .iter()
+        .find(|seed| {
+            target -= seed.weight;
+            target < 0.
+        })
+        .or(seeds.last())
+}
+
+/// Queued and played tracks, they're not recommended again
+pub fn known_track_ids(room: &Room) -> HashSet<String> {
+    room.tracks_queue
+        .iter()
+        .map(|track| track.track_id.clone())
+        .chain(
+            room.history
+                .iter()
+                .map(|played| played.track.track_id.clone()),
+        )
+        .collect()
+}
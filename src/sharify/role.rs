@@ -1,10 +1,20 @@
 use std::cmp::Ordering;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::room::TrackTooLong;
+use super::spotify::web_utils::PlaybackItemType;
+
+const MAX_ROLE_ICON_ID_LEN: usize = 32;
+const MAX_ROLE_DESCRIPTION_LEN: usize = 128;
+
 pub enum RoleError {
     NameAlreadyExists,
+    InvalidColor,
+    InvalidIconID,
+    DescriptionTooLong,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -20,20 +30,31 @@ impl RoleManager {
         Self(roles)
     }
 
-    pub fn add_role(&mut self, name: String, permissions: RolePermission) -> Result<(), RoleError> {
+    pub fn add_role(
+        &mut self,
+        name: String,
+        permissions: RolePermission,
+        display: RoleDisplay,
+    ) -> Result<Uuid, RoleError> {
         if self.0.iter().any(|role| role.name == name) {
             return Err(RoleError::NameAlreadyExists);
         }
 
+        display.validate()?;
+
+        let id = Uuid::now_v7();
+
         self.0.push(Role {
-            id: Uuid::now_v7(),
+            id,
             name,
             permissions,
+            display,
+            content: RoleContent::default(),
         });
 
         self.sort();
 
-        Ok(())
+        Ok(id)
     }
 
     pub fn delete_role(&mut self, id: Uuid) {
@@ -52,6 +73,22 @@ impl RoleManager {
         }
     }
 
+    pub fn set_role_display(&mut self, id: Uuid, display: RoleDisplay) -> Result<(), RoleError> {
+        display.validate()?;
+
+        if let Some(role) = self.0.iter_mut().find(|role| role.id == id) {
+            role.display = display;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_role_content(&mut self, id: Uuid, content: RoleContent) {
+        if let Some(role) = self.0.iter_mut().find(|role| role.id == id) {
+            role.content = content;
+        }
+    }
+
     pub fn get_role_by_name(&self, name: &str) -> Option<&Role> {
         self.0.iter().find(|role| role.name == name)
     }
@@ -103,11 +140,110 @@ pub struct RolePermission {
     pub can_manage_room: bool,
 }
 
+/// Optional metadata for the frontends to render the role
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoleDisplay {
+    /// Hex color formatted as #RRGGBB
+    pub color: Option<String>,
+    /// Icon identifier resolved by the frontends, [a-z0-9_-] only
+    pub icon_id: Option<String>,
+    pub description: Option<String>,
+}
+
+impl RoleDisplay {
+    fn new(color: &str, icon_id: &str, description: &str) -> Self {
+        Self {
+            color: Some(color.into()),
+            icon_id: Some(icon_id.into()),
+            description: Some(description.into()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), RoleError> {
+        if self
+            .color
+            .as_deref()
+            .is_some_and(|color| !is_hex_color(color))
+        {
+            return Err(RoleError::InvalidColor);
+        }
+
+        if let Some(icon_id) = &self.icon_id
+            && (icon_id.is_empty()
+                || icon_id.len() > MAX_ROLE_ICON_ID_LEN
+                || !icon_id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-'))
+        {
+            return Err(RoleError::InvalidIconID);
+        }
+
+        if self
+            .description
+            .as_ref()
+            .is_some_and(|d| d.chars().count() > MAX_ROLE_DESCRIPTION_LEN)
+        {
+            return Err(RoleError::DescriptionTooLong);
+        }
+
+        Ok(())
+    }
+}
+
+/// Formatted as #RRGGBB
+pub fn is_hex_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Limits of the content queued by the members of a role, on top of the room settings.
+/// Nothing is restricted by default, roles created before it existed included
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct RoleContent {
+    /// None to only apply the room max_track_duration
+    pub max_track_duration: Option<Duration>,
+    /// Podcast episodes cannot be queued
+    pub deny_episodes: bool,
+}
+
+pub enum RoleContentError {
+    TrackTooLong(TrackTooLong),
+    EpisodeDenied,
+}
+
+impl RoleContent {
+    pub fn check(
+        &self,
+        track_id: &str,
+        track_duration_ms: u64,
+        item_type: PlaybackItemType,
+    ) -> Result<(), RoleContentError> {
+        if self.deny_episodes && item_type == PlaybackItemType::Episode {
+            return Err(RoleContentError::EpisodeDenied);
+        }
+
+        let track_duration = Duration::from_millis(track_duration_ms);
+
+        match self.max_track_duration {
+            Some(max_track_duration) if track_duration > max_track_duration => {
+                Err(RoleContentError::TrackTooLong(TrackTooLong {
+                    track_id: track_id.to_owned(),
+                    track_duration,
+                    max_track_duration,
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Role {
     pub id: Uuid,
     pub name: String,
     pub permissions: RolePermission,
+    pub display: RoleDisplay,
+    #[serde(default)]
+    pub content: RoleContent,
 }
 
 impl Role {
@@ -122,6 +258,8 @@ impl Role {
                 can_add_moderator: false,
                 can_manage_room: false,
             },
+            display: RoleDisplay::new("#9E9E9E", "user", "Can listen along"),
+            content: RoleContent::default(),
         }
     }
 
@@ -136,6 +274,8 @@ impl Role {
                 can_add_moderator: false,
                 can_manage_room: false,
             },
+            display: RoleDisplay::new("#FFC107", "star", "Can add tracks to the queue"),
+            content: RoleContent::default(),
         }
     }
 
@@ -150,6 +290,12 @@ impl Role {
                 can_add_moderator: false,
                 can_manage_room: false,
             },
+            display: RoleDisplay::new(
+                "#4CAF50",
+                "shield",
+                "Can control the playback and manage users",
+            ),
+            content: RoleContent::default(),
         }
     }
 
@@ -164,6 +310,8 @@ impl Role {
                 can_add_moderator: true,
                 can_manage_room: false,
             },
+            display: RoleDisplay::new("#2196F3", "gavel", "Can manage users and moderators"),
+            content: RoleContent::default(),
         }
     }
 
@@ -178,6 +326,8 @@ impl Role {
                 can_add_moderator: true,
                 can_manage_room: true,
             },
+            display: RoleDisplay::new("#E91E63", "crown", "Can manage the whole room"),
+            content: RoleContent::default(),
         }
     }
 }
@@ -1,20 +1,91 @@
-use std::collections::VecDeque;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
 use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, FixedOffset, NaiveDate, Timelike as _, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use uuid::Uuid;
 
 use crate::proto;
 
-use super::role::RoleManager;
+use super::clock::system_clock;
+use super::content_policy::ContentType;
+use super::music_provider::{MusicProvider, OfflineProvider, ProviderError, ProviderKind};
+use super::role::{RoleManager, is_hex_color};
 use super::room_metadata::*;
-use super::spotify::{SpotifyTokens, Timestamp};
+use super::spotify::web_utils::{
+    PlaybackItemType, SpotifyCurrentPlaybackOutput, SpotifyPlayedTrack, SpotifyTrack,
+};
+use super::spotify::{SpotifyError, SpotifyTokens, Timestamp};
 
-pub(super) const MAX_USERS: usize = 15;
-pub(super) const MAX_LOGS_LEN: usize = 25;
-pub(super) const MAX_TRACKS_QUEUE_LEN: usize = 50;
-pub(super) const INACTIVE_ROOM_MINS: u32 = 5;
+/// Default and upper bound of RoomSettings.max_users
+pub(crate) const MAX_USERS: usize = 15;
+/// Can be overridden with the ROOM_MAX_LOGS env var
+pub(crate) const DEFAULT_MAX_LOGS_LEN: usize = 250;
+pub(super) const DEFAULT_LOGS_PAGE_LEN: usize = 25;
+pub(super) const MAX_LOGS_PAGE_LEN: usize = 100;
+/// Most recent logs embedded in the Room snapshots, the older ones are fetched with GetLogs
+pub(crate) const SNAPSHOT_LOGS_LEN: usize = DEFAULT_LOGS_PAGE_LEN;
+const MAX_AVATAR_URL_LEN: usize = 512;
+/// In characters, once trimmed
+const MAX_USERNAME_LEN: usize = 32;
+/// UTC+14:00 and UTC-12:00 are the extreme timezones
+const MAX_UTC_OFFSET_MINS: u16 = 14 * 60;
+pub(super) const DEFAULT_PUBLIC_ROOMS_PAGE_LEN: usize = 20;
+pub(super) const MAX_PUBLIC_ROOMS_PAGE_LEN: usize = 50;
+/// Default and upper bound of RoomSettings.max_tracks_queue_len
+pub(crate) const MAX_TRACKS_QUEUE_LEN: usize = 50;
+/// Default of RoomSettings.inactivity_timeout
+pub(crate) const INACTIVE_ROOM_MINS: u32 = 5;
+/// Upper bound of RoomSettings.inactivity_timeout
+pub(crate) const MAX_INACTIVE_ROOM_MINS: u32 = 60;
+/// Upper bound of RoomSettings.join_ttl
+pub(crate) const MAX_JOIN_TTL_MINS: u32 = 60;
+/// Bounds of RoomSettings.max_track_duration
+pub(crate) const MIN_TRACK_DURATION_LIMIT: Duration = Duration::from_secs(60);
+pub(crate) const MAX_TRACK_DURATION_LIMIT: Duration = Duration::from_secs(60 * 60 * 3);
+pub const SURPRISE_ME_COOLDOWN: Duration = Duration::from_secs(60);
+pub(crate) const INVITE_CODE_LEN: usize = 8;
+/// Uppercase letters and digits without the look-alike ones (0/O, 1/I/L)
+pub(crate) const INVITE_CODE_CHARSET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+pub(crate) const MAX_HISTORY_LEN: usize = 50;
+/// Recently played tracks of the host account seeding the history of a new room
+pub(crate) const PRE_SESSION_HISTORY_LEN: u16 = 5;
+pub(crate) const QUEUE_EDIT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+pub(crate) const DEFAULT_DJ_TURN_DURATION: Duration = Duration::from_secs(60 * 5);
+pub(crate) const MIN_DJ_TURN_DURATION: Duration = Duration::from_secs(60);
+pub(crate) const MAX_DJ_TURN_DURATION: Duration = Duration::from_secs(60 * 30);
+/// Tracks of a DJ mode turn, see DjRotation.turn_tracks
+pub(crate) const MAX_DJ_TURN_TRACKS: u32 = 10;
+/// Bound of the VoteSeek offsets, both ways
+pub(crate) const MAX_VOTE_SEEK_OFFSET: Duration = Duration::from_secs(60);
+/// A seek vote that didn't get a majority of the connected members within this period can be
+/// replaced by another proposal
+pub(crate) const SEEK_VOTE_TTL: Duration = Duration::from_secs(30);
+/// Command request_ids remembered per user, see CommandDedupCache
+pub(crate) const MAX_DEDUP_REQUEST_IDS: usize = 32;
+pub(crate) const MAX_REQUEST_ID_LEN: usize = 64;
+/// Default of RECONNECT_GRACE_PERIOD_MS: a user reconnecting within this period isn't announced
+/// again to the room and can resume its session with its resume token
+pub(crate) const DEFAULT_RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+pub(crate) const RESUME_TOKEN_LEN: usize = 32;
+/// A room none of whose connected members can manage it for this long gets the
+/// OwnerlessRoomPolicy applied, it also covers an owner who didn't open its WS session yet
+pub(crate) const OWNERLESS_ROOM_GRACE_PERIOD: Duration = Duration::from_secs(60);
+pub(crate) const ROOM_PASSWORD_LEN: usize = 16;
+/// Time the host has to log in with Spotify before the PKCE code verifier is dropped
+pub(crate) const SPOTIFY_LOGIN_TTL: Duration = Duration::from_secs(60 * 10);
+/// Time the host has to send CreateRoom with its Spotify grant before the tokens are dropped
+pub(crate) const SPOTIFY_GRANT_TTL: Duration = Duration::from_secs(60 * 10);
+/// Pending Spotify logins kept at once, the next ones are refused
+pub(crate) const MAX_PENDING_SPOTIFY_LOGINS: usize = 1024;
+/// Pending Spotify logins of the same client (IP), so a single one cannot fill the server cap
+pub(crate) const MAX_PENDING_SPOTIFY_LOGINS_PER_CLIENT: usize = 5;
+pub(crate) const SPOTIFY_GRANT_LEN: usize = 32;
+/// A listener's device further from the room position (seek, buffering...) is synced again
+pub(crate) const MAX_LISTENER_DRIFT: Duration = Duration::from_secs(2);
 
 // email / uuid allowed chars
 pub(super) const MIN_EMAIL_CHAR: char = '-';
@@ -22,34 +93,303 @@ pub(super) const MAX_EMAIL_CHAR: char = 'z';
 
 pub type RoomID = Uuid;
 pub type RoomUserID = String;
+/// Result of each target of a bulk moderation command
+pub type ModerationResults = Vec<(RoomUserID, Result<(), RoomError>)>;
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Room {
     pub id: RoomID,
     pub name: String,
     pub password: String,
+    /// Short code resolving to the room, see RoomManager::get_room_by_invite_code
+    pub invite_code: String,
     pub users: Vec<RoomUser>,
     pub banned_users: Vec<RoomUserID>,
     /// Role hierarchy is: Most powerful role first, then less powerfull, then less...
     pub role_manager: RoleManager,
     // pub current_device: Option<SpotifyApi.UserDevice>,
     pub tracks_queue: VecDeque<RoomTrack>,
-    pub max_users: usize,
-    // TODO: Add log on every action
-    /// Last 25 logs: Ban, Kick, Song added... (25 for memory purposes)
+    pub settings: RoomSettings,
+    /// How long a submitter can remove/replace its own queued track without can_use_controls
+    pub queue_edit_grace_period: Duration,
+    /// Last max_logs_len logs of every state-changing action: Ban, Kick, Song added...
     pub logs: VecDeque<Log>,
+    pub max_logs_len: usize,
+    /// Last MAX_HISTORY_LEN played tracks, most recent first
+    pub history: VecDeque<HistoryTrack>,
 
     #[serde(skip)]
     pub(super) metadata: RoomMetadata,
 }
 
+/// Limits the host can tailor with UpdateRoomSettings, bounded by the server ones
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct RoomSettings {
+    /// Lowering it below the current users count only prevents new users from joining
+    pub max_users: usize,
+    pub max_tracks_queue_len: usize,
+    /// The room is deleted once no user has been connected for this long
+    pub inactivity_timeout: Duration,
+    /// Listed by GET /v1/rooms
+    pub is_public: bool,
+    /// The playback is paused once no user has been connected for this long and resumed when
+    /// one connects again, it must be shorter than the inactivity_timeout
+    pub idle_pause_after: Option<Duration>,
+    /// Queued tracks are interleaved round-robin by submitter and pushed one at a time to
+    /// Spotify, see Room::enqueue_track
+    pub fair_queue: bool,
+    /// Opt-in daily activity summary sent to the configured webhook
+    pub daily_digest: Option<DailyDigest>,
+    /// Longer tracks are rejected when queued or suggested
+    pub max_track_duration: Option<Duration>,
+    /// The room gets the OwnerlessRoomPolicy Transfer whatever OWNERLESS_ROOM_POLICY is, see
+    /// RoomSettings::ownerless_policy
+    pub auto_promote_owner: bool,
+    /// Opt-in, members that haven't opened a WS session this long after joining are removed so
+    /// they don't hold a slot until the room is deleted
+    pub join_ttl: Option<Duration>,
+    /// Users joining the full room are queued instead of rejected, see RoomManager::join_waitlist
+    pub waitlist: bool,
+    /// Explicit tracks are rejected when queued and skipped by SurpriseMe otherwise
+    pub allow_explicit: bool,
+    /// Opt-in SessionSummary sent to the configured webhook when the room is deleted
+    pub session_summary_webhook: bool,
+    /// SetVolume is rejected out of these bounds, unless issued by an owner (can_manage_room)
+    pub volume_limits: Option<VolumeLimits>,
+    /// Opt-in, a recommendation seeded by the tracks of the connected members is queued once
+    /// the queue runs empty, see RoomManager::fill_queue_gap
+    pub auto_fill: bool,
+}
+
+/// Volume bounds in percent, see RoomSettings.volume_limits
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct VolumeLimits {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl VolumeLimits {
+    /// Range accepted by Spotify, enforced even for the owners
+    pub const FULL: Self = Self { min: 0, max: 100 };
+}
+
+/// Schedule of the daily digest, in the host's timezone
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct DailyDigest {
+    /// Local hour (0 to 23) from which the digest of the day is sent
+    pub hour: u8,
+    /// Fixed offset, daylight saving time changes aren't followed
+    pub utc_offset_mins: i16,
+}
+
+impl DailyDigest {
+    /// Local date of the last digest due at now, None before the hour of the first one
+    pub fn due_on(&self, now: DateTime<Utc>) -> Option<NaiveDate> {
+        let offset = FixedOffset::east_opt(self.utc_offset_mins as i32 * 60)?;
+        let local = now.with_timezone(&offset);
+
+        (local.hour() >= self.hour as u32).then(|| local.date_naive())
+    }
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            max_users: MAX_USERS,
+            max_tracks_queue_len: MAX_TRACKS_QUEUE_LEN,
+            inactivity_timeout: Duration::from_secs(INACTIVE_ROOM_MINS as u64 * 60),
+            is_public: false,
+            idle_pause_after: None,
+            fair_queue: false,
+            daily_digest: None,
+            max_track_duration: None,
+            auto_promote_owner: false,
+            join_ttl: None,
+            waitlist: false,
+            allow_explicit: true,
+            session_summary_webhook: false,
+            volume_limits: None,
+            auto_fill: false,
+        }
+    }
+}
+
+impl RoomSettings {
+    /// Policy of the room once it has no owner left, the room's auto_promote_owner takes
+    /// precedence over the default policy of the server (OWNERLESS_ROOM_POLICY)
+    pub fn ownerless_policy(&self, default: OwnerlessRoomPolicy) -> OwnerlessRoomPolicy {
+        if self.auto_promote_owner {
+            OwnerlessRoomPolicy::Transfer
+        } else {
+            default
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), RoomError> {
+        if !(1..=MAX_USERS).contains(&self.max_users)
+            || !(1..=MAX_TRACKS_QUEUE_LEN).contains(&self.max_tracks_queue_len)
+            || self.inactivity_timeout < Duration::from_secs(60)
+            || self.inactivity_timeout > Duration::from_secs(MAX_INACTIVE_ROOM_MINS as u64 * 60)
+            || self.idle_pause_after.is_some_and(|idle_pause_after| {
+                idle_pause_after < Duration::from_secs(60)
+                    || idle_pause_after >= self.inactivity_timeout
+            })
+            || self.daily_digest.is_some_and(|digest| {
+                digest.hour > 23 || digest.utc_offset_mins.unsigned_abs() > MAX_UTC_OFFSET_MINS
+            })
+            || self.max_track_duration.is_some_and(|max_track_duration| {
+                !(MIN_TRACK_DURATION_LIMIT..=MAX_TRACK_DURATION_LIMIT).contains(&max_track_duration)
+            })
+            || self.join_ttl.is_some_and(|join_ttl| {
+                join_ttl < Duration::from_secs(60)
+                    || join_ttl > Duration::from_secs(MAX_JOIN_TTL_MINS as u64 * 60)
+            })
+            || self.volume_limits.is_some_and(|limits| {
+                limits.min > limits.max || limits.max > VolumeLimits::FULL.max
+            })
+        {
+            return Err(RoomError::InvalidSettings);
+        }
+
+        Ok(())
+    }
+
+    pub fn check_track_duration(
+        &self,
+        track_id: &str,
+        track_duration_ms: u64,
+    ) -> Result<(), TrackTooLong> {
+        let track_duration = Duration::from_millis(track_duration_ms);
+
+        match self.max_track_duration {
+            Some(max_track_duration) if track_duration > max_track_duration => Err(TrackTooLong {
+                track_id: track_id.to_owned(),
+                track_duration,
+                max_track_duration,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_volume(&self, volume: u8) -> Result<(), VolumeOutOfRange> {
+        match self.volume_limits {
+            Some(volume_limits) if !(volume_limits.min..=volume_limits.max).contains(&volume) => {
+                Err(VolumeOutOfRange {
+                    volume: volume.into(),
+                    volume_limits,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a track picked by the server (playlist, recommendation...) passes the checks of
+    /// the tracks queued by the members
+    pub fn allows_track(&self, track: &SpotifyTrack) -> bool {
+        self.check_track_duration(&track.track_id, track.track_duration.max(0) as _)
+            .is_ok()
+            && self.check_explicit(&track.track_id, track.explicit).is_ok()
+    }
+
+    pub fn check_explicit(
+        &self,
+        track_id: &str,
+        explicit: bool,
+    ) -> Result<(), ExplicitTrackDenied> {
+        if explicit && !self.allow_explicit {
+            return Err(ExplicitTrackDenied {
+                track_id: track_id.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// What happens to a room when its last owner leaves or once none of its connected members can
+/// manage it, set with OWNERLESS_ROOM_POLICY and overridden per room, see
+/// RoomSettings::ownerless_policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OwnerlessRoomPolicy {
+    #[default]
+    Close,
+    /// The member with the most powerful role gets the owner role (among the connected ones when
+    /// the owners are gone), the room is closed when nobody can take it
+    Transfer,
+}
+
+impl std::str::FromStr for OwnerlessRoomPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(Self::Close),
+            "transfer" => Ok(Self::Transfer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Result of RoomManager::check_room_owners
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomOwnership {
+    Managed,
+    /// The user got the owner role
+    Transferred(RoomUserID),
+    /// The room has to be closed
+    Ownerless,
+}
+
+/// Tracks exported by ExportToPlaylist
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportSource {
+    /// Tracks played during the session, oldest first
+    History,
+    /// Tracks left in the room queue
+    Queue,
+}
+
+/// Rejection of a track longer than RoomSettings.max_track_duration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackTooLong {
+    pub track_id: String,
+    pub track_duration: Duration,
+    pub max_track_duration: Duration,
+}
+
+/// Rejection of an explicit track when RoomSettings.allow_explicit is disabled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExplicitTrackDenied {
+    pub track_id: String,
+}
+
+/// Rejection of a SetVolume out of RoomSettings.volume_limits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VolumeOutOfRange {
+    pub volume: u32,
+    pub volume_limits: VolumeLimits,
+}
+
+/// Position of a queued track, announced to its submitter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueuePosition {
+    pub track_id: String,
+    /// Tracks of the room queue played before it
+    pub position: u32,
+    /// None while the playback is paused
+    pub eta: Option<Duration>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Log {
     pub r#type: LogType,
     pub details: String,
+    /// None when the server is the author (inactivity, room closing...)
+    pub author_id: Option<RoomUserID>,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LogType {
     Other,
     Kick,
@@ -58,11 +398,34 @@ pub enum LogType {
     JoinRoom,
     LeaveRoom,
     UsernameChange,
+    RoleChange,
+    Playback,
+    RemoveTrack,
+}
+
+/// Logs retrieval filters, every log matches the unset ones
+#[derive(Clone, Debug, Default)]
+pub struct LogFilter {
+    /// Any type when empty
+    pub types: Vec<LogType>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl LogFilter {
+    pub fn matches_type(&self, log: &Log) -> bool {
+        self.types.is_empty() || self.types.contains(&log.r#type)
+    }
 }
 
 impl Log {
-    pub fn new(r#type: LogType, details: String) -> Self {
-        Self { r#type, details }
+    pub fn new(r#type: LogType, author_id: Option<RoomUserID>, details: String) -> Self {
+        Self {
+            r#type,
+            details,
+            author_id,
+            created_at: Utc::now(),
+        }
     }
 }
 
@@ -74,24 +437,96 @@ pub struct CredentialsInput {
     pub created_at: Timestamp,
 }
 
-impl From<CredentialsInput> for SpotifyTokens {
-    fn from(val: CredentialsInput) -> Self {
-        SpotifyTokens {
-            access_token: val.access_token,
-            refresh_token: val.refresh_token,
-            expires_in: val.expires_in,
-            created_at: val.created_at,
+impl TryFrom<CredentialsInput> for SpotifyTokens {
+    type Error = SpotifyError;
+
+    fn try_from(val: CredentialsInput) -> Result<Self, Self::Error> {
+        SpotifyTokens::new(
+            &val.access_token,
+            &val.refresh_token,
+            val.expires_in,
+            val.created_at,
+        )
+    }
+}
+
+/// What the room browser shows of a public room
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicRoom {
+    pub id: RoomID,
+    pub name: String,
+    pub current_track: Option<SpotifyTrack>,
+    pub users_count: usize,
+    pub max_users: usize,
+}
+
+impl From<&Room> for PublicRoom {
+    fn from(room: &Room) -> Self {
+        Self {
+            id: room.id,
+            name: room.name.clone(),
+            current_track: room
+                .now_playing
+                .as_ref()
+                .map(|playback| playback.to_track()),
+            users_count: room.users.len(),
+            max_users: room.settings.max_users,
+        }
+    }
+}
+
+/// What the operators see of any room through the /admin/v1 API
+#[derive(Clone, Debug, Serialize)]
+pub struct AdminRoomSummary {
+    pub id: RoomID,
+    pub name: String,
+    pub users_count: usize,
+    pub connected_users_count: usize,
+    pub max_users: usize,
+    pub tracks_queue_len: usize,
+    pub is_public: bool,
+    pub is_closing: bool,
+    pub is_hibernating: bool,
+}
+
+impl From<&Room> for AdminRoomSummary {
+    fn from(room: &Room) -> Self {
+        Self {
+            id: room.id,
+            name: room.name.clone(),
+            users_count: room.users.len(),
+            connected_users_count: room.users.iter().filter(|user| user.is_connected).count(),
+            max_users: room.settings.max_users,
+            tracks_queue_len: room.tracks_queue.len(),
+            is_public: room.settings.is_public,
+            is_closing: room.lifecycle == RoomLifecycle::Closing,
+            is_hibernating: room.hibernating,
         }
     }
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryTrack {
+    pub track: SpotifyTrack,
+    pub played_at: DateTime<Utc>,
+    /// Played on the host account before the room was created
+    pub pre_session: bool,
+    /// Member who queued it through the room, seeds the recommendations
+    pub added_by: Option<RoomUserID>,
+}
+
 // TODO: On current track playing fetch => if the song matches the first [0] of the list, shift it
 #[derive(Clone, Debug, Serialize)]
 pub struct RoomTrack {
     pub user_id: RoomUserID,
     pub track_id: String,
     pub track_name: String,
+    /// Looked up on the provider when queued, see Room::take_queue_position_changes
     pub track_duration: u32,
+    /// Episodes are queued with their episode URI
+    pub item_type: PlaybackItemType,
+    #[serde(skip)]
+    pub added_at: Instant,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -100,6 +535,39 @@ pub struct RoomUser {
     pub username: String,
     pub role_id: Uuid,
     pub is_connected: bool, // TODO: Handle this everywhere
+    pub profile: UserProfile,
+}
+
+/// Optional metadata for the frontends to tell the members apart
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserProfile {
+    /// https URL, up to MAX_AVATAR_URL_LEN characters
+    pub avatar_url: Option<String>,
+    /// Hex color formatted as #RRGGBB
+    pub color: Option<String>,
+}
+
+impl UserProfile {
+    pub fn validate(&self) -> Result<(), RoomError> {
+        if self.avatar_url.as_deref().is_some_and(|url| {
+            url.len() > MAX_AVATAR_URL_LEN
+                || !url.starts_with("https://")
+                || url.len() == "https://".len()
+                || url.chars().any(|c| c.is_whitespace() || c.is_control())
+        }) {
+            return Err(RoomError::InvalidProfile);
+        }
+
+        if self
+            .color
+            .as_deref()
+            .is_some_and(|color| !is_hex_color(color))
+        {
+            return Err(RoomError::InvalidProfile);
+        }
+
+        Ok(())
+    }
 }
 
 impl PartialEq for RoomUser {
@@ -120,25 +588,442 @@ pub enum RoomError {
     UserBanned,
     UserIDExists,
     Unreachable,
+    QueueEditExpired,
+    PlaybackAlreadyParked,
+    PlaybackNotParked,
+    QueueFull,
+    InvalidSettings,
+    DjRotationNotStarted,
+    RoomClosing,
+    /// The Spotify grant of CreateRoom is unknown, expired or already used
+    InvalidSpotifyGrant,
+    /// The avatar URL or the color of the UserProfile is malformed
+    InvalidProfile,
+    /// Empty or longer than MAX_USERNAME_LEN characters once trimmed
+    InvalidUsername,
+    /// Another member of the room has the same username (case insensitive)
+    UsernameTaken,
+    /// The waitlist ticket is unknown, expired or its user couldn't be admitted
+    NotWaitlisted,
+    /// The scheduled room or the ticket is unknown, or the room was opened too long ago
+    ScheduledRoomNotFound,
+    /// The opening time is in the past or further than MAX_SCHEDULE_AHEAD, or the server already
+    /// has MAX_SCHEDULED_ROOMS waiting to open
+    InvalidSchedule,
+    /// The VoteSeek offset is 0 or further than MAX_VOTE_SEEK_OFFSET
+    InvalidSeekOffset,
+    /// Another seek of the current track is being voted
+    SeekVoteRunning,
 }
 
 impl Room {
+    /// Trimmed username, unique in the room (case insensitive) among the other users
+    pub fn validate_username<'a>(
+        &self,
+        user_id: &RoomUserID,
+        username: &'a str,
+    ) -> Result<&'a str, RoomError> {
+        let username = username.trim();
+
+        if username.is_empty() || username.chars().count() > MAX_USERNAME_LEN {
+            return Err(RoomError::InvalidUsername);
+        }
+
+        if self.users.iter().any(|user| {
+            user.id != *user_id && user.username.trim().to_lowercase() == username.to_lowercase()
+        }) {
+            return Err(RoomError::UsernameTaken);
+        }
+
+        Ok(username)
+    }
+
     /// This is a helper fn to create a Room struct from a proto Room
     /// but unsafe to use because of partially uninitialized fields.
     /// This is for testing purposes only
     pub fn from_proto_unsafe(room: proto::room::Room) -> Self {
         Self {
-            id: Uuid::from_slice(&room.id[..16]).unwrap_or_default(),
+            id: Uuid::from_slice(&room.id).unwrap_or_default(),
             name: room.name,
             password: room.password,
+            invite_code: room.invite_code,
             users: room.users.into_iter().map(Into::into).collect(),
             banned_users: room.banned_users,
             role_manager: room.role_manager.map(Into::into).unwrap_or_default(),
             tracks_queue: room.tracks_queue.into_iter().map(Into::into).collect(),
             logs: room.logs.into_iter().map(Into::into).collect(),
-            max_users: room.max_users as _,
-            metadata: RoomMetadata::new(SpotifyTokens::default()),
+            max_logs_len: DEFAULT_MAX_LOGS_LEN,
+            history: room.history.into_iter().map(Into::into).collect(),
+            settings: room.settings.map(Into::into).unwrap_or_default(),
+            queue_edit_grace_period: Duration::from_secs(room.queue_edit_grace_secs as _),
+            metadata: RoomMetadata::new(SpotifyTokens::default(), system_clock()),
+        }
+    }
+
+    /// Handler of the room streaming service, cloned so neither the state nor the room are
+    /// locked during its requests
+    pub fn music_provider(&self) -> Box<dyn MusicProvider> {
+        match self.provider {
+            ProviderKind::Spotify => Box::new(self.spotify_handler.clone()),
+            ProviderKind::Offline => Box::new(OfflineProvider),
+        }
+    }
+
+    /// Progress of the current track extrapolated from the last fetched playback, None when
+    /// nothing is playing
+    pub fn playback_progress_ms(&self, now: Instant) -> Option<u64> {
+        let playback = self
+            .now_playing
+            .as_ref()
+            .filter(|playback| playback.is_playing)?;
+        let elapsed = now.saturating_duration_since(self.now_playing_at?);
+
+        Some(
+            playback
+                .progress_ms?
+                .saturating_add(elapsed.as_millis() as _)
+                .min(playback.duration_ms),
+        )
+    }
+
+    /// Adds the time the last fetched playback has been playing since, to call before replacing
+    /// it with a newly fetched one
+    pub fn record_listening_time(&mut self, now: Instant) {
+        let (Some(playback), Some(fetched_at)) = (&self.now_playing, self.now_playing_at) else {
+            return;
+        };
+        let Some(remaining_ms) = playback.remaining_ms() else {
+            return;
+        };
+
+        self.listening_stats.listening_time += now
+            .saturating_duration_since(fetched_at)
+            .min(Duration::from_millis(remaining_ms));
+    }
+
+    /// Listeners whose device doesn't follow the current playback anymore (other item or play
+    /// state, or further than MAX_LISTENER_DRIFT from its position) or only the forced one,
+    /// synced either way, they're marked synced. The listeners of the users that left the
+    /// room are dropped
+    pub fn take_listener_syncs(
+        &mut self,
+        now: Instant,
+        forced: Option<&RoomUserID>,
+    ) -> Vec<ListenerSync> {
+        let position_ms = self.playback_progress_ms(now);
+        let users = &self.users;
+        let metadata = &mut self.metadata;
+
+        metadata
+            .listeners
+            .retain(|user_id, _| users.iter().any(|user| user.id == *user_id));
+
+        let Some(playback) = &metadata.now_playing else {
+            return Vec::new();
+        };
+        let state = SyncedPlayback {
+            track_id: playback.track_id.clone(),
+            position_ms,
+            synced_at: now,
+        };
+
+        metadata
+            .listeners
+            .iter_mut()
+            .filter(|(user_id, listener)| match forced {
+                Some(forced) => forced == *user_id,
+                None => listener
+                    .synced
+                    .as_ref()
+                    .is_none_or(|synced| !synced.follows(&state)),
+            })
+            .map(|(user_id, listener)| {
+                listener.synced = Some(state.clone());
+
+                ListenerSync {
+                    user_id: user_id.clone(),
+                    device_id: listener.device_id.clone(),
+                    track_id: playback.track_id.clone(),
+                    item_type: playback.item_type,
+                    position_ms,
+                }
+            })
+            .collect()
+    }
+
+    /// Next update of the room broadcasted to its members: the full room, along with a
+    /// RoomDelta against the state they last got when it's smaller (for the sessions that
+    /// negotiated the deltas)
+    ///
+    /// None when nothing changed since the last broadcast
+    pub fn take_room_update(
+        &mut self,
+    ) -> Option<(
+        proto::room::Room,
+        Option<proto::cmd::command_response::RoomDelta>,
+    )> {
+        use prost::Message as _;
+
+        use proto::cmd::command_response::RoomDelta;
+
+        let seq = self.room_seq + 1;
+        let current = proto::room::Room {
+            seq,
+            ..self.clone().into()
+        };
+        let delta = match &self.broadcast_room {
+            Some(previous) => {
+                RoomDelta::between(previous, &current, seq, self.logs_since_broadcast)?
+            }
+            // The members have no state to apply a delta on
+            None => {
+                self.room_seq = seq;
+                self.broadcast_room = Some(current.clone());
+
+                return Some((current, None));
+            }
+        };
+        let delta = (delta.encoded_len() < current.encoded_len()).then_some(delta);
+
+        self.room_seq = seq;
+        self.logs_since_broadcast = 0;
+        self.broadcast_room = Some(current.clone());
+
+        Some((current, delta))
+    }
+
+    /// Appends the log, the oldest ones are dropped past max_logs_len
+    pub fn push_log(&mut self, mut log: Log, created_at: DateTime<Utc>) {
+        log.created_at = created_at;
+
+        while self.logs.len() >= self.max_logs_len.max(1) {
+            self.logs.pop_front();
         }
+
+        self.logs.push_back(log);
+        self.logs_since_broadcast += 1;
+    }
+
+    /// Heir of the owner role among the users matching the filter: the connected ones first,
+    /// then the most powerful role, then the earliest to join
+    pub fn fallback_owner(&self, filter: impl Fn(&RoomUser) -> bool) -> Option<&RoomUser> {
+        let roles = self.role_manager.get_roles();
+
+        self.users
+            .iter()
+            .filter(|user| filter(user))
+            .min_by_key(|user| {
+                (
+                    !user.is_connected,
+                    roles
+                        .iter()
+                        .position(|role| role.id == user.role_id)
+                        .unwrap_or(usize::MAX),
+                )
+            })
+    }
+
+    /// Updates the cached playback and adds its track to the history when it changed
+    ///
+    /// Returns true when the track is the same but Spotify rotated its album image URL
+    pub fn set_now_playing(&mut self, playback: Option<SpotifyCurrentPlaybackOutput>) -> bool {
+        let album_image_changed = playback.as_ref().is_some_and(|playback| {
+            self.now_playing.as_ref().is_some_and(|now_playing| {
+                now_playing.track_id == playback.track_id
+                    && now_playing.album_image_src != playback.album_image_src
+            })
+        });
+
+        if let Some(ref playback) = playback
+            && self
+                .now_playing
+                .as_ref()
+                .is_none_or(|now_playing| now_playing.track_id != playback.track_id)
+        {
+            self.history.push_front(HistoryTrack {
+                track: playback.to_track(),
+                played_at: Utc::now(),
+                pre_session: false,
+                added_by: self
+                    .tracks_queue
+                    .iter()
+                    .find(|track| track.track_id == playback.track_id)
+                    .map(|track| track.user_id.clone()),
+            });
+            self.history.truncate(MAX_HISTORY_LEN);
+            self.stats.tracks_played += 1;
+            self.listening_stats.record_played(&playback.artist_name);
+        }
+
+        self.now_playing = playback;
+
+        album_image_changed
+    }
+
+    /// Adds the track at the end of the queue, or in fair queue mode, at the end of the round
+    /// matching the number of tracks of the submitter not pushed to Spotify yet
+    pub fn enqueue_track(&mut self, track: RoomTrack) {
+        if !self.settings.fair_queue {
+            self.tracks_queue.push_back(track);
+            return;
+        }
+
+        let pending = self.tracks_queue.range(self.pushed_tracks_len..);
+        let round = pending
+            .clone()
+            .filter(|t| t.user_id == track.user_id)
+            .count();
+
+        // Each user's n-th pending track is in the n-th round, the rounds being in order
+        let mut user_tracks = std::collections::HashMap::<&RoomUserID, usize>::new();
+        let idx = pending
+            .take_while(|t| {
+                let user_round = user_tracks.entry(&t.user_id).or_default();
+                *user_round += 1;
+
+                *user_round - 1 <= round
+            })
+            .count();
+
+        self.tracks_queue
+            .insert(self.pushed_tracks_len + idx, track);
+    }
+
+    /// Positions of the queued tracks that moved since the last call (queued, skipped,
+    /// reordered...) for their submitter
+    ///
+    /// A track queued twice by the same member is announced at its first position. The ETA
+    /// adds up the durations of the last fetched Spotify queue, falling back to the ones looked
+    /// up when the tracks were queued (never the ones sent by the clients)
+    pub fn take_queue_position_changes(&mut self) -> Vec<(RoomUserID, QueuePosition)> {
+        let remaining_ms = self
+            .now_playing
+            .as_ref()
+            .and_then(SpotifyCurrentPlaybackOutput::remaining_ms);
+        let fetched_durations = self
+            .next_tracks
+            .iter()
+            .flatten()
+            .map(|track| (track.track_id.as_str(), track.track_duration.max(0) as u64))
+            .collect::<HashMap<_, _>>();
+        let mut positions = HashMap::new();
+        let mut changes = Vec::new();
+        let mut ahead_ms = 0;
+
+        for (idx, track) in self.tracks_queue.iter().enumerate() {
+            let key = (track.user_id.clone(), track.track_id.clone());
+            let position = idx as u32;
+
+            if let Entry::Vacant(entry) = positions.entry(key) {
+                if self.queue_positions.get(entry.key()) != Some(&position) {
+                    changes.push((
+                        track.user_id.clone(),
+                        QueuePosition {
+                            track_id: track.track_id.clone(),
+                            position,
+                            eta: remaining_ms
+                                .map(|remaining_ms| Duration::from_millis(remaining_ms + ahead_ms)),
+                        },
+                    ));
+                }
+
+                entry.insert(position);
+            }
+
+            ahead_ms += fetched_durations
+                .get(track.track_id.as_str())
+                .copied()
+                .unwrap_or(track.track_duration as u64);
+        }
+
+        // The played and removed tracks are dropped
+        self.queue_positions = positions;
+
+        changes
+    }
+
+    /// Queued tracks due to Spotify, counted as pushed right away so they can be pushed
+    /// without holding the room (see RoomManager::push_queued_tracks)
+    pub fn reserve_unpushed_tracks(&mut self) -> Vec<(String, PlaybackItemType)> {
+        let len = if self.settings.fair_queue {
+            self.tracks_queue.len().min(1)
+        } else {
+            self.tracks_queue.len()
+        };
+        let start = self.pushed_tracks_len.min(len);
+
+        self.pushed_tracks_len = self.pushed_tracks_len.max(len);
+
+        self.tracks_queue
+            .range(start..len)
+            .map(|track| (track.track_id.clone(), track.item_type))
+            .collect()
+    }
+
+    /// Gives back the reserved tracks that could not be pushed
+    pub fn release_unpushed_tracks(&mut self, count: usize) {
+        self.pushed_tracks_len = self.pushed_tracks_len.saturating_sub(count);
+    }
+
+    /// Member who queued each track of the Spotify queue, matched in order with the pushed
+    /// tracks of the room queue so a track queued twice is attributed to both submitters.
+    /// None for the tracks not queued through the room (context, autoplay...) or whose
+    /// submitter left
+    pub fn queue_submitters<'a>(
+        &self,
+        track_ids: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<Option<&RoomUser>> {
+        let mut pushed = self
+            .tracks_queue
+            .range(..self.pushed_tracks_len.min(self.tracks_queue.len()))
+            .collect::<Vec<_>>();
+
+        track_ids
+            .into_iter()
+            .map(|track_id| {
+                let idx = pushed.iter().position(|track| track.track_id == track_id)?;
+                let track = pushed.remove(idx);
+
+                self.users.iter().find(|user| user.id == track.user_id)
+            })
+            .collect()
+    }
+
+    /// Tracks to add to an exported playlist, local files cannot be added to playlists
+    pub fn export_tracks(&self, source: ExportSource) -> Vec<(String, PlaybackItemType)> {
+        let tracks = match source {
+            ExportSource::History => self
+                .history
+                .iter()
+                .rev()
+                .filter(|played| !played.pre_session)
+                .map(|played| (played.track.track_id.clone(), played.track.item_type))
+                .collect::<Vec<_>>(),
+            ExportSource::Queue => self
+                .tracks_queue
+                .iter()
+                .map(|track| (track.track_id.clone(), track.item_type))
+                .collect(),
+        };
+
+        tracks
+            .into_iter()
+            .filter(|(track_id, item_type)| {
+                ContentType::of_item(track_id, *item_type) != ContentType::Local
+            })
+            .collect()
+    }
+
+    /// Appends tracks played before the room was created, they're older than the session ones
+    pub fn seed_history(&mut self, played: Vec<SpotifyPlayedTrack>) {
+        self.history
+            .extend(played.into_iter().map(|played| HistoryTrack {
+                track: played.track,
+                played_at: played.played_at,
+                pre_session: true,
+                added_by: None,
+            }));
+        self.history.truncate(MAX_HISTORY_LEN);
     }
 
     pub fn to_json(&self) -> Value {
@@ -159,3 +1044,18 @@ impl DerefMut for Room {
         &mut self.metadata
     }
 }
+
+/// Pushes the tracks in order, on failure the number of tracks not pushed is returned with the
+/// error
+pub async fn push_tracks(
+    provider: &dyn MusicProvider,
+    tracks: &[(String, PlaybackItemType)],
+) -> Result<(), (ProviderError, usize)> {
+    for (idx, (track_id, item_type)) in tracks.iter().enumerate() {
+        if let Err(err) = provider.add_to_queue(track_id.clone(), *item_type).await {
+            return Err((err, tracks.len() - idx));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::room::{RoomID, RoomUserID};
+use super::tasks::spawn_room_task;
+
+/// Events buffered for each subscriber, a lagging one misses the oldest ones
+pub const ROOM_EVENTS_CAPACITY: usize = 256;
+
+/// State changes of the rooms, emitted by the RoomManager so the in-crate subsystems (stats,
+/// webhooks, persistence...) can react to them without patching it, see spawn_subscriber
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomEvent {
+    Created {
+        room_id: RoomID,
+        owner_id: RoomUserID,
+    },
+    /// By an owner or for inactivity
+    Deleted {
+        room_id: RoomID,
+    },
+    /// New WS sessions are rejected from now on
+    Closing {
+        room_id: RoomID,
+    },
+    /// Spotify isn't polled anymore since nobody is connected
+    Hibernated {
+        room_id: RoomID,
+    },
+    /// A user connected to the hibernating room
+    WokeUp {
+        room_id: RoomID,
+    },
+    UserJoined {
+        room_id: RoomID,
+        user_id: RoomUserID,
+    },
+    UserLeft {
+        room_id: RoomID,
+        user_id: RoomUserID,
+    },
+    UserRemoved {
+        room_id: RoomID,
+        user_id: RoomUserID,
+        reason: RemovalReason,
+    },
+    /// The user's WS session was opened or lost for good (its grace period is over)
+    PresenceChanged {
+        room_id: RoomID,
+        user_id: RoomUserID,
+        is_connected: bool,
+    },
+    /// Transferred by the previous owner or promoted by the room
+    OwnerChanged {
+        room_id: RoomID,
+        user_id: RoomUserID,
+    },
+    /// Promoted owner or demoted by an ownership transfer
+    RoleChanged {
+        room_id: RoomID,
+        user_id: RoomUserID,
+        role_id: Uuid,
+    },
+    UsernameChanged {
+        room_id: RoomID,
+        user_id: RoomUserID,
+    },
+    SettingsUpdated {
+        room_id: RoomID,
+    },
+    TrackQueued {
+        room_id: RoomID,
+        user_id: RoomUserID,
+        track_id: String,
+    },
+    /// Started playing or removed from the queue by a member
+    TrackDequeued {
+        room_id: RoomID,
+        track_id: String,
+    },
+    /// Created by RoomManager::open_due_rooms, see ScheduleRoom
+    ScheduledRoomOpened {
+        room_id: RoomID,
+        scheduled_room_id: Uuid,
+    },
+    RoleUpdated {
+        room_id: RoomID,
+        role_id: Uuid,
+        change: RoleChange,
+    },
+    /// Started, switched to or from DJ mode or given a new turn duration
+    DjRotationUpdated {
+        room_id: RoomID,
+    },
+    DjRotationStopped {
+        room_id: RoomID,
+    },
+    /// None when none of the members opted in
+    DjTurnChanged {
+        room_id: RoomID,
+        dj_id: Option<RoomUserID>,
+    },
+    PlaybackParked {
+        room_id: RoomID,
+        user_id: RoomUserID,
+    },
+    /// The parked playback and queue were restored
+    PlaybackResumed {
+        room_id: RoomID,
+    },
+    /// The previously signed URLs of the room are invalid from now on
+    SigningSecretRotated {
+        room_id: RoomID,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoleChange {
+    Created,
+    Renamed,
+    Deleted,
+    Display,
+    Content,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalReason {
+    Kicked,
+    Banned,
+    /// Kicked through the admin API
+    Operator,
+    /// Never connected within the room join_ttl
+    JoinTtl,
+}
+
+impl RoomEvent {
+    pub fn room_id(&self) -> RoomID {
+        match self {
+            Self::Created { room_id, .. }
+            | Self::Deleted { room_id }
+            | Self::Closing { room_id }
+            | Self::Hibernated { room_id }
+            | Self::WokeUp { room_id }
+            | Self::UserJoined { room_id, .. }
+            | Self::UserLeft { room_id, .. }
+            | Self::UserRemoved { room_id, .. }
+            | Self::PresenceChanged { room_id, .. }
+            | Self::OwnerChanged { room_id, .. }
+            | Self::RoleChanged { room_id, .. }
+            | Self::UsernameChanged { room_id, .. }
+            | Self::SettingsUpdated { room_id }
+            | Self::TrackQueued { room_id, .. }
+            | Self::TrackDequeued { room_id, .. }
+            | Self::ScheduledRoomOpened { room_id, .. }
+            | Self::RoleUpdated { room_id, .. }
+            | Self::DjRotationUpdated { room_id }
+            | Self::DjRotationStopped { room_id }
+            | Self::DjTurnChanged { room_id, .. }
+            | Self::PlaybackParked { room_id, .. }
+            | Self::PlaybackResumed { room_id }
+            | Self::SigningSecretRotated { room_id } => *room_id,
+        }
+    }
+}
+
+/// Runs the handler on each room event until the RoomManager is dropped, the subscribers are
+/// registered at startup with RoomManager::subscribe_events
+pub fn spawn_subscriber<F>(
+    name: &'static str,
+    mut events: broadcast::Receiver<RoomEvent>,
+    mut handler: F,
+) where
+    F: FnMut(RoomEvent) + Send + 'static,
+{
+    spawn_room_task(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => handler(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("The {name} room events subscriber missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Debug log of every room event
+pub fn init_event_log(events: broadcast::Receiver<RoomEvent>) {
+    spawn_subscriber("log", events, |event| {
+        debug!("[{}] Room event: {event:?}", event.room_id());
+    });
+}
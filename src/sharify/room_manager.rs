@@ -1,63 +1,183 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{self, Arc, PoisonError};
+use std::time::{Duration, Instant};
 
-use rand::distr::Alphanumeric;
-use rand::{Rng, rng};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::seq::IndexedRandom as _;
+use tokio::sync::{RwLock, broadcast};
 use uuid::Uuid;
 
+use super::clock::{SharedClock, system_clock};
+use super::digest::{self, RoomDigest, SessionSummary, TopContributor};
+use super::music_provider::ProviderError;
+use super::random::{SharedRandom, system_random};
+use super::recommendation::{self, GAP_FILL_COOLDOWN, Seed};
 use super::role::*;
 use super::room::*;
+use super::room_events::{ROOM_EVENTS_CAPACITY, RemovalReason, RoomEvent};
 use super::room_metadata::*;
+use super::schedule::{
+    MAX_SCHEDULE_AHEAD, MAX_SCHEDULED_ROOMS, SCHEDULED_ROOM_CLAIM_TTL, ScheduledMember,
+    ScheduledRoom, ScheduledRoomInfo, ScheduledRoomStatus,
+};
+use super::spotify::web_utils::{PlaybackItemType, SpotifyTrack};
+use super::spotify::{
+    RateLimiter, Spotify, SpotifyBaseUrls, SpotifyError, SpotifyTokens, TOKEN_REFRESH_MARGIN,
+};
+use super::tasks::{RoomTaskKind, RoomTasks};
 use super::utils::*;
+use super::waitlist::{MAX_WAITLIST_LEN, WaitlistEntry, WaitlistStatus};
 
-#[derive(Debug, Default)]
+pub type RoomReadGuard<'a> = sync::RwLockReadGuard<'a, Room>;
+pub type RoomWriteGuard<'a> = sync::RwLockWriteGuard<'a, Room>;
+
+#[derive(Debug)]
 pub struct RoomManager {
-    active_rooms: HashMap<RoomID, Room>,
+    /// Each room is behind its own lock so the room scoped work (Spotify polls, commands,
+    /// presence...) only needs the state read lock and doesn't wait on the other rooms
+    ///
+    /// Under the state write lock, the rooms are reached without locking them, see get_room_mut
+    active_rooms: HashMap<RoomID, sync::RwLock<Room>>,
     user_ids: HashSet<RoomUserID>,
+    invite_codes: HashMap<String, RoomID>,
+    /// PKCE code verifiers of the Spotify logins in progress and the client that started
+    /// them, by OAuth state
+    spotify_logins: HashMap<String, (String, IpAddr, Instant)>,
+    /// Spotify tokens of the completed logins waiting for CreateRoom, by grant
+    spotify_grants: HashMap<String, (SpotifyTokens, Instant)>,
+    /// Rooms set up ahead of time, kept for SCHEDULED_ROOM_CLAIM_TTL once opened
+    scheduled_rooms: HashMap<Uuid, ScheduledRoom>,
+    /// Room scoped loops, shut down when their room is deleted
+    tasks: RoomTasks,
+    /// See RoomEvent, it's fine to emit without any subscriber
+    events: broadcast::Sender<RoomEvent>,
+    /// Given to the Spotify handlers of the rooms and listeners, and to the Spotify logins
+    spotify_base_urls: Arc<SpotifyBaseUrls>,
+    clock: SharedClock,
+    random: SharedRandom,
+}
+
+impl Default for RoomManager {
+    fn default() -> Self {
+        Self::new(system_clock(), system_random())
+    }
 }
 
 impl RoomManager {
+    pub fn new(clock: SharedClock, random: SharedRandom) -> Self {
+        Self {
+            active_rooms: HashMap::new(),
+            user_ids: HashSet::new(),
+            invite_codes: HashMap::new(),
+            spotify_logins: HashMap::new(),
+            spotify_grants: HashMap::new(),
+            scheduled_rooms: HashMap::new(),
+            tasks: RoomTasks::default(),
+            events: broadcast::channel(ROOM_EVENTS_CAPACITY).0,
+            spotify_base_urls: Arc::new(crate::config::get().spotify_base_urls.clone()),
+            clock,
+            random,
+        }
+    }
+
+    /// Spotify (or a stand-in) the rooms created from now on talk to
+    pub fn with_spotify_base_urls(mut self, spotify_base_urls: SpotifyBaseUrls) -> Self {
+        self.spotify_base_urls = Arc::new(spotify_base_urls);
+        self
+    }
+
+    pub fn spotify_base_urls(&self) -> &Arc<SpotifyBaseUrls> {
+        &self.spotify_base_urls
+    }
+
+    /// Spotify handler of the tokens, talking to spotify_base_urls
+    fn spotify_handler(&self, tokens: SpotifyTokens) -> Spotify {
+        let mut spotify = Spotify::new(tokens, self.clock.clone());
+        spotify.base_urls = Arc::clone(&self.spotify_base_urls);
+
+        spotify
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.events.subscribe()
+    }
+
+    /// For the room changes made outside of the RoomManager, its own are emitted by its methods
+    pub fn emit(&self, event: RoomEvent) {
+        let _ = self.events.send(event);
+    }
+
+    pub fn tasks(&self) -> &RoomTasks {
+        &self.tasks
+    }
+
+    pub fn tasks_mut(&mut self) -> &mut RoomTasks {
+        &mut self.tasks
+    }
+
+    /// Shuts down the tasks of the rooms that no longer exist, returns how many were running
+    pub fn sweep_room_tasks(&mut self) -> usize {
+        self.tasks
+            .sweep(|room_id| self.active_rooms.contains_key(room_id))
+    }
+
     pub fn create_room(
         &mut self,
         user_id: RoomUserID,
         username: String,
         name: String,
-        creds: CredentialsInput,
+        tokens: SpotifyTokens,
+        profile: UserProfile,
     ) -> Result<Room, RoomError> {
+        profile.validate()?;
+
         if self.user_id_exists(&user_id) {
             return Err(RoomError::UserIDExists);
         }
 
         let id = Uuid::now_v7();
         let role_manager = RoleManager::default();
+        let invite_code = self.generate_invite_code();
+
+        self.invite_codes.insert(invite_code.clone(), id);
+
+        let mut metadata = RoomMetadata::new(tokens, self.clock.clone());
+        metadata.spotify_handler.base_urls = Arc::clone(&self.spotify_base_urls);
 
         self.active_rooms.insert(
             id,
-            Room {
+            sync::RwLock::new(Room {
                 id,
                 users: Vec::from([RoomUser {
                     id: user_id,
                     username: username.clone(),
                     role_id: role_manager.get_roles()[0].id,
                     is_connected: false,
+                    profile,
                 }]),
                 role_manager,
                 name: name.clone(),
-                password: rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(0x10)
-                    .map(char::from)
-                    .collect::<String>(),
-                logs: VecDeque::with_capacity(MAX_LOGS_LEN),
+                password: self.random.alphanumeric(ROOM_PASSWORD_LEN),
+                invite_code,
+                logs: VecDeque::new(),
+                max_logs_len: crate::config::get().room_max_logs,
+                history: VecDeque::new(),
                 banned_users: Vec::new(),
-                tracks_queue: VecDeque::with_capacity(MAX_TRACKS_QUEUE_LEN),
-                max_users: MAX_USERS,
-                metadata: RoomMetadata::new(creds.into()),
-            },
+                tracks_queue: VecDeque::new(),
+                settings: RoomSettings::default(),
+                queue_edit_grace_period: QUEUE_EDIT_GRACE_PERIOD,
+                metadata,
+            }),
         );
 
         debug!("[{}] Room {} created", id, name);
 
-        let Some(room) = self.active_rooms.get(&id) else {
+        let Some(room) = self.active_rooms.get(&id).map(read_room) else {
             error!(
                 "Unexpected error: Room not created user: {}, name: {}, active rooms len: {} cap: {}",
                 username,
@@ -69,11 +189,291 @@ impl RoomManager {
             return Err(RoomError::RoomCreationFail);
         };
 
+        let room = room.clone();
+
         for user in room.users.iter() {
             self.user_ids.insert(user.id.clone());
         }
 
-        Ok(room.to_owned())
+        self.emit(RoomEvent::Created {
+            room_id: id,
+            owner_id: room.users[0].id.clone(),
+        });
+
+        Ok(room)
+    }
+
+    /// Sets up a room opening at opens_at, see open_due_rooms. A host can only have one pending
+    /// scheduled room
+    pub fn schedule_room(
+        &mut self,
+        host: (RoomUserID, String, UserProfile),
+        name: String,
+        tokens: SpotifyTokens,
+        settings: RoomSettings,
+        opens_at: DateTime<Utc>,
+    ) -> Result<ScheduledRoomInfo, RoomError> {
+        let (user_id, username, profile) = host;
+
+        self.validate_schedule(&user_id, &profile, &settings, opens_at)?;
+
+        let ticket = self.random.uuid();
+        let scheduled = ScheduledRoom {
+            id: Uuid::now_v7(),
+            name,
+            settings,
+            opens_at,
+            tokens,
+            members: vec![ScheduledMember {
+                ticket,
+                user_id,
+                username,
+                profile,
+            }],
+            opened: None,
+        };
+        let info = scheduled.info(ticket);
+
+        debug!(
+            "Room {} scheduled to open at {opens_at} ({})",
+            scheduled.name, scheduled.id
+        );
+
+        self.scheduled_rooms.insert(scheduled.id, scheduled);
+
+        Ok(info)
+    }
+
+    /// Checks of schedule_room, done before consuming the host Spotify grant as well
+    pub fn validate_schedule(
+        &self,
+        user_id: &RoomUserID,
+        profile: &UserProfile,
+        settings: &RoomSettings,
+        opens_at: DateTime<Utc>,
+    ) -> Result<(), RoomError> {
+        let now = self.clock.utc_now();
+
+        profile.validate()?;
+        settings.validate()?;
+
+        if opens_at <= now
+            || (opens_at - now)
+                .to_std()
+                .is_ok_and(|ahead| ahead > MAX_SCHEDULE_AHEAD)
+        {
+            return Err(RoomError::InvalidSchedule);
+        }
+
+        if self.user_id_exists(user_id)
+            || self.scheduled_rooms.values().any(|scheduled| {
+                scheduled.opened.is_none() && scheduled.members[0].user_id == *user_id
+            })
+        {
+            return Err(RoomError::UserIDExists);
+        }
+
+        let pending = self
+            .scheduled_rooms
+            .values()
+            .filter(|scheduled| scheduled.opened.is_none())
+            .count();
+
+        if pending >= MAX_SCHEDULED_ROOMS {
+            return Err(RoomError::InvalidSchedule);
+        }
+
+        Ok(())
+    }
+
+    /// The user joins the room once it's opened, a user already registered gets its own ticket
+    /// back. Registrations are limited to the room max_users
+    pub fn register_scheduled_room(
+        &mut self,
+        id: Uuid,
+        user_id: RoomUserID,
+        username: String,
+        profile: UserProfile,
+    ) -> Result<ScheduledRoomInfo, RoomError> {
+        profile.validate()?;
+
+        let scheduled = self
+            .scheduled_rooms
+            .get_mut(&id)
+            .filter(|scheduled| scheduled.opened.is_none())
+            .ok_or(RoomError::ScheduledRoomNotFound)?;
+
+        if let Some(member) = scheduled
+            .members
+            .iter()
+            .find(|member| member.user_id == user_id)
+        {
+            return Ok(scheduled.info(member.ticket));
+        }
+
+        if scheduled.members.len() >= scheduled.settings.max_users {
+            return Err(RoomError::RoomFull);
+        }
+
+        let ticket = self.random.uuid();
+
+        scheduled.members.push(ScheduledMember {
+            ticket,
+            user_id,
+            username,
+            profile,
+        });
+
+        Ok(scheduled.info(ticket))
+    }
+
+    /// Creates the due scheduled rooms with their settings and joins their registered users, the
+    /// ones that cannot join (e.g. in another room by then) are dropped. A room whose host
+    /// cannot create it is dropped as well. Returns the opened rooms
+    ///
+    /// The opened ones that weren't claimed within SCHEDULED_ROOM_CLAIM_TTL are forgotten
+    pub fn open_due_rooms(&mut self) -> Vec<RoomID> {
+        let now = self.clock.utc_now();
+        let mut opened = Vec::new();
+
+        self.scheduled_rooms.retain(|_, scheduled| {
+            scheduled.opened.is_none_or(|(_, opened_at)| {
+                (now - opened_at)
+                    .to_std()
+                    .is_ok_and(|elapsed| elapsed < SCHEDULED_ROOM_CLAIM_TTL)
+            })
+        });
+
+        let due = self
+            .scheduled_rooms
+            .values()
+            .filter(|scheduled| scheduled.opened.is_none() && scheduled.opens_at <= now)
+            .map(|scheduled| scheduled.id)
+            .collect::<Vec<_>>();
+
+        for id in due {
+            let Some(scheduled) = self.scheduled_rooms.get(&id).cloned() else {
+                continue;
+            };
+            let host = &scheduled.members[0];
+
+            let room_id = match self.create_room(
+                host.user_id.clone(),
+                host.username.clone(),
+                scheduled.name.clone(),
+                scheduled.tokens.clone(),
+                host.profile.clone(),
+            ) {
+                Ok(room) => room.id,
+                Err(err) => {
+                    error!("Failed to open the scheduled room {id}: {err:?}");
+
+                    self.scheduled_rooms.remove(&id);
+
+                    continue;
+                }
+            };
+
+            if let Some(room) = self.get_room_mut(&room_id) {
+                room.settings = scheduled.settings;
+            }
+
+            let mut members = vec![host.clone()];
+
+            for member in scheduled.members.iter().skip(1) {
+                match self.join_room(
+                    room_id,
+                    member.username.clone(),
+                    member.user_id.clone(),
+                    member.profile.clone(),
+                ) {
+                    Ok(_) => members.push(member.clone()),
+                    Err(err) => debug!(
+                        "[{room_id}] Dropped user {} registered to the scheduled room: {err:?}",
+                        member.user_id
+                    ),
+                }
+            }
+
+            // Their join_ttl starts once the claim TTL is over, they learn about the opening
+            // by polling their ticket
+            let claim_deadline = self.clock.now() + SCHEDULED_ROOM_CLAIM_TTL;
+
+            if let Some(room) = self.get_room_mut(&room_id) {
+                for member in &members {
+                    if let Some(joined_at) = room.pending_members.get_mut(&member.user_id) {
+                        *joined_at = claim_deadline;
+                    }
+                }
+            }
+
+            self.emit(RoomEvent::ScheduledRoomOpened {
+                room_id,
+                scheduled_room_id: id,
+            });
+
+            if let Some(scheduled) = self.scheduled_rooms.get_mut(&id) {
+                scheduled.members = members;
+                scheduled.opened = Some((room_id, now));
+            }
+
+            info!("[{room_id}] Scheduled room {} opened", scheduled.name);
+
+            opened.push(room_id);
+        }
+
+        opened
+    }
+
+    /// What the host or registered user sees of the scheduled room, and whether it's opened
+    pub fn scheduled_room_info(
+        &self,
+        id: Uuid,
+        ticket: Uuid,
+    ) -> Result<(ScheduledRoomInfo, bool), RoomError> {
+        self.scheduled_rooms
+            .get(&id)
+            .filter(|scheduled| {
+                scheduled
+                    .members
+                    .iter()
+                    .any(|member| member.ticket == ticket)
+            })
+            .map(|scheduled| (scheduled.info(ticket), scheduled.opened.is_some()))
+            .ok_or(RoomError::ScheduledRoomNotFound)
+    }
+
+    /// The host or registered user gets the room once opened (its ticket is then consumed)
+    pub fn poll_scheduled_room(
+        &mut self,
+        id: Uuid,
+        ticket: Uuid,
+    ) -> Result<ScheduledRoomStatus, RoomError> {
+        let scheduled = self
+            .scheduled_rooms
+            .get_mut(&id)
+            .ok_or(RoomError::ScheduledRoomNotFound)?;
+        let idx = scheduled
+            .members
+            .iter()
+            .position(|member| member.ticket == ticket)
+            .ok_or(RoomError::ScheduledRoomNotFound)?;
+
+        let Some((room_id, _)) = scheduled.opened else {
+            return Ok(ScheduledRoomStatus::Pending(scheduled.info(ticket)));
+        };
+
+        let member = scheduled.members.remove(idx);
+        let room = self
+            .get_room(&room_id)
+            // Left or removed since the opening
+            .filter(|room| room.users.iter().any(|user| user.id == member.user_id))
+            .ok_or(RoomError::ScheduledRoomNotFound)?;
+
+        Ok(ScheduledRoomStatus::Opened {
+            room: Box::new(room.clone()),
+            user_id: member.user_id,
+        })
     }
 
     // If there's a user_id, it means that a user initiated the request
@@ -115,24 +515,59 @@ impl RoomManager {
         }
 
         let users = room.users.clone();
-        let _ = room;
+        let invite_code = room.invite_code.clone();
+        let send_summary = room.settings.session_summary_webhook;
+
+        drop(room);
+
+        if send_summary && let Some(summary) = self.session_summary(room_id) {
+            digest::send_session_summary(&summary);
+        }
+
+        self.invite_codes.remove(&invite_code);
 
         for user in users {
             self.user_ids.remove(&user.id);
         }
 
         self.active_rooms.remove(&room_id);
+        self.tasks.shutdown(room_id);
+        self.emit(RoomEvent::Deleted { room_id });
 
         Ok(())
     }
 
+    /// Flags the room as closing so that it rejects new users and WS sessions until it's deleted
+    pub fn begin_room_closing(&self, room_id: RoomID) -> Result<(), RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        room.lifecycle = RoomLifecycle::Closing;
+
+        debug!("Room ID {room_id} is closing");
+
+        self.emit(RoomEvent::Closing { room_id });
+
+        Ok(())
+    }
+
+    pub fn ensure_room_active(&self, room_id: RoomID) -> Result<(), RoomError> {
+        match self.get_room(&room_id).map(|room| room.lifecycle) {
+            Some(RoomLifecycle::Active) => Ok(()),
+            Some(RoomLifecycle::Closing) => Err(RoomError::RoomClosing),
+            None => Err(RoomError::RoomNotFound),
+        }
+    }
+
+    /// Returns whether the state of the user changed, it's then broadcasted as a presence event
     pub fn set_ws_user_state(
-        &mut self,
+        &self,
         room_id: RoomID,
         user_id: &RoomUserID,
         is_connected: bool,
-    ) -> Result<(), RoomError> {
-        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+    ) -> Result<bool, RoomError> {
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let room = &mut *room;
 
         let user = room
             .users
@@ -140,89 +575,1005 @@ impl RoomManager {
             .find(|c| &c.id == user_id)
             .ok_or(RoomError::RoomUserNotFound)?;
 
+        let has_changed = user.is_connected != is_connected;
         user.is_connected = is_connected;
 
+        if is_connected {
+            let connected_users = room.users.iter().filter(|user| user.is_connected).count();
+
+            room.stats.record_connection(user_id, connected_users);
+            room.pending_members.remove(user_id);
+            room.disconnected_at.remove(user_id);
+
+            // The Spotify data loop is spawned again by the connection
+            if std::mem::take(&mut room.hibernating) {
+                debug!("[{room_id}] Waking up");
+
+                self.emit(RoomEvent::WokeUp { room_id });
+            }
+        } else {
+            room.disconnected_at.insert(user_id.clone(), now);
+        }
+
+        if has_changed {
+            self.emit(RoomEvent::PresenceChanged {
+                room_id,
+                user_id: user_id.clone(),
+                is_connected,
+            });
+        }
+
+        Ok(has_changed)
+    }
+
+    /// Whether the user lost its WS session less than the reconnect grace period ago
+    pub fn is_reconnecting(&self, room_id: RoomID, user_id: &RoomUserID) -> bool {
+        self.active_rooms
+            .get(&room_id)
+            .map(read_room)
+            .and_then(|room| room.disconnected_at.get(user_id).copied())
+            .is_some_and(|disconnected_at| {
+                self.clock.elapsed_since(disconnected_at)
+                    <= crate::config::get().reconnect_grace_period
+            })
+    }
+
+    /// Keeps the user connected while its lost WS session can be resumed, it's marked
+    /// disconnected once the reconnect grace period is over, see expire_suspended_sessions
+    pub fn suspend_ws_user(&self, room_id: RoomID, user_id: &RoomUserID) -> Result<(), RoomError> {
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        if !room.users.iter().any(|user| &user.id == user_id) {
+            return Err(RoomError::RoomUserNotFound);
+        }
+
+        room.disconnected_at.insert(user_id.clone(), now);
+
+        Ok(())
+    }
+
+    /// Rotates the resume token of the user's WS session
+    pub fn issue_resume_token(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+    ) -> Result<String, RoomError> {
+        let token = self.random.alphanumeric(RESUME_TOKEN_LEN);
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        if !room.users.iter().any(|user| &user.id == user_id) {
+            return Err(RoomError::RoomUserNotFound);
+        }
+
+        room.resume_tokens.insert(user_id.clone(), token.clone());
+
+        Ok(token)
+    }
+
+    /// Links the user's next WS sessions to the CreateRoom/JoinRoom request that let it in
+    pub fn set_correlation_id(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        correlation_id: String,
+    ) -> Result<(), RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        if !room.users.iter().any(|user| &user.id == user_id) {
+            return Err(RoomError::RoomUserNotFound);
+        }
+
+        room.correlation_ids.insert(user_id.clone(), correlation_id);
+
         Ok(())
     }
 
-    pub fn get_room(&self, room_id: &RoomID) -> Option<&Room> {
-        let room = self.active_rooms.get(room_id);
+    pub fn correlation_id(&self, room_id: RoomID, user_id: &RoomUserID) -> Option<String> {
+        read_room(self.active_rooms.get(&room_id)?)
+            .correlation_ids
+            .get(user_id)
+            .cloned()
+    }
+
+    /// Whether the token is the one of the user's last WS session and this session is either
+    /// still open (takeover) or was lost less than the reconnect grace period ago
+    pub fn can_resume_session(&self, room_id: RoomID, user_id: &RoomUserID, token: &str) -> bool {
+        let Some(room) = self.active_rooms.get(&room_id).map(read_room) else {
+            return false;
+        };
+
+        room.resume_tokens
+            .get(user_id)
+            .is_some_and(|resume_token| resume_token == token)
+            && room
+                .users
+                .iter()
+                .any(|user| &user.id == user_id && user.is_connected)
+            && room
+                .disconnected_at
+                .get(user_id)
+                .is_none_or(|&disconnected_at| {
+                    self.clock.elapsed_since(disconnected_at)
+                        <= crate::config::get().reconnect_grace_period
+                })
+    }
+
+    /// Marks disconnected the users whose suspended WS session wasn't resumed within
+    /// the reconnect grace period and returns them
+    pub fn expire_suspended_sessions(&self, room_id: RoomID) -> Vec<RoomUserID> {
+        let now = self.clock.now();
+        let grace_period = crate::config::get().reconnect_grace_period;
+        let Some(mut room) = self.lock_room(&room_id) else {
+            return Vec::new();
+        };
+        let room = &mut *room;
+
+        let expired = room
+            .disconnected_at
+            .iter()
+            .filter(|&(_, &disconnected_at)| {
+                now.saturating_duration_since(disconnected_at) > grace_period
+            })
+            .map(|(user_id, _)| user_id.clone())
+            .collect::<Vec<_>>();
+
+        let mut disconnected = Vec::new();
+
+        for user_id in expired {
+            room.resume_tokens.remove(&user_id);
+
+            if let Some(user) = room
+                .users
+                .iter_mut()
+                .find(|user| user.id == user_id && user.is_connected)
+            {
+                user.is_connected = false;
+
+                self.emit(RoomEvent::PresenceChanged {
+                    room_id,
+                    user_id: user_id.clone(),
+                    is_connected: false,
+                });
+                disconnected.push(user_id);
+            }
+        }
+
+        disconnected
+    }
+
+    /// Removes the members that haven't opened a WS session within the room join_ttl after
+    /// joining, returns their IDs
+    pub fn expire_pending_members(&mut self, room_id: RoomID) -> Vec<RoomUserID> {
+        let now = self.clock.now();
+        let Some(room) = self.get_room_mut(&room_id) else {
+            return Vec::new();
+        };
+
+        let Some(join_ttl) = room.settings.join_ttl else {
+            return Vec::new();
+        };
+
+        let expired = room
+            .pending_members
+            .iter()
+            .filter(|&(_, &joined_at)| now.saturating_duration_since(joined_at) >= join_ttl)
+            .map(|(user_id, _)| user_id.clone())
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::new();
+
+        for user_id in expired {
+            room.forget_user(&user_id);
+
+            // Not found when it left or was kicked before connecting
+            if let Some(idx) = room
+                .users
+                .iter()
+                .position(|user| user.id == user_id && !user.is_connected)
+            {
+                removed.push(room.users.remove(idx));
+            }
+        }
+
+        let join_ttl_mins = join_ttl.as_secs() / 60;
+
+        for user in removed.iter() {
+            self.user_ids.remove(&user.id);
+            self.emit(RoomEvent::UserRemoved {
+                room_id,
+                user_id: user.id.clone(),
+                reason: RemovalReason::JoinTtl,
+            });
+
+            let _ = self.append_log(
+                room_id,
+                Log::new(
+                    LogType::LeaveRoom,
+                    None,
+                    format!(
+                        "User \"{}\" was removed for not connecting within {join_ttl_mins} min",
+                        user.username
+                    ),
+                ),
+            );
+        }
+
+        if !removed.is_empty() {
+            debug!(
+                "[{room_id}] Removed {} member(s) that never connected",
+                removed.len()
+            );
+        }
+
+        removed.into_iter().map(|user| user.id).collect()
+    }
+
+    /// Tracks for how long no user has been connected to the room and deletes it once it has
+    /// been inactive for its settings' inactivity_timeout
+    ///
+    /// Returns false when the room doesn't exist (anymore)
+    pub fn check_room_activity(&mut self, room_id: RoomID) -> bool {
+        let now = self.clock.now();
+        let Some(room) = self.get_room_mut(&room_id) else {
+            return false;
+        };
+
+        if room.users.iter().any(|u| u.is_connected) {
+            room.inactive_for = None;
+
+            return true;
+        }
+
+        let inactive_since = *room.inactive_for.get_or_insert(now);
+
+        if now.saturating_duration_since(inactive_since) >= room.settings.inactivity_timeout {
+            let _ = self.delete_room(room_id, None);
+
+            return false;
+        }
+
+        true
+    }
+
+    /// Stops polling Spotify as soon as nobody is connected to the room anymore instead of until
+    /// its deletion by check_room_activity, the next WS connection spawns the Spotify data loop
+    /// again
+    ///
+    /// Returns whether the room just hibernated
+    pub fn hibernate_if_idle(&mut self, room_id: RoomID) -> bool {
+        let Some(room) = self.get_room_mut(&room_id) else {
+            return false;
+        };
+
+        if room.hibernating || room.users.iter().any(|user| user.is_connected) {
+            return false;
+        }
+
+        room.hibernating = true;
+
+        self.tasks.abort(room_id, RoomTaskKind::SpotifyData);
+        self.emit(RoomEvent::Hibernated { room_id });
+
+        debug!("[{room_id}] Hibernating, nobody is connected");
+
+        true
+    }
+
+    /// Tracks for how long none of the connected members can manage the room and applies the
+    /// policy once it lasted OWNERLESS_ROOM_GRACE_PERIOD
+    ///
+    /// A room nobody is connected to with an idle pause is left to check_room_activity since
+    /// its host opted in to come back to it
+    pub fn check_room_owners(&self, room_id: RoomID, policy: OwnerlessRoomPolicy) -> RoomOwnership {
+        let now = self.clock.now();
+        let Some(mut room) = self.lock_room(&room_id) else {
+            return RoomOwnership::Managed;
+        };
 
-        if room.is_none() {
+        let can_manage_room = |user: &RoomUser| {
+            room.role_manager
+                .get_role_by_id(&user.role_id)
+                .is_some_and(|role| role.permissions.can_manage_room)
+        };
+        let is_managed = room
+            .users
+            .iter()
+            .any(|user| user.is_connected && can_manage_room(user));
+        let is_idle = room.settings.idle_pause_after.is_some()
+            && !room.users.iter().any(|user| user.is_connected);
+
+        if is_managed || is_idle || room.lifecycle == RoomLifecycle::Closing {
+            room.ownerless_since = None;
+
+            return RoomOwnership::Managed;
+        }
+
+        let ownerless_since = *room.ownerless_since.get_or_insert(now);
+
+        if now.saturating_duration_since(ownerless_since) < OWNERLESS_ROOM_GRACE_PERIOD {
+            return RoomOwnership::Managed;
+        }
+
+        room.ownerless_since = None;
+
+        if room.settings.ownerless_policy(policy) == OwnerlessRoomPolicy::Close {
+            return RoomOwnership::Ownerless;
+        }
+
+        let Some(heir_id) = room
+            .fallback_owner(|user| user.is_connected)
+            .map(|user| user.id.clone())
+        else {
+            return RoomOwnership::Ownerless;
+        };
+
+        match Self::promote_owner(
+            &mut room,
+            &heir_id,
+            "since no owner was connected",
+            self.clock.utc_now(),
+        ) {
+            Ok(role_id) => {
+                self.emit(RoomEvent::RoleChanged {
+                    room_id,
+                    user_id: heir_id.clone(),
+                    role_id,
+                });
+                self.emit(RoomEvent::OwnerChanged {
+                    room_id,
+                    user_id: heir_id.clone(),
+                });
+
+                RoomOwnership::Transferred(heir_id)
+            }
+            Err(_) => RoomOwnership::Ownerless,
+        }
+    }
+
+    /// Gives the most powerful role able to manage the room to the user, the reason completes
+    /// the log
+    ///
+    /// Returns the ID of the role
+    fn promote_owner(
+        room: &mut Room,
+        user_id: &RoomUserID,
+        reason: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Uuid, RoomError> {
+        let owner_role_id = room
+            .role_manager
+            .get_roles()
+            .iter()
+            .find(|role| role.permissions.can_manage_room)
+            .map(|role| role.id)
+            .ok_or(RoomError::RoleNotFound)?;
+
+        let user = room
+            .users
+            .iter_mut()
+            .find(|user| user.id == *user_id)
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        user.role_id = owner_role_id;
+
+        let username = user.username.clone();
+
+        debug!("[{}] User ID {user_id} is now an owner {reason}", room.id);
+
+        room.push_log(
+            Log::new(
+                LogType::RoleChange,
+                None,
+                format!("User \"{username}\" became an owner {reason}"),
+            ),
+            now,
+        );
+
+        Ok(owner_role_id)
+    }
+
+    /// Flags the room as idle paused once no user has been connected for its settings'
+    /// idle_pause_after, returns whether the playback has to be paused
+    pub fn take_idle_pause(&self, room_id: RoomID) -> bool {
+        let now = self.clock.now();
+        let Some(mut room) = self.lock_room(&room_id) else {
+            return false;
+        };
+
+        let Some(idle_pause_after) = room.settings.idle_pause_after else {
+            return false;
+        };
+
+        if room.idle_paused
+            || !room.now_playing.as_ref().is_some_and(|p| p.is_playing)
+            || room.inactive_for.is_none_or(|inactive_since| {
+                now.saturating_duration_since(inactive_since) < idle_pause_after
+            })
+        {
+            return false;
+        }
+
+        room.idle_paused = true;
+        room.push_log(
+            Log::new(
+                LogType::Playback,
+                None,
+                format!(
+                    "Playback paused, no user has been connected for {} min",
+                    idle_pause_after.as_secs() / 60
+                ),
+            ),
+            self.clock.utc_now(),
+        );
+
+        true
+    }
+
+    /// Returns whether the playback was paused by the idle mode and unflags it
+    pub fn take_idle_resume(&self, room_id: RoomID) -> bool {
+        self.lock_room(&room_id)
+            .is_some_and(|mut room| std::mem::take(&mut room.idle_paused))
+    }
+
+    /// The room read guard must be dropped before locking the room again (lock_room, the
+    /// RoomManager methods taking &self...), the room locks aren't reentrant
+    pub fn get_room(&self, room_id: &RoomID) -> Option<RoomReadGuard<'_>> {
+        let Some(room) = self.active_rooms.get(room_id) else {
             error!("Cannot find room id: {}", room_id);
 
             return None;
-        }
+        };
 
-        room
+        Some(read_room(room))
     }
 
-    pub fn get_room_mut(&mut self, room_id: &RoomID) -> Option<&mut Room> {
-        let room = self.active_rooms.get_mut(room_id);
+    /// Room write guard for the room scoped work done under the state read lock, see get_room
+    pub fn lock_room(&self, room_id: &RoomID) -> Option<RoomWriteGuard<'_>> {
+        let Some(room) = self.active_rooms.get(room_id) else {
+            error!("Cannot find room id: {room_id}");
 
-        if room.is_none() {
+            return None;
+        };
+
+        Some(write_room(room))
+    }
+
+    /// Under the state write lock, nobody else can hold a room lock
+    pub fn get_room_mut(&mut self, room_id: &RoomID) -> Option<&mut Room> {
+        let Some(room) = self.active_rooms.get_mut(room_id) else {
             error!("Cannot find room id: {room_id}");
 
             return None;
-        }
+        };
 
-        room
+        Some(room_mut(room))
     }
 
-    pub fn get_room_for_user_id(&self, user_id: RoomUserID) -> Option<&Room> {
-        self.active_rooms
+    pub fn rooms(&self) -> impl Iterator<Item = RoomReadGuard<'_>> {
+        self.active_rooms.values().map(read_room)
+    }
+
+    pub fn rooms_count(&self) -> usize {
+        self.active_rooms.len()
+    }
+
+    /// Codes are case insensitive
+    pub fn get_room_by_invite_code(&self, code: &str) -> Option<RoomReadGuard<'_>> {
+        self.invite_codes
+            .get(&code.to_ascii_uppercase())
+            .and_then(|room_id| self.active_rooms.get(room_id))
+            .map(read_room)
+    }
+
+    /// Replaces the room invite code, the previous one doesn't resolve anymore
+    pub fn regenerate_invite_code(&mut self, room_id: RoomID) -> Result<String, RoomError> {
+        let invite_code = self.generate_invite_code();
+        let room = self
+            .active_rooms
+            .get_mut(&room_id)
+            .map(room_mut)
+            .ok_or(RoomError::RoomNotFound)?;
+
+        let previous = std::mem::replace(&mut room.invite_code, invite_code.clone());
+
+        self.invite_codes.remove(&previous);
+        self.invite_codes.insert(invite_code.clone(), room_id);
+
+        Ok(invite_code)
+    }
+
+    fn generate_invite_code(&self) -> String {
+        loop {
+            let code = self
+                .random
+                .string_from(INVITE_CODE_CHARSET, INVITE_CODE_LEN);
+
+            if !self.invite_codes.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    /// Starts a Spotify login for the client, returns its OAuth state and PKCE code verifier or
+    /// None when the client or the server have too many logins pending
+    pub fn begin_spotify_login(&mut self, client: IpAddr) -> Option<(String, String)> {
+        let now = self.clock.now();
+
+        self.prune_spotify_logins();
+
+        let client_logins = self
+            .spotify_logins
             .values()
-            .find(|&p| p.users.iter().any(|user| user.id == user_id))
+            .filter(|(_, login_client, _)| *login_client == client)
+            .count();
+
+        if client_logins >= MAX_PENDING_SPOTIFY_LOGINS_PER_CLIENT
+            || self.spotify_logins.len() >= MAX_PENDING_SPOTIFY_LOGINS
+        {
+            return None;
+        }
+
+        let state = self.random.alphanumeric(SPOTIFY_GRANT_LEN);
+        let code_verifier = generate_code_verifier(&*self.random);
+
+        self.spotify_logins.insert(
+            state.clone(),
+            (code_verifier.clone(), client, now + SPOTIFY_LOGIN_TTL),
+        );
+
+        Some((state, code_verifier))
     }
 
-    pub fn add_track_to_queue(
+    /// Drops the expired Spotify logins and grants, also run by the sweeper so the abandoned
+    /// ones don't wait for the next login
+    pub fn prune_spotify_logins(&mut self) {
+        let now = self.clock.now();
+
+        self.spotify_logins
+            .retain(|_, (_, _, expires_at)| *expires_at > now);
+        self.spotify_grants
+            .retain(|_, (_, expires_at)| *expires_at > now);
+    }
+
+    /// The code verifier of the login, a state can only be used once
+    pub fn take_spotify_login(&mut self, state: &str) -> Option<String> {
+        let (code_verifier, _, expires_at) = self.spotify_logins.remove(state)?;
+
+        (expires_at > self.clock.now()).then_some(code_verifier)
+    }
+
+    /// Keeps the tokens of a completed Spotify login until CreateRoom, returns the grant sent back
+    /// instead of the tokens and its expiration
+    pub fn store_spotify_grant(&mut self, tokens: SpotifyTokens) -> (String, DateTime<Utc>) {
+        let now = self.clock.now();
+
+        self.prune_spotify_logins();
+
+        let grant = self.random.alphanumeric(SPOTIFY_GRANT_LEN);
+
+        self.spotify_grants
+            .insert(grant.clone(), (tokens, now + SPOTIFY_GRANT_TTL));
+
+        (grant, self.clock.utc_now() + SPOTIFY_GRANT_TTL)
+    }
+
+    /// The tokens of the grant, a grant can only be used once
+    pub fn take_spotify_grant(&mut self, grant: &str) -> Result<SpotifyTokens, RoomError> {
+        match self.spotify_grants.remove(grant) {
+            Some((tokens, expires_at)) if expires_at > self.clock.now() => Ok(tokens),
+            _ => Err(RoomError::InvalidSpotifyGrant),
+        }
+    }
+
+    /// Returns a page of public rooms, most recent first, and the total public rooms count
+    pub fn get_public_rooms(&self, offset: usize, limit: usize) -> (Vec<PublicRoom>, usize) {
+        let limit = match limit {
+            0 => DEFAULT_PUBLIC_ROOMS_PAGE_LEN,
+            limit => limit.min(MAX_PUBLIC_ROOMS_PAGE_LEN),
+        };
+
+        let mut rooms = self
+            .rooms()
+            .filter(|room| room.settings.is_public)
+            .collect::<Vec<_>>();
+
+        // UUIDv7 are sorted by creation time
+        rooms.sort_unstable_by_key(|room| std::cmp::Reverse(room.id));
+
+        (
+            rooms
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|room| (&**room).into())
+                .collect(),
+            rooms.len(),
+        )
+    }
+
+    /// Every room, public or not, oldest first
+    pub fn admin_room_summaries(&self) -> Vec<AdminRoomSummary> {
+        let mut rooms = self
+            .rooms()
+            .map(|room| AdminRoomSummary::from(&*room))
+            .collect::<Vec<_>>();
+
+        rooms.sort_unstable_by_key(|room| room.id);
+
+        rooms
+    }
+
+    /// Spotify rate limiter of each room, they're behind an async lock so the caller reads them
+    /// once the state guard is released
+    pub fn rate_limiters(&self) -> Vec<(RoomID, Arc<RwLock<RateLimiter>>)> {
+        self.rooms()
+            .map(|room| (room.id, Arc::clone(&room.spotify_handler.rate_limiter)))
+            .collect()
+    }
+
+    /// Kick issued by an operator through the admin API, it bypasses the role checks and the
+    /// log has no author
+    ///
+    /// Kicking the last owner hands the room over like when it leaves, see leave_room
+    pub fn admin_kick_user(
         &mut self,
         room_id: RoomID,
+        user_id: &RoomUserID,
+        reason: String,
+    ) -> Result<(), RoomError> {
+        if !self.hand_over_last_owner_role(room_id, user_id, "since the last owner was kicked")? {
+            debug!("[{room_id}] An operator kicked the last owner {user_id}: {reason}");
+
+            return self.delete_room(room_id, None);
+        }
+
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let Some(user) = room.users.iter().find(|c| c.id == *user_id).cloned() else {
+            return Err(RoomError::RoomUserNotFound);
+        };
+
+        room.users.retain(|c| c.id != *user_id);
+        room.forget_user(user_id);
+
+        self.user_ids.remove(&user.id);
+        self.emit(RoomEvent::UserRemoved {
+            room_id,
+            user_id: user.id.clone(),
+            reason: RemovalReason::Operator,
+        });
+
+        debug!("[{room_id}] An operator kicked user ID {user_id}: {reason}");
+
+        self.append_log(
+            room_id,
+            Log::new(
+                LogType::Kick,
+                None,
+                format!(
+                    "An operator kicked {} from the room for: {}",
+                    user.username, reason
+                ),
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_room_for_user_id(&self, user_id: RoomUserID) -> Option<RoomReadGuard<'_>> {
+        self.rooms()
+            .find(|room| room.users.iter().any(|user| user.id == user_id))
+    }
+
+    pub fn add_track_to_queue(
+        &self,
+        room_id: RoomID,
         user_id: RoomUserID,
         track_id: String,
         track_name: String,
         track_duration: u32,
+        item_type: PlaybackItemType,
     ) -> Result<(), RoomError> {
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let user = room
+            .users
+            .iter()
+            .find(|c| c.id == user_id)
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        if room.tracks_queue.len() >= room.settings.max_tracks_queue_len {
+            return Err(RoomError::QueueFull);
+        }
+
+        debug!(
+            "{} added {} to room {} {}",
+            user.username, track_name, room.name, room_id
+        );
+
+        let username = user.username.clone();
+
+        room.listening_stats.record_queued(&user_id);
+        self.emit(RoomEvent::TrackQueued {
+            room_id,
+            user_id: user_id.clone(),
+            track_id: track_id.clone(),
+        });
+        room.enqueue_track(RoomTrack {
+            track_id,
+            user_id: user_id.clone(),
+            track_name: track_name.clone(),
+            track_duration,
+            item_type,
+            added_at: now,
+        });
+        room.push_log(
+            Log::new(
+                LogType::AddTrack,
+                Some(user_id),
+                format!("User \"{}\" added \"{}\" to queue", username, track_name),
+            ),
+            self.clock.utc_now(),
+        );
+
+        Ok(())
+    }
+
+    /// Pushes to Spotify the queued tracks that aren't in its queue yet: every one of them, or
+    /// in fair queue mode, the head one once the previous one started playing
+    ///
+    /// Neither the state nor the room are locked during the Spotify requests, the tracks are
+    /// reserved beforehand so concurrent pushes don't queue them twice
+    pub async fn push_queued_tracks(
+        state: &RwLock<Self>,
+        room_id: RoomID,
+    ) -> Result<(), ProviderError> {
+        let (provider, tracks) = {
+            let guard = state.read().await;
+            let Some(mut room) = guard.lock_room(&room_id) else {
+                return Ok(());
+            };
+
+            (room.music_provider(), room.reserve_unpushed_tracks())
+        };
+
+        let Err((err, unpushed)) = push_tracks(provider.as_ref(), &tracks).await else {
+            return Ok(());
+        };
+
+        if let Some(mut room) = state.read().await.lock_room(&room_id) {
+            room.release_unpushed_tracks(unpushed);
+        }
+
+        Err(err)
+    }
+
+    /// Queues a recommendation once the queue of a room with auto_fill ran empty, seeded by the
+    /// tracks of the connected members (see recommendation::taste_seeds). None when the room
+    /// doesn't need one or nobody queued anything yet
+    ///
+    /// The fill is reserved for GAP_FILL_COOLDOWN beforehand so the concurrent fetches don't
+    /// queue several tracks. The recommended track isn't part of the room queue
+    pub async fn fill_queue_gap(
+        state: &RwLock<Self>,
+        room_id: RoomID,
+    ) -> Result<Option<(SpotifyTrack, Seed)>, ProviderError> {
+        let (spotify, provider, settings, seed, known_track_ids) = {
+            let guard = state.read().await;
+            let clock = guard.clock();
+            let Some(mut room) = guard.lock_room(&room_id) else {
+                return Ok(None);
+            };
+
+            if !room.settings.auto_fill
+                || !room.tracks_queue.is_empty()
+                || room
+                    .last_gap_fill
+                    .is_some_and(|instant| clock.elapsed_since(instant) < GAP_FILL_COOLDOWN)
+            {
+                return Ok(None);
+            }
+
+            let seeds = recommendation::taste_seeds(&room);
+            let Some(seed) = recommendation::pick_seed(&seeds, &mut rand::rng()).cloned() else {
+                return Ok(None);
+            };
+
+            room.last_gap_fill = Some(clock.now());
+
+            (
+                room.spotify_handler.clone(),
+                room.music_provider(),
+                room.settings,
+                seed,
+                recommendation::known_track_ids(&room),
+            )
+        };
+
+        let mut tracks = spotify.get_recommendations(seed.track_id.clone()).await?;
+
+        crate::config::get()
+            .content_policy
+            .filter_tracks(&mut tracks);
+        tracks.retain(|track| {
+            settings.allows_track(track) && !known_track_ids.contains(&track.track_id)
+        });
+
+        let Some(track) = tracks.choose(&mut rand::rng()).cloned() else {
+            return Ok(None);
+        };
+
+        provider
+            .add_to_queue(track.track_id.clone(), track.item_type)
+            .await?;
+
+        Ok(Some((track, seed)))
+    }
+
+    /// Lets the member stream the room on its own Spotify account: the tokens of the grant are
+    /// kept for the Web Playback SDK device and it follows the room playback from now on
+    pub fn register_listener(
+        &mut self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        grant: &str,
+        device_id: String,
+    ) -> Result<(), RoomError> {
+        if !self
+            .get_room_mut(&room_id)
+            .ok_or(RoomError::RoomNotFound)?
+            .users
+            .iter()
+            .any(|user| user.id == *user_id)
+        {
+            return Err(RoomError::RoomUserNotFound);
+        }
+
+        let tokens = self.take_spotify_grant(grant)?;
+        let spotify = self.spotify_handler(tokens);
         let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
 
-        let user = room
-            .users
-            .iter()
-            .find(|c| c.id == user_id)
-            .ok_or(RoomError::RoomUserNotFound)?;
+        room.listeners.insert(
+            user_id.clone(),
+            Listener {
+                spotify,
+                device_id,
+                synced: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// The device of the member stops following the room, its tokens are dropped
+    pub fn unregister_listener(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+    ) -> Result<(), RoomError> {
+        self.lock_room(&room_id)
+            .ok_or(RoomError::RoomNotFound)?
+            .listeners
+            .remove(user_id)
+            .map(|_| ())
+            .ok_or(RoomError::RoomUserNotFound)
+    }
+
+    /// Spotify handler of the member's own account, with tokens valid for TOKEN_REFRESH_MARGIN
+    /// at least (refreshed and stored otherwise)
+    pub async fn listener_spotify(
+        state: &RwLock<Self>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+    ) -> Result<Spotify, SpotifyError> {
+        let (mut spotify, now) = {
+            let guard = state.read().await;
+            let room = guard
+                .get_room(&room_id)
+                .ok_or(SpotifyError::Generic("Room not found".into()))?;
+            let listener = room
+                .listeners
+                .get(user_id)
+                .ok_or(SpotifyError::Generic("Not a listener of the room".into()))?;
+
+            (listener.spotify.clone(), guard.clock.utc_now())
+        };
+
+        if spotify.tokens.expires_at().is_some_and(|expires_at| {
+            expires_at - now > TimeDelta::from_std(TOKEN_REFRESH_MARGIN).unwrap_or_default()
+        }) {
+            return Ok(spotify);
+        }
 
-        room.tracks_queue.push_back(RoomTrack {
-            track_id,
-            user_id,
-            track_name: track_name.clone(),
-            track_duration,
-        });
+        let tokens = spotify.fetch_refresh_token().await?;
 
-        debug!(
-            "{} added {} to room {} {}",
-            user.username, track_name, room.name, room_id
-        );
+        if let Some(mut room) = state.read().await.lock_room(&room_id)
+            && let Some(listener) = room.listeners.get_mut(user_id)
+        {
+            listener.spotify.tokens = tokens;
+        }
 
-        let username = user.username.clone();
+        Ok(spotify)
+    }
 
-        self.append_log(
-            room_id,
-            Log::new(
-                LogType::AddTrack,
-                format!("User \"{}\" added \"{}\" to queue", username, track_name),
-            ),
-        )?;
+    /// Plays the current item of the room at its position on the devices of the listeners that
+    /// don't follow it anymore (or only on the device of forced), pauses them when the room is
+    /// paused
+    ///
+    /// Listeners whose account refuses it (revoked tokens, non-premium account...) are dropped,
+    /// the others are retried on the next playback fetch. Returns the last error
+    pub async fn sync_listeners(
+        state: &RwLock<Self>,
+        room_id: RoomID,
+        forced: Option<&RoomUserID>,
+    ) -> Result<(), SpotifyError> {
+        let syncs = {
+            let guard = state.read().await;
+            let now = guard.clock.now();
+            let Some(mut room) = guard.lock_room(&room_id) else {
+                return Ok(());
+            };
+
+            room.take_listener_syncs(now, forced)
+        };
+        let mut result = Ok(());
+
+        for sync in syncs {
+            let synced = async {
+                let spotify = Self::listener_spotify(state, room_id, &sync.user_id).await?;
+
+                match sync.position_ms {
+                    Some(position_ms) => {
+                        spotify
+                            .play_on_device(
+                                &sync.device_id,
+                                &sync.track_id,
+                                sync.item_type,
+                                position_ms,
+                            )
+                            .await
+                    }
+                    None => spotify.pause_device(&sync.device_id).await,
+                }
+            }
+            .await;
+
+            let Err(err) = synced else {
+                continue;
+            };
+            let guard = state.read().await;
+
+            if let Some(mut room) = guard.lock_room(&room_id) {
+                if matches!(
+                    err,
+                    SpotifyError::Unauthorized(_) | SpotifyError::Forbidden(_)
+                ) {
+                    debug!(
+                        "[{room_id}] Dropped the listener {} refused by Spotify",
+                        decode_user_email(&sync.user_id)
+                    );
+
+                    room.listeners.remove(&sync.user_id);
+                } else if let Some(listener) = room.listeners.get_mut(&sync.user_id) {
+                    listener.synced = None;
+                }
+            }
 
-        Ok(())
+            result = Err(err);
+        }
+
+        result
     }
 
     /// Sort of fail-free fn that can be ran each time Spotify current playback is fetched
     pub fn remove_track_from_queue(
-        &mut self,
+        &self,
         room_id: RoomID,
         track_id: String,
-    ) -> Result<(), RoomError> {
-        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+    ) -> Result<bool, RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
 
         if room
             .tracks_queue
@@ -230,15 +1581,104 @@ impl RoomManager {
             .is_some_and(|t| t.track_id == track_id)
         {
             let track = room.tracks_queue.pop_front();
+            room.pushed_tracks_len = room.pushed_tracks_len.saturating_sub(1);
+
+            self.emit(RoomEvent::TrackDequeued { room_id, track_id });
 
             debug!(
                 "Removed track {:?} from room ID {} queue",
                 track.map(|t| t.track_name),
                 room.id
             );
+
+            return Ok(true);
         }
 
-        Ok(())
+        Ok(false)
+    }
+
+    /// Returns the queue index of the most recent occurrence of track_id the author is allowed to
+    /// edit: its own track within the room grace period, or any track if it can use the controls
+    pub fn find_editable_queued_track(
+        &self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        track_id: &str,
+    ) -> Result<usize, RoomError> {
+        let room = self.get_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        self.editable_queued_track(&room, author_id, track_id)
+    }
+
+    fn editable_queued_track(
+        &self,
+        room: &Room,
+        author_id: &RoomUserID,
+        track_id: &str,
+    ) -> Result<usize, RoomError> {
+        let author = room
+            .users
+            .iter()
+            .find(|c| c.id == *author_id)
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        let can_use_controls = room
+            .role_manager
+            .get_role_by_id(&author.role_id)
+            .ok_or(RoomError::RoleNotFound)?
+            .permissions
+            .can_use_controls;
+
+        let idx = room
+            .tracks_queue
+            .iter()
+            .rposition(|t| t.track_id == track_id && (can_use_controls || t.user_id == *author_id))
+            .ok_or(RoomError::TrackNotFound)?;
+
+        if !can_use_controls
+            && self.clock.elapsed_since(room.tracks_queue[idx].added_at)
+                > room.queue_edit_grace_period
+        {
+            return Err(RoomError::QueueEditExpired);
+        }
+
+        Ok(idx)
+    }
+
+    /// Removes a queued track from the room queue, see find_editable_queued_track for the rules
+    ///
+    /// Since the Spotify queue cannot be edited, the track is flagged to be skipped when it starts
+    /// if it was already pushed to Spotify
+    pub fn remove_queued_track(
+        &self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        track_id: &str,
+    ) -> Result<RoomTrack, RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let idx = self.editable_queued_track(&room, author_id, track_id)?;
+
+        let track = room
+            .tracks_queue
+            .remove(idx)
+            .ok_or(RoomError::TrackNotFound)?;
+
+        if idx < room.pushed_tracks_len {
+            room.tracks_to_skip.push(track.track_id.clone());
+            room.pushed_tracks_len -= 1;
+        }
+
+        debug!(
+            "[{}] User ID {} removed {} from queue",
+            room_id, author_id, track.track_name
+        );
+
+        self.emit(RoomEvent::TrackDequeued {
+            room_id,
+            track_id: track.track_id.clone(),
+        });
+
+        Ok(track)
     }
 
     pub fn kick_user(
@@ -270,13 +1710,20 @@ impl RoomManager {
         };
 
         room.users.retain(|c| c.id != *user_id);
+        room.forget_user(user_id);
 
         self.user_ids.remove(&user.id);
+        self.emit(RoomEvent::UserRemoved {
+            room_id,
+            user_id: user.id.clone(),
+            reason: RemovalReason::Kicked,
+        });
 
         self.append_log(
             room_id,
             Log::new(
                 LogType::Kick,
+                Some(author_id.clone()),
                 format!(
                     "User {} kicked {} from the room for: {}",
                     author.username, user.username, reason
@@ -314,15 +1761,22 @@ impl RoomManager {
         };
 
         room.users.retain(|c| c.id != *user_id);
+        room.forget_user(user_id);
 
         room.banned_users.push(user_id.clone());
 
         self.user_ids.remove(&user.id);
+        self.emit(RoomEvent::UserRemoved {
+            room_id,
+            user_id: user.id.clone(),
+            reason: RemovalReason::Banned,
+        });
 
         self.append_log(
             room_id,
             Log::new(
                 LogType::Ban,
+                Some(author_id.clone()),
                 format!(
                     "User {} banned {} from the room for: {}",
                     author.username, user.username, reason
@@ -333,12 +1787,128 @@ impl RoomManager {
         Ok(())
     }
 
+    pub fn kick_users(
+        &mut self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        user_ids: &[RoomUserID],
+        reason: String,
+    ) -> Result<ModerationResults, RoomError> {
+        self.remove_users(room_id, author_id, user_ids, reason, false)
+    }
+
+    pub fn ban_users(
+        &mut self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        user_ids: &[RoomUserID],
+        reason: String,
+    ) -> Result<ModerationResults, RoomError> {
+        self.remove_users(room_id, author_id, user_ids, reason, true)
+    }
+
+    /// Kicks (or bans) every valid target at once with a single log entry and returns the
+    /// result of each target, the author cannot target itself
+    fn remove_users(
+        &mut self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        user_ids: &[RoomUserID],
+        reason: String,
+        is_ban: bool,
+    ) -> Result<ModerationResults, RoomError> {
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let Some(author) = room.users.iter().find(|c| c.id == *author_id).cloned() else {
+            error!(
+                "Unexpected error: Bulk moderation attempt from author id {author_id} that's not in the room id {room_id}"
+            );
+
+            return Err(RoomError::Unreachable);
+        };
+
+        let mut results = Vec::with_capacity(user_ids.len());
+        let mut removed = Vec::new();
+
+        for user_id in user_ids {
+            if results.iter().any(|(id, _)| id == user_id) {
+                continue;
+            }
+
+            if user_id == author_id {
+                results.push((user_id.clone(), Err(RoomError::Unauthorized)));
+                continue;
+            }
+
+            let Some(idx) = room.users.iter().position(|c| c.id == *user_id) else {
+                results.push((user_id.clone(), Err(RoomError::RoomUserNotFound)));
+                continue;
+            };
+
+            let user = room.users.remove(idx);
+            room.forget_user(&user.id);
+
+            if is_ban {
+                room.banned_users.push(user.id.clone());
+            }
+
+            removed.push(user);
+            results.push((user_id.clone(), Ok(())));
+        }
+
+        if removed.is_empty() {
+            return Ok(results);
+        }
+
+        for user in &removed {
+            self.user_ids.remove(&user.id);
+            self.emit(RoomEvent::UserRemoved {
+                room_id,
+                user_id: user.id.clone(),
+                reason: match is_ban {
+                    true => RemovalReason::Banned,
+                    false => RemovalReason::Kicked,
+                },
+            });
+        }
+
+        let (log_type, action) = if is_ban {
+            (LogType::Ban, "banned")
+        } else {
+            (LogType::Kick, "kicked")
+        };
+
+        self.append_log(
+            room_id,
+            Log::new(
+                log_type,
+                Some(author_id.clone()),
+                format!(
+                    "User {} {action} {} from the room for: {reason}",
+                    author.username,
+                    removed
+                        .iter()
+                        .map(|user| user.username.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            ),
+        )?;
+
+        Ok(results)
+    }
+
     pub fn join_room(
         &mut self,
         room_id: RoomID,
         username: String,
         user_id: RoomUserID,
+        profile: UserProfile,
     ) -> Result<Room, RoomError> {
+        let now = self.clock.now();
+
+        profile.validate()?;
+
         if self.user_id_exists(&user_id) {
             error!(
                 "Error: user ID (approx email: {}) is already in use",
@@ -348,13 +1918,25 @@ impl RoomManager {
             return Err(RoomError::UserIDExists);
         }
 
+        self.ensure_room_active(room_id)?;
+
         let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
 
         if room.banned_users.contains(&user_id) {
             return Err(RoomError::UserBanned);
         }
 
-        if room.users.len() == room.max_users {
+        if room.users.len() >= room.settings.max_users {
+            return Err(RoomError::RoomFull);
+        }
+
+        // The free slots go to the waiting users first, in order, see admit_waitlisted
+        if room
+            .waitlist
+            .iter()
+            .find(|entry| !entry.admitted && !entry.is_expired(now))
+            .is_some_and(|entry| entry.user_id != user_id)
+        {
             return Err(RoomError::RoomFull);
         }
 
@@ -362,9 +1944,11 @@ impl RoomManager {
             Some(role) => role,
             None => {
                 let guest = Role::new_guest();
-                let _ = room
-                    .role_manager
-                    .add_role(guest.name.clone(), guest.permissions);
+                let _ = room.role_manager.add_role(
+                    guest.name.clone(),
+                    guest.permissions,
+                    guest.display.clone(),
+                );
 
                 guest
             }
@@ -375,18 +1959,25 @@ impl RoomManager {
             role_id: role.id,
             username: username.clone(),
             is_connected: false,
+            profile,
         });
+        room.pending_members.insert(user_id.clone(), now);
 
         let room = room.clone();
 
         debug!("[{}] Added {} to Room {}", room_id, username, room.name);
 
-        self.user_ids.insert(user_id);
+        self.user_ids.insert(user_id.clone());
+        self.emit(RoomEvent::UserJoined {
+            room_id,
+            user_id: user_id.clone(),
+        });
 
         self.append_log(
             room_id,
             Log::new(
                 LogType::JoinRoom,
+                Some(user_id),
                 format!("User \"{}\" joined the room", username),
             ),
         )?;
@@ -394,36 +1985,285 @@ impl RoomManager {
         Ok(room)
     }
 
-    pub fn leave_room(&mut self, room_id: RoomID, user_id: RoomUserID) -> Result<(), RoomError> {
-        if self.is_user_an_owner_and_alone(room_id, &user_id)? {
-            return self.delete_room(room_id, Some(user_id));
+    /// Queues the user joining the full room when its waitlist is enabled, returns its ticket
+    /// and position (a user already waiting gets its own back)
+    pub fn join_waitlist(
+        &mut self,
+        room_id: RoomID,
+        username: String,
+        user_id: RoomUserID,
+        profile: UserProfile,
+    ) -> Result<(Uuid, usize), RoomError> {
+        let now = self.clock.now();
+        let ticket = self.random.uuid();
+
+        profile.validate()?;
+
+        if self.user_id_exists(&user_id) {
+            return Err(RoomError::UserIDExists);
+        }
+
+        self.ensure_room_active(room_id)?;
+
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        if room.banned_users.contains(&user_id) {
+            return Err(RoomError::UserBanned);
+        }
+
+        room.waitlist
+            .retain(|entry| entry.admitted || !entry.is_expired(now));
+
+        let waiting = room.waitlist.iter().filter(|entry| !entry.admitted);
+
+        if let Some((position, entry)) = waiting
+            .clone()
+            .enumerate()
+            .find(|(_, entry)| entry.user_id == user_id)
+        {
+            return Ok((entry.ticket, position + 1));
+        }
+
+        if !room.settings.waitlist || waiting.count() >= MAX_WAITLIST_LEN {
+            return Err(RoomError::RoomFull);
+        }
+
+        room.waitlist.push_back(WaitlistEntry {
+            ticket,
+            user_id,
+            username,
+            profile,
+            polled_at: now,
+            admitted: false,
+        });
+
+        Ok((
+            ticket,
+            room.waitlist.iter().filter(|entry| !entry.admitted).count(),
+        ))
+    }
+
+    /// Admits the first waiting users while the room has free slots, the users that cannot
+    /// join anymore (banned, joined another room...) are dropped. Returns the admitted ones
+    pub fn admit_waitlisted(&mut self, room_id: RoomID) -> Vec<RoomUserID> {
+        let now = self.clock.now();
+        let mut admitted = Vec::new();
+
+        while let Some(room) = self.get_room_mut(&room_id) {
+            let is_enabled = room.settings.waitlist;
+
+            room.waitlist
+                .retain(|entry| !entry.is_expired(now) && (entry.admitted || is_enabled));
+
+            if room.users.len() >= room.settings.max_users {
+                break;
+            }
+
+            let Some(entry) = room.waitlist.iter().find(|entry| !entry.admitted).cloned() else {
+                break;
+            };
+
+            match self.join_room(
+                room_id,
+                entry.username,
+                entry.user_id.clone(),
+                entry.profile,
+            ) {
+                Ok(_) => {
+                    if let Some(admitted_entry) = self.get_room_mut(&room_id).and_then(|room| {
+                        room.waitlist
+                            .iter_mut()
+                            .find(|waiting| waiting.ticket == entry.ticket)
+                    }) {
+                        admitted_entry.admitted = true;
+                    }
+
+                    admitted.push(entry.user_id);
+                }
+                Err(err) => {
+                    debug!(
+                        "[{room_id}] Dropped waitlisted user {}: {err:?}",
+                        entry.user_id
+                    );
+
+                    if let Some(room) = self.get_room_mut(&room_id) {
+                        room.waitlist
+                            .retain(|waiting| waiting.ticket != entry.ticket);
+                    }
+                }
+            }
+        }
+
+        admitted
+    }
+
+    /// Keeps the place of the waiting user, the admitted one gets the room and its entry is
+    /// removed
+    pub fn poll_waitlist(
+        &mut self,
+        room_id: RoomID,
+        ticket: Uuid,
+    ) -> Result<WaitlistStatus, RoomError> {
+        let now = self.clock.now();
+
+        self.admit_waitlisted(room_id);
+
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let idx = room
+            .waitlist
+            .iter()
+            .position(|entry| entry.ticket == ticket)
+            .ok_or(RoomError::NotWaitlisted)?;
+
+        if room.waitlist[idx].admitted {
+            let entry = room.waitlist.remove(idx).ok_or(RoomError::NotWaitlisted)?;
+
+            // Kicked or gone since its admission
+            if !room.users.iter().any(|user| user.id == entry.user_id) {
+                return Err(RoomError::NotWaitlisted);
+            }
+
+            return Ok(WaitlistStatus::Admitted {
+                room: Box::new(room.clone()),
+                user_id: entry.user_id,
+            });
+        }
+
+        room.waitlist[idx].polled_at = now;
+
+        Ok(WaitlistStatus::Waiting {
+            position: room
+                .waitlist
+                .iter()
+                .take(idx)
+                .filter(|entry| !entry.admitted)
+                .count()
+                + 1,
+        })
+    }
+
+    /// The room is deleted when its last owner leaves, unless its OwnerlessRoomPolicy hands the
+    /// owner role over to a remaining member, see fallback_owner_on_leave
+    pub fn leave_room(&mut self, room_id: RoomID, user_id: RoomUserID) -> Result<(), RoomError> {
+        if !self.hand_over_last_owner_role(room_id, &user_id, "since the last owner left")? {
+            return self.delete_room(room_id, Some(user_id));
+        }
+
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let user = room
+            .users
+            .iter()
+            .find(|c| c.id == user_id)
+            .cloned()
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        room.users.retain(|c| c.id != user_id);
+        room.forget_user(&user_id);
+
+        debug!(
+            "Removed {} from room {} {}",
+            user.username, room.name, room_id
+        );
+
+        self.user_ids.remove(&user.id);
+        self.emit(RoomEvent::UserLeft {
+            room_id,
+            user_id: user.id.clone(),
+        });
+
+        self.append_log(
+            room_id,
+            Log::new(
+                LogType::LeaveRoom,
+                Some(user.id),
+                format!("User \"{}\" left the room", user.username),
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// Gives the author's role to the user and demotes the author to the most powerful role that
+    /// cannot manage the room, so the author can then leave without closing it
+    pub fn transfer_ownership(
+        &self,
+        room_id: RoomID,
+        author_id: &RoomUserID,
+        user_id: &RoomUserID,
+    ) -> Result<(), RoomError> {
+        if author_id == user_id {
+            return Err(RoomError::Unauthorized);
+        }
+
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let author = room
+            .users
+            .iter()
+            .find(|c| c.id == *author_id)
+            .cloned()
+            .ok_or(RoomError::RoomUserNotFound)?;
+        let owner_role = room
+            .role_manager
+            .get_role_by_id(&author.role_id)
+            .ok_or(RoomError::RoleNotFound)?;
+
+        if !owner_role.permissions.can_manage_room {
+            return Err(RoomError::Unauthorized);
         }
 
-        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let owner_role_id = owner_role.id;
+        let demoted_role_id = room
+            .role_manager
+            .get_roles()
+            .iter()
+            .find(|role| !role.permissions.can_manage_room)
+            .map(|role| role.id)
+            .ok_or(RoomError::RoleNotFound)?;
 
         let user = room
             .users
-            .iter()
-            .find(|c| c.id == user_id)
-            .cloned()
+            .iter_mut()
+            .find(|c| c.id == *user_id)
             .ok_or(RoomError::RoomUserNotFound)?;
 
-        room.users.retain(|c| c.id != user_id);
+        user.role_id = owner_role_id;
 
-        debug!(
-            "Removed {} from room {} {}",
-            user.username, room.name, room_id
-        );
+        let username = user.username.clone();
 
-        self.user_ids.remove(&user.id);
+        if let Some(author) = room.users.iter_mut().find(|c| c.id == *author_id) {
+            author.role_id = demoted_role_id;
+        }
 
-        self.append_log(
+        debug!("[{room_id}] User ID {author_id} transferred the ownership to user ID {user_id}");
+
+        self.emit(RoomEvent::RoleChanged {
+            room_id,
+            user_id: user_id.clone(),
+            role_id: owner_role_id,
+        });
+        self.emit(RoomEvent::RoleChanged {
+            room_id,
+            user_id: author_id.clone(),
+            role_id: demoted_role_id,
+        });
+        self.emit(RoomEvent::OwnerChanged {
             room_id,
+            user_id: user_id.clone(),
+        });
+
+        room.push_log(
             Log::new(
-                LogType::LeaveRoom,
-                format!("User \"{}\" left the room", user.username),
+                LogType::RoleChange,
+                Some(author_id.clone()),
+                format!(
+                    "User \"{}\" transferred the room ownership to \"{username}\"",
+                    author.username
+                ),
             ),
-        )?;
+            self.clock.utc_now(),
+        );
 
         Ok(())
     }
@@ -549,13 +2389,15 @@ impl RoomManager {
     //     Ok(())
     // }
 
+    /// The username is trimmed, see Room::validate_username
     pub fn change_username(
-        &mut self,
+        &self,
         room_id: RoomID,
         user_id: RoomUserID,
         username: String,
     ) -> Result<(), RoomError> {
-        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let username = room.validate_username(&user_id, &username)?.to_owned();
 
         let user = room
             .users
@@ -563,24 +2405,442 @@ impl RoomManager {
             .find(|c| c.id == user_id)
             .ok_or(RoomError::RoomUserNotFound)?;
 
-        let old_username = user.username.clone();
+        if user.username == username {
+            return Ok(());
+        }
 
-        user.username.clone_from(&username);
+        let old_username = std::mem::replace(&mut user.username, username.clone());
 
-        self.append_log(
-            room_id,
+        room.push_log(
             Log::new(
                 LogType::UsernameChange,
+                Some(user_id.clone()),
                 format!(
                     "User \"{}\" changed its username to \"{}\"",
                     old_username, username
                 ),
             ),
-        )?;
+            self.clock.utc_now(),
+        );
+
+        drop(room);
+
+        self.emit(RoomEvent::UsernameChanged { room_id, user_id });
+
+        Ok(())
+    }
+
+    /// Replaces the whole profile of the user
+    pub fn update_profile(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        profile: UserProfile,
+    ) -> Result<(), RoomError> {
+        profile.validate()?;
+
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let user = room
+            .users
+            .iter_mut()
+            .find(|user| user.id == *user_id)
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        user.profile = profile;
+
+        Ok(())
+    }
+
+    pub fn update_room_settings(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        settings: RoomSettings,
+    ) -> Result<(), RoomError> {
+        settings.validate()?;
+
+        let now = self.clock.utc_now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        debug!(
+            "[{}] User ID {} updated the room settings: {:?}",
+            room_id, user_id, settings
+        );
+
+        // The first digest is the next due one, not the one of the current day if its hour passed
+        if let Some(digest) = settings.daily_digest
+            && room.settings.daily_digest != Some(digest)
+        {
+            room.last_digest_on = digest.due_on(now);
+        }
+
+        room.settings = settings;
+
+        self.emit(RoomEvent::SettingsUpdated { room_id });
+
+        Ok(())
+    }
+
+    pub fn session_summary(&self, room_id: RoomID) -> Option<SessionSummary> {
+        let room = self.get_room(&room_id)?;
+        let stats = &room.listening_stats;
+
+        let top_contributor = stats
+            .tracks_queued
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(user_id, tracks_queued)| TopContributor {
+                user_id: user_id.clone(),
+                username: room
+                    .users
+                    .iter()
+                    .find(|user| &user.id == user_id)
+                    .map(|user| user.username.clone()),
+                tracks_queued: *tracks_queued,
+            });
+
+        Some(SessionSummary {
+            room_id,
+            room_name: room.name.clone(),
+            duration: (self.clock.utc_now() - room.created_at)
+                .to_std()
+                .unwrap_or_default(),
+            tracks_played: stats.tracks_played,
+            top_contributor,
+        })
+    }
+
+    /// Takes the stats of the rooms whose daily digest is due, they start over from then
+    pub fn take_due_digests(&mut self) -> Vec<RoomDigest> {
+        let now = self.clock.utc_now();
+
+        self.active_rooms
+            .values_mut()
+            .map(room_mut)
+            .filter_map(|room| {
+                let date = room.settings.daily_digest?.due_on(now)?;
+
+                if room.last_digest_on == Some(date) {
+                    return None;
+                }
+
+                room.last_digest_on = Some(date);
+
+                let connected_users = room.users.iter().filter(|user| user.is_connected);
+                let stats = RoomStats {
+                    listeners: connected_users
+                        .clone()
+                        .map(|user| user.id.clone())
+                        .collect(),
+                    peak_concurrency: connected_users.count(),
+                    ..Default::default()
+                };
+
+                Some(RoomDigest {
+                    room_id: room.id,
+                    room_name: room.name.clone(),
+                    date,
+                    stats: std::mem::replace(&mut room.stats, stats),
+                })
+            })
+            .collect()
+    }
+
+    /// Starts the DJ rotation or changes the duration of the next turns if it's already running
+    pub fn start_dj_rotation(
+        &self,
+        room_id: RoomID,
+        turn_duration: Duration,
+    ) -> Result<(), RoomError> {
+        if !(MIN_DJ_TURN_DURATION..=MAX_DJ_TURN_DURATION).contains(&turn_duration) {
+            return Err(RoomError::InvalidSettings);
+        }
+
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let last_dj_turn_seq = room.last_dj_turn_seq;
+
+        let rotation = room
+            .dj_rotation
+            .get_or_insert_with(|| DjRotation::new(turn_duration, now, last_dj_turn_seq));
+
+        rotation.turn_duration = turn_duration;
+        // Back to timed turns, the current one starts over
+        if rotation.turn_tracks.take().is_some() {
+            rotation.restart_turn(now);
+        }
+
+        self.emit(RoomEvent::DjRotationUpdated { room_id });
+
+        Ok(())
+    }
+
+    /// Starts the DJ rotation in DJ mode, or switches the running one to it: the turns last
+    /// turn_tracks tracks and only the current DJ can use the controls
+    pub fn start_dj_mode(&self, room_id: RoomID, turn_tracks: u32) -> Result<(), RoomError> {
+        if !(1..=MAX_DJ_TURN_TRACKS).contains(&turn_tracks) {
+            return Err(RoomError::InvalidSettings);
+        }
+
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let last_dj_turn_seq = room.last_dj_turn_seq;
+
+        let rotation = room.dj_rotation.get_or_insert_with(|| {
+            DjRotation::new(DEFAULT_DJ_TURN_DURATION, now, last_dj_turn_seq)
+        });
+
+        // Switched from timed turns, the current one starts over so its scheduled end is stale
+        if rotation.turn_tracks.replace(turn_tracks).is_none() {
+            rotation.restart_turn(now);
+        }
+
+        self.emit(RoomEvent::DjRotationUpdated { room_id });
+
+        Ok(())
+    }
+
+    /// Counts a track that started playing for the DJ mode turns, see
+    /// DjRotation::record_track_played
+    ///
+    /// Returns whether the turn has been given to the next member
+    pub fn record_dj_track(&self, room_id: RoomID) -> bool {
+        let now = self.clock.now();
+        let Some(mut room) = self.lock_room(&room_id) else {
+            return false;
+        };
+        let user_ids = room.users.iter().map(|u| u.id.clone()).collect::<Vec<_>>();
+        let Some(rotation) = room.dj_rotation.as_mut().filter(|r| r.is_dj_mode()) else {
+            return false;
+        };
+
+        rotation.retain_members(&user_ids, now);
+
+        if !rotation.record_track_played(now) {
+            return false;
+        }
+
+        self.emit(RoomEvent::DjTurnChanged {
+            room_id,
+            dj_id: rotation.current_dj().cloned(),
+        });
+
+        true
+    }
+
+    pub fn stop_dj_rotation(&self, room_id: RoomID) -> Result<(), RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let rotation = room
+            .dj_rotation
+            .take()
+            .ok_or(RoomError::DjRotationNotStarted)?;
+
+        room.last_dj_turn_seq = rotation.turn_seq();
+
+        self.emit(RoomEvent::DjRotationStopped { room_id });
+
+        Ok(())
+    }
+
+    pub fn set_dj_rotation_opt_in(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        opt_in: bool,
+    ) -> Result<(), RoomError> {
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let rotation = room
+            .dj_rotation
+            .as_mut()
+            .ok_or(RoomError::DjRotationNotStarted)?;
+
+        if opt_in {
+            rotation.opt_in(user_id.clone(), now);
+        } else {
+            rotation.opt_out(user_id, now);
+        }
 
         Ok(())
     }
 
+    /// Proposes a seek of the current track or votes for the running proposal, it passes once a
+    /// majority of the connected members voted for it. The caller applies the returned
+    /// seek_to_ms
+    pub fn vote_seek(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        offset_ms: i64,
+    ) -> Result<SeekVoteStatus, RoomError> {
+        if offset_ms == 0 || offset_ms.unsigned_abs() > MAX_VOTE_SEEK_OFFSET.as_millis() as u64 {
+            return Err(RoomError::InvalidSeekOffset);
+        }
+
+        let now = self.clock.now();
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+        let progress_ms = room
+            .playback_progress_ms(now)
+            .ok_or(RoomError::TrackNotFound)?;
+        let (track_id, duration_ms) = room
+            .now_playing
+            .as_ref()
+            .map(|playback| (playback.track_id.clone(), playback.duration_ms))
+            .ok_or(RoomError::TrackNotFound)?;
+        let connected_users = room.users.iter().filter(|user| user.is_connected).count();
+        let required_votes = connected_users.max(1) / 2 + 1;
+
+        let mut vote = match room.seek_vote.take() {
+            Some(vote) if !vote.is_stale(&track_id, now) && vote.offset_ms != offset_ms => {
+                room.seek_vote = Some(vote);
+
+                return Err(RoomError::SeekVoteRunning);
+            }
+            Some(vote) if !vote.is_stale(&track_id, now) => vote,
+            _ => SeekVote {
+                track_id: track_id.clone(),
+                offset_ms,
+                voters: HashSet::new(),
+                started_at: now,
+            },
+        };
+
+        vote.voters.insert(user_id.clone());
+
+        let votes = vote.voters.len();
+        let seek_to_ms = (votes >= required_votes).then(|| {
+            progress_ms
+                .saturating_add_signed(offset_ms)
+                .min(duration_ms)
+        });
+
+        if seek_to_ms.is_none() {
+            room.seek_vote = Some(vote);
+        }
+
+        Ok(SeekVoteStatus {
+            track_id,
+            offset_ms,
+            votes,
+            required_votes,
+            seek_to_ms,
+        })
+    }
+
+    /// Whether the user is the DJ of a turn that hasn't ended yet
+    pub fn is_current_dj(&self, room_id: RoomID, user_id: &RoomUserID) -> bool {
+        self.active_rooms
+            .get(&room_id)
+            .map(read_room)
+            .is_some_and(|room| {
+                room.dj_rotation.as_ref().is_some_and(|rotation| {
+                    rotation.current_dj() == Some(user_id)
+                        && rotation.is_turn_running(self.clock.now())
+                })
+            })
+    }
+
+    /// See DjRotation::take_unscheduled_turn
+    pub fn take_unscheduled_dj_turn(&self, room_id: RoomID) -> Option<DjTurn> {
+        let (now, utc_now) = (self.clock.now(), self.clock.utc_now());
+
+        self.active_rooms
+            .get(&room_id)
+            .map(write_room)?
+            .dj_rotation
+            .as_mut()?
+            .take_unscheduled_turn(now, utc_now)
+    }
+
+    /// Gives the turn to the next member still in the room, unless the turn (seq) already
+    /// changed in the meantime (opt-out, rotation stopped...)
+    ///
+    /// Returns whether the turn has been given
+    pub fn end_dj_turn(&self, room_id: RoomID, seq: u64) -> bool {
+        let now = self.clock.now();
+        let Some(mut room) = self.active_rooms.get(&room_id).map(write_room) else {
+            return false;
+        };
+        let user_ids = room.users.iter().map(|u| u.id.clone()).collect::<Vec<_>>();
+        let Some(rotation) = room.dj_rotation.as_mut() else {
+            return false;
+        };
+
+        if !rotation.is_turn_current(seq) {
+            return false;
+        }
+
+        rotation.retain_members(&user_ids, now);
+
+        if rotation.is_turn_current(seq) {
+            rotation.next_turn(now);
+        }
+
+        self.emit(RoomEvent::DjTurnChanged {
+            room_id,
+            dj_id: rotation.current_dj().cloned(),
+        });
+
+        true
+    }
+
+    /// Gives the owner role to the fallback owner when the user is the last one, before it's
+    /// removed from the room, the reason completes the log
+    ///
+    /// Returns false when nobody takes it over and the room has to be closed
+    fn hand_over_last_owner_role(
+        &mut self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        reason: &str,
+    ) -> Result<bool, RoomError> {
+        if !self.is_user_an_owner_and_alone(room_id, user_id)? {
+            return Ok(true);
+        }
+
+        let Some(heir_id) = self.fallback_owner_on_leave(room_id, user_id) else {
+            return Ok(false);
+        };
+
+        let now = self.clock.utc_now();
+        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let role_id = Self::promote_owner(room, &heir_id, reason, now)?;
+
+        self.emit(RoomEvent::RoleChanged {
+            room_id,
+            user_id: heir_id.clone(),
+            role_id,
+        });
+        self.emit(RoomEvent::OwnerChanged {
+            room_id,
+            user_id: heir_id,
+        });
+
+        Ok(true)
+    }
+
+    /// Member getting the owner role when the last owner leaves, None when the room has to be
+    /// closed (OwnerlessRoomPolicy Close or nobody left)
+    pub fn fallback_owner_on_leave(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+    ) -> Option<RoomUserID> {
+        let room = read_room(self.active_rooms.get(&room_id)?);
+        let policy = room
+            .settings
+            .ownerless_policy(crate::config::get().ownerless_room_policy);
+
+        if policy == OwnerlessRoomPolicy::Close {
+            return None;
+        }
+
+        room.fallback_owner(|user| user.id != *user_id)
+            .map(|user| user.id.clone())
+    }
+
     /// Returns whether a user is an owner/room manager and if s.he is alone to control the room
     pub fn is_user_an_owner_and_alone(
         &self,
@@ -590,6 +2850,7 @@ impl RoomManager {
         let room = self
             .active_rooms
             .get(&room_id)
+            .map(read_room)
             .ok_or(RoomError::RoomNotFound)?;
 
         let user = room
@@ -626,19 +2887,172 @@ impl RoomManager {
                 <= 1)
     }
 
+    pub fn user_ids_count(&self) -> usize {
+        self.user_ids.len()
+    }
+
+    /// Rebuilds user_ids from the actual room members, returns the count of orphan IDs removed
+    /// and of members that were missing
+    pub fn reconcile_user_ids(&mut self) -> (usize, usize) {
+        let members = self
+            .rooms()
+            .flat_map(|room| {
+                room.users
+                    .iter()
+                    .map(|user| user.id.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+
+        let orphans = self.user_ids.difference(&members).count();
+        let missing = members.difference(&self.user_ids).count();
+
+        if orphans > 0 || missing > 0 {
+            self.user_ids = members;
+        }
+
+        (orphans, missing)
+    }
+
+    /// Matches the connection flag of every member against its WS session, has_session tells
+    /// whether the user has a live session in the room:
+    /// - A member flagged connected without a session is suspended, it's then disconnected like
+    ///   any lost session once the reconnect grace period is over
+    /// - A member with a session but flagged disconnected (or suspended) is connected back
+    ///
+    /// Returns how many ghost members and unflagged sessions were fixed
+    pub fn reconcile_connections(
+        &mut self,
+        has_session: impl Fn(&RoomID, &RoomUserID) -> bool,
+    ) -> (usize, usize) {
+        let mut ghost_members = Vec::new();
+        let mut unflagged_sessions = Vec::new();
+
+        for room in self.rooms() {
+            for user in room.users.iter() {
+                let is_suspended = room.disconnected_at.contains_key(&user.id);
+
+                match (has_session(&room.id, &user.id), user.is_connected) {
+                    (false, true) if !is_suspended => {
+                        ghost_members.push((room.id, user.id.clone()))
+                    }
+                    (true, false) => unflagged_sessions.push((room.id, user.id.clone())),
+                    (true, true) if is_suspended => {
+                        unflagged_sessions.push((room.id, user.id.clone()))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for (room_id, user_id) in ghost_members.iter() {
+            let _ = self.suspend_ws_user(*room_id, user_id);
+        }
+
+        for (room_id, user_id) in unflagged_sessions.iter() {
+            let _ = self.set_ws_user_state(*room_id, user_id, true);
+        }
+
+        (ghost_members.len(), unflagged_sessions.len())
+    }
+
+    /// Whether the user is a member of the room, used to find dangling WS sessions
+    pub fn is_room_member(&self, room_id: &RoomID, user_id: &RoomUserID) -> bool {
+        self.active_rooms
+            .get(room_id)
+            .map(read_room)
+            .is_some_and(|room| room.users.iter().any(|user| user.id == *user_id))
+    }
+
     pub fn user_id_exists(&self, user_id: &RoomUserID) -> bool {
         self.user_ids.contains(user_id)
     }
 
-    pub fn append_log(&mut self, room_id: RoomID, log: Log) -> Result<(), RoomError> {
-        let room = self.get_room_mut(&room_id).ok_or(RoomError::RoomNotFound)?;
+    pub fn append_log(&self, room_id: RoomID, log: Log) -> Result<(), RoomError> {
+        self.lock_room(&room_id)
+            .ok_or(RoomError::RoomNotFound)?
+            .push_log(log, self.clock.utc_now());
 
-        if room.logs.len() >= MAX_LOGS_LEN {
-            room.logs.pop_front();
-        }
+        Ok(())
+    }
 
-        room.logs.push_back(log);
+    /// Logs an action made by a room user, the details are prefixed with its username
+    pub fn append_user_log(
+        &self,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        r#type: LogType,
+        action: String,
+    ) -> Result<(), RoomError> {
+        let mut room = self.lock_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let user = room
+            .users
+            .iter()
+            .find(|c| c.id == *user_id)
+            .ok_or(RoomError::RoomUserNotFound)?;
+
+        let details = format!("User \"{}\" {action}", user.username);
+
+        room.push_log(
+            Log::new(r#type, Some(user_id.clone()), details),
+            self.clock.utc_now(),
+        );
 
         Ok(())
     }
+
+    /// Returns a page of the logs matching the filter, most recent first, and their total count
+    pub fn get_logs(
+        &self,
+        room_id: RoomID,
+        filter: &LogFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<Log>, usize), RoomError> {
+        let room = self.get_room(&room_id).ok_or(RoomError::RoomNotFound)?;
+
+        let limit = match limit {
+            0 => DEFAULT_LOGS_PAGE_LEN,
+            limit => limit.min(MAX_LOGS_PAGE_LEN),
+        };
+
+        // Logs are appended in chronological order, the date range is binary searched
+        let start = filter.since.map_or(0, |since| {
+            room.logs.partition_point(|log| log.created_at < since)
+        });
+        let end = filter.until.map_or(room.logs.len(), |until| {
+            room.logs.partition_point(|log| log.created_at <= until)
+        });
+        let logs = room.logs.range(start..end.max(start));
+
+        let total = if filter.types.is_empty() {
+            logs.len()
+        } else {
+            logs.clone().filter(|log| filter.matches_type(log)).count()
+        };
+
+        Ok((
+            logs.rev()
+                .filter(|log| filter.matches_type(log))
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+            total,
+        ))
+    }
+}
+
+/// A panic while a room was locked doesn't make it unusable, its lock is taken anyway
+fn read_room(room: &sync::RwLock<Room>) -> RoomReadGuard<'_> {
+    room.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_room(room: &sync::RwLock<Room>) -> RoomWriteGuard<'_> {
+    room.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn room_mut(room: &mut sync::RwLock<Room>) -> &mut Room {
+    room.get_mut().unwrap_or_else(PoisonError::into_inner)
 }
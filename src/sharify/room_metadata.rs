@@ -1,43 +1,636 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use tokio::sync::{Mutex, mpsc};
 
-use super::spotify::{Spotify, SpotifyTokens};
+use crate::proto;
+
+use super::clock::SharedClock;
+use super::music_provider::{ProviderError, ProviderKind};
+use super::room::{MAX_LISTENER_DRIFT, RoomTrack, RoomUserID, SEEK_VOTE_TTL};
+use super::signed_url::SigningSecret;
+use super::spotify::web_utils::{PlaybackItemType, SpotifyCurrentPlaybackOutput, SpotifyTackArray};
+use super::spotify::{PlaybackScheduler, Spotify, SpotifyTokens};
+use super::waitlist::WaitlistEntry;
+use super::websocket::commands::{CommandDedupCache, CommandRateLimiter};
+
+/// Snapshot of the playback taken when it's parked (interrupted) so it can be restored as is
+#[derive(Clone, Debug)]
+pub struct ParkedPlayback {
+    pub track_id: String,
+    pub item_type: PlaybackItemType,
+    pub progress_ms: u64,
+    pub was_playing: bool,
+    pub tracks_queue: VecDeque<RoomTrack>,
+    pub parked_by: RoomUserID,
+    pub parked_at: Instant,
+}
+
+/// Member playing the room on its own Spotify account (Web Playback SDK player), its device
+/// follows the room playback, see RoomManager::sync_listeners
+#[derive(Clone, Debug)]
+pub struct Listener {
+    /// Separate from the host's spotify_handler, with its own tokens and rate limit
+    pub spotify: Spotify,
+    pub device_id: String,
+    pub synced: Option<SyncedPlayback>,
+}
+
+/// Playback last applied to the device of a listener
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncedPlayback {
+    pub track_id: String,
+    /// None when paused
+    pub position_ms: Option<u64>,
+    pub synced_at: Instant,
+}
+
+impl SyncedPlayback {
+    /// Whether the device still plays the current playback, the position it reached since the
+    /// sync is compared to the current one
+    pub fn follows(&self, current: &SyncedPlayback) -> bool {
+        let elapsed_ms = current
+            .synced_at
+            .saturating_duration_since(self.synced_at)
+            .as_millis();
+        let position_ms = self
+            .position_ms
+            .map(|position_ms| position_ms + elapsed_ms as u64);
+
+        self.track_id == current.track_id
+            && match (position_ms, current.position_ms) {
+                (Some(position_ms), Some(current_ms)) => {
+                    position_ms.abs_diff(current_ms) <= MAX_LISTENER_DRIFT.as_millis() as u64
+                }
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// Playback to apply to the device of a listener, see Room::take_listener_syncs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerSync {
+    pub user_id: RoomUserID,
+    pub device_id: String,
+    pub track_id: String,
+    pub item_type: PlaybackItemType,
+    /// None to pause the device
+    pub position_ms: Option<u64>,
+}
+
+/// Opted-in members take turns with a temporary can_use_controls window, the turn moves to the
+/// next member once the window is over
+///
+/// In DJ mode (see turn_tracks), the turns last a number of tracks instead and the controls are
+/// reserved to the current DJ
+#[derive(Clone, Debug)]
+pub struct DjRotation {
+    pub turn_duration: Duration,
+    /// DJ mode, the turn moves to the next member every turn_tracks played tracks
+    pub turn_tracks: Option<u32>,
+    /// Tracks that started playing during the current turn
+    pub turn_tracks_played: u32,
+    /// Opted-in members, in turn order
+    pub members: Vec<RoomUserID>,
+    /// Index of the current DJ in members
+    pub current: usize,
+    pub turn_started_at: Instant,
+    /// Incremented on each turn so a scheduled turn end can tell if it's stale
+    turn_seq: u64,
+    /// Last turn whose end has been scheduled
+    scheduled_seq: Option<u64>,
+}
+
+/// Snapshot of a DJ turn, broadcasted when it starts
+#[derive(Clone, Debug)]
+pub struct DjTurn {
+    pub user_id: RoomUserID,
+    pub seq: u64,
+    /// None in DJ mode, the turn ends with its tracks
+    pub remaining: Option<Duration>,
+    pub ends_at: Option<DateTime<Utc>>,
+    /// Only in DJ mode
+    pub turn_tracks: Option<u32>,
+}
+
+impl DjRotation {
+    /// turn_seq is the last one of the previous rotation, so its scheduled turn end is stale
+    pub fn new(turn_duration: Duration, now: Instant, turn_seq: u64) -> Self {
+        Self {
+            turn_duration,
+            turn_tracks: None,
+            turn_tracks_played: 0,
+            members: Vec::new(),
+            current: 0,
+            turn_started_at: now,
+            turn_seq,
+            scheduled_seq: None,
+        }
+    }
+
+    pub fn turn_seq(&self) -> u64 {
+        self.turn_seq
+    }
+
+    pub fn current_dj(&self) -> Option<&RoomUserID> {
+        self.members.get(self.current)
+    }
+
+    pub fn is_turn_current(&self, seq: u64) -> bool {
+        self.turn_seq == seq
+    }
+
+    pub fn is_dj_mode(&self) -> bool {
+        self.turn_tracks.is_some()
+    }
+
+    /// Whether the current DJ still has the controls at now
+    pub fn is_turn_running(&self, now: Instant) -> bool {
+        self.is_dj_mode()
+            || now.saturating_duration_since(self.turn_started_at) < self.turn_duration
+    }
+
+    /// Opts out the members that left the room
+    pub fn retain_members(&mut self, user_ids: &[RoomUserID], now: Instant) {
+        for left_user_id in self
+            .members
+            .iter()
+            .filter(|id| !user_ids.contains(id))
+            .cloned()
+            .collect::<Vec<_>>()
+        {
+            self.opt_out(&left_user_id, now);
+        }
+    }
+
+    /// Counts a track that started playing, in DJ mode the turn moves to the next member once
+    /// the current DJ played its turn_tracks
+    ///
+    /// Returns whether the current DJ changed
+    pub fn record_track_played(&mut self, now: Instant) -> bool {
+        let Some(turn_tracks) = self.turn_tracks else {
+            return false;
+        };
+
+        if self.members.is_empty() {
+            return false;
+        }
+
+        self.turn_tracks_played += 1;
+
+        if self.turn_tracks_played < turn_tracks {
+            return false;
+        }
+
+        let previous_dj = self.current_dj().cloned();
+
+        self.next_turn(now);
+
+        self.current_dj() != previous_dj.as_ref()
+    }
+
+    /// Returns whether the current DJ changed
+    pub fn opt_in(&mut self, user_id: RoomUserID, now: Instant) -> bool {
+        if self.members.contains(&user_id) {
+            return false;
+        }
+
+        self.members.push(user_id);
+
+        // First member, its turn starts right away
+        if self.members.len() == 1 {
+            self.start_turn(0, now);
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns whether the current DJ changed
+    pub fn opt_out(&mut self, user_id: &RoomUserID, now: Instant) -> bool {
+        let Some(idx) = self.members.iter().position(|id| id == user_id) else {
+            return false;
+        };
+
+        self.members.remove(idx);
+
+        match idx.cmp(&self.current) {
+            std::cmp::Ordering::Less => {
+                self.current -= 1;
+
+                false
+            }
+            std::cmp::Ordering::Equal => {
+                // The next member took its index
+                self.start_turn(self.current, now);
+
+                true
+            }
+            std::cmp::Ordering::Greater => false,
+        }
+    }
+
+    /// Gives the turn to the next member
+    pub fn next_turn(&mut self, now: Instant) {
+        self.start_turn(self.current + 1, now);
+    }
+
+    /// The current DJ gets a new turn, the end of the previous one is stale
+    pub fn restart_turn(&mut self, now: Instant) {
+        self.start_turn(self.current, now);
+    }
+
+    /// Returns the current turn if its end hasn't been scheduled yet and flags it as scheduled
+    pub fn take_unscheduled_turn(
+        &mut self,
+        now: Instant,
+        utc_now: DateTime<Utc>,
+    ) -> Option<DjTurn> {
+        if self.scheduled_seq == Some(self.turn_seq) {
+            return None;
+        }
+
+        let user_id = self.current_dj()?.clone();
+        let remaining = (!self.is_dj_mode()).then(|| {
+            self.turn_duration
+                .saturating_sub(now.saturating_duration_since(self.turn_started_at))
+        });
+
+        self.scheduled_seq = Some(self.turn_seq);
+
+        Some(DjTurn {
+            user_id,
+            seq: self.turn_seq,
+            remaining,
+            ends_at: remaining.map(|remaining| utc_now + remaining),
+            turn_tracks: self.turn_tracks,
+        })
+    }
+
+    fn start_turn(&mut self, idx: usize, now: Instant) {
+        self.current = if self.members.is_empty() {
+            0
+        } else {
+            idx % self.members.len()
+        };
+        self.turn_started_at = now;
+        self.turn_tracks_played = 0;
+        self.turn_seq += 1;
+    }
+}
+
+/// Seek of the current track proposed with VoteSeek, see RoomManager::vote_seek
+#[derive(Clone, Debug)]
+pub struct SeekVote {
+    pub track_id: String,
+    /// From the playback position when the vote passes, negative to seek backward
+    pub offset_ms: i64,
+    pub voters: HashSet<RoomUserID>,
+    pub started_at: Instant,
+}
+
+impl SeekVote {
+    /// Whether another proposal can replace it
+    pub fn is_stale(&self, track_id: &str, now: Instant) -> bool {
+        self.track_id != track_id || now.saturating_duration_since(self.started_at) >= SEEK_VOTE_TTL
+    }
+}
+
+/// Outcome of a VoteSeek
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeekVoteStatus {
+    pub track_id: String,
+    pub offset_ms: i64,
+    pub votes: usize,
+    pub required_votes: usize,
+    /// Set once passed, the position to seek to
+    pub seek_to_ms: Option<u64>,
+}
+
+/// Health of the room Spotify tokens so the owners can re-authenticate before they're unusable
+#[derive(Clone, Debug)]
+pub struct SpotifyTokensStatus {
+    /// Negative once expired, None when the tokens creation date is invalid
+    pub expires_in: Option<TimeDelta>,
+    pub refreshed_at: Option<DateTime<Utc>>,
+    pub refresh_failures: u32,
+}
+
+/// Spotify unreachable by the room data loop, the room is degraded (no playback updates) rather
+/// than closed until the outage max duration, see RoomMetadata::record_spotify_failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpotifyOutage {
+    pub since: Instant,
+    /// Consecutive failed fetches
+    pub failures: u32,
+    /// Spotify rejected the tokens (401) after their refresh failed, retrying won't help
+    pub tokens_rejected: bool,
+}
+
+impl SpotifyOutage {
+    /// Delay before the next fetch, doubled on each failure
+    pub fn retry_in(&self) -> Duration {
+        let config = crate::config::get();
+
+        config
+            .spotify_outage_retry_backoff
+            .saturating_mul(2u32.saturating_pow(self.failures.saturating_sub(1)))
+            .min(config.spotify_outage_max_retry_interval)
+    }
+
+    /// Zero once the room should be closed
+    pub fn closes_in(&self, now: Instant) -> Duration {
+        if self.tokens_rejected {
+            return Duration::ZERO;
+        }
+
+        crate::config::get()
+            .spotify_outage_max_duration
+            .saturating_sub(now.saturating_duration_since(self.since))
+    }
+}
+
+/// Activity since the last daily digest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomStats {
+    pub tracks_played: u32,
+    /// Users who connected at least once
+    pub listeners: HashSet<RoomUserID>,
+    /// Most users connected at the same time
+    pub peak_concurrency: usize,
+}
+
+impl RoomStats {
+    pub fn record_connection(&mut self, user_id: &RoomUserID, connected_users: usize) {
+        self.listeners.insert(user_id.clone());
+        self.peak_concurrency = self.peak_concurrency.max(connected_users);
+    }
+}
+
+/// Most played artists listed by GetStats
+pub const TOP_ARTISTS_LEN: usize = 10;
+
+/// Listening activity since the room was created, answered by GetStats and broadcasted one last
+/// time when the room closes. Unlike RoomStats, it's never reset
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListeningStats {
+    pub tracks_played: u32,
+    /// Time the room playback was playing
+    pub listening_time: Duration,
+    /// Tracks queued by each member, SurpriseMe picks included
+    pub tracks_queued: HashMap<RoomUserID, u32>,
+    /// SkipNext commands of each member
+    pub skips: HashMap<RoomUserID, u32>,
+    /// Played tracks by artist, as displayed by the playback (features included)
+    pub artist_plays: HashMap<String, u32>,
+}
+
+impl ListeningStats {
+    pub fn record_played(&mut self, artist_name: &str) {
+        self.tracks_played += 1;
+
+        if !artist_name.is_empty() {
+            *self.artist_plays.entry(artist_name.to_owned()).or_default() += 1;
+        }
+    }
+
+    pub fn record_queued(&mut self, user_id: &RoomUserID) {
+        *self.tracks_queued.entry(user_id.clone()).or_default() += 1;
+    }
+
+    pub fn record_skip(&mut self, user_id: &RoomUserID) {
+        *self.skips.entry(user_id.clone()).or_default() += 1;
+    }
+
+    /// Up to TOP_ARTISTS_LEN, the most played first then by name
+    pub fn top_artists(&self) -> Vec<(&str, u32)> {
+        let mut artists = self
+            .artist_plays
+            .iter()
+            .map(|(artist_name, plays)| (artist_name.as_str(), *plays))
+            .collect::<Vec<_>>();
+
+        artists.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        artists.truncate(TOP_ARTISTS_LEN);
+
+        artists
+    }
+}
+
+/// Only active rooms accept new users and WS sessions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoomLifecycle {
+    #[default]
+    Active,
+    /// The room sessions are being closed before its deletion
+    Closing,
+}
 
 #[derive(Clone, Debug)]
 pub struct RoomMetadata {
-    pub are_threads_initiated: bool,
+    pub created_at: DateTime<Utc>,
     pub inactive_for: Option<Instant>,
+    /// Since when none of the connected members can manage the room, see OwnerlessRoomPolicy
+    pub ownerless_since: Option<Instant>,
+    /// Streaming service of the room, see Room::music_provider
+    pub provider: ProviderKind,
     pub spotify_handler: Spotify,
+    /// Last time each user got a track from SurpriseMe
+    pub surprise_me_cooldowns: HashMap<RoomUserID, Instant>,
+    /// Last time the queue gap was filled, see RoomManager::fill_queue_gap
+    pub last_gap_fill: Option<Instant>,
+    /// Track IDs removed from the room queue that are still in the Spotify queue (it cannot
+    /// be edited) so they're skipped as soon as they start playing
+    pub tracks_to_skip: Vec<String>,
+    pub parked: Option<ParkedPlayback>,
+    /// Last fetched playback, served to spectators so they don't consume the room rate limit
+    pub now_playing: Option<SpotifyCurrentPlaybackOutput>,
+    /// When now_playing was fetched, see Room::playback_progress_ms
+    pub now_playing_at: Option<Instant>,
+    /// Upcoming and recent tracks of the last fetch, replayed along with now_playing to the
+    /// sessions that lagged behind the room broadcasts
+    pub next_tracks: Option<SpotifyTackArray>,
+    pub previous_tracks: Option<SpotifyTackArray>,
+    pub playback_scheduler: PlaybackScheduler,
+    pub signing_secret: SigningSecret,
+    /// Country of the host's Spotify account, fetched on the first AddToQueue
+    pub market: Option<String>,
+    /// When each member that never opened a WS session joined, see RoomSettings.join_ttl. The
+    /// members of a scheduled room are set at the end of their SCHEDULED_ROOM_CLAIM_TTL
+    pub pending_members: HashMap<RoomUserID, Instant>,
+    /// When each disconnected user lost its WS session, see Config::reconnect_grace_period
+    pub disconnected_at: HashMap<RoomUserID, Instant>,
+    /// Token of the last WS session of each user, it can be resumed with it
+    pub resume_tokens: HashMap<RoomUserID, String>,
+    /// Correlation ID of the CreateRoom/JoinRoom request of each user, carried by its WS logs
+    pub correlation_ids: HashMap<RoomUserID, String>,
+    pub dj_rotation: Option<DjRotation>,
+    /// Last DJ turn sequence of the stopped rotation
+    pub last_dj_turn_seq: u64,
+    /// Last successful refresh of the Spotify tokens
+    pub tokens_refreshed_at: Option<DateTime<Utc>>,
+    /// Consecutive failed refreshes of the Spotify tokens (each one after every retry)
+    pub token_refresh_failures: u32,
+    /// Set while the room data loop cannot reach Spotify
+    pub spotify_outage: Option<SpotifyOutage>,
+    /// Behind its own lock so every command can take a token under the state read lock, it's
+    /// never held across an await
+    pub command_rate_limiter: Arc<std::sync::Mutex<CommandRateLimiter>>,
+    pub command_dedup: CommandDedupCache,
+    /// FIFO queue of the state-changing commands of the room (tokio's Mutex is fair), they're
+    /// applied one at a time so only one of them per room waits for the state and room locks
+    pub mutation_queue: Arc<Mutex<()>>,
+    /// The playback was paused because no user was connected, see RoomSettings.idle_pause_after
+    pub idle_paused: bool,
+    /// Nobody is connected, Spotify isn't polled until the next WS session, see
+    /// RoomManager::hibernate_if_idle
+    pub hibernating: bool,
+    /// Head tracks of the room queue that are in the Spotify queue
+    pub pushed_tracks_len: usize,
+    /// Last position announced to the submitter of each queued track, by (user, track) IDs
+    pub queue_positions: HashMap<(RoomUserID, String), u32>,
+    pub lifecycle: RoomLifecycle,
+    pub stats: RoomStats,
+    pub listening_stats: ListeningStats,
+    /// Local date of the last daily digest sent, see RoomSettings.daily_digest
+    pub last_digest_on: Option<NaiveDate>,
+    /// Of the last room update broadcasted to the members, see Room::take_room_update
+    pub room_seq: u64,
+    /// State of the room the members last got, the next RoomDelta is computed against it
+    pub broadcast_room: Option<proto::room::Room>,
+    /// Logs pushed since broadcast_room, the ones the next RoomDelta appends
+    pub logs_since_broadcast: usize,
+    /// Members streaming the room on their own Spotify account, by user ID
+    pub listeners: HashMap<RoomUserID, Listener>,
+    /// In join order, see RoomSettings.waitlist
+    pub waitlist: VecDeque<WaitlistEntry>,
+    pub seek_vote: Option<SeekVote>,
 
     spotify_data_sleeper: Option<mpsc::Sender<Duration>>,
+    /// Incremented on each playback fetch so a scheduled TrackTransition can tell if it's stale
+    track_transition_seq: u64,
 }
 
 impl RoomMetadata {
-    pub fn new(spotify_tokens: SpotifyTokens) -> Self {
+    pub fn new(spotify_tokens: SpotifyTokens, clock: SharedClock) -> Self {
         Self {
-            are_threads_initiated: false,
-            spotify_handler: Spotify::new(spotify_tokens),
+            created_at: clock.utc_now(),
+            provider: ProviderKind::default(),
+            spotify_handler: Spotify::new(spotify_tokens, clock),
             inactive_for: None,
+            ownerless_since: None,
+            surprise_me_cooldowns: HashMap::new(),
+            last_gap_fill: None,
+            tracks_to_skip: Vec::new(),
+            parked: None,
+            now_playing: None,
+            now_playing_at: None,
+            next_tracks: None,
+            previous_tracks: None,
+            playback_scheduler: PlaybackScheduler::default(),
+            signing_secret: SigningSecret::default(),
+            market: None,
+            pending_members: HashMap::new(),
+            disconnected_at: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            correlation_ids: HashMap::new(),
+            dj_rotation: None,
+            last_dj_turn_seq: 0,
+            tokens_refreshed_at: None,
+            token_refresh_failures: 0,
+            spotify_outage: None,
+            command_rate_limiter: Arc::default(),
+            command_dedup: CommandDedupCache::default(),
+            mutation_queue: Arc::default(),
+            idle_paused: false,
+            hibernating: false,
+            pushed_tracks_len: 0,
+            queue_positions: HashMap::new(),
+            lifecycle: RoomLifecycle::Active,
+            stats: RoomStats::default(),
+            listening_stats: ListeningStats::default(),
+            last_digest_on: None,
+            room_seq: 0,
+            broadcast_room: None,
+            logs_since_broadcast: 0,
+            listeners: HashMap::new(),
+            waitlist: VecDeque::new(),
+            seek_vote: None,
             spotify_data_sleeper: None,
+            track_transition_seq: 0,
         }
     }
 
-    pub fn init_spotify_tick_tx(&mut self, tx: mpsc::Sender<Duration>) {
-        self.spotify_data_sleeper = Some(tx);
+    /// Returns whether the track was flagged to be skipped and unflags it
+    pub fn take_track_to_skip(&mut self, track_id: &str) -> bool {
+        let Some(idx) = self.tracks_to_skip.iter().position(|id| id == track_id) else {
+            return false;
+        };
+
+        self.tracks_to_skip.remove(idx);
+
+        true
     }
 
-    pub async fn set_spotify_tick(&mut self, tick: Duration) {
-        if let Some(sleeper) = self.spotify_data_sleeper.as_ref() {
-            if let Err(err) = sleeper.send(tick).await {
-                error!("An error occured while trying to send the new tick {err}");
-            }
+    /// Invalidates the scheduled TrackTransition (if any) and returns the new sequence
+    pub fn next_track_transition_seq(&mut self) -> u64 {
+        self.track_transition_seq += 1;
+        self.track_transition_seq
+    }
 
-            return;
+    pub fn is_track_transition_current(&self, seq: u64) -> bool {
+        self.track_transition_seq == seq
+    }
+
+    pub fn tokens_status(&self, now: DateTime<Utc>) -> SpotifyTokensStatus {
+        SpotifyTokensStatus {
+            expires_in: self
+                .spotify_handler
+                .tokens
+                .expires_at()
+                .map(|expires_at| expires_at - now),
+            refreshed_at: self.tokens_refreshed_at,
+            refresh_failures: self.token_refresh_failures,
         }
+    }
+
+    /// Drops what the room keeps about a member that left or was removed
+    pub fn forget_user(&mut self, user_id: &RoomUserID) {
+        self.pending_members.remove(user_id);
+        self.disconnected_at.remove(user_id);
+        self.resume_tokens.remove(user_id);
+        self.correlation_ids.remove(user_id);
+        self.surprise_me_cooldowns.remove(user_id);
+        self.command_rate_limiter.lock().unwrap().forget(user_id);
+        self.command_dedup.forget(user_id);
+    }
+
+    /// Starts or extends the Spotify outage of the room
+    pub fn record_spotify_failure(&mut self, now: Instant, err: &ProviderError) -> SpotifyOutage {
+        let outage = self.spotify_outage.get_or_insert(SpotifyOutage {
+            since: now,
+            failures: 0,
+            tokens_rejected: false,
+        });
+
+        outage.failures += 1;
+        outage.tokens_rejected =
+            matches!(err, ProviderError::Unauthorized(_)) && self.token_refresh_failures > 0;
+
+        *outage
+    }
+
+    pub fn init_spotify_tick_tx(&mut self, tx: mpsc::Sender<Duration>) {
+        self.spotify_data_sleeper = Some(tx);
+    }
 
-        error!(
-            "Unreachable error: Trying to set the sleep duration when it has not been (yet) defined"
-        );
+    /// Resets the Spotify data loop sleeper, the sender is cloned so the tick is sent without
+    /// holding the room
+    pub fn spotify_tick_tx(&self) -> Option<mpsc::Sender<Duration>> {
+        self.spotify_data_sleeper.clone()
     }
 }
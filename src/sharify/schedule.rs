@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_rt::time;
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::room::{Room, RoomID, RoomSettings, RoomUserID, UserProfile};
+use super::room_manager::RoomManager;
+use super::spotify::SpotifyTokens;
+use super::tasks::spawn_room_task;
+
+/// Rooms can't be scheduled to open further than this
+pub const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+/// Rooms waiting to open across the server, ScheduleRoom fails with InvalidSchedule beyond
+pub const MAX_SCHEDULED_ROOMS: usize = 500;
+/// Granularity of the rooms opening
+pub const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// Once opened, the host and registered users have this long to poll their ticket, the room
+/// join_ttl only applies to them afterwards
+pub const SCHEDULED_ROOM_CLAIM_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Host or user registered with RegisterScheduledRoom
+#[derive(Clone, Debug)]
+pub struct ScheduledMember {
+    /// Only known by the user, it's polled with GET /scheduled/{id}/{ticket}
+    pub ticket: Uuid,
+    pub user_id: RoomUserID,
+    pub username: String,
+    pub profile: UserProfile,
+}
+
+/// Room set up ahead of time with ScheduleRoom, created by RoomManager::open_due_rooms
+#[derive(Clone, Debug)]
+pub struct ScheduledRoom {
+    pub id: Uuid,
+    pub name: String,
+    pub settings: RoomSettings,
+    pub opens_at: DateTime<Utc>,
+    pub tokens: SpotifyTokens,
+    /// The host first, then in registration order. The ones that polled the opened room are
+    /// removed
+    pub members: Vec<ScheduledMember>,
+    /// Set once opened, with the opening time
+    pub opened: Option<(RoomID, DateTime<Utc>)>,
+}
+
+impl ScheduledRoom {
+    pub fn info(&self, ticket: Uuid) -> ScheduledRoomInfo {
+        ScheduledRoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            opens_at: self.opens_at,
+            ticket,
+            registered_users: self.members.len(),
+        }
+    }
+}
+
+/// What the host and registered users see of the scheduled room
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledRoomInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub opens_at: DateTime<Utc>,
+    pub ticket: Uuid,
+    pub registered_users: usize,
+}
+
+/// Outcome of RoomManager::poll_scheduled_room
+#[derive(Debug)]
+pub enum ScheduledRoomStatus {
+    Pending(ScheduledRoomInfo),
+    Opened {
+        room: Box<Room>,
+        user_id: RoomUserID,
+    },
+}
+
+/// Opens the scheduled rooms once due, their host and registered users get the room when they
+/// poll their ticket (they're notified by GET /scheduled/{id}/{ticket}/events)
+pub fn init_scheduled_rooms_opener(state_mgr: Arc<RwLock<RoomManager>>) {
+    spawn_room_task(async move {
+        loop {
+            time::sleep(SCHEDULE_CHECK_INTERVAL).await;
+
+            let opened = state_mgr.write().await.open_due_rooms();
+
+            if !opened.is_empty() {
+                info!("Opened {} scheduled rooms", opened.len());
+            }
+        }
+    });
+}
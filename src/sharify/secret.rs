@@ -0,0 +1,116 @@
+use std::sync::LazyLock;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+use rand::RngCore as _;
+use serde::{Serialize, Serializer};
+
+use crate::config;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const REDACTED: &str = "[redacted]";
+
+/// AES-256-GCM key of the Spotify tokens, random unless TOKENS_ENCRYPTION_KEY is set
+static TOKENS_KEY: LazyLock<[u8; KEY_LEN]> = LazyLock::new(|| {
+    let mut key = [0; KEY_LEN];
+
+    match config::get()
+        .tokens_encryption_key
+        .as_deref()
+        .map(|key| STANDARD.decode(key))
+    {
+        Some(Ok(decoded)) if decoded.len() == KEY_LEN => key.copy_from_slice(&decoded),
+        configured => {
+            if configured.is_some() {
+                warn!(
+                    "TOKENS_ENCRYPTION_KEY isn't a base64 {KEY_LEN} bytes key, a random one is used"
+                );
+            }
+
+            rand::rng().fill_bytes(&mut key);
+        }
+    }
+
+    key
+});
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SecretError {
+    EncryptionFailed,
+    /// Wrong key or tampered ciphertext
+    DecryptionFailed,
+}
+
+/// Encrypted credential, the plaintext only exists while revealed and Debug/Serialize redact it
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret {
+    /// nonce, ciphertext then tag, empty for an empty secret
+    sealed: Vec<u8>,
+}
+
+impl Secret {
+    pub fn seal(plaintext: &str) -> Result<Self, SecretError> {
+        if plaintext.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+        let mut tag = [0; TAG_LEN];
+
+        rand::rng().fill_bytes(&mut nonce);
+
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &*TOKENS_KEY,
+            Some(&nonce),
+            &[],
+            plaintext.as_bytes(),
+            &mut tag,
+        )
+        .map_err(|_| SecretError::EncryptionFailed)?;
+
+        Ok(Self {
+            sealed: [&nonce[..], &ciphertext, &tag].concat(),
+        })
+    }
+
+    pub fn reveal(&self) -> Result<String, SecretError> {
+        if self.sealed.is_empty() {
+            return Ok(String::new());
+        }
+
+        if self.sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(SecretError::DecryptionFailed);
+        }
+
+        let (nonce, rest) = self.sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &*TOKENS_KEY,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| SecretError::DecryptionFailed)?;
+
+        String::from_utf8(plaintext).map_err(|_| SecretError::DecryptionFailed)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::RngCore as _;
+
+use super::room::RoomID;
+use crate::api::ApiVersion;
+
+pub const SIGNING_SECRET_LEN: usize = 32;
+pub const DEFAULT_SIGNED_URL_TTL: Duration = Duration::from_secs(60 * 60);
+pub const MAX_SIGNED_URL_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignedUrlError {
+    Expired,
+    InvalidSignature,
+    SigningFailed,
+}
+
+/// Per-room HMAC key of the spectator URLs, rotating it revokes every URL signed with it
+#[derive(Clone)]
+pub struct SigningSecret([u8; SIGNING_SECRET_LEN]);
+
+impl std::fmt::Debug for SigningSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningSecret(..)")
+    }
+}
+
+impl Default for SigningSecret {
+    fn default() -> Self {
+        let mut secret = [0; SIGNING_SECRET_LEN];
+
+        rand::rng().fill_bytes(&mut secret);
+
+        Self(secret)
+    }
+}
+
+impl SigningSecret {
+    /// Base64 URL-safe HMAC-SHA256 of "{room_id}:{expires}"
+    pub fn sign(&self, room_id: RoomID, expires: i64) -> Result<String, SignedUrlError> {
+        let key = PKey::hmac(&self.0).map_err(|_| SignedUrlError::SigningFailed)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)
+            .map_err(|_| SignedUrlError::SigningFailed)?;
+
+        signer
+            .update(format!("{room_id}:{expires}").as_bytes())
+            .map_err(|_| SignedUrlError::SigningFailed)?;
+
+        let signature = signer
+            .sign_to_vec()
+            .map_err(|_| SignedUrlError::SigningFailed)?;
+
+        Ok(URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    pub fn verify(
+        &self,
+        room_id: RoomID,
+        expires: i64,
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), SignedUrlError> {
+        let expected = self.sign(room_id, expires)?;
+
+        // Constant time comparison so the signature cannot be guessed byte per byte
+        if expected.len() != signature.len()
+            || !memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        {
+            return Err(SignedUrlError::InvalidSignature);
+        }
+
+        if now.timestamp() >= expires {
+            return Err(SignedUrlError::Expired);
+        }
+
+        Ok(())
+    }
+}
+
+/// Path (with query) of the public now-playing endpoint, the client prepends the host
+pub fn now_playing_path(room_id: RoomID, expires: i64, signature: &str) -> String {
+    format!("/spectate/{room_id}/now-playing?expires={expires}&sig={signature}")
+}
+
+/// Path (with query) of the Display projection of the room events, see EventProjection
+pub fn display_events_path(room_id: RoomID, expires: i64, signature: &str) -> String {
+    format!("/spectate/{room_id}/events?expires={expires}&sig={signature}")
+}
+
+/// Path (with query) of the read-only SSE stream of the room, signed with the same secret
+pub fn events_path(room_id: RoomID, expires: i64, signature: &str) -> String {
+    format!(
+        "{}/{room_id}/events?expires={expires}&sig={signature}",
+        ApiVersion::LATEST.prefix()
+    )
+}
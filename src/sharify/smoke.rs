@@ -0,0 +1,234 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::proto::cmd::command;
+
+use super::clock::system_clock;
+use super::music_provider::ProviderKind;
+use super::random::system_random;
+use super::room::{CredentialsInput, RoomID};
+use super::room_manager::RoomManager;
+use super::spotify::Timestamp;
+use super::websocket::commands::Command as WSCmd;
+
+const OWNER_ID: &str = "smoke-test-owner";
+const MEMBER_ID: &str = "smoke-test-member";
+const TRACK_ID: &str = "smoke-test-track";
+
+#[derive(Debug, Serialize)]
+pub struct SmokeStep {
+    pub name: &'static str,
+    pub passed: bool,
+    pub duration_us: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SmokeReport {
+    pub passed: bool,
+    pub duration_us: u128,
+    pub steps: Vec<SmokeStep>,
+}
+
+impl SmokeReport {
+    async fn run_step(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = Result<(), String>>,
+    ) -> Result<(), ()> {
+        let started_at = Instant::now();
+        let result = step.await;
+        let passed = result.is_ok();
+
+        self.steps.push(SmokeStep {
+            name,
+            passed,
+            duration_us: started_at.elapsed().as_micros(),
+            error: result.err(),
+        });
+
+        if passed { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Deep health check: measures the live state lock then runs a scripted room lifecycle (create,
+/// join, queue, skip, leave, close) on a throwaway RoomManager so no user room is touched. The
+/// member actions go through the websocket commands against an OfflineProvider room so Spotify
+/// is never called. It stops at the first failing step
+pub async fn run(sharify_state: Option<Arc<RwLock<RoomManager>>>) -> SmokeReport {
+    let started_at = Instant::now();
+    let mut report = SmokeReport {
+        passed: false,
+        duration_us: 0,
+        steps: Vec::new(),
+    };
+
+    if let Some(sharify_state) = sharify_state {
+        let lock_started_at = Instant::now();
+        let active_rooms = sharify_state.read().await.rooms_count();
+
+        report.steps.push(SmokeStep {
+            name: "state_lock",
+            passed: true,
+            duration_us: lock_started_at.elapsed().as_micros(),
+            error: None,
+        });
+
+        trace!("Smoke test: state lock acquired, {active_rooms} active room(s)");
+    }
+
+    report.passed = run_lifecycle(&mut report).await.is_ok();
+    report.duration_us = started_at.elapsed().as_micros();
+
+    if report.passed {
+        debug!("Smoke test passed in {}us", report.duration_us);
+    } else {
+        warn!("Smoke test failed: {:?}", report.steps.last());
+    }
+
+    report
+}
+
+/// Runs the command as the websocket instance does for a member message
+async fn run_command(
+    state: &Arc<RwLock<RoomManager>>,
+    room_id: RoomID,
+    user_id: &str,
+    cmd_type: command::Type,
+) -> Result<(), String> {
+    let (result, _) = WSCmd::new(Arc::clone(state), user_id.into(), room_id, cmd_type)
+        .process()
+        .await;
+
+    result.map(|_| ()).map_err(|err| format!("{err:?}"))
+}
+
+async fn run_lifecycle(report: &mut SmokeReport) -> Result<(), ()> {
+    let state = Arc::new(RwLock::new(RoomManager::new(
+        system_clock(),
+        system_random(),
+    )));
+    let mut room_id = RoomID::nil();
+
+    report
+        .run_step("create_room", async {
+            let tokens = CredentialsInput {
+                access_token: String::new(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                created_at: Timestamp::from(0),
+            }
+            .try_into()
+            .map_err(|err| format!("{err:?}"))?;
+
+            let mut guard = state.write().await;
+
+            room_id = guard
+                .create_room(
+                    OWNER_ID.into(),
+                    "Smoke owner".into(),
+                    "Smoke test".into(),
+                    tokens,
+                    Default::default(),
+                )
+                .map_err(|err| format!("{err:?}"))?
+                .id;
+
+            let room = guard.get_room_mut(&room_id).ok_or("Room not found")?;
+            room.provider = ProviderKind::Offline;
+
+            Ok(())
+        })
+        .await?;
+
+    report
+        .run_step("join_room", async {
+            state
+                .write()
+                .await
+                .join_room(
+                    room_id,
+                    "Smoke member".into(),
+                    MEMBER_ID.into(),
+                    Default::default(),
+                )
+                .map(|_| ())
+                .map_err(|err| format!("{err:?}"))
+        })
+        .await?;
+
+    report
+        .run_step("add_track_to_queue", async {
+            run_command(
+                &state,
+                room_id,
+                // The members join as guests, who can't queue
+                OWNER_ID,
+                command::Type::AddToQueue(command::AddTrackToQueue {
+                    track_id: TRACK_ID.into(),
+                    track_name: "Smoke track".into(),
+                    track_duration: 1000,
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+            match state.read().await.get_room(&room_id) {
+                Some(room) if room.tracks_queue.len() == 1 => Ok(()),
+                Some(room) => Err(format!("Queue has {} track(s)", room.tracks_queue.len())),
+                None => Err("Room not found".into()),
+            }
+        })
+        .await?;
+
+    report
+        .run_step("skip_track", async {
+            run_command(&state, room_id, OWNER_ID, command::Type::SkipNext(true)).await?;
+
+            // Same bookkeeping as when the fetched playback moved past the queue head
+            let guard = state.read().await;
+
+            guard
+                .remove_track_from_queue(room_id, TRACK_ID.into())
+                .map_err(|err| format!("{err:?}"))?;
+
+            match guard.get_room(&room_id) {
+                Some(room) if room.tracks_queue.is_empty() => Ok(()),
+                Some(_) => Err("Track still queued after the skip".into()),
+                None => Err("Room not found".into()),
+            }
+        })
+        .await?;
+
+    report
+        .run_step("leave_room", async {
+            run_command(&state, room_id, MEMBER_ID, command::Type::LeaveRoom(true)).await?;
+
+            if state.read().await.user_id_exists(&MEMBER_ID.into()) {
+                return Err("Member still in the room after leaving".into());
+            }
+
+            Ok(())
+        })
+        .await?;
+
+    report
+        .run_step("close_room", async {
+            let mut guard = state.write().await;
+
+            guard
+                .delete_room(room_id, Some(OWNER_ID.into()))
+                .map_err(|err| format!("{err:?}"))?;
+
+            if guard.get_room(&room_id).is_some() || guard.user_id_exists(&OWNER_ID.into()) {
+                return Err("Room leftovers after the close".into());
+            }
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::SpotifyError;
+use super::web_utils::{SpotifyCurrentPlaybackOutput, SpotifyTackArray, SpotifyTrack};
+use crate::sharify::clock::Clock;
+
+/// SHA-256 of the access token (or item URI) so the plaintext isn't kept as a map key
+pub type CacheKey = [u8; 32];
+
+type Slot<T> = Arc<Mutex<Option<(Instant, T)>>>;
+
+pub static PLAYBACK_CACHE: LazyLock<FetchCache<Option<SpotifyCurrentPlaybackOutput>>> =
+    LazyLock::new(FetchCache::default);
+pub static QUEUE_CACHE: LazyLock<FetchCache<SpotifyTackArray>> = LazyLock::new(FetchCache::default);
+/// Keyed by item URI rather than by token: the tracks and episodes are the same for every account
+pub static ITEM_CACHE: LazyLock<FetchCache<SpotifyTrack>> = LazyLock::new(FetchCache::default);
+
+pub fn cache_key(access_token: &str) -> CacheKey {
+    Sha256::digest(access_token).into()
+}
+
+/// Responses of the Spotify API shared by the rooms of the same host account (same access
+/// token) for a short TTL, see SPOTIFY_CACHE_TTL_MS
+///
+/// Concurrent fetches of the same key are coalesced: the first one fetches while the others
+/// wait for its response instead of sending theirs
+pub struct FetchCache<T> {
+    slots: Mutex<HashMap<CacheKey, Slot<T>>>,
+}
+
+impl<T> Default for FetchCache<T> {
+    fn default() -> Self {
+        Self {
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> FetchCache<T> {
+    /// Failed fetches aren't cached, a zero TTL disables the cache
+    pub async fn get_or_fetch(
+        &self,
+        key: CacheKey,
+        ttl: Duration,
+        clock: &dyn Clock,
+        fetch: impl Future<Output = Result<T, SpotifyError>>,
+    ) -> Result<T, SpotifyError> {
+        if ttl.is_zero() {
+            return fetch.await;
+        }
+
+        let slot = self.slot(key, ttl, clock).await;
+        let mut slot_guard = slot.lock().await;
+
+        if let Some((fetched_at, ref value)) = *slot_guard
+            && clock.elapsed_since(fetched_at) < ttl
+        {
+            return Ok(value.clone());
+        }
+
+        let value = fetch.await?;
+
+        *slot_guard = Some((clock.now(), value.clone()));
+
+        Ok(value)
+    }
+
+    /// Drops the response of the key, once the playback has been changed through its token
+    pub async fn invalidate(&self, key: &CacheKey) {
+        self.slots.lock().await.remove(key);
+    }
+
+    /// Creates the slot of the key and prunes the expired ones that aren't being fetched
+    async fn slot(&self, key: CacheKey, ttl: Duration, clock: &dyn Clock) -> Slot<T> {
+        let mut slots = self.slots.lock().await;
+
+        if let Some(slot) = slots.get(&key) {
+            return Arc::clone(slot);
+        }
+
+        slots.retain(|_, slot| match slot.try_lock() {
+            Ok(slot) => slot
+                .as_ref()
+                .is_some_and(|(fetched_at, _)| clock.elapsed_since(*fetched_at) < ttl),
+            // Being fetched
+            Err(_) => true,
+        });
+
+        Arc::clone(slots.entry(key).or_default())
+    }
+}
@@ -1,24 +1,91 @@
+pub mod cache;
 pub mod web_utils;
 
 use std::num::ParseIntError;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock};
 use std::time::{Duration, Instant};
 
 use chrono::{DateTime, TimeZone as _, Utc};
+use reqwest::StatusCode;
+use reqwest::header::RETRY_AFTER;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use urlencoding::encode as encode_url;
 
+use super::clock::{SharedClock, system_clock};
+use super::secret::{Secret, SecretError};
 use web_utils::endpoints::*;
-use web_utils::{RefreshTokenOutput, SpotifyCurrentPlaybackOutput, SpotifyTackArray, SpotifyTrack};
+use web_utils::{
+    PlaybackItemType, RefreshTokenOutput, RepeatMode, SearchType, SpotifyCurrentPlaybackOutput,
+    SpotifyDeviceArray, SpotifyPlayedTrack, SpotifyPlaylist, SpotifyPlaylistArray,
+    SpotifySearchResults, SpotifyTackArray, SpotifyTrack, payloads,
+};
 
 /// This is a safe offset to fetch next playback after the song ends. This is due to the fact that
 /// the playback API from Spotify is ~900ms late
 pub const FETCH_OFFSET_MS: u64 = 2000;
-pub const DEFAULT_DATA_INTERVAL: Duration = Duration::from_millis(1000 * 60 * 2);
+/// Offset of the fetch scheduled after the end of the track, shorter than FETCH_OFFSET_MS since
+/// it's verified: with a crossfade the next track is already playing by then, otherwise it's
+/// re-polled every TRACK_END_VERIFY_MS until it is
+pub const TRACK_END_FETCH_OFFSET_MS: u64 = 500;
+pub const TRACK_END_VERIFY_MS: u64 = 1000;
+/// Re-polls of a track that should have ended before its remaining time is trusted again
+pub const MAX_TRACK_END_VERIFICATIONS: u32 = 3;
+/// The queue head is prefetched when the current track ends within this window
+pub const TRACK_TRANSITION_PREFETCH_MS: u64 = 1000 * 60 * 2;
+/// When more than this is left on the track, an extra fetch is done in the middle to keep sync
+/// with an external Spotify client/player
+pub const MID_TRACK_FETCH_THRESHOLD_MS: u64 = 1000 * 60 * 2;
+/// Spotify's max page size of the search endpoint
+pub const MAX_SEARCH_LIMIT: u32 = 50;
+/// Spotify rejects offsets above this one
+pub const MAX_SEARCH_OFFSET: u32 = 1000;
+pub const DEFAULT_SEARCH_LIMIT: u32 = 20;
 pub const RATE_LIMIT_REQUEST_WINDOW: Duration = Duration::from_secs(30);
-pub const REQUEST_COUNT_PER_WINDOW: u8 = 20;
+/// Attempts made after the first one for transient failures (network, 429, 5xx)
+pub const MAX_REQUEST_RETRIES: u32 = 3;
+/// Doubled on each retry, unless Spotify sent a Retry-After header
+pub const REQUEST_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+/// Scopes asked to the room host on the Spotify login
+pub const SPOTIFY_SCOPES: &str = "user-read-private user-read-playback-state user-modify-playback-state user-read-currently-playing user-read-recently-played playlist-read-private playlist-read-collaborative playlist-modify-private";
+/// Scopes asked to the listeners on the Spotify login, the Web Playback SDK requires streaming,
+/// user-read-email and user-read-private
+pub const LISTENER_SPOTIFY_SCOPES: &str = "streaming user-read-email user-read-private user-read-playback-state user-modify-playback-state";
+/// A Retry-After longer than this is returned as RateLimited instead of being awaited
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+/// Tokens are refreshed this long before they expire
+pub const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60 * 5);
+pub const TOKEN_REFRESH_MAX_RETRIES: u32 = 5;
+/// Doubled on each failed refresh attempt
+pub const TOKEN_REFRESH_BACKOFF: Duration = Duration::from_secs(2);
+/// Delay before trying again once every retry failed
+pub const TOKEN_REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Default of SPOTIFY_OUTAGE_RETRY_BACKOFF_MS: delay before fetching the room data again after
+/// a failed fetch, doubled on each failure
+pub const DEFAULT_OUTAGE_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+/// Default of SPOTIFY_OUTAGE_MAX_RETRY_INTERVAL_MS
+pub const DEFAULT_MAX_OUTAGE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Default of SPOTIFY_OUTAGE_MAX_DURATION_MS: the room is closed once Spotify has been
+/// unreachable for this long
+pub const DEFAULT_MAX_OUTAGE_DURATION: Duration = Duration::from_secs(60 * 10);
+/// How long a reachability probe result is reused so health checks don't hammer Spotify
+pub const REACHABILITY_CACHE_TTL: Duration = Duration::from_secs(30);
+/// How long a track or episode looked up with Spotify::get_item is reused, their duration and
+/// explicit flag don't change
+pub const ITEM_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+/// Spotify limit of the items added to a playlist at once
+pub const MAX_PLAYLIST_ITEMS_PER_REQUEST: usize = 100;
+
+static PROBE_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .expect("Failed to build HTTP Client")
+});
+static LAST_REACHABILITY: LazyLock<Mutex<Option<(Instant, bool)>>> =
+    LazyLock::new(|| Mutex::new(None));
 
 // pub static CODE: OnceLock<Arc<RwLock<String>>> = OnceLock::new();
 
@@ -53,49 +120,135 @@ impl From<i64> for Timestamp {
     }
 }
 
+/// Delay before the next playback fetch when `rest_ms` are left on the playing track
+pub fn next_fetch_delay(mut rest_ms: u64) -> Duration {
+    if rest_ms > MID_TRACK_FETCH_THRESHOLD_MS {
+        rest_ms /= 2;
+    }
+
+    Duration::from_millis(rest_ms.saturating_add(FETCH_OFFSET_MS))
+}
+
+/// Schedules the playback fetches of a room, the one following the end of a track must see
+/// another track before the remaining time of the new playback is trusted since seeks, skips from
+/// other clients and crossfades move the actual track end
+#[derive(Clone, Debug, Default)]
+pub struct PlaybackScheduler {
+    /// Track that should have changed by the next fetch
+    awaited_change: Option<String>,
+    verifications: u32,
+}
+
+impl PlaybackScheduler {
+    /// Delay before the next playback fetch when `rest_ms` are left on the playing track
+    pub fn next_delay(&mut self, track_id: &str, rest_ms: u64) -> Duration {
+        let is_change_awaited = self.awaited_change.as_deref() == Some(track_id);
+
+        // Still at the end of the track that should have changed (late playback API or no
+        // crossfade), a long rest means it was seeked back or replayed
+        if is_change_awaited && rest_ms <= FETCH_OFFSET_MS {
+            if self.verifications < MAX_TRACK_END_VERIFICATIONS {
+                self.verifications += 1;
+
+                return Duration::from_millis(TRACK_END_VERIFY_MS);
+            }
+
+            // Spotify keeps reporting the end of the track, back to the regular delay
+            return next_fetch_delay(rest_ms);
+        }
+
+        if rest_ms > MID_TRACK_FETCH_THRESHOLD_MS {
+            self.reset();
+
+            return next_fetch_delay(rest_ms);
+        }
+
+        self.expect_track_change(track_id);
+
+        Duration::from_millis(rest_ms.saturating_add(TRACK_END_FETCH_OFFSET_MS))
+    }
+
+    /// The next fetch must see another track, e.g. once the current one has been skipped
+    pub fn expect_track_change(&mut self, track_id: &str) {
+        self.awaited_change = Some(track_id.to_owned());
+        self.verifications = 0;
+    }
+
+    /// Nothing is playing, the next fetch starts over
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SpotifyError {
     Generic(String),
     RateLimited(u64),
+    /// 401, the access token is invalid or expired
+    Unauthorized(String),
+    /// 403, the request is valid but not allowed (e.g. non-premium account)
+    Forbidden(String),
 }
 
 impl From<SpotifyError> for String {
     fn from(err: SpotifyError) -> Self {
         match err {
-            SpotifyError::Generic(string) => string,
+            SpotifyError::Generic(string)
+            | SpotifyError::Unauthorized(string)
+            | SpotifyError::Forbidden(string) => string,
             SpotifyError::RateLimited(time) => format!("Spotify API rate limited for {time}s"),
         }
     }
 }
 
+impl From<SecretError> for SpotifyError {
+    fn from(err: SecretError) -> Self {
+        match err {
+            SecretError::EncryptionFailed => {
+                Self::Generic("Failed to encrypt the Spotify tokens".into())
+            }
+            SecretError::DecryptionFailed => {
+                Self::Generic("Failed to decrypt the Spotify tokens".into())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RateLimiter {
     pub current_window: Instant,
     pub request_count_on_window: AtomicU8,
+
+    clock: SharedClock,
 }
 
 impl Default for RateLimiter {
     fn default() -> Self {
+        Self::new(system_clock())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(clock: SharedClock) -> Self {
         Self {
-            current_window: Instant::now(),
+            current_window: clock.now(),
             request_count_on_window: AtomicU8::new(1),
+            clock,
         }
     }
-}
 
-impl RateLimiter {
     pub fn increment(&mut self) -> Result<(), SpotifyError> {
-        let elapsed_since_window = self.current_window.elapsed();
+        let elapsed_since_window = self.clock.elapsed_since(self.current_window);
 
         if elapsed_since_window > RATE_LIMIT_REQUEST_WINDOW {
-            self.current_window = Instant::now();
+            self.current_window = self.clock.now();
             self.request_count_on_window.store(1, Ordering::SeqCst);
 
             return Ok(());
         }
 
         if self.request_count_on_window.fetch_add(1, Ordering::Acquire) + 1
-            >= REQUEST_COUNT_PER_WINDOW
+            >= crate::config::get().spotify_requests_per_window
         {
             return Err(SpotifyError::RateLimited(
                 RATE_LIMIT_REQUEST_WINDOW.as_secs() - elapsed_since_window.as_secs(),
@@ -104,80 +257,446 @@ impl RateLimiter {
 
         Ok(())
     }
+
+    /// Read-only check used for status reporting, doesn't count as a request
+    pub fn is_rate_limited(&self) -> bool {
+        self.clock.elapsed_since(self.current_window) <= RATE_LIMIT_REQUEST_WINDOW
+            && self.request_count_on_window.load(Ordering::Relaxed)
+                >= crate::config::get().spotify_requests_per_window
+    }
+
+    /// Read-only snapshot for the operators, doesn't count as a request either
+    pub fn state(&self) -> RateLimiterState {
+        let elapsed_since_window = self.clock.elapsed_since(self.current_window);
+
+        if elapsed_since_window > RATE_LIMIT_REQUEST_WINDOW {
+            return RateLimiterState::default();
+        }
+
+        RateLimiterState {
+            requests_on_window: self.request_count_on_window.load(Ordering::Relaxed),
+            window_remaining_secs: (RATE_LIMIT_REQUEST_WINDOW - elapsed_since_window).as_secs(),
+            is_rate_limited: self.is_rate_limited(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RateLimiterState {
+    pub requests_on_window: u8,
+    /// 0 when the window elapsed, the next request starts a new one
+    pub window_remaining_secs: u64,
+    pub is_rate_limited: bool,
+}
+
+/// SPOTIFY_CLIENT_ID of the config
+fn client_id() -> Result<String, SpotifyError> {
+    crate::config::get()
+        .spotify_client_id
+        .clone()
+        .ok_or(SpotifyError::Generic(
+            "SPOTIFY_CLIENT_ID env var not found".into(),
+        ))
+}
+
+/// Item of a `spotify:local:{artist}:{album}:{title}:{duration_secs}` URI, local files aren't
+/// known by the Web API and are never flagged explicit
+fn local_file_item(uri: String) -> Result<SpotifyTrack, SpotifyError> {
+    let invalid = || SpotifyError::Generic(format!("Invalid local file URI {uri}"));
+
+    let mut parts = uri.trim_start_matches("spotify:local:").rsplitn(2, ':');
+    let duration_secs: i64 = parts
+        .next()
+        .and_then(|secs| secs.parse().ok())
+        .ok_or_else(invalid)?;
+    let decode = |part: &str| {
+        urlencoding::decode(&part.replace('+', " "))
+            .map(Into::into)
+            .unwrap_or_else(|_| part.to_owned())
+    };
+    let (artist_name, album_name, track_name) = match parts.next().map(|rest| {
+        let mut fields = rest.splitn(3, ':').map(decode);
+        (fields.next(), fields.next(), fields.next())
+    }) {
+        Some((Some(artist), Some(album), Some(title))) => (artist, album, title),
+        _ => return Err(invalid()),
+    };
+
+    Ok(SpotifyTrack {
+        track_id: uri,
+        track_name,
+        artist_name,
+        track_duration: duration_secs.saturating_mul(1000),
+        album_name,
+        album_image_src: String::new(),
+        explicit: false,
+        popularity: None,
+        item_type: PlaybackItemType::Track,
+    })
+}
+
+/// Whether the Spotify Web API answers at all (any HTTP status counts, an unauthenticated
+/// request is expected to get a 401). The result is cached for REACHABILITY_CACHE_TTL
+pub async fn is_api_reachable() -> bool {
+    let mut guard = LAST_REACHABILITY.lock().await;
+
+    if let Some((checked_at, reachable)) = *guard
+        && checked_at.elapsed() < REACHABILITY_CACHE_TTL
+    {
+        return reachable;
+    }
+
+    let reachable = PROBE_CLIENT
+        .get(&crate::config::get().spotify_base_urls.api)
+        .send()
+        .await
+        .is_ok();
+
+    if !reachable {
+        warn!("Spotify API is unreachable");
+    }
+
+    *guard = Some((Instant::now(), reachable));
+
+    reachable
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct SpotifyTokens {
-    pub access_token: String,
-    pub refresh_token: String,
+    pub access_token: Secret,
+    pub refresh_token: Secret,
     /// In seconds - offset from created_at
     pub expires_in: u32,
     pub created_at: Timestamp,
 }
 
-#[derive(Clone, Debug, Default)]
+impl SpotifyTokens {
+    /// Encrypts the tokens, they're only revealed to call Spotify
+    pub fn new(
+        access_token: &str,
+        refresh_token: &str,
+        expires_in: u32,
+        created_at: Timestamp,
+    ) -> Result<Self, SpotifyError> {
+        Ok(Self {
+            access_token: Secret::seal(access_token)?,
+            refresh_token: Secret::seal(refresh_token)?,
+            expires_in,
+            created_at,
+        })
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+            .to_datetime()
+            .ok()?
+            .checked_add_signed(chrono::TimeDelta::seconds(self.expires_in as _))
+    }
+}
+
+/// Roots of the Spotify endpoints, see SPOTIFY_API_URL and SPOTIFY_ACCOUNTS_URL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpotifyBaseUrls {
+    /// Web API
+    pub api: String,
+    /// Authorization and tokens
+    pub accounts: String,
+}
+
+impl Default for SpotifyBaseUrls {
+    fn default() -> Self {
+        Self {
+            api: DEFAULT_API_BASE_URL.into(),
+            accounts: DEFAULT_ACCOUNTS_BASE_URL.into(),
+        }
+    }
+}
+
+impl SpotifyBaseUrls {
+    /// The unset or empty ones default to Spotify's
+    pub fn new(api: Option<String>, accounts: Option<String>) -> Self {
+        let base_url = |url: Option<String>, default: &str| {
+            url.map(|url| url.trim().trim_end_matches('/').to_owned())
+                .filter(|url| !url.is_empty())
+                .unwrap_or(default.into())
+        };
+
+        Self {
+            api: base_url(api, DEFAULT_API_BASE_URL),
+            accounts: base_url(accounts, DEFAULT_ACCOUNTS_BASE_URL),
+        }
+    }
+
+    pub fn api_url(&self, path: &str) -> String {
+        format!("{}{path}", self.api)
+    }
+
+    pub fn accounts_url(&self, path: &str) -> String {
+        format!("{}{path}", self.accounts)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Spotify {
     client: reqwest::Client, // cannot use the blocking client because it's used in async threads and blocks them with trying to lock
     pub tokens: SpotifyTokens,
     pub rate_limiter: Arc<RwLock<RateLimiter>>,
+    pub base_urls: Arc<SpotifyBaseUrls>,
+    clock: SharedClock,
+}
+
+impl Default for Spotify {
+    fn default() -> Self {
+        Self::new(SpotifyTokens::default(), system_clock())
+    }
 }
 
 impl Spotify {
-    pub fn new(tokens: SpotifyTokens) -> Self {
+    pub fn new(tokens: SpotifyTokens, clock: SharedClock) -> Self {
         Spotify {
+            client: reqwest::Client::default(),
             tokens,
-            ..Default::default()
+            rate_limiter: Arc::new(RwLock::new(RateLimiter::new(Arc::clone(&clock)))),
+            base_urls: Arc::new(crate::config::get().spotify_base_urls.clone()),
+            clock,
+        }
+    }
+
+    fn api_url(&self, path: &str) -> String {
+        self.base_urls.api_url(path)
+    }
+
+    /// Authorization header value of the Spotify API requests
+    fn bearer(&self) -> Result<String, SpotifyError> {
+        Ok(format!("Bearer {}", self.tokens.access_token.reveal()?))
+    }
+
+    /// Rooms of the same host account share their cached responses, see cache::FetchCache
+    fn cache_key(&self) -> Result<cache::CacheKey, SpotifyError> {
+        Ok(cache::cache_key(&self.tokens.access_token.reveal()?))
+    }
+
+    async fn invalidate_cache(&self) {
+        if let Ok(key) = self.cache_key() {
+            cache::PLAYBACK_CACHE.invalidate(&key).await;
+            cache::QUEUE_CACHE.invalidate(&key).await;
+        }
+    }
+
+    /// Sends the request and retries transient failures with an exponential backoff
+    ///
+    /// action is used for the error messages: "Failed to fetch {action}"
+    #[tracing::instrument(name = "spotify_request", skip_all, fields(action = %action))]
+    async fn send(
+        &self,
+        req: reqwest::RequestBuilder,
+        action: &str,
+    ) -> Result<reqwest::Response, SpotifyError> {
+        let mut backoff = REQUEST_RETRY_BACKOFF;
+        let mut attempt = 0;
+        let method = req
+            .try_clone()
+            .and_then(|req| req.build().ok())
+            .map(|req| req.method().clone());
+        // The cached playback/queue of the account are outdated once it succeeds
+        let is_mutation = method.as_ref().is_some_and(|m| m != reqwest::Method::GET);
+        // A timed out or failed POST (add to queue, skip) may have been applied already,
+        // only the requests that can be replayed safely are retried on those errors
+        let is_idempotent = !is_mutation || method == Some(reqwest::Method::PUT);
+
+        loop {
+            let Some(attempt_req) = req.try_clone() else {
+                return Err(SpotifyError::Generic(format!(
+                    "Failed to build {action} request"
+                )));
+            };
+
+            let is_last_attempt = attempt >= MAX_REQUEST_RETRIES;
+            attempt += 1;
+
+            let res = match attempt_req.send().await {
+                Ok(res) => res,
+                Err(err)
+                    if !is_last_attempt
+                        && (err.is_connect() || (is_idempotent && err.is_timeout())) =>
+                {
+                    debug!("Retrying {action} request in {backoff:?} after error: {err}");
+
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+
+                    continue;
+                }
+                Err(err) => {
+                    return Err(SpotifyError::Generic(format!(
+                        "Failed to send {action} request: {err}"
+                    )));
+                }
+            };
+
+            let status = res.status();
+
+            if status.is_success() {
+                if is_mutation {
+                    self.invalidate_cache().await;
+                }
+
+                return Ok(res);
+            }
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = res
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or(backoff);
+
+                if is_last_attempt || retry_after > MAX_RETRY_AFTER {
+                    return Err(SpotifyError::RateLimited(retry_after.as_secs().max(1)));
+                }
+
+                debug!("Spotify rate limited the {action} request, retrying in {retry_after:?}");
+
+                tokio::time::sleep(retry_after).await;
+                backoff *= 2;
+
+                continue;
+            }
+
+            if status.is_server_error() && is_idempotent && !is_last_attempt {
+                debug!("Retrying {action} request in {backoff:?} after status {status}");
+
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+
+                continue;
+            }
+
+            let message = format!(
+                "Failed to fetch {action}: ({status}) {:?}",
+                res.text().await.unwrap_or_default()
+            );
+
+            return Err(match status {
+                StatusCode::UNAUTHORIZED => SpotifyError::Unauthorized(message),
+                StatusCode::FORBIDDEN => SpotifyError::Forbidden(message),
+                _ => SpotifyError::Generic(message),
+            });
         }
     }
 
     pub async fn fetch_refresh_token(&mut self) -> Result<SpotifyTokens, SpotifyError> {
-        let id = dotenvy::var("SPOTIFY_CLIENT_ID").map_err(|err| {
-            SpotifyError::Generic(format!("Failed to get Spotify client ID from env: {err}"))
-        })?;
+        let id = client_id()?;
 
         let res = self
-            .client
-            .post(format!(
-                "{}?grant_type=refresh_token&client_id={}&refresh_token={}",
-                TOKEN_URL, id, self.tokens.refresh_token,
-            ))
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .header("Content-Length", "0")
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!(
-                    "Failed to send Spotify refresh token request: {err}"
-                ))
-            })?;
-
-        if !res.status().is_success() || !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch Spotify token: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+            .send(
+                self.client
+                    .post(format!(
+                        "{}?grant_type=refresh_token&client_id={}&refresh_token={}",
+                        self.base_urls.accounts_url(TOKEN),
+                        id,
+                        self.tokens.refresh_token.reveal()?,
+                    ))
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .header("Content-Length", "0"),
+                "Spotify token",
+            )
+            .await?;
 
         let body: RefreshTokenOutput = res.json().await.map_err(|err| {
             SpotifyError::Generic(format!("Failed to get Spotify token json result: {err}"))
         })?;
 
-        self.tokens = SpotifyTokens {
-            access_token: body.access_token,
-            refresh_token: body.refresh_token,
-            expires_in: body.expires_in as _,
-            created_at: Timestamp::from(chrono::Local::now().timestamp()),
-        };
+        self.tokens = SpotifyTokens::new(
+            &body.access_token,
+            &body.refresh_token,
+            body.expires_in as _,
+            Timestamp::from(Utc::now().timestamp_millis()),
+        )?;
 
         Ok(self.tokens.clone())
     }
 
-    // https://developer.spotify.com/documentation/web-api/reference/get-recently-played
+    /// Spotify login page the room host (or a listener) is redirected to, with the PKCE code
+    /// challenge
+    pub fn authorize_url(
+        state: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+        scopes: &str,
+    ) -> Result<String, SpotifyError> {
+        let id = client_id()?;
+
+        Ok(format!(
+            "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&code_challenge_method=S256&code_challenge={}",
+            crate::config::get()
+                .spotify_base_urls
+                .accounts_url(AUTHORIZE),
+            encode_url(&id),
+            encode_url(redirect_uri),
+            encode_url(scopes),
+            encode_url(state),
+            super::utils::generate_code_challenge(code_verifier.to_owned()),
+        ))
+    }
+
+    /// Completes the PKCE authorization code flow, the tokens never go through the client
+    pub async fn exchange_code(
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+        base_urls: Arc<SpotifyBaseUrls>,
+    ) -> Result<SpotifyTokens, SpotifyError> {
+        let id = client_id()?;
+        let spotify = Self {
+            base_urls,
+            ..Self::new(SpotifyTokens::default(), system_clock())
+        };
+
+        let res = spotify
+            .send(
+                spotify
+                    .client
+                    .post(spotify.base_urls.accounts_url(TOKEN))
+                    .form(&[
+                        ("grant_type", "authorization_code"),
+                        ("code", code),
+                        ("redirect_uri", redirect_uri),
+                        ("client_id", &id),
+                        ("code_verifier", code_verifier),
+                    ]),
+                "Spotify token",
+            )
+            .await?;
+
+        let body: RefreshTokenOutput = res.json().await.map_err(|err| {
+            SpotifyError::Generic(format!("Failed to get Spotify token json result: {err}"))
+        })?;
+
+        SpotifyTokens::new(
+            &body.access_token,
+            &body.refresh_token,
+            body.expires_in as _,
+            Timestamp::from(Utc::now().timestamp_millis()),
+        )
+    }
+
     pub async fn get_recent_tracks(
         &self,
         number: Option<u16>,
     ) -> Result<SpotifyTackArray, SpotifyError> {
+        self.get_recently_played(number)
+            .await
+            .map(|played| played.into_iter().map(|item| item.track).collect())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-recently-played
+    pub async fn get_recently_played(
+        &self,
+        number: Option<u16>,
+    ) -> Result<Vec<SpotifyPlayedTrack>, SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
         let number = number.unwrap_or(5);
@@ -187,320 +706,176 @@ impl Spotify {
             ));
         }
 
-        let mut output = Vec::new();
-
         let res = self
-            .client
-            .get(format!("{RECENTLY_PLAYED_TRACKS}/?limit={number}"))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+            .send(
+                self.client
+                    .get(format!(
+                        "{}/?limit={number}",
+                        self.api_url(RECENTLY_PLAYED_TRACKS)
+                    ))
+                    .header("Authorization", self.bearer()?),
+                &format!("{number} recent tracks"),
             )
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!(
-                    "Failed to send Spotify {number} recently played tracks request: {err}"
-                ))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch {} recent tracks: ({}) {:?}",
-                number,
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+            .await?;
 
-        let body: serde_json::Value = res.json().await.map_err(|err| {
-            SpotifyError::Generic(format!("Failed to parse recent tracks json result: {err}"))
-        })?;
+        let body: payloads::Paging<payloads::PlayHistory> =
+            Self::parse(res, "recent tracks").await?;
 
-        let Some(items) = body["items"].as_array() else {
-            error!("Unexpected error: Cannot get items from json output {body:?}");
-            return Err(SpotifyError::Generic(
-                "Unexpected error: Cannot get items from json output".into(),
-            ));
-        };
-
-        for item in items {
-            output.push(SpotifyTrack {
-                track_id: item["track"]["id"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track ID".into()))?
-                    .to_owned(),
-                track_name: item["track"]["name"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track name".into()))?
-                    .to_owned(),
-                artist_name: item["track"]["artists"]
-                    .as_array()
-                    .ok_or(SpotifyError::Generic("Cannot get track artists".into()))?
-                    .iter()
-                    .map(|artist| artist["name"].as_str().unwrap_or("Unknown artist"))
-                    .collect::<Vec<_>>()
-                    .join(" - "),
-                track_duration: item["track"]["duration_ms"]
-                    .as_i64()
-                    .ok_or(SpotifyError::Generic("Cannot get track duration".into()))?
-                    .to_owned(),
-            });
-        }
+        Ok(body.items.into_iter().map(Into::into).collect())
+    }
 
-        Ok(output)
+    /// Cached for the rooms of the same host account, see SPOTIFY_CACHE_TTL_MS
+    pub async fn get_current_playback_state(
+        &self,
+    ) -> Result<Option<SpotifyCurrentPlaybackOutput>, SpotifyError> {
+        cache::PLAYBACK_CACHE
+            .get_or_fetch(
+                self.cache_key()?,
+                crate::config::get().spotify_cache_ttl,
+                self.clock.as_ref(),
+                self.fetch_current_playback_state(),
+            )
+            .await
     }
 
     // https://developer.spotify.com/documentation/web-api/reference/get-information-about-the-users-current-playback
-    pub async fn get_current_playback_state(
+    async fn fetch_current_playback_state(
         &self,
     ) -> Result<Option<SpotifyCurrentPlaybackOutput>, SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
         let res = self
-            .client
-            .get(CURRENT_PLAYBACK_STATE)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+            .send(
+                self.client
+                    .get(self.api_url(CURRENT_PLAYBACK_STATE))
+                    .header("Authorization", self.bearer()?),
+                "current playback state",
             )
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!(
-                    "Failed to send Spotify current playback state request: {err}"
-                ))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch current playback state: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
+            .await?;
+
+        let body = res.text().await.map_err(|err| {
+            SpotifyError::Generic(format!("Failed to read current playback state: {err}"))
+        })?;
+
+        // Empty body (204) when the client is not playing
+        if body.trim().is_empty() {
+            return Ok(None);
         }
 
-        let body: serde_json::Value = match res.json().await {
-            Ok(v) => v,
-            Err(err) => {
-                debug!(
-                    "Failed to parse current playback state json result (probably empty body because client is not playing): {err}"
-                );
-                return Ok(None);
-            }
-        };
+        let state: payloads::PlaybackState = serde_json::from_str(&body).map_err(|err| {
+            SpotifyError::Generic(format!(
+                "Failed to parse current playback state json result: {err}"
+            ))
+        })?;
 
-        Ok(Some(SpotifyCurrentPlaybackOutput {
-            device_id: body["device"]["id"]
-                .as_str()
-                .ok_or(SpotifyError::Generic("Cannot get device ID".into()))?
-                .to_owned(),
-            device_volume: body["device"]["volume_percent"]
-                .as_u64()
-                .ok_or(SpotifyError::Generic("Cannot get device ID".into()))?
-                as _,
-            shuffle: body["shuffle_state"]
-                .as_bool()
-                .ok_or(SpotifyError::Generic("Cannot get shuffle state".into()))?,
-            progress_ms: if body["progress_ms"].is_null() {
-                None
-            } else {
-                Some(
-                    body["progress_ms"]
-                        .as_u64()
-                        .ok_or(SpotifyError::Generic("Cannot get progress ms".into()))?
-                        as _,
-                )
-            },
-            duration_ms: body["item"]["duration_ms"]
-                .as_u64()
-                .ok_or(SpotifyError::Generic("Cannot get track duration ms".into()))?,
-            is_playing: body["is_playing"]
-                .as_bool()
-                .ok_or(SpotifyError::Generic("Cannot get is playing state".into()))?,
-            track_id: body["item"]["id"]
-                .as_str()
-                .ok_or(SpotifyError::Generic("Cannot get track ID".into()))?
-                .to_owned(),
-            track_name: body["item"]["name"]
-                .as_str()
-                .ok_or(SpotifyError::Generic("Cannot get track name".into()))?
-                .to_owned(),
-            artist_name: body["item"]["artists"]
-                .as_array()
-                .ok_or(SpotifyError::Generic("Cannot get track artists".into()))?
-                .iter()
-                .map(|artist| artist["name"].as_str().unwrap_or("Unknown artist"))
-                .collect::<Vec<_>>()
-                .join(" - "),
-            album_image_src: body["item"]["album"]["images"]
-                .as_array()
-                .ok_or(SpotifyError::Generic("Cannot get album image".into()))?
-                .first()
-                .ok_or(SpotifyError::Generic("Cannot get first album cover".into()))?["url"]
-                .as_str()
-                .ok_or(SpotifyError::Generic(
-                    "Cannot get url field on first album cover image".into(),
-                ))?
-                .to_owned(),
-        }))
+        Ok(state.into_output())
     }
 
-    // https://developer.spotify.com/documentation/web-api/reference/get-queue
+    /// Cached for the rooms of the same host account, see SPOTIFY_CACHE_TTL_MS
     pub async fn get_next_tracks(&self) -> Result<SpotifyTackArray, SpotifyError> {
-        self.rate_limiter.write().await.increment()?;
-
-        let mut output = Vec::new();
-
-        let res = self
-            .client
-            .get(PLAYER_QUEUE)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+        cache::QUEUE_CACHE
+            .get_or_fetch(
+                self.cache_key()?,
+                crate::config::get().spotify_cache_ttl,
+                self.clock.as_ref(),
+                self.fetch_next_tracks(),
             )
-            .send()
             .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send player queue request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch player queue: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+    }
 
-        let body: serde_json::Value = res.json().await.map_err(|err| {
-            SpotifyError::Generic(format!("Failed to parse next tracks json result: {err}"))
-        })?;
+    // https://developer.spotify.com/documentation/web-api/reference/get-queue
+    async fn fetch_next_tracks(&self) -> Result<SpotifyTackArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
 
-        let Some(items) = body["queue"].as_array() else {
-            error!("Unexpected error: Cannot get items from json output {body:?}");
-            return Err(SpotifyError::Generic(
-                "Unexpected error: Cannot get items from json output".into(),
-            ));
-        };
+        let res = self
+            .send(
+                self.client
+                    .get(self.api_url(PLAYER_QUEUE))
+                    .header("Authorization", self.bearer()?),
+                "player queue",
+            )
+            .await?;
 
-        for item in items {
-            output.push(SpotifyTrack {
-                track_id: item["id"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track ID".into()))?
-                    .to_owned(),
-                track_name: item["name"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track name".into()))?
-                    .to_owned(),
-                artist_name: item["artists"]
-                    .as_array()
-                    .ok_or(SpotifyError::Generic("Cannot get track artists".into()))?
-                    .iter()
-                    .map(|artist| artist["name"].as_str().unwrap_or("Unknown artist"))
-                    .collect::<Vec<_>>()
-                    .join(" - "),
-                track_duration: item["duration_ms"]
-                    .as_i64()
-                    .ok_or(SpotifyError::Generic("Cannot get track duration".into()))?,
-            });
-        }
+        let body: payloads::Queue = Self::parse(res, "next tracks").await?;
 
-        Ok(output)
+        Ok(body
+            .queue
+            .into_iter()
+            .filter_map(payloads::Playable::into_track)
+            .collect())
     }
 
     // https://developer.spotify.com/documentation/web-api/reference/search
     pub async fn search_track(&self, value: String) -> Result<SpotifyTackArray, SpotifyError> {
+        self.search(value, &[SearchType::Track], 0, DEFAULT_SEARCH_LIMIT)
+            .await
+            .map(|results| results.tracks)
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/search
+    /// Searches tracks only when no type is given. The limit applies to each type, it defaults
+    /// to DEFAULT_SEARCH_LIMIT when 0 and both the limit and offset are capped to Spotify's max
+    pub async fn search(
+        &self,
+        value: String,
+        types: &[SearchType],
+        offset: u32,
+        limit: u32,
+    ) -> Result<SpotifySearchResults, SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let mut tracks = Vec::new();
+        let types = match types {
+            [] => SearchType::Track.as_str().to_owned(),
+            types => types
+                .iter()
+                .map(SearchType::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let limit = match limit {
+            0 => DEFAULT_SEARCH_LIMIT,
+            limit => limit.min(MAX_SEARCH_LIMIT),
+        };
 
         let res = self
-            .client
-            .get(format!(
-                "{SEARCH}?type=track&q={}&limit=20",
-                encode_url(&value)
-            ))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+            .send(
+                self.client
+                    .get(format!(
+                        "{}?type={types}&q={}&limit={limit}&offset={}",
+                        self.api_url(SEARCH),
+                        encode_url(&value),
+                        offset.min(MAX_SEARCH_OFFSET),
+                    ))
+                    .header("Authorization", self.bearer()?),
+                "search",
             )
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send search request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch search: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+            .await?;
 
-        let body: serde_json::Value = res.json().await.map_err(|err| {
-            SpotifyError::Generic(format!("Failed to parse search json result: {err}"))
-        })?;
+        let body: payloads::Search = Self::parse(res, "search").await?;
 
-        for track in body["tracks"]["items"]
-            .as_array()
-            .ok_or(SpotifyError::Generic("Cannot parse tracks to array".into()))?
-        {
-            tracks.push(SpotifyTrack {
-                track_id: track["id"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track id".into()))?
-                    .to_owned(),
-                track_name: track["name"]
-                    .as_str()
-                    .ok_or(SpotifyError::Generic("Cannot get track name".into()))?
-                    .to_owned(),
-                artist_name: track["artists"]
-                    .as_array()
-                    .ok_or(SpotifyError::Generic("Cannot get track artists".into()))?
-                    .iter()
-                    .map(|artist| artist["name"].as_str().unwrap_or("Unknown artist"))
-                    .collect::<Vec<_>>()
-                    .join(" - "),
-                track_duration: track["duration_ms"]
-                    .as_i64()
-                    .ok_or(SpotifyError::Generic("Cannot get track duration".into()))?,
-            })
-        }
-
-        Ok(tracks)
+        Ok(body.into())
     }
 
     // https://developer.spotify.com/documentation/web-api/reference/add-to-queue
-    pub async fn add_track_to_queue(&self, track_id: String) -> Result<(), SpotifyError> {
+    pub async fn add_track_to_queue(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .post(format!(
-                "{ADD_TO_QUEUE}?uri={}",
-                encode_url(&format!("spotify:track:{track_id}"))
-            ))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send add to queue request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch add to queue: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .post(format!(
+                    "{}?uri={}",
+                    self.api_url(ADD_TO_QUEUE),
+                    encode_url(&format!("spotify:{}:{track_id}", item_type.as_str()))
+                ))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "add to queue",
+        )
+        .await?;
 
         Ok(())
     }
@@ -509,27 +884,88 @@ impl Spotify {
     pub async fn play_resume(&self) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .put(PLAY_RESUME)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send play resume request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch play resume: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .put(self.api_url(PLAY_RESUME))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "play resume",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/start-a-users-playback
+    pub async fn play_track_at(
+        &self,
+        track_id: String,
+        item_type: PlaybackItemType,
+        position_ms: u64,
+    ) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(self.api_url(PLAY_RESUME))
+                .header("Authorization", self.bearer()?)
+                .json(&serde_json::json!({
+                    "uris": [format!("spotify:{}:{track_id}", item_type.as_str())],
+                    "position_ms": position_ms,
+                })),
+            "play track",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Plays the item on the device of the account, e.g. a Web Playback SDK player
+    // https://developer.spotify.com/documentation/web-api/reference/start-a-users-playback
+    pub async fn play_on_device(
+        &self,
+        device_id: &str,
+        track_id: &str,
+        item_type: PlaybackItemType,
+        position_ms: u64,
+    ) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(format!(
+                    "{}?device_id={}",
+                    self.api_url(PLAY_RESUME),
+                    encode_url(device_id)
+                ))
+                .header("Authorization", self.bearer()?)
+                .json(&serde_json::json!({
+                    "uris": [format!("spotify:{}:{track_id}", item_type.as_str())],
+                    "position_ms": position_ms,
+                })),
+            "play on device",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/pause-a-users-playback
+    pub async fn pause_device(&self, device_id: &str) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(format!(
+                    "{}?device_id={}",
+                    self.api_url(PAUSE),
+                    encode_url(device_id)
+                ))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "pause device",
+        )
+        .await?;
 
         Ok(())
     }
@@ -538,25 +974,14 @@ impl Spotify {
     pub async fn pause(&self) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .put(PAUSE)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| SpotifyError::Generic(format!("Failed to send pause request: {err}")))?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch pause: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .put(self.api_url(PAUSE))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "pause",
+        )
+        .await?;
 
         Ok(())
     }
@@ -565,27 +990,14 @@ impl Spotify {
     pub async fn skip_previous(&self) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .post(SKIP_PREVIOUS)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send skip to previous request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch skip to previous: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .post(self.api_url(SKIP_PREVIOUS))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "skip to previous",
+        )
+        .await?;
 
         Ok(())
     }
@@ -594,27 +1006,14 @@ impl Spotify {
     pub async fn skip_next(&self) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .post(SKIP_NEXT)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send skip to next request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch skip to next: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .post(self.api_url(SKIP_NEXT))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "skip to next",
+        )
+        .await?;
 
         Ok(())
     }
@@ -623,27 +1022,14 @@ impl Spotify {
     pub async fn seek_to_ms(&self, ms: u64) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
-        let res = self
-            .client
-            .put(format!("{SEEK_TO_POS}?position_ms={}", ms))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
-            )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send seek to pos request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch seek to pos: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
-        }
+        self.send(
+            self.client
+                .put(format!("{}?position_ms={}", self.api_url(SEEK_TO_POS), ms))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "seek to pos",
+        )
+        .await?;
 
         Ok(())
     }
@@ -652,59 +1038,422 @@ impl Spotify {
     pub async fn set_volume(&self, volume: u8) -> Result<(), SpotifyError> {
         self.rate_limiter.write().await.increment()?;
 
+        self.send(
+            self.client
+                .put(format!(
+                    "{}?volume_percent={}",
+                    self.api_url(SET_VOLUME),
+                    volume
+                ))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "set volume",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-a-users-available-devices
+    pub async fn get_devices(&self) -> Result<SpotifyDeviceArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
         let res = self
-            .client
-            .put(format!("{SET_VOLUME}?volume_percent={}", volume))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+            .send(
+                self.client
+                    .get(self.api_url(DEVICES))
+                    .header("Authorization", self.bearer()?),
+                "devices",
             )
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .map_err(|err| SpotifyError::Generic(format!("Failed to set volume request: {err}")))?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch set volume: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
+            .await?;
+
+        let body: payloads::Devices = Self::parse(res, "devices").await?;
+
+        Ok(body
+            .devices
+            .into_iter()
+            .filter_map(payloads::Device::into_device)
+            .collect())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/transfer-a-users-playback
+    /// The playback state (playing or paused) is kept on the new device
+    pub async fn transfer_playback(&self, device_id: String) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(self.api_url(TRANSFER_PLAYBACK))
+                .header("Authorization", self.bearer()?)
+                .json(&serde_json::json!({ "device_ids": [device_id] })),
+            "transfer playback",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-a-list-of-current-users-playlists
+    pub async fn get_user_playlists(&self) -> Result<SpotifyPlaylistArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!("{}?limit=50", self.api_url(USER_PLAYLISTS)))
+                    .header("Authorization", self.bearer()?),
+                "user playlists",
+            )
+            .await?;
+
+        let body: payloads::Paging<payloads::Playlist> = Self::parse(res, "user playlists").await?;
+
+        Ok(body.items.into_iter().map(Into::into).collect())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-playlists-tracks
+    /// Only the first 100 tracks are returned
+    pub async fn get_playlist_tracks(
+        &self,
+        playlist_id: String,
+    ) -> Result<SpotifyTackArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!(
+                        "{}/{}/tracks?limit=100",
+                        self.api_url(PLAYLISTS),
+                        encode_url(&playlist_id)
+                    ))
+                    .header("Authorization", self.bearer()?),
+                "playlist tracks",
+            )
+            .await?;
+
+        let body: payloads::Paging<payloads::PlaylistItem> =
+            Self::parse(res, "playlist tracks").await?;
+
+        Ok(body
+            .items
+            .into_iter()
+            .filter_map(|item| item.track?.into_track())
+            .collect())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/create-playlist
+    /// Private playlist of the host account
+    pub async fn create_playlist(
+        &self,
+        name: String,
+        description: String,
+    ) -> Result<SpotifyPlaylist, SpotifyError> {
+        let user_id = self.get_my_id().await?;
+
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .post(format!(
+                        "{}/{}/playlists",
+                        self.api_url(USERS),
+                        encode_url(&user_id)
+                    ))
+                    .header("Authorization", self.bearer()?)
+                    .json(&serde_json::json!({
+                        "name": name,
+                        "description": description,
+                        "public": false,
+                    })),
+                "create playlist",
+            )
+            .await?;
+
+        let body: payloads::Playlist = Self::parse(res, "create playlist").await?;
+
+        Ok(body.into())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/add-tracks-to-playlist
+    /// Appended in order, MAX_PLAYLIST_ITEMS_PER_REQUEST at a time
+    pub async fn add_tracks_to_playlist(
+        &self,
+        playlist_id: String,
+        tracks: Vec<(String, PlaybackItemType)>,
+    ) -> Result<(), SpotifyError> {
+        for chunk in tracks.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+            self.rate_limiter.write().await.increment()?;
+
+            let uris = chunk
+                .iter()
+                .map(|(track_id, item_type)| format!("spotify:{}:{track_id}", item_type.as_str()))
+                .collect::<Vec<_>>();
+
+            self.send(
+                self.client
+                    .post(format!(
+                        "{}/{}/tracks",
+                        self.api_url(PLAYLISTS),
+                        encode_url(&playlist_id)
+                    ))
+                    .header("Authorization", self.bearer()?)
+                    .json(&serde_json::json!({ "uris": uris })),
+                "add tracks to playlist",
+            )
+            .await?;
         }
 
         Ok(())
     }
 
-    pub async fn get_my_id(&self) -> Result<String, SpotifyError> {
+    // https://developer.spotify.com/documentation/web-api/reference/start-a-users-playback
+    /// Starts playing the items as the base queue (e.g. the allowed tracks of a playlist), the
+    /// tracks added to the Spotify queue still play before its next items
+    pub async fn play_items(
+        &self,
+        tracks: Vec<(String, PlaybackItemType)>,
+    ) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let uris = tracks
+            .iter()
+            .map(|(track_id, item_type)| format!("spotify:{}:{track_id}", item_type.as_str()))
+            .collect::<Vec<_>>();
+
+        self.send(
+            self.client
+                .put(self.api_url(PLAY_RESUME))
+                .header("Authorization", self.bearer()?)
+                .json(&serde_json::json!({ "uris": uris })),
+            "play items",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/toggle-shuffle-for-users-playback
+    pub async fn set_shuffle(&self, state: bool) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(format!("{}?state={state}", self.api_url(SET_SHUFFLE)))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "set shuffle",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/set-repeat-mode-on-users-playback
+    pub async fn set_repeat(&self, mode: RepeatMode) -> Result<(), SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        self.send(
+            self.client
+                .put(format!(
+                    "{}?state={}",
+                    self.api_url(SET_REPEAT),
+                    mode.as_str()
+                ))
+                .header("Authorization", self.bearer()?)
+                .header("Content-Length", 0),
+            "set repeat",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-users-top-artists-and-tracks
+    pub async fn get_top_tracks(&self) -> Result<SpotifyTackArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!("{}?limit=50", self.api_url(TOP_TRACKS)))
+                    .header("Authorization", self.bearer()?),
+                "top tracks",
+            )
+            .await?;
+
+        let body: payloads::Paging<payloads::Track> = Self::parse(res, "top tracks").await?;
+
+        Ok(body.items.into_iter().map(Into::into).collect())
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-recommendations
+    pub async fn get_recommendations(
+        &self,
+        seed_track_id: String,
+    ) -> Result<SpotifyTackArray, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!(
+                        "{}?seed_tracks={}&limit=20",
+                        self.api_url(RECOMMENDATIONS),
+                        encode_url(&seed_track_id)
+                    ))
+                    .header("Authorization", self.bearer()?),
+                "recommendations",
+            )
+            .await?;
+
+        let body: payloads::Recommendations = Self::parse(res, "recommendations").await?;
+
+        Ok(body.tracks.into_iter().map(Into::into).collect())
+    }
+
+    async fn parse<T: DeserializeOwned>(
+        res: reqwest::Response,
+        action: &str,
+    ) -> Result<T, SpotifyError> {
+        res.json().await.map_err(|err| {
+            SpotifyError::Generic(format!("Failed to parse {action} json result: {err}"))
+        })
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-current-users-profile
+    /// ISO 3166-1 alpha-2 country of the account, None without the user-read-private scope
+    pub async fn get_my_market(&self) -> Result<Option<String>, SpotifyError> {
+        #[derive(Deserialize)]
+        struct User {
+            country: Option<String>,
+        }
+
         self.rate_limiter.write().await.increment()?;
 
         let res = self
-            .client
-            .get("https://api.spotify.com/v1/me")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.tokens.access_token),
+            .send(
+                self.client
+                    .get(self.api_url(CURRENT_USER))
+                    .header("Authorization", self.bearer()?),
+                "Spotify user market",
+            )
+            .await?;
+
+        let body: User = Self::parse(res, "Spotify user market").await?;
+
+        Ok(body.country)
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-track
+    /// Markets (ISO 3166-1 alpha-2 countries) in which the track can be played
+    pub async fn get_track_markets(&self, track_id: String) -> Result<Vec<String>, SpotifyError> {
+        #[derive(Deserialize)]
+        struct Track {
+            #[serde(default)]
+            available_markets: Vec<String>,
+        }
+
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!(
+                        "{}/{}",
+                        self.api_url(TRACKS),
+                        encode_url(&track_id)
+                    ))
+                    .header("Authorization", self.bearer()?),
+                "track markets",
+            )
+            .await?;
+
+        let body: Track = Self::parse(res, "track markets").await?;
+
+        Ok(body.available_markets)
+    }
+
+    /// The track or episode as Spotify knows it, so the duration and explicit flag checked
+    /// against the room settings aren't the ones sent by the client. Cached for ITEM_CACHE_TTL
+    pub async fn get_item(
+        &self,
+        item_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<SpotifyTrack, SpotifyError> {
+        // Local files only exist on the host devices, their URI is all there is to know
+        if item_id.starts_with("spotify:local:") {
+            return local_file_item(item_id);
+        }
+
+        let uri = format!("spotify:{}:{item_id}", item_type.as_str());
+
+        cache::ITEM_CACHE
+            .get_or_fetch(
+                cache::cache_key(&uri),
+                ITEM_CACHE_TTL,
+                self.clock.as_ref(),
+                self.fetch_item(item_id, item_type),
             )
-            .send()
             .await
-            .map_err(|err| {
-                SpotifyError::Generic(format!("Failed to send Spotify user info request: {err}"))
-            })?;
-
-        if !res.status().is_success() {
-            return Err(SpotifyError::Generic(format!(
-                "Failed to fetch Spotify user info: ({}) {:?}",
-                res.status(),
-                res.text().await.unwrap()
-            )));
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-track
+    // https://developer.spotify.com/documentation/web-api/reference/get-an-episode
+    async fn fetch_item(
+        &self,
+        item_id: String,
+        item_type: PlaybackItemType,
+    ) -> Result<SpotifyTrack, SpotifyError> {
+        self.rate_limiter.write().await.increment()?;
+
+        let endpoint = match item_type {
+            PlaybackItemType::Track => TRACKS,
+            PlaybackItemType::Episode => EPISODES,
+        };
+
+        let res = self
+            .send(
+                self.client
+                    .get(format!(
+                        "{}/{}",
+                        self.api_url(endpoint),
+                        encode_url(&item_id)
+                    ))
+                    .header("Authorization", self.bearer()?),
+                &format!("{} {item_id}", item_type.as_str()),
+            )
+            .await?;
+
+        Ok(match item_type {
+            PlaybackItemType::Track => Self::parse::<payloads::Track>(res, "track").await?.into(),
+            PlaybackItemType::Episode => Self::parse::<payloads::Episode>(res, "episode")
+                .await?
+                .into(),
+        })
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-current-users-profile
+    pub async fn get_my_id(&self) -> Result<String, SpotifyError> {
+        #[derive(Deserialize)]
+        struct User {
+            id: String,
         }
 
-        let body: serde_json::Value = res.json().await.map_err(|err| {
-            SpotifyError::Generic(format!(
-                "Failed to parse Spotify user info json result: {err}"
-            ))
-        })?;
+        self.rate_limiter.write().await.increment()?;
+
+        let res = self
+            .send(
+                self.client
+                    .get(self.api_url(CURRENT_USER))
+                    .header("Authorization", self.bearer()?),
+                "Spotify user info",
+            )
+            .await?;
+
+        let body: User = Self::parse(res, "Spotify user info").await?;
 
-        Ok(body["id"].as_str().unwrap().to_owned())
+        Ok(body.id)
     }
 }
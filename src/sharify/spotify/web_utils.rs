@@ -1,18 +1,39 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+/// Paths of the Web API (under SpotifyBaseUrls.api) and of the accounts service (under
+/// SpotifyBaseUrls.accounts)
 pub mod endpoints {
-    pub const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-    pub const RECENTLY_PLAYED_TRACKS: &str = "https://api.spotify.com/v1/me/player/recently-played";
-    pub const CURRENT_PLAYBACK_STATE: &str = "https://api.spotify.com/v1/me/player";
-    pub const PLAYER_QUEUE: &str = "https://api.spotify.com/v1/me/player/queue";
-    pub const SEARCH: &str = "https://api.spotify.com/v1/search";
-    pub const ADD_TO_QUEUE: &str = "https://api.spotify.com/v1/me/player/queue";
-    pub const SET_VOLUME: &str = "https://api.spotify.com/v1/me/player/volume";
-    pub const SEEK_TO_POS: &str = "https://api.spotify.com/v1/me/player/seek";
-    pub const SKIP_PREVIOUS: &str = "https://api.spotify.com/v1/me/player/previous";
-    pub const SKIP_NEXT: &str = "https://api.spotify.com/v1/me/player/next";
-    pub const PLAY_RESUME: &str = "https://api.spotify.com/v1/me/player/play";
-    pub const PAUSE: &str = "https://api.spotify.com/v1/me/player/pause";
+    pub const DEFAULT_API_BASE_URL: &str = "https://api.spotify.com/v1";
+    pub const DEFAULT_ACCOUNTS_BASE_URL: &str = "https://accounts.spotify.com";
+
+    pub const AUTHORIZE: &str = "/authorize";
+    pub const TOKEN: &str = "/api/token";
+
+    pub const CURRENT_USER: &str = "/me";
+    pub const RECENTLY_PLAYED_TRACKS: &str = "/me/player/recently-played";
+    pub const CURRENT_PLAYBACK_STATE: &str = "/me/player";
+    pub const PLAYER_QUEUE: &str = "/me/player/queue";
+    pub const SEARCH: &str = "/search";
+    pub const ADD_TO_QUEUE: &str = "/me/player/queue";
+    pub const SET_SHUFFLE: &str = "/me/player/shuffle";
+    pub const SET_REPEAT: &str = "/me/player/repeat";
+    pub const SET_VOLUME: &str = "/me/player/volume";
+    pub const SEEK_TO_POS: &str = "/me/player/seek";
+    pub const SKIP_PREVIOUS: &str = "/me/player/previous";
+    pub const SKIP_NEXT: &str = "/me/player/next";
+    pub const PLAY_RESUME: &str = "/me/player/play";
+    pub const PAUSE: &str = "/me/player/pause";
+    pub const TOP_TRACKS: &str = "/me/top/tracks";
+    pub const RECOMMENDATIONS: &str = "/recommendations";
+    pub const DEVICES: &str = "/me/player/devices";
+    pub const TRANSFER_PLAYBACK: &str = "/me/player";
+    pub const USER_PLAYLISTS: &str = "/me/playlists";
+    pub const PLAYLISTS: &str = "/playlists";
+    pub const USERS: &str = "/users";
+    pub const TRACKS: &str = "/tracks";
+    pub const EPISODES: &str = "/episodes";
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,21 +45,152 @@ pub struct RefreshTokenOutput {
     pub scope: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SpotifyTrack {
     pub track_id: String,
     pub track_name: String,
     pub artist_name: String,
     pub track_duration: i64,
+    /// Show name for episodes
+    pub album_name: String,
+    pub album_image_src: String,
+    pub explicit: bool,
+    /// 0 to 100, None for episodes and local files
+    pub popularity: Option<u32>,
+    #[serde(default)]
+    pub item_type: PlaybackItemType,
 }
 
 pub type SpotifyTackArray = Vec<SpotifyTrack>;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotifyDevice {
+    pub device_id: String,
+    pub name: String,
+    /// Computer, Smartphone, Speaker...
+    pub device_type: String,
+    pub is_active: bool,
+    pub volume_percent: Option<u8>,
+}
+
+pub type SpotifyDeviceArray = Vec<SpotifyDevice>;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotifyPlaylist {
+    pub playlist_id: String,
+    pub name: String,
+    pub owner_name: String,
+    pub tracks_count: u32,
+    pub image_src: String,
+}
+
+pub type SpotifyPlaylistArray = Vec<SpotifyPlaylist>;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotifyAlbum {
+    pub album_id: String,
+    pub name: String,
+    pub artist_name: String,
+    pub image_src: String,
+    pub tracks_count: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotifyArtist {
+    pub artist_id: String,
+    pub name: String,
+    pub image_src: String,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SearchType {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+}
+
+impl SearchType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Album => "album",
+            Self::Artist => "artist",
+            Self::Playlist => "playlist",
+        }
+    }
+}
+
+/// Only the searched types are filled, totals are the count of results across every page
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SpotifySearchResults {
+    pub tracks: SpotifyTackArray,
+    pub tracks_total: u32,
+    pub albums: Vec<SpotifyAlbum>,
+    pub albums_total: u32,
+    pub artists: Vec<SpotifyArtist>,
+    pub artists_total: u32,
+    pub playlists: SpotifyPlaylistArray,
+    pub playlists_total: u32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum PlaybackItemType {
+    #[default]
+    Track,
+    /// Podcast episode, the show name is used as the artist name
+    Episode,
+}
+
+impl PlaybackItemType {
+    /// Type of the Spotify URI
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Episode => "episode",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Repeats the playlist/album
+    Context,
+    Track,
+}
+
+impl RepeatMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Context => "context",
+            Self::Track => "track",
+        }
+    }
+}
+
+/// Short hash of an image URL, it changes when Spotify rotates the URL so clients can bust their
+/// cached cover. Empty without image
+pub fn image_revision(src: &str) -> String {
+    if src.is_empty() {
+        return String::new();
+    }
+
+    Sha256::digest(src.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct SpotifyCurrentPlaybackOutput {
     pub device_id: String,
     pub device_volume: u8,
     pub shuffle: bool,
+    pub repeat: RepeatMode,
     pub progress_ms: Option<u64>,
     pub duration_ms: u64,
     pub is_playing: bool,
@@ -46,4 +198,423 @@ pub struct SpotifyCurrentPlaybackOutput {
     pub track_name: String,
     pub artist_name: String,
     pub album_image_src: String,
+    pub item_type: PlaybackItemType,
+}
+
+impl SpotifyCurrentPlaybackOutput {
+    /// Time left before the end of the track, None when it's not playing
+    pub fn remaining_ms(&self) -> Option<u64> {
+        if !self.is_playing {
+            return None;
+        }
+
+        self.progress_ms
+            .map(|progress_ms| self.duration_ms.saturating_sub(progress_ms))
+    }
+
+    /// Only the fields known from the playback are set
+    pub fn to_track(&self) -> SpotifyTrack {
+        SpotifyTrack {
+            track_id: self.track_id.clone(),
+            track_name: self.track_name.clone(),
+            artist_name: self.artist_name.clone(),
+            track_duration: i64::try_from(self.duration_ms).unwrap_or(i64::MAX),
+            album_name: String::new(),
+            album_image_src: self.album_image_src.clone(),
+            explicit: false,
+            popularity: None,
+            item_type: self.item_type,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotifyPlayedTrack {
+    pub track: SpotifyTrack,
+    pub played_at: DateTime<Utc>,
+}
+
+/// Spotify Web API payloads, only the fields used are declared
+pub mod payloads {
+    use chrono::{DateTime, Utc};
+    use serde::Deserialize;
+
+    use super::{
+        PlaybackItemType, RepeatMode, SpotifyAlbum, SpotifyArtist, SpotifyCurrentPlaybackOutput,
+        SpotifyDevice, SpotifyPlayedTrack, SpotifyPlaylist, SpotifySearchResults, SpotifyTrack,
+    };
+
+    #[derive(Debug, Deserialize)]
+    pub struct Paging<T> {
+        pub items: Vec<T>,
+        #[serde(default)]
+        pub total: u32,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Image {
+        pub url: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Artist {
+        #[serde(default)]
+        pub id: String,
+        pub name: String,
+        /// Only on full artist objects (search), not on the ones nested in tracks
+        #[serde(default)]
+        pub images: Vec<Image>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Album {
+        #[serde(default)]
+        pub id: String,
+        #[serde(default)]
+        pub name: String,
+        #[serde(default)]
+        pub images: Vec<Image>,
+        #[serde(default)]
+        pub artists: Vec<Artist>,
+        #[serde(default)]
+        pub total_tracks: u32,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Track {
+        /// Null for local files
+        pub id: Option<String>,
+        pub uri: String,
+        pub name: String,
+        #[serde(default)]
+        pub artists: Vec<Artist>,
+        pub duration_ms: u64,
+        pub album: Option<Album>,
+        #[serde(default)]
+        pub explicit: bool,
+        /// Missing for local files
+        pub popularity: Option<u32>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Show {
+        pub name: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Episode {
+        pub id: String,
+        pub name: String,
+        pub duration_ms: u64,
+        pub show: Option<Show>,
+        #[serde(default)]
+        pub images: Vec<Image>,
+        #[serde(default)]
+        pub explicit: bool,
+    }
+
+    /// Items of the playback and queue endpoints can be tracks or podcast episodes
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    pub enum Playable {
+        Track(Track),
+        Episode(Episode),
+        #[serde(other)]
+        Unknown,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Device {
+        /// Null for restricted devices
+        pub id: Option<String>,
+        pub volume_percent: Option<u8>,
+        #[serde(default)]
+        pub name: String,
+        #[serde(default, rename = "type")]
+        pub device_type: String,
+        #[serde(default)]
+        pub is_active: bool,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-a-users-available-devices
+    #[derive(Debug, Deserialize)]
+    pub struct Devices {
+        pub devices: Vec<Device>,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-information-about-the-users-current-playback
+    #[derive(Debug, Deserialize)]
+    pub struct PlaybackState {
+        pub device: Device,
+        #[serde(default)]
+        pub shuffle_state: bool,
+        #[serde(default)]
+        pub repeat_state: RepeatMode,
+        pub progress_ms: Option<u64>,
+        pub is_playing: bool,
+        /// Null during ads or when nothing is loaded
+        pub item: Option<Playable>,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-queue
+    #[derive(Debug, Deserialize)]
+    pub struct Queue {
+        pub queue: Vec<Playable>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct PlaylistOwner {
+        pub display_name: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct PlaylistTracksRef {
+        pub total: u32,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-a-list-of-current-users-playlists
+    #[derive(Debug, Deserialize)]
+    pub struct Playlist {
+        pub id: String,
+        pub name: String,
+        pub owner: PlaylistOwner,
+        pub tracks: PlaylistTracksRef,
+        /// Null when the playlist has no cover
+        pub images: Option<Vec<Image>>,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-playlists-tracks
+    #[derive(Debug, Deserialize)]
+    pub struct PlaylistItem {
+        /// Null when the track is no longer available
+        pub track: Option<Playable>,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-recently-played
+    #[derive(Debug, Deserialize)]
+    pub struct PlayHistory {
+        pub track: Track,
+        pub played_at: DateTime<Utc>,
+    }
+
+    impl From<PlayHistory> for SpotifyPlayedTrack {
+        fn from(item: PlayHistory) -> Self {
+            Self {
+                track: item.track.into(),
+                played_at: item.played_at,
+            }
+        }
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/search
+    /// Only the searched types are returned, albums and playlists items can be null
+    #[derive(Debug, Deserialize)]
+    pub struct Search {
+        pub tracks: Option<Paging<Track>>,
+        pub albums: Option<Paging<Option<Album>>>,
+        pub artists: Option<Paging<Artist>>,
+        pub playlists: Option<Paging<Option<Playlist>>>,
+    }
+
+    // https://developer.spotify.com/documentation/web-api/reference/get-recommendations
+    #[derive(Debug, Deserialize)]
+    pub struct Recommendations {
+        pub tracks: Vec<Track>,
+    }
+
+    fn artists_name(artists: &[Artist]) -> String {
+        if artists.is_empty() {
+            return "Unknown artist".into();
+        }
+
+        artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" - ")
+    }
+
+    impl Track {
+        fn artist_name(&self) -> String {
+            artists_name(&self.artists)
+        }
+    }
+
+    fn first_image_src(images: Vec<Image>) -> String {
+        images
+            .into_iter()
+            .next()
+            .map(|image| image.url)
+            .unwrap_or_default()
+    }
+
+    impl From<Track> for SpotifyTrack {
+        fn from(track: Track) -> Self {
+            let artist_name = track.artist_name();
+            let (album_name, album_image_src) = track
+                .album
+                .map(|album| (album.name, first_image_src(album.images)))
+                .unwrap_or_default();
+
+            Self {
+                artist_name,
+                // Local files have no ID, their URI is the only identifier
+                track_id: track.id.unwrap_or(track.uri),
+                track_name: track.name,
+                track_duration: i64::try_from(track.duration_ms).unwrap_or(i64::MAX),
+                album_name,
+                album_image_src,
+                explicit: track.explicit,
+                popularity: track.popularity,
+                item_type: PlaybackItemType::Track,
+            }
+        }
+    }
+
+    impl From<Episode> for SpotifyTrack {
+        fn from(episode: Episode) -> Self {
+            let show_name = episode
+                .show
+                .map(|show| show.name)
+                .unwrap_or("Unknown show".into());
+
+            Self {
+                track_id: episode.id,
+                track_name: episode.name,
+                artist_name: show_name.clone(),
+                track_duration: i64::try_from(episode.duration_ms).unwrap_or(i64::MAX),
+                album_name: show_name,
+                album_image_src: first_image_src(episode.images),
+                explicit: episode.explicit,
+                popularity: None,
+                item_type: PlaybackItemType::Episode,
+            }
+        }
+    }
+
+    impl From<Album> for SpotifyAlbum {
+        fn from(album: Album) -> Self {
+            Self {
+                artist_name: artists_name(&album.artists),
+                album_id: album.id,
+                name: album.name,
+                image_src: first_image_src(album.images),
+                tracks_count: album.total_tracks,
+            }
+        }
+    }
+
+    impl From<Artist> for SpotifyArtist {
+        fn from(artist: Artist) -> Self {
+            Self {
+                artist_id: artist.id,
+                name: artist.name,
+                image_src: first_image_src(artist.images),
+            }
+        }
+    }
+
+    impl From<Search> for SpotifySearchResults {
+        fn from(search: Search) -> Self {
+            let mut results = Self::default();
+
+            if let Some(tracks) = search.tracks {
+                results.tracks_total = tracks.total;
+                results.tracks = tracks.items.into_iter().map(Into::into).collect();
+            }
+
+            if let Some(albums) = search.albums {
+                results.albums_total = albums.total;
+                results.albums = albums.items.into_iter().flatten().map(Into::into).collect();
+            }
+
+            if let Some(artists) = search.artists {
+                results.artists_total = artists.total;
+                results.artists = artists.items.into_iter().map(Into::into).collect();
+            }
+
+            if let Some(playlists) = search.playlists {
+                results.playlists_total = playlists.total;
+                results.playlists = playlists
+                    .items
+                    .into_iter()
+                    .flatten()
+                    .map(Into::into)
+                    .collect();
+            }
+
+            results
+        }
+    }
+
+    impl From<Playlist> for SpotifyPlaylist {
+        fn from(playlist: Playlist) -> Self {
+            Self {
+                image_src: first_image_src(playlist.images.unwrap_or_default()),
+                playlist_id: playlist.id,
+                name: playlist.name,
+                owner_name: playlist.owner.display_name.unwrap_or("Unknown user".into()),
+                tracks_count: playlist.tracks.total,
+            }
+        }
+    }
+
+    impl Device {
+        /// None for restricted devices, the playback can't be transferred to them
+        pub fn into_device(self) -> Option<SpotifyDevice> {
+            Some(SpotifyDevice {
+                device_id: self.id?,
+                name: self.name,
+                device_type: self.device_type,
+                is_active: self.is_active,
+                volume_percent: self.volume_percent,
+            })
+        }
+    }
+
+    impl Playable {
+        pub fn into_track(self) -> Option<SpotifyTrack> {
+            match self {
+                Self::Track(track) => Some(track.into()),
+                Self::Episode(episode) => Some(episode.into()),
+                Self::Unknown => None,
+            }
+        }
+
+        fn item_type(&self) -> Option<PlaybackItemType> {
+            match self {
+                Self::Track(_) => Some(PlaybackItemType::Track),
+                Self::Episode(_) => Some(PlaybackItemType::Episode),
+                Self::Unknown => None,
+            }
+        }
+    }
+
+    impl PlaybackState {
+        /// None when no track or episode is loaded
+        pub fn into_output(self) -> Option<SpotifyCurrentPlaybackOutput> {
+            let item = self.item?;
+            let item_type = item.item_type()?;
+            let track = item.into_track()?;
+            let duration_ms = u64::try_from(track.track_duration).unwrap_or_default();
+
+            Some(SpotifyCurrentPlaybackOutput {
+                device_id: self.device.id.unwrap_or_default(),
+                device_volume: self.device.volume_percent.unwrap_or_default(),
+                shuffle: self.shuffle_state,
+                repeat: self.repeat_state,
+                // Spotify can report a progress beyond the duration (crossfade, episodes)
+                progress_ms: self
+                    .progress_ms
+                    .map(|progress_ms| progress_ms.min(duration_ms)),
+                duration_ms,
+                is_playing: self.is_playing,
+                track_id: track.track_id,
+                track_name: track.track_name,
+                artist_name: track.artist_name,
+                album_image_src: track.album_image_src,
+                item_type,
+            })
+        }
+    }
 }
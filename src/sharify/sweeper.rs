@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use actix_rt::time;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::proto::cmd::WsCloseCode;
+
+use super::room_manager::RoomManager;
+use super::tasks::spawn_room_task;
+use super::websocket::SharifyWsManager;
+
+/// Counters since the server started and gauges of the last run
+#[derive(Debug, Default)]
+pub struct SweeperMetrics {
+    runs: AtomicU64,
+    orphan_user_ids: AtomicU64,
+    missing_user_ids: AtomicU64,
+    orphan_ws_sessions: AtomicU64,
+    orphan_room_loops: AtomicU64,
+    ghost_members: AtomicU64,
+    unflagged_sessions: AtomicU64,
+    tracked_user_ids: AtomicUsize,
+    ws_sessions: AtomicUsize,
+    active_rooms: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SweeperMetricsSnapshot {
+    pub runs: u64,
+    pub orphan_user_ids: u64,
+    pub missing_user_ids: u64,
+    pub orphan_ws_sessions: u64,
+    pub orphan_room_loops: u64,
+    /// Members flagged connected without a WS session
+    pub ghost_members: u64,
+    /// WS sessions of members flagged disconnected
+    pub unflagged_sessions: u64,
+    pub tracked_user_ids: usize,
+    pub ws_sessions: usize,
+    pub active_rooms: usize,
+}
+
+pub static METRICS: SweeperMetrics = SweeperMetrics {
+    runs: AtomicU64::new(0),
+    orphan_user_ids: AtomicU64::new(0),
+    missing_user_ids: AtomicU64::new(0),
+    orphan_ws_sessions: AtomicU64::new(0),
+    orphan_room_loops: AtomicU64::new(0),
+    ghost_members: AtomicU64::new(0),
+    unflagged_sessions: AtomicU64::new(0),
+    tracked_user_ids: AtomicUsize::new(0),
+    ws_sessions: AtomicUsize::new(0),
+    active_rooms: AtomicUsize::new(0),
+};
+
+impl SweeperMetrics {
+    pub fn snapshot(&self) -> SweeperMetricsSnapshot {
+        SweeperMetricsSnapshot {
+            runs: self.runs.load(Ordering::Relaxed),
+            orphan_user_ids: self.orphan_user_ids.load(Ordering::Relaxed),
+            missing_user_ids: self.missing_user_ids.load(Ordering::Relaxed),
+            orphan_ws_sessions: self.orphan_ws_sessions.load(Ordering::Relaxed),
+            orphan_room_loops: self.orphan_room_loops.load(Ordering::Relaxed),
+            ghost_members: self.ghost_members.load(Ordering::Relaxed),
+            unflagged_sessions: self.unflagged_sessions.load(Ordering::Relaxed),
+            tracked_user_ids: self.tracked_user_ids.load(Ordering::Relaxed),
+            ws_sessions: self.ws_sessions.load(Ordering::Relaxed),
+            active_rooms: self.active_rooms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Periodically reconciles the in-memory state in case a cleanup path was missed:
+/// - user_ids against the actual room members
+/// - WS sessions against live rooms and their members
+/// - The connection flags of the members against their WS sessions
+/// - Room scoped tasks (data fetching, token refresh, activity check) against live rooms
+/// - The expired Spotify logins and grants
+pub fn init_integrity_sweeper(
+    ws_mgr: Arc<RwLock<SharifyWsManager>>,
+    state_mgr: Arc<RwLock<RoomManager>>,
+) {
+    spawn_room_task(async move {
+        loop {
+            // Read on each tick so a config reload applies
+            time::sleep(crate::config::get().sweeper_interval).await;
+
+            sweep(&ws_mgr, &state_mgr).await;
+        }
+    });
+}
+
+async fn sweep(ws_mgr: &Arc<RwLock<SharifyWsManager>>, state_mgr: &Arc<RwLock<RoomManager>>) {
+    let (orphan_sessions, orphan_user_ids, missing_user_ids, connections, orphan_room_loops) = {
+        // The state is locked before the WS manager (as on WS init) and both are held so a user
+        // joining in between cannot be seen as an orphan
+        let mut state_guard = state_mgr.write().await;
+        let mut ws_guard = ws_mgr.write().await;
+
+        let (orphan_user_ids, missing_user_ids) = state_guard.reconcile_user_ids();
+
+        state_guard.prune_spotify_logins();
+
+        let orphan_ids = ws_guard
+            .iter()
+            .filter(|(user_id, instance)| !state_guard.is_room_member(&instance.room_id(), user_id))
+            .map(|(user_id, _)| user_id.clone())
+            .collect::<Vec<_>>();
+
+        let orphan_sessions = orphan_ids
+            .into_iter()
+            .filter_map(|user_id| ws_guard.remove(&user_id))
+            .collect::<Vec<_>>();
+
+        // After the orphans removal so a dropped session leaves its user suspended
+        let connections = state_guard.reconcile_connections(|room_id, user_id| {
+            ws_guard
+                .get(user_id)
+                .is_some_and(|instance| instance.room_id() == *room_id)
+        });
+
+        METRICS
+            .tracked_user_ids
+            .store(state_guard.user_ids_count(), Ordering::Relaxed);
+        METRICS.ws_sessions.store(ws_guard.len(), Ordering::Relaxed);
+        METRICS
+            .active_rooms
+            .store(state_guard.rooms_count(), Ordering::Relaxed);
+
+        (
+            orphan_sessions,
+            orphan_user_ids,
+            missing_user_ids,
+            connections,
+            state_guard.sweep_room_tasks(),
+        )
+    };
+
+    let orphan_sessions_count = orphan_sessions.len();
+    let (ghost_members, unflagged_sessions) = connections;
+
+    for instance in orphan_sessions {
+        instance
+            .close(Some(
+                WsCloseCode::SessionOrphaned.reason("Room or room member not found"),
+            ))
+            .await;
+    }
+
+    METRICS.runs.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .orphan_user_ids
+        .fetch_add(orphan_user_ids as _, Ordering::Relaxed);
+    METRICS
+        .missing_user_ids
+        .fetch_add(missing_user_ids as _, Ordering::Relaxed);
+    METRICS
+        .orphan_ws_sessions
+        .fetch_add(orphan_sessions_count as _, Ordering::Relaxed);
+    METRICS
+        .orphan_room_loops
+        .fetch_add(orphan_room_loops as _, Ordering::Relaxed);
+    METRICS
+        .ghost_members
+        .fetch_add(ghost_members as _, Ordering::Relaxed);
+    METRICS
+        .unflagged_sessions
+        .fetch_add(unflagged_sessions as _, Ordering::Relaxed);
+
+    if orphan_user_ids
+        + missing_user_ids
+        + orphan_sessions_count
+        + orphan_room_loops
+        + ghost_members
+        + unflagged_sessions
+        > 0
+    {
+        warn!(
+            "Integrity sweeper fixed {orphan_user_ids} orphan user ID(s), {missing_user_ids} \
+            missing user ID(s), {orphan_sessions_count} orphan WS session(s), \
+            {orphan_room_loops} orphan room loop(s), {ghost_members} ghost member(s) and \
+            {unflagged_sessions} unflagged WS session(s)"
+        );
+    }
+}
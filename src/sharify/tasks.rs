@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tracing::Instrument as _;
+
+use super::room::RoomID;
+
+/// Long-running room tasks (Spotify data fetching, token refresh, activity checks...) run on
+/// this multi-threaded runtime instead of the HTTP workers' single-threaded ones so busy rooms
+/// cannot starve the accept loop and the WS sessions handled by the same worker
+static ROOM_TASKS_RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    let config = crate::config::get();
+
+    Builder::new_multi_thread()
+        .worker_threads(config.room_tasks_threads.max(1))
+        .thread_name("room-tasks")
+        .enable_all()
+        .build()
+        .expect("Failed to build the room tasks runtime")
+});
+
+pub fn spawn_room_task<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // Keeps the span of the room/command it was spawned from
+    ROOM_TASKS_RUNTIME.spawn(future.in_current_span())
+}
+
+/// At most one task of each kind runs per room
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RoomTaskKind {
+    ActivityCheck,
+    SpotifyData,
+    TokenRefresh,
+}
+
+#[derive(Debug, Default)]
+struct RoomTaskSet {
+    set: JoinSet<()>,
+    handles: HashMap<RoomTaskKind, AbortHandle>,
+}
+
+impl RoomTaskSet {
+    /// Joins the finished tasks so a panicked one is logged and can be spawned again
+    fn reap(&mut self, room_id: RoomID) {
+        while let Some(result) = self.set.try_join_next() {
+            if let Err(err) = result
+                && err.is_panic()
+            {
+                error!("A task of room {room_id} panicked: {err}");
+            }
+        }
+
+        self.handles.retain(|_, handle| !handle.is_finished());
+    }
+}
+
+/// Registry of the room scoped tasks, owned by the RoomManager so that they're shut down along
+/// with their room
+///
+/// Dropping a room's JoinSet aborts its tasks, none of them can outlive the registry entry
+#[derive(Debug, Default)]
+pub struct RoomTasks {
+    rooms: HashMap<RoomID, RoomTaskSet>,
+}
+
+impl RoomTasks {
+    /// Spawns the task on the room tasks runtime unless one of the same kind is still running,
+    /// returns whether it was spawned
+    pub fn spawn<F>(&mut self, room_id: RoomID, kind: RoomTaskKind, future: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let tasks = self.rooms.entry(room_id).or_default();
+
+        tasks.reap(room_id);
+
+        if tasks.handles.contains_key(&kind) {
+            return false;
+        }
+
+        let span = info_span!(parent: None, "room_task", %room_id, ?kind);
+        let handle = tasks
+            .set
+            .spawn_on(future.instrument(span), ROOM_TASKS_RUNTIME.handle());
+
+        tasks.handles.insert(kind, handle);
+
+        true
+    }
+
+    pub fn is_running(&self, room_id: RoomID, kind: RoomTaskKind) -> bool {
+        self.rooms
+            .get(&room_id)
+            .and_then(|tasks| tasks.handles.get(&kind))
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Aborts the room task of this kind, returns whether it was running
+    pub fn abort(&mut self, room_id: RoomID, kind: RoomTaskKind) -> bool {
+        let Some(handle) = self
+            .rooms
+            .get_mut(&room_id)
+            .and_then(|tasks| tasks.handles.remove(&kind))
+        else {
+            return false;
+        };
+
+        handle.abort();
+
+        !handle.is_finished()
+    }
+
+    /// Aborts every task of the room, returns how many were still running
+    ///
+    /// A task shutting down its own room is aborted at its next await point
+    pub fn shutdown(&mut self, room_id: RoomID) -> usize {
+        let Some(mut tasks) = self.rooms.remove(&room_id) else {
+            return 0;
+        };
+
+        let running = tasks
+            .handles
+            .values()
+            .filter(|handle| !handle.is_finished())
+            .count();
+
+        tasks.set.abort_all();
+
+        if running > 0 {
+            debug!("{running} task(s) of room {room_id} shut down");
+        }
+
+        running
+    }
+
+    /// Reaps the finished tasks and shuts down those of the rooms that no longer exist, returns
+    /// how many orphan tasks were still running
+    pub fn sweep(&mut self, is_live: impl Fn(&RoomID) -> bool) -> usize {
+        let orphan_ids = self
+            .rooms
+            .keys()
+            .filter(|room_id| !is_live(room_id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        let orphan_tasks = orphan_ids
+            .into_iter()
+            .map(|room_id| self.shutdown(room_id))
+            .sum();
+
+        for (room_id, tasks) in self.rooms.iter_mut() {
+            tasks.reap(*room_id);
+        }
+
+        self.rooms.retain(|_, tasks| !tasks.set.is_empty());
+
+        orphan_tasks
+    }
+}
@@ -1,9 +1,8 @@
 use base64::Engine as _;
 use base64::prelude::BASE64_URL_SAFE;
-use rand::distr::Alphanumeric;
-use rand::{Rng, rng};
 use sha2::{Digest, Sha256};
 
+use super::random::RandomSource;
 use super::room::{MAX_EMAIL_CHAR, MIN_EMAIL_CHAR, RoomUserID};
 
 #[macro_export]
@@ -26,12 +25,11 @@ pub const SPOTIFY_FETCH_ALL: SpotifyFetchT = SPOTIFY_FETCH_PLAYBACK | SPOTIFY_FE
 pub const SPOTIFY_FETCH_PLAYBACK: SpotifyFetchT = 1 << 0;
 pub const SPOTIFY_FETCH_TRACKS_Q: SpotifyFetchT = 1 << 1;
 
-pub fn generate_code_verifier() -> String {
-    rng()
-        .sample_iter(&Alphanumeric)
-        .take(128)
-        .map(char::from)
-        .collect()
+/// Longest PKCE code verifier allowed
+pub const CODE_VERIFIER_LEN: usize = 128;
+
+pub fn generate_code_verifier(random: &dyn RandomSource) -> String {
+    random.alphanumeric(CODE_VERIFIER_LEN)
 }
 
 pub fn generate_code_challenge(code_verifier: String) -> String {
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{RwLock, broadcast};
+use uuid::Uuid;
+
+use super::room::{Room, RoomUserID, UserProfile};
+use super::room_events::{RoomEvent, spawn_subscriber};
+use super::room_manager::RoomManager;
+use super::tasks::spawn_room_task;
+
+/// Users waiting for a slot of a room, beyond that JoinRoom answers RoomFull
+pub const MAX_WAITLIST_LEN: usize = 50;
+/// Waiting users that haven't polled their ticket for this long lose their place, the admitted
+/// ones that never polled it are dropped (they're removed from the room by its join_ttl, if set)
+pub const WAITLIST_POLL_TTL: Duration = Duration::from_secs(60);
+
+/// User queued by a JoinRoom on the full room, see RoomSettings.waitlist
+#[derive(Clone, Debug)]
+pub struct WaitlistEntry {
+    /// Only known by the waiting user, it's polled with GET /{room_id}/waitlist/{ticket}
+    pub ticket: Uuid,
+    pub user_id: RoomUserID,
+    pub username: String,
+    pub profile: UserProfile,
+    pub polled_at: Instant,
+    /// Joined the room, kept until the user polls its ticket to get its session token
+    pub admitted: bool,
+}
+
+impl WaitlistEntry {
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.polled_at) >= WAITLIST_POLL_TTL
+    }
+}
+
+/// Outcome of RoomManager::poll_waitlist
+#[derive(Debug)]
+pub enum WaitlistStatus {
+    /// Starts at 1
+    Waiting {
+        position: usize,
+    },
+    Admitted {
+        room: Box<Room>,
+        user_id: RoomUserID,
+    },
+}
+
+/// Admits the waiting users as soon as a slot frees up (a member left or was removed, or the
+/// max_users setting was raised) instead of on their next poll
+pub fn init_waitlist_admissions(
+    events: broadcast::Receiver<RoomEvent>,
+    state_mgr: Arc<RwLock<RoomManager>>,
+) {
+    spawn_subscriber("waitlist", events, move |event| {
+        let (RoomEvent::UserLeft { room_id, .. }
+        | RoomEvent::UserRemoved { room_id, .. }
+        | RoomEvent::SettingsUpdated { room_id }) = event
+        else {
+            return;
+        };
+        let state_mgr = Arc::clone(&state_mgr);
+
+        spawn_room_task(async move {
+            let admitted = state_mgr.write().await.admit_waitlisted(room_id);
+
+            if !admitted.is_empty() {
+                debug!("[{room_id}] Admitted {} waitlisted users", admitted.len());
+            }
+        });
+    });
+}
@@ -1,14 +1,33 @@
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use tokio::sync::RwLock;
+use rand::rng;
+use rand::seq::IndexedRandom as _;
+use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
+use crate::config;
+use crate::proto::cmd::ExportSource;
 use crate::proto::cmd::command;
 use crate::proto::cmd::command_response;
-use crate::sharify::room::{RoomError, RoomID, RoomTrack, RoomUserID};
+use crate::proto::room::{RoomSettings, UserProfile};
+use crate::proto::spotify::RepeatMode;
+use crate::sharify::content_policy::ContentType;
+use crate::sharify::music_provider::{MusicProvider, ProviderKind};
+use crate::sharify::recommendation;
+use crate::sharify::role::RoleContent;
+use crate::sharify::room::{
+    DEFAULT_DJ_TURN_DURATION, LogFilter, LogType, MAX_DEDUP_REQUEST_IDS, RoomError, RoomID,
+    RoomTrack, RoomUserID, SURPRISE_ME_COOLDOWN, VolumeLimits, VolumeOutOfRange,
+};
+use crate::sharify::room_events::{RoleChange, RoomEvent};
 use crate::sharify::room_manager::RoomManager;
-use crate::sharify::spotify::Spotify;
+use crate::sharify::room_metadata::ParkedPlayback;
+use crate::sharify::signed_url::{self, DEFAULT_SIGNED_URL_TTL, MAX_SIGNED_URL_TTL, SigningSecret};
+use crate::sharify::spotify::web_utils::{PlaybackItemType, SpotifyTrack};
+use crate::sharify::spotify::{Spotify, SpotifyError, web_utils};
 use crate::sharify::utils::*;
 
 pub enum StateImpact {
@@ -18,6 +37,177 @@ pub enum StateImpact {
     Both(SpotifyFetchT),
 }
 
+/// How a command accesses the state, see Command::process
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandAccess {
+    /// Only reads the state under read locks (or from a snapshot)
+    Read,
+    /// Changes the room or player state, it goes through the room mutation queue
+    Write,
+}
+
+/// Commands sharing a rate limit, see CommandRateLimiter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CommandCategory {
+    /// Spotify reads (search, playlists, devices)
+    Search,
+    Playback,
+    Queue,
+    Other,
+}
+
+impl CommandCategory {
+    pub const ALL: [Self; 4] = [Self::Search, Self::Playback, Self::Queue, Self::Other];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Search => "search",
+            Self::Playback => "playback",
+            Self::Queue => "queue",
+            Self::Other => "other",
+        }
+    }
+
+    pub fn default_limit(&self) -> CommandRateLimit {
+        let (burst, per_min) = match self {
+            Self::Search => (5, 30),
+            Self::Playback => (5, 30),
+            Self::Queue => (5, 20),
+            Self::Other => (20, 120),
+        };
+
+        CommandRateLimit { burst, per_min }
+    }
+}
+
+/// Token bucket of `burst` tokens refilled with `per_min` tokens per minute, 0 disables it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandRateLimit {
+    pub burst: u32,
+    pub per_min: u32,
+}
+
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets of each user and CommandCategory, it lives in the room so a reconnection
+/// doesn't refill them
+#[derive(Clone, Debug, Default)]
+pub struct CommandRateLimiter {
+    buckets: HashMap<(RoomUserID, CommandCategory), TokenBucket>,
+}
+
+impl CommandRateLimiter {
+    /// Takes a token from the user's bucket, returns the time left before the next token when
+    /// it's empty
+    pub fn try_acquire(
+        &mut self,
+        user_id: &RoomUserID,
+        category: CommandCategory,
+        limit: CommandRateLimit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if limit.per_min == 0 {
+            return Ok(());
+        }
+
+        let capacity = limit.burst.max(1) as f64;
+        let per_sec = limit.per_min as f64 / 60.;
+        let bucket = self
+            .buckets
+            .entry((user_id.clone(), category))
+            .or_insert(TokenBucket {
+                tokens: capacity,
+                refilled_at: now,
+            });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_sec).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1. - bucket.tokens) / per_sec))
+    }
+
+    /// Drops the buckets of a user that left or was removed from the room
+    pub fn forget(&mut self, user_id: &RoomUserID) {
+        self.buckets.retain(|(id, _), _| id != user_id);
+    }
+}
+
+/// Outcome of a command, see Command::process
+pub type CommandResult = Result<Option<command_response::Type>, command_response::Type>;
+
+/// Replay of a command request_id, see CommandDedupCache::begin
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandReplay {
+    /// First time the request_id is seen, the command has to be run
+    New,
+    /// Still running, from another session of the user
+    InProgress,
+    Done(Box<CommandResult>),
+}
+
+/// Last commands request_ids of each user with their result so a command resent after a
+/// reconnection isn't run twice. Like CommandRateLimiter, it lives in the room so it outlives the
+/// WS sessions
+#[derive(Clone, Debug, Default)]
+pub struct CommandDedupCache {
+    requests: HashMap<RoomUserID, VecDeque<(String, Option<CommandResult>)>>,
+}
+
+impl CommandDedupCache {
+    /// Registers the request_id as in progress when it's new, the oldest ones of the user are
+    /// evicted beyond MAX_DEDUP_REQUEST_IDS
+    pub fn begin(&mut self, user_id: &RoomUserID, request_id: &str) -> CommandReplay {
+        let requests = self.requests.entry(user_id.clone()).or_default();
+
+        if let Some((_, result)) = requests.iter().find(|(id, _)| id == request_id) {
+            return match result {
+                Some(result) => CommandReplay::Done(Box::new(result.clone())),
+                None => CommandReplay::InProgress,
+            };
+        }
+
+        if requests.len() >= MAX_DEDUP_REQUEST_IDS {
+            requests.pop_front();
+        }
+
+        requests.push_back((request_id.to_owned(), None));
+
+        CommandReplay::New
+    }
+
+    /// Failed commands are forgotten so they can be resent as is (e.g. once rate limited)
+    pub fn complete(&mut self, user_id: &RoomUserID, request_id: &str, result: CommandResult) {
+        let Some(requests) = self.requests.get_mut(user_id) else {
+            return;
+        };
+        let Some(idx) = requests.iter().position(|(id, _)| id == request_id) else {
+            return;
+        };
+
+        if result.is_err() {
+            requests.remove(idx);
+        } else {
+            requests[idx].1 = Some(result);
+        }
+    }
+
+    /// Drops the request_ids of a user that left or was removed from the room
+    pub fn forget(&mut self, user_id: &RoomUserID) {
+        self.requests.remove(user_id);
+    }
+}
+
 #[async_trait]
 trait Commands {
     type T;
@@ -25,19 +215,56 @@ trait Commands {
 
     async fn get_room(self) -> Self::Output;
     async fn search(self, name: String) -> Self::Output;
+    async fn search_query(self, opts: command::SearchQuery) -> Self::Output;
     async fn add_to_queue(self, opts: command::AddTrackToQueue) -> Self::Output;
-    async fn set_volume(self, percentage: u8) -> Self::Output;
+    async fn set_volume(self, percentage: u32) -> Self::Output;
     async fn play_resume(self) -> Self::Output;
     async fn pause(self) -> Self::Output;
     async fn skip_next(self) -> Self::Output;
     async fn skip_previous(self) -> Self::Output;
     async fn seek_to_pos(self, pos: u64) -> Self::Output;
+    async fn vote_seek(self, offset_ms: i64) -> Self::Output;
     async fn kick(self, opts: command::Kick) -> Self::Output;
     async fn ban(self, opts: command::Ban) -> Self::Output;
     async fn leave_room(self) -> Self::Output;
     async fn create_role(self, opts: command::CreateRole) -> Self::Output;
     async fn rename_role(self, opts: command::RenameRole) -> Self::Output;
     async fn delete_role(self, id: Vec<u8>) -> Self::Output;
+    async fn set_role_display(self, opts: command::SetRoleDisplay) -> Self::Output;
+    async fn set_role_content(self, opts: command::SetRoleContent) -> Self::Output;
+    async fn surprise_me(self, opts: command::SurpriseMe) -> Self::Output;
+    async fn remove_queued_track(self, opts: command::RemoveQueuedTrack) -> Self::Output;
+    async fn replace_queued_track(self, opts: command::ReplaceQueuedTrack) -> Self::Output;
+    async fn get_logs(self, opts: command::GetLogs) -> Self::Output;
+    async fn get_stats(self) -> Self::Output;
+    async fn park_playback(self) -> Self::Output;
+    async fn resume_parked(self) -> Self::Output;
+    async fn list_devices(self) -> Self::Output;
+    async fn transfer_playback(self, device_id: String) -> Self::Output;
+    async fn create_signed_url(self, opts: command::CreateSignedUrl) -> Self::Output;
+    async fn rotate_signing_secret(self) -> Self::Output;
+    async fn list_playlists(self) -> Self::Output;
+    async fn get_playlist_tracks(self, playlist_id: String) -> Self::Output;
+    async fn queue_playlist(self, playlist_id: String) -> Self::Output;
+    async fn export_to_playlist(self, opts: command::ExportToPlaylist) -> Self::Output;
+    async fn set_shuffle(self, state: bool) -> Self::Output;
+    async fn set_repeat(self, mode: i32) -> Self::Output;
+    async fn update_room_settings(self, settings: RoomSettings) -> Self::Output;
+    async fn start_dj_rotation(self, turn_secs: u32) -> Self::Output;
+    async fn start_dj_mode(self, turn_tracks: u32) -> Self::Output;
+    async fn stop_dj_rotation(self) -> Self::Output;
+    async fn set_dj_rotation_opt_in(self, opt_in: bool) -> Self::Output;
+    async fn update_profile(self, profile: UserProfile) -> Self::Output;
+    async fn change_username(self, username: String) -> Self::Output;
+    async fn register_listener(self, opts: command::RegisterListener) -> Self::Output;
+    async fn unregister_listener(self) -> Self::Output;
+    async fn sync_listener(self) -> Self::Output;
+    async fn get_listener_token(self) -> Self::Output;
+    async fn regenerate_invite(self) -> Self::Output;
+    async fn get_spotify_status(self) -> Self::Output;
+    async fn kick_many(self, opts: command::KickMany) -> Self::Output;
+    async fn ban_many(self, opts: command::BanMany) -> Self::Output;
+    async fn transfer_ownership(self, opts: command::TransferOwnership) -> Self::Output;
 }
 
 pub struct Command {
@@ -74,12 +301,10 @@ impl Command {
     ///
     /// For DX (pattern matching) purposes, the StateImpact is also on the Err variant even if it
     /// has no real sense because the command shouldn't have affected any state
-    pub async fn process(
-        self,
-    ) -> (
-        Result<Option<command_response::Type>, command_response::Type>,
-        StateImpact,
-    ) {
+    ///
+    /// Read-only commands (see CommandAccess) only take read locks while the state-changing ones
+    /// wait for their turn in the room mutation queue
+    pub async fn process(self) -> (CommandResult, StateImpact) {
         if !self.has_permission_to().await {
             return (
                 Err(command_response::Type::RoomError(
@@ -89,54 +314,602 @@ impl Command {
             );
         }
 
-        let cmd_impact = self.get_cmd_impact();
+        if let Err(retry_after) = self.acquire_rate_limit_token().await {
+            return (
+                Err(command_response::Type::RateLimited(
+                    command_response::RateLimited {
+                        category: Self::get_cmd_category(&self.cmd_type).into(),
+                        retry_after_ms: retry_after.as_millis() as _,
+                    },
+                )),
+                StateImpact::Nothing,
+            );
+        }
 
-        (
-            match self.cmd_type.clone() {
-                command::Type::GetRoom(_) => self.get_room().await,
-                command::Type::Search(name) => self.search(name).await,
-                command::Type::AddToQueue(room_track) => self.add_to_queue(room_track).await,
-                command::Type::SetVolume(percentage) => self.set_volume(percentage as _).await,
-                command::Type::PlayResume(_) => self.play_resume().await,
-                command::Type::Pause(_) => self.pause().await,
-                command::Type::SkipNext(_) => self.skip_next().await,
-                command::Type::SkipPrevious(_) => self.skip_previous().await,
-                command::Type::SeekToPos(pos) => self.seek_to_pos(pos).await,
-                command::Type::Kick(opts) => self.kick(opts).await,
-                command::Type::Ban(opts) => self.ban(opts).await,
-                command::Type::LeaveRoom(_) => self.leave_room().await,
-                command::Type::CreateRole(opts) => self.create_role(opts).await,
-                command::Type::RenameRole(opts) => self.rename_role(opts).await,
-                command::Type::DeleteRole(id) => self.delete_role(id).await,
+        // Read-only commands skip the queue, they never wait behind the room mutations
+        let _mutation_permit = match Self::get_cmd_access(&self.cmd_type) {
+            CommandAccess::Read => None,
+            CommandAccess::Write => match self.get_mutation_queue().await {
+                Some(mutation_queue) => Some(mutation_queue.lock_owned().await),
+                // A missing room is handled by the command itself
+                None => None,
             },
-            cmd_impact,
-        )
+        };
+
+        let cmd_impact = self.get_cmd_impact();
+        let (sharify_state, room_id, user_id, cmd_type) = (
+            Arc::clone(&self.sharify_state),
+            self.room_id,
+            self.user_id.clone(),
+            self.cmd_type.clone(),
+        );
+
+        let result = match self.cmd_type.clone() {
+            command::Type::GetRoom(_) => self.get_room().await,
+            command::Type::Search(name) => self.search(name).await,
+            command::Type::SearchQuery(opts) => self.search_query(opts).await,
+            command::Type::AddToQueue(room_track) => self.add_to_queue(room_track).await,
+            command::Type::SetVolume(percentage) => self.set_volume(percentage).await,
+            command::Type::PlayResume(_) => self.play_resume().await,
+            command::Type::Pause(_) => self.pause().await,
+            command::Type::SkipNext(_) => self.skip_next().await,
+            command::Type::SkipPrevious(_) => self.skip_previous().await,
+            command::Type::SeekToPos(pos) => self.seek_to_pos(pos).await,
+            command::Type::VoteSeek(offset_ms) => self.vote_seek(offset_ms).await,
+            command::Type::Kick(opts) => self.kick(opts).await,
+            command::Type::Ban(opts) => self.ban(opts).await,
+            command::Type::KickMany(opts) => self.kick_many(opts).await,
+            command::Type::BanMany(opts) => self.ban_many(opts).await,
+            command::Type::LeaveRoom(_) => self.leave_room().await,
+            command::Type::TransferOwnership(opts) => self.transfer_ownership(opts).await,
+            command::Type::CreateRole(opts) => self.create_role(opts).await,
+            command::Type::RenameRole(opts) => self.rename_role(opts).await,
+            command::Type::DeleteRole(id) => self.delete_role(id).await,
+            command::Type::SetRoleDisplay(opts) => self.set_role_display(opts).await,
+            command::Type::SetRoleContent(opts) => self.set_role_content(opts).await,
+            command::Type::ParkPlayback(_) => self.park_playback().await,
+            command::Type::ResumeParked(_) => self.resume_parked().await,
+            command::Type::ListDevices(_) => self.list_devices().await,
+            command::Type::TransferPlayback(device_id) => self.transfer_playback(device_id).await,
+            command::Type::CreateSignedUrl(opts) => self.create_signed_url(opts).await,
+            command::Type::RotateSigningSecret(_) => self.rotate_signing_secret().await,
+            command::Type::ListPlaylists(_) => self.list_playlists().await,
+            command::Type::GetPlaylistTracks(id) => self.get_playlist_tracks(id).await,
+            command::Type::QueuePlaylist(id) => self.queue_playlist(id).await,
+            command::Type::ExportToPlaylist(opts) => self.export_to_playlist(opts).await,
+            command::Type::SetShuffle(state) => self.set_shuffle(state).await,
+            command::Type::SetRepeat(mode) => self.set_repeat(mode).await,
+            command::Type::UpdateRoomSettings(settings) => {
+                self.update_room_settings(settings).await
+            }
+            command::Type::StartDjRotation(turn_secs) => self.start_dj_rotation(turn_secs).await,
+            command::Type::StartDjMode(turn_tracks) => self.start_dj_mode(turn_tracks).await,
+            command::Type::StopDjRotation(_) => self.stop_dj_rotation().await,
+            command::Type::SetDjRotationOptIn(opt_in) => self.set_dj_rotation_opt_in(opt_in).await,
+            command::Type::UpdateProfile(profile) => self.update_profile(profile).await,
+            command::Type::ChangeUsername(username) => self.change_username(username).await,
+            command::Type::RegisterListener(opts) => self.register_listener(opts).await,
+            command::Type::UnregisterListener(_) => self.unregister_listener().await,
+            command::Type::SyncListener(_) => self.sync_listener().await,
+            command::Type::GetListenerToken(_) => self.get_listener_token().await,
+            command::Type::RegenerateInvite(_) => self.regenerate_invite().await,
+            command::Type::GetSpotifyStatus(_) => self.get_spotify_status().await,
+            command::Type::SurpriseMe(opts) => self.surprise_me(opts).await,
+            command::Type::RemoveQueuedTrack(opts) => self.remove_queued_track(opts).await,
+            command::Type::ReplaceQueuedTrack(opts) => self.replace_queued_track(opts).await,
+            command::Type::GetLogs(opts) => self.get_logs(opts).await,
+            command::Type::GetStats(_) => self.get_stats().await,
+        };
+
+        if let Ok(ref response) = result
+            && let Some((log_type, action)) = Self::get_cmd_log(&cmd_type, response)
+        {
+            let _ = sharify_state
+                .read()
+                .await
+                .append_user_log(room_id, &user_id, log_type, action);
+        }
+
+        (result, cmd_impact)
+    }
+
+    /// Proto field name of the command, carried by its tracing span
+    pub fn get_cmd_name(cmd_type: &command::Type) -> &'static str {
+        match cmd_type {
+            command::Type::GetRoom(_) => "get_room",
+            command::Type::Search(_) => "search",
+            command::Type::SearchQuery(_) => "search_query",
+            command::Type::AddToQueue(_) => "add_to_queue",
+            command::Type::SetVolume(_) => "set_volume",
+            command::Type::PlayResume(_) => "play_resume",
+            command::Type::Pause(_) => "pause",
+            command::Type::SkipNext(_) => "skip_next",
+            command::Type::SkipPrevious(_) => "skip_previous",
+            command::Type::SeekToPos(_) => "seek_to_pos",
+            command::Type::VoteSeek(_) => "vote_seek",
+            command::Type::Kick(_) => "kick",
+            command::Type::Ban(_) => "ban",
+            command::Type::KickMany(_) => "kick_many",
+            command::Type::BanMany(_) => "ban_many",
+            command::Type::LeaveRoom(_) => "leave_room",
+            command::Type::TransferOwnership(_) => "transfer_ownership",
+            command::Type::CreateRole(_) => "create_role",
+            command::Type::RenameRole(_) => "rename_role",
+            command::Type::DeleteRole(_) => "delete_role",
+            command::Type::SetRoleDisplay(_) => "set_role_display",
+            command::Type::SetRoleContent(_) => "set_role_content",
+            command::Type::ParkPlayback(_) => "park_playback",
+            command::Type::ResumeParked(_) => "resume_parked",
+            command::Type::ListDevices(_) => "list_devices",
+            command::Type::TransferPlayback(_) => "transfer_playback",
+            command::Type::CreateSignedUrl(_) => "create_signed_url",
+            command::Type::RotateSigningSecret(_) => "rotate_signing_secret",
+            command::Type::ListPlaylists(_) => "list_playlists",
+            command::Type::GetPlaylistTracks(_) => "get_playlist_tracks",
+            command::Type::QueuePlaylist(_) => "queue_playlist",
+            command::Type::ExportToPlaylist(_) => "export_to_playlist",
+            command::Type::SetShuffle(_) => "set_shuffle",
+            command::Type::SetRepeat(_) => "set_repeat",
+            command::Type::UpdateRoomSettings(_) => "update_room_settings",
+            command::Type::StartDjRotation(_) => "start_dj_rotation",
+            command::Type::StartDjMode(_) => "start_dj_mode",
+            command::Type::StopDjRotation(_) => "stop_dj_rotation",
+            command::Type::SetDjRotationOptIn(_) => "set_dj_rotation_opt_in",
+            command::Type::UpdateProfile(_) => "update_profile",
+            command::Type::ChangeUsername(_) => "change_username",
+            command::Type::RegisterListener(_) => "register_listener",
+            command::Type::UnregisterListener(_) => "unregister_listener",
+            command::Type::SyncListener(_) => "sync_listener",
+            command::Type::GetListenerToken(_) => "get_listener_token",
+            command::Type::RegenerateInvite(_) => "regenerate_invite",
+            command::Type::GetSpotifyStatus(_) => "get_spotify_status",
+            command::Type::SurpriseMe(_) => "surprise_me",
+            command::Type::RemoveQueuedTrack(_) => "remove_queued_track",
+            command::Type::ReplaceQueuedTrack(_) => "replace_queued_track",
+            command::Type::GetLogs(_) => "get_logs",
+            command::Type::GetStats(_) => "get_stats",
+        }
+    }
+
+    pub fn get_cmd_access(cmd_type: &command::Type) -> CommandAccess {
+        match cmd_type {
+            command::Type::GetRoom(_)
+            | command::Type::Search(_)
+            | command::Type::SearchQuery(_)
+            | command::Type::ListDevices(_)
+            | command::Type::ListPlaylists(_)
+            | command::Type::GetPlaylistTracks(_)
+            | command::Type::ExportToPlaylist(_)
+            | command::Type::CreateSignedUrl(_)
+            | command::Type::GetSpotifyStatus(_)
+            | command::Type::GetLogs(_)
+            | command::Type::GetStats(_) => CommandAccess::Read,
+            command::Type::AddToQueue(_)
+            | command::Type::SetVolume(_)
+            | command::Type::PlayResume(_)
+            | command::Type::Pause(_)
+            | command::Type::SkipNext(_)
+            | command::Type::SkipPrevious(_)
+            | command::Type::SeekToPos(_)
+            | command::Type::VoteSeek(_)
+            | command::Type::Kick(_)
+            | command::Type::Ban(_)
+            | command::Type::KickMany(_)
+            | command::Type::BanMany(_)
+            | command::Type::LeaveRoom(_)
+            | command::Type::TransferOwnership(_)
+            | command::Type::CreateRole(_)
+            | command::Type::RenameRole(_)
+            | command::Type::DeleteRole(_)
+            | command::Type::SetRoleDisplay(_)
+            | command::Type::SetRoleContent(_)
+            | command::Type::SurpriseMe(_)
+            | command::Type::RemoveQueuedTrack(_)
+            | command::Type::ReplaceQueuedTrack(_)
+            | command::Type::ParkPlayback(_)
+            | command::Type::ResumeParked(_)
+            | command::Type::TransferPlayback(_)
+            | command::Type::RotateSigningSecret(_)
+            | command::Type::QueuePlaylist(_)
+            | command::Type::SetShuffle(_)
+            | command::Type::SetRepeat(_)
+            | command::Type::UpdateRoomSettings(_)
+            | command::Type::StartDjRotation(_)
+            | command::Type::StartDjMode(_)
+            | command::Type::StopDjRotation(_)
+            | command::Type::SetDjRotationOptIn(_)
+            | command::Type::UpdateProfile(_)
+            | command::Type::ChangeUsername(_)
+            | command::Type::RegisterListener(_)
+            | command::Type::UnregisterListener(_)
+            | command::Type::SyncListener(_)
+            | command::Type::GetListenerToken(_)
+            | command::Type::RegenerateInvite(_) => CommandAccess::Write,
+        }
+    }
+
+    async fn get_mutation_queue(&self) -> Option<Arc<Mutex<()>>> {
+        self.sharify_state
+            .read()
+            .await
+            .get_room(&self.room_id)
+            .map(|room| Arc::clone(&room.mutation_queue))
+    }
+
+    fn get_cmd_category(cmd_type: &command::Type) -> CommandCategory {
+        match cmd_type {
+            command::Type::Search(_)
+            | command::Type::SearchQuery(_)
+            | command::Type::ListDevices(_)
+            | command::Type::ListPlaylists(_)
+            | command::Type::GetPlaylistTracks(_) => CommandCategory::Search,
+            command::Type::SetVolume(_)
+            | command::Type::PlayResume(_)
+            | command::Type::Pause(_)
+            | command::Type::SkipNext(_)
+            | command::Type::SkipPrevious(_)
+            | command::Type::SeekToPos(_)
+            | command::Type::VoteSeek(_)
+            | command::Type::ParkPlayback(_)
+            | command::Type::ResumeParked(_)
+            | command::Type::TransferPlayback(_)
+            | command::Type::QueuePlaylist(_)
+            | command::Type::SetShuffle(_)
+            | command::Type::SetRepeat(_)
+            | command::Type::SyncListener(_) => CommandCategory::Playback,
+            command::Type::AddToQueue(_)
+            | command::Type::SurpriseMe(_)
+            | command::Type::RemoveQueuedTrack(_)
+            | command::Type::ReplaceQueuedTrack(_) => CommandCategory::Queue,
+            command::Type::GetRoom(_)
+            | command::Type::Kick(_)
+            | command::Type::Ban(_)
+            | command::Type::KickMany(_)
+            | command::Type::BanMany(_)
+            | command::Type::LeaveRoom(_)
+            | command::Type::TransferOwnership(_)
+            | command::Type::CreateRole(_)
+            | command::Type::RenameRole(_)
+            | command::Type::DeleteRole(_)
+            | command::Type::SetRoleDisplay(_)
+            | command::Type::SetRoleContent(_)
+            | command::Type::ExportToPlaylist(_)
+            | command::Type::CreateSignedUrl(_)
+            | command::Type::RotateSigningSecret(_)
+            | command::Type::UpdateRoomSettings(_)
+            | command::Type::StartDjRotation(_)
+            | command::Type::StartDjMode(_)
+            | command::Type::StopDjRotation(_)
+            | command::Type::SetDjRotationOptIn(_)
+            | command::Type::UpdateProfile(_)
+            | command::Type::ChangeUsername(_)
+            | command::Type::RegisterListener(_)
+            | command::Type::UnregisterListener(_)
+            | command::Type::GetListenerToken(_)
+            | command::Type::RegenerateInvite(_)
+            | command::Type::GetSpotifyStatus(_)
+            | command::Type::GetLogs(_)
+            | command::Type::GetStats(_) => CommandCategory::Other,
+        }
+    }
+
+    /// Returns the time left before the next token when the user is rate limited
+    async fn acquire_rate_limit_token(&self) -> Result<(), Duration> {
+        let category = Self::get_cmd_category(&self.cmd_type);
+        let limit = crate::config::get()
+            .ws_command_limits
+            .get(&category)
+            .copied()
+            .unwrap_or(category.default_limit());
+
+        let (command_rate_limiter, now) = {
+            let guard = self.sharify_state.read().await;
+
+            // A missing room is handled by the command itself
+            let Some(room) = guard.get_room(&self.room_id) else {
+                return Ok(());
+            };
+
+            (Arc::clone(&room.command_rate_limiter), guard.clock().now())
+        };
+
+        command_rate_limiter
+            .lock()
+            .unwrap()
+            .try_acquire(&self.user_id, category, limit, now)
+    }
+
+    /// Audit log entry for a successful state-changing command. Kick(Many), Ban(Many),
+    /// LeaveRoom, TransferOwnership, ChangeUsername, AddToQueue and SurpriseMe are logged by the
+    /// RoomManager itself
+    fn get_cmd_log(
+        cmd_type: &command::Type,
+        response: &Option<command_response::Type>,
+    ) -> Option<(LogType, String)> {
+        Some(match cmd_type {
+            command::Type::GetRoom(_)
+            | command::Type::Search(_)
+            | command::Type::SearchQuery(_)
+            | command::Type::GetLogs(_)
+            | command::Type::GetStats(_)
+            | command::Type::ListDevices(_)
+            | command::Type::ListPlaylists(_)
+            | command::Type::GetPlaylistTracks(_)
+            | command::Type::GetSpotifyStatus(_)
+            | command::Type::RegisterListener(_)
+            | command::Type::UnregisterListener(_)
+            | command::Type::SyncListener(_)
+            | command::Type::GetListenerToken(_)
+            | command::Type::Kick(_)
+            | command::Type::Ban(_)
+            | command::Type::KickMany(_)
+            | command::Type::BanMany(_)
+            | command::Type::LeaveRoom(_)
+            | command::Type::TransferOwnership(_)
+            | command::Type::ChangeUsername(_)
+            | command::Type::AddToQueue(_)
+            | command::Type::SurpriseMe(_) => return None,
+            command::Type::RemoveQueuedTrack(opts) => (
+                LogType::RemoveTrack,
+                format!("removed track {} from queue", opts.track_id),
+            ),
+            command::Type::ReplaceQueuedTrack(opts) => (
+                LogType::RemoveTrack,
+                format!(
+                    "replaced queued track {} by \"{}\"",
+                    opts.track_id,
+                    opts.new_track
+                        .as_ref()
+                        .map(|t| t.track_name.as_str())
+                        .unwrap_or_default()
+                ),
+            ),
+            command::Type::SetVolume(percentage) => (
+                LogType::Playback,
+                format!("set the volume to {percentage}%"),
+            ),
+            command::Type::PlayResume(_) => (LogType::Playback, "resumed the playback".into()),
+            command::Type::Pause(_) => (LogType::Playback, "paused the playback".into()),
+            command::Type::ParkPlayback(_) => (LogType::Playback, "parked the playback".into()),
+            command::Type::ResumeParked(_) => {
+                (LogType::Playback, "resumed the parked playback".into())
+            }
+            command::Type::QueuePlaylist(id) => {
+                (LogType::AddTrack, format!("queued the playlist {id}"))
+            }
+            command::Type::SetShuffle(state) => (
+                LogType::Playback,
+                format!("turned {} shuffle", if *state { "on" } else { "off" }),
+            ),
+            command::Type::SetRepeat(mode) => (
+                LogType::Playback,
+                format!(
+                    "set repeat to {}",
+                    RepeatMode::try_from(*mode)
+                        .map(|mode| web_utils::RepeatMode::from(mode).as_str())
+                        .unwrap_or_default()
+                ),
+            ),
+            command::Type::TransferPlayback(_) => (
+                LogType::Playback,
+                "transferred the playback to another device".into(),
+            ),
+            command::Type::SkipNext(_) => (LogType::Playback, "skipped to the next track".into()),
+            command::Type::SkipPrevious(_) => {
+                (LogType::Playback, "skipped to the previous track".into())
+            }
+            command::Type::SeekToPos(pos) => (
+                LogType::Playback,
+                format!("seeked to {}:{:02}", pos / 1000 / 60, pos / 1000 % 60),
+            ),
+            command::Type::VoteSeek(offset_ms) => (
+                LogType::Playback,
+                format!(
+                    "voted to {} {}s{}",
+                    if *offset_ms > 0 { "skip" } else { "replay" },
+                    offset_ms.unsigned_abs() / 1000,
+                    match response {
+                        Some(command_response::Type::SeekVote(vote)) if vote.passed => {
+                            ", the seek passed"
+                        }
+                        _ => "",
+                    }
+                ),
+            ),
+            command::Type::CreateRole(opts) => (
+                LogType::RoleChange,
+                format!("created the role \"{}\"", opts.name),
+            ),
+            command::Type::RenameRole(opts) => (
+                LogType::RoleChange,
+                format!("renamed a role to \"{}\"", opts.name),
+            ),
+            command::Type::DeleteRole(_) => (LogType::RoleChange, "deleted a role".into()),
+            command::Type::CreateSignedUrl(_) => (LogType::Other, "created a spectator URL".into()),
+            command::Type::RotateSigningSecret(_) => {
+                (LogType::Other, "revoked every spectator URL".into())
+            }
+            command::Type::SetRoleDisplay(_) => {
+                (LogType::RoleChange, "changed the display of a role".into())
+            }
+            command::Type::SetRoleContent(_) => (
+                LogType::RoleChange,
+                "changed the content restrictions of a role".into(),
+            ),
+            command::Type::ExportToPlaylist(opts) => (
+                LogType::Other,
+                format!(
+                    "exported the room {} to the playlist \"{}\"",
+                    match opts.source() {
+                        ExportSource::History => "history",
+                        ExportSource::Queue => "queue",
+                    },
+                    opts.name
+                ),
+            ),
+            command::Type::StartDjRotation(turn_secs) => (
+                LogType::Other,
+                match turn_secs {
+                    0 => "started the DJ rotation".into(),
+                    secs => format!("started the DJ rotation with {secs}s turns"),
+                },
+            ),
+            command::Type::StartDjMode(turn_tracks) => (
+                LogType::Other,
+                format!("started the DJ mode ({turn_tracks} tracks per turn)"),
+            ),
+            command::Type::StopDjRotation(_) => (LogType::Other, "stopped the DJ rotation".into()),
+            command::Type::RegenerateInvite(_) => {
+                (LogType::Other, "regenerated the invite code".into())
+            }
+            command::Type::SetDjRotationOptIn(opt_in) => (
+                LogType::Other,
+                format!(
+                    "{} the DJ rotation",
+                    if *opt_in { "joined" } else { "left" }
+                ),
+            ),
+            command::Type::UpdateProfile(_) => (LogType::Other, "updated its profile".into()),
+            command::Type::UpdateRoomSettings(settings) => (
+                LogType::Other,
+                format!(
+                    "updated the room settings: {} users max, {} queued tracks max, deleted after {} min of inactivity, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}",
+                    settings.max_users,
+                    settings.max_tracks_queue_len,
+                    settings.inactivity_timeout_mins,
+                    if settings.is_public {
+                        "public"
+                    } else {
+                        "private"
+                    },
+                    match settings.idle_pause_mins {
+                        0 => "no idle pause".into(),
+                        mins => format!("paused after {mins} min without users"),
+                    },
+                    if settings.fair_queue {
+                        "fair queue"
+                    } else {
+                        "first come first served queue"
+                    },
+                    match settings.daily_digest {
+                        Some(digest) => format!(
+                            "daily digest at {}h (UTC{:+}min)",
+                            digest.hour, digest.utc_offset_mins
+                        ),
+                        None => "no daily digest".into(),
+                    },
+                    match settings.max_track_duration_secs {
+                        0 => "no track duration limit".into(),
+                        secs => format!("tracks up to {}min{:02}", secs / 60, secs % 60),
+                    },
+                    if settings.auto_promote_owner {
+                        "owner auto-promotion"
+                    } else {
+                        "server ownerless policy"
+                    },
+                    match settings.join_ttl_mins {
+                        0 => "no join TTL".into(),
+                        mins =>
+                            format!("members removed if not connected {mins} min after joining"),
+                    },
+                    if settings.waitlist {
+                        "waitlist when full"
+                    } else {
+                        "no waitlist"
+                    },
+                    if settings.allow_explicit.unwrap_or(true) {
+                        "explicit tracks allowed"
+                    } else {
+                        "no explicit tracks"
+                    },
+                    if settings.session_summary_webhook {
+                        "session summary sent to the webhook"
+                    } else {
+                        "no session summary webhook"
+                    },
+                    match settings.volume_limits {
+                        Some(limits) => format!(
+                            "volume from {}% to {}%",
+                            limits.min_volume, limits.max_volume
+                        ),
+                        None => "no volume limits".into(),
+                    }
+                ),
+            ),
+        })
     }
 
     fn get_cmd_impact(&self) -> StateImpact {
         match &self.cmd_type {
-            command::Type::GetRoom(_) | command::Type::Search(_) => StateImpact::Nothing,
+            command::Type::GetRoom(_)
+            | command::Type::Search(_)
+            | command::Type::SearchQuery(_)
+            | command::Type::GetLogs(_)
+            | command::Type::GetStats(_)
+            | command::Type::ListDevices(_)
+            | command::Type::ListPlaylists(_)
+            | command::Type::GetPlaylistTracks(_)
+            | command::Type::ExportToPlaylist(_)
+            | command::Type::CreateSignedUrl(_)
+            | command::Type::RotateSigningSecret(_)
+            | command::Type::GetSpotifyStatus(_)
+            | command::Type::RegisterListener(_)
+            | command::Type::UnregisterListener(_)
+            | command::Type::SyncListener(_)
+            | command::Type::GetListenerToken(_) => StateImpact::Nothing,
             command::Type::DeleteRole(_)
             | command::Type::CreateRole(_)
             | command::Type::RenameRole(_)
+            | command::Type::SetRoleDisplay(_)
+            | command::Type::SetRoleContent(_)
+            | command::Type::UpdateRoomSettings(_)
+            | command::Type::StartDjRotation(_)
+            | command::Type::StartDjMode(_)
+            | command::Type::StopDjRotation(_)
+            | command::Type::SetDjRotationOptIn(_)
+            | command::Type::UpdateProfile(_)
+            | command::Type::ChangeUsername(_)
+            | command::Type::RegenerateInvite(_)
             | command::Type::LeaveRoom(_)
+            | command::Type::TransferOwnership(_)
             | command::Type::Kick(_)
-            | command::Type::Ban(_) => StateImpact::Room,
+            | command::Type::Ban(_)
+            | command::Type::KickMany(_)
+            | command::Type::BanMany(_) => StateImpact::Room,
             command::Type::AddToQueue(_)
+            | command::Type::SurpriseMe(_)
+            | command::Type::RemoveQueuedTrack(_)
+            | command::Type::ReplaceQueuedTrack(_)
             | command::Type::SetVolume(_)
             | command::Type::PlayResume(_)
             | command::Type::Pause(_)
             | command::Type::SkipNext(_)
             | command::Type::SkipPrevious(_)
-            | command::Type::SeekToPos(_) => StateImpact::Both(match &self.cmd_type {
-                command::Type::AddToQueue(_) => SPOTIFY_FETCH_TRACKS_Q,
+            | command::Type::SeekToPos(_)
+            | command::Type::VoteSeek(_)
+            | command::Type::ParkPlayback(_)
+            | command::Type::ResumeParked(_)
+            | command::Type::TransferPlayback(_)
+            | command::Type::QueuePlaylist(_)
+            | command::Type::SetShuffle(_)
+            | command::Type::SetRepeat(_) => StateImpact::Both(match &self.cmd_type {
+                command::Type::AddToQueue(_)
+                | command::Type::SurpriseMe(_)
+                | command::Type::RemoveQueuedTrack(_)
+                | command::Type::ReplaceQueuedTrack(_) => SPOTIFY_FETCH_TRACKS_Q,
                 command::Type::SetVolume(_)
                 | command::Type::PlayResume(_)
                 | command::Type::Pause(_)
-                | command::Type::SeekToPos(_) => SPOTIFY_FETCH_PLAYBACK,
-                command::Type::SkipNext(_) | command::Type::SkipPrevious(_) => {
-                    SPOTIFY_FETCH_TRACKS_Q | SPOTIFY_FETCH_PLAYBACK
-                }
+                | command::Type::SeekToPos(_)
+                | command::Type::VoteSeek(_)
+                | command::Type::ParkPlayback(_)
+                | command::Type::TransferPlayback(_)
+                | command::Type::SetRepeat(_) => SPOTIFY_FETCH_PLAYBACK,
+                command::Type::SkipNext(_)
+                | command::Type::SkipPrevious(_)
+                | command::Type::ResumeParked(_)
+                | command::Type::QueuePlaylist(_)
+                | command::Type::SetShuffle(_) => SPOTIFY_FETCH_TRACKS_Q | SPOTIFY_FETCH_PLAYBACK,
                 _ => unreachable!(),
             }),
         }
@@ -144,6 +917,8 @@ impl Command {
 
     async fn has_permission_to(&self) -> bool {
         let guard = self.sharify_state.read().await;
+        // The current DJ of the rotation temporarily has the controls
+        let is_current_dj = guard.is_current_dj(self.room_id, &self.user_id);
         let Some(room) = guard.get_room(&self.room_id) else {
             return false;
         };
@@ -161,9 +936,20 @@ impl Command {
         };
 
         let perms = role.permissions;
+        // In DJ mode, the controls are reserved to the current DJ whatever the roles
+        let can_use_controls = match room.dj_rotation.as_ref() {
+            Some(rotation) if rotation.is_dj_mode() && rotation.current_dj().is_some() => {
+                is_current_dj
+            }
+            _ => perms.can_use_controls || is_current_dj,
+        };
 
-        if let command::Type::RenameRole(opts) = &self.cmd_type {
-            let Ok(role_id) = Uuid::from_slice(&opts.role_id[..16]) else {
+        if let command::Type::RenameRole(command::RenameRole { role_id, .. })
+        | command::Type::SetRoleDisplay(command::SetRoleDisplay { role_id, .. })
+        | command::Type::SetRoleContent(command::SetRoleContent { role_id, .. }) = &self.cmd_type
+        {
+            // Not sliced, a short role_id would panic
+            let Ok(role_id) = Uuid::from_slice(role_id) else {
                 return false;
             };
             let Some(target_role) = room.role_manager.get_role_by_id(&role_id) else {
@@ -175,25 +961,203 @@ impl Command {
             }
         }
 
+        drop(room);
         drop(guard);
 
         match self.cmd_type {
-            command::Type::GetRoom(_) | command::Type::LeaveRoom(_) => true,
-            command::Type::Search(_) | command::Type::AddToQueue(_) => perms.can_add_song,
+            // Ownership and grace period are checked by the RoomManager
+            command::Type::GetRoom(_)
+            | command::Type::GetStats(_)
+            | command::Type::LeaveRoom(_)
+            | command::Type::RemoveQueuedTrack(_)
+            | command::Type::SetDjRotationOptIn(_)
+            | command::Type::VoteSeek(_)
+            | command::Type::UpdateProfile(_)
+            | command::Type::ChangeUsername(_)
+            | command::Type::RegisterListener(_)
+            | command::Type::UnregisterListener(_)
+            | command::Type::SyncListener(_)
+            | command::Type::GetListenerToken(_) => true,
+            command::Type::Search(_)
+            | command::Type::SearchQuery(_)
+            | command::Type::AddToQueue(_)
+            | command::Type::SurpriseMe(_)
+            | command::Type::ReplaceQueuedTrack(_) => perms.can_add_song,
             command::Type::SetVolume(_)
             | command::Type::PlayResume(_)
             | command::Type::Pause(_)
             | command::Type::SkipNext(_)
             | command::Type::SkipPrevious(_)
-            | command::Type::SeekToPos(_) => perms.can_use_controls,
-            command::Type::Kick(_) | command::Type::Ban(_) => perms.can_manage_users,
+            | command::Type::SeekToPos(_)
+            | command::Type::ParkPlayback(_)
+            | command::Type::ResumeParked(_)
+            | command::Type::ListDevices(_)
+            | command::Type::TransferPlayback(_)
+            | command::Type::ListPlaylists(_)
+            | command::Type::GetPlaylistTracks(_)
+            | command::Type::QueuePlaylist(_)
+            | command::Type::SetShuffle(_)
+            | command::Type::SetRepeat(_) => can_use_controls,
+            command::Type::Kick(_)
+            | command::Type::Ban(_)
+            | command::Type::KickMany(_)
+            | command::Type::BanMany(_)
+            | command::Type::GetLogs(_) => perms.can_manage_users,
             command::Type::DeleteRole(_)
             | command::Type::CreateRole(_)
-            | command::Type::RenameRole(_) => perms.can_manage_users && perms.can_add_moderator,
+            | command::Type::RenameRole(_)
+            | command::Type::SetRoleDisplay(_)
+            | command::Type::SetRoleContent(_) => perms.can_manage_users && perms.can_add_moderator,
+            command::Type::ExportToPlaylist(_) => perms.can_add_moderator,
+            command::Type::CreateSignedUrl(_)
+            | command::Type::RotateSigningSecret(_)
+            | command::Type::UpdateRoomSettings(_)
+            | command::Type::StartDjRotation(_)
+            | command::Type::StartDjMode(_)
+            | command::Type::StopDjRotation(_)
+            | command::Type::RegenerateInvite(_)
+            | command::Type::GetSpotifyStatus(_)
+            | command::Type::TransferOwnership(_) => perms.can_manage_room,
+        }
+    }
+
+    /// Returns a warning when the track cannot be played in the host's market, Spotify would
+    /// silently skip it. The check is skipped when the market or the track cannot be fetched
+    async fn check_track_market(
+        &self,
+        spotify: Spotify,
+        market: Option<String>,
+        track_id: String,
+    ) -> Option<command_response::TrackUnavailableInMarket> {
+        // Local files have no markets
+        if track_id.starts_with("spotify:local:") {
+            return None;
+        }
+
+        let market = match market {
+            Some(market) => market,
+            None => {
+                let market = spotify.get_my_market().await.ok().flatten()?;
+
+                if let Some(mut room) = self.sharify_state.read().await.lock_room(&self.room_id) {
+                    room.market = Some(market.clone());
+                }
+
+                market
+            }
+        };
+
+        let markets = spotify.get_track_markets(track_id.clone()).await.ok()?;
+
+        if markets.contains(&market) {
+            return None;
+        }
+
+        Some(command_response::TrackUnavailableInMarket { track_id, market })
+    }
+
+    /// Access token of the author's own Spotify account, see RoomManager::listener_spotify
+    async fn listener_token(&self) -> Result<command_response::Type, command_response::Type> {
+        let spotify =
+            RoomManager::listener_spotify(&self.sharify_state, self.room_id, &self.user_id).await?;
+        let access_token = spotify
+            .tokens
+            .access_token
+            .reveal()
+            .map_err(SpotifyError::from)?;
+
+        Ok(command_response::Type::ListenerToken(
+            command_response::ListenerToken {
+                access_token,
+                expires_at: spotify
+                    .tokens
+                    .expires_at()
+                    .map(|expires_at| crate::proto::Timestamp {
+                        seconds: expires_at.timestamp(),
+                        nanos: expires_at.timestamp_subsec_nanos() as _,
+                    }),
+            },
+        ))
+    }
+
+    /// Content restrictions of the author's role
+    fn role_content(&self, guard: &RoomManager) -> Result<RoleContent, command_response::Type> {
+        let room = guard
+            .get_room(&self.room_id)
+            .ok_or(command_response::Type::RoomError(
+                RoomError::RoomNotFound.into(),
+            ))?;
+
+        room.users
+            .iter()
+            .find(|user| user.id == self.user_id)
+            .and_then(|user| room.role_manager.get_role_by_id(&user.role_id))
+            .map(|role| role.content)
+            .ok_or(command_response::Type::RoomError(
+                RoomError::RoomUserNotFound.into(),
+            ))
+    }
+
+    /// Enforces the server content policy, the host's market is only fetched when some markets
+    /// are denied and an unknown one is allowed
+    async fn check_content_policy(
+        &self,
+        content_types: &[ContentType],
+    ) -> Result<(), command_response::Type> {
+        let config = config::get();
+        let policy = &config.content_policy;
+
+        for content_type in content_types {
+            policy.check_type(*content_type)?;
+        }
+
+        if policy.denied_markets.is_empty() {
+            return Ok(());
         }
+
+        // The markets are Spotify ones
+        let Some((spotify, market)) = self.get_spotify_market().await? else {
+            return Ok(());
+        };
+
+        let market = match market {
+            Some(market) => Some(market),
+            None => {
+                let market = spotify.get_my_market().await.ok().flatten();
+
+                if let Some(market) = &market
+                    && let Some(mut room) = self.sharify_state.read().await.lock_room(&self.room_id)
+                {
+                    room.market = Some(market.clone());
+                }
+
+                market
+            }
+        };
+
+        policy.check_market(market.as_deref())?;
+
+        Ok(())
+    }
+
+    /// Spotify handler and known market of the room, None when it doesn't play from Spotify
+    async fn get_spotify_market(
+        &self,
+    ) -> Result<Option<(Spotify, Option<String>)>, command_response::Type> {
+        let guard = self.sharify_state.read().await;
+
+        let room = guard
+            .get_room(&self.room_id)
+            .ok_or(command_response::Type::RoomError(
+                RoomError::RoomNotFound.into(),
+            ))?;
+
+        Ok((room.provider == ProviderKind::Spotify)
+            .then(|| (room.spotify_handler.clone(), room.market.clone())))
     }
 
-    async fn get_spotify_handler(&self) -> Result<Spotify, command_response::Type> {
+    /// See Room::music_provider
+    async fn get_music_provider(&self) -> Result<Box<dyn MusicProvider>, command_response::Type> {
         let guard = self.sharify_state.read().await;
 
         let room = guard
@@ -202,7 +1166,37 @@ impl Command {
                 RoomError::RoomNotFound.into(),
             ))?;
 
-        Ok(room.spotify_handler.clone())
+        Ok(room.music_provider())
+    }
+
+    /// See RoomSettings::allows_track, the role content of the member applies too
+    fn is_queueable(
+        settings: &crate::sharify::room::RoomSettings,
+        role_content: &RoleContent,
+        track: &SpotifyTrack,
+    ) -> bool {
+        settings.allows_track(track)
+            && role_content
+                .check(
+                    &track.track_id,
+                    track.track_duration.max(0) as _,
+                    track.item_type,
+                )
+                .is_ok()
+    }
+
+    /// The item as the provider knows it, the duration and explicit flag sent by the client
+    /// are only displayed
+    async fn get_item(
+        &self,
+        track_id: &str,
+        item_type: PlaybackItemType,
+    ) -> Result<SpotifyTrack, command_response::Type> {
+        self.get_music_provider()
+            .await?
+            .get_item(track_id.to_owned(), item_type)
+            .await
+            .map_err(Into::into)
     }
 }
 
@@ -212,9 +1206,11 @@ impl Commands for Command {
     type Output = Result<Option<Self::T>, Self::T>;
 
     async fn get_room(self) -> Self::Output {
-        let guard = self.sharify_state.read().await;
-
-        let room = guard
+        // Converted from a snapshot so the read lock isn't held during the conversion
+        let room = self
+            .sharify_state
+            .read()
+            .await
             .get_room(&self.room_id)
             .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?
             .clone();
@@ -223,44 +1219,130 @@ impl Commands for Command {
     }
 
     async fn search(self, name: String) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        self.check_content_policy(&[ContentType::Track]).await?;
+
+        let provider = self.get_music_provider().await?;
 
-        let tracks = spotify
-            .search_track(name)
+        let mut tracks = provider
+            .search_tracks(name)
             .await
             .map_err(Into::<Self::T>::into)?;
 
+        config::get().content_policy.filter_tracks(&mut tracks);
+
         Ok(Some(Self::T::SpotifySearchResult(tracks.into())))
     }
 
+    async fn search_query(self, opts: command::SearchQuery) -> Self::Output {
+        let provider = self.get_music_provider().await?;
+
+        let types = opts
+            .types()
+            .map(Into::<web_utils::SearchType>::into)
+            .collect::<Vec<_>>();
+
+        let content_types = match types.as_slice() {
+            [] => vec![ContentType::Track],
+            types => types.iter().copied().map(Into::into).collect(),
+        };
+
+        self.check_content_policy(&content_types).await?;
+
+        let mut results = provider
+            .search(opts.query, &types, opts.offset, opts.limit)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        config::get()
+            .content_policy
+            .filter_tracks(&mut results.tracks);
+
+        Ok(Some(Self::T::SearchResults(results.into())))
+    }
+
     async fn add_to_queue(self, opts: command::AddTrackToQueue) -> Self::Output {
-        let mut guard = self.sharify_state.write().await;
+        let item_type = PlaybackItemType::from(opts.item_type());
 
-        let room = guard
-            .get_room_mut(&self.room_id)
-            .ok_or(command_response::Type::RoomError(
-                RoomError::RoomNotFound.into(),
-            ))?;
+        self.check_content_policy(&[ContentType::of_item(&opts.track_id, item_type)])
+            .await?;
 
-        room.tracks_queue.push_back(RoomTrack {
-            user_id: self.user_id,
-            track_id: opts.track_id.clone(),
-            track_name: opts.track_name,
-            track_duration: opts.track_duration,
-        });
+        let item = self.get_item(&opts.track_id, item_type).await?;
+
+        let guard = self.sharify_state.read().await;
+
+        {
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            room.settings
+                .check_track_duration(&opts.track_id, item.track_duration as _)?;
+            room.settings
+                .check_explicit(&opts.track_id, item.explicit)?;
+        }
+
+        self.role_content(&guard)?
+            .check(&opts.track_id, item.track_duration as _, item_type)?;
+
+        guard
+            .add_track_to_queue(
+                self.room_id,
+                self.user_id.clone(),
+                opts.track_id.clone(),
+                opts.track_name,
+                item.track_duration as _,
+                item_type,
+            )
+            .map_err(Into::<Self::T>::into)?;
+
+        drop(guard);
 
-        room.spotify_handler
-            .add_track_to_queue(opts.track_id)
+        RoomManager::push_queued_tracks(&self.sharify_state, self.room_id)
             .await
             .map_err(Into::<Self::T>::into)?;
 
-        Ok(None)
+        let Some((spotify, market)) = self.get_spotify_market().await? else {
+            return Ok(None);
+        };
+
+        Ok(self
+            .check_track_market(spotify, market, opts.track_id)
+            .await
+            .map(Self::T::TrackUnavailableInMarket))
     }
 
-    async fn set_volume(self, percentage: u8) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+    async fn set_volume(self, percentage: u32) -> Self::Output {
+        // Checked before narrowing, 356 would wrap to 100 otherwise
+        let percentage = u8::try_from(percentage)
+            .ok()
+            .filter(|percentage| *percentage <= VolumeLimits::FULL.max)
+            .ok_or(VolumeOutOfRange {
+                volume: percentage,
+                volume_limits: VolumeLimits::FULL,
+            })?;
+
+        {
+            let guard = self.sharify_state.read().await;
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            // The owners can override the limits
+            let can_manage_room = room
+                .users
+                .iter()
+                .find(|user| user.id == self.user_id)
+                .and_then(|user| room.role_manager.get_role_by_id(&user.role_id))
+                .is_some_and(|role| role.permissions.can_manage_room);
+
+            if !can_manage_room {
+                room.settings.check_volume(percentage)?;
+            }
+        }
 
-        spotify
+        let provider = self.get_music_provider().await?;
+
+        provider
             .set_volume(percentage)
             .await
             .map_err(Into::<Self::T>::into)?;
@@ -269,33 +1351,40 @@ impl Commands for Command {
     }
 
     async fn play_resume(self) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        let provider = self.get_music_provider().await?;
 
-        spotify.play_resume().await.map_err(Into::<Self::T>::into)?;
+        provider
+            .play_resume()
+            .await
+            .map_err(Into::<Self::T>::into)?;
 
         Ok(None)
     }
 
     async fn pause(self) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        let provider = self.get_music_provider().await?;
 
-        spotify.pause().await.map_err(Into::<Self::T>::into)?;
+        provider.pause().await.map_err(Into::<Self::T>::into)?;
 
         Ok(None)
     }
 
     async fn skip_next(self) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        let provider = self.get_music_provider().await?;
+
+        provider.skip_next().await.map_err(Into::<Self::T>::into)?;
 
-        spotify.skip_next().await.map_err(Into::<Self::T>::into)?;
+        if let Some(mut room) = self.sharify_state.read().await.lock_room(&self.room_id) {
+            room.listening_stats.record_skip(&self.user_id);
+        }
 
         Ok(None)
     }
 
     async fn skip_previous(self) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        let provider = self.get_music_provider().await?;
 
-        spotify
+        provider
             .skip_previous()
             .await
             .map_err(Into::<Self::T>::into)?;
@@ -304,9 +1393,9 @@ impl Commands for Command {
     }
 
     async fn seek_to_pos(self, pos: u64) -> Self::Output {
-        let spotify = self.get_spotify_handler().await?;
+        let provider = self.get_music_provider().await?;
 
-        spotify
+        provider
             .seek_to_ms(pos)
             .await
             .map_err(Into::<Self::T>::into)?;
@@ -314,6 +1403,26 @@ impl Commands for Command {
         Ok(None)
     }
 
+    async fn vote_seek(self, offset_ms: i64) -> Self::Output {
+        let status = self
+            .sharify_state
+            .read()
+            .await
+            .vote_seek(self.room_id, &self.user_id, offset_ms)
+            .map_err(Into::<Self::T>::into)?;
+
+        if let Some(seek_to_ms) = status.seek_to_ms {
+            let provider = self.get_music_provider().await?;
+
+            provider
+                .seek_to_ms(seek_to_ms)
+                .await
+                .map_err(Into::<Self::T>::into)?;
+        }
+
+        Ok(Some(Self::T::SeekVote(status.into())))
+    }
+
     async fn kick(self, opts: command::Kick) -> Self::Output {
         let mut guard = self.sharify_state.write().await;
 
@@ -334,70 +1443,876 @@ impl Commands for Command {
         Ok(None)
     }
 
-    async fn leave_room(self) -> Self::Output {
+    async fn kick_many(self, opts: command::KickMany) -> Self::Output {
         let mut guard = self.sharify_state.write().await;
 
-        guard
-            .leave_room(self.room_id, self.user_id)
+        let results = guard
+            .kick_users(self.room_id, &self.user_id, &opts.user_ids, opts.reason)
             .map_err(Into::<Self::T>::into)?;
 
-        Ok(None)
+        Ok(Some(Self::T::ModerationResults(results.into())))
     }
 
-    async fn create_role(self, opts: command::CreateRole) -> Self::Output {
+    async fn ban_many(self, opts: command::BanMany) -> Self::Output {
         let mut guard = self.sharify_state.write().await;
 
-        let room = guard
-            .get_room_mut(&self.room_id)
-            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
-
-        room.role_manager
-            .add_role(
-                opts.name,
-                opts.permissions
-                    .ok_or(Self::T::GenericError(
-                        "Permissions missing from request".into(),
-                    ))?
-                    .into(),
-            )
+        let results = guard
+            .ban_users(self.room_id, &self.user_id, &opts.user_ids, opts.reason)
             .map_err(Into::<Self::T>::into)?;
 
-        Ok(None)
+        Ok(Some(Self::T::ModerationResults(results.into())))
     }
 
-    async fn rename_role(self, opts: command::RenameRole) -> Self::Output {
+    async fn leave_room(self) -> Self::Output {
         let mut guard = self.sharify_state.write().await;
 
-        let room = guard
-            .get_room_mut(&self.room_id)
+        guard
+            .leave_room(self.room_id, self.user_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn transfer_ownership(self, opts: command::TransferOwnership) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .transfer_ownership(self.room_id, &self.user_id, &opts.user_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn create_role(self, opts: command::CreateRole) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        let role_id = room
+            .role_manager
+            .add_role(
+                opts.name,
+                opts.permissions
+                    .ok_or(Self::T::GenericError(
+                        "Permissions missing from request".into(),
+                    ))?
+                    .into(),
+                opts.display.map(Into::into).unwrap_or_default(),
+            )
+            .map_err(Into::<Self::T>::into)?;
+
+        guard.emit(RoomEvent::RoleUpdated {
+            room_id: self.room_id,
+            role_id,
+            change: RoleChange::Created,
+        });
+
+        Ok(None)
+    }
+
+    async fn rename_role(self, opts: command::RenameRole) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
             .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
 
-        let role_id = Uuid::from_slice(&opts.role_id[..16])
+        let role_id = Uuid::from_slice(&opts.role_id)
             .map_err(|err| Self::T::GenericError(format!("Failed to read role_id {err}")))?;
 
-        let role = room
+        let permissions = room
             .role_manager
             .get_role_by_id(&role_id)
-            .ok_or(Self::T::RoomError(RoomError::RoleNotFound.into()))?;
+            .ok_or(Self::T::RoomError(RoomError::RoleNotFound.into()))?
+            .permissions;
 
-        room.role_manager
-            .edit_role(role_id, opts.name, role.permissions);
+        room.role_manager.edit_role(role_id, opts.name, permissions);
+
+        guard.emit(RoomEvent::RoleUpdated {
+            room_id: self.room_id,
+            role_id,
+            change: RoleChange::Renamed,
+        });
 
         Ok(None)
     }
 
     async fn delete_role(self, id: Vec<u8>) -> Self::Output {
-        let mut guard = self.sharify_state.write().await;
+        let guard = self.sharify_state.read().await;
 
-        let room = guard
-            .get_room_mut(&self.room_id)
+        let mut room = guard
+            .lock_room(&self.room_id)
             .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
 
-        let role_id = Uuid::from_slice(&id[..16])
+        let role_id = Uuid::from_slice(&id)
             .map_err(|err| Self::T::GenericError(format!("Failed to read role_id {err}")))?;
 
         room.role_manager.delete_role(role_id);
 
+        guard.emit(RoomEvent::RoleUpdated {
+            room_id: self.room_id,
+            role_id,
+            change: RoleChange::Deleted,
+        });
+
+        Ok(None)
+    }
+
+    async fn set_role_display(self, opts: command::SetRoleDisplay) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        let role_id = Uuid::from_slice(&opts.role_id)
+            .map_err(|_| Self::T::RoomError(RoomError::RoleNotFound.into()))?;
+
+        room.role_manager
+            .get_role_by_id(&role_id)
+            .ok_or(Self::T::RoomError(RoomError::RoleNotFound.into()))?;
+
+        room.role_manager
+            .set_role_display(role_id, opts.display.map(Into::into).unwrap_or_default())
+            .map_err(Into::<Self::T>::into)?;
+
+        guard.emit(RoomEvent::RoleUpdated {
+            room_id: self.room_id,
+            role_id,
+            change: RoleChange::Display,
+        });
+
+        Ok(None)
+    }
+
+    async fn set_role_content(self, opts: command::SetRoleContent) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        let role_id = Uuid::from_slice(&opts.role_id)
+            .map_err(|_| Self::T::RoomError(RoomError::RoleNotFound.into()))?;
+
+        room.role_manager
+            .get_role_by_id(&role_id)
+            .ok_or(Self::T::RoomError(RoomError::RoleNotFound.into()))?;
+
+        room.role_manager
+            .set_role_content(role_id, opts.content.map(Into::into).unwrap_or_default());
+
+        guard.emit(RoomEvent::RoleUpdated {
+            room_id: self.room_id,
+            role_id,
+            change: RoleChange::Content,
+        });
+
+        Ok(None)
+    }
+
+    async fn park_playback(self) -> Self::Output {
+        let spotify = {
+            let guard = self.sharify_state.read().await;
+
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            if room.parked.is_some() {
+                return Err(Self::T::RoomError(RoomError::PlaybackAlreadyParked.into()));
+            }
+
+            room.spotify_handler.clone()
+        };
+
+        let state = spotify
+            .get_current_playback_state()
+            .await
+            .map_err(Into::<Self::T>::into)?
+            .ok_or(Self::T::GenericError("Nothing is playing".into()))?;
+
+        if state.is_playing {
+            spotify.pause().await.map_err(Into::<Self::T>::into)?;
+        }
+
+        let guard = self.sharify_state.read().await;
+        let now = guard.clock().now();
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        room.parked = Some(ParkedPlayback {
+            track_id: state.track_id,
+            item_type: state.item_type,
+            progress_ms: state.progress_ms.unwrap_or_default(),
+            was_playing: state.is_playing,
+            tracks_queue: room.tracks_queue.clone(),
+            parked_by: self.user_id.clone(),
+            parked_at: now,
+        });
+
+        guard.emit(RoomEvent::PlaybackParked {
+            room_id: self.room_id,
+            user_id: self.user_id,
+        });
+
+        Ok(None)
+    }
+
+    async fn resume_parked(self) -> Self::Output {
+        let (spotify, parked) = {
+            let guard = self.sharify_state.read().await;
+
+            let mut room = guard
+                .lock_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            let parked = room
+                .parked
+                .take()
+                .ok_or(Self::T::RoomError(RoomError::PlaybackNotParked.into()))?;
+
+            (room.spotify_handler.clone(), parked)
+        };
+
+        let restored = async {
+            let current_track_id = spotify
+                .get_current_playback_state()
+                .await?
+                .map(|state| state.track_id);
+
+            // Something else may have been played during the interruption
+            if current_track_id.as_ref() == Some(&parked.track_id) {
+                spotify.seek_to_ms(parked.progress_ms).await?;
+
+                if parked.was_playing {
+                    spotify.play_resume().await?;
+                }
+            } else {
+                spotify
+                    .play_track_at(
+                        parked.track_id.clone(),
+                        parked.item_type,
+                        parked.progress_ms,
+                    )
+                    .await?;
+
+                if !parked.was_playing {
+                    spotify.pause().await?;
+                }
+            }
+
+            Ok::<_, SpotifyError>(())
+        }
+        .await;
+
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        if let Err(err) = restored {
+            // Keep it parked so it can be resumed again
+            room.parked = Some(parked);
+
+            return Err(err.into());
+        }
+
+        // Tracks queued during the interruption are kept after the snapshot
+        let queued_while_parked = room
+            .tracks_queue
+            .drain(..)
+            .filter(|track| track.added_at > parked.parked_at);
+
+        let mut tracks_queue = parked.tracks_queue;
+        tracks_queue.extend(queued_while_parked);
+        room.tracks_queue = tracks_queue;
+
+        guard.emit(RoomEvent::PlaybackResumed {
+            room_id: self.room_id,
+        });
+
+        Ok(None)
+    }
+
+    async fn list_devices(self) -> Self::Output {
+        let provider = self.get_music_provider().await?;
+
+        let devices = provider.devices().await.map_err(Into::<Self::T>::into)?;
+
+        Ok(Some(Self::T::DeviceArray(devices.into())))
+    }
+
+    async fn transfer_playback(self, device_id: String) -> Self::Output {
+        let provider = self.get_music_provider().await?;
+
+        provider
+            .transfer_playback(device_id)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn create_signed_url(self, opts: command::CreateSignedUrl) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let room = guard
+            .get_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        let ttl = match opts.ttl_secs {
+            0 => DEFAULT_SIGNED_URL_TTL,
+            secs => Duration::from_secs(secs).min(MAX_SIGNED_URL_TTL),
+        };
+        let expires_at = guard.clock().utc_now() + ttl;
+        let signature = room
+            .signing_secret
+            .sign(self.room_id, expires_at.timestamp())
+            .map_err(|_| Self::T::GenericError("Failed to sign the URL".into()))?;
+
+        Ok(Some(Self::T::SignedUrl(command_response::SignedUrl {
+            path: signed_url::now_playing_path(self.room_id, expires_at.timestamp(), &signature),
+            events_path: signed_url::events_path(self.room_id, expires_at.timestamp(), &signature),
+            display_events_path: signed_url::display_events_path(
+                self.room_id,
+                expires_at.timestamp(),
+                &signature,
+            ),
+            expires_at: Some(crate::proto::Timestamp {
+                seconds: expires_at.timestamp(),
+                nanos: 0,
+            }),
+        })))
+    }
+
+    async fn rotate_signing_secret(self) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let mut room = guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        room.signing_secret = SigningSecret::default();
+
+        guard.emit(RoomEvent::SigningSecretRotated {
+            room_id: self.room_id,
+        });
+
+        Ok(None)
+    }
+
+    async fn list_playlists(self) -> Self::Output {
+        let provider = self.get_music_provider().await?;
+
+        let playlists = provider.playlists().await.map_err(Into::<Self::T>::into)?;
+
+        Ok(Some(Self::T::Playlists(playlists.into())))
+    }
+
+    async fn get_playlist_tracks(self, playlist_id: String) -> Self::Output {
+        self.check_content_policy(&[ContentType::Playlist]).await?;
+
+        let provider = self.get_music_provider().await?;
+
+        let mut tracks = provider
+            .playlist_tracks(playlist_id)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        config::get().content_policy.filter_tracks(&mut tracks);
+
+        Ok(Some(Self::T::PlaylistTracks(tracks.into())))
+    }
+
+    async fn queue_playlist(self, playlist_id: String) -> Self::Output {
+        self.check_content_policy(&[ContentType::Playlist]).await?;
+
+        let (provider, settings, role_content) = {
+            let guard = self.sharify_state.read().await;
+            let role_content = self.role_content(&guard)?;
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            (room.music_provider(), room.settings, role_content)
+        };
+
+        let mut tracks = provider
+            .playlist_tracks(playlist_id)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        config::get().content_policy.filter_tracks(&mut tracks);
+        // Local files only exist on the devices of the playlist owner
+        tracks.retain(|track| {
+            !track.track_id.starts_with("spotify:local:")
+                && Self::is_queueable(&settings, &role_content, track)
+        });
+
+        if tracks.is_empty() {
+            return Err(Self::T::GenericError(
+                "No track of the playlist is allowed in the room".into(),
+            ));
+        }
+
+        provider
+            .play_items(
+                tracks
+                    .into_iter()
+                    .map(|track| (track.track_id, track.item_type))
+                    .collect(),
+            )
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
         Ok(None)
     }
+
+    async fn export_to_playlist(self, opts: command::ExportToPlaylist) -> Self::Output {
+        let name = opts.name.trim().to_owned();
+
+        if name.is_empty() {
+            return Err(Self::T::GenericError("The playlist needs a name".into()));
+        }
+
+        let (spotify, room_name, tracks) = {
+            let guard = self.sharify_state.read().await;
+
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            (
+                room.spotify_handler.clone(),
+                room.name.clone(),
+                room.export_tracks(opts.source().into()),
+            )
+        };
+
+        if tracks.is_empty() {
+            return Err(Self::T::GenericError("No track to export".into()));
+        }
+
+        let mut playlist = spotify
+            .create_playlist(name, format!("Exported from the Sharify room {room_name}"))
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        playlist.tracks_count = tracks.len() as _;
+
+        spotify
+            .add_tracks_to_playlist(playlist.playlist_id.clone(), tracks)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(Some(Self::T::ExportedPlaylist(playlist.into())))
+    }
+
+    async fn set_shuffle(self, state: bool) -> Self::Output {
+        let provider = self.get_music_provider().await?;
+
+        provider
+            .set_shuffle(state)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn set_repeat(self, mode: i32) -> Self::Output {
+        let mode = RepeatMode::try_from(mode)
+            .map_err(|err| Self::T::GenericError(format!("Invalid repeat mode {err}")))?;
+
+        let provider = self.get_music_provider().await?;
+
+        provider
+            .set_repeat(mode.into())
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn update_room_settings(self, settings: RoomSettings) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .update_room_settings(self.room_id, &self.user_id, settings.into())
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn start_dj_rotation(self, turn_secs: u32) -> Self::Output {
+        let turn_duration = match turn_secs {
+            0 => DEFAULT_DJ_TURN_DURATION,
+            secs => Duration::from_secs(secs as _),
+        };
+
+        self.sharify_state
+            .read()
+            .await
+            .start_dj_rotation(self.room_id, turn_duration)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn start_dj_mode(self, turn_tracks: u32) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .start_dj_mode(self.room_id, turn_tracks)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn stop_dj_rotation(self) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .stop_dj_rotation(self.room_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn set_dj_rotation_opt_in(self, opt_in: bool) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .set_dj_rotation_opt_in(self.room_id, &self.user_id, opt_in)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn update_profile(self, profile: UserProfile) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .update_profile(self.room_id, &self.user_id, profile.into())
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn change_username(self, username: String) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .change_username(self.room_id, self.user_id.clone(), username)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn register_listener(self, opts: command::RegisterListener) -> Self::Output {
+        self.sharify_state
+            .write()
+            .await
+            .register_listener(
+                self.room_id,
+                &self.user_id,
+                &opts.spotify_grant,
+                opts.device_id,
+            )
+            .map_err(Into::<Self::T>::into)?;
+
+        let token = self.listener_token().await?;
+
+        // The SDK player may not be ready yet, it's synced again on the next playback fetch
+        if let Err(err) =
+            RoomManager::sync_listeners(&self.sharify_state, self.room_id, Some(&self.user_id))
+                .await
+        {
+            debug!(
+                "[{}] Failed to sync the new listener: {}",
+                self.room_id,
+                String::from(err)
+            );
+        }
+
+        Ok(Some(token))
+    }
+
+    async fn unregister_listener(self) -> Self::Output {
+        self.sharify_state
+            .read()
+            .await
+            .unregister_listener(self.room_id, &self.user_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn sync_listener(self) -> Self::Output {
+        RoomManager::sync_listeners(&self.sharify_state, self.room_id, Some(&self.user_id))
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn get_listener_token(self) -> Self::Output {
+        self.listener_token().await.map(Some)
+    }
+
+    async fn regenerate_invite(self) -> Self::Output {
+        self.sharify_state
+            .write()
+            .await
+            .regenerate_invite_code(self.room_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn get_spotify_status(self) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let room = guard
+            .get_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        Ok(Some(Self::T::SpotifyTokensStatus(
+            room.tokens_status(guard.clock().utc_now()).into(),
+        )))
+    }
+
+    async fn surprise_me(self, opts: command::SurpriseMe) -> Self::Output {
+        self.check_content_policy(&[ContentType::Track]).await?;
+
+        let (spotify, settings, role_content, seed, known_track_ids, previous_cooldown) = {
+            let guard = self.sharify_state.read().await;
+            let role_content = self.role_content(&guard)?;
+            let clock = guard.clock();
+
+            let mut room = guard
+                .lock_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            room.surprise_me_cooldowns
+                .retain(|_, instant| clock.elapsed_since(*instant) < SURPRISE_ME_COOLDOWN);
+
+            if let Some(elapsed) = room
+                .surprise_me_cooldowns
+                .get(&self.user_id)
+                .map(|instant| clock.elapsed_since(*instant))
+            {
+                return Err(Self::T::SurpriseMeCooldown(
+                    (SURPRISE_ME_COOLDOWN - elapsed).as_secs(),
+                ));
+            }
+
+            let seed = if opts.for_room {
+                let seeds = recommendation::taste_seeds(&room);
+
+                Some(
+                    recommendation::pick_seed(&seeds, &mut rng())
+                        .cloned()
+                        .ok_or(Self::T::GenericError(
+                            "No track queued by the connected members yet".into(),
+                        ))?,
+                )
+            } else {
+                None
+            };
+
+            // Reserved before the Spotify requests so the concurrent ones are rejected, it's
+            // given back when no track gets queued
+            let previous_cooldown = room
+                .surprise_me_cooldowns
+                .insert(self.user_id.clone(), clock.now());
+
+            (
+                room.spotify_handler.clone(),
+                room.settings,
+                role_content,
+                seed,
+                recommendation::known_track_ids(&room),
+                previous_cooldown,
+            )
+        };
+
+        let queued: Result<_, Self::T> = async {
+            let mut tracks = match (&seed, opts.seed_track_id) {
+                (Some(seed), _) => spotify.get_recommendations(seed.track_id.clone()).await,
+                (None, Some(seed_track_id)) => spotify.get_recommendations(seed_track_id).await,
+                (None, None) => spotify.get_top_tracks().await,
+            }
+            .map_err(Into::<Self::T>::into)?;
+
+            tracks.retain(|track| {
+                Self::is_queueable(&settings, &role_content, track)
+                    && (seed.is_none() || !known_track_ids.contains(&track.track_id))
+            });
+
+            let track = tracks
+                .choose(&mut rng())
+                .cloned()
+                .ok_or(Self::T::GenericError(
+                    "No track found to surprise you".into(),
+                ))?;
+
+            self.sharify_state
+                .read()
+                .await
+                .add_track_to_queue(
+                    self.room_id,
+                    self.user_id.clone(),
+                    track.track_id.clone(),
+                    track.track_name.clone(),
+                    track.track_duration as _,
+                    track.item_type,
+                )
+                .map_err(Into::<Self::T>::into)?;
+
+            Ok(track)
+        }
+        .await;
+
+        let track = match queued {
+            Ok(track) => track,
+            Err(err) => {
+                if let Some(mut room) = self.sharify_state.read().await.lock_room(&self.room_id) {
+                    match previous_cooldown {
+                        Some(instant) => {
+                            room.surprise_me_cooldowns
+                                .insert(self.user_id.clone(), instant);
+                        }
+                        None => {
+                            room.surprise_me_cooldowns.remove(&self.user_id);
+                        }
+                    }
+                }
+
+                return Err(err);
+            }
+        };
+
+        RoomManager::push_queued_tracks(&self.sharify_state, self.room_id)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(Some(match seed {
+            Some(seed) => Self::T::Recommendation(command_response::Recommendation {
+                track: Some(track.into()),
+                explanation: seed.explanation(),
+                seed_track_id: seed.track_id,
+                seed_user_id: seed.user_id,
+            }),
+            None => Self::T::SurpriseMeTrack(track.into()),
+        }))
+    }
+
+    async fn remove_queued_track(self, opts: command::RemoveQueuedTrack) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        guard
+            .remove_queued_track(self.room_id, &self.user_id, &opts.track_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn replace_queued_track(self, opts: command::ReplaceQueuedTrack) -> Self::Output {
+        let new_track = opts.new_track.ok_or(Self::T::GenericError(
+            "New track missing from request".into(),
+        ))?;
+
+        let item_type = PlaybackItemType::from(new_track.item_type());
+
+        self.check_content_policy(&[ContentType::of_item(&new_track.track_id, item_type)])
+            .await?;
+
+        let item = self.get_item(&new_track.track_id, item_type).await?;
+
+        let guard = self.sharify_state.read().await;
+        let now = guard.clock().now();
+
+        {
+            let room = guard
+                .get_room(&self.room_id)
+                .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+            room.settings
+                .check_track_duration(&new_track.track_id, item.track_duration as _)?;
+            room.settings
+                .check_explicit(&new_track.track_id, item.explicit)?;
+        }
+
+        self.role_content(&guard)?.check(
+            &new_track.track_id,
+            item.track_duration as _,
+            item_type,
+        )?;
+
+        guard
+            .remove_queued_track(self.room_id, &self.user_id, &opts.track_id)
+            .map_err(Into::<Self::T>::into)?;
+
+        guard
+            .lock_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?
+            .enqueue_track(RoomTrack {
+                user_id: self.user_id.clone(),
+                track_id: new_track.track_id.clone(),
+                track_name: new_track.track_name,
+                track_duration: item.track_duration as _,
+                item_type,
+                added_at: now,
+            });
+        guard.emit(RoomEvent::TrackQueued {
+            room_id: self.room_id,
+            user_id: self.user_id,
+            track_id: new_track.track_id,
+        });
+
+        drop(guard);
+
+        RoomManager::push_queued_tracks(&self.sharify_state, self.room_id)
+            .await
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(None)
+    }
+
+    async fn get_logs(self, opts: command::GetLogs) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let (logs, total) = guard
+            .get_logs(
+                self.room_id,
+                &LogFilter::from(&opts),
+                opts.offset as _,
+                opts.limit as _,
+            )
+            .map_err(Into::<Self::T>::into)?;
+
+        Ok(Some(Self::T::LogPage(command_response::LogPage {
+            logs: logs.into_iter().map(Into::into).collect(),
+            total: total as _,
+            offset: opts.offset,
+        })))
+    }
+
+    async fn get_stats(self) -> Self::Output {
+        let guard = self.sharify_state.read().await;
+
+        let room = guard
+            .get_room(&self.room_id)
+            .ok_or(Self::T::RoomError(RoomError::RoomNotFound.into()))?;
+
+        Ok(Some(Self::T::ListeningStats(
+            (&room.listening_stats).into(),
+        )))
+    }
 }
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::proto::cmd::{CommandResponse, command_response};
+use crate::proto::{room, spotify};
+use crate::sharify::room::{self as sharify_room, RoomID};
+use crate::sharify::room_events::{RoomEvent, spawn_subscriber};
+use crate::sharify::spotify::web_utils::{
+    PlaybackItemType, SpotifyCurrentPlaybackOutput, image_revision,
+};
+
+/// Comment line sent when nothing was broadcasted for this long so proxies keep the stream open
+pub const EVENTS_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+pub const MAX_EVENT_STREAMS_PER_ROOM: usize = 16;
+/// Events buffered for a slow client, the next ones are dropped until it catches up
+const EVENT_STREAM_BUFFER: usize = 32;
+
+/// Read-only SSE streams of each room, fed by SharifyWsInstance::send_in_room
+static EVENT_STREAMS: LazyLock<Mutex<HashMap<RoomID, Vec<EventStream>>>> =
+    LazyLock::new(Default::default);
+
+/// Shape of the events of a stream, every projection is fed by the same room broadcasts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventProjection {
+    /// JSON CommandResponse, see public_event
+    Full,
+    /// Flat now_playing and queue objects for the consumers that can't deal with the protobuf
+    /// schema (smart displays, kiosk screens...), see display_event
+    Display,
+}
+
+#[derive(Debug)]
+struct EventStream {
+    projection: EventProjection,
+    sender: mpsc::Sender<Bytes>,
+}
+
+/// None when the room already has MAX_EVENT_STREAMS_PER_ROOM streams
+pub fn subscribe(room_id: RoomID, projection: EventProjection) -> Option<mpsc::Receiver<Bytes>> {
+    let mut streams = EVENT_STREAMS.lock().unwrap();
+
+    // Streams whose clients went away are only cleaned up here
+    streams.retain(|_, senders| {
+        senders.retain(|stream| !stream.sender.is_closed());
+        !senders.is_empty()
+    });
+
+    let senders = streams.entry(room_id).or_default();
+
+    if senders.len() >= MAX_EVENT_STREAMS_PER_ROOM {
+        return None;
+    }
+
+    let (sender, rx) = mpsc::channel(EVENT_STREAM_BUFFER);
+
+    senders.push(EventStream { projection, sender });
+
+    Some(rx)
+}
+
+/// Forwards the CommandResponses broadcasted together in the room to its event streams, each
+/// one is its own event
+pub fn broadcast(room_id: RoomID, cmds: &[CommandResponse]) {
+    let mut streams = EVENT_STREAMS.lock().unwrap();
+
+    let Some(senders) = streams.get_mut(&room_id) else {
+        return;
+    };
+
+    for cmd in cmds {
+        // Each projection is only built if a stream needs it
+        let wants = |projection| senders.iter().any(|stream| stream.projection == projection);
+        let full_event = wants(EventProjection::Full)
+            .then(|| public_event(cmd.clone()))
+            .flatten()
+            .and_then(|(name, cmd)| sse_event(name, &cmd));
+        let display_event = wants(EventProjection::Display)
+            .then(|| display_event(cmd))
+            .flatten();
+
+        senders.retain(|stream| {
+            let event = match stream.projection {
+                EventProjection::Full => &full_event,
+                EventProjection::Display => &display_event,
+            };
+
+            let Some(event) = event else {
+                return !stream.sender.is_closed();
+            };
+
+            match stream.sender.try_send(event.clone()) {
+                Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => true,
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    if senders.is_empty() {
+        streams.remove(&room_id);
+    }
+}
+
+/// Ends the event streams of the room
+pub fn close_room(room_id: RoomID) {
+    EVENT_STREAMS.lock().unwrap().remove(&room_id);
+}
+
+/// Ends the event streams of the deleted rooms, closed by an owner or removed by the RoomManager
+/// (inactivity, last member left...), see RoomEvent::Deleted
+pub fn init_stream_cleanup(events: broadcast::Receiver<RoomEvent>) {
+    spawn_subscriber("event streams", events, |event| {
+        if let RoomEvent::Deleted { room_id } = event {
+            close_room(room_id);
+        }
+    });
+}
+
+/// "event: {name}" and the JSON payload as data
+pub fn sse_event<T: Serialize>(name: &str, payload: &T) -> Option<Bytes> {
+    match serde_json::to_string(payload) {
+        Ok(json) => Some(Bytes::from(format!("event: {name}\ndata: {json}\n\n"))),
+        Err(err) => {
+            error!("Failed to serialize a {name} event: {err}");
+
+            None
+        }
+    }
+}
+
+/// Keeps the room and playback updates only, without anything tied to the members since the
+/// stream has no RoomUser (user IDs are encoded emails)
+pub fn public_event(cmd: CommandResponse) -> Option<(&'static str, CommandResponse)> {
+    let (name, r#type) = match cmd.r#type? {
+        command_response::Type::Room(room) => {
+            ("room", command_response::Type::Room(public_room(room)))
+        }
+        command_response::Type::SpotifyAllState(state) => (
+            "spotify_all_state",
+            command_response::Type::SpotifyAllState(command_response::SpotifyAllState {
+                queue: anonymous_queue(state.queue),
+                ..state
+            }),
+        ),
+        r#type @ command_response::Type::SpotifyPlaybackState(_) => {
+            ("spotify_playback_state", r#type)
+        }
+        command_response::Type::SpotifyTracksState(state) => (
+            "spotify_tracks_state",
+            command_response::Type::SpotifyTracksState(command_response::SpotifyTracksState {
+                queue: anonymous_queue(state.queue),
+                ..state
+            }),
+        ),
+        r#type @ command_response::Type::TrackTransition(_) => ("track_transition", r#type),
+        r#type @ command_response::Type::AlbumImageChanged(_) => ("album_image_changed", r#type),
+        r#type @ command_response::Type::PlaybackTick(_) => ("playback_tick", r#type),
+        _ => return None,
+    };
+
+    Some((
+        name,
+        CommandResponse {
+            r#type: Some(r#type),
+            ..Default::default()
+        },
+    ))
+}
+
+fn public_room(room: room::Room) -> room::Room {
+    room::Room {
+        id: room.id,
+        name: room.name,
+        tracks_queue: room
+            .tracks_queue
+            .into_iter()
+            .map(|track| room::RoomTrack {
+                user_id: String::new(),
+                ..track
+            })
+            .collect(),
+        queue_edit_grace_secs: room.queue_edit_grace_secs,
+        settings: room.settings,
+        history: room.history,
+        ..Default::default()
+    }
+}
+
+fn anonymous_queue(
+    queue: Vec<command_response::QueuedTrack>,
+) -> Vec<command_response::QueuedTrack> {
+    queue
+        .into_iter()
+        .map(|queued| command_response::QueuedTrack {
+            track: queued.track,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Current playback of the Display projection and of the spectator now-playing endpoint
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NowPlaying {
+    pub track_id: String,
+    pub track_name: String,
+    pub artist_name: String,
+    pub album_image_src: String,
+    pub album_image_rev: String,
+    pub item_type: PlaybackItemType,
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub duration_ms: u64,
+}
+
+impl From<SpotifyCurrentPlaybackOutput> for NowPlaying {
+    fn from(playback: SpotifyCurrentPlaybackOutput) -> Self {
+        Self {
+            track_id: playback.track_id,
+            track_name: playback.track_name,
+            artist_name: playback.artist_name,
+            album_image_rev: image_revision(&playback.album_image_src),
+            album_image_src: playback.album_image_src,
+            item_type: playback.item_type,
+            is_playing: playback.is_playing,
+            progress_ms: playback.progress_ms,
+            duration_ms: playback.duration_ms,
+        }
+    }
+}
+
+impl From<spotify::PlaybackState> for NowPlaying {
+    fn from(state: spotify::PlaybackState) -> Self {
+        Self {
+            item_type: state.item_type().into(),
+            track_id: state.track_id,
+            track_name: state.track_name,
+            artist_name: state.artist_name,
+            album_image_src: state.album_image_src,
+            album_image_rev: state.album_image_rev,
+            is_playing: state.is_playing,
+            progress_ms: state.progress_ms,
+            duration_ms: state.duration_ms,
+        }
+    }
+}
+
+/// Track starting at the end of the current one (TrackTransition)
+impl From<spotify::Track> for NowPlaying {
+    fn from(track: spotify::Track) -> Self {
+        Self {
+            item_type: track.item_type().into(),
+            track_id: track.track_id,
+            track_name: track.track_name,
+            artist_name: track.artist_name,
+            album_image_src: track.album_image_src,
+            album_image_rev: track.album_image_rev,
+            is_playing: true,
+            progress_ms: Some(0),
+            duration_ms: track.track_duration as _,
+        }
+    }
+}
+
+/// Room queue of the Display projection, without the submitters
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueuedTrack {
+    pub track_id: String,
+    pub track_name: String,
+    pub duration_ms: u32,
+}
+
+impl From<room::RoomTrack> for QueuedTrack {
+    fn from(track: room::RoomTrack) -> Self {
+        Self {
+            track_id: track.track_id,
+            track_name: track.track_name,
+            duration_ms: track.track_duration,
+        }
+    }
+}
+
+impl From<&sharify_room::RoomTrack> for QueuedTrack {
+    fn from(track: &sharify_room::RoomTrack) -> Self {
+        Self {
+            track_id: track.track_id.clone(),
+            track_name: track.track_name.clone(),
+            duration_ms: track.track_duration,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Queue<'a> {
+    tracks: &'a [QueuedTrack],
+}
+
+/// "now_playing" event, null data when nothing is playing
+pub fn now_playing_event(now_playing: Option<NowPlaying>) -> Option<Bytes> {
+    sse_event("now_playing", &now_playing)
+}
+
+/// "queue" event with the room queue as {"tracks": [...]}
+pub fn queue_event(tracks: &[QueuedTrack]) -> Option<Bytes> {
+    sse_event("queue", &Queue { tracks })
+}
+
+/// Display projection of a room broadcast, None for the ones it doesn't carry
+pub fn display_event(cmd: &CommandResponse) -> Option<Bytes> {
+    match cmd.r#type.as_ref()? {
+        command_response::Type::Room(room) => queue_event(
+            &room
+                .tracks_queue
+                .iter()
+                .cloned()
+                .map(Into::into)
+                .collect::<Vec<_>>(),
+        ),
+        command_response::Type::SpotifyAllState(command_response::SpotifyAllState {
+            state,
+            ..
+        })
+        | command_response::Type::SpotifyPlaybackState(command_response::SpotifyPlaybackState {
+            state,
+        }) => now_playing_event(state.clone().map(Into::into)),
+        command_response::Type::TrackTransition(transition) => {
+            now_playing_event(transition.starting.clone().map(Into::into))
+        }
+        _ => None,
+    }
+}
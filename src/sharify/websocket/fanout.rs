@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use actix_web::web::Bytes;
+use prost::Message as _;
+use tokio::sync::broadcast;
+
+use super::instance::{SessionEncoding, SessionFrame};
+use crate::proto::cmd::{CommandResponse, command_response};
+use crate::proto::room;
+use crate::sharify::room::{QueuePosition, RoomID, RoomUserID};
+
+/// Frames buffered for a slow session, it lags past that and gets the room state replayed
+pub const ROOM_CHANNEL_CAPACITY: usize = 64;
+
+/// Broadcast channel of each room, every WS session of the room is subscribed to it and sends
+/// the frames from its own loop so that a slow client only delays itself
+static ROOM_CHANNELS: LazyLock<Mutex<HashMap<RoomID, broadcast::Sender<RoomFrame>>>> =
+    LazyLock::new(Default::default);
+
+/// CommandResponse(s) sent in a room
+#[derive(Clone, Debug)]
+pub enum RoomFrame {
+    Broadcast {
+        update: Arc<RoomUpdate>,
+        /// Member whose session skips the frame, e.g. the one that triggered it
+        except: Option<RoomUserID>,
+    },
+    /// Protobuf encoded responses of their own for some of the members, sent as one frame so
+    /// that they're ordered with the broadcasts
+    PerUser(Arc<HashMap<RoomUserID, Vec<Bytes>>>),
+}
+
+impl RoomFrame {
+    pub fn frames_for(
+        &self,
+        user_id: &RoomUserID,
+        encoding: SessionEncoding,
+    ) -> Arc<[SessionFrame]> {
+        match self {
+            Self::Broadcast { update, except } if except.as_ref() != Some(user_id) => {
+                update.frames_for(encoding)
+            }
+            Self::Broadcast { .. } => Arc::new([]),
+            Self::PerUser(payloads) => payloads
+                .get(user_id)
+                .into_iter()
+                .flatten()
+                .filter_map(|buf| encode(encoding, buf.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Responses broadcasted together, they're encoded once for each SessionEncoding of the room
+/// sessions (the first session of an encoding does it for the others)
+#[derive(Debug, Default)]
+pub struct RoomUpdate {
+    responses: Vec<CommandResponse>,
+    /// Sent instead of the Room of the responses to the sessions that negotiated it
+    room_delta: Option<command_response::RoomDelta>,
+    /// Sent to their submitters right after the responses, see Room::take_queue_position_changes
+    queue_positions: Vec<(RoomUserID, QueuePosition)>,
+    frames: Mutex<HashMap<SessionEncoding, Arc<[SessionFrame]>>>,
+}
+
+impl RoomUpdate {
+    pub fn new(responses: Vec<CommandResponse>) -> Self {
+        Self {
+            responses,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, cmd: CommandResponse) {
+        self.responses.push(cmd);
+    }
+
+    /// See Room::take_room_update
+    pub fn push_room(&mut self, room: room::Room, room_delta: Option<command_response::RoomDelta>) {
+        self.responses.push(CommandResponse {
+            r#type: Some(command_response::Type::Room(room)),
+            ..Default::default()
+        });
+        self.room_delta = room_delta;
+    }
+
+    pub fn push_queue_positions(&mut self, positions: Vec<(RoomUserID, QueuePosition)>) {
+        self.queue_positions.extend(positions);
+    }
+
+    /// Per-user frame of the queue positions, None when none moved
+    pub fn take_queue_positions(&mut self) -> Option<RoomFrame> {
+        if self.queue_positions.is_empty() {
+            return None;
+        }
+
+        let mut payloads = HashMap::<RoomUserID, Vec<Bytes>>::new();
+
+        for (user_id, position) in std::mem::take(&mut self.queue_positions) {
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::YourTrackPosition(position.into())),
+                ..Default::default()
+            };
+
+            payloads
+                .entry(user_id)
+                .or_default()
+                .push(cmd.encode_to_vec().into());
+        }
+
+        Some(RoomFrame::PerUser(Arc::new(payloads)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// Without the RoomDelta, e.g. for the event streams that can't apply it
+    pub fn responses(&self) -> &[CommandResponse] {
+        &self.responses
+    }
+
+    pub fn frames_for(&self, encoding: SessionEncoding) -> Arc<[SessionFrame]> {
+        let mut frames = self.frames.lock().unwrap();
+
+        Arc::clone(frames.entry(encoding).or_insert_with(|| {
+            encoding
+                .negotiate(self.responses.clone(), self.room_delta.as_ref())
+                .into_iter()
+                .filter_map(|cmd| encode(encoding, cmd.encode_to_vec().into()))
+                .collect()
+        }))
+    }
+}
+
+fn encode(encoding: SessionEncoding, buf: Bytes) -> Option<SessionFrame> {
+    encoding
+        .encode(buf)
+        .inspect_err(|err| error!("Failed to encode a room frame: {err}"))
+        .ok()
+}
+
+pub fn subscribe(room_id: RoomID) -> broadcast::Receiver<RoomFrame> {
+    let mut channels = ROOM_CHANNELS.lock().unwrap();
+
+    // Channels of the rooms deleted without close_room are only cleaned up here
+    channels.retain(|id, sender| *id == room_id || sender.receiver_count() > 0);
+
+    channels
+        .entry(room_id)
+        .or_insert_with(|| broadcast::channel(ROOM_CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// Returns to how many sessions the frame was queued
+pub fn send(room_id: RoomID, frame: RoomFrame) -> usize {
+    ROOM_CHANNELS
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .and_then(|sender| sender.send(frame).ok())
+        .unwrap_or_default()
+}
+
+/// Drops the room channel, its subscribers are left with the frames already queued
+pub fn close_room(room_id: RoomID) {
+    ROOM_CHANNELS.lock().unwrap().remove(&room_id);
+}
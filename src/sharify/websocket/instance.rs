@@ -1,35 +1,206 @@
 use std::collections::HashMap;
+use std::io::Write as _;
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use actix_rt::time;
-use actix_web::web::{self, Bytes};
+use actix_web::web;
 use actix_web::{HttpRequest, HttpResponse, Responder};
-use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Session};
-use chrono::TimeDelta;
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseReason, MessageStream, Session};
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
 use prost::Message as _;
-use tokio::sync::{Mutex, RwLock, mpsc};
-
-use super::commands::{Command as WSCmd, StateImpact};
+use serde::Deserialize;
+use tokio::sync::{Mutex, RwLock, broadcast, mpsc};
+use tracing::Instrument as _;
+use uuid::Uuid;
+
+use super::commands::{Command as WSCmd, CommandReplay, CommandResult, StateImpact};
+use super::events;
+use super::fanout::{self, RoomFrame, RoomUpdate};
 use crate::match_flags;
-use crate::proto::cmd::{Command, CommandResponse, command, command_response};
-use crate::sharify::room::{INACTIVE_ROOM_MINS, RoomError, RoomID, RoomUserID};
+use crate::proto::WireFormat;
+use crate::proto::cmd::{Command, CommandResponse, WsCloseCode, command, command_response};
+use crate::routes::CorrelationId;
+use crate::sharify::clock::SharedClock;
+use crate::sharify::identity;
+use crate::sharify::music_provider::{MusicProvider, ProviderError};
+use crate::sharify::room::{
+    MAX_REQUEST_ID_LEN, Room, RoomError, RoomID, RoomOwnership, RoomUserID,
+};
+use crate::sharify::room_events::{RoomEvent, spawn_subscriber};
 use crate::sharify::room_manager::RoomManager;
-use crate::sharify::spotify::{self, SpotifyError};
+use crate::sharify::spotify;
+use crate::sharify::spotify::web_utils::{
+    SpotifyCurrentPlaybackOutput, SpotifyTackArray, image_revision,
+};
+use crate::sharify::tasks::{RoomTaskKind, spawn_room_task};
 use crate::sharify::utils::*;
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// 2 times the HEARTBEAT_INTERVAL because we handle HB and Messages on the same loop and a message
 ///   has priority so if the HB is skipped once, it's safe but its unlikley be a problem
-const USER_WS_TIMEOUT: Duration = Duration::from_secs(HEARTBEAT_INTERVAL.as_secs() * 2);
+pub(crate) const USER_WS_TIMEOUT: Duration = Duration::from_secs(HEARTBEAT_INTERVAL.as_secs() * 2);
+
+/// Period of the PlaybackTick broadcasts, from the Spotify data loop
+pub(crate) const PLAYBACK_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds of WsTransport.max_frame_size
+pub(crate) const MIN_WS_FRAME_SIZE: usize = 1024;
+pub(crate) const MAX_WS_FRAME_SIZE: usize = 1024 * 1024 * 16;
+
+/// Limits of the messages sent by the clients, they're told in their SessionResume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WsTransport {
+    pub max_frame_size: usize,
+    pub max_continuation_size: usize,
+    /// Messages split into continuation frames are rejected when false
+    pub aggregate_continuations: bool,
+}
+
+impl Default for WsTransport {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 1024 * 128,
+            max_continuation_size: 1024 * 1024,
+            aggregate_continuations: true,
+        }
+    }
+}
+
+impl WsTransport {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_WS_FRAME_SIZE..=MAX_WS_FRAME_SIZE).contains(&self.max_frame_size) {
+            return Err(format!(
+                "WS_MAX_FRAME_SIZE must be between {MIN_WS_FRAME_SIZE} and {MAX_WS_FRAME_SIZE} bytes"
+            ));
+        }
+
+        if self.aggregate_continuations && self.max_continuation_size < self.max_frame_size {
+            return Err("WS_MAX_CONTINUATION_SIZE cannot be lower than WS_MAX_FRAME_SIZE".into());
+        }
+
+        if self.max_continuation_size > u32::MAX as usize {
+            return Err(format!(
+                "WS_MAX_CONTINUATION_SIZE cannot exceed {} bytes",
+                u32::MAX
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn apply(&self, stream: MessageStream) -> AggregatedMessageStream {
+        stream
+            .max_frame_size(self.max_frame_size)
+            .aggregate_continuations()
+            // A continuation frame can't fit in 0 bytes
+            .max_continuation_size(match self.aggregate_continuations {
+                true => self.max_continuation_size,
+                false => 0,
+            })
+    }
+}
+
+/// Encoding of the frames sent to a session, negotiated by the query of the WS init
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SessionEncoding {
+    pub format: WireFormat,
+    /// The frames following the SessionResume are raw DEFLATE compressed (always binary), the
+    /// browsers inflate them with DecompressionStream("deflate-raw")
+    ///
+    /// actix-ws can't negotiate permessage-deflate on the handshake, hence the compression of
+    /// the payloads themselves
+    pub deflate: bool,
+    /// The responses broadcasted together are sent as a single Batch, one by one otherwise
+    pub batch: bool,
+    /// The room updates are sent as RoomDelta when smaller than the full Room
+    pub room_delta: bool,
+}
+
+/// Frame of a session, see SessionEncoding::encode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionFrame {
+    Binary(web::Bytes),
+    Text(String),
+}
+
+impl SessionEncoding {
+    /// fmt, compress and features (comma separated) query params of the WS URL
+    pub fn from_query(fmt: Option<&str>, compress: Option<&str>, features: Option<&str>) -> Self {
+        let features = features.unwrap_or_default().split(',').collect::<Vec<_>>();
+
+        Self {
+            format: WireFormat::from_query(fmt),
+            deflate: compress == Some("deflate"),
+            batch: features.contains(&"batch"),
+            room_delta: features.contains(&"room_delta"),
+        }
+    }
+
+    /// The responses sent together, as the session negotiated them. The room_delta replaces
+    /// the Room of the responses
+    pub fn negotiate(
+        &self,
+        mut responses: Vec<CommandResponse>,
+        room_delta: Option<&command_response::RoomDelta>,
+    ) -> Vec<CommandResponse> {
+        if let (true, Some(room_delta)) = (self.room_delta, room_delta) {
+            for cmd in responses.iter_mut() {
+                if let Some(command_response::Type::Room(_)) = cmd.r#type {
+                    cmd.r#type = Some(command_response::Type::RoomDelta(room_delta.clone()));
+                }
+            }
+        }
+
+        match self.batch {
+            true => CommandResponse::batch(responses).into_iter().collect(),
+            false => responses,
+        }
+    }
+
+    /// Converts the protobuf encoded CommandResponse to the frame sent to the session
+    pub fn encode(&self, buf: web::Bytes) -> Result<SessionFrame, String> {
+        let frame = match self.format {
+            WireFormat::Protobuf => SessionFrame::Binary(buf),
+            WireFormat::Json => {
+                SessionFrame::Text(WireFormat::protobuf_to_json::<CommandResponse>(buf)?)
+            }
+        };
+
+        if !self.deflate {
+            return Ok(frame);
+        }
+
+        let payload = match &frame {
+            SessionFrame::Binary(bytes) => bytes.as_ref(),
+            SessionFrame::Text(text) => text.as_bytes(),
+        };
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+
+        encoder
+            .write_all(payload)
+            .and_then(|_| encoder.finish())
+            .map(|deflated| SessionFrame::Binary(deflated.into()))
+            .map_err(|err| err.to_string())
+    }
+}
 
 pub struct SharifyWsInstance {
+    /// Tells apart the sessions of the same user when it reconnects (takeover)
+    instance_id: Uuid,
+    /// Set by the CreateRoom/JoinRoom request of the user, prefixes the logs of the session
+    correlation_id: String,
     session: Session,
     room_id: RoomID,
     hb: Arc<Mutex<Instant>>,
+    clock: SharedClock,
     // This is true when the Client responded at the first ping
     // sent so the instance can recieve its initial data
     is_ready: bool,
+    /// Format and compression of the messages sent to the client
+    encoding: SessionEncoding,
 
     ws_mgr: Arc<RwLock<SharifyWsManager>>,
     state_mgr: Arc<RwLock<RoomManager>>,
@@ -39,6 +210,7 @@ impl std::fmt::Debug for SharifyWsInstance {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SharifyWsInstance")
             .field("room_id", &self.room_id)
+            .field("correlation_id", &self.correlation_id)
             .finish_non_exhaustive()
     }
 }
@@ -47,18 +219,125 @@ impl std::fmt::Debug for SharifyWsInstance {
 // 1. The user can have multiple tabs open with the same session instead of overriding
 // 2. The user could be on 2 different rooms (bigger feature)
 /// Maps a user_id to its SharifyWsInstance
-pub type SharifyWsManager = HashMap<RoomUserID, SharifyWsInstance>;
+#[derive(Debug, Default)]
+pub struct SharifyWsManager(HashMap<RoomUserID, SharifyWsInstance>);
+
+impl Deref for SharifyWsManager {
+    type Target = HashMap<RoomUserID, SharifyWsInstance>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SharifyWsManager {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl SharifyWsManager {
+    /// Sends the response to the session of the user only, if it's in the room
+    ///
+    /// The manager isn't locked while sending. Returns false when the user has no session in
+    /// the room or it's closed
+    pub async fn send_to_user(
+        ws_mgr: &RwLock<Self>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        cmd: CommandResponse,
+    ) -> bool {
+        let session = ws_mgr
+            .read()
+            .await
+            .get(user_id)
+            .filter(|instance| instance.room_id == room_id)
+            .map(|instance| (instance.session.clone(), instance.encoding));
+        let Some((mut session, encoding)) = session else {
+            return false;
+        };
+
+        SharifyWsInstance::send_binary(&mut session, encoding, user_id, cmd.encode_to_vec()).await
+    }
+}
+
+/// Tells the members their new role, see RoomEvent::RoleChanged
+pub fn init_role_change_notifications(
+    events: broadcast::Receiver<RoomEvent>,
+    ws_mgr: Arc<RwLock<SharifyWsManager>>,
+) {
+    spawn_subscriber("role changes", events, move |event| {
+        let RoomEvent::RoleChanged {
+            room_id,
+            user_id,
+            role_id,
+        } = event
+        else {
+            return;
+        };
+        let ws_mgr = Arc::clone(&ws_mgr);
+
+        spawn_room_task(async move {
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::RoleChanged(
+                    command_response::RoleChanged {
+                        role_id: role_id.into_bytes().into(),
+                    },
+                )),
+                ..Default::default()
+            };
+
+            SharifyWsManager::send_to_user(&ws_mgr, room_id, &user_id, cmd).await;
+        });
+    });
+}
+
+/// Outcome of SharifyWsInstance::connect
+struct Connection {
+    /// The user wasn't flagged connected yet
+    has_connected: bool,
+    /// Resumed, took over a live session or came back within the reconnect grace period
+    is_reconnecting: bool,
+    /// First session of the room, its scoped loops have been started
+    init_room_threads: bool,
+    /// Session taken over, to be closed
+    previous_instance: Option<SharifyWsInstance>,
+}
+
+#[derive(Deserialize)]
+pub struct InitQuery {
+    /// Returned by CreateRoom/JoinRoom, the path user ID alone is known by every member
+    session_token: Option<String>,
+    /// Token of the session to resume, sent in the SessionResume of the previous session
+    resume_token: Option<String>,
+    /// Proves that the user is the owner of the path user ID, see identity::resolve_user_id
+    identity_token: Option<String>,
+    /// "json" for the JSON fallback, protobuf otherwise
+    fmt: Option<String>,
+    /// "deflate" to compress the frames sent to the session, see SessionEncoding
+    compress: Option<String>,
+    /// Comma separated, "batch" to get the responses broadcasted together in a single frame and
+    /// "room_delta" to get the RoomDelta updates
+    features: Option<String>,
+}
 
 impl SharifyWsInstance {
     fn new(
         room_id: RoomID,
+        correlation_id: String,
         session: Session,
+        clock: SharedClock,
+        encoding: SessionEncoding,
         ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
     ) -> Self {
         SharifyWsInstance {
-            hb: Arc::new(Mutex::new(Instant::now())),
+            instance_id: Uuid::now_v7(),
+            correlation_id,
+            hb: Arc::new(Mutex::new(clock.now())),
+            clock,
             is_ready: false,
+            encoding,
             room_id,
             session,
             ws_mgr,
@@ -66,108 +345,274 @@ impl SharifyWsInstance {
         }
     }
 
+    pub fn room_id(&self) -> RoomID {
+        self.room_id
+    }
+
+    pub async fn close(self, reason: Option<CloseReason>) {
+        let _ = self.session.close(reason).await;
+    }
+
     pub async fn init(
         req: HttpRequest,
         body: web::Payload,
         ws_mgr: web::Data<Arc<RwLock<SharifyWsManager>>>,
         state_mgr: web::Data<Arc<RwLock<RoomManager>>>,
         path: web::Path<(RoomID, RoomUserID)>,
+        query: web::Query<InitQuery>,
     ) -> actix_web::Result<impl Responder> {
         let (room_id, user_id) = path.into_inner();
         let state_guard = state_mgr.read().await;
-        let Some(room) = state_guard.get_room(&room_id) else {
-            return Ok(HttpResponse::BadRequest().body(format!("Room {} does not exist", room_id)));
-        };
 
-        let are_room_threads_init = room.are_threads_initiated;
+        match query.session_token.as_deref().map(|token| {
+            identity::verify_session_token(token, room_id, state_guard.clock().utc_now())
+        }) {
+            Some(Ok(session_user_id)) if session_user_id == user_id => (),
+            _ => return Ok(HttpResponse::Unauthorized().finish()),
+        }
 
-        let Some(user) = room.users.iter().find(|e| e.id == user_id) else {
+        // The resume token is only issued to the identity owner so it cannot be impersonated
+        match identity::resolve_user_id(
+            user_id.clone(),
+            query.identity_token.as_deref(),
+            state_guard.clock().utc_now(),
+        ) {
+            Ok(identity_user_id) if identity_user_id == user_id => (),
+            Ok(_) | Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+        }
+        let username = match state_guard.get_room(&room_id) {
+            Some(room) => room
+                .users
+                .iter()
+                .find(|e| e.id == user_id)
+                .map(|user| user.username.clone()),
+            None => {
+                return Ok(
+                    HttpResponse::BadRequest().body(format!("Room {} does not exist", room_id))
+                );
+            }
+        };
+        let Some(username) = username else {
             // User should have joined the room before WS init
             return Ok(HttpResponse::Unauthorized().finish());
         };
 
-        let username = user.username.clone();
-
-        if let Some(instance) = ws_mgr.write().await.remove(&user_id) {
-            let _ = instance.session.close(None).await;
+        if let Err(err) = state_guard.ensure_room_active(room_id) {
+            return Ok(Self::room_error_response(err));
         }
 
-        drop(state_guard);
-
-        {
-            let mut state_guard = state_mgr.write().await;
+        let clock = Arc::clone(state_guard.clock());
+        // Falls back to the ID of the WS init request when the user joined before a restart
+        let correlation_id = state_guard
+            .correlation_id(room_id, &user_id)
+            .unwrap_or_else(|| CorrelationId::of(&req));
 
-            if let Err(e) = state_guard.set_ws_user_state(room_id, &user_id, true) {
-                return Ok(HttpResponse::InternalServerError().body(format!("{e:?}")));
-            }
+        let is_resuming = query
+            .resume_token
+            .as_deref()
+            .is_some_and(|token| state_guard.can_resume_session(room_id, &user_id, token));
 
-            if !are_room_threads_init {
-                state_guard
-                    .get_room_mut(&room_id)
-                    .unwrap()
-                    .are_threads_initiated = true;
-            }
-        }
+        drop(state_guard);
 
         debug!(
-            "[WS] Starting ws session for roomID {} and userID {}",
-            room_id, user_id
+            %correlation_id,
+            "[WS] Starting ws session for roomID {} and userID {}", room_id, user_id
         );
 
         let (res, session, stream) = actix_ws::handle(&req, body)?;
         let this = Self::new(
             room_id,
+            correlation_id,
             session,
+            clock,
+            SessionEncoding::from_query(
+                query.fmt.as_deref(),
+                query.compress.as_deref(),
+                query.features.as_deref(),
+            ),
             Arc::clone(&ws_mgr),
             Arc::clone(&state_mgr),
         );
 
-        // max 128kb stream
-        let stream = stream.max_frame_size(1024 * 128).aggregate_continuations();
+        let stream = crate::config::get().ws_transport.apply(stream);
 
-        // WS Instance scoped thread(s)
-        this.init_main_loop(stream, user_id.clone());
+        let Connection {
+            has_connected,
+            is_reconnecting,
+            init_room_threads,
+            previous_instance,
+        } = match this.connect(user_id.clone(), stream, is_resuming).await {
+            Ok(connection) => connection,
+            Err(err) => return Ok(Self::room_error_response(err)),
+        };
+
+        // Takeover: the user reconnected before its previous session was closed
+        if let Some(instance) = previous_instance {
+            let _ = instance
+                .session
+                .close(Some(
+                    WsCloseCode::SessionReplaced.reason("Replaced by a new session"),
+                ))
+                .await;
+        }
+
+        // The session is already registered, the user isn't notified of its own arrival
+        if !init_room_threads && !is_reconnecting {
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::NewUserJoined(username)),
+                ..Default::default()
+            };
+
+            Self::send_in_room_except(room_id, Some(&user_id), cmd);
+        }
+
+        if has_connected {
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::UserConnected(user_id.clone())),
+                ..Default::default()
+            };
+
+            Self::send_in_room_except(room_id, Some(&user_id), cmd);
+        }
+
+        Ok(res)
+    }
+
+    /// Connect transaction: the user is flagged connected and its session registered under both
+    /// guards (state then WS, like the integrity sweeper) so that no path can observe one without
+    /// the other, the session loops are only started once it can no longer be rejected
+    async fn connect(
+        self,
+        user_id: RoomUserID,
+        stream: AggregatedMessageStream,
+        is_resuming: bool,
+    ) -> Result<Connection, RoomError> {
+        let ws_mgr = Arc::clone(&self.ws_mgr);
+        let state_mgr = Arc::clone(&self.state_mgr);
+        let room_id = self.room_id;
+
+        let mut state_guard = state_mgr.write().await;
+        let mut ws_guard = ws_mgr.write().await;
+
+        // close_room flags the room before closing its sessions under the WS guard, a session
+        // registered after that would never be closed
+        state_guard.ensure_room_active(room_id)?;
 
-        this.send_data_when_ready(user_id.clone());
+        let is_reconnecting = is_resuming
+            || ws_guard.contains_key(&user_id)
+            || state_guard.is_reconnecting(room_id, &user_id);
 
-        // Room scoped thread(s)
-        if !are_room_threads_init {
-            // Avoid fetching anything with Spotify on integration/unit tests
-            if !cfg!(test) {
-                // FIXME? ATM 5 is kinda arbitrary to avoid senders to be blocked but I may have to
-                // think deeper about this buffer len
-                let (tx, rx) = mpsc::channel(5);
+        let resume_token = state_guard.issue_resume_token(room_id, &user_id)?;
+        let has_connected = state_guard.set_ws_user_state(room_id, &user_id, true)?;
 
+        if has_connected && state_guard.take_idle_resume(room_id) {
+            let spotify = state_guard
+                .get_room(&room_id)
+                .map(|room| room.spotify_handler.clone());
+
+            spawn_room_task(async move {
+                if let Some(spotify) = spotify
+                    && let Err(err) = spotify.play_resume().await
                 {
-                    state_mgr
-                        .write()
-                        .await
-                        .get_room_mut(&room_id)
-                        .expect("Unreachable error: Room should exists")
-                        .init_spotify_tick_tx(tx);
+                    error!(
+                        "Failed to resume the idle room {room_id}: {}",
+                        String::from(err)
+                    );
                 }
+            });
+        }
 
-                this.init_spotify_data_loop(rx);
-            }
+        let init_room_threads = !state_guard
+            .tasks()
+            .is_running(room_id, RoomTaskKind::ActivityCheck);
 
-            this.init_room_activity_check_loop();
+        // Room scoped task(s), a kind still running (e.g. the room idled) isn't spawned again
+        if !state_guard
+            .tasks()
+            .is_running(room_id, RoomTaskKind::SpotifyData)
+        {
+            // FIXME? ATM 5 is kinda arbitrary to avoid senders to be blocked but I may have to
+            // think deeper about this buffer len
+            let (tx, rx) = mpsc::channel(5);
 
-        // New Room user entered
-        } else {
-            let mut buf = Vec::new();
+            state_guard
+                .get_room_mut(&room_id)
+                .ok_or(RoomError::RoomNotFound)?
+                .init_spotify_tick_tx(tx);
 
-            let cmd = CommandResponse {
-                r#type: Some(command_response::Type::NewUserJoined(username)),
-            };
+            self.init_spotify_data_loop(&mut state_guard, rx);
+        }
+
+        self.init_token_refresh_loop(&mut state_guard);
+
+        self.init_room_activity_check_loop(&mut state_guard);
+
+        // WS Instance scoped thread(s)
+        self.init_main_loop(stream, user_id.clone());
+
+        self.send_data_when_ready(
+            user_id.clone(),
+            command_response::SessionResume {
+                token: resume_token,
+                resumed: is_resuming,
+                transport: Some(crate::config::get().ws_transport.into()),
+                deflate: self.encoding.deflate,
+                batch: self.encoding.batch,
+                room_delta: self.encoding.room_delta,
+            },
+        );
+
+        let previous_instance = ws_guard.insert(user_id, self);
+
+        Ok(Connection {
+            has_connected,
+            is_reconnecting,
+            init_room_threads,
+            previous_instance,
+        })
+    }
 
-            cmd.encode(&mut buf).unwrap();
+    /// Disconnect transaction, the counterpart of connect: the session is unregistered and the
+    /// user suspended under both guards
+    ///
+    /// When instance_id is set, the session is only removed if it's still the user's current one
+    /// so a session taken over by a reconnection doesn't remove the new one
+    async fn disconnect(
+        ws_mgr: &Arc<RwLock<SharifyWsManager>>,
+        state_mgr: &Arc<RwLock<RoomManager>>,
+        user_id: &RoomUserID,
+        instance_id: Option<Uuid>,
+    ) -> Option<SharifyWsInstance> {
+        let state_guard = state_mgr.read().await;
+        let mut ws_guard = ws_mgr.write().await;
 
-            Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+        if let Some(instance_id) = instance_id
+            && ws_guard
+                .get(user_id)
+                .is_none_or(|instance| instance.instance_id != instance_id)
+        {
+            return None;
         }
 
-        ws_mgr.write().await.insert(user_id, this);
+        let instance = ws_guard.remove(user_id)?;
 
-        Ok(res)
+        // It can be resumed within the reconnect grace period
+        let _ = state_guard.suspend_ws_user(instance.room_id, user_id);
+
+        Some(instance)
+    }
+
+    /// WS upgrade rejection with the RoomError as protobuf body
+    fn room_error_response(err: RoomError) -> HttpResponse {
+        let mut response = match err {
+            RoomError::RoomClosing => HttpResponse::Gone(),
+            _ => HttpResponse::BadRequest(),
+        };
+        let mut buf = Vec::new();
+
+        CommandResponse::from(err).encode(&mut buf).unwrap();
+
+        response.body(buf)
     }
 
     /// Handles MessageAggregator (so, Message stream) and Heartbeat
@@ -177,10 +622,20 @@ impl SharifyWsInstance {
         let state_mgr = Arc::clone(&self.state_mgr);
         let mut interval = time::interval(HEARTBEAT_INTERVAL);
         let hb = Arc::clone(&self.hb);
+        let clock = Arc::clone(&self.clock);
         let mut session = self.session.clone();
         let room_id = self.room_id;
+        let instance_id = self.instance_id;
+        let correlation_id = self.correlation_id.clone();
+        let encoding = self.encoding;
+        // Subscribed before the session is registered so it doesn't miss a broadcast
+        let mut room_rx = fanout::subscribe(room_id);
+        let mut is_room_closed = false;
+        let span = info_span!("ws_session", %room_id, %user_id, %correlation_id);
 
         actix_rt::spawn(async move {
+            let mut close_reason = None;
+
             loop {
                 tokio::select! {
                     biased;
@@ -199,19 +654,34 @@ impl SharifyWsInstance {
                                             instance.is_ready = true;
                                         }
 
-                                        *hb.lock().await = Instant::now();
+                                        *hb.lock().await = clock.now();
                                     }
-                                    AggregatedMessage::Text(_) => {}
                                     AggregatedMessage::Close(_) => {
                                         break;
                                     }
+                                    // Text frames are JSON commands, binary ones protobuf commands
+                                    AggregatedMessage::Text(text) => {
+                                        if !Self::handle_command_message(
+                                            WireFormat::Json.decode(text.as_bytes()),
+                                            Arc::clone(&ws_mgr),
+                                            Arc::clone(&state_mgr),
+                                            room_id,
+                                            &user_id,
+                                            &correlation_id,
+                                            encoding,
+                                        ).await {
+                                            break;
+                                        }
+                                    }
                                     AggregatedMessage::Binary(bytes) => {
-                                        if !Self::handle_binary_message(
-                                            bytes,
+                                        if !Self::handle_command_message(
+                                            WireFormat::Protobuf.decode(&bytes),
                                             Arc::clone(&ws_mgr),
                                             Arc::clone(&state_mgr),
                                             room_id,
-                                            &user_id
+                                            &user_id,
+                                            &correlation_id,
+                                            encoding,
                                         ).await {
                                             break;
                                         }
@@ -222,14 +692,48 @@ impl SharifyWsInstance {
                             None | Some(Err(_)) => break
                         }
                     }
+                    frame = room_rx.recv(), if !is_room_closed => {
+                        match frame {
+                            Ok(frame) => {
+                                let mut is_sent = true;
+
+                                for session_frame in frame.frames_for(&user_id, encoding).iter() {
+                                    is_sent = Self::send_frame(&mut session, session_frame.clone()).await;
+
+                                    if !is_sent {
+                                        break;
+                                    }
+                                }
+
+                                if !is_sent {
+                                    break;
+                                }
+                            }
+                            // The skipped frames are superseded by the current room state
+                            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                debug!(
+                                    "[WS] Session of user {user_id} lagged {skipped} frame(s) behind, replaying the room state"
+                                );
+
+                                Self::replay_room_state(&mut session, encoding, &user_id, &state_mgr, room_id).await;
+                            }
+                            // The session is closed along with its reason by close_room
+                            Err(broadcast::error::RecvError::Closed) => {
+                                is_room_closed = true;
+                            }
+                        }
+                    }
                     _ = interval.tick() => {
-                        if Instant::now().duration_since(*hb.lock().await) > USER_WS_TIMEOUT {
+                        if clock.elapsed_since(*hb.lock().await) > USER_WS_TIMEOUT {
                             debug!(
                                 "[WS] Disconnecting failed heartbeat email:{}, id:{}, room_id:{}",
                                 decode_user_email(&user_id),
                                 user_id,
                                 room_id
                             );
+
+                            close_reason = Some(WsCloseCode::HeartbeatTimeout.reason("Heartbeat timeout"));
+
                             break;
                         }
 
@@ -240,26 +744,67 @@ impl SharifyWsInstance {
                 }
             }
 
-            Self::close_session(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), user_id, None).await;
-        });
+            Self::close_session(
+                Arc::clone(&ws_mgr),
+                Arc::clone(&state_mgr),
+                user_id,
+                Some(instance_id),
+                close_reason,
+            )
+            .await;
+        }.instrument(span));
     }
 
     /// Returns wether the aggregator loop should or shouldn't continue
-    async fn handle_binary_message(
-        bytes: Bytes,
+    async fn handle_command_message(
+        command: Result<Command, String>,
         ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
         user_id: &RoomUserID,
+        correlation_id: &str,
+        encoding: SessionEncoding,
     ) -> bool {
-        let Ok(command) = Command::decode(bytes) else {
+        let Ok(command) = command else {
             debug!(
-                "Unrecognized command from user: {}",
+                "[WS] Unrecognized command from user: {}",
                 decode_user_email(user_id)
             );
             return true;
         };
-        let Some(cmd_type) = command.r#type else {
+        let Some(cmd_type) = &command.r#type else {
+            return true;
+        };
+
+        let span = info_span!("command", cmd = WSCmd::get_cmd_name(cmd_type));
+
+        Self::handle_command(
+            command,
+            ws_mgr,
+            state_mgr,
+            room_id,
+            user_id,
+            correlation_id,
+            encoding,
+        )
+        .instrument(span)
+        .await
+    }
+
+    async fn handle_command(
+        command: Command,
+        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+        state_mgr: Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        correlation_id: &str,
+        encoding: SessionEncoding,
+    ) -> bool {
+        let Command {
+            r#type: Some(cmd_type),
+            request_id,
+        } = command
+        else {
             return true;
         };
 
@@ -273,10 +818,50 @@ impl SharifyWsInstance {
 
         drop(ws_guard);
 
-        let should_room_be_closed = state_mgr
-            .read()
-            .await
-            .is_user_an_owner_and_alone(room_id, user_id);
+        // Resent command (e.g. after a reconnection), answered without running it again
+        if let Some(request_id) = &request_id {
+            let replay = if request_id.chars().count() > MAX_REQUEST_ID_LEN {
+                Some(Err(command_response::Type::GenericError(format!(
+                    "The request_id is longer than {MAX_REQUEST_ID_LEN} characters"
+                ))))
+            } else {
+                match state_mgr
+                    .read()
+                    .await
+                    .lock_room(&room_id)
+                    .map(|mut room| room.command_dedup.begin(user_id, request_id))
+                {
+                    Some(CommandReplay::Done(result)) => Some(*result),
+                    Some(CommandReplay::InProgress) => {
+                        Some(Err(command_response::Type::GenericError(
+                            "The command is already being processed".into(),
+                        )))
+                    }
+                    // A missing room is handled by the command itself
+                    Some(CommandReplay::New) | None => None,
+                }
+            };
+
+            if let Some(result) = replay {
+                debug!("[WS] Replayed command {request_id} of user {user_id}");
+
+                Self::send_command_result(
+                    &mut session,
+                    encoding,
+                    user_id,
+                    correlation_id,
+                    &result,
+                    Some(request_id.clone()),
+                )
+                .await;
+
+                return true;
+            }
+        }
+
+        // The last owner leaving either closes the room or hands it over (OwnerlessRoomPolicy)
+        let (should_room_be_closed, is_owner_promoted) =
+            Self::last_owner_removal(&state_mgr, room_id, user_id).await;
 
         let ws_cmd = WSCmd::new(
             Arc::clone(&state_mgr),
@@ -294,7 +879,6 @@ impl SharifyWsInstance {
                 impact @ StateImpact::Room | impact @ StateImpact::Both(_) => {
                     if let StateImpact::Both(spotify_fetching) = impact {
                         let spotify_fetching = *spotify_fetching;
-                        let ws_mgr = Arc::clone(&ws_mgr);
                         let state_mgr = Arc::clone(&state_mgr);
 
                         // This is a bit ugly but wesocket is so fast that
@@ -303,21 +887,14 @@ impl SharifyWsInstance {
                         // The room data needs to happen after since the command
                         // could have been Skip(Next|Previous) and the TracksQueue
                         // has to be sync
-                        actix_rt::spawn(async move {
+                        spawn_room_task(async move {
                             actix_rt::time::sleep(Duration::from_millis(500)).await;
 
                             let _ = Self::send_spotify_state_in_room(
-                                Arc::clone(&ws_mgr),
                                 Arc::clone(&state_mgr),
                                 room_id,
                                 spotify_fetching,
-                            )
-                            .await;
-
-                            Self::send_room_data_in_room(
-                                Arc::clone(&ws_mgr),
-                                Arc::clone(&state_mgr),
-                                room_id,
+                                true,
                             )
                             .await;
                         });
@@ -326,15 +903,57 @@ impl SharifyWsInstance {
             }
         }
 
+        if let Some(request_id) = &request_id
+            && let Some(mut room) = state_mgr.read().await.lock_room(&room_id)
+        {
+            room.command_dedup
+                .complete(user_id, request_id, processed_cmd.0.clone());
+        }
+
+        Self::send_command_result(
+            &mut session,
+            encoding,
+            user_id,
+            correlation_id,
+            &processed_cmd.0,
+            request_id,
+        )
+        .await;
+
         // Then handle cmd result
         match processed_cmd {
-            // Ignore the Result until I might need to do smth differently based on it
             (Ok(Some(response)), _) | (Err(response), _) => {
-                let mut buf = Vec::new();
-                response.encode(&mut buf);
+                // The other members get the vote state too
+                if let command_response::Type::SeekVote(_) = response {
+                    let cmd = CommandResponse {
+                        r#type: Some(response),
+                        ..Default::default()
+                    };
+
+                    Self::send_in_room_except(room_id, Some(user_id), cmd);
+
+                    return true;
+                }
+
+                if let command_response::Type::ModerationResults(moderation) = response {
+                    let (reason, is_ban) = match cmd_type {
+                        command::Type::KickMany(opts) => (opts.reason, false),
+                        command::Type::BanMany(opts) => (opts.reason, true),
+                        _ => return true,
+                    };
 
-                if !Self::send_binary(&mut session, user_id, Arc::clone(&ws_mgr), buf).await {
-                    debug!("Failed to send command response to user {user_id}. WS session closed");
+                    for result in moderation.results {
+                        if result.error.is_none() {
+                            Self::notify_removed_user(
+                                Arc::clone(&ws_mgr),
+                                room_id,
+                                &result.user_id,
+                                reason.clone(),
+                                is_ban,
+                            )
+                            .await;
+                        }
+                    }
                 }
             }
             (Ok(None), _) => {
@@ -343,25 +962,36 @@ impl SharifyWsInstance {
                 match cmd_type {
                     command::Type::Kick(command::Kick { reason, user_id })
                     | command::Type::Ban(command::Ban { reason, user_id }) => {
-                        if let Some(mut instance) = ws_mgr.write().await.remove(&user_id) {
-                            let mut buf = Vec::new();
-
-                            let cmd = if is_ban {
-                                command_response::Type::Ban(command_response::Ban { reason })
-                            } else {
-                                command_response::Type::Kick(command_response::Kick { reason })
-                            };
+                        Self::notify_removed_user(
+                            Arc::clone(&ws_mgr),
+                            room_id,
+                            &user_id,
+                            reason,
+                            is_ban,
+                        )
+                        .await;
+                    }
+                    command::Type::TransferOwnership(command::TransferOwnership {
+                        user_id: new_owner_id,
+                    }) => {
+                        Self::send_presence_in_room(
+                            room_id,
+                            command_response::Type::OwnershipTransferred(
+                                command_response::OwnershipTransferred {
+                                    previous_owner_id: user_id.clone(),
+                                    new_owner_id,
+                                },
+                            ),
+                        )
+                        .await;
 
-                            cmd.encode(&mut buf);
-
-                            let _ = SharifyWsInstance::send_binary(
-                                &mut instance.session,
-                                &user_id,
-                                Arc::clone(&ws_mgr),
-                                buf,
-                            )
-                            .await;
-                        }
+                        Self::send_room_data_in_room(Arc::clone(&state_mgr), room_id).await;
+                    }
+                    // A new turn may have started (first opt-in, opt-out of the current DJ...)
+                    command::Type::StartDjRotation(_)
+                    | command::Type::StartDjMode(_)
+                    | command::Type::SetDjRotationOptIn(_) => {
+                        Self::schedule_dj_turns(Arc::clone(&state_mgr), room_id);
                     }
                     command::Type::LeaveRoom(_) => {
                         Self::close_session(
@@ -369,20 +999,26 @@ impl SharifyWsInstance {
                             Arc::clone(&state_mgr),
                             user_id.clone(),
                             None,
+                            Some(WsCloseCode::LeftRoom.reason("Left the room")),
                         )
                         .await;
 
-                        if should_room_be_closed.is_ok_and(|b| b) {
+                        if should_room_be_closed {
                             Self::close_room(
                                 ws_mgr,
                                 state_mgr,
                                 room_id,
-                                Some("No owner left to manage the room, closing...".into()),
+                                WsCloseCode::NoOwnerLeft
+                                    .reason("No owner left to manage the room, closing..."),
                             )
                             .await;
 
                             return false;
                         }
+
+                        if is_owner_promoted {
+                            Self::send_room_data_in_room(Arc::clone(&state_mgr), room_id).await;
+                        }
                     }
                     _ => {}
                 }
@@ -392,7 +1028,98 @@ impl SharifyWsInstance {
         true
     }
 
-    fn send_data_when_ready(&self, user_id: RoomUserID) {
+    /// Answers the author of the command, the ones without response data are only answered when
+    /// they carry a request_id
+    async fn send_command_result(
+        session: &mut Session,
+        encoding: SessionEncoding,
+        user_id: &RoomUserID,
+        correlation_id: &str,
+        result: &CommandResult,
+        request_id: Option<String>,
+    ) {
+        let (r#type, debug) = match result {
+            Ok(None) if request_id.is_none() => return,
+            Ok(response) => (response.clone(), None),
+            // Errors carry the correlation ID so the client can report it
+            Err(response) => {
+                debug!("[WS] Command of user {user_id} failed: {response:?}");
+
+                (
+                    Some(response.clone()),
+                    Some(command_response::Debug {
+                        correlation_id: correlation_id.to_owned(),
+                    }),
+                )
+            }
+        };
+
+        let buf = CommandResponse {
+            r#type,
+            debug,
+            request_id,
+        }
+        .encode_to_vec();
+
+        if !Self::send_binary(session, encoding, user_id, buf).await {
+            debug!("[WS] Failed to send command response to user {user_id}. WS session closed");
+        }
+    }
+
+    /// Sends the Kick/Ban to the user removed from the room and closes its session
+    async fn notify_removed_user(
+        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        reason: String,
+        is_ban: bool,
+    ) {
+        let (cmd, close_reason) = if is_ban {
+            (
+                command_response::Type::Ban(command_response::Ban {
+                    reason: reason.clone(),
+                }),
+                WsCloseCode::Banned.reason(reason),
+            )
+        } else {
+            (
+                command_response::Type::Kick(command_response::Kick {
+                    reason: reason.clone(),
+                }),
+                WsCloseCode::Kicked.reason(reason),
+            )
+        };
+        let cmd = CommandResponse {
+            r#type: Some(cmd),
+            ..Default::default()
+        };
+
+        SharifyWsManager::send_to_user(&ws_mgr, room_id, user_id, cmd).await;
+
+        let mut ws_guard = ws_mgr.write().await;
+
+        // Unless it reconnected to another room meanwhile
+        if ws_guard
+            .get(user_id)
+            .is_none_or(|instance| instance.room_id != room_id)
+        {
+            return;
+        }
+
+        let instance = ws_guard.remove(user_id);
+
+        drop(ws_guard);
+
+        if let Some(instance) = instance {
+            instance.close(Some(close_reason)).await;
+        }
+    }
+
+    /// Sends the SessionResume then the room data once the client answered the first ping
+    ///
+    /// A resumed session only gets the current room state, which supersedes the updates it
+    /// missed since they're all snapshots, instead of refetching and broadcasting it
+    fn send_data_when_ready(&self, user_id: RoomUserID, resume: command_response::SessionResume) {
         let ws_mgr = Arc::clone(&self.ws_mgr);
         let state_mgr = Arc::clone(&self.state_mgr);
 
@@ -402,7 +1129,7 @@ impl SharifyWsInstance {
             loop {
                 interval.tick().await;
 
-                let (mut session, room_id) = {
+                let (mut session, encoding, room_id) = {
                     let ws_guard = ws_mgr.read().await;
                     let Some(instance) = ws_guard.get(&user_id) else {
                         // Reachable if the client is dropped instantly
@@ -413,29 +1140,71 @@ impl SharifyWsInstance {
                         continue;
                     }
 
-                    (instance.session.clone(), instance.room_id)
+                    (
+                        instance.session.clone(),
+                        instance.encoding,
+                        instance.room_id,
+                    )
                 };
 
-                Self::send_room_data_in_room(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), room_id)
-                    .await;
-
+                let resumed = resume.resumed;
                 let mut buf = Vec::new();
 
+                CommandResponse {
+                    r#type: Some(command_response::Type::SessionResume(resume)),
+                    ..Default::default()
+                }
+                .encode(&mut buf)
+                .unwrap();
+
+                // Uncompressed since it tells the client whether the next frames are
+                let resume_encoding = SessionEncoding {
+                    deflate: false,
+                    ..encoding
+                };
+
+                if !Self::send_binary(&mut session, resume_encoding, &user_id, buf).await {
+                    break;
+                }
+
+                if resumed {
+                    Self::replay_room_state(&mut session, encoding, &user_id, &state_mgr, room_id)
+                        .await;
+
+                    break;
+                }
+
                 if let Err(err) = Self::send_spotify_state_in_room(
-                    Arc::clone(&ws_mgr),
                     Arc::clone(&state_mgr),
                     room_id,
                     SPOTIFY_FETCH_TRACKS_Q | SPOTIFY_FETCH_PLAYBACK,
+                    true,
                 )
                 .await
                 {
                     let cmd = CommandResponse {
                         r#type: Some(err.into()),
+                        ..Default::default()
                     };
 
-                    cmd.encode(&mut buf).unwrap();
+                    SharifyWsManager::send_to_user(&ws_mgr, room_id, &user_id, cmd).await;
+                }
+
+                // The room update it was broadcasted along may be a delta, which this session
+                // has no state to apply on, or skipped when the room didn't change
+                let room = state_mgr
+                    .read()
+                    .await
+                    .get_room(&room_id)
+                    .map(|room| Room::clone(&room));
 
-                    Self::send_binary(&mut session, &user_id, Arc::clone(&ws_mgr), buf).await;
+                if let Some(room) = room {
+                    let cmd = CommandResponse {
+                        r#type: Some(command_response::Type::Room(room.into())),
+                        ..Default::default()
+                    };
+
+                    Self::send_binary(&mut session, encoding, &user_id, cmd.encode_to_vec()).await;
                 }
 
                 break;
@@ -443,108 +1212,354 @@ impl SharifyWsInstance {
         });
     }
 
-    fn init_room_activity_check_loop(&self) {
+    async fn replay_room_state(
+        session: &mut Session,
+        encoding: SessionEncoding,
+        user_id: &RoomUserID,
+        state_mgr: &Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+    ) {
+        let snapshots = {
+            let state_guard = state_mgr.read().await;
+            let Some(room) = state_guard.get_room(&room_id) else {
+                return;
+            };
+
+            Self::room_state_snapshots(&room)
+        };
+
+        for cmd in encoding.negotiate(snapshots, None) {
+            if !Self::send_binary(session, encoding, user_id, cmd.encode_to_vec()).await {
+                return;
+            }
+        }
+    }
+
+    /// The room, its playback and the Spotify queue as last fetched, see replay_room_state
+    pub(crate) fn room_state_snapshots(room: &Room) -> Vec<CommandResponse> {
+        [
+            command_response::Type::Room(room.clone().into()),
+            command_response::Type::SpotifyAllState(command_response::SpotifyAllState {
+                state: room.now_playing.clone().map(Into::into),
+                previous_tracks: room.previous_tracks.clone().map(Into::into),
+                next_tracks: room.next_tracks.clone().map(Into::into),
+                queue: Self::attributed_queue(Some(room), room.next_tracks.as_ref()),
+            }),
+        ]
+        .into_iter()
+        .map(|snapshot| CommandResponse {
+            r#type: Some(snapshot),
+            ..Default::default()
+        })
+        .collect()
+    }
+
+    /// Once it breaks, the room has been deleted and its tasks, this one included, shut down
+    fn init_room_activity_check_loop(&self, state: &mut RoomManager) {
         let room_id = self.room_id;
+        let ws_mgr = Arc::clone(&self.ws_mgr);
         let state_mgr = Arc::clone(&self.state_mgr);
 
-        actix_rt::spawn(async move {
-            let mut interval = time::interval(crate::DATA_FETCHING_INTERVAL);
-
+        let task = async move {
             loop {
-                interval.tick().await;
+                // Read on each tick so a config reload applies to running rooms
+                time::sleep(crate::config::get().data_fetching_interval).await;
+
+                let (disconnected, has_expired_members, is_active, ownership, idle_pause) = {
+                    let mut state_guard = state_mgr.write().await;
+                    let disconnected = state_guard.expire_suspended_sessions(room_id);
+                    let has_expired_members =
+                        !state_guard.expire_pending_members(room_id).is_empty();
+                    let is_active = state_guard.check_room_activity(room_id);
+                    let ownership = match is_active {
+                        true => state_guard
+                            .check_room_owners(room_id, crate::config::get().ownerless_room_policy),
+                        false => RoomOwnership::Managed,
+                    };
+                    let idle_pause = state_guard
+                        .take_idle_pause(room_id)
+                        .then(|| state_guard.get_room(&room_id))
+                        .flatten()
+                        .map(|room| room.spotify_handler.clone());
+
+                    if is_active {
+                        state_guard.hibernate_if_idle(room_id);
+                    }
 
-                let mut guard = state_mgr.write().await;
-                let Some(room) = guard.get_room_mut(&room_id) else {
-                    break;
+                    (
+                        disconnected,
+                        has_expired_members,
+                        is_active,
+                        ownership,
+                        idle_pause,
+                    )
                 };
 
-                // No user connected to the Room
-                if room.users.iter().filter(|u| u.is_connected).count() == 0 {
-                    if room.inactive_for.is_some_and(|inactive| {
-                        inactive.elapsed().as_secs() >= INACTIVE_ROOM_MINS as _
-                    }) {
-                        let _ = guard.delete_room(room_id, None);
+                // Deleted for inactivity, the sessions that are still open are told why
+                if !is_active {
+                    Self::close_room(
+                        Arc::clone(&ws_mgr),
+                        Arc::clone(&state_mgr),
+                        room_id,
+                        WsCloseCode::RoomClosed.reason("The room has been closed for inactivity"),
+                    )
+                    .await;
+
+                    break;
+                }
+
+                if has_expired_members {
+                    Self::send_room_data_in_room(Arc::clone(&state_mgr), room_id).await;
+                }
+
+                // The owner(s) lost their sessions without leaving, the room would otherwise keep
+                // polling Spotify until its inactivity_timeout
+                match ownership {
+                    RoomOwnership::Managed => {}
+                    RoomOwnership::Transferred(_) => {
+                        Self::send_room_data_in_room(Arc::clone(&state_mgr), room_id).await;
+                    }
+                    RoomOwnership::Ownerless => {
+                        Self::close_room(
+                            Arc::clone(&ws_mgr),
+                            Arc::clone(&state_mgr),
+                            room_id,
+                            WsCloseCode::NoOwnerLeft
+                                .reason("No owner left to manage the room, closing..."),
+                        )
+                        .await;
 
                         break;
-                    } else {
-                        room.inactive_for = Some(Instant::now());
                     }
-                } else {
-                    room.inactive_for = None;
                 }
-            }
 
-            let mut data_fetching_guard = crate::DATA_FETCHING_INTERVALS
-                .get_or_init(|| Arc::new(Mutex::new(HashMap::new())))
-                .lock()
-                .await;
+                if let Some(spotify) = idle_pause
+                    && let Err(err) = spotify.pause().await
+                {
+                    error!(
+                        "Failed to pause the idle room {room_id}: {}",
+                        String::from(err)
+                    );
+
+                    // Not resumed on the next connection since it's not paused
+                    state_mgr.read().await.take_idle_resume(room_id);
+                }
 
-            // Break spotify_data_loop if it still exists
-            if let Some(tx) = data_fetching_guard.remove(&room_id) {
-                let _ = tx.send(()).await;
+                for user_id in disconnected {
+                    Self::send_presence_in_room(
+                        room_id,
+                        command_response::Type::UserDisconnected(user_id),
+                    )
+                    .await;
+                }
             }
-        });
+        };
+
+        state
+            .tasks_mut()
+            .spawn(room_id, RoomTaskKind::ActivityCheck, task);
     }
 
-    fn init_spotify_data_loop(&self, mut tick_rx: mpsc::Receiver<Duration>) {
-        // Implicit copy to avoid self refs
+    /// Refreshes the Spotify tokens TOKEN_REFRESH_MARGIN before they expire and pushes them
+    /// to the room owner(s) so they can persist them
+    ///
+    /// When every retry failed, the owner(s) are notified and it tries again later instead of
+    /// closing the room
+    fn init_token_refresh_loop(&self, state: &mut RoomManager) {
         let room_id = self.room_id;
         let ws_mgr = Arc::clone(&self.ws_mgr);
         let state_mgr = Arc::clone(&self.state_mgr);
 
-        actix_rt::spawn(async move {
-            let mut data_fetching_guard = crate::DATA_FETCHING_INTERVALS
-                .get_or_init(|| Arc::new(Mutex::new(HashMap::new())))
-                .lock()
-                .await;
+        let task = async move {
+            let mut retry_delay = None;
 
-            if data_fetching_guard.contains_key(&room_id) {
-                error!(
-                    "Unexpected error: Trying to start a spotify data loop while it already exists on that room id"
-                );
-                return;
+            loop {
+                let delay = match retry_delay.take() {
+                    Some(delay) => delay,
+                    None => {
+                        let guard = state_mgr.read().await;
+                        let now = guard.clock().utc_now();
+                        let Some(room) = guard.get_room(&room_id) else {
+                            break;
+                        };
+
+                        room.spotify_handler
+                            .tokens
+                            .expires_at()
+                            .and_then(|expires_at| (expires_at - now).to_std().ok())
+                            .map(|expires_in| {
+                                expires_in.saturating_sub(spotify::TOKEN_REFRESH_MARGIN)
+                            })
+                            .unwrap_or_default()
+                    }
+                };
+
+                time::sleep(delay).await;
+
+                if let Err(err) =
+                    Self::refresh_room_tokens(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), room_id)
+                        .await
+                {
+                    error!("Failed to refresh Spotify tokens of room {room_id}: {err}");
+
+                    let status = {
+                        let guard = state_mgr.read().await;
+                        let now = guard.clock().utc_now();
+
+                        guard.lock_room(&room_id).map(|mut room| {
+                            room.token_refresh_failures += 1;
+                            room.tokens_status(now)
+                        })
+                    };
+
+                    Self::send_to_room_owners(
+                        Arc::clone(&ws_mgr),
+                        Arc::clone(&state_mgr),
+                        room_id,
+                        CommandResponse {
+                            r#type: Some(command_response::Type::GenericError(
+                                "Failed to refresh Spotify tokens, retrying later".into(),
+                            )),
+                            ..Default::default()
+                        },
+                    )
+                    .await;
+
+                    if let Some(status) = status {
+                        Self::send_to_room_owners(
+                            Arc::clone(&ws_mgr),
+                            Arc::clone(&state_mgr),
+                            room_id,
+                            CommandResponse {
+                                r#type: Some(command_response::Type::SpotifyTokensStatus(
+                                    status.into(),
+                                )),
+                                ..Default::default()
+                            },
+                        )
+                        .await;
+                    }
+
+                    retry_delay = Some(spotify::TOKEN_REFRESH_RETRY_INTERVAL);
+                }
+            }
+        };
+
+        state
+            .tasks_mut()
+            .spawn(room_id, RoomTaskKind::TokenRefresh, task);
+    }
+
+    /// Fetches new tokens with an exponential backoff, stores them in the room and sends the
+    /// new tokens status to the room owner(s), the tokens themselves never leave the server
+    async fn refresh_room_tokens(
+        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+        state_mgr: Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+    ) -> Result<(), String> {
+        // The HTTP request is made on a clone so the room isn't locked meanwhile
+        let mut spotify = state_mgr
+            .read()
+            .await
+            .get_room(&room_id)
+            .map(|room| room.spotify_handler.clone())
+            .ok_or("Room not found")?;
+
+        let mut backoff = spotify::TOKEN_REFRESH_BACKOFF;
+        let mut attempt = 1;
+
+        let tokens = loop {
+            match spotify.fetch_refresh_token().await {
+                Ok(tokens) => break tokens,
+                Err(err) if attempt >= spotify::TOKEN_REFRESH_MAX_RETRIES => {
+                    return Err(String::from(err));
+                }
+                Err(err) => {
+                    debug!(
+                        "Spotify tokens refresh attempt {attempt} failed for room {room_id}: {}",
+                        String::from(err)
+                    );
+
+                    time::sleep(backoff).await;
+
+                    backoff *= 2;
+                    attempt += 1;
+                }
             }
+        };
 
-            let (tx, mut rx) = mpsc::channel::<()>(1);
-            data_fetching_guard.insert(room_id, tx);
+        let status = {
+            let guard = state_mgr.read().await;
+            let now = guard.clock().utc_now();
+            let mut room = guard.lock_room(&room_id).ok_or("Room not found")?;
 
-            drop(data_fetching_guard);
+            room.spotify_handler.tokens = tokens;
+            room.tokens_refreshed_at = Some(now);
+            room.token_refresh_failures = 0;
 
-            if Self::send_spotify_state_in_room(
-                Arc::clone(&ws_mgr),
+            room.tokens_status(now)
+        };
+
+        debug!("Spotify tokens refreshed for room {room_id}");
+
+        let cmd = CommandResponse {
+            r#type: Some(command_response::Type::SpotifyTokensStatus(status.into())),
+            ..Default::default()
+        };
+
+        Self::send_to_room_owners(ws_mgr, state_mgr, room_id, cmd).await;
+
+        Ok(())
+    }
+
+    fn init_spotify_data_loop(
+        &self,
+        state: &mut RoomManager,
+        mut tick_rx: mpsc::Receiver<Duration>,
+    ) {
+        // Implicit copy to avoid self refs
+        let room_id = self.room_id;
+        let ws_mgr = Arc::clone(&self.ws_mgr);
+        let state_mgr = Arc::clone(&self.state_mgr);
+
+        let task = async move {
+            let first_fetch = Self::send_spotify_state_in_room(
                 Arc::clone(&state_mgr),
                 room_id,
                 SPOTIFY_FETCH_PLAYBACK | SPOTIFY_FETCH_TRACKS_Q,
+                false,
             )
-            .await
-            .is_err()
-            {
-                // FIXME? UX related
-                // Most probably revoked tokens. They may have been refreshed from here or
-                // elsewhere but the client holds stale/outdated tokens
-                Self::close_room(
-                    ws_mgr,
-                    state_mgr,
-                    room_id,
-                    Some("Spotify request error. Closing room...".into()),
-                )
-                .await;
+            .await;
+            let first_delay = match first_fetch {
+                Ok(()) => crate::config::get().spotify_data_interval,
+                Err(err) => {
+                    let Some(retry_in) = Self::handle_spotify_outage(
+                        Arc::clone(&ws_mgr),
+                        Arc::clone(&state_mgr),
+                        room_id,
+                        &err,
+                    )
+                    .await
+                    else {
+                        return;
+                    };
 
-                return;
-            }
+                    retry_in
+                }
+            };
 
-            let sleep_fut =
-                time::sleep_until(time::Instant::now() + spotify::DEFAULT_DATA_INTERVAL);
+            let sleep_fut = time::sleep_until(time::Instant::now() + first_delay);
 
             tokio::pin!(sleep_fut);
 
+            let mut playback_ticks = time::interval(PLAYBACK_TICK_INTERVAL);
+
+            playback_ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
             loop {
                 tokio::select! {
                     biased;
 
-                    _ = rx.recv() => {
-                        break;
-                    }
                     myb_tick = tick_rx.recv() => {
                         match myb_tick {
                             Some(tick) => {
@@ -556,103 +1571,218 @@ impl SharifyWsInstance {
                         }
                     }
                     _ = &mut sleep_fut => {
-                        if Self::send_spotify_state_in_room(
-                            Arc::clone(&ws_mgr),
+                        let fetch = Self::send_spotify_state_in_room(
                             Arc::clone(&state_mgr),
                             room_id,
                             SPOTIFY_FETCH_PLAYBACK | SPOTIFY_FETCH_TRACKS_Q,
-                        ).await.is_err() {
-                            Self::close_room(
-                                ws_mgr,
-                                state_mgr,
-                                room_id,
-                                Some("Spotify request error. Closing room...".into()),
-                            ).await;
+                            false,
+                        ).await;
+
+                        let Err(err) = fetch else {
+                            Self::end_spotify_outage(&state_mgr, room_id).await;
 
+                            // Overridden right after by the tick the fetch sent, if any
+                            sleep_fut.as_mut().reset(
+                                time::Instant::now() + crate::config::get().spotify_data_interval,
+                            );
+
+                            continue;
+                        };
+
+                        let Some(retry_in) = Self::handle_spotify_outage(
+                            Arc::clone(&ws_mgr),
+                            Arc::clone(&state_mgr),
+                            room_id,
+                            &err,
+                        ).await else {
                             break;
-                        }
+                        };
+
+                        sleep_fut.as_mut().reset(time::Instant::now() + retry_in);
+                    }
+                    _ = playback_ticks.tick() => {
+                        Self::send_playback_tick(&state_mgr, room_id).await;
                     }
                 }
             }
-        });
+        };
+
+        state
+            .tasks_mut()
+            .spawn(room_id, RoomTaskKind::SpotifyData, task);
     }
 
-    /// Also handles refresh token fetch when expired
+    /// Called on each failed fetch of the Spotify data loop: the room is degraded rather than
+    /// closed, its members are told Spotify is unavailable and the fetch is retried with a
+    /// backoff. The room is only closed once the outage lasted SPOTIFY_OUTAGE_MAX_DURATION_MS, or
+    /// right away when Spotify rejects tokens that couldn't be refreshed
     ///
-    /// Can fail if:
-    ///     - Room not found
-    ///     - Spotify endpoint fetch is err
-    ///     - Refresh token fetch fail
-    async fn send_spotify_state_in_room(
+    /// Returns the delay before the next fetch, None if the room is gone or closed
+    pub(crate) async fn handle_spotify_outage(
         ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
-        spotify_fetch_flags: SpotifyFetchT,
-    ) -> Result<(), SpotifyError> {
-        let mut guard = state_mgr.write().await;
-        let Some(room) = guard.get_room_mut(&room_id) else {
-            return Err(SpotifyError::Generic("Room not found".into()));
-        };
-
-        let now = chrono::Utc::now();
-        let created_at = room
-            .spotify_handler
-            .tokens
-            .created_at
-            .to_datetime()
-            .unwrap();
-        let expires_at = created_at
-            .checked_add_signed(TimeDelta::seconds(
-                room.spotify_handler.tokens.expires_in as _,
-            ))
-            .unwrap();
-
-        if now > expires_at
-            && let Err(err) = room.spotify_handler.fetch_refresh_token().await
-        {
-            let mut buf = Vec::new();
+        err: &ProviderError,
+    ) -> Option<Duration> {
+        let (outage, closes_in) = {
+            let guard = state_mgr.read().await;
+            let now = guard.clock().now();
+            let mut room = guard.lock_room(&room_id)?;
+            let outage = room.record_spotify_failure(now, err);
+
+            (outage, outage.closes_in(now))
+        };
 
-            CommandResponse::from(err).encode(&mut buf).unwrap();
+        if closes_in.is_zero() {
+            let reason = if outage.tokens_rejected {
+                "Spotify rejected the room tokens. Closing room..."
+            } else {
+                "Spotify unreachable for too long. Closing room..."
+            };
 
-            Self::send_in_room(ws_mgr, room_id, buf).await;
+            Self::close_room(
+                ws_mgr,
+                state_mgr,
+                room_id,
+                WsCloseCode::SpotifyUnreachable.reason(reason),
+            )
+            .await;
 
-            return Err(SpotifyError::Generic("Failed to refresh tokens".into()));
+            return None;
         }
 
-        drop(guard);
+        let retry_in = outage.retry_in().min(closes_in);
 
-        let cmd = match_flags!(
-            spotify_fetch_flags,
-            [SPOTIFY_FETCH_ALL; Self::fetch_spotify_all(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), room_id)],
-            [SPOTIFY_FETCH_PLAYBACK; Self::fetch_spotify_playback(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), room_id)],
-            [SPOTIFY_FETCH_TRACKS_Q; Self::fetch_spotify_tracks(Arc::clone(&ws_mgr), Arc::clone(&state_mgr), room_id)];
-            [flags; panic!("Unhandled Spotify Fetch flags: {flags}")]
+        warn!(
+            "[{room_id}] Spotify unavailable ({} consecutive failures), retrying in {}s",
+            outage.failures,
+            retry_in.as_secs()
         );
 
-        let mut buf = Vec::new();
+        let cmd = CommandResponse {
+            r#type: Some(command_response::Type::SpotifyUnavailable(
+                command_response::SpotifyUnavailable {
+                    failures: outage.failures,
+                    retry_in_ms: retry_in.as_millis() as _,
+                    closes_in_ms: closes_in.as_millis() as _,
+                },
+            )),
+            ..Default::default()
+        };
 
-        cmd.encode(&mut buf).unwrap();
+        Self::send_in_room(room_id, cmd);
 
-        Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+        Some(retry_in)
+    }
 
-        Ok(())
+    /// Ends the Spotify outage of the room (if any) after a successful fetch
+    async fn end_spotify_outage(state_mgr: &Arc<RwLock<RoomManager>>, room_id: RoomID) {
+        let outage = state_mgr
+            .read()
+            .await
+            .lock_room(&room_id)
+            .and_then(|mut room| room.spotify_outage.take());
+
+        if let Some(outage) = outage {
+            info!(
+                "[{room_id}] Spotify available again after {} failed fetches",
+                outage.failures
+            );
+
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::SpotifyRecovered(true)),
+                ..Default::default()
+            };
+
+            Self::send_in_room(room_id, cmd);
+        }
+    }
+
+    /// Progress of the current track extrapolated by the server, so that the clients don't
+    /// drift from each other between the fetches
+    async fn send_playback_tick(state_mgr: &Arc<RwLock<RoomManager>>, room_id: RoomID) {
+        let tick = {
+            let state_guard = state_mgr.read().await;
+            let now = state_guard.clock().now();
+
+            state_guard.get_room(&room_id).and_then(|room| {
+                let progress_ms = room.playback_progress_ms(now)?;
+                let playback = room.now_playing.as_ref()?;
+
+                Some(command_response::PlaybackTick {
+                    track_id: playback.track_id.clone(),
+                    progress_ms,
+                    duration_ms: playback.duration_ms,
+                })
+            })
+        };
+
+        if let Some(tick) = tick {
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::PlaybackTick(tick)),
+                ..Default::default()
+            };
+
+            Self::send_in_room(room_id, cmd);
+        }
+    }
+
+    /// Tokens are refreshed by the token_refresh_loop
+    ///
+    /// The state is broadcasted in a single frame along with the updates its fetch led to (rate
+    /// limit, album image, dequeued track) and the room data when with_room_data, which is sent
+    /// even if the fetch failed
+    ///
+    /// Can fail if:
+    ///     - Room not found
+    ///     - Spotify endpoint fetch is err
+    pub(crate) async fn send_spotify_state_in_room(
+        state_mgr: Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        spotify_fetch_flags: SpotifyFetchT,
+        with_room_data: bool,
+    ) -> Result<(), ProviderError> {
+        if state_mgr.read().await.get_room(&room_id).is_none() {
+            return Err(ProviderError::Generic("Room not found".into()));
+        }
+
+        let mut batch = RoomUpdate::default();
+        let fetched: Result<CommandResponse, ProviderError> = async {
+            Ok(match_flags!(
+                spotify_fetch_flags,
+                [SPOTIFY_FETCH_ALL; Self::fetch_spotify_all(Arc::clone(&state_mgr), room_id, &mut batch)],
+                [SPOTIFY_FETCH_PLAYBACK; Self::fetch_spotify_playback(Arc::clone(&state_mgr), room_id, &mut batch)],
+                [SPOTIFY_FETCH_TRACKS_Q; Self::fetch_spotify_tracks(Arc::clone(&state_mgr), room_id, &mut batch)];
+                [flags; panic!("Unhandled Spotify Fetch flags: {flags}")]
+            ))
+        }
+        .await;
+        let result = fetched.map(|cmd| batch.push(cmd));
+
+        // Unless already added by the fetch
+        if with_room_data {
+            Self::push_room_update(&state_mgr, room_id, &mut batch).await;
+        }
+
+        if !batch.is_empty() {
+            Self::broadcast_in_room(room_id, None, batch);
+        }
+
+        result
     }
 
     async fn fetch_spotify_all(
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
-    ) -> Result<CommandResponse, SpotifyError> {
+        batch: &mut RoomUpdate,
+    ) -> Result<CommandResponse, ProviderError> {
         let mut rate_limit = None;
-        let mut guard = state_mgr.write().await;
-        let Some(room) = guard.get_room_mut(&room_id) else {
-            return Err(SpotifyError::Generic("Room not found".into()));
-        };
+        let provider = Self::room_music_provider(&state_mgr, room_id).await?;
 
         let (state, next, previous) = tokio::join!(
-            room.spotify_handler.get_current_playback_state(),
-            room.spotify_handler.get_next_tracks(),
-            room.spotify_handler.get_recent_tracks(Some(10)),
+            provider.now_playing(),
+            provider.next_tracks(),
+            provider.recent_tracks(10),
         );
 
         if let Err(ref err) = previous {
@@ -661,7 +1791,7 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
@@ -672,7 +1802,7 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
@@ -683,71 +1813,74 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
 
         if let Some(time) = rate_limit {
-            let cmd = CommandResponse {
+            batch.push(CommandResponse {
                 r#type: Some(command_response::Type::SpotifyRateLimited(*time)),
-            };
-
-            let mut buf = Vec::new();
-
-            cmd.encode(&mut buf).unwrap();
-
-            Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+                ..Default::default()
+            });
         }
 
-        if let Ok(Some(ref playback)) = state {
-            if playback.is_playing
-                && let Some(progress_ms) = playback.progress_ms
-            {
-                let mut rest_ms = playback.duration_ms - progress_ms;
-
-                // If there's more than 2min left, add a fetch in the middle to keep sync with an
-                // external spotify client/player
-                if rest_ms > 1000 * 60 * 2 {
-                    rest_ms /= 2;
-                }
+        // Nothing to show, the data loop treats it as an outage
+        if let (Err(err), Err(_)) = (&state, &next) {
+            return Err(err.clone());
+        }
 
-                room.set_spotify_tick(Duration::from_millis(rest_ms + spotify::FETCH_OFFSET_MS))
-                    .await;
-            } else {
-                // Playtrack is not playing
-                room.set_spotify_tick(spotify::DEFAULT_DATA_INTERVAL).await;
-            }
+        if let Ok(ref playback) = state {
+            Self::apply_playback_state(
+                &state_mgr,
+                room_id,
+                provider.as_ref(),
+                playback.as_ref(),
+                next.as_ref().ok(),
+                Some(crate::config::get().spotify_data_interval),
+                batch,
+            )
+            .await;
+        }
 
-            let _ = guard.remove_track_from_queue(room_id, playback.track_id.clone());
+        // The queue ran empty while playing, see RoomSettings::auto_fill
+        if let (Ok(Some(playback)), Ok(next)) = (&state, &next)
+            && playback.is_playing
+            && next.is_empty()
+        {
+            Self::fill_queue_gap(Arc::clone(&state_mgr), room_id);
         }
 
+        let queue = Self::record_fetched_tracks(
+            &state_mgr,
+            room_id,
+            next.as_ref().ok(),
+            previous.as_ref().ok(),
+        )
+        .await;
+
         Ok(CommandResponse {
             r#type: Some(command_response::Type::SpotifyAllState(
                 command_response::SpotifyAllState {
                     previous_tracks: previous.map(|v| Some(v.into())).unwrap_or_default(),
                     state: state.map(|v| v.map(Into::into)).unwrap_or_default(),
                     next_tracks: next.map(|v| Some(v.into())).unwrap_or_default(),
+                    queue,
                 },
             )),
+            ..Default::default()
         })
     }
 
     async fn fetch_spotify_tracks(
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
-    ) -> Result<CommandResponse, SpotifyError> {
+        batch: &mut RoomUpdate,
+    ) -> Result<CommandResponse, ProviderError> {
         let mut rate_limit = None;
-        let mut guard = state_mgr.write().await;
-        let Some(room) = guard.get_room_mut(&room_id) else {
-            return Err(SpotifyError::Generic("Room not found".into()));
-        };
+        let provider = Self::room_music_provider(&state_mgr, room_id).await?;
 
-        let (next, previous) = tokio::join!(
-            room.spotify_handler.get_next_tracks(),
-            room.spotify_handler.get_recent_tracks(Some(10)),
-        );
+        let (next, previous) = tokio::join!(provider.next_tracks(), provider.recent_tracks(10));
 
         if let Err(ref err) = previous {
             error!(
@@ -755,7 +1888,7 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
@@ -766,45 +1899,127 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
 
         if let Some(time) = rate_limit {
-            let cmd = CommandResponse {
+            batch.push(CommandResponse {
                 r#type: Some(command_response::Type::SpotifyRateLimited(*time)),
-            };
-
-            let mut buf = Vec::new();
-
-            cmd.encode(&mut buf).unwrap();
-
-            Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+                ..Default::default()
+            });
         }
 
+        let queue = Self::record_fetched_tracks(
+            &state_mgr,
+            room_id,
+            next.as_ref().ok(),
+            previous.as_ref().ok(),
+        )
+        .await;
+
         Ok(CommandResponse {
             r#type: Some(command_response::Type::SpotifyTracksState(
                 command_response::SpotifyTracksState {
                     previous_tracks: previous.map(|v| Some(v.into())).unwrap_or_default(),
                     next_tracks: next.map(|v| Some(v.into())).unwrap_or_default(),
+                    queue,
                 },
             )),
+            ..Default::default()
         })
     }
 
+    /// Broadcasts the recommendation queued in the background along with its seed, see
+    /// RoomManager::fill_queue_gap
+    fn fill_queue_gap(state_mgr: Arc<RwLock<RoomManager>>, room_id: RoomID) {
+        spawn_room_task(async move {
+            let (track, seed) = match RoomManager::fill_queue_gap(&state_mgr, room_id).await {
+                Ok(Some(filled)) => filled,
+                Ok(None) => return,
+                Err(err) => {
+                    debug!(
+                        "[{room_id}] Failed to fill the queue gap: {}",
+                        String::from(err)
+                    );
+
+                    return;
+                }
+            };
+
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::Recommendation(
+                    command_response::Recommendation {
+                        track: Some(track.into()),
+                        explanation: seed.explanation(),
+                        seed_track_id: seed.track_id,
+                        seed_user_id: seed.user_id,
+                    },
+                )),
+                ..Default::default()
+            };
+
+            Self::send_in_room(room_id, cmd);
+        });
+    }
+
+    /// Merges the Spotify queue with the submitters of its tracks, see Room::queue_submitters
+    /// Keeps the fetched tracks for the replays and attributes the queue to its submitters
+    async fn record_fetched_tracks(
+        state_mgr: &RwLock<RoomManager>,
+        room_id: RoomID,
+        next_tracks: Option<&SpotifyTackArray>,
+        previous_tracks: Option<&SpotifyTackArray>,
+    ) -> Vec<command_response::QueuedTrack> {
+        let guard = state_mgr.read().await;
+        let Some(mut room) = guard.lock_room(&room_id) else {
+            return Vec::new();
+        };
+
+        if let Some(next_tracks) = next_tracks {
+            room.next_tracks = Some(next_tracks.clone());
+        }
+
+        if let Some(previous_tracks) = previous_tracks {
+            room.previous_tracks = Some(previous_tracks.clone());
+        }
+
+        Self::attributed_queue(Some(&room), next_tracks)
+    }
+
+    fn attributed_queue(
+        room: Option<&Room>,
+        next_tracks: Option<&SpotifyTackArray>,
+    ) -> Vec<command_response::QueuedTrack> {
+        let (Some(room), Some(next_tracks)) = (room, next_tracks) else {
+            return Vec::new();
+        };
+        let submitters =
+            room.queue_submitters(next_tracks.iter().map(|track| track.track_id.as_str()));
+
+        next_tracks
+            .iter()
+            .zip(submitters)
+            .map(|(track, submitter)| command_response::QueuedTrack {
+                track: Some(track.clone().into()),
+                user_id: submitter.map(|user| user.id.clone()).unwrap_or_default(),
+                username: submitter
+                    .map(|user| user.username.clone())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
     async fn fetch_spotify_playback(
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
-    ) -> Result<CommandResponse, SpotifyError> {
+        batch: &mut RoomUpdate,
+    ) -> Result<CommandResponse, ProviderError> {
         let mut rate_limit = None;
-        let mut guard = state_mgr.write().await;
-        let Some(room) = guard.get_room_mut(&room_id) else {
-            return Err(SpotifyError::Generic("Room not found".into()));
-        };
+        let provider = Self::room_music_provider(&state_mgr, room_id).await?;
 
-        let state = room.spotify_handler.get_current_playback_state().await;
+        let state = provider.now_playing().await;
 
         if let Err(ref err) = state {
             error!(
@@ -812,154 +2027,534 @@ impl SharifyWsInstance {
                 String::from(err.clone())
             );
 
-            if let SpotifyError::RateLimited(time) = err {
+            if let ProviderError::RateLimited(time) = err {
                 rate_limit = Some(time);
             }
         }
 
         if let Some(time) = rate_limit {
-            let cmd = CommandResponse {
+            batch.push(CommandResponse {
                 r#type: Some(command_response::Type::SpotifyRateLimited(*time)),
+                ..Default::default()
+            });
+        }
+
+        if let Ok(ref playback) = state {
+            Self::apply_playback_state(
+                &state_mgr,
+                room_id,
+                provider.as_ref(),
+                playback.as_ref(),
+                None,
+                None,
+                batch,
+            )
+            .await;
+        }
+
+        // The clients keep the last playback instead of an empty one, see init_spotify_data_loop
+        let state = state?;
+
+        Ok(CommandResponse {
+            r#type: Some(command_response::Type::SpotifyPlaybackState(
+                command_response::SpotifyPlaybackState {
+                    state: state.map(Into::into),
+                },
+            )),
+            ..Default::default()
+        })
+    }
+
+    /// See Room::music_provider
+    async fn room_music_provider(
+        state_mgr: &Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+    ) -> Result<Box<dyn MusicProvider>, ProviderError> {
+        state_mgr
+            .read()
+            .await
+            .get_room(&room_id)
+            .map(|room| room.music_provider())
+            .ok_or(ProviderError::Generic("Room not found".into()))
+    }
+
+    /// Applies the fetched playback to the room then makes the Spotify requests it leads to
+    /// (skip, transition prefetch, queue push) once the room is released
+    ///
+    /// `paused_tick` resets the Spotify data loop sleeper when nothing is playing and the updates
+    /// to broadcast are added to the batch of the fetched state
+    async fn apply_playback_state(
+        state_mgr: &Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        provider: &dyn MusicProvider,
+        playback: Option<&SpotifyCurrentPlaybackOutput>,
+        next_tracks: Option<&SpotifyTackArray>,
+        paused_tick: Option<Duration>,
+        batch: &mut RoomUpdate,
+    ) {
+        let (
+            album_image_changed,
+            track_changed,
+            transition_seq,
+            skip,
+            tick,
+            tick_tx,
+            has_listeners,
+        ) = {
+            let guard = state_mgr.read().await;
+            let Some(mut room) = guard.lock_room(&room_id) else {
+                return;
             };
 
-            let mut buf = Vec::new();
+            let track_changed = playback.is_some_and(|playback| {
+                room.now_playing
+                    .as_ref()
+                    .is_none_or(|now_playing| now_playing.track_id != playback.track_id)
+            });
+            room.record_listening_time(guard.clock().now());
+            let album_image_changed = room.set_now_playing(playback.cloned());
+            room.now_playing_at = Some(guard.clock().now());
+            let transition_seq = room.next_track_transition_seq();
+            let (skip, tick) = match playback {
+                None => (false, None),
+                Some(playback) if room.take_track_to_skip(&playback.track_id) => {
+                    room.playback_scheduler
+                        .expect_track_change(&playback.track_id);
+
+                    (true, Some(Duration::from_millis(spotify::FETCH_OFFSET_MS)))
+                }
+                Some(playback) => match playback.remaining_ms() {
+                    Some(rest_ms) => (
+                        false,
+                        Some(
+                            room.playback_scheduler
+                                .next_delay(&playback.track_id, rest_ms),
+                        ),
+                    ),
+                    // Playtrack is not playing
+                    None => {
+                        room.playback_scheduler.reset();
+
+                        (false, paused_tick)
+                    }
+                },
+            };
 
-            cmd.encode(&mut buf).unwrap();
+            (
+                album_image_changed,
+                track_changed,
+                transition_seq,
+                skip,
+                tick,
+                room.spotify_tick_tx(),
+                !room.listeners.is_empty(),
+            )
+        };
 
-            Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+        // DJ mode turns last a number of tracks
+        if track_changed && state_mgr.read().await.record_dj_track(room_id) {
+            Self::schedule_dj_turns(Arc::clone(state_mgr), room_id);
+            Self::send_room_data_in_room(Arc::clone(state_mgr), room_id).await;
         }
 
-        if let Ok(Some(ref playback)) = state {
-            if playback.is_playing
-                && let Some(progress_ms) = playback.progress_ms
-            {
-                let mut rest_ms = playback.duration_ms - progress_ms;
+        let Some(playback) = playback else {
+            return;
+        };
 
-                // If there's more than 2min left, add a fetch in the middle to keep sync with an
-                // external spotify client/player
-                if rest_ms > 1000 * 60 * 2 {
-                    rest_ms /= 2;
+        // The devices of the listeners follow the room playback, on their own accounts
+        if has_listeners {
+            let state_mgr = Arc::clone(state_mgr);
+
+            spawn_room_task(async move {
+                if let Err(err) = RoomManager::sync_listeners(&state_mgr, room_id, None).await {
+                    debug!(
+                        "[{room_id}] Failed to sync the listeners: {}",
+                        String::from(err)
+                    );
                 }
+            });
+        }
 
-                room.set_spotify_tick(Duration::from_millis(rest_ms + spotify::FETCH_OFFSET_MS))
-                    .await;
-            }
+        if album_image_changed {
+            batch.push(Self::album_image_changed_response(playback));
+        }
+
+        if skip {
+            Self::skip_removed_track(room_id, provider).await;
+        } else if let Some(rest_ms) = playback.remaining_ms() {
+            Self::schedule_track_transition(
+                Arc::clone(state_mgr),
+                room_id,
+                provider,
+                playback,
+                rest_ms,
+                transition_seq,
+                next_tracks,
+            )
+            .await;
+        }
 
-            let _ = guard.remove_track_from_queue(room_id, playback.track_id.clone());
+        if let Some(tick) = tick {
+            Self::set_spotify_tick(tick_tx, tick).await;
         }
 
-        Ok(CommandResponse {
-            r#type: Some(command_response::Type::SpotifyPlaybackState(
-                command_response::SpotifyPlaybackState {
-                    state: state.map(|v| v.map(Into::into)).unwrap_or_default(),
+        let is_dequeued = state_mgr
+            .read()
+            .await
+            .remove_track_from_queue(room_id, playback.track_id.clone())
+            .unwrap_or_default();
+
+        // In fair queue mode, the next track is pushed once the previous one started
+        if let Err(err) = RoomManager::push_queued_tracks(state_mgr, room_id).await {
+            error!(
+                "Failed to push the queued tracks of room {room_id}: {}",
+                String::from(err)
+            );
+        }
+
+        if is_dequeued {
+            Self::push_room_update(state_mgr, room_id, batch).await;
+        }
+    }
+
+    /// Resets the sleeper of the Spotify data loop, see RoomMetadata::spotify_tick_tx
+    async fn set_spotify_tick(tick_tx: Option<mpsc::Sender<Duration>>, tick: Duration) {
+        let Some(tick_tx) = tick_tx else {
+            error!(
+                "Unreachable error: Trying to set the sleep duration when it has not been (yet) defined"
+            );
+
+            return;
+        };
+
+        if let Err(err) = tick_tx.send(tick).await {
+            error!("An error occured while trying to send the new tick {err}");
+        }
+    }
+
+    /// Lets the clients reload the cover only instead of waiting for a full state
+    fn album_image_changed_response(playback: &SpotifyCurrentPlaybackOutput) -> CommandResponse {
+        CommandResponse {
+            r#type: Some(command_response::Type::AlbumImageChanged(
+                command_response::AlbumImageChanged {
+                    track_id: playback.track_id.clone(),
+                    album_image_src: playback.album_image_src.clone(),
+                    album_image_rev: image_revision(&playback.album_image_src),
                 },
             )),
-        })
+            ..Default::default()
+        }
     }
 
-    async fn send_room_data_in_room(
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+    /// When the current track ends before the next scheduled fetch, prefetches the queue head
+    /// (unless already fetched) and broadcasts a TrackTransition right at the end of the track
+    /// so clients don't show an empty player until the next fetch
+    async fn schedule_track_transition(
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
+        provider: &dyn MusicProvider,
+        playback: &SpotifyCurrentPlaybackOutput,
+        rest_ms: u64,
+        seq: u64,
+        next_tracks: Option<&SpotifyTackArray>,
     ) {
-        let mut buf = Vec::new();
+        if rest_ms > spotify::TRACK_TRANSITION_PREFETCH_MS {
+            return;
+        }
 
-        let cmd = CommandResponse {
-            r#type: Some(match state_mgr.write().await.get_room_mut(&room_id) {
-                None => command_response::Type::RoomError(
-                    // TODO Unreachable ?
+        let starting = match next_tracks {
+            Some(next_tracks) => next_tracks.first().cloned(),
+            None => provider
+                .next_tracks()
+                .await
+                .ok()
+                .and_then(|next_tracks| next_tracks.into_iter().next()),
+        };
+
+        let transition = command_response::TrackTransition {
+            ended: Some(playback.to_track().into()),
+            starting: starting.map(Into::into),
+        };
+
+        spawn_room_task(async move {
+            time::sleep(Duration::from_millis(rest_ms)).await;
+
+            // The playback changed (pause, skip, seek...) since it was scheduled
+            if !state_mgr
+                .read()
+                .await
+                .get_room(&room_id)
+                .is_some_and(|room| room.is_track_transition_current(seq))
+            {
+                return;
+            }
+
+            let cmd = CommandResponse {
+                r#type: Some(command_response::Type::TrackTransition(transition)),
+                ..Default::default()
+            };
+
+            Self::send_in_room(room_id, cmd);
+        });
+    }
+
+    /// Broadcasts the current DJ turn and gives the turn to the next member at its end, until
+    /// nobody is opted in or the turn is changed by a command, which schedules its own turns
+    fn schedule_dj_turns(state_mgr: Arc<RwLock<RoomManager>>, room_id: RoomID) {
+        spawn_room_task(async move {
+            loop {
+                let Some(turn) = state_mgr.read().await.take_unscheduled_dj_turn(room_id) else {
+                    return;
+                };
+
+                debug!(
+                    "[{room_id}] DJ turn of {}",
+                    decode_user_email(&turn.user_id)
+                );
+
+                let cmd = CommandResponse {
+                    r#type: Some(command_response::Type::DjTurn(command_response::DjTurn {
+                        user_id: turn.user_id,
+                        ends_at: turn.ends_at.map(|ends_at| crate::proto::Timestamp {
+                            seconds: ends_at.timestamp(),
+                            nanos: ends_at.timestamp_subsec_nanos() as _,
+                        }),
+                        turn_tracks: turn.turn_tracks.unwrap_or_default(),
+                    })),
+                    ..Default::default()
+                };
+
+                Self::send_in_room(room_id, cmd);
+
+                // DJ mode, the turn is given on the track changes, see apply_playback_state
+                let Some(remaining) = turn.remaining else {
+                    return;
+                };
+
+                time::sleep(remaining).await;
+
+                if !state_mgr.read().await.end_dj_turn(room_id, turn.seq) {
+                    return;
+                }
+
+                Self::send_room_data_in_room(Arc::clone(&state_mgr), room_id).await;
+            }
+        });
+    }
+
+    /// The track has been removed from the room queue but was still in the Spotify one
+    async fn skip_removed_track(room_id: RoomID, provider: &dyn MusicProvider) {
+        debug!("[{room_id}] Skipping a track removed from the queue");
+
+        if let Err(err) = provider.skip_next().await {
+            error!(
+                "Failed to skip removed track for room {room_id}: {}",
+                String::from(err)
+            );
+        }
+    }
+
+    async fn send_room_data_in_room(state_mgr: Arc<RwLock<RoomManager>>, room_id: RoomID) {
+        let mut update = RoomUpdate::default();
+
+        Self::push_room_update(&state_mgr, room_id, &mut update).await;
+
+        if !update.is_empty() {
+            Self::broadcast_in_room(room_id, None, update);
+        }
+    }
+
+    /// See Room::take_room_update, nothing is pushed when the room didn't change
+    ///
+    /// The queue positions are only taken along with a room update since the queue is part of it
+    async fn push_room_update(
+        state_mgr: &Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        update: &mut RoomUpdate,
+    ) {
+        let state_guard = state_mgr.read().await;
+        let Some(mut room) = state_guard.lock_room(&room_id) else {
+            update.push(CommandResponse {
+                // TODO Unreachable ?
+                r#type: Some(command_response::Type::RoomError(
                     RoomError::RoomNotFound.into(),
-                ),
-                Some(room) => command_response::Type::Room(room.clone().into()),
-            }),
+                )),
+                ..Default::default()
+            });
+
+            return;
         };
 
-        cmd.encode(&mut buf).unwrap();
+        if let Some((room_update, room_delta)) = room.take_room_update() {
+            update.push_room(room_update, room_delta);
+            update.push_queue_positions(room.take_queue_position_changes());
+        }
+    }
+
+    async fn send_presence_in_room(room_id: RoomID, presence: command_response::Type) {
+        let cmd = CommandResponse {
+            r#type: Some(presence),
+            ..Default::default()
+        };
 
-        Self::send_in_room(Arc::clone(&ws_mgr), room_id, buf).await;
+        Self::send_in_room(room_id, cmd);
     }
 
-    /// Returns false when session is closed and has been removed
+    /// Sends the protobuf encoded CommandResponse in the session encoding
+    ///
+    /// Returns false when session is closed, its main loop then disconnects it from the room
+    /// (removing it here would leave the user flagged connected)
     async fn send_binary(
         session: &mut Session,
+        encoding: SessionEncoding,
         user_id: &RoomUserID,
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
         buf: impl Into<web::Bytes>,
     ) -> bool {
-        if session.binary(buf).await.is_err() {
-            ws_mgr.write().await.remove(user_id);
-            return false;
+        match encoding.encode(buf.into()) {
+            Ok(frame) => Self::send_frame(session, frame).await,
+            Err(err) => {
+                error!("Failed to encode a CommandResponse for user {user_id}: {err}");
+
+                true
+            }
         }
+    }
 
-        true
+    /// Same as send_binary with a frame already in the session encoding
+    async fn send_frame(session: &mut Session, frame: SessionFrame) -> bool {
+        let sent = match frame {
+            SessionFrame::Binary(bytes) => session.binary(bytes).await,
+            SessionFrame::Text(text) => session.text(text).await,
+        };
+
+        sent.is_ok()
     }
 
-    async fn send_in_room(
-        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+    fn send_in_room(room_id: RoomID, cmd: CommandResponse) {
+        Self::send_in_room_except(room_id, None, cmd);
+    }
+
+    /// Same as send_in_room but skips the session of except_user_id
+    fn send_in_room_except(
         room_id: RoomID,
-        buf: impl Into<web::Bytes> + Clone,
+        except_user_id: Option<&RoomUserID>,
+        cmd: CommandResponse,
     ) {
-        let ws_guard = ws_mgr.read().await;
+        Self::broadcast_in_room(room_id, except_user_id, RoomUpdate::new(vec![cmd]));
+    }
 
-        let room_users = ws_guard
-            .iter()
-            .filter_map(|(id, instance)| {
-                if instance.room_id == room_id {
-                    Some((id.clone(), instance.session.clone()))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    /// The responses are queued on the room channel as one frame (batched for the sessions that
+    /// negotiated it) and sent by each session's main loop, see fanout
+    fn broadcast_in_room(
+        room_id: RoomID,
+        except_user_id: Option<&RoomUserID>,
+        mut update: RoomUpdate,
+    ) {
+        let queue_positions = update.take_queue_positions();
 
-        drop(ws_guard);
+        events::broadcast(room_id, update.responses());
 
-        for (room_user_id, mut session) in room_users {
-            Self::send_binary(
-                &mut session,
-                &room_user_id,
-                Arc::clone(&ws_mgr),
-                buf.clone().into(),
-            )
-            .await;
+        fanout::send(
+            room_id,
+            RoomFrame::Broadcast {
+                update: Arc::new(update),
+                except: except_user_id.cloned(),
+            },
+        );
+
+        // After the update that moved them
+        if let Some(frame) = queue_positions {
+            fanout::send(room_id, frame);
+        }
+    }
+
+    async fn send_to_room_owners(
+        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+        state_mgr: Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        cmd: CommandResponse,
+    ) {
+        let owners_id = state_mgr
+            .read()
+            .await
+            .get_room(&room_id)
+            .map(|room| {
+                room.users
+                    .iter()
+                    .filter(|user| {
+                        room.role_manager
+                            .get_role_by_id(&user.role_id)
+                            .is_some_and(|role| role.permissions.can_manage_room)
+                    })
+                    .map(|user| user.id.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        for owner_id in owners_id {
+            SharifyWsManager::send_to_user(&ws_mgr, room_id, &owner_id, cmd.clone()).await;
         }
     }
 
+    /// When instance_id is set, the session is only closed if it's still the user's current one
     async fn close_session(
         ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         user_id: RoomUserID,
+        instance_id: Option<Uuid>,
         reason: Option<CloseReason>,
     ) {
+        let Some(instance) = Self::disconnect(&ws_mgr, &state_mgr, &user_id, instance_id).await
+        else {
+            return;
+        };
+
         debug!(
+            correlation_id = %instance.correlation_id,
             "[WS] Closing session email:{}, id:{}",
             decode_user_email(&user_id),
             user_id,
         );
 
-        let Some(SharifyWsInstance {
-            ref session,
-            room_id,
-            ..
-        }) = ws_mgr.write().await.remove(&user_id)
-        else {
-            return;
-        };
-
-        let _ = session.clone().close(reason).await;
-
-        let _ = state_mgr
-            .write()
-            .await
-            .set_ws_user_state(room_id, &user_id, false);
+        instance.close(reason).await;
     }
 
-    async fn close_room(
+    pub async fn close_room(
         ws_mgr: Arc<RwLock<SharifyWsManager>>,
         state_mgr: Arc<RwLock<RoomManager>>,
         room_id: RoomID,
-        reason: Option<String>,
+        reason: CloseReason,
     ) {
+        // Final listening stats and session summary, broadcasted right before the sessions are
+        // closed
+        let final_update = {
+            let guard = state_mgr.read().await;
+            // New WS sessions are rejected from now on
+            let _ = guard.begin_room_closing(room_id);
+
+            let stats = guard
+                .get_room(&room_id)
+                .map(|room| command_response::Type::ListeningStats((&room.listening_stats).into()));
+            let summary = guard
+                .session_summary(room_id)
+                .map(|summary| command_response::Type::SessionSummary(summary.into()));
+
+            RoomUpdate::new(
+                stats
+                    .into_iter()
+                    .chain(summary)
+                    .map(|r#type| CommandResponse {
+                        r#type: Some(r#type),
+                        ..Default::default()
+                    })
+                    .collect(),
+            )
+        };
+
+        // The event streams end once the room is deleted, see events::init_stream_cleanup
+        events::broadcast(room_id, final_update.responses());
+        fanout::close_room(room_id);
+
         let mut ws_guard = ws_mgr.write().await;
 
         let room_users_id = ws_guard
@@ -974,17 +2569,72 @@ impl SharifyWsInstance {
             .collect::<Vec<_>>();
 
         for room_user_id in room_users_id {
-            if let Some(instance) = ws_guard.remove(&room_user_id) {
-                let _ = instance
-                    .session
-                    .close(Some(CloseReason {
-                        code: CloseCode::Normal,
-                        description: reason.clone(),
-                    }))
-                    .await;
+            if let Some(mut instance) = ws_guard.remove(&room_user_id) {
+                // Sent right away since the room channel is closed
+                for frame in final_update.frames_for(instance.encoding).iter() {
+                    Self::send_frame(&mut instance.session, frame.clone()).await;
+                }
+
+                let _ = instance.session.close(Some(reason.clone())).await;
             }
         }
 
         let _ = state_mgr.write().await.delete_room(room_id, None);
     }
+
+    /// Kick issued through the admin API, the user gets the Kick like a regular one and the
+    /// room members the updated room, or the room is closed when it was its last owner and
+    /// nobody takes it over
+    pub async fn admin_kick_user(
+        ws_mgr: Arc<RwLock<SharifyWsManager>>,
+        state_mgr: Arc<RwLock<RoomManager>>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+        reason: String,
+    ) -> Result<(), RoomError> {
+        let (should_room_be_closed, _) =
+            Self::last_owner_removal(&state_mgr, room_id, user_id).await;
+
+        state_mgr
+            .write()
+            .await
+            .admin_kick_user(room_id, user_id, reason.clone())?;
+
+        Self::notify_removed_user(Arc::clone(&ws_mgr), room_id, user_id, reason, false).await;
+
+        if should_room_be_closed {
+            Self::close_room(
+                ws_mgr,
+                state_mgr,
+                room_id,
+                WsCloseCode::NoOwnerLeft.reason("No owner left to manage the room, closing..."),
+            )
+            .await;
+
+            return Ok(());
+        }
+
+        Self::send_room_data_in_room(state_mgr, room_id).await;
+
+        Ok(())
+    }
+
+    /// Whether removing the user closes the room and whether it hands the owner role over, see
+    /// RoomManager::fallback_owner_on_leave
+    async fn last_owner_removal(
+        state_mgr: &RwLock<RoomManager>,
+        room_id: RoomID,
+        user_id: &RoomUserID,
+    ) -> (bool, bool) {
+        let state_guard = state_mgr.read().await;
+        let is_last_owner = state_guard
+            .is_user_an_owner_and_alone(room_id, user_id)
+            .is_ok_and(|b| b);
+        let has_fallback_owner = is_last_owner
+            && state_guard
+                .fallback_owner_on_leave(room_id, user_id)
+                .is_some();
+
+        (is_last_owner && !has_fallback_owner, has_fallback_owner)
+    }
 }
@@ -1,4 +1,6 @@
 pub mod commands;
+pub mod events;
+pub mod fanout;
 mod instance;
 
 pub use instance::*;
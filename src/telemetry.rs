@@ -0,0 +1,81 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::util::SubscriberInitExt as _;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
+
+/// Used when LOG is unset or invalid
+const DEFAULT_LOG_FILTER: &str = "debug";
+
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Output of the logs, see LOG_FORMAT
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line with the fields of the current spans, for log aggregation
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+fn env_filter(directives: &str) -> EnvFilter {
+    EnvFilter::try_new(directives).unwrap_or_else(|err| {
+        eprintln!("Invalid LOG filter {directives:?}, defaulting to {DEFAULT_LOG_FILTER}: {err}");
+
+        EnvFilter::new(DEFAULT_LOG_FILTER)
+    })
+}
+
+/// Installs the tracing subscriber, the `log` records (actix, reqwest...) are forwarded to it
+///
+/// The rooms tasks and WS sessions run in spans carrying their room_id and user_id, the
+/// commands in a nested one carrying their name and the HTTP requests in one carrying their
+/// correlation_id
+pub fn init(directives: &str, format: LogFormat) {
+    let (filter, handle) = reload::Layer::new(env_filter(directives));
+    let registry = tracing_subscriber::registry().with(filter);
+
+    let result = match format {
+        LogFormat::Text => registry.with(fmt::layer()).try_init(),
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .try_init(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Failed to install the tracing subscriber: {err}");
+
+        return;
+    }
+
+    let _ = FILTER_HANDLE.set(handle);
+}
+
+/// Swaps the filter of the subscriber, see config::reload
+pub fn set_filter(directives: &str) {
+    let Some(handle) = FILTER_HANDLE.get() else {
+        return;
+    };
+
+    if let Err(err) = handle.reload(env_filter(directives)) {
+        error!("Failed to reload the log filter: {err}");
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::ServerHandle;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+
+use crate::sharify::spotify::SpotifyBaseUrls;
+
+/// Prefix of the Web API routes, the accounts ones are served at the root
+const API_PREFIX: &str = "/v1";
+
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub body: String,
+    /// Retry-After header, in seconds
+    pub retry_after: Option<u64>,
+}
+
+impl MockResponse {
+    pub fn json(body: serde_json::Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            body: body.to_string(),
+            retry_after: None,
+        }
+    }
+
+    pub fn status(status: StatusCode) -> Self {
+        Self {
+            status,
+            body: String::new(),
+            retry_after: None,
+        }
+    }
+
+    pub fn rate_limited(retry_after: u64) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::status(StatusCode::TOO_MANY_REQUESTS)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Without the API prefix for the Web API routes, e.g. /me/player
+    pub path: String,
+    pub query: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    /// The last response of a route is repeated once the others are consumed
+    responses: HashMap<(Method, String), VecDeque<MockResponse>>,
+    requests: Vec<RecordedRequest>,
+}
+
+/// Spotify Web API and accounts service stand-in bound to a random port, the routes answer
+/// the responses queued with `respond` and 404 otherwise
+pub struct MockSpotify {
+    pub base_urls: SpotifyBaseUrls,
+    state: Arc<Mutex<MockState>>,
+    handle: ServerHandle,
+}
+
+impl MockSpotify {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let app_state = Arc::clone(&state);
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::from(Arc::clone(&app_state)))
+                .default_service(web::to(handle_request))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .expect("Failed to bind the mock Spotify server");
+
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+
+        actix_rt::spawn(server);
+
+        Self {
+            base_urls: SpotifyBaseUrls {
+                api: format!("http://127.0.0.1:{port}{API_PREFIX}"),
+                accounts: format!("http://127.0.0.1:{port}"),
+            },
+            state,
+            handle,
+        }
+    }
+
+    /// Queues the responses of a route, path being one of spotify::web_utils::endpoints
+    pub fn respond(&self, method: Method, path: &str, responses: Vec<MockResponse>) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry((method, path.to_owned()))
+            .or_default()
+            .extend(responses);
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Requests received by a route
+    pub fn hits(&self, method: Method, path: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|req| req.method == method && req.path == path)
+            .count()
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+    }
+}
+
+async fn handle_request(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<Mutex<MockState>>,
+) -> HttpResponse {
+    let path = req
+        .path()
+        .strip_prefix(API_PREFIX)
+        .unwrap_or(req.path())
+        .trim_end_matches('/')
+        .to_owned();
+    let mut state = state.lock().unwrap();
+
+    state.requests.push(RecordedRequest {
+        method: req.method().clone(),
+        path: path.clone(),
+        query: req.query_string().to_owned(),
+        authorization: req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+
+    let Some(queue) = state.responses.get_mut(&(req.method().clone(), path)) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let response = if queue.len() > 1 {
+        queue.pop_front()
+    } else {
+        queue.front().cloned()
+    };
+
+    let Some(response) = response else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let mut builder = HttpResponse::build(response.status);
+
+    if let Some(retry_after) = response.retry_after {
+        builder.insert_header(("Retry-After", retry_after.to_string()));
+    }
+
+    if response.body.is_empty() {
+        return builder.finish();
+    }
+
+    builder.content_type("application/json").body(response.body)
+}
@@ -1,2 +1,4 @@
+pub mod mock_spotify;
+pub mod spotify;
 pub mod unit;
 pub mod websocket;
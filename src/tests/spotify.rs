@@ -0,0 +1,899 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::{Method, StatusCode};
+use serde_json::json;
+use tokio::sync::RwLock;
+
+use super::mock_spotify::{MockResponse, MockSpotify};
+use crate::proto::cmd::{command, command_response};
+use crate::sharify::clock::MockClock;
+use crate::sharify::music_provider::ProviderError;
+use crate::sharify::random::SeededRandom;
+use crate::sharify::role::RoleContent;
+use crate::sharify::room::{RoomID, SURPRISE_ME_COOLDOWN, push_tracks};
+use crate::sharify::room_manager::RoomManager;
+use crate::sharify::spotify::web_utils::endpoints::*;
+use crate::sharify::spotify::web_utils::{PlaybackItemType, SpotifyCurrentPlaybackOutput};
+use crate::sharify::spotify::{Spotify, SpotifyBaseUrls, SpotifyError, SpotifyTokens, Timestamp};
+use crate::sharify::utils::{SPOTIFY_FETCH_PLAYBACK, SPOTIFY_FETCH_TRACKS_Q};
+use crate::sharify::websocket::commands::Command as WSCmd;
+use crate::sharify::websocket::{SharifyWsInstance, SharifyWsManager};
+
+/// The access token is the key of the shared Spotify responses cache so each test has its own
+fn mock_spotify_handler(mock: &MockSpotify, access_token: &str) -> Spotify {
+    let tokens =
+        SpotifyTokens::new(access_token, "refresh token", 3600, Timestamp::from(0)).unwrap();
+
+    let mut spotify = Spotify::new(tokens, Arc::new(MockClock::default()));
+    spotify.base_urls = Arc::new(mock.base_urls.clone());
+
+    spotify
+}
+
+fn playback_state(track_id: &str) -> serde_json::Value {
+    json!({
+        "device": { "id": "device", "volume_percent": 50 },
+        "progress_ms": 1000,
+        "is_playing": true,
+        "item": {
+            "type": "track",
+            "id": track_id,
+            "uri": format!("spotify:track:{track_id}"),
+            "name": "Track",
+            "artists": [{ "name": "Artist" }],
+            "duration_ms": 180000,
+        },
+    })
+}
+
+#[test]
+fn spotify_base_urls_default_to_spotify() {
+    assert_eq!(SpotifyBaseUrls::new(None, None), SpotifyBaseUrls::default());
+    assert_eq!(
+        SpotifyBaseUrls::new(Some(" ".into()), None).api,
+        DEFAULT_API_BASE_URL
+    );
+
+    let proxied = SpotifyBaseUrls::new(
+        Some("http://proxy.local/spotify/v1/".into()),
+        Some("http://proxy.local/accounts".into()),
+    );
+    assert_eq!(
+        proxied.api_url(CURRENT_PLAYBACK_STATE),
+        "http://proxy.local/spotify/v1/me/player"
+    );
+    assert_eq!(
+        proxied.accounts_url(TOKEN),
+        "http://proxy.local/accounts/api/token"
+    );
+    assert_eq!(
+        SpotifyBaseUrls::default().accounts_url(TOKEN),
+        "https://accounts.spotify.com/api/token"
+    );
+}
+
+#[actix_rt::test]
+async fn playback_is_polled_from_the_spotify_api() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        CURRENT_PLAYBACK_STATE,
+        vec![
+            MockResponse::json(playback_state("track")),
+            MockResponse::status(StatusCode::NO_CONTENT),
+        ],
+    );
+
+    let spotify = mock_spotify_handler(&mock, "playback token");
+
+    let playback = spotify.get_current_playback_state().await.unwrap().unwrap();
+    assert_eq!(playback.track_id, "track");
+    assert_eq!(playback.progress_ms, Some(1000));
+    assert!(playback.is_playing);
+
+    // Served from the cache until a mutation
+    spotify.get_current_playback_state().await.unwrap();
+    assert_eq!(mock.hits(Method::GET, CURRENT_PLAYBACK_STATE), 1);
+    assert_eq!(
+        mock.requests()[0].authorization.as_deref(),
+        Some("Bearer playback token")
+    );
+
+    // Nothing playing once the cached response is invalidated
+    mock.respond(
+        Method::POST,
+        SKIP_NEXT,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+    spotify.skip_next().await.unwrap();
+    assert!(
+        spotify
+            .get_current_playback_state()
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(mock.hits(Method::GET, CURRENT_PLAYBACK_STATE), 2);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn spotify_tokens_are_refreshed_against_the_accounts_service() {
+    crate::config::set_for_tests(|config| config.spotify_client_id = Some("client".into()));
+
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::POST,
+        TOKEN,
+        vec![MockResponse::json(json!({
+            "access_token": "new access token",
+            "refresh_token": "new refresh token",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "scope": "",
+        }))],
+    );
+
+    let mut spotify = mock_spotify_handler(&mock, "refreshed token");
+
+    let tokens = spotify.fetch_refresh_token().await.unwrap();
+    assert_eq!(tokens.access_token.reveal().unwrap(), "new access token");
+    assert_eq!(
+        spotify.tokens.refresh_token.reveal().unwrap(),
+        "new refresh token"
+    );
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].query.contains("grant_type=refresh_token"));
+    assert!(requests[0].query.contains("client_id=client"));
+
+    // The next requests use the new access token
+    mock.respond(
+        Method::POST,
+        SKIP_PREVIOUS,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+    spotify.skip_previous().await.unwrap();
+    assert_eq!(
+        mock.requests()[1].authorization.as_deref(),
+        Some("Bearer new access token")
+    );
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn spotify_rate_limits_are_retried_then_reported() {
+    let mock = MockSpotify::start().await;
+    let spotify = mock_spotify_handler(&mock, "rate limited token");
+
+    // Short Retry-After, awaited then retried
+    mock.respond(
+        Method::PUT,
+        PAUSE,
+        vec![
+            MockResponse::rate_limited(0),
+            MockResponse::status(StatusCode::NO_CONTENT),
+        ],
+    );
+    spotify.pause().await.unwrap();
+    assert_eq!(mock.hits(Method::PUT, PAUSE), 2);
+
+    // Too long to be awaited
+    mock.respond(
+        Method::PUT,
+        PLAY_RESUME,
+        vec![MockResponse::rate_limited(120)],
+    );
+    assert!(matches!(
+        spotify.play_resume().await,
+        Err(SpotifyError::RateLimited(120))
+    ));
+    assert_eq!(mock.hits(Method::PUT, PLAY_RESUME), 1);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn spotify_server_errors_are_only_retried_for_idempotent_requests() {
+    let mock = MockSpotify::start().await;
+    let spotify = mock_spotify_handler(&mock, "server error token");
+
+    mock.respond(
+        Method::PUT,
+        PAUSE,
+        vec![
+            MockResponse::status(StatusCode::BAD_GATEWAY),
+            MockResponse::status(StatusCode::NO_CONTENT),
+        ],
+    );
+    spotify.pause().await.unwrap();
+    assert_eq!(mock.hits(Method::PUT, PAUSE), 2);
+
+    // The track may have been skipped already
+    mock.respond(
+        Method::POST,
+        SKIP_NEXT,
+        vec![
+            MockResponse::status(StatusCode::BAD_GATEWAY),
+            MockResponse::status(StatusCode::NO_CONTENT),
+        ],
+    );
+    assert!(spotify.skip_next().await.is_err());
+    assert_eq!(mock.hits(Method::POST, SKIP_NEXT), 1);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn parked_episodes_are_restored_with_their_uri() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::PUT,
+        PLAY_RESUME,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+
+    let spotify = mock_spotify_handler(&mock, "parked episode token");
+
+    spotify
+        .play_track_at("episode".into(), PlaybackItemType::Episode, 1000)
+        .await
+        .unwrap();
+
+    let body: serde_json::Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+    assert_eq!(body["uris"], json!(["spotify:episode:episode"]));
+    assert_eq!(body["position_ms"], 1000);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn unauthorized_playback_fetches_degrade_then_close_the_room() {
+    let mock = MockSpotify::start().await;
+    for path in [CURRENT_PLAYBACK_STATE, PLAYER_QUEUE, RECENTLY_PLAYED_TRACKS] {
+        mock.respond(
+            Method::GET,
+            path,
+            vec![MockResponse::status(StatusCode::UNAUTHORIZED)],
+        );
+    }
+
+    let clock = Arc::new(MockClock::default());
+    let mut room_manager = RoomManager::new(clock.clone(), Arc::new(SeededRandom::new(0)))
+        .with_spotify_base_urls(mock.base_urls.clone());
+    let room_id = room_manager
+        .create_room(
+            "owner".into(),
+            "Owner".into(),
+            "Room".into(),
+            SpotifyTokens::new("expired token", "refresh token", 3600, Timestamp::from(0)).unwrap(),
+            Default::default(),
+        )
+        .unwrap()
+        .id;
+    let state = Arc::new(RwLock::new(room_manager));
+    let ws_mgr = Arc::new(RwLock::new(SharifyWsManager::default()));
+
+    // Same steps as the room Spotify data loop
+    let mut failures = 0;
+    loop {
+        let err = SharifyWsInstance::send_spotify_state_in_room(
+            Arc::clone(&state),
+            room_id,
+            SPOTIFY_FETCH_PLAYBACK | SPOTIFY_FETCH_TRACKS_Q,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ProviderError::Unauthorized(_)));
+
+        failures += 1;
+
+        let Some(retry_in) = SharifyWsInstance::handle_spotify_outage(
+            Arc::clone(&ws_mgr),
+            Arc::clone(&state),
+            room_id,
+            &err,
+        )
+        .await
+        else {
+            break;
+        };
+
+        clock.advance(retry_in);
+    }
+
+    // Degraded for a while rather than closed on the first 401, which isn't retried
+    assert!(failures > 2);
+    assert_eq!(mock.hits(Method::GET, CURRENT_PLAYBACK_STATE), failures);
+    assert!(state.read().await.get_room(&room_id).is_none());
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn out_of_range_volumes_are_rejected_before_narrowing() {
+    let mock = MockSpotify::start().await;
+
+    let mut room_manager = RoomManager::new(
+        Arc::new(MockClock::default()),
+        Arc::new(SeededRandom::new(0)),
+    );
+    let room_id = room_manager
+        .create_room(
+            "owner".into(),
+            "Owner".into(),
+            "Room".into(),
+            SpotifyTokens::new("volume token", "refresh token", 3600, Timestamp::from(0)).unwrap(),
+            Default::default(),
+        )
+        .unwrap()
+        .id;
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .spotify_handler
+        .base_urls = Arc::new(mock.base_urls.clone());
+
+    let state = Arc::new(RwLock::new(room_manager));
+
+    // Would wrap to 100 as a u8, the owners can't go past the Spotify range either
+    let (result, _) = WSCmd::new(
+        Arc::clone(&state),
+        "owner".into(),
+        room_id,
+        command::Type::SetVolume(356),
+    )
+    .process()
+    .await;
+
+    assert_eq!(
+        result.unwrap_err(),
+        command_response::Type::VolumeOutOfRange(command_response::VolumeOutOfRange {
+            volume: 356,
+            volume_limits: Some(crate::proto::room::VolumeLimits {
+                min_volume: 0,
+                max_volume: 100,
+            }),
+        })
+    );
+    assert_eq!(mock.hits(Method::PUT, SET_VOLUME), 0);
+
+    mock.stop().await;
+}
+
+/// Room whose Spotify handler requests the mock, the access token keys the shared caches
+fn surprise_me_room(mock: &MockSpotify, access_token: &str) -> (Arc<RwLock<RoomManager>>, RoomID) {
+    let mut room_manager = RoomManager::new(
+        Arc::new(MockClock::default()),
+        Arc::new(SeededRandom::new(0)),
+    );
+    let room_id = room_manager
+        .create_room(
+            "owner".into(),
+            "Owner".into(),
+            "Room".into(),
+            SpotifyTokens::new(access_token, "refresh token", 3600, Timestamp::from(0)).unwrap(),
+            Default::default(),
+        )
+        .unwrap()
+        .id;
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .spotify_handler
+        .base_urls = Arc::new(mock.base_urls.clone());
+
+    (Arc::new(RwLock::new(room_manager)), room_id)
+}
+
+#[actix_rt::test]
+async fn queued_tracks_are_pushed_through_the_music_provider() {
+    let mock = MockSpotify::start().await;
+    // The third track is refused, by then the queue is full
+    mock.respond(
+        Method::POST,
+        ADD_TO_QUEUE,
+        vec![
+            MockResponse::status(StatusCode::NO_CONTENT),
+            MockResponse::status(StatusCode::NO_CONTENT),
+            MockResponse::status(StatusCode::BAD_REQUEST),
+        ],
+    );
+
+    let spotify = mock_spotify_handler(&mock, "push token");
+    let tracks = ["a", "b", "c"].map(|id| (id.to_owned(), PlaybackItemType::Track));
+
+    assert!(matches!(
+        push_tracks(&spotify, &tracks).await,
+        Err((ProviderError::Generic(_), 1))
+    ));
+    assert_eq!(
+        mock.requests()
+            .iter()
+            .filter(|req| req.method == Method::POST && req.path == ADD_TO_QUEUE)
+            .map(|req| req.query.clone())
+            .collect::<Vec<_>>(),
+        ["a", "b", "c"].map(|id| format!("uri=spotify%3Atrack%3A{id}"))
+    );
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn surprise_me_tracks_are_pushed_once() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        TOP_TRACKS,
+        vec![MockResponse::json(json!({
+            "items": [{
+                "id": "surprise",
+                "uri": "spotify:track:surprise",
+                "name": "Surprise",
+                "artists": [{ "name": "Artist" }],
+                "duration_ms": 180000,
+                "popularity": 50,
+            }],
+        }))],
+    );
+    mock.respond(
+        Method::POST,
+        ADD_TO_QUEUE,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+    mock.respond(
+        Method::GET,
+        &format!("{TRACKS}/queued"),
+        vec![MockResponse::json(json!({
+            "id": "queued",
+            "uri": "spotify:track:queued",
+            "name": "Queued",
+            "duration_ms": 180000,
+        }))],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "surprise token");
+
+    for cmd_type in [
+        command::Type::SurpriseMe(Default::default()),
+        command::Type::AddToQueue(command::AddTrackToQueue {
+            track_id: "queued".into(),
+            track_name: "Queued".into(),
+            track_duration: 180000,
+            ..Default::default()
+        }),
+    ] {
+        let (result, _) = WSCmd::new(Arc::clone(&state), "owner".into(), room_id, cmd_type)
+            .process()
+            .await;
+
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    // The SurpriseMe track isn't pushed again with the next queued one
+    assert_eq!(mock.hits(Method::POST, ADD_TO_QUEUE), 2);
+    assert_eq!(
+        state
+            .read()
+            .await
+            .get_room(&room_id)
+            .unwrap()
+            .tracks_queue
+            .len(),
+        2
+    );
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn queued_tracks_are_checked_with_their_spotify_duration() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        &format!("{TRACKS}/long"),
+        vec![MockResponse::json(json!({
+            "id": "long",
+            "uri": "spotify:track:long",
+            "name": "Long",
+            "duration_ms": 600000,
+        }))],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "duration token");
+    state
+        .write()
+        .await
+        .get_room_mut(&room_id)
+        .unwrap()
+        .settings
+        .max_track_duration = Some(Duration::from_secs(300));
+
+    // The client claims a short track, the one Spotify knows is too long
+    for _ in 0..2 {
+        let (result, _) = WSCmd::new(
+            Arc::clone(&state),
+            "owner".into(),
+            room_id,
+            command::Type::AddToQueue(command::AddTrackToQueue {
+                track_id: "long".into(),
+                track_name: "Long".into(),
+                track_duration: 1000,
+                ..Default::default()
+            }),
+        )
+        .process()
+        .await;
+
+        assert!(
+            matches!(result, Err(command_response::Type::TrackTooLong(_))),
+            "{result:?}"
+        );
+    }
+
+    // Looked up once
+    assert_eq!(mock.hits(Method::GET, &format!("{TRACKS}/long")), 1);
+    assert_eq!(mock.hits(Method::POST, ADD_TO_QUEUE), 0);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn role_content_is_checked_with_the_spotify_duration() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        &format!("{TRACKS}/role-long"),
+        vec![MockResponse::json(json!({
+            "id": "role-long",
+            "uri": "spotify:track:role-long",
+            "name": "Long",
+            "duration_ms": 600000,
+        }))],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "role duration token");
+    {
+        let mut guard = state.write().await;
+        let room = guard.get_room_mut(&room_id).unwrap();
+        let role_id = room.users[0].role_id;
+
+        room.role_manager.set_role_content(
+            role_id,
+            RoleContent {
+                max_track_duration: Some(Duration::from_secs(300)),
+                deny_episodes: false,
+            },
+        );
+    }
+
+    let (result, _) = WSCmd::new(
+        Arc::clone(&state),
+        "owner".into(),
+        room_id,
+        command::Type::AddToQueue(command::AddTrackToQueue {
+            track_id: "role-long".into(),
+            track_name: "Long".into(),
+            track_duration: 1000,
+            ..Default::default()
+        }),
+    )
+    .process()
+    .await;
+
+    assert!(
+        matches!(
+            result,
+            Err(command_response::Type::TrackTooLong(
+                command_response::TrackTooLong {
+                    track_duration_ms: 600000,
+                    ..
+                }
+            ))
+        ),
+        "{result:?}"
+    );
+    assert_eq!(mock.hits(Method::POST, ADD_TO_QUEUE), 0);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn queued_tracks_are_checked_with_their_spotify_explicit_flag() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        &format!("{TRACKS}/explicit"),
+        vec![MockResponse::json(json!({
+            "id": "explicit",
+            "uri": "spotify:track:explicit",
+            "name": "Explicit",
+            "duration_ms": 180000,
+            "explicit": true,
+        }))],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "explicit token");
+    state
+        .write()
+        .await
+        .get_room_mut(&room_id)
+        .unwrap()
+        .settings
+        .allow_explicit = false;
+
+    let (result, _) = WSCmd::new(
+        Arc::clone(&state),
+        "owner".into(),
+        room_id,
+        command::Type::AddToQueue(command::AddTrackToQueue {
+            track_id: "explicit".into(),
+            track_name: "Explicit".into(),
+            track_duration: 180000,
+            explicit: false,
+            ..Default::default()
+        }),
+    )
+    .process()
+    .await;
+
+    assert!(
+        matches!(result, Err(command_response::Type::ExplicitTrackDenied(_))),
+        "{result:?}"
+    );
+    assert_eq!(mock.hits(Method::POST, ADD_TO_QUEUE), 0);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn queued_playlists_only_play_the_allowed_tracks() {
+    let track = |id: &str, duration_ms: u64, explicit: bool| {
+        json!({
+            "track": {
+                "type": "track",
+                "id": id,
+                "uri": format!("spotify:track:{id}"),
+                "name": id,
+                "duration_ms": duration_ms,
+                "explicit": explicit,
+            },
+        })
+    };
+
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        &format!("{PLAYLISTS}/mix/tracks"),
+        vec![MockResponse::json(json!({
+            "items": [
+                track("allowed", 180000, false),
+                track("explicit", 180000, true),
+                track("long", 600000, false),
+                track("spotify:local:Artist:Album:Title:120", 120000, false),
+            ],
+        }))],
+    );
+    mock.respond(
+        Method::PUT,
+        PLAY_RESUME,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "playlist token");
+    {
+        let mut guard = state.write().await;
+        let room = guard.get_room_mut(&room_id).unwrap();
+
+        room.settings.allow_explicit = false;
+        room.settings.max_track_duration = Some(Duration::from_secs(300));
+    }
+
+    let (result, _) = WSCmd::new(
+        Arc::clone(&state),
+        "owner".into(),
+        room_id,
+        command::Type::QueuePlaylist("mix".into()),
+    )
+    .process()
+    .await;
+
+    assert!(result.is_ok(), "{result:?}");
+
+    let requests = mock.requests();
+    let play = requests
+        .iter()
+        .find(|req| req.method == Method::PUT && req.path == PLAY_RESUME)
+        .unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&play.body).unwrap(),
+        json!({ "uris": ["spotify:track:allowed"] })
+    );
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn local_files_are_looked_up_from_their_uri() {
+    let mock = MockSpotify::start().await;
+    let spotify = mock_spotify_handler(&mock, "local token");
+
+    let item = spotify
+        .get_item(
+            "spotify:local:Some+Artist:Album:A+Title:215".into(),
+            PlaybackItemType::Track,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(item.track_name, "A Title");
+    assert_eq!(item.artist_name, "Some Artist");
+    assert_eq!(item.track_duration, 215000);
+    assert!(!item.explicit);
+    assert!(
+        spotify
+            .get_item("spotify:local:broken".into(), PlaybackItemType::Track)
+            .await
+            .is_err()
+    );
+    assert!(mock.requests().is_empty());
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn queue_gaps_are_filled_with_the_tastes_of_the_connected_members() {
+    let track = |id: &str, explicit: bool| {
+        json!({
+            "id": id,
+            "uri": format!("spotify:track:{id}"),
+            "name": id,
+            "duration_ms": 180000,
+            "explicit": explicit,
+        })
+    };
+
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        RECOMMENDATIONS,
+        vec![MockResponse::json(json!({
+            "tracks": [track("seed", false), track("explicit", true), track("fill", false)],
+        }))],
+    );
+    mock.respond(
+        Method::POST,
+        ADD_TO_QUEUE,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "gap token");
+    {
+        let mut guard = state.write().await;
+
+        guard
+            .add_track_to_queue(
+                room_id,
+                "owner".into(),
+                "seed".into(),
+                "Seed".into(),
+                180000,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+
+        let room = guard.get_room_mut(&room_id).unwrap();
+
+        room.users[0].is_connected = true;
+        room.settings.allow_explicit = false;
+        // Played, the queue is empty again
+        room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+            track_id: "seed".into(),
+            track_name: "Seed".into(),
+            ..Default::default()
+        }));
+        room.tracks_queue.clear();
+    }
+
+    // Opt-in
+    assert!(
+        RoomManager::fill_queue_gap(&state, room_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(mock.requests().is_empty());
+
+    state
+        .write()
+        .await
+        .get_room_mut(&room_id)
+        .unwrap()
+        .settings
+        .auto_fill = true;
+
+    let (filled, seed) = RoomManager::fill_queue_gap(&state, room_id)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Neither the known nor the explicit tracks are recommended
+    assert_eq!(filled.track_id, "fill");
+    assert_eq!(seed.explanation(), "because Owner queued Seed");
+    assert_eq!(mock.hits(Method::POST, ADD_TO_QUEUE), 1);
+
+    // Not filled again until GAP_FILL_COOLDOWN
+    assert!(
+        RoomManager::fill_queue_gap(&state, room_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(mock.hits(Method::GET, RECOMMENDATIONS), 1);
+
+    mock.stop().await;
+}
+
+#[actix_rt::test]
+async fn surprise_me_cooldown_is_reserved_until_a_track_is_queued() {
+    let mock = MockSpotify::start().await;
+    mock.respond(
+        Method::GET,
+        TOP_TRACKS,
+        vec![
+            MockResponse::status(StatusCode::BAD_REQUEST),
+            MockResponse::json(json!({
+                "items": [{
+                    "id": "surprise",
+                    "uri": "spotify:track:surprise",
+                    "name": "Surprise",
+                    "artists": [{ "name": "Artist" }],
+                    "duration_ms": 180000,
+                    "popularity": 50,
+                }],
+            })),
+        ],
+    );
+    mock.respond(
+        Method::POST,
+        ADD_TO_QUEUE,
+        vec![MockResponse::status(StatusCode::NO_CONTENT)],
+    );
+
+    let (state, room_id) = surprise_me_room(&mock, "cooldown token");
+    let surprise_me = || {
+        WSCmd::new(
+            Arc::clone(&state),
+            "owner".into(),
+            room_id,
+            command::Type::SurpriseMe(Default::default()),
+        )
+        .process()
+    };
+
+    // Given back when no track got queued
+    assert!(surprise_me().await.0.is_err());
+    assert!(
+        state
+            .read()
+            .await
+            .get_room(&room_id)
+            .unwrap()
+            .surprise_me_cooldowns
+            .is_empty()
+    );
+
+    assert!(surprise_me().await.0.is_ok());
+    assert_eq!(
+        surprise_me().await.0.unwrap_err(),
+        command_response::Type::SurpriseMeCooldown(SURPRISE_ME_COOLDOWN.as_secs())
+    );
+    assert_eq!(mock.hits(Method::GET, TOP_TRACKS), 2);
+
+    mock.stop().await;
+}
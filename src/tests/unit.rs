@@ -1,6 +1,63 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix_web::http::{StatusCode, header};
+use actix_web::web::Bytes;
+use prost::Message as _;
+use rand::SeedableRng as _;
 use regex::Regex;
+use tokio::sync::broadcast::error::TryRecvError;
 
+use crate::discord::{
+    MAX_WEBHOOK_CONTENT_LEN, SendWebhookPayload, WEBHOOK_DEDUP_WINDOW, WEBHOOK_THROTTLE_WINDOW,
+    Webhook, WebhookError, WebhookThrottle, WebhookType,
+};
+use crate::feedback::{FeedbackStatus, FeedbackStore, MAX_STORED_FEEDBACK};
+use crate::proto::WireFormat;
+use crate::proto::cmd::{Command, CommandResponse, command, command_response};
+use crate::sharify::clock::{Clock as _, MockClock};
+use crate::sharify::content_policy::{ContentPolicy, ContentPolicyError, ContentType};
+use crate::sharify::digest::TopContributor;
+use crate::sharify::identity::{self, Identity, IdentityError, IdentityProvider, OAuthClient};
+use crate::sharify::music_provider::{ProviderError, ProviderKind};
+use crate::sharify::random::{ALPHANUMERIC_CHARSET, RandomSource as _, SeededRandom, SystemRandom};
+use crate::sharify::recommendation;
+use crate::sharify::role::{RoleContent, RoleContentError, RoleDisplay, RoleError};
+use crate::sharify::room::*;
+use crate::sharify::room_events::{RemovalReason, RoleChange, RoomEvent};
+use crate::sharify::room_manager::RoomManager;
+use crate::sharify::room_metadata::ListenerSync;
+use crate::sharify::schedule::{
+    MAX_SCHEDULE_AHEAD, MAX_SCHEDULED_ROOMS, SCHEDULED_ROOM_CLAIM_TTL, ScheduledRoomStatus,
+};
+use crate::sharify::secret::Secret;
+use crate::sharify::signed_url::{SignedUrlError, SigningSecret};
+use crate::sharify::spotify::cache::{FetchCache, cache_key};
+use crate::sharify::spotify::web_utils::{
+    PlaybackItemType, SpotifyCurrentPlaybackOutput, SpotifySearchResults, SpotifyTrack,
+    image_revision, payloads,
+};
+use crate::sharify::spotify::{
+    DEFAULT_MAX_OUTAGE_DURATION, DEFAULT_MAX_OUTAGE_RETRY_INTERVAL, DEFAULT_OUTAGE_RETRY_BACKOFF,
+    FETCH_OFFSET_MS, MAX_TRACK_END_VERIFICATIONS, MID_TRACK_FETCH_THRESHOLD_MS, PlaybackScheduler,
+    RATE_LIMIT_REQUEST_WINDOW, RateLimiter, SpotifyError, SpotifyTokens, TRACK_END_FETCH_OFFSET_MS,
+    TRACK_END_VERIFY_MS, Timestamp, next_fetch_delay,
+};
+use crate::sharify::tasks::{RoomTaskKind, RoomTasks};
 use crate::sharify::utils::*;
+use crate::sharify::waitlist::{WAITLIST_POLL_TTL, WaitlistStatus};
+use crate::sharify::websocket::commands::{
+    Command as WSCmd, CommandAccess, CommandCategory, CommandDedupCache, CommandRateLimit,
+    CommandRateLimiter, CommandReplay, CommandResult,
+};
+use crate::sharify::websocket::events;
+use crate::sharify::websocket::fanout::{self, ROOM_CHANNEL_CAPACITY, RoomFrame, RoomUpdate};
+use crate::sharify::websocket::{
+    MAX_WS_FRAME_SIZE, MIN_WS_FRAME_SIZE, SessionEncoding, SessionFrame, SharifyWsInstance,
+    SharifyWsManager, WsTransport,
+};
 
 const LENGTH: usize = 15;
 const DUMMY_EMAILS: [&str; 6] = [
@@ -66,3 +123,4690 @@ fn converts_uuid_to_initial_email() {
         assert!(res.is_some_and(|e| e == email));
     }
 }
+
+// Time-based logic driven by a MockClock
+fn mock_room_manager() -> (Arc<MockClock>, RoomManager, RoomID) {
+    let clock = Arc::new(MockClock::default());
+    let mut room_manager = RoomManager::new(clock.clone(), Arc::new(SeededRandom::new(0)));
+
+    let room = room_manager
+        .create_room(
+            "owner".into(),
+            "Owner".into(),
+            "Room".into(),
+            CredentialsInput {
+                access_token: String::new(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                created_at: Timestamp::from(0),
+            }
+            .try_into()
+            .unwrap(),
+            Default::default(),
+        )
+        .unwrap();
+
+    (clock, room_manager, room.id)
+}
+
+#[test]
+fn rate_limiter_window_resets() {
+    let clock = Arc::new(MockClock::default());
+    let mut rate_limiter = RateLimiter::new(clock.clone());
+
+    while rate_limiter.increment().is_ok() {}
+
+    assert!(rate_limiter.is_rate_limited());
+
+    clock.advance(RATE_LIMIT_REQUEST_WINDOW + Duration::from_millis(1));
+
+    assert!(!rate_limiter.is_rate_limited());
+    assert!(rate_limiter.increment().is_ok());
+}
+
+#[test]
+fn ws_commands_token_bucket_refills() {
+    let clock = MockClock::default();
+    let mut limiter = CommandRateLimiter::default();
+    let limit = CommandRateLimit {
+        burst: 2,
+        per_min: 30,
+    };
+    let user_id = "user".to_string();
+
+    for _ in 0..2 {
+        assert!(
+            limiter
+                .try_acquire(&user_id, CommandCategory::Search, limit, clock.now())
+                .is_ok()
+        );
+    }
+
+    let retry_after = limiter
+        .try_acquire(&user_id, CommandCategory::Search, limit, clock.now())
+        .unwrap_err();
+
+    assert_eq!(retry_after, Duration::from_secs(2));
+    // Each category and user has its own bucket
+    assert!(
+        limiter
+            .try_acquire(&user_id, CommandCategory::Playback, limit, clock.now())
+            .is_ok()
+    );
+    assert!(
+        limiter
+            .try_acquire(&"other".into(), CommandCategory::Search, limit, clock.now())
+            .is_ok()
+    );
+
+    clock.advance(retry_after);
+    assert!(
+        limiter
+            .try_acquire(&user_id, CommandCategory::Search, limit, clock.now())
+            .is_ok()
+    );
+
+    let unlimited = CommandRateLimit {
+        burst: 0,
+        per_min: 0,
+    };
+
+    for _ in 0..100 {
+        assert!(
+            limiter
+                .try_acquire(&user_id, CommandCategory::Other, unlimited, clock.now())
+                .is_ok()
+        );
+    }
+}
+
+#[test]
+fn removed_members_per_user_state_is_dropped() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let (owner, guest) = (RoomUserID::from("owner"), RoomUserID::from("guest"));
+    let limit = CommandRateLimit {
+        burst: 2,
+        per_min: 30,
+    };
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+    room_manager.suspend_ws_user(room_id, &guest).unwrap();
+    room_manager
+        .set_correlation_id(room_id, &guest, "request".into())
+        .unwrap();
+
+    {
+        let room = room_manager.get_room_mut(&room_id).unwrap();
+
+        room.command_dedup.begin(&guest, "skip");
+        room.command_rate_limiter
+            .lock()
+            .unwrap()
+            .try_acquire(&guest, CommandCategory::Search, limit, clock.now())
+            .unwrap();
+    }
+
+    room_manager
+        .kick_user(room_id, &owner, &guest, String::new())
+        .unwrap();
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    assert!(!room.disconnected_at.contains_key(&guest));
+    assert!(!room.correlation_ids.contains_key(&guest));
+    assert_eq!(room.command_dedup.begin(&guest, "skip"), CommandReplay::New);
+
+    // A full bucket again, it was dropped
+    let mut rate_limiter = room.command_rate_limiter.lock().unwrap();
+
+    for _ in 0..2 {
+        assert!(
+            rate_limiter
+                .try_acquire(&guest, CommandCategory::Search, limit, clock.now())
+                .is_ok()
+        );
+    }
+}
+
+#[test]
+fn resent_commands_are_answered_from_the_dedup_cache() {
+    let mut dedup = CommandDedupCache::default();
+    let user_id = "user".to_string();
+    let skipped: CommandResult = Ok(None);
+
+    assert_eq!(dedup.begin(&user_id, "skip"), CommandReplay::New);
+    // Resent from another session while the first one runs
+    assert_eq!(dedup.begin(&user_id, "skip"), CommandReplay::InProgress);
+
+    dedup.complete(&user_id, "skip", skipped.clone());
+    assert_eq!(
+        dedup.begin(&user_id, "skip"),
+        CommandReplay::Done(Box::new(skipped))
+    );
+    // Each user has its own request_ids
+    assert_eq!(dedup.begin(&"other".into(), "skip"), CommandReplay::New);
+
+    // Failed commands can be resent
+    dedup.begin(&user_id, "queue");
+    dedup.complete(
+        &user_id,
+        "queue",
+        Err(command_response::Type::GenericError("Rate limited".into())),
+    );
+    assert_eq!(dedup.begin(&user_id, "queue"), CommandReplay::New);
+
+    // Only the last request_ids are remembered
+    for idx in 0..MAX_DEDUP_REQUEST_IDS {
+        let request_id = idx.to_string();
+
+        dedup.begin(&user_id, &request_id);
+        dedup.complete(&user_id, &request_id, Ok(None));
+    }
+
+    assert_eq!(dedup.begin(&user_id, "skip"), CommandReplay::New);
+    assert!(matches!(
+        dedup.begin(&user_id, &(MAX_DEDUP_REQUEST_IDS - 1).to_string()),
+        CommandReplay::Done(_)
+    ));
+
+    // Echoed in the JSON responses only when set
+    let command = WireFormat::Json
+        .decode::<Command>(br#"{"type": {"skip_next": true}, "request_id": "skip"}"#)
+        .unwrap();
+
+    assert_eq!(command.request_id.as_deref(), Some("skip"));
+
+    let response = CommandResponse {
+        request_id: command.request_id,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        String::from_utf8(WireFormat::Json.encode(&response).unwrap()).unwrap(),
+        r#"{"request_id":"skip","type":null}"#
+    );
+}
+
+#[test]
+fn queue_edit_grace_period_expires() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), "guest".into(), Default::default())
+        .unwrap();
+
+    for _ in 0..2 {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                "guest".into(),
+                "track".into(),
+                "Track".into(),
+                0,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    assert!(
+        room_manager
+            .remove_queued_track(room_id, &"guest".into(), "track")
+            .is_ok()
+    );
+
+    clock.advance(QUEUE_EDIT_GRACE_PERIOD + Duration::from_secs(1));
+
+    assert!(matches!(
+        room_manager.remove_queued_track(room_id, &"guest".into(), "track"),
+        Err(RoomError::QueueEditExpired)
+    ));
+}
+
+#[test]
+fn inactive_room_is_deleted() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+
+    assert!(room_manager.check_room_activity(room_id));
+
+    clock.advance(Duration::from_secs(INACTIVE_ROOM_MINS as u64 * 60 - 1));
+    assert!(room_manager.check_room_activity(room_id));
+
+    clock.advance(Duration::from_secs(1));
+    assert!(!room_manager.check_room_activity(room_id));
+    assert!(room_manager.get_room(&room_id).is_none());
+}
+
+#[test]
+fn idle_room_playback_is_paused() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    let settings = RoomSettings {
+        idle_pause_after: Some(Duration::from_secs(60 * 2)),
+        ..Default::default()
+    };
+
+    assert!(settings.validate().is_ok());
+    assert!(
+        RoomSettings {
+            idle_pause_after: Some(settings.inactivity_timeout),
+            ..settings
+        }
+        .validate()
+        .is_err()
+    );
+
+    room_manager
+        .update_room_settings(room_id, &owner, settings)
+        .unwrap();
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+            is_playing: true,
+            ..Default::default()
+        }));
+
+    assert!(room_manager.check_room_activity(room_id));
+    clock.advance(Duration::from_secs(60 * 2 - 1));
+    assert!(!room_manager.take_idle_pause(room_id));
+
+    clock.advance(Duration::from_secs(1));
+    assert!(room_manager.take_idle_pause(room_id));
+    // Only paused once
+    assert!(!room_manager.take_idle_pause(room_id));
+    // The room isn't deleted before its inactivity_timeout
+    assert!(room_manager.check_room_activity(room_id));
+
+    assert!(
+        room_manager
+            .set_ws_user_state(room_id, &owner, true)
+            .unwrap()
+    );
+    assert!(room_manager.take_idle_resume(room_id));
+    assert!(!room_manager.take_idle_resume(room_id));
+}
+
+#[test]
+fn room_settings_are_enforced() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let owner_id = room_manager.get_room(&room_id).unwrap().users[0].id.clone();
+    let settings = RoomSettings {
+        max_users: 1,
+        max_tracks_queue_len: 1,
+        ..Default::default()
+    };
+
+    assert!(matches!(
+        room_manager.update_room_settings(
+            room_id,
+            &owner_id,
+            RoomSettings {
+                max_users: MAX_USERS + 1,
+                ..settings
+            }
+        ),
+        Err(RoomError::InvalidSettings)
+    ));
+    assert!(
+        room_manager
+            .update_room_settings(room_id, &owner_id, settings)
+            .is_ok()
+    );
+
+    assert!(matches!(
+        room_manager.join_room(room_id, "Guest".into(), "guest".into(), Default::default()),
+        Err(RoomError::RoomFull)
+    ));
+
+    room_manager
+        .add_track_to_queue(
+            room_id,
+            owner_id.clone(),
+            "track".into(),
+            "Track".into(),
+            0,
+            PlaybackItemType::Track,
+        )
+        .unwrap();
+
+    assert!(matches!(
+        room_manager.add_track_to_queue(
+            room_id,
+            owner_id,
+            "track".into(),
+            "Track".into(),
+            0,
+            PlaybackItemType::Track
+        ),
+        Err(RoomError::QueueFull)
+    ));
+}
+
+#[test]
+fn dj_rotation_turns() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let (owner, guest) = (RoomUserID::from("owner"), RoomUserID::from("guest"));
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    assert!(matches!(
+        room_manager.set_dj_rotation_opt_in(room_id, &guest, true),
+        Err(RoomError::DjRotationNotStarted)
+    ));
+
+    room_manager
+        .start_dj_rotation(room_id, MIN_DJ_TURN_DURATION)
+        .unwrap();
+    room_manager
+        .set_dj_rotation_opt_in(room_id, &guest, true)
+        .unwrap();
+    room_manager
+        .set_dj_rotation_opt_in(room_id, &owner, true)
+        .unwrap();
+
+    let turn = room_manager.take_unscheduled_dj_turn(room_id).unwrap();
+
+    assert_eq!(turn.user_id, guest);
+    assert!(room_manager.take_unscheduled_dj_turn(room_id).is_none());
+    assert!(room_manager.is_current_dj(room_id, &guest));
+
+    // The window is over, control is reclaimed even before the turn is given
+    clock.advance(MIN_DJ_TURN_DURATION);
+    assert!(!room_manager.is_current_dj(room_id, &guest));
+
+    assert!(room_manager.end_dj_turn(room_id, turn.seq));
+    assert!(!room_manager.end_dj_turn(room_id, turn.seq));
+    assert!(room_manager.is_current_dj(room_id, &owner));
+
+    // The owner's turn is given back to the guest when it opts out
+    room_manager
+        .set_dj_rotation_opt_in(room_id, &owner, false)
+        .unwrap();
+    assert_eq!(
+        room_manager
+            .take_unscheduled_dj_turn(room_id)
+            .map(|turn| turn.user_id),
+        Some(guest)
+    );
+}
+
+#[test]
+fn dj_mode_turns_last_a_number_of_tracks() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let (owner, guest) = (RoomUserID::from("owner"), RoomUserID::from("guest"));
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    for turn_tracks in [0, MAX_DJ_TURN_TRACKS + 1] {
+        assert!(matches!(
+            room_manager.start_dj_mode(room_id, turn_tracks),
+            Err(RoomError::InvalidSettings)
+        ));
+    }
+
+    room_manager.start_dj_mode(room_id, 2).unwrap();
+    room_manager
+        .set_dj_rotation_opt_in(room_id, &guest, true)
+        .unwrap();
+    room_manager
+        .set_dj_rotation_opt_in(room_id, &owner, true)
+        .unwrap();
+
+    let turn = room_manager.take_unscheduled_dj_turn(room_id).unwrap();
+
+    assert_eq!(turn.user_id, guest);
+    assert_eq!((turn.remaining, turn.turn_tracks), (None, Some(2)));
+
+    // The turn isn't timed
+    clock.advance(MAX_DJ_TURN_DURATION);
+    assert!(room_manager.is_current_dj(room_id, &guest));
+
+    assert!(!room_manager.record_dj_track(room_id));
+    assert!(room_manager.record_dj_track(room_id));
+    assert!(room_manager.is_current_dj(room_id, &owner));
+    assert!(!room_manager.is_current_dj(room_id, &guest));
+
+    // The guest left, the turn comes back to the owner
+    room_manager.leave_room(room_id, guest.clone()).unwrap();
+    room_manager.record_dj_track(room_id);
+    assert!(!room_manager.record_dj_track(room_id));
+    assert!(room_manager.is_current_dj(room_id, &owner));
+
+    // Back to timed turns
+    room_manager
+        .start_dj_rotation(room_id, MIN_DJ_TURN_DURATION)
+        .unwrap();
+    assert!(!room_manager.record_dj_track(room_id));
+
+    let turn = room_manager.take_unscheduled_dj_turn(room_id).unwrap();
+
+    assert_eq!(turn.user_id, owner);
+    assert_eq!(turn.remaining, Some(MIN_DJ_TURN_DURATION));
+}
+
+#[test]
+fn seek_votes_pass_with_a_majority_of_the_connected_members() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let (owner, a, b) = (
+        RoomUserID::from("owner"),
+        RoomUserID::from("a"),
+        RoomUserID::from("b"),
+    );
+
+    for user_id in [&a, &b] {
+        room_manager
+            .join_room(
+                room_id,
+                user_id.clone(),
+                user_id.clone(),
+                Default::default(),
+            )
+            .unwrap();
+    }
+
+    for user_id in [&owner, &a, &b] {
+        room_manager
+            .set_ws_user_state(room_id, user_id, true)
+            .unwrap();
+    }
+
+    // Nothing playing
+    assert!(matches!(
+        room_manager.vote_seek(room_id, &a, 30000),
+        Err(RoomError::TrackNotFound)
+    ));
+
+    {
+        let room = room_manager.get_room_mut(&room_id).unwrap();
+
+        room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+            track_id: "track".into(),
+            is_playing: true,
+            progress_ms: Some(1000 * 10),
+            duration_ms: 1000 * 60 * 3,
+            ..Default::default()
+        }));
+        room.now_playing_at = Some(clock.now());
+    }
+
+    for offset_ms in [0, MAX_VOTE_SEEK_OFFSET.as_millis() as i64 + 1] {
+        assert!(matches!(
+            room_manager.vote_seek(room_id, &a, -offset_ms),
+            Err(RoomError::InvalidSeekOffset)
+        ));
+    }
+
+    let status = room_manager.vote_seek(room_id, &a, 30000).unwrap();
+
+    assert_eq!((status.votes, status.required_votes), (1, 2));
+    assert_eq!(status.seek_to_ms, None);
+
+    // Voting twice doesn't count
+    assert_eq!(room_manager.vote_seek(room_id, &a, 30000).unwrap().votes, 1);
+    assert!(matches!(
+        room_manager.vote_seek(room_id, &b, -30000),
+        Err(RoomError::SeekVoteRunning)
+    ));
+
+    clock.advance(Duration::from_secs(5));
+
+    let status = room_manager.vote_seek(room_id, &b, 30000).unwrap();
+
+    assert_eq!(status.votes, 2);
+    // From the extrapolated position
+    assert_eq!(status.seek_to_ms, Some(1000 * 45));
+    assert!(command_response::SeekVote::from(status).passed);
+
+    // A stale proposal is replaced
+    room_manager.vote_seek(room_id, &a, 30000).unwrap();
+    clock.advance(SEEK_VOTE_TTL);
+
+    let status = room_manager.vote_seek(room_id, &owner, -60000).unwrap();
+
+    assert_eq!((status.offset_ms, status.votes), (-60000, 1));
+
+    room_manager.set_ws_user_state(room_id, &b, false).unwrap();
+
+    // Can't go before the start of the track
+    let status = room_manager.vote_seek(room_id, &a, -60000).unwrap();
+
+    assert_eq!(status.required_votes, 2);
+    assert_eq!(status.seek_to_ms, Some(0));
+}
+
+#[test]
+fn ws_sessions_are_resumed_within_grace_period() {
+    let (clock, room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    assert!(
+        room_manager
+            .set_ws_user_state(room_id, &owner, true)
+            .unwrap()
+    );
+    let token = room_manager.issue_resume_token(room_id, &owner).unwrap();
+
+    assert_eq!(token.len(), RESUME_TOKEN_LEN);
+    // Takeover of a session still open
+    assert!(room_manager.can_resume_session(room_id, &owner, &token));
+    assert!(!room_manager.can_resume_session(room_id, &owner, "invalid"));
+
+    // The lost session keeps the user connected until the grace period is over
+    room_manager.suspend_ws_user(room_id, &owner).unwrap();
+    clock.advance(DEFAULT_RECONNECT_GRACE_PERIOD);
+
+    assert!(room_manager.expire_suspended_sessions(room_id).is_empty());
+    assert!(room_manager.get_room(&room_id).unwrap().users[0].is_connected);
+    assert!(room_manager.can_resume_session(room_id, &owner, &token));
+
+    // A resumed session is issued a new token
+    assert!(
+        !room_manager
+            .set_ws_user_state(room_id, &owner, true)
+            .unwrap()
+    );
+    let new_token = room_manager.issue_resume_token(room_id, &owner).unwrap();
+
+    assert!(!room_manager.can_resume_session(room_id, &owner, &token));
+
+    room_manager.suspend_ws_user(room_id, &owner).unwrap();
+    clock.advance(DEFAULT_RECONNECT_GRACE_PERIOD + Duration::from_secs(1));
+
+    assert_eq!(
+        room_manager.expire_suspended_sessions(room_id),
+        vec![owner.clone()]
+    );
+    assert!(room_manager.expire_suspended_sessions(room_id).is_empty());
+    assert!(!room_manager.get_room(&room_id).unwrap().users[0].is_connected);
+    assert!(!room_manager.can_resume_session(room_id, &owner, &new_token));
+    assert!(!room_manager.is_reconnecting(room_id, &owner));
+}
+
+#[test]
+fn bulk_moderation_reports_each_target() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    for user_id in ["raider1", "raider2"] {
+        room_manager
+            .join_room(room_id, user_id.into(), user_id.into(), Default::default())
+            .unwrap();
+    }
+
+    let logs_len = room_manager
+        .get_logs(room_id, &LogFilter::default(), 0, 100)
+        .unwrap()
+        .1;
+    let results = room_manager
+        .ban_users(
+            room_id,
+            &owner,
+            &[
+                "raider1".into(),
+                "raider2".into(),
+                "raider1".into(),
+                owner.clone(),
+                "unknown".into(),
+            ],
+            "raid".into(),
+        )
+        .unwrap();
+
+    assert_eq!(results.len(), 4);
+    assert!(results[0].1.is_ok() && results[1].1.is_ok());
+    assert!(matches!(results[2].1, Err(RoomError::Unauthorized)));
+    assert!(matches!(results[3].1, Err(RoomError::RoomUserNotFound)));
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users.len(), 1);
+    assert_eq!(room.banned_users, vec!["raider1", "raider2"]);
+
+    let (logs, total) = room_manager
+        .get_logs(room_id, &LogFilter::default(), 0, 1)
+        .unwrap();
+
+    assert_eq!(total, logs_len + 1);
+    assert!(logs[0].details.contains("raider1, raider2"));
+}
+
+#[test]
+fn invite_codes_resolve_rooms() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let code = room_manager.get_room(&room_id).unwrap().invite_code.clone();
+
+    assert_eq!(code.len(), INVITE_CODE_LEN);
+    assert_eq!(
+        room_manager
+            .get_room_by_invite_code(&code.to_lowercase())
+            .map(|room| room.id),
+        Some(room_id)
+    );
+
+    let new_code = room_manager.regenerate_invite_code(room_id).unwrap();
+
+    assert!(room_manager.get_room_by_invite_code(&code).is_none());
+    assert!(room_manager.get_room_by_invite_code(&new_code).is_some());
+
+    room_manager.delete_room(room_id, None).unwrap();
+
+    assert!(room_manager.get_room_by_invite_code(&new_code).is_none());
+}
+
+#[test]
+fn spotify_tokens_status_counts_down() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    room.spotify_handler.tokens.created_at = Timestamp::from(clock.utc_now().timestamp_millis());
+
+    let status = room.tokens_status(clock.utc_now());
+
+    // created_at is truncated to the millisecond
+    assert!(
+        status
+            .expires_in
+            .is_some_and(|d| (3599..=3600).contains(&d.num_seconds()))
+    );
+    assert_eq!(status.refresh_failures, 0);
+
+    clock.advance(Duration::from_secs(3601));
+
+    let status = room.tokens_status(clock.utc_now());
+
+    assert!(
+        status
+            .expires_in
+            .is_some_and(|d| d < chrono::TimeDelta::zero())
+    );
+}
+
+#[test]
+fn public_rooms_are_listed() {
+    let (_, room_manager, room_id) = mock_room_manager();
+
+    assert_eq!(room_manager.get_public_rooms(0, 0).1, 0);
+
+    room_manager
+        .update_room_settings(
+            room_id,
+            &"owner".into(),
+            RoomSettings {
+                is_public: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let (rooms, total) = room_manager.get_public_rooms(0, 0);
+
+    assert_eq!(total, 1);
+    assert_eq!(rooms[0].id, room_id);
+    assert_eq!(rooms[0].users_count, 1);
+    assert!(room_manager.get_public_rooms(1, 0).0.is_empty());
+}
+
+#[test]
+fn validates_role_display() {
+    let display = |color: &str, icon_id: &str| RoleDisplay {
+        color: Some(color.into()),
+        icon_id: Some(icon_id.into()),
+        description: None,
+    };
+
+    assert!(display("#1DB954", "crown").validate().is_ok());
+    assert!(matches!(
+        display("1DB954", "crown").validate(),
+        Err(RoleError::InvalidColor)
+    ));
+    assert!(matches!(
+        display("#1DB954", "Crown!").validate(),
+        Err(RoleError::InvalidIconID)
+    ));
+    assert!(matches!(
+        RoleDisplay {
+            description: Some("a".repeat(129)),
+            ..Default::default()
+        }
+        .validate(),
+        Err(RoleError::DescriptionTooLong)
+    ));
+}
+
+#[actix_rt::test]
+async fn malformed_role_ids_are_rejected() {
+    let (_, room_manager, room_id) = mock_room_manager();
+    let state = Arc::new(tokio::sync::RwLock::new(room_manager));
+
+    // Too short to be sliced, or trailing bytes that used to be ignored
+    for role_id in [vec![1, 2, 3], vec![0; 17]] {
+        for cmd_type in [
+            command::Type::SetRoleDisplay(command::SetRoleDisplay {
+                role_id: role_id.clone(),
+                ..Default::default()
+            }),
+            command::Type::SetRoleContent(command::SetRoleContent {
+                role_id: role_id.clone(),
+                ..Default::default()
+            }),
+            command::Type::RenameRole(command::RenameRole {
+                role_id: role_id.clone(),
+                name: "Renamed".into(),
+            }),
+            command::Type::DeleteRole(role_id.clone()),
+        ] {
+            let (result, _) = WSCmd::new(Arc::clone(&state), "owner".into(), room_id, cmd_type)
+                .process()
+                .await;
+
+            assert!(result.is_err());
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn room_changes_made_by_commands_are_emitted() {
+    let (_, room_manager, room_id) = mock_room_manager();
+    let mut events = room_manager.subscribe_events();
+    let state = Arc::new(tokio::sync::RwLock::new(room_manager));
+    let process = |cmd_type| async {
+        let (result, _) = WSCmd::new(Arc::clone(&state), "owner".into(), room_id, cmd_type)
+            .process()
+            .await;
+
+        assert!(result.is_ok(), "{result:?}");
+    };
+
+    process(command::Type::CreateRole(command::CreateRole {
+        name: "DJs".into(),
+        permissions: Some(Default::default()),
+        display: None,
+    }))
+    .await;
+
+    let Ok(RoomEvent::RoleUpdated {
+        role_id,
+        change: RoleChange::Created,
+        ..
+    }) = events.try_recv()
+    else {
+        panic!("The role creation wasn't emitted");
+    };
+
+    for (cmd_type, change) in [
+        (
+            command::Type::RenameRole(command::RenameRole {
+                role_id: role_id.into_bytes().into(),
+                name: "Selectors".into(),
+            }),
+            RoleChange::Renamed,
+        ),
+        (
+            command::Type::SetRoleContent(command::SetRoleContent {
+                role_id: role_id.into_bytes().into(),
+                content: None,
+            }),
+            RoleChange::Content,
+        ),
+        (
+            command::Type::DeleteRole(role_id.into_bytes().into()),
+            RoleChange::Deleted,
+        ),
+    ] {
+        process(cmd_type).await;
+
+        assert_eq!(
+            events.try_recv(),
+            Ok(RoomEvent::RoleUpdated {
+                room_id,
+                role_id,
+                change,
+            })
+        );
+    }
+
+    process(command::Type::StartDjRotation(0)).await;
+    process(command::Type::StopDjRotation(true)).await;
+    process(command::Type::RotateSigningSecret(true)).await;
+
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::DjRotationUpdated { room_id })
+    );
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::DjRotationStopped { room_id })
+    );
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::SigningSecretRotated { room_id })
+    );
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[actix_rt::test]
+async fn event_streams_end_with_their_deleted_room() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    events::init_stream_cleanup(room_manager.subscribe_events());
+
+    let mut rx = events::subscribe(room_id, events::EventProjection::Full).unwrap();
+
+    // Not closed by an owner
+    room_manager.delete_room(room_id, None).unwrap();
+
+    let ended = tokio::time::timeout(std::time::Duration::from_secs(1), rx.recv()).await;
+
+    assert_eq!(ended, Ok(None));
+}
+
+#[test]
+fn parses_spotify_playables() {
+    let queue: payloads::Queue = serde_json::from_value(serde_json::json!({
+        "queue": [
+            {
+                "type": "track",
+                "id": null,
+                "uri": "spotify:local:artist:album:title:180",
+                "name": "Local file",
+                "artists": [],
+                "duration_ms": 180000,
+                "album": null
+            },
+            {
+                "type": "episode",
+                "id": "episode_id",
+                "name": "Episode",
+                "duration_ms": 3600000,
+                "show": { "name": "Show" },
+                "images": []
+            },
+            { "type": "audiobook" }
+        ]
+    }))
+    .unwrap();
+
+    let tracks = queue
+        .queue
+        .into_iter()
+        .filter_map(payloads::Playable::into_track)
+        .collect::<Vec<_>>();
+
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].track_id, "spotify:local:artist:album:title:180");
+    assert_eq!(tracks[0].popularity, None);
+    assert_eq!(tracks[1].album_name, "Show");
+    assert_eq!(tracks[1].artist_name, "Show");
+
+    let state: payloads::PlaybackState = serde_json::from_value(serde_json::json!({
+        "device": { "id": null, "volume_percent": null },
+        "shuffle_state": false,
+        "progress_ms": null,
+        "is_playing": true,
+        "item": null
+    }))
+    .unwrap();
+
+    assert!(state.into_output().is_none());
+}
+
+#[test]
+fn parses_episode_playback_state() {
+    let state: payloads::PlaybackState = serde_json::from_value(serde_json::json!({
+        "device": { "id": "device_id", "volume_percent": 50 },
+        "shuffle_state": false,
+        "progress_ms": 1000,
+        "is_playing": true,
+        "item": {
+            "type": "episode",
+            "id": "episode_id",
+            "name": "Episode",
+            "duration_ms": 3600000,
+            "show": { "name": "Show" },
+            "images": [{ "url": "https://i.scdn.co/image/episode" }]
+        }
+    }))
+    .unwrap();
+
+    let output = state.into_output().unwrap();
+
+    assert_eq!(output.item_type, PlaybackItemType::Episode);
+    assert_eq!(output.artist_name, "Show");
+    assert_eq!(output.duration_ms, 3600000);
+    assert_eq!(output.album_image_src, "https://i.scdn.co/image/episode");
+}
+
+#[test]
+fn clamps_adversarial_playback_progress() {
+    let playback = |progress_ms: serde_json::Value, duration_ms: u64, is_playing: bool| {
+        let state: payloads::PlaybackState = serde_json::from_value(serde_json::json!({
+            "device": { "id": "device_id", "volume_percent": 50 },
+            "shuffle_state": false,
+            "progress_ms": progress_ms,
+            "is_playing": is_playing,
+            "item": {
+                "type": "episode",
+                "id": "episode_id",
+                "name": "Episode",
+                "duration_ms": duration_ms,
+                "show": { "name": "Show" },
+                "images": []
+            }
+        }))
+        .unwrap();
+
+        state.into_output().unwrap()
+    };
+
+    // Progress beyond the duration
+    let output = playback(serde_json::json!(190000), 180000, true);
+    assert_eq!(output.progress_ms, Some(180000));
+    assert_eq!(output.remaining_ms(), Some(0));
+
+    let output = playback(serde_json::json!(1000), 0, true);
+    assert_eq!(output.progress_ms, Some(0));
+    assert_eq!(output.remaining_ms(), Some(0));
+
+    let output = playback(serde_json::json!(u64::MAX), u64::MAX, true);
+    assert_eq!(output.duration_ms, i64::MAX as u64);
+    assert_eq!(output.remaining_ms(), Some(0));
+
+    assert_eq!(
+        playback(serde_json::Value::Null, 180000, true).remaining_ms(),
+        None
+    );
+    assert_eq!(
+        playback(serde_json::json!(1000), 180000, false).remaining_ms(),
+        None
+    );
+
+    assert_eq!(next_fetch_delay(0), Duration::from_millis(FETCH_OFFSET_MS));
+    assert_eq!(
+        next_fetch_delay(MID_TRACK_FETCH_THRESHOLD_MS * 2),
+        Duration::from_millis(MID_TRACK_FETCH_THRESHOLD_MS + FETCH_OFFSET_MS)
+    );
+    assert_eq!(
+        next_fetch_delay(u64::MAX),
+        Duration::from_millis(u64::MAX / 2 + FETCH_OFFSET_MS)
+    );
+}
+
+#[test]
+fn playback_fetch_after_a_track_end_is_verified() {
+    let mut scheduler = PlaybackScheduler::default();
+    let verify = Duration::from_millis(TRACK_END_VERIFY_MS);
+
+    // Mid-track fetch then the one after the track end
+    assert_eq!(
+        scheduler.next_delay("first", MID_TRACK_FETCH_THRESHOLD_MS * 2),
+        next_fetch_delay(MID_TRACK_FETCH_THRESHOLD_MS * 2)
+    );
+    assert_eq!(
+        scheduler.next_delay("first", 10_000),
+        Duration::from_millis(10_000 + TRACK_END_FETCH_OFFSET_MS)
+    );
+
+    // The track didn't change yet, it's re-polled shortly a few times only
+    for _ in 0..MAX_TRACK_END_VERIFICATIONS {
+        assert_eq!(scheduler.next_delay("first", 0), verify);
+    }
+    assert_eq!(scheduler.next_delay("first", 0), next_fetch_delay(0));
+
+    // The next track is trusted
+    assert_eq!(
+        scheduler.next_delay("second", 5000),
+        Duration::from_millis(5000 + TRACK_END_FETCH_OFFSET_MS)
+    );
+    assert_eq!(scheduler.next_delay("second", 0), verify);
+
+    // Seeked back
+    assert_eq!(
+        scheduler.next_delay("second", 60_000),
+        Duration::from_millis(60_000 + TRACK_END_FETCH_OFFSET_MS)
+    );
+
+    scheduler.expect_track_change("skipped");
+    assert_eq!(scheduler.next_delay("skipped", 0), verify);
+}
+
+#[test]
+fn history_is_seeded_with_pre_session_tracks() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+    let played: Vec<payloads::PlayHistory> = serde_json::from_value(serde_json::json!([{
+        "played_at": "2025-01-01T12:00:00.000Z",
+        "track": {
+            "id": "old_track",
+            "uri": "spotify:track:old_track",
+            "name": "Old track",
+            "duration_ms": 1000,
+            "artists": [],
+            "album": {}
+        }
+    }]))
+    .unwrap();
+    let playback: payloads::PlaybackState = serde_json::from_value(serde_json::json!({
+        "device": { "id": "device_id", "volume_percent": 50 },
+        "shuffle_state": false,
+        "progress_ms": 0,
+        "is_playing": true,
+        "item": {
+            "type": "track",
+            "id": "new_track",
+            "uri": "spotify:track:new_track",
+            "name": "New track",
+            "duration_ms": 1000,
+            "artists": [],
+            "album": {}
+        }
+    }))
+    .unwrap();
+    let playback = playback.into_output();
+
+    room.set_now_playing(playback.clone());
+    room.set_now_playing(playback);
+    room.seed_history(played.into_iter().map(Into::into).collect());
+
+    let history = room
+        .history
+        .iter()
+        .map(|item| (item.track.track_id.as_str(), item.pre_session))
+        .collect::<Vec<_>>();
+
+    assert_eq!(history, [("new_track", false), ("old_track", true)]);
+}
+
+#[test]
+fn parses_search_results_with_null_items() {
+    let search: payloads::Search = serde_json::from_value(serde_json::json!({
+        "albums": {
+            "total": 42,
+            "items": [
+                null,
+                {
+                    "id": "album_id",
+                    "name": "Album",
+                    "total_tracks": 12,
+                    "artists": [{ "name": "Artist 1" }, { "name": "Artist 2" }],
+                    "images": [{ "url": "https://i.scdn.co/image/album" }]
+                }
+            ]
+        },
+        "playlists": { "total": 3, "items": [null] }
+    }))
+    .unwrap();
+
+    let results = SpotifySearchResults::from(search);
+
+    assert!(results.tracks.is_empty());
+    assert_eq!(results.albums_total, 42);
+    assert_eq!(results.albums.len(), 1);
+    assert_eq!(results.albums[0].artist_name, "Artist 1 - Artist 2");
+    assert_eq!(results.albums[0].tracks_count, 12);
+    assert_eq!(results.playlists_total, 3);
+    assert!(results.playlists.is_empty());
+}
+
+#[test]
+fn verifies_signed_urls() {
+    let clock = MockClock::default();
+    let room_id = uuid::Uuid::now_v7();
+    let secret = SigningSecret::default();
+    let expires = clock.utc_now().timestamp() + 60;
+    let signature = secret.sign(room_id, expires).unwrap();
+
+    assert_eq!(
+        secret.verify(room_id, expires, &signature, clock.utc_now()),
+        Ok(())
+    );
+    assert_eq!(
+        secret.verify(room_id, expires + 1, &signature, clock.utc_now()),
+        Err(SignedUrlError::InvalidSignature)
+    );
+    assert_eq!(
+        secret.verify(uuid::Uuid::now_v7(), expires, &signature, clock.utc_now()),
+        Err(SignedUrlError::InvalidSignature)
+    );
+
+    clock.advance(Duration::from_secs(60));
+
+    assert_eq!(
+        secret.verify(room_id, expires, &signature, clock.utc_now()),
+        Err(SignedUrlError::Expired)
+    );
+
+    // Rotating the secret revokes the URL
+    assert_eq!(
+        SigningSecret::default().verify(room_id, expires, &signature, clock.utc_now()),
+        Err(SignedUrlError::InvalidSignature)
+    );
+}
+
+#[test]
+fn fair_queue_interleaves_submitters() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    for user_id in ["alice", "bob"] {
+        room_manager
+            .join_room(room_id, user_id.into(), user_id.into(), Default::default())
+            .unwrap();
+    }
+
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .settings
+        .fair_queue = true;
+
+    for (user_id, track_id) in [
+        ("alice", "a1"),
+        ("alice", "a2"),
+        ("alice", "a3"),
+        ("bob", "b1"),
+        ("bob", "b2"),
+        ("owner", "o1"),
+    ] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                user_id.into(),
+                track_id.into(),
+                track_id.into(),
+                1000,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    let queue = |room_manager: &RoomManager| {
+        room_manager
+            .get_room(&room_id)
+            .unwrap()
+            .tracks_queue
+            .iter()
+            .map(|t| t.track_id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(queue(&room_manager), ["a1", "b1", "o1", "a2", "b2", "a3"]);
+
+    // The head track is in the Spotify queue, it's not reordered anymore
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .pushed_tracks_len = 1;
+    room_manager
+        .add_track_to_queue(
+            room_id,
+            "bob".into(),
+            "b3".into(),
+            "b3".into(),
+            1000,
+            PlaybackItemType::Track,
+        )
+        .unwrap();
+
+    assert_eq!(
+        queue(&room_manager),
+        ["a1", "b1", "o1", "a2", "b2", "a3", "b3"]
+    );
+
+    // Only pushed tracks have to be skipped by Spotify
+    room_manager
+        .remove_queued_track(room_id, &"alice".into(), "a2")
+        .unwrap();
+    room_manager
+        .remove_queued_track(room_id, &"alice".into(), "a1")
+        .unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.tracks_to_skip, vec!["a1"]);
+    assert_eq!(room.pushed_tracks_len, 0);
+}
+
+#[test]
+fn unpushed_tracks_are_reserved_until_released() {
+    let (_, room_manager, room_id) = mock_room_manager();
+
+    for track_id in ["t1", "t2", "t3"] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                "owner".into(),
+                track_id.into(),
+                track_id.into(),
+                1000,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    let track_ids = |tracks: Vec<(String, PlaybackItemType)>| {
+        tracks
+            .into_iter()
+            .map(|(track_id, _)| track_id)
+            .collect::<Vec<_>>()
+    };
+    let mut room = room_manager.lock_room(&room_id).unwrap();
+
+    assert_eq!(
+        track_ids(room.reserve_unpushed_tracks()),
+        ["t1", "t2", "t3"]
+    );
+    // A concurrent push doesn't queue them twice
+    assert!(room.reserve_unpushed_tracks().is_empty());
+
+    // The last two failed to be pushed
+    room.release_unpushed_tracks(2);
+
+    assert_eq!(room.pushed_tracks_len, 1);
+    assert_eq!(track_ids(room.reserve_unpushed_tracks()), ["t2", "t3"]);
+
+    // Only the head track is pushed in fair queue mode
+    room.release_unpushed_tracks(3);
+    room.settings.fair_queue = true;
+
+    assert_eq!(track_ids(room.reserve_unpushed_tracks()), ["t1"]);
+    assert!(room.reserve_unpushed_tracks().is_empty());
+    assert_eq!(room.pushed_tracks_len, 1);
+}
+
+#[test]
+fn room_changes_are_emitted_to_the_subscribers() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let mut events = room_manager.subscribe_events();
+    let guest = RoomUserID::from("guest");
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &guest, true)
+        .unwrap();
+    // Unchanged presence
+    room_manager
+        .set_ws_user_state(room_id, &guest, true)
+        .unwrap();
+    room_manager
+        .add_track_to_queue(
+            room_id,
+            guest.clone(),
+            "track_id".into(),
+            "Track".into(),
+            1000,
+            PlaybackItemType::Track,
+        )
+        .unwrap();
+    room_manager
+        .remove_track_from_queue(room_id, "track_id".into())
+        .unwrap();
+    room_manager
+        .kick_user(room_id, &"owner".into(), &guest, "reason".into())
+        .unwrap();
+    room_manager.delete_room(room_id, None).unwrap();
+
+    let expected = [
+        RoomEvent::UserJoined {
+            room_id,
+            user_id: guest.clone(),
+        },
+        RoomEvent::PresenceChanged {
+            room_id,
+            user_id: guest.clone(),
+            is_connected: true,
+        },
+        RoomEvent::TrackQueued {
+            room_id,
+            user_id: guest.clone(),
+            track_id: "track_id".into(),
+        },
+        RoomEvent::TrackDequeued {
+            room_id,
+            track_id: "track_id".into(),
+        },
+        RoomEvent::UserRemoved {
+            room_id,
+            user_id: guest,
+            reason: RemovalReason::Kicked,
+        },
+        RoomEvent::Deleted { room_id },
+    ];
+
+    for event in expected {
+        assert_eq!(events.try_recv(), Ok(event));
+    }
+
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn logs_are_filtered_by_type_and_date() {
+    let (clock, room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    clock.advance(Duration::from_secs(60));
+    let since = clock.utc_now();
+
+    for (log_type, details) in [
+        (LogType::Kick, "kicked"),
+        (LogType::AddTrack, "added"),
+        (LogType::Ban, "banned"),
+    ] {
+        room_manager
+            .append_user_log(room_id, &owner, log_type, details.into())
+            .unwrap();
+        clock.advance(Duration::from_secs(60));
+    }
+
+    let moderation = LogFilter {
+        types: vec![LogType::Kick, LogType::Ban],
+        ..Default::default()
+    };
+    let (logs, total) = room_manager.get_logs(room_id, &moderation, 0, 0).unwrap();
+
+    assert_eq!(total, 2);
+    assert!(logs[0].details.contains("banned") && logs[1].details.contains("kicked"));
+
+    let range = LogFilter {
+        since: Some(since),
+        until: Some(since + chrono::TimeDelta::seconds(60)),
+        ..Default::default()
+    };
+    let (logs, total) = room_manager.get_logs(room_id, &range, 0, 0).unwrap();
+
+    assert_eq!(total, 2);
+    assert!(logs[0].details.contains("added"));
+
+    let (logs, total) = room_manager
+        .get_logs(
+            room_id,
+            &LogFilter {
+                types: vec![LogType::Kick],
+                ..range
+            },
+            1,
+            0,
+        )
+        .unwrap();
+
+    assert_eq!(total, 1);
+    assert!(logs.is_empty());
+}
+
+#[actix_rt::test]
+async fn commands_are_audit_logged_and_paged() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), "guest".into(), Default::default())
+        .unwrap();
+
+    {
+        let room = room_manager.get_room_mut(&room_id).unwrap();
+
+        room.provider = ProviderKind::Offline;
+        room.max_logs_len = 3;
+        room.logs.clear();
+    }
+
+    let state = Arc::new(tokio::sync::RwLock::new(room_manager));
+    let process = |user_id: &str, cmd_type| {
+        let cmd = WSCmd::new(Arc::clone(&state), user_id.into(), room_id, cmd_type);
+
+        async move { cmd.process().await.0 }
+    };
+
+    for cmd_type in [
+        command::Type::Pause(true),
+        command::Type::SkipNext(true),
+        command::Type::SeekToPos(61_000),
+        command::Type::CreateRole(command::CreateRole {
+            name: "DJs".into(),
+            permissions: Some(Default::default()),
+            display: None,
+        }),
+    ] {
+        assert!(process("owner", cmd_type).await.is_ok());
+    }
+
+    // Reviewing the logs takes can_manage_users, the failed command isn't logged
+    assert!(matches!(
+        process("guest", command::Type::GetLogs(Default::default())).await,
+        Err(command_response::Type::RoomError(_))
+    ));
+
+    let get_page = |offset| {
+        let page = process(
+            "owner",
+            command::Type::GetLogs(command::GetLogs {
+                offset,
+                limit: 2,
+                ..Default::default()
+            }),
+        );
+
+        async move {
+            let Ok(Some(command_response::Type::LogPage(page))) = page.await else {
+                panic!("GetLogs didn't return a page");
+            };
+
+            page
+        }
+    };
+    let details = |page: &command_response::LogPage| {
+        page.logs
+            .iter()
+            .map(|log| log.details.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // The pause was dropped past max_logs_len, most recent first
+    let page = get_page(0).await;
+
+    assert_eq!(page.total, 3);
+    assert_eq!(
+        details(&page),
+        [
+            "User \"Owner\" created the role \"DJs\"",
+            "User \"Owner\" seeked to 1:01",
+        ]
+    );
+    assert!(page.logs.iter().all(|log| log.author_id == "owner"));
+
+    let page = get_page(2).await;
+
+    assert_eq!(page.offset, 2);
+    assert_eq!(details(&page), ["User \"Owner\" skipped to the next track"]);
+}
+
+#[test]
+fn closing_rooms_reject_new_users() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    assert!(room_manager.ensure_room_active(room_id).is_ok());
+
+    room_manager.begin_room_closing(room_id).unwrap();
+
+    assert!(matches!(
+        room_manager.ensure_room_active(room_id),
+        Err(RoomError::RoomClosing)
+    ));
+    assert!(matches!(
+        room_manager.join_room(room_id, "late".into(), "late".into(), Default::default()),
+        Err(RoomError::RoomClosing)
+    ));
+
+    room_manager.delete_room(room_id, None).unwrap();
+
+    assert!(matches!(
+        room_manager.ensure_room_active(room_id),
+        Err(RoomError::RoomNotFound)
+    ));
+}
+
+#[actix_rt::test]
+async fn ws_inits_are_rejected_during_the_room_teardown() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let guest = RoomUserID::from("guest");
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    let session_token = identity::issue_session_token(room_id, &guest, clock.utc_now()).unwrap();
+
+    // close_room flags the room first, it's only deleted once its sessions are closed
+    room_manager.begin_room_closing(room_id).unwrap();
+
+    let state = Arc::new(tokio::sync::RwLock::new(room_manager));
+    let app = actix_web::test::init_service(
+        actix_web::App::new()
+            .app_data(actix_web::web::Data::new(Arc::clone(&state)))
+            .app_data(actix_web::web::Data::new(Arc::new(
+                tokio::sync::RwLock::new(SharifyWsManager::default()),
+            )))
+            .route(
+                "/{room_id}/{user_id}",
+                actix_web::web::get().to(SharifyWsInstance::init),
+            ),
+    )
+    .await;
+    let req = actix_web::test::TestRequest::get()
+        .uri(&format!("/{room_id}/{guest}?session_token={session_token}"))
+        .insert_header((header::CONNECTION, "upgrade"))
+        .insert_header((header::UPGRADE, "websocket"))
+        .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+        .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+        .to_request();
+
+    let res = actix_web::test::call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::GONE);
+
+    let body = actix_web::test::read_body(res).await;
+
+    assert_eq!(
+        CommandResponse::decode(body).unwrap(),
+        CommandResponse::from(RoomError::RoomClosing)
+    );
+    // Neither connected nor given a session that close_room would miss
+    assert!(
+        state
+            .read()
+            .await
+            .get_room(&room_id)
+            .unwrap()
+            .users
+            .iter()
+            .all(|user| !user.is_connected)
+    );
+}
+
+#[test]
+fn identity_tokens_are_bound_to_the_provider_account() {
+    let now = chrono::Utc::now();
+    let identity = Identity {
+        provider: IdentityProvider::GitHub,
+        subject: "583231".into(),
+    };
+    let user_id = identity.user_id();
+
+    assert_eq!(user_id, identity.user_id());
+    assert_ne!(
+        user_id,
+        Identity {
+            provider: IdentityProvider::Google,
+            ..identity.clone()
+        }
+        .user_id()
+    );
+    assert!(
+        user_id
+            .split(':')
+            .all(|group| group.len() == 4 && u16::from_str_radix(group, 16).is_ok())
+    );
+
+    let (token, expires_at) = identity::issue_identity_token(&user_id, now).unwrap();
+
+    assert_eq!(
+        identity::verify_identity_token(&token, now),
+        Ok(user_id.clone())
+    );
+    assert_eq!(
+        identity::verify_identity_token(&token, expires_at),
+        Err(IdentityError::Expired)
+    );
+
+    let forged = token.replacen(&user_id[..4], "0000", 1);
+
+    assert_eq!(
+        identity::verify_identity_token(&forged, now),
+        Err(IdentityError::InvalidToken)
+    );
+    // The body user ID is ignored once an identity token is given
+    assert_eq!(
+        identity::resolve_user_id("someone-else".into(), Some(&token), now),
+        Ok(user_id)
+    );
+
+    // Only the identity token identifies the callers once a provider is configured
+    let mut config = crate::config::Config::clone(&crate::config::get());
+
+    config.identity_required = false;
+    config.oauth_clients.clear();
+    assert!(identity::trusts_client_user_ids(&config));
+
+    config.oauth_clients.insert(
+        IdentityProvider::GitHub,
+        OAuthClient {
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+        },
+    );
+    assert!(!identity::trusts_client_user_ids(&config));
+
+    let login = identity::login_state(IdentityProvider::Google, now).unwrap();
+    let nonce = Some(login.nonce.as_str());
+
+    assert!(
+        identity::verify_login_state(IdentityProvider::Google, &login.state, nonce, now).is_ok()
+    );
+    assert_eq!(
+        identity::verify_login_state(IdentityProvider::GitHub, &login.state, nonce, now),
+        Err(IdentityError::InvalidState)
+    );
+    assert_eq!(
+        identity::verify_login_state(
+            IdentityProvider::Google,
+            &login.state,
+            nonce,
+            now + identity::LOGIN_STATE_TTL
+        ),
+        Err(IdentityError::InvalidState)
+    );
+
+    // Only accepted from the browser holding the nonce cookie
+    let other = identity::login_state(IdentityProvider::Google, now).unwrap();
+
+    for cookie_nonce in [None, Some(other.nonce.as_str())] {
+        assert_eq!(
+            identity::verify_login_state(IdentityProvider::Google, &login.state, cookie_nonce, now),
+            Err(IdentityError::InvalidState)
+        );
+    }
+}
+
+#[test]
+fn commands_round_trip_through_json() {
+    let command = WireFormat::Json
+        .decode::<Command>(br#"{"type": {"get_logs": {"limit": 10, "since": {"seconds": 60}}}}"#)
+        .unwrap();
+
+    let Some(command::Type::GetLogs(opts)) = command.r#type else {
+        panic!("Unexpected command {command:?}");
+    };
+
+    assert_eq!((opts.offset, opts.limit), (0, 10));
+    assert_eq!(opts.since.map(|since| since.seconds), Some(60));
+
+    let response = CommandResponse {
+        r#type: Some(command_response::Type::DjTurn(command_response::DjTurn {
+            user_id: "dj".into(),
+            ends_at: Some(crate::proto::Timestamp {
+                seconds: 120,
+                nanos: 0,
+            }),
+            turn_tracks: 0,
+        })),
+        ..Default::default()
+    };
+    let buf = WireFormat::Protobuf.encode(&response).unwrap();
+    let json = WireFormat::protobuf_to_json::<CommandResponse>(buf.into()).unwrap();
+
+    assert_eq!(
+        json,
+        r#"{"type":{"dj_turn":{"user_id":"dj","ends_at":{"seconds":120,"nanos":0},"turn_tracks":0}}}"#
+    );
+    assert_eq!(
+        WireFormat::Json.decode::<CommandResponse>(json.as_bytes()),
+        Ok(response)
+    );
+}
+
+#[test]
+fn ws_close_codes_are_application_codes() {
+    use crate::proto::cmd::WsCloseCode;
+
+    let reason = WsCloseCode::Banned.reason("Spam");
+
+    assert_eq!(u16::from(reason.code), 4005);
+    assert_eq!(reason.description.as_deref(), Some("Spam"));
+
+    // Cut to fit the close frame, without splitting the multi-byte chars
+    let reason = WsCloseCode::Kicked.reason("é".repeat(100));
+
+    assert_eq!(reason.description.as_deref(), Some("é".repeat(61).as_str()));
+
+    // Contiguous from 4000, in the range reserved to the applications
+    for code in 4000..=4009 {
+        let close_code = actix_ws::CloseCode::from(WsCloseCode::try_from(code).unwrap());
+
+        assert_eq!(u16::from(close_code), code as u16);
+    }
+
+    assert!(WsCloseCode::try_from(4010).is_err());
+}
+
+#[test]
+fn batched_responses_are_split_and_deflated_frames_inflate_back() {
+    use std::io::Read as _;
+
+    let (_, _, room_id) = mock_room_manager();
+    let playback = CommandResponse {
+        r#type: Some(command_response::Type::SpotifyPlaybackState(
+            command_response::SpotifyPlaybackState::default(),
+        )),
+        ..Default::default()
+    };
+    let rate_limited = CommandResponse {
+        r#type: Some(command_response::Type::SpotifyRateLimited(30)),
+        ..Default::default()
+    };
+
+    assert_eq!(CommandResponse::batch(Vec::new()), None);
+    // A lone response isn't wrapped
+    assert_eq!(
+        CommandResponse::batch(vec![playback.clone()]),
+        Some(playback.clone())
+    );
+
+    let batch = CommandResponse::batch(vec![rate_limited.clone(), playback.clone()]).unwrap();
+
+    assert_eq!(
+        batch.r#type,
+        Some(command_response::Type::Batch(command_response::Batch {
+            responses: vec![rate_limited.clone(), playback.clone()],
+        }))
+    );
+
+    // The event streams get each public response broadcasted together
+    let mut rx = events::subscribe(room_id, events::EventProjection::Full).unwrap();
+
+    events::broadcast(room_id, &[rate_limited.clone(), playback.clone()]);
+
+    assert!(
+        rx.try_recv()
+            .unwrap()
+            .starts_with(b"event: spotify_playback_state\n")
+    );
+    assert!(rx.try_recv().is_err());
+
+    events::close_room(room_id);
+
+    let buf = Bytes::from(batch.encode_to_vec());
+    let inflate = |frame| {
+        let SessionFrame::Binary(deflated) = frame else {
+            panic!("Deflated frames are binary");
+        };
+        let mut inflated = Vec::new();
+
+        flate2::read::DeflateDecoder::new(deflated.as_ref())
+            .read_to_end(&mut inflated)
+            .unwrap();
+
+        inflated
+    };
+
+    assert_eq!(
+        SessionEncoding::from_query(None, None, None).encode(buf.clone()),
+        Ok(SessionFrame::Binary(buf.clone()))
+    );
+    assert_eq!(
+        inflate(
+            SessionEncoding::from_query(None, Some("deflate"), None)
+                .encode(buf.clone())
+                .unwrap()
+        ),
+        buf
+    );
+
+    let json_encoding = SessionEncoding::from_query(Some("json"), Some("deflate"), None);
+    let json = WireFormat::protobuf_to_json::<CommandResponse>(buf.clone()).unwrap();
+
+    assert_eq!(inflate(json_encoding.encode(buf).unwrap()), json.as_bytes());
+
+    // The batches are opt-in
+    let batching = SessionEncoding::from_query(None, None, Some("room_delta,batch"));
+    let responses = vec![rate_limited, playback];
+
+    assert!(batching.batch);
+    assert_eq!(batching.negotiate(responses.clone(), None), vec![batch]);
+    assert_eq!(
+        SessionEncoding::default().negotiate(responses.clone(), None),
+        responses
+    );
+
+    // A broadcast is encoded once for all the sessions of the same encoding
+    let update = RoomUpdate::new(responses);
+
+    assert_eq!(update.frames_for(batching).len(), 1);
+    assert_eq!(update.frames_for(SessionEncoding::default()).len(), 2);
+    assert!(Arc::ptr_eq(
+        &update.frames_for(batching),
+        &update.frames_for(batching)
+    ));
+}
+
+#[test]
+fn session_summaries_credit_the_top_contributor() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "A".into(), "a".into(), Default::default())
+        .unwrap();
+
+    let summary = room_manager.session_summary(room_id).unwrap();
+
+    assert_eq!(summary.duration, Duration::ZERO);
+    assert_eq!(summary.top_contributor, None);
+
+    {
+        let room = room_manager.get_room_mut(&room_id).unwrap();
+
+        room.listening_stats.record_played("Artist");
+        room.listening_stats.record_queued(&"owner".to_string());
+        room.listening_stats.record_queued(&"a".to_string());
+        room.listening_stats.record_queued(&"a".to_string());
+    }
+
+    clock.advance(Duration::from_secs(60 * 90));
+    room_manager.leave_room(room_id, "a".into()).unwrap();
+
+    let summary = room_manager.session_summary(room_id).unwrap();
+
+    assert_eq!(summary.duration, Duration::from_secs(60 * 90));
+    assert_eq!(summary.tracks_played, 1);
+    // Still credited after leaving
+    assert_eq!(
+        summary.top_contributor,
+        Some(TopContributor {
+            user_id: "a".into(),
+            username: None,
+            tracks_queued: 2,
+        })
+    );
+    assert!(summary.to_webhook_content().contains("Duration: 1h30"));
+
+    let proto = command_response::SessionSummary::from(summary);
+
+    assert_eq!(proto.duration_secs, 60 * 90);
+    assert_eq!(proto.top_contributor.unwrap().username, "");
+}
+
+#[test]
+fn daily_digests_follow_the_room_timezone() {
+    use chrono::Timelike as _;
+
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    // Local time of the room is 10:xx whatever the actual UTC time is
+    let utc_now = clock.utc_now();
+    let utc_offset_mins = (10 - utc_now.hour() as i16) * 60;
+    let settings = RoomSettings {
+        daily_digest: Some(DailyDigest {
+            hour: 11,
+            utc_offset_mins,
+        }),
+        ..Default::default()
+    };
+
+    room_manager
+        .update_room_settings(room_id, &owner, settings)
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &owner, true)
+        .unwrap();
+    room_manager
+        .get_room_mut(&room_id)
+        .unwrap()
+        .stats
+        .tracks_played = 3;
+
+    assert!(room_manager.take_due_digests().is_empty());
+
+    // 11:01 local time
+    clock.advance(Duration::from_secs(
+        (61 - utc_now.minute() as u64) * 60 - utc_now.second() as u64,
+    ));
+
+    let digests = room_manager.take_due_digests();
+
+    assert_eq!(digests.len(), 1);
+    assert_eq!(digests[0].stats.tracks_played, 3);
+    assert_eq!(digests[0].stats.listeners.len(), 1);
+    assert_eq!(digests[0].stats.peak_concurrency, 1);
+    assert!(room_manager.take_due_digests().is_empty());
+
+    clock.advance(Duration::from_secs(60 * 60 * 24));
+
+    let digests = room_manager.take_due_digests();
+
+    assert_eq!(digests.len(), 1);
+    assert_eq!(digests[0].stats.tracks_played, 0);
+    // Still connected users are carried over to the next digest
+    assert_eq!(digests[0].stats.listeners.len(), 1);
+
+    assert!(
+        RoomSettings {
+            daily_digest: Some(DailyDigest {
+                hour: 24,
+                utc_offset_mins: 0,
+            }),
+            ..Default::default()
+        }
+        .validate()
+        .is_err()
+    );
+}
+
+#[test]
+fn listening_stats_cover_the_whole_session() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    room_manager
+        .join_room(room_id, "A".into(), "a".into(), Default::default())
+        .unwrap();
+
+    for (user_id, track_id) in [(&owner, "1"), (&owner, "2"), (&"a".to_string(), "3")] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                user_id.clone(),
+                track_id.into(),
+                track_id.into(),
+                1000 * 60,
+                Default::default(),
+            )
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+    let playback = |track_id: &str, artist_name: &str, progress_ms| SpotifyCurrentPlaybackOutput {
+        track_id: track_id.into(),
+        artist_name: artist_name.into(),
+        is_playing: true,
+        progress_ms: Some(progress_ms),
+        duration_ms: 1000 * 60,
+        ..Default::default()
+    };
+
+    for (track_id, artist_name) in [("1", "Artist"), ("2", "Other"), ("3", "Artist")] {
+        room.record_listening_time(clock.now());
+        room.set_now_playing(Some(playback(track_id, artist_name, 0)));
+        room.now_playing_at = Some(clock.now());
+        // Refetched mid-track
+        clock.advance(Duration::from_secs(20));
+        room.record_listening_time(clock.now());
+        room.set_now_playing(Some(playback(track_id, artist_name, 1000 * 20)));
+        room.now_playing_at = Some(clock.now());
+        // Capped at the end of the track
+        clock.advance(Duration::from_secs(60));
+    }
+
+    room.listening_stats.record_skip(&"a".to_string());
+
+    let stats = command_response::ListeningStats::from(&room.listening_stats);
+
+    assert_eq!(stats.tracks_played, 3);
+    // The last track is still playing
+    assert_eq!(stats.listening_time_secs, 60 * 2 + 20);
+    assert_eq!(
+        stats
+            .users
+            .iter()
+            .map(|user| (user.user_id.as_str(), user.tracks_queued, user.skips))
+            .collect::<Vec<_>>(),
+        [("owner", 2, 0), ("a", 1, 1)]
+    );
+    assert_eq!(
+        stats
+            .top_artists
+            .iter()
+            .map(|artist| (artist.artist_name.as_str(), artist.plays))
+            .collect::<Vec<_>>(),
+        [("Artist", 2), ("Other", 1)]
+    );
+}
+
+#[test]
+fn content_policy_denies_types_and_markets() {
+    let policy = ContentPolicy::parse(" Episode,local,unknown,", "fr, de,");
+
+    assert_eq!(
+        policy.denied_types.iter().collect::<Vec<_>>(),
+        [&ContentType::Episode, &ContentType::Local]
+    );
+    assert_eq!(
+        policy.check_type(ContentType::Episode),
+        Err(ContentPolicyError::TypeDenied(ContentType::Episode))
+    );
+    assert!(policy.check_type(ContentType::Playlist).is_ok());
+    assert_eq!(
+        ContentType::of_track_id("spotify:local:artist:album:title:180"),
+        ContentType::Local
+    );
+
+    assert_eq!(
+        policy.check_market(Some("fr")),
+        Err(ContentPolicyError::MarketDenied("fr".into()))
+    );
+    assert!(policy.check_market(Some("US")).is_ok());
+    assert!(policy.check_market(None).is_ok());
+
+    let queue: payloads::Queue = serde_json::from_value(serde_json::json!({
+        "queue": [
+            {
+                "type": "track",
+                "id": "track_id",
+                "uri": "spotify:track:track_id",
+                "name": "Track",
+                "artists": [],
+                "duration_ms": 180000,
+                "album": null
+            },
+            {
+                "type": "track",
+                "id": null,
+                "uri": "spotify:local:artist:album:title:180",
+                "name": "Local file",
+                "artists": [],
+                "duration_ms": 180000,
+                "album": null
+            },
+            {
+                "type": "episode",
+                "id": "episode_id",
+                "name": "Episode",
+                "duration_ms": 3600000,
+                "show": { "name": "Show" },
+                "images": []
+            }
+        ]
+    }))
+    .unwrap();
+
+    let mut tracks = queue
+        .queue
+        .into_iter()
+        .filter_map(payloads::Playable::into_track)
+        .collect::<Vec<_>>();
+
+    policy.filter_tracks(&mut tracks);
+
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].track_id, "track_id");
+
+    let denied = command_response::Type::from(ContentPolicyError::TypeDenied(ContentType::Local));
+
+    assert!(matches!(
+        denied,
+        command_response::Type::ContentDenied(command_response::ContentDenied {
+            reason: Some(command_response::content_denied::Reason::ContentType(content_type)),
+        }) if content_type == crate::proto::spotify::ContentType::Local as i32
+    ));
+}
+
+#[test]
+fn room_events_strip_the_members_data() {
+    let room = crate::proto::room::Room {
+        name: "Room".into(),
+        password: "password".into(),
+        invite_code: "code".into(),
+        banned_users: vec!["banned".into()],
+        tracks_queue: vec![crate::proto::room::RoomTrack {
+            user_id: "user".into(),
+            track_id: "track_id".into(),
+            track_name: "Track".into(),
+            track_duration: 1000,
+        }],
+        ..Default::default()
+    };
+
+    let (name, cmd) = events::public_event(CommandResponse {
+        r#type: Some(command_response::Type::Room(room)),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let Some(command_response::Type::Room(room)) = &cmd.r#type else {
+        panic!("Expected a room event");
+    };
+
+    assert_eq!(name, "room");
+    assert_eq!(room.name, "Room");
+    assert!(room.password.is_empty());
+    assert!(room.invite_code.is_empty());
+    assert!(room.banned_users.is_empty());
+    assert!(room.tracks_queue[0].user_id.is_empty());
+    assert_eq!(room.tracks_queue[0].track_id, "track_id");
+
+    let event = events::sse_event(name, &cmd).unwrap();
+
+    assert!(event.starts_with(b"event: room\ndata: {"));
+    assert!(event.ends_with(b"}\n\n"));
+
+    assert!(
+        events::public_event(CommandResponse {
+            r#type: Some(command_response::Type::UserConnected("user".into())),
+            ..Default::default()
+        })
+        .is_none()
+    );
+}
+
+#[test]
+fn session_tokens_are_bound_to_the_room_user() {
+    let now = chrono::Utc::now();
+    let (room_id, other_room_id) = (uuid::Uuid::now_v7(), uuid::Uuid::now_v7());
+    let user_id = encode_user_email("test@email.com".into(), LENGTH);
+
+    let token = identity::issue_session_token(room_id, &user_id, now).unwrap();
+
+    assert_eq!(
+        identity::verify_session_token(&token, room_id, now),
+        Ok(user_id.clone())
+    );
+    assert_eq!(
+        identity::verify_session_token(&token, other_room_id, now),
+        Err(IdentityError::InvalidToken)
+    );
+    assert_eq!(
+        identity::verify_session_token(&token, room_id, now + identity::SESSION_TOKEN_TTL),
+        Err(IdentityError::Expired)
+    );
+
+    // Another user ID with the same signature
+    let (payload, signature) = token.rsplit_once('.').unwrap();
+    let (_, expires) = payload.rsplit_once('.').unwrap();
+
+    assert_eq!(
+        identity::verify_session_token(
+            &format!(
+                "{room_id}.{}.{expires}.{signature}",
+                encode_user_email("other@email.com".into(), LENGTH)
+            ),
+            room_id,
+            now
+        ),
+        Err(IdentityError::InvalidToken)
+    );
+
+    // An identity token of the same user isn't a session token
+    let (identity_token, _) = identity::issue_identity_token(&user_id, now).unwrap();
+
+    assert!(identity::verify_session_token(&identity_token, room_id, now).is_err());
+}
+
+#[test]
+fn read_only_commands_skip_the_mutation_queue() {
+    for cmd_type in [
+        command::Type::GetRoom(true),
+        command::Type::Search("query".into()),
+        command::Type::GetLogs(command::GetLogs::default()),
+        command::Type::GetSpotifyStatus(true),
+    ] {
+        assert_eq!(WSCmd::get_cmd_access(&cmd_type), CommandAccess::Read);
+    }
+
+    for cmd_type in [
+        command::Type::AddToQueue(command::AddTrackToQueue::default()),
+        command::Type::SkipNext(true),
+        command::Type::UpdateRoomSettings(Default::default()),
+        command::Type::LeaveRoom(true),
+    ] {
+        assert_eq!(WSCmd::get_cmd_access(&cmd_type), CommandAccess::Write);
+    }
+}
+
+#[actix_rt::test]
+async fn mutations_wait_their_turn_while_reads_go_through() {
+    let (_, room_manager, room_id) = mock_room_manager();
+    let state = Arc::new(tokio::sync::RwLock::new(room_manager));
+    let mutation_queue = Arc::clone(
+        &state
+            .read()
+            .await
+            .get_room(&room_id)
+            .unwrap()
+            .mutation_queue,
+    );
+    let create_role = |name: &str| {
+        let cmd = WSCmd::new(
+            Arc::clone(&state),
+            "owner".into(),
+            room_id,
+            command::Type::CreateRole(command::CreateRole {
+                name: name.into(),
+                permissions: Some(Default::default()),
+                display: None,
+            }),
+        );
+
+        actix_rt::spawn(cmd.process())
+    };
+
+    // A mutation in flight, e.g. waiting on Spotify
+    let in_flight = mutation_queue.lock().await;
+
+    let first = create_role("First");
+    actix_rt::time::sleep(Duration::from_millis(20)).await;
+    let second = create_role("Second");
+
+    let (result, _) = actix_rt::time::timeout(
+        Duration::from_secs(1),
+        WSCmd::new(
+            Arc::clone(&state),
+            "owner".into(),
+            room_id,
+            command::Type::GetRoom(true),
+        )
+        .process(),
+    )
+    .await
+    .expect("The read waited behind the mutation");
+
+    assert!(matches!(result, Ok(Some(command_response::Type::Room(_)))));
+
+    actix_rt::time::sleep(Duration::from_millis(20)).await;
+
+    assert!(!first.is_finished() && !second.is_finished());
+
+    drop(in_flight);
+
+    assert!(first.await.unwrap().0.is_ok());
+    assert!(second.await.unwrap().0.is_ok());
+
+    // Applied in the order they were queued
+    let guard = state.read().await;
+    let room = guard.get_room(&room_id).unwrap();
+    let role_logs = room
+        .logs
+        .iter()
+        .filter(|log| log.r#type == LogType::RoleChange)
+        .map(|log| log.details.as_str())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        role_logs,
+        [
+            "User \"Owner\" created the role \"First\"",
+            "User \"Owner\" created the role \"Second\"",
+        ]
+    );
+}
+
+#[test]
+fn spotify_logins_and_grants_are_single_use() {
+    let (clock, mut room_manager, _) = mock_room_manager();
+    let client = IpAddr::from([127, 0, 0, 1]);
+
+    let (state, code_verifier) = room_manager.begin_spotify_login(client).unwrap();
+
+    assert_eq!(
+        generate_code_challenge(code_verifier.clone()).len(),
+        43,
+        "The code challenge must be an unpadded base64 SHA-256"
+    );
+    assert_eq!(room_manager.take_spotify_login(&state), Some(code_verifier));
+    assert_eq!(room_manager.take_spotify_login(&state), None);
+
+    let (state, _) = room_manager.begin_spotify_login(client).unwrap();
+
+    clock.advance(SPOTIFY_LOGIN_TTL);
+
+    assert_eq!(room_manager.take_spotify_login(&state), None);
+
+    for _ in 0..MAX_PENDING_SPOTIFY_LOGINS_PER_CLIENT {
+        assert!(room_manager.begin_spotify_login(client).is_some());
+    }
+    assert!(
+        room_manager.begin_spotify_login(client).is_none(),
+        "A client must not hold more than MAX_PENDING_SPOTIFY_LOGINS_PER_CLIENT logins"
+    );
+    assert!(
+        room_manager
+            .begin_spotify_login(IpAddr::from([127, 0, 0, 2]))
+            .is_some()
+    );
+
+    clock.advance(SPOTIFY_LOGIN_TTL);
+    room_manager.prune_spotify_logins();
+
+    assert!(
+        room_manager.begin_spotify_login(client).is_some(),
+        "Expired logins must not count towards the per client throttle"
+    );
+
+    let tokens = SpotifyTokens::new("access", "refresh", 3600, Timestamp::from(0)).unwrap();
+    let (grant, _) = room_manager.store_spotify_grant(tokens.clone());
+
+    assert_eq!(
+        room_manager
+            .take_spotify_grant(&grant)
+            .map(|tokens| tokens.refresh_token)
+            .ok(),
+        Some(tokens.refresh_token.clone())
+    );
+    assert!(matches!(
+        room_manager.take_spotify_grant(&grant),
+        Err(RoomError::InvalidSpotifyGrant)
+    ));
+
+    let (grant, _) = room_manager.store_spotify_grant(tokens);
+
+    clock.advance(SPOTIFY_GRANT_TTL);
+
+    assert!(matches!(
+        room_manager.take_spotify_grant(&grant),
+        Err(RoomError::InvalidSpotifyGrant)
+    ));
+}
+
+#[test]
+fn max_track_duration_rejects_longer_tracks() {
+    let settings = RoomSettings {
+        max_track_duration: Some(Duration::from_secs(60 * 10)),
+        ..Default::default()
+    };
+
+    assert!(settings.validate().is_ok());
+    assert!(
+        settings
+            .check_track_duration("short", 1000 * 60 * 10)
+            .is_ok()
+    );
+    assert!(
+        RoomSettings::default()
+            .check_track_duration("mix", 1000 * 60 * 60)
+            .is_ok()
+    );
+
+    let err = settings
+        .check_track_duration("mix", 1000 * 60 * 30)
+        .unwrap_err();
+
+    assert_eq!(
+        command_response::Type::from(err),
+        command_response::Type::TrackTooLong(command_response::TrackTooLong {
+            track_id: "mix".into(),
+            track_duration_ms: 1000 * 60 * 30,
+            max_track_duration_secs: 60 * 10,
+        })
+    );
+
+    for max_track_duration in [Duration::from_secs(59), Duration::from_secs(60 * 60 * 4)] {
+        assert!(
+            RoomSettings {
+                max_track_duration: Some(max_track_duration),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    // 0 disables the limit on the wire
+    let proto_settings = crate::proto::room::RoomSettings::from(settings);
+
+    assert_eq!(proto_settings.max_track_duration_secs, 60 * 10);
+    assert_eq!(
+        RoomSettings::from(crate::proto::room::RoomSettings {
+            max_track_duration_secs: 0,
+            ..proto_settings
+        })
+        .max_track_duration,
+        None
+    );
+}
+
+#[test]
+fn volume_limits_reject_out_of_range_volumes() {
+    let volume_limits = VolumeLimits { min: 10, max: 60 };
+    let settings = RoomSettings {
+        volume_limits: Some(volume_limits),
+        ..Default::default()
+    };
+
+    assert!(settings.validate().is_ok());
+    assert!(settings.check_volume(10).is_ok());
+    assert!(settings.check_volume(60).is_ok());
+    assert!(RoomSettings::default().check_volume(100).is_ok());
+
+    let err = settings.check_volume(100).unwrap_err();
+
+    assert_eq!(
+        command_response::Type::from(err),
+        command_response::Type::VolumeOutOfRange(command_response::VolumeOutOfRange {
+            volume: 100,
+            volume_limits: Some(crate::proto::room::VolumeLimits {
+                min_volume: 10,
+                max_volume: 60,
+            }),
+        })
+    );
+    assert!(settings.check_volume(5).is_err());
+
+    for (min, max) in [(70, 60), (0, 101)] {
+        assert!(
+            RoomSettings {
+                volume_limits: Some(VolumeLimits { min, max }),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    // Out of range values on the wire can't wrap into valid limits
+    let from_proto = RoomSettings::from(crate::proto::room::RoomSettings {
+        volume_limits: Some(crate::proto::room::VolumeLimits {
+            min_volume: 0,
+            max_volume: 256 + 50,
+        }),
+        ..crate::proto::room::RoomSettings::from(RoomSettings::default())
+    });
+
+    assert_eq!(
+        from_proto.volume_limits,
+        Some(VolumeLimits {
+            min: 0,
+            max: u8::MAX
+        })
+    );
+    assert!(from_proto.validate().is_err());
+}
+
+#[test]
+fn explicit_tracks_are_rejected_when_disallowed() {
+    assert!(
+        RoomSettings::default()
+            .check_explicit("explicit", true)
+            .is_ok()
+    );
+
+    let settings = RoomSettings {
+        allow_explicit: false,
+        ..Default::default()
+    };
+
+    assert!(settings.check_explicit("clean", false).is_ok());
+    assert_eq!(
+        command_response::Type::from(settings.check_explicit("explicit", true).unwrap_err()),
+        command_response::Type::ExplicitTrackDenied(command_response::ExplicitTrackDenied {
+            track_id: "explicit".into(),
+        })
+    );
+
+    // Unset on the wire allows them
+    let proto_settings = crate::proto::room::RoomSettings::from(settings);
+
+    assert_eq!(proto_settings.allow_explicit, Some(false));
+    assert!(
+        RoomSettings::from(crate::proto::room::RoomSettings {
+            allow_explicit: None,
+            ..proto_settings
+        })
+        .allow_explicit
+    );
+}
+
+#[test]
+fn spotify_tokens_are_encrypted_and_redacted() {
+    let tokens = SpotifyTokens::new("access", "refresh", 3600, Timestamp::from(0)).unwrap();
+
+    assert_eq!(tokens.access_token.reveal().unwrap(), "access");
+    assert_eq!(tokens.refresh_token.reveal().unwrap(), "refresh");
+    // A fresh nonce is used for every seal
+    assert_ne!(Secret::seal("access").unwrap(), tokens.access_token);
+
+    let debug = format!("{tokens:?}");
+    let json = serde_json::to_string(&tokens).unwrap();
+
+    for output in [debug, json] {
+        assert!(!output.contains("\"access\"") && !output.contains("\"refresh\""));
+        assert!(output.contains("[redacted]"));
+    }
+
+    assert_eq!(Secret::seal("").unwrap().reveal().unwrap(), "");
+    assert_eq!(
+        Secret::default().reveal(),
+        Ok(String::new()),
+        "The tokens of the rooms without Spotify account are empty"
+    );
+}
+
+#[test]
+fn album_image_rotation_is_detected() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    let playback = SpotifyCurrentPlaybackOutput {
+        track_id: "track".into(),
+        album_image_src: "https://i.scdn.co/image/a".into(),
+        ..Default::default()
+    };
+    let rotated = SpotifyCurrentPlaybackOutput {
+        album_image_src: "https://i.scdn.co/image/b".into(),
+        ..playback.clone()
+    };
+
+    assert!(!room.set_now_playing(Some(playback.clone())));
+    assert!(!room.set_now_playing(Some(playback.clone())));
+    assert!(room.set_now_playing(Some(rotated.clone())));
+    // Another track isn't a rotation
+    assert!(!room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        track_id: "other".into(),
+        ..playback.clone()
+    })));
+
+    let revision = image_revision(&playback.album_image_src);
+
+    assert_eq!(revision.len(), 16);
+    assert_eq!(revision, image_revision(&playback.album_image_src));
+    assert_ne!(revision, image_revision(&rotated.album_image_src));
+    assert_eq!(image_revision(""), "");
+    assert_eq!(
+        crate::proto::spotify::PlaybackState::from(playback).album_image_rev,
+        revision
+    );
+}
+
+#[test]
+fn taste_seeds_weigh_each_connected_member_equally() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "Alice".into(), "alice".into(), Default::default())
+        .unwrap();
+    room_manager
+        .join_room(room_id, "Bob".into(), "bob".into(), Default::default())
+        .unwrap();
+
+    for (user_id, track_id) in [
+        ("alice", "a1"),
+        ("alice", "a2"),
+        ("bob", "b1"),
+        ("owner", "o1"),
+    ] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                user_id.into(),
+                track_id.into(),
+                track_id.to_uppercase(),
+                0,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    for user in room.users.iter_mut() {
+        // The owner isn't connected so its tracks aren't seeds
+        user.is_connected = user.id != "owner";
+    }
+
+    // a1 was played, its submitter is kept in the history
+    room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        track_id: "a1".into(),
+        track_name: "A1".into(),
+        ..Default::default()
+    }));
+    room.tracks_queue.retain(|track| track.track_id != "a1");
+
+    assert_eq!(room.history[0].added_by.as_deref(), Some("alice"));
+
+    let seeds = recommendation::taste_seeds(room);
+    let weights = seeds
+        .iter()
+        .map(|seed| (seed.track_id.as_str(), seed.weight))
+        .collect::<Vec<_>>();
+
+    // The latest contribution of a member weighs twice the previous one
+    assert_eq!(weights, [("a2", 2. / 3.), ("a1", 1. / 3.), ("b1", 1.)]);
+    assert_eq!(seeds[0].explanation(), "because Alice queued A2");
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut picked_bob = 0;
+
+    for _ in 0..1000 {
+        if recommendation::pick_seed(&seeds, &mut rng).unwrap().user_id == "bob" {
+            picked_bob += 1;
+        }
+    }
+
+    assert!(
+        (400..600).contains(&picked_bob),
+        "Bob picked {picked_bob} times"
+    );
+    assert!(recommendation::pick_seed(&[], &mut rng).is_none());
+    assert!(recommendation::known_track_ids(room).contains("a1"));
+}
+
+#[test]
+fn seeded_random_makes_secrets_reproducible() {
+    let create_room = |seed| {
+        let mut room_manager = RoomManager::new(
+            Arc::new(MockClock::default()),
+            Arc::new(SeededRandom::new(seed)),
+        );
+        let room = room_manager
+            .create_room(
+                "owner".into(),
+                "Owner".into(),
+                "Room".into(),
+                Default::default(),
+                Default::default(),
+            )
+            .unwrap();
+        let resume_token = room_manager
+            .issue_resume_token(room.id, &"owner".to_owned())
+            .unwrap();
+
+        (room.password, room.invite_code, resume_token)
+    };
+
+    let (password, invite_code, resume_token) = create_room(42);
+
+    assert_eq!(
+        (password.clone(), invite_code.clone(), resume_token.clone()),
+        create_room(42)
+    );
+    assert_ne!(password, create_room(43).0);
+
+    assert_eq!(password.len(), ROOM_PASSWORD_LEN);
+    assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_eq!(resume_token.len(), RESUME_TOKEN_LEN);
+    assert!(resume_token.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_eq!(invite_code.len(), INVITE_CODE_LEN);
+    assert!(
+        invite_code
+            .bytes()
+            .all(|c| INVITE_CODE_CHARSET.contains(&c))
+    );
+
+    let code_verifier = generate_code_verifier(&SeededRandom::new(42));
+
+    assert_eq!(
+        code_verifier,
+        generate_code_verifier(&SeededRandom::new(42))
+    );
+    assert_eq!(code_verifier.len(), CODE_VERIFIER_LEN);
+    assert!(code_verifier.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_ne!(
+        generate_code_verifier(&SystemRandom),
+        generate_code_verifier(&SystemRandom)
+    );
+}
+
+#[test]
+fn random_strings_are_uniform_over_the_charset() {
+    let random = SeededRandom::new(7);
+    let samples = random.string_from(INVITE_CODE_CHARSET, INVITE_CODE_CHARSET.len() * 1000);
+
+    assert_eq!(samples.len(), INVITE_CODE_CHARSET.len() * 1000);
+
+    // Each char is expected 1000 times, a modulo bias or a missing char would be way off
+    for c in INVITE_CODE_CHARSET {
+        let count = samples.bytes().filter(|b| b == c).count();
+
+        assert!(
+            (850..1150).contains(&count),
+            "{} drawn {count} times",
+            *c as char
+        );
+    }
+
+    // ~5.95 bits per alphanumeric char, a 16 chars password is above 95 bits
+    let entropy_bits = ROOM_PASSWORD_LEN as f64 * (ALPHANUMERIC_CHARSET.len() as f64).log2();
+
+    assert!(entropy_bits > 95.);
+}
+
+#[test]
+fn admin_api_lists_rooms_and_kicks_users() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), "guest".into(), Default::default())
+        .unwrap();
+
+    let summaries = room_manager.admin_room_summaries();
+
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].id, room_id);
+    assert_eq!(summaries[0].users_count, 2);
+    assert!(!summaries[0].is_closing);
+
+    assert!(matches!(
+        room_manager.admin_kick_user(room_id, &"unknown".into(), "spam".into()),
+        Err(RoomError::RoomUserNotFound)
+    ));
+
+    room_manager
+        .admin_kick_user(room_id, &"guest".into(), "spam".into())
+        .unwrap();
+
+    assert_eq!(room_manager.admin_room_summaries()[0].users_count, 1);
+
+    let (logs, _) = room_manager
+        .get_logs(room_id, &LogFilter::default(), 0, 1)
+        .unwrap();
+
+    assert_eq!(logs[0].r#type, LogType::Kick);
+    assert!(logs[0].author_id.is_none());
+
+    let rate_limiters = room_manager.rate_limiters();
+
+    assert_eq!(rate_limiters.len(), 1);
+    assert_eq!(rate_limiters[0].0, room_id);
+
+    let mut rate_limiter = RateLimiter::new(clock.clone());
+
+    while rate_limiter.increment().is_ok() {}
+
+    let state = rate_limiter.state();
+
+    assert!(state.is_rate_limited);
+    assert_eq!(
+        state.window_remaining_secs,
+        RATE_LIMIT_REQUEST_WINDOW.as_secs()
+    );
+
+    clock.advance(RATE_LIMIT_REQUEST_WINDOW + Duration::from_millis(1));
+
+    assert_eq!(rate_limiter.state(), Default::default());
+}
+
+#[test]
+fn ownerless_rooms_are_closed_or_transferred() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+    let guest = "guest".to_string();
+    let auto_promote_owner = RoomSettings {
+        auto_promote_owner: true,
+        ..Default::default()
+    };
+
+    // The room's setting takes precedence over the policy of the server
+    for policy in [OwnerlessRoomPolicy::Close, OwnerlessRoomPolicy::Transfer] {
+        assert_eq!(RoomSettings::default().ownerless_policy(policy), policy);
+        assert_eq!(
+            auto_promote_owner.ownerless_policy(policy),
+            OwnerlessRoomPolicy::Transfer
+        );
+    }
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &guest, true)
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &owner, true)
+        .unwrap();
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close),
+        RoomOwnership::Managed
+    );
+
+    // The owner's session expired without LeaveRoom
+    room_manager
+        .set_ws_user_state(room_id, &owner, false)
+        .unwrap();
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close),
+        RoomOwnership::Managed
+    );
+
+    clock.advance(OWNERLESS_ROOM_GRACE_PERIOD);
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close),
+        RoomOwnership::Ownerless
+    );
+
+    // Same absence with the Transfer policy
+    room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Transfer);
+    clock.advance(OWNERLESS_ROOM_GRACE_PERIOD);
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Transfer),
+        RoomOwnership::Transferred(guest.clone())
+    );
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users[1].role_id, room.users[0].role_id);
+
+    drop(room);
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close),
+        RoomOwnership::Managed
+    );
+}
+
+#[test]
+fn display_events_flatten_the_room_broadcasts() {
+    let event = events::display_event(&CommandResponse {
+        r#type: Some(command_response::Type::SpotifyPlaybackState(
+            command_response::SpotifyPlaybackState {
+                state: Some(crate::proto::spotify::PlaybackState {
+                    track_id: "track_id".into(),
+                    track_name: "Track".into(),
+                    device_id: "device".into(),
+                    is_playing: true,
+                    duration_ms: 1000,
+                    ..Default::default()
+                }),
+            },
+        )),
+        ..Default::default()
+    })
+    .unwrap();
+    let event = std::str::from_utf8(&event).unwrap();
+
+    assert!(event.starts_with("event: now_playing\ndata: {"));
+    assert!(event.contains(r#""track_name":"Track""#));
+    assert!(event.contains(r#""item_type":"Track""#));
+    assert!(!event.contains("device"));
+
+    let event = events::display_event(&CommandResponse {
+        r#type: Some(command_response::Type::Room(crate::proto::room::Room {
+            password: "password".into(),
+            tracks_queue: vec![crate::proto::room::RoomTrack {
+                user_id: "user".into(),
+                track_id: "track_id".into(),
+                track_name: "Track".into(),
+                track_duration: 1000,
+            }],
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::str::from_utf8(&event).unwrap(),
+        "event: queue\ndata: {\"tracks\":[{\"track_id\":\"track_id\",\"track_name\":\"Track\",\"duration_ms\":1000}]}\n\n"
+    );
+
+    assert_eq!(
+        events::display_event(&CommandResponse {
+            r#type: Some(command_response::Type::TrackTransition(
+                command_response::TrackTransition::default(),
+            )),
+            ..Default::default()
+        })
+        .as_deref(),
+        Some(&b"event: now_playing\ndata: null\n\n"[..])
+    );
+    assert!(
+        events::display_event(&CommandResponse {
+            r#type: Some(command_response::Type::UserConnected("user".into())),
+            ..Default::default()
+        })
+        .is_none()
+    );
+}
+
+#[test]
+fn user_profiles_are_validated_and_sent_with_the_room() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let guest = "guest".to_owned();
+    let profile = |avatar_url: Option<&str>, color: Option<&str>| UserProfile {
+        avatar_url: avatar_url.map(Into::into),
+        color: color.map(Into::into),
+    };
+
+    for invalid in [
+        profile(Some("http://example.com/avatar.png"), None),
+        profile(Some("https://"), None),
+        profile(Some("https://example.com/my avatar.png"), None),
+        profile(None, Some("red")),
+        profile(None, Some("#12345G")),
+    ] {
+        assert!(matches!(
+            room_manager.join_room(room_id, "Guest".into(), guest.clone(), invalid),
+            Err(RoomError::InvalidProfile)
+        ));
+    }
+
+    let room = room_manager
+        .join_room(
+            room_id,
+            "Guest".into(),
+            guest.clone(),
+            profile(Some("https://example.com/avatar.png"), Some("#1DB954")),
+        )
+        .unwrap();
+    let proto_room = crate::proto::room::Room::from(room);
+    let proto_profile = proto_room.users[1].profile.as_ref().unwrap();
+
+    assert_eq!(
+        proto_profile.avatar_url.as_deref(),
+        Some("https://example.com/avatar.png")
+    );
+    assert_eq!(proto_profile.color.as_deref(), Some("#1DB954"));
+
+    assert!(matches!(
+        room_manager.update_profile(room_id, &guest, profile(None, Some("blue"))),
+        Err(RoomError::InvalidProfile)
+    ));
+    // The whole profile is replaced
+    room_manager
+        .update_profile(room_id, &guest, profile(None, Some("#000000")))
+        .unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users[1].profile, profile(None, Some("#000000")));
+}
+
+#[test]
+fn usernames_are_trimmed_unique_and_logged_when_changed() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let guest = "guest".to_owned();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    for username in ["   ".into(), "a".repeat(33)] {
+        assert!(matches!(
+            room_manager.change_username(room_id, guest.clone(), username),
+            Err(RoomError::InvalidUsername)
+        ));
+    }
+
+    assert!(matches!(
+        room_manager.change_username(room_id, guest.clone(), " OWNER ".into()),
+        Err(RoomError::UsernameTaken)
+    ));
+
+    let mut events = room_manager.subscribe_events();
+
+    // Only the case of its own username changes
+    room_manager
+        .change_username(room_id, guest.clone(), " guest ".into())
+        .unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users[1].username, "guest");
+    assert_eq!(room.logs.back().unwrap().r#type, LogType::UsernameChange);
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::UsernameChanged {
+            room_id,
+            user_id: guest.clone(),
+        })
+    );
+
+    let logs_len = room.logs.len();
+
+    drop(room);
+
+    // Unchanged
+    room_manager
+        .change_username(room_id, guest.clone(), "guest".into())
+        .unwrap();
+
+    assert_eq!(
+        room_manager.get_room(&room_id).unwrap().logs.len(),
+        logs_len
+    );
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn ownership_transfer_demotes_the_previous_owner() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+    let guest = "guest".to_string();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    assert!(matches!(
+        room_manager.transfer_ownership(room_id, &guest, &owner),
+        Err(RoomError::Unauthorized)
+    ));
+    assert!(matches!(
+        room_manager.transfer_ownership(room_id, &owner, &"unknown".into()),
+        Err(RoomError::RoomUserNotFound)
+    ));
+
+    let mut events = room_manager.subscribe_events();
+
+    room_manager
+        .transfer_ownership(room_id, &owner, &guest)
+        .unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+    let roles = room.role_manager.get_roles();
+
+    assert_eq!(room.users[1].role_id, roles[0].id);
+    assert_eq!(room.users[0].role_id, roles[1].id);
+    assert!(!roles[1].permissions.can_manage_room);
+    // Each member is notified of its new role
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::RoleChanged {
+            room_id,
+            user_id: guest.clone(),
+            role_id: roles[0].id,
+        })
+    );
+    assert_eq!(
+        events.try_recv(),
+        Ok(RoomEvent::RoleChanged {
+            room_id,
+            user_id: owner.clone(),
+            role_id: roles[1].id,
+        })
+    );
+
+    drop(room);
+
+    // The previous owner can now leave without closing the room
+    assert!(
+        !room_manager
+            .is_user_an_owner_and_alone(room_id, &owner)
+            .unwrap()
+    );
+
+    room_manager.leave_room(room_id, owner).unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users.len(), 1);
+    assert_eq!(
+        room.logs
+            .iter()
+            .filter(|log| log.r#type == LogType::RoleChange)
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn auto_promote_owner_keeps_the_room_open() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    for user_id in ["guest", "moderator", "vip"] {
+        room_manager
+            .join_room(room_id, user_id.into(), user_id.into(), Default::default())
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+    let moderator_role_id = room.role_manager.get_role_by_name("Moderator").unwrap().id;
+
+    room.users[2].role_id = moderator_role_id;
+
+    // Disabled by default, the room is closed with its last owner
+    assert_eq!(room_manager.fallback_owner_on_leave(room_id, &owner), None);
+
+    room_manager
+        .update_room_settings(
+            room_id,
+            &owner,
+            RoomSettings {
+                auto_promote_owner: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    // The most powerful role is preferred to the longest present member
+    assert_eq!(
+        room_manager.fallback_owner_on_leave(room_id, &owner),
+        Some("moderator".into())
+    );
+
+    room_manager.leave_room(room_id, owner).unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+    let owner_role_id = room.role_manager.get_roles()[0].id;
+
+    assert_eq!(room.users[1].role_id, owner_role_id);
+
+    drop(room);
+
+    // It also overrides the Close policy when the owner's session is gone
+    let moderator = "moderator".to_string();
+
+    room_manager
+        .set_ws_user_state(room_id, &"vip".into(), true)
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &moderator, false)
+        .unwrap();
+    room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close);
+    clock.advance(OWNERLESS_ROOM_GRACE_PERIOD);
+
+    assert_eq!(
+        room_manager.check_room_owners(room_id, OwnerlessRoomPolicy::Close),
+        RoomOwnership::Transferred("vip".into())
+    );
+
+    // Nobody left to promote
+    room_manager.leave_room(room_id, "guest".into()).unwrap();
+    room_manager.leave_room(room_id, moderator).unwrap();
+
+    assert_eq!(
+        room_manager.fallback_owner_on_leave(room_id, &"vip".into()),
+        None
+    );
+
+    room_manager.leave_room(room_id, "vip".into()).unwrap();
+
+    assert!(room_manager.get_room(&room_id).is_none());
+}
+
+#[test]
+fn admin_kicks_of_the_last_owner_hand_the_room_over() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let (owner, guest) = (RoomUserID::from("owner"), RoomUserID::from("guest"));
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+    room_manager.issue_resume_token(room_id, &owner).unwrap();
+    room_manager
+        .update_room_settings(
+            room_id,
+            &owner,
+            RoomSettings {
+                auto_promote_owner: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    room_manager
+        .admin_kick_user(room_id, &owner, "spam".into())
+        .unwrap();
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert_eq!(room.users.len(), 1);
+    assert!(
+        room.role_manager
+            .get_role_by_id(&room.users[0].role_id)
+            .unwrap()
+            .permissions
+            .can_manage_room
+    );
+    // Its session can't be resumed anymore
+    assert!(!room.resume_tokens.contains_key(&owner));
+
+    drop(room);
+
+    // Nobody left to promote
+    room_manager
+        .admin_kick_user(room_id, &guest, "spam".into())
+        .unwrap();
+
+    assert!(room_manager.get_room(&room_id).is_none());
+}
+
+#[test]
+fn correlation_ids_are_kept_per_room_user() {
+    let (_, room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    assert_eq!(room_manager.correlation_id(room_id, &owner), None);
+
+    room_manager
+        .set_correlation_id(room_id, &owner, "request_id".into())
+        .unwrap();
+
+    assert_eq!(
+        room_manager.correlation_id(room_id, &owner).as_deref(),
+        Some("request_id")
+    );
+    assert!(matches!(
+        room_manager.set_correlation_id(room_id, &"stranger".into(), "other".into()),
+        Err(RoomError::RoomUserNotFound)
+    ));
+}
+
+#[test]
+fn room_snapshots_embed_the_most_recent_logs() {
+    let (_, room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+    let logs_len = room_manager.get_room(&room_id).unwrap().logs.len() + SNAPSHOT_LOGS_LEN;
+
+    for idx in 0..SNAPSHOT_LOGS_LEN {
+        room_manager
+            .append_user_log(room_id, &owner, LogType::Other, format!("log {idx}"))
+            .unwrap();
+    }
+
+    let room = crate::proto::room::Room::from(room_manager.get_room(&room_id).unwrap().clone());
+
+    assert_eq!(room.logs_total, logs_len as u64);
+    assert_eq!(room.logs.len(), SNAPSHOT_LOGS_LEN);
+    assert!(room.logs[0].details.ends_with("log 0"));
+    assert!(
+        room.logs[SNAPSHOT_LOGS_LEN - 1]
+            .details
+            .ends_with(&format!("log {}", SNAPSHOT_LOGS_LEN - 1))
+    );
+}
+
+#[test]
+fn spotify_queue_tracks_are_attributed_to_their_submitter() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .join_room(room_id, "Alice".into(), "alice".into(), Default::default())
+        .unwrap();
+
+    for (user_id, track_id) in [
+        ("alice", "a"),
+        ("owner", "o"),
+        ("alice", "a"),
+        ("owner", "x"),
+    ] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                user_id.into(),
+                track_id.into(),
+                track_id.into(),
+                1000,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    // x isn't in the Spotify queue yet, the one in it comes from the playback context
+    room.pushed_tracks_len = 3;
+
+    let submitters = room
+        .queue_submitters(["a", "o", "a", "x"])
+        .into_iter()
+        .map(|user| user.map(|user| user.username.as_str()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        submitters,
+        [
+            Some("Alice"),
+            Some(room.users[0].username.as_str()),
+            Some("Alice"),
+            None
+        ]
+    );
+}
+
+#[test]
+fn role_content_gates_episodes_and_long_tracks() {
+    let content = RoleContent {
+        max_track_duration: Some(Duration::from_secs(60)),
+        deny_episodes: true,
+    };
+
+    assert!(
+        content
+            .check("track", 60_000, PlaybackItemType::Track)
+            .is_ok()
+    );
+    assert!(matches!(
+        content.check("track", 61_000, PlaybackItemType::Track),
+        Err(RoleContentError::TrackTooLong(TrackTooLong { max_track_duration, .. }))
+            if max_track_duration == Duration::from_secs(60)
+    ));
+    assert!(matches!(
+        content.check("episode", 1000, PlaybackItemType::Episode),
+        Err(RoleContentError::EpisodeDenied)
+    ));
+
+    // Roles are unrestricted by default
+    assert!(
+        RoleContent::default()
+            .check("episode", 3_600_000, PlaybackItemType::Episode)
+            .is_ok()
+    );
+}
+
+#[test]
+fn exported_tracks_skip_local_files_and_pre_session_plays() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    for (track_id, item_type) in [
+        ("track", PlaybackItemType::Track),
+        (
+            "spotify:local:artist:album:title:180",
+            PlaybackItemType::Track,
+        ),
+        ("episode", PlaybackItemType::Episode),
+    ] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                owner.clone(),
+                track_id.into(),
+                track_id.into(),
+                1000,
+                item_type,
+            )
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    assert_eq!(
+        room.export_tracks(ExportSource::Queue),
+        [
+            ("track".to_string(), PlaybackItemType::Track),
+            ("episode".to_string(), PlaybackItemType::Episode),
+        ]
+    );
+
+    // Most recent first
+    for (track_id, pre_session) in [("second", false), ("first", false), ("before", true)] {
+        room.history.push_back(HistoryTrack {
+            track: SpotifyTrack {
+                track_id: track_id.into(),
+                track_name: track_id.into(),
+                artist_name: "Artist".into(),
+                track_duration: 1000,
+                album_name: "Album".into(),
+                album_image_src: String::new(),
+                explicit: false,
+                popularity: None,
+                item_type: PlaybackItemType::Track,
+            },
+            played_at: chrono::Utc::now(),
+            pre_session,
+            added_by: None,
+        });
+    }
+
+    assert_eq!(
+        room.export_tracks(ExportSource::History),
+        [
+            ("first".to_string(), PlaybackItemType::Track),
+            ("second".to_string(), PlaybackItemType::Track),
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn smoke_test_runs_the_room_lifecycle() {
+    let report = crate::sharify::smoke::run(None).await;
+
+    assert!(report.passed, "{:?}", report.steps);
+    assert_eq!(
+        report
+            .steps
+            .iter()
+            .map(|step| step.name)
+            .collect::<Vec<_>>(),
+        [
+            "create_room",
+            "join_room",
+            "add_track_to_queue",
+            "skip_track",
+            "leave_room",
+            "close_room",
+        ]
+    );
+}
+
+#[test]
+fn connection_flags_are_reconciled_with_ws_sessions() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner = "owner".to_string();
+
+    room_manager
+        .join_room(
+            room_id,
+            "Member".into(),
+            "member".into(),
+            Default::default(),
+        )
+        .unwrap();
+
+    // Both flagged disconnected while the owner has a session
+    assert_eq!(
+        room_manager.reconcile_connections(|_, user_id| *user_id == owner),
+        (0, 1)
+    );
+    assert!(room_manager.get_room(&room_id).unwrap().users[0].is_connected);
+
+    // The owner lost its session without being suspended
+    assert_eq!(room_manager.reconcile_connections(|_, _| false), (1, 0));
+    assert_eq!(room_manager.reconcile_connections(|_, _| false), (0, 0));
+
+    clock.advance(DEFAULT_RECONNECT_GRACE_PERIOD + Duration::from_secs(1));
+
+    assert_eq!(
+        room_manager.expire_suspended_sessions(room_id),
+        vec![owner.clone()]
+    );
+}
+
+#[actix_rt::test]
+async fn spotify_responses_are_cached_per_access_token() {
+    let cache = FetchCache::<u32>::default();
+    let fetches = std::sync::atomic::AtomicU32::new(0);
+    let fetch = || async { Ok(fetches.fetch_add(1, std::sync::atomic::Ordering::Relaxed)) };
+    let ttl = Duration::from_secs(60);
+    let host = cache_key("host access token");
+    let clock = MockClock::default();
+
+    assert_eq!(
+        cache
+            .get_or_fetch(host, ttl, &clock, fetch())
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        cache
+            .get_or_fetch(host, ttl, &clock, fetch())
+            .await
+            .unwrap(),
+        0
+    );
+
+    // Concurrent fetches are coalesced
+    let (a, b) = futures_util::join!(
+        cache.get_or_fetch(cache_key("other"), ttl, &clock, fetch()),
+        cache.get_or_fetch(cache_key("other"), ttl, &clock, fetch()),
+    );
+    assert_eq!((a.unwrap(), b.unwrap()), (1, 1));
+
+    // Expired once the TTL elapsed on the clock
+    clock.advance(ttl);
+    assert_eq!(
+        cache
+            .get_or_fetch(cache_key("other"), ttl, &clock, fetch())
+            .await
+            .unwrap(),
+        2
+    );
+
+    cache.invalidate(&host).await;
+    assert_eq!(
+        cache
+            .get_or_fetch(host, ttl, &clock, fetch())
+            .await
+            .unwrap(),
+        3
+    );
+
+    // Failures aren't cached and a zero TTL disables the cache
+    let failure = async { Err(SpotifyError::Generic("failure".into())) };
+    assert!(
+        cache
+            .get_or_fetch(cache_key("failing"), ttl, &clock, failure)
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        cache
+            .get_or_fetch(cache_key("failing"), ttl, &clock, fetch())
+            .await
+            .unwrap(),
+        4
+    );
+    assert_eq!(
+        cache
+            .get_or_fetch(host, Duration::ZERO, &clock, fetch())
+            .await
+            .unwrap(),
+        5
+    );
+}
+
+#[test]
+fn ws_transport_config_is_validated() {
+    let transport = WsTransport::default();
+
+    assert_eq!(transport.validate(), Ok(()));
+    assert!(
+        WsTransport {
+            max_frame_size: MIN_WS_FRAME_SIZE - 1,
+            ..transport
+        }
+        .validate()
+        .is_err()
+    );
+    assert!(
+        WsTransport {
+            max_frame_size: MAX_WS_FRAME_SIZE + 1,
+            ..transport
+        }
+        .validate()
+        .is_err()
+    );
+    assert!(
+        WsTransport {
+            max_continuation_size: transport.max_frame_size - 1,
+            ..transport
+        }
+        .validate()
+        .is_err()
+    );
+    // Not aggregated, the continuation size is unused
+    assert_eq!(
+        WsTransport {
+            max_continuation_size: 0,
+            aggregate_continuations: false,
+            ..transport
+        }
+        .validate(),
+        Ok(())
+    );
+}
+
+#[test]
+fn room_tasks_are_shut_down_with_their_room() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let orphan_id = RoomID::nil();
+
+    assert!(room_manager.tasks_mut().spawn(
+        room_id,
+        RoomTaskKind::SpotifyData,
+        std::future::pending()
+    ));
+    // One task of each kind per room
+    assert!(!room_manager.tasks_mut().spawn(
+        room_id,
+        RoomTaskKind::SpotifyData,
+        std::future::pending()
+    ));
+    assert!(room_manager.tasks_mut().spawn(
+        room_id,
+        RoomTaskKind::TokenRefresh,
+        std::future::pending()
+    ));
+    assert!(room_manager.tasks_mut().spawn(
+        orphan_id,
+        RoomTaskKind::ActivityCheck,
+        std::future::pending()
+    ));
+
+    assert_eq!(room_manager.sweep_room_tasks(), 1);
+    assert!(
+        !room_manager
+            .tasks()
+            .is_running(orphan_id, RoomTaskKind::ActivityCheck)
+    );
+    assert!(
+        room_manager
+            .tasks()
+            .is_running(room_id, RoomTaskKind::SpotifyData)
+    );
+
+    room_manager.delete_room(room_id, None).unwrap();
+
+    assert!(
+        !room_manager
+            .tasks()
+            .is_running(room_id, RoomTaskKind::SpotifyData)
+    );
+    assert!(
+        !room_manager
+            .tasks()
+            .is_running(room_id, RoomTaskKind::TokenRefresh)
+    );
+    assert_eq!(room_manager.sweep_room_tasks(), 0);
+}
+
+#[actix_rt::test]
+async fn busy_rooms_dont_starve_the_http_workers() {
+    let mut tasks = RoomTasks::default();
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    // Rooms whose Spotify data tasks hog every room tasks thread with synchronous work
+    for _ in 0..64 {
+        let fetches = Arc::clone(&fetches);
+
+        tasks.spawn(RoomID::now_v7(), RoomTaskKind::SpotifyData, async move {
+            loop {
+                std::thread::sleep(Duration::from_millis(5));
+                fetches.fetch_add(1, Ordering::Relaxed);
+                tokio::task::yield_now().await;
+            }
+        });
+    }
+
+    // Meanwhile the worker runtime keeps its timers on time
+    let mut max_delay = Duration::ZERO;
+
+    for _ in 0..50 {
+        let started_at = std::time::Instant::now();
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+
+        max_delay = max_delay.max(started_at.elapsed());
+    }
+
+    assert!(max_delay < Duration::from_millis(100), "{max_delay:?}");
+    assert!(fetches.load(Ordering::Relaxed) > 0);
+
+    // Aborts the room tasks
+    drop(tasks);
+}
+
+#[test]
+fn idle_rooms_hibernate_until_a_user_connects() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+    let owner = RoomUserID::from("owner");
+    let is_polling = |room_manager: &RoomManager| {
+        room_manager
+            .tasks()
+            .is_running(room_id, RoomTaskKind::SpotifyData)
+    };
+
+    room_manager
+        .set_ws_user_state(room_id, &owner, true)
+        .unwrap();
+    room_manager
+        .tasks_mut()
+        .spawn(room_id, RoomTaskKind::SpotifyData, std::future::pending());
+
+    assert!(!room_manager.hibernate_if_idle(room_id));
+    assert!(is_polling(&room_manager));
+
+    room_manager
+        .set_ws_user_state(room_id, &owner, false)
+        .unwrap();
+
+    assert!(room_manager.hibernate_if_idle(room_id));
+    assert!(!room_manager.hibernate_if_idle(room_id));
+    assert!(!is_polling(&room_manager));
+    assert!(room_manager.admin_room_summaries()[0].is_hibernating);
+    // Still deleted after its inactivity_timeout only
+    assert!(room_manager.check_room_activity(room_id));
+
+    room_manager
+        .set_ws_user_state(room_id, &owner, true)
+        .unwrap();
+
+    assert!(!room_manager.get_room(&room_id).unwrap().hibernating);
+}
+
+#[test]
+fn room_frames_are_fanned_out_to_the_room_sessions() {
+    let room_id = RoomID::now_v7();
+    let cmd = CommandResponse {
+        r#type: Some(command_response::Type::SpotifyRecovered(true)),
+        ..Default::default()
+    };
+    let buf = Bytes::from(cmd.encode_to_vec());
+    let frame = |except: Option<&str>| RoomFrame::Broadcast {
+        update: Arc::new(RoomUpdate::new(vec![cmd.clone()])),
+        except: except.map(Into::into),
+    };
+    let encoding = SessionEncoding::default();
+
+    let mut first = fanout::subscribe(room_id);
+    let mut second = fanout::subscribe(room_id);
+
+    // Every session gets it, the excepted one skips it in its main loop
+    assert_eq!(fanout::send(room_id, frame(Some("first"))), 2);
+    assert_eq!(
+        *second
+            .try_recv()
+            .unwrap()
+            .frames_for(&"second".into(), encoding),
+        [SessionFrame::Binary(buf)]
+    );
+    assert!(
+        first
+            .try_recv()
+            .unwrap()
+            .frames_for(&"first".into(), encoding)
+            .is_empty()
+    );
+
+    // A slow session lags instead of blocking the others
+    for _ in 0..=ROOM_CHANNEL_CAPACITY {
+        fanout::send(room_id, frame(None));
+    }
+
+    assert!(matches!(first.try_recv(), Err(TryRecvError::Lagged(1))));
+
+    fanout::close_room(room_id);
+
+    // The frames already queued are still sent
+    assert_eq!(fanout::send(room_id, frame(None)), 0);
+    assert!(first.try_recv().is_ok());
+}
+
+#[test]
+fn lagging_sessions_get_the_playback_and_queue_replayed() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    room_manager
+        .add_track_to_queue(
+            room_id,
+            "owner".into(),
+            "queued".into(),
+            "Queued".into(),
+            1000,
+            PlaybackItemType::Track,
+        )
+        .unwrap();
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+    let track = |track_id: &str| SpotifyTrack {
+        track_id: track_id.into(),
+        track_name: track_id.into(),
+        artist_name: "Artist".into(),
+        track_duration: 1000,
+        album_name: "Album".into(),
+        album_image_src: String::new(),
+        explicit: false,
+        popularity: None,
+        item_type: PlaybackItemType::Track,
+    };
+
+    room.reserve_unpushed_tracks();
+    room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        track_id: "playing".into(),
+        is_playing: true,
+        ..Default::default()
+    }));
+    room.next_tracks = Some(vec![track("queued"), track("autoplay")]);
+    room.previous_tracks = Some(vec![track("played")]);
+
+    let snapshots = SharifyWsInstance::room_state_snapshots(room);
+
+    assert!(matches!(
+        snapshots[0].r#type,
+        Some(command_response::Type::Room(_))
+    ));
+
+    let Some(command_response::Type::SpotifyAllState(state)) = &snapshots[1].r#type else {
+        panic!("The Spotify state isn't replayed");
+    };
+
+    assert_eq!(state.state.as_ref().unwrap().track_id, "playing");
+    assert_eq!(state.previous_tracks.as_ref().unwrap().tracks.len(), 1);
+    assert_eq!(state.next_tracks.as_ref().unwrap().tracks.len(), 2);
+    // Attributed like the broadcasted queue
+    assert_eq!(
+        state
+            .queue
+            .iter()
+            .map(|queued| queued.user_id.as_str())
+            .collect::<Vec<_>>(),
+        ["owner", ""]
+    );
+}
+
+#[test]
+fn queue_positions_are_announced_when_they_change() {
+    let (_, mut room_manager, room_id) = mock_room_manager();
+
+    for (track_id, track_duration) in [("first", 60_000), ("second", 30_000)] {
+        room_manager
+            .add_track_to_queue(
+                room_id,
+                "owner".into(),
+                track_id.into(),
+                "Track".into(),
+                track_duration,
+                PlaybackItemType::Track,
+            )
+            .unwrap();
+    }
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        is_playing: true,
+        progress_ms: Some(10_000),
+        duration_ms: 40_000,
+        ..Default::default()
+    }));
+
+    assert_eq!(
+        room.take_queue_position_changes(),
+        vec![
+            (
+                "owner".into(),
+                QueuePosition {
+                    track_id: "first".into(),
+                    position: 0,
+                    eta: Some(Duration::from_secs(30)),
+                }
+            ),
+            (
+                "owner".into(),
+                QueuePosition {
+                    track_id: "second".into(),
+                    position: 1,
+                    eta: Some(Duration::from_secs(90)),
+                }
+            ),
+        ]
+    );
+    // Nothing moved
+    assert!(room.take_queue_position_changes().is_empty());
+
+    // The duration of the fetched Spotify queue wins over the one looked up when queued
+    room.next_tracks = Some(vec![SpotifyTrack {
+        track_id: "first".into(),
+        track_name: "Track".into(),
+        artist_name: "Artist".into(),
+        track_duration: 20_000,
+        album_name: "Album".into(),
+        album_image_src: String::new(),
+        explicit: false,
+        popularity: None,
+        item_type: PlaybackItemType::Track,
+    }]);
+    room_manager
+        .add_track_to_queue(
+            room_id,
+            "owner".into(),
+            "third".into(),
+            "Track".into(),
+            10_000,
+            PlaybackItemType::Track,
+        )
+        .unwrap();
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    assert_eq!(
+        room.take_queue_position_changes(),
+        vec![(
+            "owner".into(),
+            QueuePosition {
+                track_id: "third".into(),
+                position: 2,
+                eta: Some(Duration::from_secs(80)),
+            }
+        )]
+    );
+
+    room_manager
+        .remove_track_from_queue(room_id, "first".into())
+        .unwrap();
+
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+
+    room.set_now_playing(None);
+
+    assert_eq!(
+        room.take_queue_position_changes(),
+        vec![
+            (
+                "owner".into(),
+                QueuePosition {
+                    track_id: "second".into(),
+                    position: 0,
+                    eta: None,
+                }
+            ),
+            (
+                "owner".into(),
+                QueuePosition {
+                    track_id: "third".into(),
+                    position: 1,
+                    eta: None,
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn members_that_never_connect_are_removed_after_the_join_ttl() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let join_ttl = Duration::from_secs(60 * 10);
+
+    for user_id in ["pending", "connected", "left"] {
+        room_manager
+            .join_room(room_id, user_id.into(), user_id.into(), Default::default())
+            .unwrap();
+    }
+
+    room_manager
+        .set_ws_user_state(room_id, &"connected".into(), true)
+        .unwrap();
+    room_manager
+        .set_ws_user_state(room_id, &"connected".into(), false)
+        .unwrap();
+    room_manager.leave_room(room_id, "left".into()).unwrap();
+
+    {
+        let room = room_manager.get_room_mut(&room_id).unwrap();
+
+        // Connected or left members aren't pending anymore
+        assert_eq!(
+            room.pending_members.keys().collect::<Vec<_>>(),
+            [&RoomUserID::from("pending")]
+        );
+
+        // Opt-in
+        assert_eq!(room.settings.join_ttl, None);
+        room.settings.join_ttl = Some(join_ttl);
+    }
+
+    clock.advance(join_ttl - Duration::from_secs(1));
+    assert!(room_manager.expire_pending_members(room_id).is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(
+        room_manager.expire_pending_members(room_id),
+        vec![RoomUserID::from("pending")]
+    );
+
+    let room = room_manager.get_room(&room_id).unwrap();
+
+    assert!(room.users.iter().all(|user| user.id != "pending"));
+    assert!(room.users.iter().any(|user| user.id == "connected"));
+
+    drop(room);
+
+    assert!(!room_manager.user_id_exists(&"pending".into()));
+    assert!(room_manager.expire_pending_members(room_id).is_empty());
+}
+
+#[test]
+fn room_updates_are_deltas_of_the_last_broadcasted_state() {
+    use crate::proto::room::{Log as ProtoLog, Room as ProtoRoom, RoomUser as ProtoRoomUser};
+
+    let (_, room_manager, room_id) = mock_room_manager();
+    let owner = RoomUserID::from("owner");
+    let take_update =
+        |room_manager: &RoomManager| room_manager.lock_room(&room_id).unwrap().take_room_update();
+
+    // The members have no state yet
+    let Some((room, None)) = take_update(&room_manager) else {
+        panic!("The first update must be the full room");
+    };
+
+    assert_eq!(room.seq, 1);
+    assert_eq!(take_update(&room_manager), None);
+
+    room_manager
+        .set_ws_user_state(room_id, &owner, true)
+        .unwrap();
+
+    let Some((room, Some(delta))) = take_update(&room_manager) else {
+        panic!("A small change must be a delta");
+    };
+
+    // Only the sessions that negotiated the deltas get it
+    let room_cmd = CommandResponse {
+        r#type: Some(command_response::Type::Room(room)),
+        ..Default::default()
+    };
+    let delta_cmd = CommandResponse {
+        r#type: Some(command_response::Type::RoomDelta(delta.clone())),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        SessionEncoding::from_query(None, None, Some("room_delta"))
+            .negotiate(vec![room_cmd.clone()], Some(&delta)),
+        vec![delta_cmd]
+    );
+    assert_eq!(
+        SessionEncoding::default().negotiate(vec![room_cmd.clone()], Some(&delta)),
+        vec![room_cmd]
+    );
+
+    assert_eq!(delta.seq, 2);
+    assert_eq!(delta.upserted_users.len(), 1);
+    assert!(delta.upserted_users[0].is_connected);
+    assert!(delta.removed_users.is_empty());
+    assert_eq!(delta.tracks_queue, None);
+    assert_eq!(delta.room, None);
+    // The full rooms sent to a single member carry the seq the next deltas apply on
+    assert_eq!(
+        ProtoRoom::from(Room::clone(&room_manager.get_room(&room_id).unwrap())).seq,
+        2
+    );
+
+    let log = |details: &str| ProtoLog {
+        details: details.into(),
+        ..Default::default()
+    };
+    let user = |id: &str| ProtoRoomUser {
+        id: id.into(),
+        ..Default::default()
+    };
+    let previous = ProtoRoom {
+        users: vec![user("owner"), user("guest")],
+        logs: vec![log("first")],
+        logs_total: 1,
+        ..Default::default()
+    };
+    let current = ProtoRoom {
+        name: "Renamed".into(),
+        users: vec![user("owner")],
+        logs: vec![log("first"), log("second")],
+        logs_total: 2,
+        ..Default::default()
+    };
+    let delta = command_response::RoomDelta::between(&previous, &current, 3, 1).unwrap();
+
+    assert_eq!(delta.removed_users, vec!["guest".to_string()]);
+    assert!(delta.upserted_users.is_empty());
+    assert_eq!(delta.appended_logs, vec![log("second")]);
+    assert_eq!(delta.logs_total, 2);
+    assert_eq!(delta.room.map(|room| room.name), Some("Renamed".into()));
+    assert_eq!(
+        command_response::RoomDelta::between(&current, &current, 4, 0),
+        None
+    );
+
+    // Logs identical to the last previous one are new all the same
+    let repeated = ProtoRoom {
+        logs: vec![log("first"), log("second"), log("second"), log("second")],
+        logs_total: 4,
+        ..current.clone()
+    };
+    let delta = command_response::RoomDelta::between(&current, &repeated, 4, 2).unwrap();
+
+    assert_eq!(delta.appended_logs, vec![log("second"), log("second")]);
+}
+
+#[test]
+fn playback_progress_is_extrapolated_from_the_last_fetch() {
+    let (clock, room_manager, room_id) = mock_room_manager();
+    let mut room = room_manager.lock_room(&room_id).unwrap();
+
+    assert_eq!(room.playback_progress_ms(clock.now()), None);
+
+    room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        is_playing: true,
+        progress_ms: Some(1000),
+        duration_ms: 3000,
+        ..Default::default()
+    }));
+    room.now_playing_at = Some(clock.now());
+    clock.advance(Duration::from_millis(1500));
+
+    assert_eq!(room.playback_progress_ms(clock.now()), Some(2500));
+
+    // Capped at the end of the track until the next fetch
+    clock.advance(Duration::from_secs(1));
+
+    assert_eq!(room.playback_progress_ms(clock.now()), Some(3000));
+
+    room.now_playing.as_mut().unwrap().is_playing = false;
+
+    assert_eq!(room.playback_progress_ms(clock.now()), None);
+}
+
+#[test]
+fn listeners_follow_the_room_playback() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let guest = "guest".to_owned();
+    let tokens = SpotifyTokens::new("access", "refresh", 3600, Timestamp::from(0)).unwrap();
+
+    room_manager
+        .join_room(room_id, "Guest".into(), guest.clone(), Default::default())
+        .unwrap();
+
+    assert!(matches!(
+        room_manager.register_listener(room_id, &guest, "unknown", "device".into()),
+        Err(RoomError::InvalidSpotifyGrant)
+    ));
+
+    let (grant, _) = room_manager.store_spotify_grant(tokens);
+
+    room_manager
+        .register_listener(room_id, &guest, &grant, "device".into())
+        .unwrap();
+
+    let mut room = room_manager.lock_room(&room_id).unwrap();
+
+    // Nothing to follow yet
+    assert!(room.take_listener_syncs(clock.now(), None).is_empty());
+
+    room.set_now_playing(Some(SpotifyCurrentPlaybackOutput {
+        track_id: "track".into(),
+        is_playing: true,
+        progress_ms: Some(1000),
+        duration_ms: 300_000,
+        ..Default::default()
+    }));
+    room.now_playing_at = Some(clock.now());
+
+    let sync = ListenerSync {
+        user_id: guest.clone(),
+        device_id: "device".into(),
+        track_id: "track".into(),
+        item_type: PlaybackItemType::Track,
+        position_ms: Some(1000),
+    };
+
+    assert_eq!(
+        room.take_listener_syncs(clock.now(), None),
+        std::slice::from_ref(&sync)
+    );
+    // Already synced, unless forced
+    assert!(room.take_listener_syncs(clock.now(), None).is_empty());
+
+    clock.advance(Duration::from_millis(500));
+
+    assert_eq!(
+        room.take_listener_syncs(clock.now(), Some(&guest)),
+        [ListenerSync {
+            position_ms: Some(1500),
+            ..sync.clone()
+        }]
+    );
+    // Still following the position
+    clock.advance(Duration::from_millis(500));
+    assert!(room.take_listener_syncs(clock.now(), None).is_empty());
+
+    // Seeked away from the device position
+    room.now_playing.as_mut().unwrap().progress_ms = Some(60_000);
+    room.now_playing_at = Some(clock.now());
+
+    assert_eq!(
+        room.take_listener_syncs(clock.now(), None),
+        [ListenerSync {
+            position_ms: Some(60_000),
+            ..sync.clone()
+        }]
+    );
+
+    room.now_playing.as_mut().unwrap().is_playing = false;
+
+    assert_eq!(
+        room.take_listener_syncs(clock.now(), None),
+        [ListenerSync {
+            position_ms: None,
+            ..sync
+        }]
+    );
+
+    drop(room);
+
+    room_manager.leave_room(room_id, guest.clone()).unwrap();
+
+    let mut room = room_manager.lock_room(&room_id).unwrap();
+
+    assert!(
+        room.take_listener_syncs(clock.now(), Some(&guest))
+            .is_empty()
+    );
+    assert!(room.listeners.is_empty());
+}
+
+#[test]
+fn spotify_outages_back_off_until_the_room_is_closed() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let room = room_manager.get_room_mut(&room_id).unwrap();
+    let unreachable = ProviderError::Generic("Connection refused".into());
+
+    let outage = room.record_spotify_failure(clock.now(), &unreachable);
+    assert_eq!(outage.failures, 1);
+    assert_eq!(outage.retry_in(), DEFAULT_OUTAGE_RETRY_BACKOFF);
+    assert_eq!(outage.closes_in(clock.now()), DEFAULT_MAX_OUTAGE_DURATION);
+
+    clock.advance(outage.retry_in());
+    let outage = room.record_spotify_failure(clock.now(), &unreachable);
+    assert_eq!(outage.failures, 2);
+    assert_eq!(outage.retry_in(), DEFAULT_OUTAGE_RETRY_BACKOFF * 2);
+    assert_eq!(
+        outage.closes_in(clock.now()),
+        DEFAULT_MAX_OUTAGE_DURATION - DEFAULT_OUTAGE_RETRY_BACKOFF
+    );
+
+    for _ in 0..10 {
+        room.record_spotify_failure(clock.now(), &unreachable);
+    }
+    let outage = room.spotify_outage.unwrap();
+    assert_eq!(outage.retry_in(), DEFAULT_MAX_OUTAGE_RETRY_INTERVAL);
+
+    clock.advance(DEFAULT_MAX_OUTAGE_DURATION);
+    assert!(outage.closes_in(clock.now()).is_zero());
+
+    // A successful fetch ends it, the next failure starts a new one
+    assert!(room.spotify_outage.take().is_some());
+    let outage = room.record_spotify_failure(clock.now(), &unreachable);
+    assert_eq!(outage.failures, 1);
+    assert_eq!(outage.closes_in(clock.now()), DEFAULT_MAX_OUTAGE_DURATION);
+
+    // A 401 is retried while the tokens may still be refreshed, not once their refresh failed
+    let unauthorized = ProviderError::Unauthorized("The access token expired".into());
+    let outage = room.record_spotify_failure(clock.now(), &unauthorized);
+    assert!(!outage.closes_in(clock.now()).is_zero());
+
+    room.token_refresh_failures = 1;
+    let outage = room.record_spotify_failure(clock.now(), &unauthorized);
+    assert!(outage.tokens_rejected);
+    assert!(outage.closes_in(clock.now()).is_zero());
+}
+
+#[test]
+fn client_webhooks_are_validated_deduplicated_and_throttled_per_ip() {
+    let payload = |wh_type, content: &str| SendWebhookPayload {
+        wh_type,
+        content: content.into(),
+        room_id: None,
+        user_id: None,
+    };
+
+    assert_eq!(
+        Webhook::try_from(payload(WebhookType::RoomDigest, "digest")),
+        Err(WebhookError::ForbiddenType)
+    );
+    assert_eq!(
+        Webhook::try_from(payload(WebhookType::SessionSummary, "summary")),
+        Err(WebhookError::ForbiddenType)
+    );
+    assert_eq!(
+        Webhook::try_from(payload(WebhookType::Feedback, "  ")),
+        Err(WebhookError::InvalidContent)
+    );
+    assert_eq!(
+        Webhook::try_from(payload(
+            WebhookType::BugReport,
+            &"a".repeat(MAX_WEBHOOK_CONTENT_LEN + 1)
+        )),
+        Err(WebhookError::InvalidContent)
+    );
+
+    let webhook =
+        |content: &str| Webhook::try_from(payload(WebhookType::Feedback, content)).unwrap();
+    let mut throttle = WebhookThrottle::default();
+    let ip = IpAddr::from([127, 0, 0, 1]);
+    let other_ip = IpAddr::from([127, 0, 0, 2]);
+    let now = std::time::Instant::now();
+
+    assert_eq!(throttle.admit(ip, &webhook(" first "), now), Ok(()));
+    assert_eq!(
+        throttle.admit(other_ip, &webhook("first"), now),
+        Err(WebhookError::Duplicate)
+    );
+    assert_eq!(throttle.admit(ip, &webhook("second"), now), Ok(()));
+    assert_eq!(throttle.admit(ip, &webhook("third"), now), Ok(()));
+    assert_eq!(
+        throttle.admit(ip, &webhook("fourth"), now),
+        Err(WebhookError::Throttled)
+    );
+    assert_eq!(throttle.admit(other_ip, &webhook("fourth"), now), Ok(()));
+
+    let later = now + WEBHOOK_THROTTLE_WINDOW.max(WEBHOOK_DEDUP_WINDOW);
+    assert_eq!(throttle.admit(ip, &webhook("first"), later), Ok(()));
+}
+
+#[test]
+fn feedback_is_stored_listed_and_acknowledged_across_restarts() {
+    let path =
+        std::env::temp_dir().join(format!("sharify-feedback-{}.jsonl", uuid::Uuid::now_v7()));
+    let now = chrono::Utc::now();
+
+    let store = FeedbackStore::open(Some(path.clone())).unwrap();
+    let mut webhook = Webhook::new(WebhookType::BugReport, "It crashed".into());
+    webhook.user_id = Some("user".into());
+    let bug = store.insert(&webhook, now).unwrap();
+    let feedback = store
+        .insert(&Webhook::new(WebhookType::Feedback, "Nice".into()), now)
+        .unwrap();
+
+    assert_eq!(store.list(None), [feedback.clone(), bug.clone()]);
+    assert!(store.acknowledge(bug.id).unwrap());
+    assert!(!store.acknowledge(uuid::Uuid::now_v7()).unwrap());
+
+    let store = FeedbackStore::open(Some(path.clone())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(store.list(Some(FeedbackStatus::New)), [feedback]);
+    let acknowledged = store.list(Some(FeedbackStatus::Acknowledged));
+    assert_eq!(acknowledged.len(), 1);
+    assert_eq!(acknowledged[0].id, bug.id);
+    assert_eq!(acknowledged[0].user_id.as_deref(), Some("user"));
+}
+
+#[test]
+fn stored_feedback_is_capped_dropping_the_acknowledged_first() {
+    let now = chrono::Utc::now();
+    let store = FeedbackStore::open(None).unwrap();
+    let submission = |content: &str| Webhook::new(WebhookType::Feedback, content.into());
+
+    let first = store.insert(&submission("first"), now).unwrap();
+    let acknowledged = store.insert(&submission("acknowledged"), now).unwrap();
+
+    store.acknowledge(acknowledged.id).unwrap();
+
+    for idx in 2..MAX_STORED_FEEDBACK {
+        store.insert(&submission(&idx.to_string()), now).unwrap();
+    }
+
+    let last = store.insert(&submission("last"), now).unwrap();
+    let stored = store.list(None);
+
+    assert_eq!(stored.len(), MAX_STORED_FEEDBACK);
+    assert_eq!(stored[0], last);
+    assert!(!stored.iter().any(|feedback| feedback.id == acknowledged.id));
+
+    // Then the oldest
+    store.insert(&submission("next"), now).unwrap();
+    assert!(
+        !store
+            .list(None)
+            .iter()
+            .any(|feedback| feedback.id == first.id)
+    );
+}
+
+#[test]
+fn command_span_names_are_the_json_field_names() {
+    for cmd_type in [
+        command::Type::GetRoom(true),
+        command::Type::SeekToPos(0),
+        command::Type::GetLogs(Default::default()),
+        command::Type::RegisterListener(Default::default()),
+    ] {
+        let command = Command {
+            r#type: Some(cmd_type.clone()),
+            ..Default::default()
+        };
+        let json = String::from_utf8(WireFormat::Json.encode(&command).unwrap()).unwrap();
+
+        assert!(
+            json.contains(&format!("\"{}\"", WSCmd::get_cmd_name(&cmd_type))),
+            "{json}"
+        );
+    }
+}
+
+#[test]
+fn full_rooms_waitlist_users_until_a_slot_frees_up() {
+    let (clock, mut room_manager, room_id) = mock_room_manager();
+    let owner_id = "owner".to_string();
+    let settings = RoomSettings {
+        max_users: 1,
+        ..Default::default()
+    };
+
+    room_manager
+        .update_room_settings(room_id, &owner_id, settings)
+        .unwrap();
+
+    // Disabled by default
+    assert!(matches!(
+        room_manager.join_waitlist(room_id, "A".into(), "a".into(), Default::default()),
+        Err(RoomError::RoomFull)
+    ));
+
+    room_manager
+        .update_room_settings(
+            room_id,
+            &owner_id,
+            RoomSettings {
+                waitlist: true,
+                ..settings
+            },
+        )
+        .unwrap();
+
+    let (ticket_a, position) = room_manager
+        .join_waitlist(room_id, "A".into(), "a".into(), Default::default())
+        .unwrap();
+    assert_eq!(position, 1);
+    assert_eq!(
+        room_manager
+            .join_waitlist(room_id, "A".into(), "a".into(), Default::default())
+            .unwrap(),
+        (ticket_a, 1)
+    );
+    let (ticket_b, position) = room_manager
+        .join_waitlist(room_id, "B".into(), "b".into(), Default::default())
+        .unwrap();
+    assert_eq!(position, 2);
+
+    assert!(matches!(
+        room_manager.poll_waitlist(room_id, ticket_b),
+        Ok(WaitlistStatus::Waiting { position: 2 })
+    ));
+
+    // A slot frees up
+    room_manager
+        .update_room_settings(
+            room_id,
+            &owner_id,
+            RoomSettings {
+                max_users: 2,
+                waitlist: true,
+                ..settings
+            },
+        )
+        .unwrap();
+    // Direct joins don't jump the queue
+    assert!(matches!(
+        room_manager.join_room(room_id, "C".into(), "c".into(), Default::default()),
+        Err(RoomError::RoomFull)
+    ));
+    assert_eq!(
+        room_manager.admit_waitlisted(room_id),
+        vec!["a".to_string()]
+    );
+    assert!(matches!(
+        room_manager.poll_waitlist(room_id, ticket_b),
+        Ok(WaitlistStatus::Waiting { position: 1 })
+    ));
+
+    match room_manager.poll_waitlist(room_id, ticket_a) {
+        Ok(WaitlistStatus::Admitted { room, user_id }) => {
+            assert_eq!(user_id, "a");
+            assert!(room.users.iter().any(|user| user.id == "a"));
+        }
+        status => panic!("Unexpected waitlist status: {status:?}"),
+    }
+    assert!(matches!(
+        room_manager.poll_waitlist(room_id, ticket_a),
+        Err(RoomError::NotWaitlisted)
+    ));
+
+    // B stopped polling and lost its place
+    clock.advance(WAITLIST_POLL_TTL);
+    room_manager.leave_room(room_id, "a".into()).unwrap();
+    assert!(room_manager.admit_waitlisted(room_id).is_empty());
+    assert!(matches!(
+        room_manager.poll_waitlist(room_id, ticket_b),
+        Err(RoomError::NotWaitlisted)
+    ));
+}
+
+#[test]
+fn scheduled_rooms_open_with_their_registered_users() {
+    let clock = Arc::new(MockClock::default());
+    let mut room_manager = RoomManager::new(clock.clone(), Arc::new(SeededRandom::new(0)));
+    let tokens: SpotifyTokens = CredentialsInput {
+        access_token: String::new(),
+        refresh_token: String::new(),
+        expires_in: 3600,
+        created_at: Timestamp::from(0),
+    }
+    .try_into()
+    .unwrap();
+    let settings = RoomSettings {
+        max_users: 2,
+        ..Default::default()
+    };
+    let host = || {
+        (
+            "host".to_string(),
+            "Host".to_string(),
+            UserProfile::default(),
+        )
+    };
+    let opens_at = clock.utc_now() + chrono::TimeDelta::hours(1);
+
+    for invalid in [
+        clock.utc_now(),
+        clock.utc_now() + chrono::TimeDelta::from_std(MAX_SCHEDULE_AHEAD * 2).unwrap(),
+    ] {
+        assert!(matches!(
+            room_manager.schedule_room(host(), "Room".into(), tokens.clone(), settings, invalid),
+            Err(RoomError::InvalidSchedule)
+        ));
+    }
+
+    let info = room_manager
+        .schedule_room(host(), "Room".into(), tokens.clone(), settings, opens_at)
+        .unwrap();
+    assert_eq!(info.registered_users, 1);
+    assert!(matches!(
+        room_manager.schedule_room(host(), "Other".into(), tokens, settings, opens_at),
+        Err(RoomError::UserIDExists)
+    ));
+
+    let member = room_manager
+        .register_scheduled_room(info.id, "a".into(), "A".into(), Default::default())
+        .unwrap();
+    assert_eq!(member.registered_users, 2);
+    assert_eq!(
+        room_manager
+            .register_scheduled_room(info.id, "a".into(), "A".into(), Default::default())
+            .unwrap()
+            .ticket,
+        member.ticket
+    );
+    assert!(matches!(
+        room_manager.register_scheduled_room(info.id, "b".into(), "B".into(), Default::default()),
+        Err(RoomError::RoomFull)
+    ));
+
+    // Not due yet
+    assert!(room_manager.open_due_rooms().is_empty());
+    assert!(matches!(
+        room_manager.poll_scheduled_room(info.id, member.ticket),
+        Ok(ScheduledRoomStatus::Pending(pending)) if pending == member
+    ));
+
+    clock.advance(Duration::from_secs(60 * 60));
+    let opened = room_manager.open_due_rooms();
+    assert_eq!(opened.len(), 1);
+
+    {
+        let room = room_manager.get_room(&opened[0]).unwrap();
+        assert_eq!(room.name, "Room");
+        assert_eq!(room.settings.max_users, 2);
+        assert_eq!(room.users.len(), 2);
+    }
+
+    match room_manager.poll_scheduled_room(info.id, member.ticket) {
+        Ok(ScheduledRoomStatus::Opened { room, user_id }) => {
+            assert_eq!(user_id, "a");
+            assert_eq!(room.id, opened[0]);
+        }
+        status => panic!("Unexpected scheduled room status: {status:?}"),
+    }
+    // The ticket is consumed
+    assert!(matches!(
+        room_manager.poll_scheduled_room(info.id, member.ticket),
+        Err(RoomError::ScheduledRoomNotFound)
+    ));
+
+    // The host never claimed it
+    clock.advance(SCHEDULED_ROOM_CLAIM_TTL);
+    assert!(room_manager.open_due_rooms().is_empty());
+    assert!(matches!(
+        room_manager.poll_scheduled_room(info.id, info.ticket),
+        Err(RoomError::ScheduledRoomNotFound)
+    ));
+}
+
+#[test]
+fn scheduled_room_members_are_notified_and_kept_until_the_claim_ttl() {
+    let clock = Arc::new(MockClock::default());
+    let mut room_manager = RoomManager::new(clock.clone(), Arc::new(SeededRandom::new(0)));
+    let tokens: SpotifyTokens = CredentialsInput {
+        access_token: String::new(),
+        refresh_token: String::new(),
+        expires_in: 3600,
+        created_at: Timestamp::from(0),
+    }
+    .try_into()
+    .unwrap();
+    let settings = RoomSettings {
+        join_ttl: Some(Duration::from_secs(5 * 60)),
+        ..Default::default()
+    };
+    let opens_at = clock.utc_now() + chrono::TimeDelta::hours(1);
+
+    let info = room_manager
+        .schedule_room(
+            ("host".into(), "Host".into(), UserProfile::default()),
+            "Room".into(),
+            tokens.clone(),
+            settings,
+            opens_at,
+        )
+        .unwrap();
+    let member = room_manager
+        .register_scheduled_room(info.id, "a".into(), "A".into(), Default::default())
+        .unwrap();
+    assert!(matches!(
+        room_manager.scheduled_room_info(info.id, member.ticket),
+        Ok((_, false))
+    ));
+    assert!(matches!(
+        room_manager.scheduled_room_info(info.id, uuid::Uuid::nil()),
+        Err(RoomError::ScheduledRoomNotFound)
+    ));
+
+    let mut events = room_manager.subscribe_events();
+    clock.advance(Duration::from_secs(60 * 60));
+    let room_id = room_manager.open_due_rooms()[0];
+
+    let opened = std::iter::from_fn(|| events.try_recv().ok()).find(|event| {
+        matches!(event, RoomEvent::ScheduledRoomOpened { scheduled_room_id, .. } if *scheduled_room_id == info.id)
+    });
+    assert_eq!(opened.map(|event| event.room_id()), Some(room_id));
+    assert!(matches!(
+        room_manager.scheduled_room_info(info.id, member.ticket),
+        Ok((_, true))
+    ));
+
+    // Their join_ttl only starts once they had the time to claim the room
+    clock.advance(Duration::from_secs(10 * 60));
+    assert!(room_manager.expire_pending_members(room_id).is_empty());
+    clock.advance(SCHEDULED_ROOM_CLAIM_TTL);
+    assert_eq!(
+        room_manager.expire_pending_members(room_id),
+        vec![RoomUserID::from("a")]
+    );
+}
+
+#[test]
+fn scheduled_rooms_are_capped() {
+    let clock = Arc::new(MockClock::default());
+    let mut room_manager = RoomManager::new(clock.clone(), Arc::new(SeededRandom::new(0)));
+    let tokens: SpotifyTokens = CredentialsInput {
+        access_token: String::new(),
+        refresh_token: String::new(),
+        expires_in: 3600,
+        created_at: Timestamp::from(0),
+    }
+    .try_into()
+    .unwrap();
+    let opens_at = clock.utc_now() + chrono::TimeDelta::hours(1);
+    let host = |idx: usize| {
+        (
+            format!("host{idx}"),
+            "Host".to_string(),
+            UserProfile::default(),
+        )
+    };
+
+    for idx in 0..MAX_SCHEDULED_ROOMS {
+        room_manager
+            .schedule_room(
+                host(idx),
+                "Room".into(),
+                tokens.clone(),
+                Default::default(),
+                opens_at,
+            )
+            .unwrap();
+    }
+
+    let (user_id, _, profile) = host(MAX_SCHEDULED_ROOMS);
+    assert!(matches!(
+        room_manager.validate_schedule(&user_id, &profile, &Default::default(), opens_at),
+        Err(RoomError::InvalidSchedule)
+    ));
+}
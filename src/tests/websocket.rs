@@ -1,27 +1,75 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
 
 use actix_rt::time;
+use actix_web::http::Method;
 use futures_util::{SinkExt as _, TryStreamExt as _};
 use prost::Message as _;
-use reqwest::{Client, ClientBuilder, StatusCode};
-use reqwest_websocket::{CloseCode, Message, RequestBuilderExt};
+use reqwest::{Client, ClientBuilder, StatusCode, header, redirect};
+use reqwest_websocket::{CloseCode, Message, RequestBuilderExt, WebSocket};
+use serde_json::json;
 use tokio::sync::mpsc;
 
+use super::mock_spotify::{MockResponse, MockSpotify};
+use crate::config;
 use crate::proto::cmd::{
-    Command, CommandResponse, HttpCommand, command, command_response, http_command,
+    Command, CommandResponse, HttpCommand, WsCloseCode, command, command_response, http_command,
+};
+use crate::routes::SESSION_TOKEN_HEADER;
+use crate::sharify::clock::{MockClock, SharedClock, system_clock};
+use crate::sharify::room::{DEFAULT_RECONNECT_GRACE_PERIOD, Room};
+use crate::sharify::spotify::SpotifyBaseUrls;
+use crate::sharify::spotify::web_utils::endpoints::{
+    CURRENT_PLAYBACK_STATE, PLAYER_QUEUE, RECENTLY_PLAYED_TRACKS, TOKEN,
 };
-use crate::sharify::room::Room;
 use crate::sharify::utils;
+use crate::sharify::websocket::{HEARTBEAT_INTERVAL, USER_WS_TIMEOUT};
 
+const PORT: u16 = 3100;
 const BASE_URL: &str = "http://127.0.0.1:3100/v1";
 
 static NEXT_ROOM_ID: AtomicU8 = AtomicU8::new(1);
+/// Session tokens returned by CreateRoom/JoinRoom, by user ID
+static SESSION_TOKENS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Default::default);
+
+fn store_session_token(user_id: String, res: &reqwest::Response) {
+    let token = res
+        .headers()
+        .get(SESSION_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .expect("No session token in the response")
+        .to_owned();
+
+    SESSION_TOKENS.lock().unwrap().insert(user_id, token);
+}
+
+fn session_token(user_id: &str) -> String {
+    SESSION_TOKENS
+        .lock()
+        .unwrap()
+        .get(user_id)
+        .cloned()
+        .expect("No session token stored for this user")
+}
+
+fn base_url(port: u16) -> String {
+    format!("http://127.0.0.1:{port}/v1")
+}
 
-async fn run_server_with_timeout(seconds: u64, mut cancel_rx: mpsc::Receiver<()>) {
+async fn run_server_with_timeout(
+    seconds: u64,
+    mut cancel_rx: mpsc::Receiver<()>,
+    clock: SharedClock,
+    port: u16,
+    spotify_base_urls: SpotifyBaseUrls,
+) {
     actix_rt::spawn(async move {
+        let server = crate::serve(false, clock, port, spotify_base_urls);
+
         tokio::select! {
-            timeout = time::timeout(Duration::from_secs(seconds), crate::serve(false)) => {
+            timeout = time::timeout(Duration::from_secs(seconds), server) => {
                 if timeout.is_err() {
                     panic!("Timeout hit during test");
                 }
@@ -33,7 +81,7 @@ async fn run_server_with_timeout(seconds: u64, mut cancel_rx: mpsc::Receiver<()>
     // Await for server start
     for _ in 0..4 {
         if Client::default()
-            .get(BASE_URL)
+            .get(base_url(port))
             .timeout(Duration::from_millis(1000))
             .send()
             .await
@@ -46,32 +94,137 @@ async fn run_server_with_timeout(seconds: u64, mut cancel_rx: mpsc::Receiver<()>
     panic!("Server unreachable");
 }
 
+/// Goes through the Spotify login against the mock accounts service, as the room host would
+async fn spotify_grant(port: u16) -> String {
+    let client = ClientBuilder::default()
+        .redirect(redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("{}/oauth/login", base_url(port)))
+        .send()
+        .await
+        .expect("Failed to send the Spotify login request");
+
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .expect("No Spotify authorize URL in the login response");
+    let state = location
+        .split(['?', '&'])
+        .find_map(|param| param.strip_prefix("state="))
+        .expect("No state in the Spotify authorize URL");
+
+    let res = client
+        .get(format!("{}/oauth/callback", base_url(port)))
+        .query(&[("code", "code"), ("state", state)])
+        .send()
+        .await
+        .expect("Failed to send the Spotify callback request");
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = CommandResponse::decode(res.bytes().await.expect("Failed to get response bytes"))
+        .expect("Failed to decode respones into Protobuf CommandResponse");
+
+    let Some(command_response::Type::SpotifyGrant(grant)) = res.r#type else {
+        panic!("Expected a SpotifyGrant, got {res:?}");
+    };
+
+    grant.grant
+}
+
 async fn create_room_impl(sv_timeout: u64) -> (mpsc::Sender<()>, Client, Room) {
+    create_room_with_clock(sv_timeout, system_clock(), PORT).await
+}
+
+async fn create_room_with_clock(
+    sv_timeout: u64,
+    clock: SharedClock,
+    port: u16,
+) -> (mpsc::Sender<()>, Client, Room) {
+    let mock = start_mock_spotify(
+        port,
+        MockResponse::status(actix_web::http::StatusCode::NO_CONTENT),
+    )
+    .await;
+
+    create_room_with_spotify(sv_timeout, clock, port, &mock).await
+}
+
+/// Spotify of the server on the port, answering the token exchange and the room data loop
+/// fetches with the given playback state
+async fn start_mock_spotify(port: u16, playback: MockResponse) -> MockSpotify {
+    let mock = MockSpotify::start().await;
+
+    mock.respond(
+        Method::POST,
+        TOKEN,
+        vec![MockResponse::json(json!({
+            // The playback/queue responses are shared by the rooms of the same access token
+            "access_token": format!("access token {port}"),
+            "refresh_token": "refresh token",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "scope": "",
+        }))],
+    );
+    mock.respond(Method::GET, CURRENT_PLAYBACK_STATE, vec![playback]);
+    mock.respond(
+        Method::GET,
+        PLAYER_QUEUE,
+        vec![MockResponse::json(json!({ "queue": [] }))],
+    );
+    mock.respond(
+        Method::GET,
+        RECENTLY_PLAYED_TRACKS,
+        vec![MockResponse::json(json!({ "items": [] }))],
+    );
+
+    mock
+}
+
+async fn create_room_with_spotify(
+    sv_timeout: u64,
+    clock: SharedClock,
+    port: u16,
+    mock: &MockSpotify,
+) -> (mpsc::Sender<()>, Client, Room) {
     let (cancel_tx, cancel_rx) = mpsc::channel::<()>(1);
-    run_server_with_timeout(sv_timeout, cancel_rx).await;
+
+    config::set_for_tests(|config| {
+        config.spotify_client_id = Some("client".into());
+        config.oauth_redirect_base_url = Some("http://127.0.0.1".into());
+    });
+
+    run_server_with_timeout(sv_timeout, cancel_rx, clock, port, mock.base_urls.clone()).await;
 
     let user = ClientBuilder::default()
         .timeout(Duration::from_secs(60 * 2))
         .build()
         .unwrap();
+    let spotify_grant = spotify_grant(port).await;
+
+    let user_id = utils::encode_user_email(
+        format!(
+            "test{}@email.com",
+            NEXT_ROOM_ID.fetch_add(1, Ordering::SeqCst)
+        ),
+        10,
+    );
 
     let command = HttpCommand {
         r#type: Some(http_command::Type::CreateRoom(http_command::CreateRoom {
-            user_id: utils::encode_user_email(
-                format!(
-                    "test{}@email.com",
-                    NEXT_ROOM_ID.fetch_add(1, Ordering::SeqCst)
-                ),
-                10,
-            ),
+            user_id: user_id.clone(),
             username: "test".into(),
             name: format!("Room {}", NEXT_ROOM_ID.fetch_add(1, Ordering::SeqCst)),
-            credentials: Some(http_command::Credentials {
-                access_token: "".into(),
-                refresh_token: "".into(),
-                expires_in: 0,
-                created_at: "0".into(),
-            }),
+            identity_token: None,
+            spotify_grant: Some(spotify_grant),
+            ..Default::default()
         })),
     };
 
@@ -82,7 +235,7 @@ async fn create_room_impl(sv_timeout: u64) -> (mpsc::Sender<()>, Client, Room) {
     );
 
     let req = user
-        .post(BASE_URL)
+        .post(base_url(port))
         .body(buf)
         .send()
         .await
@@ -90,6 +243,8 @@ async fn create_room_impl(sv_timeout: u64) -> (mpsc::Sender<()>, Client, Room) {
 
     assert_eq!(req.status(), StatusCode::CREATED);
 
+    store_session_token(user_id, &req);
+
     let res = CommandResponse::decode(req.bytes().await.expect("Failed to get response bytes"))
         .expect("Failed to decode respones into Protobuf CommandResponse");
 
@@ -106,6 +261,201 @@ async fn create_room_impl(sv_timeout: u64) -> (mpsc::Sender<()>, Client, Room) {
     (cancel_tx, user, room.into())
 }
 
+async fn join_room(client: &Client, port: u16, room: &Room, username: &str) -> String {
+    let user_id = utils::encode_user_email(
+        format!(
+            "{username}{}@email.com",
+            NEXT_ROOM_ID.fetch_add(1, Ordering::SeqCst)
+        ),
+        10,
+    );
+
+    let command = HttpCommand {
+        r#type: Some(http_command::Type::JoinRoom(http_command::JoinRoom {
+            room_id: room.id.as_bytes().to_vec(),
+            user_id: user_id.clone(),
+            username: username.into(),
+            identity_token: None,
+            profile: None,
+        })),
+    };
+
+    let mut buf = Vec::new();
+    command.encode(&mut buf).unwrap();
+
+    let req = client
+        .post(base_url(port))
+        .body(buf)
+        .send()
+        .await
+        .expect("Failed to send JoinRoom POST request");
+
+    assert!(req.status().is_success(), "Failed to join the room");
+
+    store_session_token(user_id.clone(), &req);
+
+    user_id
+}
+
+async fn connect_ws(client: &Client, port: u16, room: &Room, user_id: &str) -> WebSocket {
+    connect_ws_with_url(
+        client,
+        format!(
+            "{}/{}/{user_id}?session_token={}",
+            base_url(port),
+            room.id,
+            session_token(user_id)
+        ),
+    )
+    .await
+}
+
+async fn resume_ws(
+    client: &Client,
+    port: u16,
+    room: &Room,
+    user_id: &str,
+    resume_token: &str,
+) -> WebSocket {
+    connect_ws_with_url(
+        client,
+        format!(
+            "{}/{}/{user_id}?session_token={}&resume_token={resume_token}",
+            base_url(port),
+            room.id,
+            session_token(user_id)
+        ),
+    )
+    .await
+}
+
+async fn connect_ws_with_url(client: &Client, url: String) -> WebSocket {
+    let req = client
+        .get(url)
+        .upgrade()
+        .send()
+        .await
+        .expect("Failed to send HTTP GET request to create WS conn");
+
+    assert_eq!(req.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    req.into_websocket()
+        .await
+        .expect("Failed to upgrade HTTP request to WS")
+}
+
+/// Reads the WS (answering pings) until a NewUserJoined is received or the timeout is hit
+async fn wait_new_user_joined(ws: &mut WebSocket, timeout: Duration) -> Option<String> {
+    time::timeout(timeout, async {
+        while let Ok(Some(msg)) = ws.try_next().await {
+            let Message::Binary(bytes) = msg else {
+                continue;
+            };
+
+            if let Ok(CommandResponse {
+                r#type: Some(command_response::Type::NewUserJoined(username)),
+                ..
+            }) = CommandResponse::decode(bytes)
+            {
+                return Some(username);
+            }
+        }
+
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Reads the WS (answering pings) until the SessionResume is received or the timeout is hit
+async fn wait_session_resume(
+    ws: &mut WebSocket,
+    timeout: Duration,
+) -> Option<command_response::SessionResume> {
+    time::timeout(timeout, async {
+        while let Ok(Some(msg)) = ws.try_next().await {
+            let Message::Binary(bytes) = msg else {
+                continue;
+            };
+
+            if let Ok(CommandResponse {
+                r#type: Some(command_response::Type::SessionResume(resume)),
+                ..
+            }) = CommandResponse::decode(bytes)
+            {
+                return Some(resume);
+            }
+        }
+
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// First response of the type picked by the filter, batched or not
+async fn wait_response<T>(
+    ws: &mut WebSocket,
+    timeout: Duration,
+    filter: impl Fn(command_response::Type) -> Option<T>,
+) -> Option<T> {
+    time::timeout(timeout, async {
+        while let Ok(Some(msg)) = ws.try_next().await {
+            let Message::Binary(bytes) = msg else {
+                continue;
+            };
+            let Ok(CommandResponse {
+                r#type: Some(r#type),
+                ..
+            }) = CommandResponse::decode(bytes)
+            else {
+                continue;
+            };
+            let responses = match r#type {
+                command_response::Type::Batch(batch) => batch
+                    .responses
+                    .into_iter()
+                    .filter_map(|response| response.r#type)
+                    .collect(),
+                r#type => vec![r#type],
+            };
+
+            if let Some(found) = responses.into_iter().find_map(&filter) {
+                return Some(found);
+            }
+        }
+
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// The client answers the pings only while it reads the socket, not reading it for a whole
+/// heartbeat interval lets the server see a missed pong
+async fn ignore_pings(duration: Duration) {
+    time::sleep(duration).await;
+}
+
+/// Reads the WS (answering pings) until it's closed by the server or the timeout is hit
+async fn wait_close(ws: &mut WebSocket, timeout: Duration) -> Option<CloseCode> {
+    time::timeout(timeout, async {
+        while let Ok(Some(msg)) = ws.try_next().await {
+            if let Message::Close { code, .. } = msg {
+                return Some(code);
+            }
+        }
+
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
 #[actix_rt::test]
 async fn create_room() {
     create_room_impl(60 * 2).await;
@@ -116,7 +466,12 @@ async fn create_room_and_get_room_via_ws() {
     let (cancel_tx, user, room) = create_room_impl(60 * 4).await;
 
     let req = user
-        .get(format!("{BASE_URL}/{}/{}", room.id, room.users[0].id))
+        .get(format!(
+            "{BASE_URL}/{}/{}?session_token={}",
+            room.id,
+            room.users[0].id,
+            session_token(&room.users[0].id)
+        ))
         .upgrade()
         .send()
         .await
@@ -131,6 +486,7 @@ async fn create_room_and_get_room_via_ws() {
 
     let command = Command {
         r#type: Some(command::Type::GetRoom(false)),
+        ..Default::default()
     };
 
     let mut buf = Vec::new();
@@ -189,3 +545,289 @@ async fn create_room_and_get_room_via_ws() {
     let _ = cancel_tx.send(()).await;
     unreachable!("If this is triggered, this means that WS conn has been closed");
 }
+
+#[actix_rt::test]
+async fn heartbeat_timeout_closes_session() {
+    let port = 3101;
+    let clock = Arc::new(MockClock::default());
+    let (cancel_tx, user, room) = create_room_with_clock(60, clock.clone(), port).await;
+
+    let mut ws = connect_ws(&user, port, &room, &room.users[0].id).await;
+
+    clock.advance(USER_WS_TIMEOUT + Duration::from_secs(1));
+    ignore_pings(HEARTBEAT_INTERVAL + Duration::from_secs(1)).await;
+
+    let close = wait_close(&mut ws, HEARTBEAT_INTERVAL * 2).await;
+
+    let _ = cancel_tx.send(()).await;
+
+    assert_eq!(
+        close,
+        Some(CloseCode::from(WsCloseCode::HeartbeatTimeout as u16))
+    );
+}
+
+#[actix_rt::test]
+async fn reconnection_grace_period() {
+    let port = 3102;
+    let clock = Arc::new(MockClock::default());
+    let (cancel_tx, owner, room) = create_room_with_clock(60, clock.clone(), port).await;
+
+    let mut owner_ws = connect_ws(&owner, port, &room, &room.users[0].id).await;
+
+    let guest = Client::default();
+    let guest_id = join_room(&guest, port, &room, "guest").await;
+    let guest_ws = connect_ws(&guest, port, &room, &guest_id).await;
+
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        Some("guest".into())
+    );
+
+    // Takeover: the guest reconnects while its previous session is still open
+    let guest_ws_2 = connect_ws(&guest, port, &room, &guest_id).await;
+
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        None
+    );
+
+    drop(guest_ws);
+
+    // Reconnection within the grace period keeps the guest silently in the room
+    let _ = guest_ws_2.close(CloseCode::Normal, None).await;
+    time::sleep(Duration::from_millis(500)).await;
+
+    let guest_ws_3 = connect_ws(&guest, port, &room, &guest_id).await;
+
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        None
+    );
+
+    // Reconnection after the grace period is announced again
+    let _ = guest_ws_3.close(CloseCode::Normal, None).await;
+    time::sleep(Duration::from_millis(500)).await;
+
+    clock.advance(DEFAULT_RECONNECT_GRACE_PERIOD + Duration::from_secs(1));
+    ignore_pings(HEARTBEAT_INTERVAL + Duration::from_secs(1)).await;
+
+    // The jump also expires the owner's heartbeat, it reconnects within its own grace period
+    assert_eq!(
+        wait_close(&mut owner_ws, HEARTBEAT_INTERVAL * 2).await,
+        Some(CloseCode::from(WsCloseCode::HeartbeatTimeout as u16))
+    );
+
+    let mut owner_ws = connect_ws(&owner, port, &room, &room.users[0].id).await;
+    let _guest_ws_4 = connect_ws(&guest, port, &room, &guest_id).await;
+
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        Some("guest".into())
+    );
+
+    let _ = cancel_tx.send(()).await;
+}
+
+#[actix_rt::test]
+async fn session_resume() {
+    let port = 3103;
+    let (cancel_tx, owner, room) = create_room_with_clock(60, system_clock(), port).await;
+
+    let mut owner_ws = connect_ws(&owner, port, &room, &room.users[0].id).await;
+
+    let guest = Client::default();
+    let guest_id = join_room(&guest, port, &room, "guest").await;
+    let mut guest_ws = connect_ws(&guest, port, &room, &guest_id).await;
+
+    let resume = wait_session_resume(&mut guest_ws, Duration::from_secs(2))
+        .await
+        .expect("No SessionResume received");
+
+    assert!(!resume.resumed);
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        Some("guest".into())
+    );
+
+    // Network blip
+    drop(guest_ws);
+    time::sleep(Duration::from_millis(500)).await;
+
+    let mut guest_ws = resume_ws(&guest, port, &room, &guest_id, &resume.token).await;
+
+    let resumed = wait_session_resume(&mut guest_ws, Duration::from_secs(2))
+        .await
+        .expect("No SessionResume received");
+
+    assert!(resumed.resumed);
+    assert_ne!(resumed.token, resume.token);
+    assert_eq!(
+        wait_new_user_joined(&mut owner_ws, Duration::from_secs(2)).await,
+        None
+    );
+
+    // The previous token was rotated
+    drop(guest_ws);
+    time::sleep(Duration::from_millis(500)).await;
+
+    let mut guest_ws = resume_ws(&guest, port, &room, &guest_id, &resume.token).await;
+
+    assert!(
+        !wait_session_resume(&mut guest_ws, Duration::from_secs(2))
+            .await
+            .expect("No SessionResume received")
+            .resumed
+    );
+
+    let _ = cancel_tx.send(()).await;
+}
+
+#[actix_rt::test]
+async fn json_fallback_transport() {
+    let port = 3104;
+    let (cancel_tx, owner, room) = create_room_with_clock(60, system_clock(), port).await;
+
+    let mut ws = connect_ws_with_url(
+        &owner,
+        format!(
+            "{}/{}/{}?fmt=json&session_token={}",
+            base_url(port),
+            room.id,
+            room.users[0].id,
+            session_token(&room.users[0].id)
+        ),
+    )
+    .await;
+
+    // Answers pings until the room data, every message is a JSON CommandResponse
+    let wait_room = async |ws: &mut WebSocket| {
+        time::timeout(Duration::from_secs(2), async {
+            while let Ok(Some(msg)) = ws.try_next().await {
+                match msg {
+                    Message::Text(text) => {
+                        let cmd = serde_json::from_str::<CommandResponse>(&text)
+                            .expect("Failed to deserialize JSON CommandResponse");
+
+                        if let Some(command_response::Type::Room(room)) = cmd.r#type {
+                            return Some(room);
+                        }
+                    }
+                    Message::Binary(_) => panic!("Received a protobuf message on a JSON session"),
+                    _ => {}
+                }
+            }
+
+            None
+        })
+        .await
+        .ok()
+        .flatten()
+    };
+
+    assert!(wait_room(&mut ws).await.is_some());
+
+    assert!(
+        ws.send(Message::Text(r#"{"type": {"get_room": true}}"#.into()))
+            .await
+            .is_ok(),
+        "Failed to send JSON Command to WS"
+    );
+
+    let received_room: Room = wait_room(&mut ws)
+        .await
+        .expect("No Room received for the JSON GetRoom")
+        .into();
+
+    assert_eq!(received_room.id, room.id);
+
+    let _ = cancel_tx.send(()).await;
+}
+
+#[actix_rt::test]
+async fn ws_init_requires_the_session_token() {
+    let port = 3105;
+    let (cancel_tx, owner, room) = create_room_with_clock(60, system_clock(), port).await;
+
+    let guest = Client::default();
+    let guest_id = join_room(&guest, port, &room, "guest").await;
+
+    let init_status = async |url: String| {
+        guest
+            .get(url)
+            .upgrade()
+            .send()
+            .await
+            .expect("Failed to send HTTP GET request to create WS conn")
+            .status()
+    };
+
+    // Knowing the owner ID isn't enough to take its session
+    assert_eq!(
+        init_status(format!(
+            "{}/{}/{}",
+            base_url(port),
+            room.id,
+            room.users[0].id
+        ))
+        .await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        init_status(format!(
+            "{}/{}/{}?session_token={}",
+            base_url(port),
+            room.id,
+            room.users[0].id,
+            session_token(&guest_id)
+        ))
+        .await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let _owner_ws = connect_ws(&owner, port, &room, &room.users[0].id).await;
+
+    let _ = cancel_tx.send(()).await;
+}
+
+#[actix_rt::test]
+async fn spotify_data_loop_broadcasts_the_playback() {
+    let port = 3106;
+    let mock = start_mock_spotify(
+        port,
+        MockResponse::json(json!({
+            "device": { "id": "device", "volume_percent": 50 },
+            "progress_ms": 1000,
+            "is_playing": true,
+            "item": {
+                "type": "track",
+                "id": "track",
+                "uri": "spotify:track:track",
+                "name": "Track",
+                "artists": [{ "name": "Artist" }],
+                "duration_ms": 180000,
+            },
+        })),
+    )
+    .await;
+    let (cancel_tx, owner, room) = create_room_with_spotify(60, system_clock(), port, &mock).await;
+
+    let mut owner_ws = connect_ws(&owner, port, &room, &room.users[0].id).await;
+
+    let state = wait_response(
+        &mut owner_ws,
+        Duration::from_secs(5),
+        |r#type| match r#type {
+            command_response::Type::SpotifyAllState(all) => all.state,
+            _ => None,
+        },
+    )
+    .await
+    .expect("No playback state received");
+
+    let _ = cancel_tx.send(()).await;
+
+    assert_eq!(state.track_id, "track");
+    assert!(state.is_playing);
+    assert!(mock.hits(Method::GET, CURRENT_PLAYBACK_STATE) > 0);
+}